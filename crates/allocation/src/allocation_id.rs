@@ -0,0 +1,93 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thegraph_core::alloy::primitives::{Address, B256};
+
+/// Id of a Horizon allocation in the `SubgraphService`, the address of the
+/// allocation left padded to 32 bytes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CollectionId(B256);
+
+impl CollectionId {
+    /// Wraps a collection id read from a receipt or a RAV
+    pub fn new(id: B256) -> Self {
+        Self(id)
+    }
+
+    /// Address of the allocation, the last 20 bytes of the collection id
+    pub fn as_address(&self) -> Address {
+        Address::from_word(self.0)
+    }
+}
+
+impl From<Address> for CollectionId {
+    fn from(address: Address) -> Self {
+        Self(address.into_word())
+    }
+}
+
+impl From<CollectionId> for B256 {
+    fn from(id: CollectionId) -> Self {
+        id.0
+    }
+}
+
+impl FromStr for CollectionId {
+    type Err = <B256 as FromStr>::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        B256::from_str(s).map(Self)
+    }
+}
+
+impl Display for CollectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Id of an allocation, whether it was opened in the legacy staking contract
+/// or in a Horizon provision
+///
+/// Downstream watchers still key the allocations by [AllocationId::address],
+/// which is the same for both variants.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AllocationId {
+    /// Legacy allocation
+    Legacy(Address),
+    /// New Subgraph DataService allocation
+    Horizon(CollectionId),
+}
+
+impl AllocationId {
+    /// Take the inner address for both allocation types
+    pub fn address(&self) -> Address {
+        match self {
+            AllocationId::Legacy(address) => *address,
+            AllocationId::Horizon(collection_id) => collection_id.as_address(),
+        }
+    }
+
+    /// Whether the receipts of the allocation are TAP v2 receipts
+    pub fn is_horizon(&self) -> bool {
+        matches!(self, AllocationId::Horizon(_))
+    }
+}
+
+impl Display for AllocationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.address().fmt(f)
+    }
+}
+
+/// Provision of a Horizon allocation, the stake of `service_provider`
+/// dedicated to `data_service`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provision {
+    pub service_provider: Address,
+    pub data_service: Address,
+}
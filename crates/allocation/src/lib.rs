@@ -1,15 +1,19 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod allocation_id;
+
 use std::str::FromStr;
 
 use indexer_query::allocations_query;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thegraph_core::{
     alloy::primitives::{Address, U256},
     DeploymentId,
 };
 
+pub use crate::allocation_id::{AllocationId, CollectionId, Provision};
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Allocation {
     pub id: Address,
@@ -25,6 +29,20 @@ pub struct Allocation {
     pub poi: Option<String>,
     pub query_fee_rebates: Option<U256>,
     pub query_fees_collected: Option<U256>,
+    /// Provision the allocation was opened in, `None` for the legacy
+    /// allocations of the staking contract
+    pub provision: Option<Provision>,
+}
+
+impl Allocation {
+    /// Id of the allocation, a [CollectionId] if it was opened in a Horizon
+    /// provision
+    pub fn allocation_id(&self) -> AllocationId {
+        match self.provision {
+            Some(_) => AllocationId::Horizon(CollectionId::from(self.id)),
+            None => AllocationId::Legacy(self.id),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -36,7 +54,7 @@ pub enum AllocationStatus {
     Claimed,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SubgraphDeployment {
     pub id: DeploymentId,
     #[serde(rename = "deniedAt")]
@@ -63,6 +81,8 @@ impl<'d> Deserialize<'d> for Allocation {
             createdAtBlockHash: String,
             createdAtEpoch: u64,
             closedAtEpoch: Option<u64>,
+            #[serde(default)]
+            provision: Option<Provision>,
         }
 
         let outer = Outer::deserialize(deserializer)?;
@@ -81,10 +101,51 @@ impl<'d> Deserialize<'d> for Allocation {
             poi: None,
             query_fee_rebates: None,
             query_fees_collected: None,
+            provision: outer.provision,
         })
     }
 }
 
+/// Serialized as the network subgraph returns it, the fields it doesn't return
+/// are left out
+impl Serialize for Allocation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        #[derive(Serialize)]
+        struct InnerIndexer {
+            id: Address,
+        }
+
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct Outer<'a> {
+            id: Address,
+            subgraphDeployment: &'a SubgraphDeployment,
+            indexer: InnerIndexer,
+            allocatedTokens: U256,
+            createdAtBlockHash: &'a str,
+            createdAtEpoch: u64,
+            closedAtEpoch: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            provision: Option<&'a Provision>,
+        }
+
+        Outer {
+            id: self.id,
+            subgraphDeployment: &self.subgraph_deployment,
+            indexer: InnerIndexer { id: self.indexer },
+            allocatedTokens: self.allocated_tokens,
+            createdAtBlockHash: &self.created_at_block_hash,
+            createdAtEpoch: self.created_at_epoch,
+            closedAtEpoch: self.closed_at_epoch,
+            provision: self.provision.as_ref(),
+        }
+        .serialize(serializer)
+    }
+}
+
 impl TryFrom<allocations_query::AllocationFragment> for Allocation {
     type Error = anyhow::Error;
 
@@ -108,6 +169,15 @@ impl TryFrom<allocations_query::AllocationFragment> for Allocation {
             poi: None,
             query_fee_rebates: None,
             query_fees_collected: None,
+            provision: value
+                .provision
+                .map(|provision| {
+                    anyhow::Ok(Provision {
+                        service_provider: Address::from_str(&provision.indexer.id)?,
+                        data_service: Address::from_str(&provision.data_service.id)?,
+                    })
+                })
+                .transpose()?,
         })
     }
 }
@@ -1,10 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
 use indexer_allocation::Allocation;
+use lru::LruCache;
 use thegraph_core::{
     alloy::{
-        primitives::{Address, ChainId},
+        primitives::{keccak256, Address, ChainId, B256},
         signers::{
             k256,
             local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner},
@@ -16,6 +23,13 @@ use thegraph_core::{
     DeploymentId,
 };
 
+mod response_signature;
+
+pub use response_signature::{
+    recover_response_signer, response_signature_eip712_domain, response_signing_hash,
+    ResponseSigner,
+};
+
 pub fn derive_key_pair(
     indexer_mnemonic: &str,
     epoch: u64,
@@ -41,14 +55,66 @@ pub fn derive_key_pair(
         .build()?)
 }
 
+/// Number of attestations kept by each signer
+const ATTESTATION_CACHE_SIZE: NonZeroUsize = match NonZeroUsize::new(1000) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// Keccak256 hashes of a request and its response, the key of their
+/// attestation in the cache of a signer. The deployment is part of the key
+/// through the signer itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AttestationKey {
+    request: B256,
+    response: B256,
+}
+
+impl AttestationKey {
+    pub fn new(request: &str, response: &str) -> Self {
+        Self {
+            request: keccak256(request),
+            response: keccak256(response),
+        }
+    }
+}
+
+/// Attestations already signed
+type AttestationCache = LruCache<AttestationKey, Attestation>;
+
 /// An attestation signer tied to a specific allocation via its signer key
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Signing is the most expensive part of serving a query, so the signer
+/// keeps the attestations of the latest requests and hands them out again
+/// when the same request gets the same response. Clones share the cache.
+#[derive(Clone)]
 pub struct AttestationSigner {
     deployment: DeploymentId,
     domain: Eip712Domain,
     signer: k256::ecdsa::SigningKey,
+    cache: Arc<Mutex<AttestationCache>>,
 }
 
+impl fmt::Debug for AttestationSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttestationSigner")
+            .field("deployment", &self.deployment)
+            .field("domain", &self.domain)
+            .field("signer", &self.signer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for AttestationSigner {
+    fn eq(&self, other: &Self) -> bool {
+        self.deployment == other.deployment
+            && self.domain == other.domain
+            && self.signer == other.signer
+    }
+}
+
+impl Eq for AttestationSigner {}
+
 impl AttestationSigner {
     pub fn new(
         indexer_mnemonic: &str,
@@ -63,12 +129,44 @@ impl AttestationSigner {
             deployment: allocation.subgraph_deployment.id,
             domain: attestation::eip712_domain(chain_id, dispute_manager),
             signer: wallet.into_credential(),
+            cache: Arc::new(Mutex::new(LruCache::new(ATTESTATION_CACHE_SIZE))),
         })
     }
 
-    pub fn create_attestation(&self, request: &str, response: &str) -> Attestation {
+    pub fn deployment(&self) -> DeploymentId {
+        self.deployment
+    }
+
+    /// Returns the attestation previously created for the same request
+    /// and response if it is still cached, or else their [AttestationKey]
+    /// to pass to [Self::sign_attestation]
+    pub fn cached_attestation(
+        &self,
+        request: &str,
+        response: &str,
+    ) -> Result<Attestation, AttestationKey> {
+        let key = AttestationKey::new(request, response);
+        self.cache.lock().unwrap().get(&key).cloned().ok_or(key)
+    }
+
+    /// Signs the attestation of `request` and `response` and caches it
+    /// with their `key`, returned by [Self::cached_attestation]
+    pub fn sign_attestation(
+        &self,
+        key: AttestationKey,
+        request: &str,
+        response: &str,
+    ) -> Attestation {
         let wallet = PrivateKeySigner::from_signing_key(self.signer.clone());
-        attestation::create(&self.domain, &wallet, &self.deployment, request, response)
+        let attestation =
+            attestation::create(&self.domain, &wallet, &self.deployment, request, response);
+        self.cache.lock().unwrap().put(key, attestation.clone());
+        attestation
+    }
+
+    pub fn create_attestation(&self, request: &str, response: &str) -> Attestation {
+        self.cached_attestation(request, response)
+            .unwrap_or_else(|key| self.sign_attestation(key, request, response))
     }
 
     pub fn verify(
@@ -195,6 +293,7 @@ mod tests {
             poi: None,
             query_fee_rebates: None,
             query_fees_collected: None,
+            provision: None,
         };
         assert_eq!(
             PrivateKeySigner::from_signing_key(
@@ -217,6 +316,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_attestation_cache() {
+        let allocation = Allocation {
+            id: address!("a171cd12c3dde7eb8fe7717a0bcd06f3ffa65658"),
+            status: AllocationStatus::Null,
+            subgraph_deployment: SubgraphDeployment {
+                id: DeploymentId::from_str(
+                    "0xbbde25a2c85f55b53b7698b9476610c3d1202d88870e66502ab0076b7218f98a",
+                )
+                .unwrap(),
+                denied_at: None,
+            },
+            indexer: Address::ZERO,
+            allocated_tokens: U256::ZERO,
+            created_at_epoch: 940,
+            created_at_block_hash: "".to_string(),
+            closed_at_epoch: None,
+            closed_at_epoch_start_block_hash: None,
+            previous_epoch_start_block_hash: None,
+            poi: None,
+            query_fee_rebates: None,
+            query_fees_collected: None,
+            provision: None,
+        };
+        let signer = AttestationSigner::new(
+            INDEXER_OPERATOR_MNEMONIC,
+            &allocation,
+            1,
+            DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+
+        let request = r#"{"query":"{ _meta { block { number } } }"}"#;
+        assert_eq!(
+            signer.cached_attestation(request, "response"),
+            Err(AttestationKey::new(request, "response"))
+        );
+
+        let attestation = signer.create_attestation(request, "response");
+        // clones share the cache, like the signers handed to each request
+        assert_eq!(
+            signer.clone().cached_attestation(request, "response"),
+            Ok(attestation.clone())
+        );
+        assert_eq!(signer.create_attestation(request, "response"), attestation);
+        assert!(signer
+            .cached_attestation(request, "another response")
+            .is_err());
+    }
+
     #[test]
     fn test_attestation_signer_error() {
         // Note that because allocation will try 200 derivations paths, this is a slow test
@@ -242,6 +391,7 @@ mod tests {
             poi: None,
             query_fee_rebates: None,
             query_fees_collected: None,
+            provision: None,
         };
         assert!(AttestationSigner::new(
             INDEXER_OPERATOR_MNEMONIC,
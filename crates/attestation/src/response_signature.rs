@@ -0,0 +1,148 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signatures of the responses of free queries
+//!
+//! Free queries are not tied to an allocation, so they can't be attested.
+//! The operator signs them instead, so the consumers can still check which
+//! indexer served a response. The signature is an EIP-712 signature of a
+//! `SignedResponse { requestHash, responseHash, timestampMs }`, the keccak256
+//! hashes of the bodies, in the domain returned by
+//! [response_signature_eip712_domain]. The operator key signs nothing else
+//! in that domain, a signed response can't be mistaken for a transaction or
+//! for another message of the operator.
+
+use thegraph_core::alloy::{
+    primitives::{
+        keccak256, Address, ChainId, PrimitiveSignature as Signature, SignatureError, B256,
+    },
+    signers::{local::PrivateKeySigner, SignerSync},
+    sol,
+    sol_types::{eip712_domain, Eip712Domain, SolStruct},
+};
+
+sol! {
+    /// Response to a free query, signed by the operator
+    #[derive(Debug, PartialEq)]
+    struct SignedResponse {
+        bytes32 requestHash;
+        bytes32 responseHash;
+        uint64 timestampMs;
+    }
+}
+
+/// EIP-712 domain of the response signatures on `chain_id`
+pub fn response_signature_eip712_domain(chain_id: ChainId) -> Eip712Domain {
+    eip712_domain! {
+        name: "Graph Indexer Response Signature",
+        version: "0",
+        chain_id: chain_id,
+    }
+}
+
+/// Hash signed for `response` to `request`, at `timestamp_ms`
+pub fn response_signing_hash(
+    domain: &Eip712Domain,
+    request: &[u8],
+    response: &[u8],
+    timestamp_ms: u64,
+) -> B256 {
+    SignedResponse {
+        requestHash: keccak256(request),
+        responseHash: keccak256(response),
+        timestampMs: timestamp_ms,
+    }
+    .eip712_signing_hash(domain)
+}
+
+/// Address that signed `response` to `request` at `timestamp_ms`
+pub fn recover_response_signer(
+    domain: &Eip712Domain,
+    signature: &Signature,
+    request: &[u8],
+    response: &[u8],
+    timestamp_ms: u64,
+) -> Result<Address, SignatureError> {
+    signature.recover_address_from_prehash(&response_signing_hash(
+        domain,
+        request,
+        response,
+        timestamp_ms,
+    ))
+}
+
+/// Signs the responses of free queries with the operator key
+#[derive(Clone, Debug)]
+pub struct ResponseSigner {
+    wallet: PrivateKeySigner,
+    domain: Eip712Domain,
+}
+
+impl ResponseSigner {
+    pub fn new(wallet: PrivateKeySigner, chain_id: ChainId) -> Self {
+        Self {
+            wallet,
+            domain: response_signature_eip712_domain(chain_id),
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.wallet.address()
+    }
+
+    pub fn domain(&self) -> &Eip712Domain {
+        &self.domain
+    }
+
+    pub fn sign(
+        &self,
+        request: &[u8],
+        response: &[u8],
+        timestamp_ms: u64,
+    ) -> anyhow::Result<Signature> {
+        let hash = response_signing_hash(&self.domain, request, response, timestamp_ms);
+        Ok(self.wallet.sign_hash_sync(&hash)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thegraph_core::alloy::signers::local::PrivateKeySigner;
+
+    use super::{recover_response_signer, response_signature_eip712_domain, ResponseSigner};
+
+    #[test]
+    fn test_recover_response_signer() {
+        let signer = ResponseSigner::new(PrivateKeySigner::random(), 42161);
+        let domain = signer.domain();
+        let request = br#"{"query":"{ _meta { block { number } } }"}"#;
+        let response = br#"{"data":{"_meta":{"block":{"number":1}}}}"#;
+        let signature = signer.sign(request, response, 1000).unwrap();
+
+        assert_eq!(
+            recover_response_signer(domain, &signature, request, response, 1000).unwrap(),
+            signer.address()
+        );
+        // any change to the request, response or timestamp gives another signer
+        assert_ne!(
+            recover_response_signer(domain, &signature, request, response, 1001).unwrap(),
+            signer.address()
+        );
+        assert_ne!(
+            recover_response_signer(domain, &signature, request, b"{}", 1000).unwrap(),
+            signer.address()
+        );
+        // nor is the signature valid on another chain
+        assert_ne!(
+            recover_response_signer(
+                &response_signature_eip712_domain(1),
+                &signature,
+                request,
+                response,
+                1000
+            )
+            .unwrap(),
+            signer.address()
+        );
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Load test of a running indexer-service
+//!
+//! Paid queries are sent at a constant rate, each with a new receipt signed
+//! the way gateways sign them. Once done, the latency percentiles of the
+//! responses are reported along with the rate the receipts were accepted,
+//! and so written to the database, at.
+//!
+//! ```bash
+//! cargo run --release -p indexer-bench -- \
+//!     --service-url http://localhost:7600/ \
+//!     --deployment QmUhiH6Z5xo6o3GNzsSvqpGKLmCt6w5WzKQ1yHk6C8AA8S \
+//!     --allocation 0xfa44c72b753a66591f241c7dc04e8178c30e13af \
+//!     --qps 200 --duration-secs 60
+//! ```
+
+mod queries;
+mod report;
+
+use std::{path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use indexer_receipt::TapReceipt;
+use indexer_test_harness::{assets, gateway::MockGateway};
+use reqwest::Url;
+use tap_core::tap_eip712_domain;
+use thegraph_core::{
+    alloy::{primitives::Address, signers::local::PrivateKeySigner},
+    DeploymentId,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::report::{Outcome, Report};
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// URL of the service, e.g. http://localhost:7600/
+    #[arg(long)]
+    service_url: Url,
+
+    /// Deployment the queries are sent to
+    #[arg(long)]
+    deployment: DeploymentId,
+
+    /// Allocation of the indexer for the deployment, the receipts are for it
+    #[arg(long)]
+    allocation: Address,
+
+    /// Queries sent per second
+    #[arg(long, default_value_t = 10)]
+    qps: u32,
+
+    /// How long the queries are sent for, in seconds
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+
+    /// Queries waiting for a response at most, the queries over it are
+    /// skipped and counted instead of being sent
+    #[arg(long, default_value_t = 1000)]
+    max_in_flight: usize,
+
+    /// Value of each receipt, in GRT wei
+    #[arg(long, default_value_t = 10_000_000_000_000)]
+    receipt_value: u128,
+
+    /// Version of the receipts
+    #[arg(long, value_enum, default_value_t = ReceiptVersion::V1)]
+    receipt_version: ReceiptVersion,
+
+    /// Private key the receipts are signed with, it must be an authorized
+    /// signer of a sender. The signer of the test assets if not set.
+    #[arg(long, env = "INDEXER_BENCH_SIGNER_KEY", hide_env_values = true)]
+    signer_key: Option<String>,
+
+    /// Chain ID of the TAP EIP-712 domain, `blockchain.chain_id` of the service
+    #[arg(long, default_value_t = 1)]
+    chain_id: u64,
+
+    /// Verifier of the TAP EIP-712 domain, `blockchain.receipts_verifier_address`
+    /// of the service. The verifier of the test assets if not set.
+    #[arg(long)]
+    verifier: Option<Address>,
+
+    /// Payer of the v2 receipts, the sender of the test assets if not set
+    #[arg(long)]
+    payer: Option<Address>,
+
+    /// Indexer the v2 receipts are for, the indexer of the test assets if not set
+    #[arg(long)]
+    service_provider: Option<Address>,
+
+    /// Data service of the v2 receipts
+    #[arg(long, default_value_t = Address::ZERO)]
+    data_service: Address,
+
+    /// File with one query per line, sent in turn. A set of queries that any
+    /// deployment answers if not set.
+    #[arg(long, value_name = "FILE")]
+    queries: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReceiptVersion {
+    V1,
+    V2,
+}
+
+impl Cli {
+    /// Gateway signing the receipts for the domain of the service
+    fn gateway(&self) -> anyhow::Result<MockGateway> {
+        let mut gateway = MockGateway::new(self.service_url.clone())
+            .with_domain_separator(tap_eip712_domain(
+                self.chain_id,
+                self.verifier.unwrap_or(assets::VERIFIER_ADDRESS),
+            ))
+            .with_v2_parties(
+                self.payer.unwrap_or(assets::TAP_SENDER.1),
+                self.data_service,
+                self.service_provider.unwrap_or(assets::INDEXER_ADDRESS),
+            );
+        if let Some(signer_key) = &self.signer_key {
+            gateway = gateway.with_signer(PrivateKeySigner::from_str(signer_key)?);
+        }
+        Ok(gateway)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    anyhow::ensure!(cli.qps > 0, "--qps must be greater than 0");
+    let queries = match &cli.queries {
+        Some(path) => queries::from_file(path)?,
+        None => queries::defaults(),
+    };
+    let gateway = Arc::new(cli.gateway()?);
+
+    println!(
+        "Sending {} queries per second to {} for {}s",
+        cli.qps, cli.deployment, cli.duration_secs
+    );
+    let report = run(&cli, gateway, queries).await;
+    println!("{report}");
+    Ok(())
+}
+
+/// Sends the queries in turn at the rate of `cli`, until its duration is
+/// elapsed and every response is received
+async fn run(cli: &Cli, gateway: Arc<MockGateway>, queries: Vec<Arc<str>>) -> Report {
+    let in_flight = Arc::new(Semaphore::new(cli.max_in_flight));
+    let (outcomes_tx, mut outcomes_rx) = mpsc::unbounded_channel();
+    let mut report = Report::default();
+
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / f64::from(cli.qps)));
+    // catch up with the rate after a slow tick, it is what gateways do
+    interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(cli.duration_secs);
+    for query in queries.iter().cycle() {
+        if interval.tick().await >= deadline {
+            break;
+        }
+        let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+            report.skip();
+            continue;
+        };
+        let (gateway, query, outcomes_tx) = (gateway.clone(), query.clone(), outcomes_tx.clone());
+        let (deployment, allocation, value) = (cli.deployment, cli.allocation, cli.receipt_value);
+        let version = cli.receipt_version;
+        tokio::spawn(async move {
+            let receipt = match version {
+                ReceiptVersion::V1 => TapReceipt::V1(gateway.receipt_v1(allocation, value)),
+                ReceiptVersion::V2 => TapReceipt::V2(gateway.receipt_v2(allocation, value)),
+            };
+            let sent = Instant::now();
+            let outcome = match gateway.query(deployment, &query, Some(&receipt)).await {
+                // the latency includes the body, it is sent once attested
+                Ok(response) => {
+                    let status = response.status();
+                    match response.bytes().await {
+                        Ok(_) => Outcome::Response(status),
+                        Err(_) => Outcome::Failed,
+                    }
+                }
+                Err(_) => Outcome::Failed,
+            };
+            let _ = outcomes_tx.send((outcome, sent.elapsed()));
+            drop(permit);
+        });
+    }
+
+    drop(outcomes_tx);
+    while let Some((outcome, latency)) = outcomes_rx.recv().await {
+        report.record(outcome, latency);
+    }
+    report.finish(started.elapsed());
+    report
+}
@@ -0,0 +1,64 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Queries sent by the load test
+
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::Context;
+
+/// Queries any deployment answers, whatever its schema
+///
+/// The queries of gateways depend on the schema of the deployment, those of
+/// the deployment should be given with `--queries` for a realistic load on
+/// graph-node.
+const DEFAULT_QUERIES: [&str; 4] = [
+    "{ _meta { block { number } } }",
+    "{ _meta { deployment hasIndexingErrors block { number hash timestamp } } }",
+    "query Meta { _meta { block { number hash } } __typename }",
+    "{ __schema { queryType { name fields { name } } } }",
+];
+
+pub fn defaults() -> Vec<Arc<str>> {
+    DEFAULT_QUERIES.into_iter().map(Arc::from).collect()
+}
+
+/// Queries of a file with one query per line, empty lines and lines
+/// starting with `#` are ignored
+pub fn from_file(path: &Path) -> anyhow::Result<Vec<Arc<str>>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the queries of {}", path.display()))?;
+    let queries = parse(&content);
+    anyhow::ensure!(!queries.is_empty(), "No query in {}", path.display());
+    Ok(queries)
+}
+
+fn parse(content: &str) -> Vec<Arc<str>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Arc::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::parse;
+
+    #[test]
+    fn test_parse_queries() {
+        let content = "# top tokens\n\
+            { tokens(first: 100) { id symbol } }\n\
+            \n  { pairs(first: 10, skip: 10) { id } }  \n";
+        assert_eq!(
+            parse(content),
+            vec![
+                Arc::from("{ tokens(first: 100) { id symbol } }"),
+                Arc::from("{ pairs(first: 10, skip: 10) { id } }"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Results of the load test
+
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use reqwest::StatusCode;
+
+/// Outcome of a query
+pub enum Outcome {
+    Response(StatusCode),
+    /// The query couldn't be sent or the response received
+    Failed,
+}
+
+#[derive(Default)]
+pub struct Report {
+    /// Latencies of the queries answered successfully, their receipt was
+    /// stored by the service
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, usize>,
+    failed: usize,
+    /// Queries not sent since too many were waiting for a response
+    skipped: usize,
+    elapsed: Duration,
+}
+
+impl Report {
+    pub fn record(&mut self, outcome: Outcome, latency: Duration) {
+        match outcome {
+            Outcome::Response(status) => {
+                *self.statuses.entry(status.as_u16()).or_default() += 1;
+                if status.is_success() {
+                    self.latencies.push(latency);
+                }
+            }
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Sorts the latencies, once every outcome is recorded `elapsed` after
+    /// the first query was sent
+    pub fn finish(&mut self, elapsed: Duration) {
+        self.latencies.sort_unstable();
+        self.elapsed = elapsed;
+    }
+
+    fn sent(&self) -> usize {
+        self.statuses.values().sum::<usize>() + self.failed
+    }
+
+    /// Receipts accepted, and so written to the database, per second
+    fn receipts_per_sec(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let index = (last as f64 * percentile).round() as usize;
+        Some(self.latencies[index])
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sent = self.sent();
+        writeln!(
+            f,
+            "Queries sent:       {sent} in {:.1}s ({:.1}/s), {} skipped",
+            self.elapsed.as_secs_f64(),
+            sent as f64 / self.elapsed.as_secs_f64(),
+            self.skipped,
+        )?;
+        write!(f, "Responses:          ")?;
+        for (status, count) in &self.statuses {
+            write!(f, "{status}: {count}  ")?;
+        }
+        writeln!(f, "failed: {}", self.failed)?;
+        writeln!(
+            f,
+            "Receipts accepted:  {} ({:.1}/s)",
+            self.latencies.len(),
+            self.receipts_per_sec(),
+        )?;
+        write!(f, "Latency:           ")?;
+        for (name, percentile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("max", 1.0)] {
+            match self.percentile(percentile) {
+                Some(latency) => write!(f, " {name} {:.1}ms", latency.as_secs_f64() * 1000.0)?,
+                None => write!(f, " {name} -")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::{Outcome, Report};
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        for latency in (1..=100).rev() {
+            report.record(
+                Outcome::Response(StatusCode::OK),
+                Duration::from_millis(latency),
+            );
+        }
+        // not accepted, not in the latencies
+        report.record(
+            Outcome::Response(StatusCode::PAYMENT_REQUIRED),
+            Duration::from_secs(1),
+        );
+        report.record(Outcome::Failed, Duration::from_secs(1));
+        report.skip();
+        report.finish(Duration::from_secs(10));
+
+        assert_eq!(report.sent(), 102);
+        assert_eq!(report.receipts_per_sec(), 10.0);
+        assert_eq!(report.percentile(0.5), Some(Duration::from_millis(51)));
+        assert_eq!(report.percentile(0.99), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(1.0), Some(Duration::from_millis(100)));
+        assert_eq!(Report::default().percentile(0.5), None);
+    }
+}
@@ -2,8 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod config;
+mod diagnostics;
 mod grt;
 
 pub use bip39::Mnemonic;
 pub use config::*;
+pub use diagnostics::ConfigDiagnostics;
 pub use grt::*;
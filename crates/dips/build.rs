@@ -19,5 +19,12 @@ fn main() {
             .protoc_arg("--experimental_allow_proto3_optional")
             .compile_protos(&["proto/gateway.proto"], &["proto"])
             .expect("Failed to compile DIPs gateway RPC proto(s)");
+
+        tonic_build::configure()
+            .out_dir("src/proto")
+            .include_file("sender_stats.rs")
+            .protoc_arg("--experimental_allow_proto3_optional")
+            .compile_protos(&["proto/sender_stats.proto"], &["proto"])
+            .expect("Failed to compile sender stats RPC proto(s)");
     }
 }
@@ -6,13 +6,18 @@ use std::str::FromStr;
 use async_trait::async_trait;
 use build_info::chrono::{DateTime, Utc};
 use sqlx::{types::BigDecimal, PgPool};
-use thegraph_core::alloy::{core::primitives::U256 as uint256, hex::ToHexExt, sol_types::SolType};
+use thegraph_core::alloy::{
+    core::primitives::{Address, U256 as uint256},
+    hex::ToHexExt,
+    sol_types::SolType,
+};
 use uuid::Uuid;
 
 use crate::{
+    decode_voucher_metadata,
     store::{AgreementStore, StoredIndexingAgreement},
-    DipsError, SignedCancellationRequest, SignedIndexingAgreementVoucher,
-    SubgraphIndexingVoucherMetadata,
+    DipsError, PriceEscalation, SignedCancellationRequest, SignedIndexingAgreementVoucher,
+    SubgraphIndexingVoucherMetadata, ESCALATING_VOUCHER_VERSION,
 };
 
 #[derive(Debug)]
@@ -20,92 +25,269 @@ pub struct PsqlAgreementStore {
     pub pool: PgPool,
 }
 
+struct AgreementRow {
+    signed_payload: Vec<u8>,
+    version: i64,
+    accepted_at: DateTime<Utc>,
+    cancelled_at: Option<DateTime<Utc>>,
+    unprofitable_at: Option<DateTime<Utc>>,
+    current_allocation_id: Option<String>,
+    last_allocation_id: Option<String>,
+    last_payment_collected_at: Option<DateTime<Utc>>,
+    indexing_started_at: Option<DateTime<Utc>>,
+    synced_at: Option<DateTime<Utc>>,
+}
+
+impl AgreementRow {
+    fn decode(self) -> Result<StoredIndexingAgreement, DipsError> {
+        let signed = SignedIndexingAgreementVoucher::abi_decode(self.signed_payload.as_ref(), true)
+            .map_err(|e| DipsError::AbiDecoding(e.to_string()))?;
+        let (metadata, escalation) =
+            decode_voucher_metadata(self.version as u64, signed.voucher.metadata.as_ref())?;
+        Ok(StoredIndexingAgreement {
+            voucher: signed,
+            metadata,
+            escalation,
+            accepted_at: self.accepted_at,
+            cancelled: self.cancelled_at.is_some(),
+            unprofitable: self.unprofitable_at.is_some(),
+            current_allocation_id: self.current_allocation_id,
+            last_allocation_id: self.last_allocation_id,
+            last_payment_collected_at: self.last_payment_collected_at,
+            indexing_started_at: self.indexing_started_at,
+            synced_at: self.synced_at,
+        })
+    }
+}
+
+/// Columns of the terms of an agreement, that can be replaced by an amendment
+struct AgreementTerms {
+    signed_payload: Vec<u8>,
+    version: i64,
+    base_price_per_epoch: BigDecimal,
+    price_per_entity: BigDecimal,
+    deadline: DateTime<Utc>,
+    duration_epochs: i64,
+    max_initial_amount: BigDecimal,
+    max_ongoing_amount_per_epoch: BigDecimal,
+    min_epochs_per_collection: i64,
+    max_epochs_per_collection: i64,
+}
+
+impl AgreementTerms {
+    fn new(
+        agreement: &SignedIndexingAgreementVoucher,
+        metadata: &SubgraphIndexingVoucherMetadata,
+        escalation: Option<&PriceEscalation>,
+    ) -> Result<Self, DipsError> {
+        let deadline_i64: i64 = agreement
+            .voucher
+            .deadline
+            .try_into()
+            .map_err(|_| DipsError::InvalidVoucher("deadline".to_string()))?;
+        let deadline = DateTime::from_timestamp(deadline_i64, 0)
+            .ok_or(DipsError::InvalidVoucher("deadline".to_string()))?;
+        Ok(Self {
+            signed_payload: agreement.encode_vec(),
+            version: match escalation {
+                Some(_) => ESCALATING_VOUCHER_VERSION as i64,
+                None => 1,
+            },
+            base_price_per_epoch: uint256_to_bigdecimal(
+                &metadata.basePricePerEpoch,
+                "basePricePerEpoch",
+            )?,
+            price_per_entity: uint256_to_bigdecimal(&metadata.pricePerEntity, "pricePerEntity")?,
+            deadline,
+            duration_epochs: agreement.voucher.durationEpochs.into(),
+            max_initial_amount: uint256_to_bigdecimal(
+                &agreement.voucher.maxInitialAmount,
+                "maxInitialAmount",
+            )?,
+            max_ongoing_amount_per_epoch: uint256_to_bigdecimal(
+                &agreement.voucher.maxOngoingAmountPerEpoch,
+                "maxOngoingAmountPerEpoch",
+            )?,
+            min_epochs_per_collection: agreement.voucher.minEpochsPerCollection.into(),
+            max_epochs_per_collection: agreement.voucher.maxEpochsPerCollection.into(),
+        })
+    }
+}
+
 fn uint256_to_bigdecimal(value: &uint256, field: &str) -> Result<BigDecimal, DipsError> {
     BigDecimal::from_str(&value.to_string())
         .map_err(|e| DipsError::InvalidVoucher(format!("{}: {}", field, e)))
 }
 
+impl PsqlAgreementStore {
+    /// Agreements with the id and the payer, any of them if `None`
+    async fn select_agreements(
+        &self,
+        id: Option<Uuid>,
+        payer: Option<String>,
+    ) -> Result<Vec<AgreementRow>, DipsError> {
+        sqlx::query_as!(
+            AgreementRow,
+            r#"
+                SELECT
+                    signed_payload,
+                    version,
+                    COALESCE(amended_at, created_at) AS "accepted_at!",
+                    cancelled_at,
+                    unprofitable_at,
+                    current_allocation_id,
+                    last_allocation_id,
+                    last_payment_collected_at,
+                    indexing_started_at,
+                    synced_at
+                FROM indexing_agreements
+                WHERE ($1::UUID IS NULL OR id = $1)
+                    AND ($2::CHAR(40) IS NULL OR payer = $2)
+                ORDER BY id
+            "#,
+            id,
+            payer
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| DipsError::UnknownError(e.into()))
+    }
+}
+
 #[async_trait]
 impl AgreementStore for PsqlAgreementStore {
     async fn get_by_id(&self, id: Uuid) -> Result<Option<StoredIndexingAgreement>, DipsError> {
-        let item = sqlx::query!("SELECT * FROM indexing_agreements WHERE id=$1", id,)
-            .fetch_one(&self.pool)
-            .await;
-
-        let item = match item {
-            Ok(item) => item,
-            Err(sqlx::Error::RowNotFound) => return Ok(None),
-            Err(err) => return Err(DipsError::UnknownError(err.into())),
-        };
+        let rows = self.select_agreements(Some(id), None).await?;
 
-        let signed = SignedIndexingAgreementVoucher::abi_decode(item.signed_payload.as_ref(), true)
-            .map_err(|e| DipsError::AbiDecoding(e.to_string()))?;
-        let metadata =
-            SubgraphIndexingVoucherMetadata::abi_decode(signed.voucher.metadata.as_ref(), true)
-                .map_err(|e| DipsError::AbiDecoding(e.to_string()))?;
-        let cancelled = item.cancelled_at.is_some();
-        Ok(Some(StoredIndexingAgreement {
-            voucher: signed,
-            metadata,
-            cancelled,
-            current_allocation_id: item.current_allocation_id,
-            last_allocation_id: item.last_allocation_id,
-            last_payment_collected_at: item.last_payment_collected_at,
-        }))
+        rows.into_iter()
+            .next()
+            .map(AgreementRow::decode)
+            .transpose()
+    }
+    async fn list_agreements(
+        &self,
+        payer: Option<Address>,
+    ) -> Result<Vec<StoredIndexingAgreement>, DipsError> {
+        let rows = self
+            .select_agreements(None, payer.map(|payer| payer.encode_hex()))
+            .await?;
+
+        rows.into_iter().map(AgreementRow::decode).collect()
     }
     async fn create_agreement(
         &self,
         agreement: SignedIndexingAgreementVoucher,
         metadata: SubgraphIndexingVoucherMetadata,
+        escalation: Option<PriceEscalation>,
     ) -> Result<(), DipsError> {
         let id = Uuid::from_bytes(agreement.voucher.agreement_id.into());
-        let bs = agreement.encode_vec();
+        let terms = AgreementTerms::new(&agreement, &metadata, escalation.as_ref())?;
         let now = Utc::now();
-        let deadline_i64: i64 = agreement
-            .voucher
-            .deadline
-            .try_into()
-            .map_err(|_| DipsError::InvalidVoucher("deadline".to_string()))?;
-        let deadline = DateTime::from_timestamp(deadline_i64, 0)
-            .ok_or(DipsError::InvalidVoucher("deadline".to_string()))?;
-        let base_price_per_epoch =
-            uint256_to_bigdecimal(&metadata.basePricePerEpoch, "basePricePerEpoch")?;
-        let price_per_entity = uint256_to_bigdecimal(&metadata.pricePerEntity, "pricePerEntity")?;
-        let duration_epochs: i64 = agreement.voucher.durationEpochs.into();
-        let max_initial_amount =
-            uint256_to_bigdecimal(&agreement.voucher.maxInitialAmount, "maxInitialAmount")?;
-        let max_ongoing_amount_per_epoch = uint256_to_bigdecimal(
-            &agreement.voucher.maxOngoingAmountPerEpoch,
-            "maxOngoingAmountPerEpoch",
-        )?;
-        let min_epochs_per_collection: i64 = agreement.voucher.minEpochsPerCollection.into();
-        let max_epochs_per_collection: i64 = agreement.voucher.maxEpochsPerCollection.into();
         sqlx::query!(
-            "INSERT INTO indexing_agreements VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16,$17,$18,$19,null,null,null,null,null)",
+            r#"
+                INSERT INTO indexing_agreements (
+                    id,
+                    signature,
+                    signed_payload,
+                    protocol_network,
+                    chain_id,
+                    base_price_per_epoch,
+                    price_per_entity,
+                    subgraph_deployment_id,
+                    service,
+                    payee,
+                    payer,
+                    deadline,
+                    duration_epochs,
+                    max_initial_amount,
+                    max_ongoing_amount_per_epoch,
+                    min_epochs_per_collection,
+                    max_epochs_per_collection,
+                    created_at,
+                    updated_at,
+                    version
+                )
+                VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
+                    $11, $12, $13, $14, $15, $16, $17, $18, $18, $19
+                )
+            "#,
             id,
             agreement.signature.as_ref(),
-            bs,
+            terms.signed_payload,
             metadata.protocolNetwork,
             metadata.chainId,
-            base_price_per_epoch,
-            price_per_entity,
+            terms.base_price_per_epoch,
+            terms.price_per_entity,
             metadata.subgraphDeploymentId,
             agreement.voucher.service.encode_hex(),
             agreement.voucher.recipient.encode_hex(),
             agreement.voucher.payer.encode_hex(),
-            deadline,
-            duration_epochs,
-            max_initial_amount,
-            max_ongoing_amount_per_epoch,
-            min_epochs_per_collection,
-            max_epochs_per_collection,
+            terms.deadline,
+            terms.duration_epochs,
+            terms.max_initial_amount,
+            terms.max_ongoing_amount_per_epoch,
+            terms.min_epochs_per_collection,
+            terms.max_epochs_per_collection,
             now,
+            terms.version
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DipsError::UnknownError(e.into()))?;
+
+        Ok(())
+    }
+    async fn amend_agreement(
+        &self,
+        agreement: SignedIndexingAgreementVoucher,
+        metadata: SubgraphIndexingVoucherMetadata,
+        escalation: Option<PriceEscalation>,
+    ) -> Result<(), DipsError> {
+        let id = Uuid::from_bytes(agreement.voucher.agreement_id.into());
+        let terms = AgreementTerms::new(&agreement, &metadata, escalation.as_ref())?;
+        let now = Utc::now();
+        let result = sqlx::query!(
+            r#"
+                UPDATE indexing_agreements
+                SET
+                    signature = $2,
+                    signed_payload = $3,
+                    version = $4,
+                    base_price_per_epoch = $5,
+                    price_per_entity = $6,
+                    deadline = $7,
+                    duration_epochs = $8,
+                    max_initial_amount = $9,
+                    max_ongoing_amount_per_epoch = $10,
+                    min_epochs_per_collection = $11,
+                    max_epochs_per_collection = $12,
+                    updated_at = $13,
+                    amended_at = $13,
+                    unprofitable_at = NULL
+                WHERE id = $1 AND cancelled_at IS NULL
+            "#,
+            id,
+            agreement.signature.as_ref(),
+            terms.signed_payload,
+            terms.version,
+            terms.base_price_per_epoch,
+            terms.price_per_entity,
+            terms.deadline,
+            terms.duration_epochs,
+            terms.max_initial_amount,
+            terms.max_ongoing_amount_per_epoch,
+            terms.min_epochs_per_collection,
+            terms.max_epochs_per_collection,
             now
         )
         .execute(&self.pool)
         .await
         .map_err(|e| DipsError::UnknownError(e.into()))?;
 
+        if result.rows_affected() == 0 {
+            return Err(DipsError::AgreementNotFound);
+        }
         Ok(())
     }
     async fn cancel_agreement(
@@ -128,6 +310,55 @@ impl AgreementStore for PsqlAgreementStore {
 
         Ok(id)
     }
+    async fn set_unprofitable(&self, id: Uuid, unprofitable: bool) -> Result<(), DipsError> {
+        sqlx::query!(
+            r#"
+                UPDATE indexing_agreements
+                SET unprofitable_at = CASE WHEN $2 THEN COALESCE(unprofitable_at, $3) END
+                WHERE id = $1
+            "#,
+            id,
+            unprofitable,
+            Utc::now()
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DipsError::UnknownError(e.into()))?;
+
+        Ok(())
+    }
+    async fn set_indexing_started(&self, id: Uuid) -> Result<(), DipsError> {
+        sqlx::query!(
+            r#"
+                UPDATE indexing_agreements
+                SET indexing_started_at = COALESCE(indexing_started_at, $2)
+                WHERE id = $1
+            "#,
+            id,
+            Utc::now()
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DipsError::UnknownError(e.into()))?;
+
+        Ok(())
+    }
+    async fn set_synced(&self, id: Uuid) -> Result<(), DipsError> {
+        sqlx::query!(
+            r#"
+                UPDATE indexing_agreements
+                SET synced_at = COALESCE(synced_at, $2)
+                WHERE id = $1
+            "#,
+            id,
+            Utc::now()
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DipsError::UnknownError(e.into()))?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -143,7 +374,9 @@ pub(crate) mod test {
     use uuid::Uuid;
 
     use super::*;
-    use crate::{CancellationRequest, IndexingAgreementVoucher};
+    use crate::{
+        CancellationRequest, EscalatingSubgraphIndexingVoucherMetadata, IndexingAgreementVoucher,
+    };
 
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_store_agreement(pool: PgPool) {
@@ -179,7 +412,7 @@ pub(crate) mod test {
 
         // Store agreement
         store
-            .create_agreement(agreement.clone(), metadata)
+            .create_agreement(agreement.clone(), metadata, None)
             .await
             .unwrap();
 
@@ -230,7 +463,7 @@ pub(crate) mod test {
 
         // Store agreement
         store
-            .create_agreement(agreement.clone(), metadata.clone())
+            .create_agreement(agreement.clone(), metadata.clone(), None)
             .await
             .unwrap();
 
@@ -311,7 +544,7 @@ pub(crate) mod test {
 
         // Store agreement
         store
-            .create_agreement(agreement.clone(), metadata)
+            .create_agreement(agreement.clone(), metadata, None)
             .await
             .unwrap();
 
@@ -336,4 +569,129 @@ pub(crate) mod test {
             Some(cancellation.encode_vec())
         );
     }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_list_agreements(pool: PgPool) {
+        let store = Arc::new(PsqlAgreementStore { pool });
+        let payer = Address::from_str("1234567890123456789012345678901234567890").unwrap();
+        let other_payer = Address::from_str("4567890123456789012345678901234567890123").unwrap();
+
+        let metadata = SubgraphIndexingVoucherMetadata {
+            protocolNetwork: "eip155:42161".to_string(),
+            chainId: "eip155:1".to_string(),
+            basePricePerEpoch: U256::from(5000),
+            pricePerEntity: U256::from(10),
+            subgraphDeploymentId: "Qm123".to_string(),
+        };
+        let ids = [
+            Uuid::parse_str("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7f1").unwrap(),
+            Uuid::parse_str("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7f2").unwrap(),
+            Uuid::parse_str("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7f3").unwrap(),
+        ];
+        for (i, (id, payer)) in ids.iter().zip([payer, other_payer, payer]).enumerate() {
+            let agreement = SignedIndexingAgreementVoucher {
+                // signatures are unique
+                signature: vec![1, 2, i as u8].into(),
+                voucher: IndexingAgreementVoucher {
+                    agreement_id: id.as_bytes().into(),
+                    deadline: (Utc::now() + Duration::days(30)).timestamp() as u64,
+                    payer,
+                    recipient: Address::from_str("2345678901234567890123456789012345678901")
+                        .unwrap(),
+                    service: Address::from_str("3456789012345678901234567890123456789012").unwrap(),
+                    durationEpochs: 30,
+                    maxInitialAmount: U256::from(1000),
+                    maxOngoingAmountPerEpoch: U256::from(100),
+                    maxEpochsPerCollection: 5,
+                    minEpochsPerCollection: 1,
+                    metadata: metadata.abi_encode().into(),
+                },
+            };
+            store
+                .create_agreement(agreement, metadata.clone(), None)
+                .await
+                .unwrap();
+        }
+
+        let agreement_ids = |agreements: Vec<StoredIndexingAgreement>| {
+            agreements
+                .into_iter()
+                .map(|agreement| Uuid::from_bytes(agreement.voucher.voucher.agreement_id.into()))
+                .collect::<Vec<_>>()
+        };
+        let all = store.list_agreements(None).await.unwrap();
+        assert_eq!(agreement_ids(all), ids.to_vec());
+
+        let of_payer = store.list_agreements(Some(payer)).await.unwrap();
+        assert_eq!(agreement_ids(of_payer), vec![ids[0], ids[2]]);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_amend_agreement(pool: PgPool) {
+        let store = Arc::new(PsqlAgreementStore { pool });
+        let id = Uuid::parse_str("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7e1").unwrap();
+
+        let metadata = SubgraphIndexingVoucherMetadata {
+            protocolNetwork: "eip155:42161".to_string(),
+            chainId: "eip155:1".to_string(),
+            basePricePerEpoch: U256::from(5000),
+            pricePerEntity: U256::from(10),
+            subgraphDeploymentId: "Qm123".to_string(),
+        };
+        let agreement = SignedIndexingAgreementVoucher {
+            signature: vec![1, 2, 3].into(),
+            voucher: IndexingAgreementVoucher {
+                agreement_id: id.as_bytes().into(),
+                deadline: (Utc::now() + Duration::days(30)).timestamp() as u64,
+                payer: Address::from_str("1234567890123456789012345678901234567890").unwrap(),
+                recipient: Address::from_str("2345678901234567890123456789012345678901").unwrap(),
+                service: Address::from_str("3456789012345678901234567890123456789012").unwrap(),
+                durationEpochs: 30,
+                maxInitialAmount: U256::from(1000),
+                maxOngoingAmountPerEpoch: U256::from(100),
+                maxEpochsPerCollection: 5,
+                minEpochsPerCollection: 1,
+                metadata: metadata.abi_encode().into(),
+            },
+        };
+        store
+            .create_agreement(agreement.clone(), metadata.clone(), None)
+            .await
+            .unwrap();
+
+        store.set_unprofitable(id, true).await.unwrap();
+        let stored = store.get_by_id(id).await.unwrap().unwrap();
+        assert!(stored.unprofitable);
+
+        let escalation = PriceEscalation {
+            periodSeconds: 3600,
+            basisPointsPerPeriod: 100,
+        };
+        let amended_metadata = SubgraphIndexingVoucherMetadata {
+            pricePerEntity: U256::from(20),
+            ..metadata
+        };
+        let amended = SignedIndexingAgreementVoucher {
+            signature: vec![4, 5, 6].into(),
+            voucher: IndexingAgreementVoucher {
+                metadata: EscalatingSubgraphIndexingVoucherMetadata {
+                    terms: amended_metadata.clone(),
+                    escalation: escalation.clone(),
+                }
+                .abi_encode()
+                .into(),
+                ..agreement.voucher
+            },
+        };
+        store
+            .amend_agreement(amended.clone(), amended_metadata, Some(escalation.clone()))
+            .await
+            .unwrap();
+
+        let stored = store.get_by_id(id).await.unwrap().unwrap();
+        assert_eq!(stored.voucher, amended);
+        assert_eq!(stored.metadata.pricePerEntity, U256::from(20));
+        assert_eq!(stored.escalation, Some(escalation));
+        assert!(!stored.unprofitable);
+    }
 }
@@ -0,0 +1,188 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Price escalation of the agreements and detection of the agreements that are
+//! no longer profitable
+//!
+//! The prices of the vouchers proposed with version 2 increase at the end of
+//! each escalation period. Agreements whose current price is below the minimum
+//! price of their chain are flagged as unprofitable, so the gateway can see them
+//! and propose an amendment with new terms.
+
+use std::time::Duration;
+
+use build_info::chrono::{DateTime, Utc};
+use thegraph_core::alloy::primitives::U256;
+use uuid::Uuid;
+
+use crate::{
+    price::PriceCalculator,
+    store::{AgreementStore, StoredIndexingAgreement},
+    DipsError, PriceEscalation,
+};
+
+const BASIS_POINTS: u64 = 10_000;
+
+impl PriceEscalation {
+    /// `price` after the periods elapsed since the terms were accepted
+    pub fn escalate(&self, price: U256, elapsed: Duration) -> U256 {
+        if self.periodSeconds == 0 {
+            return price;
+        }
+        let periods = U256::from(elapsed.as_secs() / self.periodSeconds);
+        let increase = price
+            .saturating_mul(U256::from(self.basisPointsPerPeriod))
+            .saturating_mul(periods)
+            / U256::from(BASIS_POINTS);
+        price.saturating_add(increase)
+    }
+}
+
+impl StoredIndexingAgreement {
+    /// Price per entity of the agreement at `now`
+    pub fn current_price_per_entity(&self, now: DateTime<Utc>) -> U256 {
+        let price = self.metadata.pricePerEntity;
+        match &self.escalation {
+            Some(escalation) => {
+                let elapsed = (now - self.accepted_at).to_std().unwrap_or_default();
+                escalation.escalate(price, elapsed)
+            }
+            None => price,
+        }
+    }
+
+    /// The current price is below the minimum price of the chain, or the
+    /// chain is no longer supported
+    ///
+    /// Never unprofitable if no minimum price is configured at all, the
+    /// profitability can't be told.
+    pub fn is_unprofitable(&self, price_calculator: &PriceCalculator, now: DateTime<Utc>) -> bool {
+        if price_calculator.is_empty() {
+            return false;
+        }
+        match price_calculator.get_minimum_price(&self.metadata.chainId) {
+            Some(minimum_price) => self.current_price_per_entity(now) < U256::from(minimum_price),
+            None => true,
+        }
+    }
+}
+
+/// Updates the unprofitable flag of the agreements that are not cancelled,
+/// returns the agreements that became unprofitable
+pub async fn flag_unprofitable_agreements(
+    store: &dyn AgreementStore,
+    price_calculator: &PriceCalculator,
+    now: DateTime<Utc>,
+) -> Result<Vec<Uuid>, DipsError> {
+    let mut flagged = Vec::new();
+    for agreement in store.list_agreements(None).await? {
+        if agreement.cancelled {
+            continue;
+        }
+        let unprofitable = agreement.is_unprofitable(price_calculator, now);
+        if unprofitable == agreement.unprofitable {
+            continue;
+        }
+        let id = Uuid::from_bytes(agreement.voucher.voucher.agreement_id.into());
+        store.set_unprofitable(id, unprofitable).await?;
+        if unprofitable {
+            flagged.push(id);
+        }
+    }
+    Ok(flagged)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use build_info::chrono::{self, Utc};
+    use thegraph_core::alloy::primitives::U256;
+    use uuid::Uuid;
+
+    use super::flag_unprofitable_agreements;
+    use crate::{
+        price::PriceCalculator,
+        store::{AgreementStore, InMemoryAgreementStore},
+        test_agreement, PriceEscalation,
+    };
+
+    const DEPLOYMENT: &str = "Qmbg1qF4YgHjiVfsVt6a13ddrVcRtWyJQfD4LA3CwHM29f";
+    const DEADLINE: u64 = 10000000;
+
+    fn escalation() -> PriceEscalation {
+        PriceEscalation {
+            periodSeconds: 3600,
+            basisPointsPerPeriod: 500,
+        }
+    }
+
+    #[test]
+    fn test_escalate() {
+        let price = U256::from(100);
+        assert_eq!(
+            escalation().escalate(price, Duration::from_secs(3599)),
+            price
+        );
+        assert_eq!(
+            escalation().escalate(price, Duration::from_secs(3600)),
+            U256::from(105)
+        );
+        assert_eq!(
+            escalation().escalate(price, Duration::from_secs(4 * 3600)),
+            U256::from(120)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flag_unprofitable_agreements() {
+        let store = InMemoryAgreementStore::default();
+        // minimum price of 100
+        let price_calculator = PriceCalculator::for_testing();
+
+        let mut ids = Vec::new();
+        for (price, escalation) in [(90_u64, None), (100, None), (90, Some(escalation()))] {
+            let id = Uuid::now_v7();
+            let (voucher, metadata) = test_agreement(id, price, DEPLOYMENT, DEADLINE);
+            store
+                .create_agreement(voucher, metadata, escalation)
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+
+        let flagged = flag_unprofitable_agreements(&store, &price_calculator, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(flagged.len(), 2);
+        assert!(flagged.contains(&ids[0]) && flagged.contains(&ids[2]));
+        assert!(store.get_by_id(ids[0]).await.unwrap().unwrap().unprofitable);
+
+        // three periods later, the escalated price is above the minimum
+        let later = Utc::now() + chrono::Duration::hours(3);
+        let flagged = flag_unprofitable_agreements(&store, &price_calculator, later)
+            .await
+            .unwrap();
+        assert!(flagged.is_empty());
+        assert!(store.get_by_id(ids[0]).await.unwrap().unwrap().unprofitable);
+        assert!(!store.get_by_id(ids[2]).await.unwrap().unwrap().unprofitable);
+    }
+
+    #[tokio::test]
+    async fn test_no_minimum_price() {
+        let store = InMemoryAgreementStore::default();
+        let id = Uuid::now_v7();
+        let (voucher, metadata) = test_agreement(id, 1, DEPLOYMENT, DEADLINE);
+        store
+            .create_agreement(voucher, metadata, None)
+            .await
+            .unwrap();
+
+        // the default calculator has no price, nothing is flagged
+        let flagged = flag_unprofitable_agreements(&store, &PriceCalculator::default(), Utc::now())
+            .await
+            .unwrap();
+        assert!(flagged.is_empty());
+        assert!(!store.get_by_id(id).await.unwrap().unwrap().unprofitable);
+    }
+}
@@ -0,0 +1,576 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Indexing of the deployments of the accepted agreements
+//!
+//! Accepting an agreement doesn't index anything by itself. The
+//! [IndexingTracker] hands the deployment of each new agreement to an
+//! [IndexingScheduler], either the indexing rules of indexer-agent or the admin
+//! API of graph-node, then follows its sync in the status API of graph-node
+//! until it is synced. Deployments still syncing after the deadline of their
+//! voucher are reported as late.
+//!
+//! Cancelled agreements are skipped, the indexing of their deployment is left
+//! to the operator.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use build_info::chrono::{DateTime, Utc};
+use graphql_client::GraphQLQuery;
+use indexer_query::sync_progress_query::{self, SyncProgressQuery};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use reqwest::Url;
+use serde_json::json;
+use thegraph_core::DeploymentId;
+use uuid::Uuid;
+
+use crate::store::{AgreementStore, StoredIndexingAgreement};
+
+lazy_static! {
+    static ref SYNC_PROGRESS: GaugeVec = register_gauge_vec!(
+        "indexer_dips_agreement_sync_progress",
+        "Ratio of the chain head block the deployment of an agreement indexed, until it is synced",
+        &["agreement", "deployment"]
+    )
+    .unwrap();
+    static ref SYNC_LATE: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_dips_agreement_sync_late",
+        "Whether the deployment of an agreement is still syncing after the deadline of its voucher",
+        &["agreement", "deployment"]
+    )
+    .unwrap();
+}
+
+/// Timeout of the requests to graph-node
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Starts indexing the deployments of the agreements
+#[async_trait]
+pub trait IndexingScheduler: Send + Sync + fmt::Debug {
+    /// Starts indexing `deployment` for an agreement on `protocol_network`.
+    /// Starting a deployment already indexed must succeed.
+    async fn start_indexing(
+        &self,
+        deployment: DeploymentId,
+        protocol_network: &str,
+    ) -> anyhow::Result<()>;
+}
+
+/// Sets an `always` indexing rule for the deployment, indexer-agent then
+/// deploys it on graph-node and allocates to it
+///
+/// The rule of the deployment is only created if it doesn't exist, an
+/// existing rule was set by the operator and is left untouched.
+#[cfg(feature = "db")]
+#[derive(Debug)]
+pub struct IndexingRulesScheduler {
+    pub pool: sqlx::PgPool,
+}
+
+#[cfg(feature = "db")]
+#[async_trait]
+impl IndexingScheduler for IndexingRulesScheduler {
+    async fn start_indexing(
+        &self,
+        deployment: DeploymentId,
+        protocol_network: &str,
+    ) -> anyhow::Result<()> {
+        // indexer-agent identifies the deployments by their hex id
+        let identifier = format!("{deployment:#x}");
+        // the table is created by indexer-agent, not by the migrations
+        sqlx::query(
+            r#"
+                INSERT INTO "IndexingRules" (
+                    identifier,
+                    "identifierType",
+                    "decisionBasis",
+                    "protocolNetwork",
+                    "createdAt",
+                    "updatedAt"
+                )
+                VALUES ($1, 'deployment', 'always', $2, NOW(), NOW())
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&identifier)
+        .bind(protocol_network)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Deploys the deployment on graph-node with its admin API, under the name
+/// `dips/<deployment>`
+#[derive(Debug)]
+pub struct GraphNodeScheduler {
+    admin_url: Url,
+    client: reqwest::Client,
+}
+
+impl GraphNodeScheduler {
+    pub fn new(admin_url: Url) -> Self {
+        Self {
+            admin_url,
+            client: http_client(),
+        }
+    }
+
+    async fn call(&self, method: &str, params: serde_json::Value) -> anyhow::Result<()> {
+        let response: serde_json::Value = self
+            .client
+            .post(self.admin_url.clone())
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match response.get("error") {
+            Some(error) => Err(anyhow!(
+                "{method} failed: {}",
+                error["message"].as_str().unwrap_or("unknown error")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl IndexingScheduler for GraphNodeScheduler {
+    async fn start_indexing(&self, deployment: DeploymentId, _: &str) -> anyhow::Result<()> {
+        let name = format!("dips/{deployment}");
+        // fails if the name already exists, graph-node doesn't tell it apart
+        // from the other errors but deploying to it still succeeds then
+        let created = self.call("subgraph_create", json!({ "name": name })).await;
+        let deployed = self
+            .call(
+                "subgraph_deploy",
+                json!({ "name": name, "ipfs_hash": deployment.to_string() }),
+            )
+            .await;
+        match (deployed, created) {
+            (Ok(()), _) => Ok(()),
+            (Err(error), Ok(())) => Err(error),
+            (Err(error), Err(create_error)) => Err(error.context(create_error)),
+        }
+    }
+}
+
+/// Sync of the deployment of an agreement, as reported by graph-node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncStatus {
+    pub synced: bool,
+    /// ratio of the chain head block indexed, between 0 and 1
+    pub progress: f64,
+}
+
+/// Progress of the deployment of an agreement not synced yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgreementSync {
+    pub agreement_id: Uuid,
+    pub deployment: DeploymentId,
+    pub status: SyncStatus,
+    /// still syncing after the deadline of the voucher
+    pub late: bool,
+}
+
+/// Starts the indexing of the new agreements and follows the sync of their
+/// deployments
+#[derive(Debug)]
+pub struct IndexingTracker {
+    store: Arc<dyn AgreementStore>,
+    scheduler: Arc<dyn IndexingScheduler>,
+    status_url: Url,
+    client: reqwest::Client,
+    /// agreements and deployments the sync metrics are exported for
+    exported: Mutex<HashSet<(Uuid, DeploymentId)>>,
+}
+
+impl IndexingTracker {
+    pub fn new(
+        store: Arc<dyn AgreementStore>,
+        scheduler: Arc<dyn IndexingScheduler>,
+        status_url: Url,
+    ) -> Self {
+        Self {
+            store,
+            scheduler,
+            status_url,
+            client: http_client(),
+            exported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Checks the agreements every `interval`
+    pub async fn run(self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.check(Utc::now()).await {
+                tracing::error!(%error, "Failed to check the indexing of the DIPS agreements");
+            }
+        }
+    }
+
+    /// Starts the indexing of the agreements that are not indexed yet, then
+    /// returns the progress of the ones still syncing at `now`
+    pub async fn check(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<AgreementSync>> {
+        let mut syncing = Vec::new();
+        for agreement in self.store.list_agreements(None).await? {
+            if agreement.cancelled || agreement.synced_at.is_some() {
+                continue;
+            }
+            let id = Uuid::from_bytes(agreement.voucher.voucher.agreement_id.into());
+            let deployment = match DeploymentId::from_str(&agreement.metadata.subgraphDeploymentId)
+            {
+                Ok(deployment) => deployment,
+                Err(error) => {
+                    tracing::warn!(
+                        agreement_id = %id,
+                        %error,
+                        "Invalid deployment id, the agreement is not indexed"
+                    );
+                    continue;
+                }
+            };
+            if agreement.indexing_started_at.is_none() {
+                if let Err(error) = self
+                    .scheduler
+                    .start_indexing(deployment, &agreement.metadata.protocolNetwork)
+                    .await
+                {
+                    tracing::error!(
+                        agreement_id = %id,
+                        %deployment,
+                        %error,
+                        "Failed to start indexing the deployment of the agreement"
+                    );
+                    continue;
+                }
+                self.store.set_indexing_started(id).await?;
+                tracing::info!(agreement_id = %id, %deployment, "Started indexing the deployment of the agreement");
+            }
+            syncing.push((id, deployment, agreement));
+        }
+        if syncing.is_empty() {
+            self.remove_metrics(HashSet::new());
+            return Ok(Vec::new());
+        }
+
+        let statuses = self
+            .sync_statuses(syncing.iter().map(|(_, deployment, _)| *deployment))
+            .await?;
+        let mut progress = Vec::new();
+        for (id, deployment, agreement) in syncing {
+            let (agreement_label, deployment_label) = (id.to_string(), deployment.to_string());
+            let labels = [agreement_label.as_str(), deployment_label.as_str()];
+            // not known by graph-node until indexer-agent deployed it
+            let status = statuses.get(&deployment).copied().unwrap_or(SyncStatus {
+                synced: false,
+                progress: 0.0,
+            });
+            if status.synced {
+                self.store.set_synced(id).await?;
+                tracing::info!(agreement_id = %id, %deployment, "The deployment of the agreement is synced");
+                continue;
+            }
+
+            let late = is_late(&agreement, now);
+            if late {
+                tracing::warn!(
+                    agreement_id = %id,
+                    %deployment,
+                    progress = status.progress,
+                    "The deployment of the agreement is still syncing after its deadline"
+                );
+            }
+            SYNC_PROGRESS
+                .with_label_values(&labels)
+                .set(status.progress);
+            SYNC_LATE.with_label_values(&labels).set(late.into());
+            progress.push(AgreementSync {
+                agreement_id: id,
+                deployment,
+                status,
+                late,
+            });
+        }
+        self.remove_metrics(
+            progress
+                .iter()
+                .map(|sync| (sync.agreement_id, sync.deployment))
+                .collect(),
+        );
+        Ok(progress)
+    }
+
+    /// Removes the sync metrics of the agreements no longer syncing, they
+    /// were synced or cancelled
+    fn remove_metrics(&self, syncing: HashSet<(Uuid, DeploymentId)>) {
+        let mut exported = self.exported.lock().unwrap();
+        for (id, deployment) in exported.difference(&syncing) {
+            let (agreement_label, deployment_label) = (id.to_string(), deployment.to_string());
+            let labels = [agreement_label.as_str(), deployment_label.as_str()];
+            let _ = SYNC_PROGRESS.remove_label_values(&labels);
+            let _ = SYNC_LATE.remove_label_values(&labels);
+        }
+        *exported = syncing;
+    }
+
+    async fn sync_statuses(
+        &self,
+        deployments: impl Iterator<Item = DeploymentId>,
+    ) -> anyhow::Result<HashMap<DeploymentId, SyncStatus>> {
+        let query = SyncProgressQuery::build_query(sync_progress_query::Variables {
+            ids: deployments
+                .map(|deployment| deployment.to_string())
+                .collect(),
+        });
+        let response: graphql_client::Response<sync_progress_query::ResponseData> = self
+            .client
+            .post(self.status_url.clone())
+            .json(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let data = response
+            .data
+            .context("Failed to query the sync of the agreement deployments")?;
+        Ok(data
+            .indexing_statuses
+            .into_iter()
+            .filter_map(|status| {
+                let deployment = DeploymentId::from_str(&status.subgraph).ok()?;
+                let progress = status
+                    .chains
+                    .iter()
+                    .map(|chain| {
+                        let latest = chain
+                            .latest_block
+                            .as_ref()
+                            .and_then(|block| block.number.parse().ok());
+                        let chain_head = chain
+                            .chain_head_block
+                            .as_ref()
+                            .and_then(|block| block.number.parse().ok());
+                        sync_progress(latest, chain_head)
+                    })
+                    // the least advanced chain
+                    .reduce(f64::min)
+                    .unwrap_or_default();
+                Some((
+                    deployment,
+                    SyncStatus {
+                        synced: status.synced,
+                        progress,
+                    },
+                ))
+            })
+            .collect())
+    }
+}
+
+fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Failed to build the HTTP client")
+}
+
+/// The deadline of the voucher passed
+fn is_late(agreement: &StoredIndexingAgreement, now: DateTime<Utc>) -> bool {
+    let deadline = i64::try_from(agreement.voucher.voucher.deadline).unwrap_or(i64::MAX);
+    now.timestamp() > deadline
+}
+
+/// Ratio of `chain_head` that `latest` reached, 0 if either is unknown
+fn sync_progress(latest: Option<u64>, chain_head: Option<u64>) -> f64 {
+    match (latest, chain_head) {
+        (Some(latest), Some(chain_head)) if chain_head > 0 => {
+            (latest as f64 / chain_head as f64).min(1.0)
+        }
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+    use build_info::chrono::{DateTime, Utc};
+    use serde_json::json;
+    use thegraph_core::DeploymentId;
+    use uuid::Uuid;
+    use wiremock::{
+        matchers::{body_partial_json, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{GraphNodeScheduler, IndexingScheduler, IndexingTracker};
+    use crate::{
+        store::{AgreementStore, InMemoryAgreementStore},
+        test_agreement,
+    };
+
+    const DEPLOYMENT: &str = "Qmbg1qF4YgHjiVfsVt6a13ddrVcRtWyJQfD4LA3CwHM29f";
+    const DEADLINE: u64 = 1_700_000_000;
+
+    #[derive(Debug, Default)]
+    struct RecordingScheduler {
+        started: Mutex<Vec<DeploymentId>>,
+    }
+
+    #[async_trait]
+    impl IndexingScheduler for RecordingScheduler {
+        async fn start_indexing(&self, deployment: DeploymentId, _: &str) -> anyhow::Result<()> {
+            self.started.lock().unwrap().push(deployment);
+            Ok(())
+        }
+    }
+
+    async fn create_agreement(store: &InMemoryAgreementStore) -> Uuid {
+        let id = Uuid::now_v7();
+        let (voucher, metadata) = test_agreement(id, 100, DEPLOYMENT, DEADLINE);
+        store
+            .create_agreement(voucher, metadata, None)
+            .await
+            .unwrap();
+        id
+    }
+
+    fn status(synced: bool, latest: u64) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "data": {
+                "indexingStatuses": [{
+                    "subgraph": DEPLOYMENT,
+                    "synced": synced,
+                    "health": "healthy",
+                    "chains": [{
+                        "latestBlock": { "number": latest.to_string() },
+                        "chainHeadBlock": { "number": "1000" },
+                    }],
+                }],
+            },
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_indexing_tracker() {
+        let graph_node = MockServer::start().await;
+        let store = Arc::new(InMemoryAgreementStore::default());
+        let scheduler = Arc::new(RecordingScheduler::default());
+        let tracker = IndexingTracker::new(
+            store.clone(),
+            scheduler.clone(),
+            graph_node.uri().parse().unwrap(),
+        );
+        let id = create_agreement(&store).await;
+        let before_deadline = DateTime::from_timestamp(DEADLINE as i64 - 1, 0).unwrap();
+        let after_deadline = DateTime::from_timestamp(DEADLINE as i64 + 1, 0).unwrap();
+
+        // started once, then followed until synced
+        let syncing_status = Mock::given(method("POST"))
+            .respond_with(status(false, 250))
+            .mount_as_scoped(&graph_node)
+            .await;
+        let syncing = tracker.check(before_deadline).await.unwrap();
+        assert_eq!(
+            *scheduler.started.lock().unwrap(),
+            vec![DeploymentId::from_str(DEPLOYMENT).unwrap()]
+        );
+        assert!(store
+            .get_by_id(id)
+            .await
+            .unwrap()
+            .unwrap()
+            .indexing_started_at
+            .is_some());
+        assert_eq!(syncing.len(), 1);
+        assert_eq!(syncing[0].agreement_id, id);
+        assert_eq!(syncing[0].status.progress, 0.25);
+        assert!(!syncing[0].late);
+
+        let syncing = tracker.check(after_deadline).await.unwrap();
+        assert_eq!(scheduler.started.lock().unwrap().len(), 1);
+        assert!(syncing[0].late);
+        drop(syncing_status);
+
+        Mock::given(method("POST"))
+            .respond_with(status(true, 1000))
+            .mount(&graph_node)
+            .await;
+        let syncing = tracker.check(Utc::now()).await.unwrap();
+        assert!(syncing.is_empty());
+        assert!(store
+            .get_by_id(id)
+            .await
+            .unwrap()
+            .unwrap()
+            .synced_at
+            .is_some());
+        // the metrics of the synced agreement were removed
+        let labels = [id.to_string(), DEPLOYMENT.to_string()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+        assert!(super::SYNC_PROGRESS.remove_label_values(&labels).is_err());
+        assert!(super::SYNC_LATE.remove_label_values(&labels).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_graph_node_scheduler_existing_name() {
+        let graph_node = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "subgraph_create" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": { "code": 2, "message": "subgraph name already exists" },
+            })))
+            .mount(&graph_node)
+            .await;
+        let deploy = Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "method": "subgraph_deploy" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {},
+            })))
+            .mount_as_scoped(&graph_node)
+            .await;
+        let scheduler = GraphNodeScheduler::new(graph_node.uri().parse().unwrap());
+        let deployment = DeploymentId::from_str(DEPLOYMENT).unwrap();
+
+        scheduler
+            .start_indexing(deployment, "eip155:42161")
+            .await
+            .unwrap();
+
+        // the creation error is reported if the deployment fails too
+        drop(deploy);
+        let error = scheduler
+            .start_indexing(deployment, "eip155:42161")
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("subgraph name already exists"));
+    }
+}
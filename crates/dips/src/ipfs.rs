@@ -1,13 +1,10 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use async_trait::async_trait;
-use derivative::Derivative;
-use futures::TryStreamExt;
-use http::Uri;
-use ipfs_api_prelude::{IpfsApi, TryFromUri};
+pub use indexer_monitor::IpfsClient;
 use serde::Deserialize;
 
 use crate::DipsError;
@@ -24,33 +21,13 @@ impl<T: IpfsFetcher> IpfsFetcher for Arc<T> {
     }
 }
 
-#[derive(Derivative)]
-#[derivative(Debug)]
-pub struct IpfsClient {
-    #[derivative(Debug = "ignore")]
-    client: ipfs_api_backend_hyper::IpfsClient,
-}
-
-impl IpfsClient {
-    pub fn new(url: &str) -> anyhow::Result<Self> {
-        Ok(Self {
-            client: ipfs_api_backend_hyper::IpfsClient::build_with_base_uri(
-                Uri::from_str(url).map_err(anyhow::Error::from)?,
-            ),
-        })
-    }
-}
-
 #[async_trait]
 impl IpfsFetcher for IpfsClient {
     async fn fetch(&self, file: &str) -> Result<GraphManifest, DipsError> {
-        let content = self
-            .client
-            .get(file.as_ref())
-            .map_ok(|chunk| chunk.to_vec())
-            .try_concat()
-            .await
-            .unwrap();
+        let content = self.cat(file).await.map_err(|error| {
+            tracing::warn!(file, "{error:#}");
+            DipsError::InvalidSubgraphManifest(file.to_string())
+        })?;
 
         let manifest: GraphManifest = serde_yaml::from_slice(&content)
             .map_err(|_| DipsError::InvalidSubgraphManifest(file.to_string()))?;
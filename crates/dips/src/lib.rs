@@ -1,7 +1,7 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use server::DipsServerContext;
 use thegraph_core::alloy::{
@@ -14,6 +14,8 @@ use thegraph_core::alloy::{
 
 #[cfg(feature = "db")]
 pub mod database;
+pub mod escalation;
+pub mod indexing;
 pub mod ipfs;
 pub mod price;
 #[cfg(feature = "rpc")]
@@ -28,40 +30,49 @@ use thiserror::Error;
 use uuid::Uuid;
 
 /// The Arbitrum One (mainnet) chain ID (eip155).
+#[cfg(test)]
 const CHAIN_ID_ARBITRUM_ONE: ChainId = 0xa4b1; // 42161
 
+/// Version of the proposals whose voucher metadata has price escalation terms
+pub const ESCALATING_VOUCHER_VERSION: u64 = 2;
+
 /// DIPs EIP-712 domain salt
 const EIP712_DOMAIN_SALT: B256 =
     b256!("b4632c657c26dce5d4d7da1d65bda185b14ff8f905ddbb03ea0382ed06c5ef28");
 
-/// Create an EIP-712 domain given a chain ID and dispute manager address.
-pub fn dips_agreement_eip712_domain() -> Eip712Domain {
+/// Create the EIP-712 domain of the agreement vouchers given a chain ID.
+pub fn dips_agreement_eip712_domain(chain_id: ChainId) -> Eip712Domain {
     eip712_domain! {
         name: "Graph Protocol Indexing Agreement",
         version: "0",
-        chain_id: CHAIN_ID_ARBITRUM_ONE,
+        chain_id: chain_id,
         salt: EIP712_DOMAIN_SALT,
     }
 }
 
-pub fn dips_cancellation_eip712_domain() -> Eip712Domain {
+pub fn dips_cancellation_eip712_domain(chain_id: ChainId) -> Eip712Domain {
     eip712_domain! {
         name: "Graph Protocol Indexing Agreement Cancellation",
         version: "0",
-        chain_id: CHAIN_ID_ARBITRUM_ONE,
+        chain_id: chain_id,
         salt: EIP712_DOMAIN_SALT,
     }
 }
 
-pub fn dips_collection_eip712_domain() -> Eip712Domain {
+pub fn dips_collection_eip712_domain(chain_id: ChainId) -> Eip712Domain {
     eip712_domain! {
         name: "Graph Protocol Indexing Agreement Collection",
         version: "0",
-        chain_id: CHAIN_ID_ARBITRUM_ONE,
+        chain_id: chain_id,
         salt: EIP712_DOMAIN_SALT,
     }
 }
 
+/// CAIP-2 id of the protocol network the messages signed for `domain` are valid on
+fn protocol_network(domain: &Eip712Domain) -> String {
+    format!("eip155:{}", domain.chain_id.unwrap_or_default())
+}
+
 sol! {
     // EIP712 encoded bytes
     #[derive(Debug, PartialEq)]
@@ -104,6 +115,19 @@ sol! {
         string chainId; // indexed chain, e.g. "eip155:1"
     }
 
+    // metadata of the vouchers proposed with version 2, whose prices increase over time
+    #[derive(Debug, PartialEq)]
+    struct EscalatingSubgraphIndexingVoucherMetadata {
+        SubgraphIndexingVoucherMetadata terms;
+        PriceEscalation escalation;
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PriceEscalation {
+        uint64 periodSeconds; // the prices increase at the end of each period since the terms are accepted
+        uint32 basisPointsPerPeriod; // of the initial prices
+    }
+
     #[derive(Debug, PartialEq)]
     struct SignedCancellationRequest {
         CancellationRequest request;
@@ -147,6 +171,18 @@ pub enum DipsError {
     UnsupportedChainId(String),
     #[error("price per block is below configured price for chain {0}, minimum: {1}, offered: {2}")]
     PricePerBlockTooLow(String, u64, String),
+    #[error("voucher for protocol network {actual}, expected {expected}")]
+    UnexpectedProtocolNetwork { expected: String, actual: String },
+    #[error("version {0} is not supported")]
+    UnsupportedVersion(u64),
+    // amendment
+    #[error("amendment changes {0}, only the terms can be amended")]
+    AmendmentMismatch(&'static str),
+    #[error("amendment deadline {deadline} is not after the deadline {accepted_deadline} of the accepted voucher")]
+    AmendmentNotNewer {
+        accepted_deadline: u64,
+        deadline: u64,
+    },
     // cancellation
     #[error("cancelled_by is expected to match the signer")]
     UnexpectedSigner,
@@ -167,11 +203,43 @@ pub enum DipsError {
     InvalidVoucher(String),
 }
 
-// TODO: send back messages
 #[cfg(feature = "rpc")]
 impl From<DipsError> for tonic::Status {
-    fn from(_val: DipsError) -> Self {
-        tonic::Status::internal("unknown errr")
+    fn from(error: DipsError) -> Self {
+        let message = error.to_string();
+        match error {
+            DipsError::InvalidSignature(_)
+            | DipsError::UnexpectedPayee { .. }
+            | DipsError::InvalidSubgraphManifest(_)
+            | DipsError::SubgraphChainIdMistmatch(_, _)
+            | DipsError::UnexpectedProtocolNetwork { .. }
+            | DipsError::UnsupportedVersion(_)
+            | DipsError::AmendmentMismatch(_)
+            | DipsError::AbiDecoding(_)
+            | DipsError::InvalidVoucher(_) => tonic::Status::invalid_argument(message),
+            DipsError::PayerNotAuthorised(_)
+            | DipsError::UnexpectedSigner
+            | DipsError::SignerNotAuthorised(_) => tonic::Status::permission_denied(message),
+            DipsError::UnsupportedChainId(_)
+            | DipsError::PricePerBlockTooLow(_, _, _)
+            | DipsError::ExpiredRequest
+            | DipsError::AmendmentNotNewer { .. }
+            | DipsError::AgreementCancelled => tonic::Status::failed_precondition(message),
+            DipsError::AgreementNotFound => tonic::Status::not_found(message),
+            DipsError::UnknownError(_) => tonic::Status::internal(message),
+        }
+    }
+}
+
+impl DipsError {
+    /// The proposal is valid, but its terms are not accepted by the indexer
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            DipsError::UnsupportedChainId(_)
+                | DipsError::PricePerBlockTooLow(_, _, _)
+                | DipsError::AmendmentNotNewer { .. }
+        )
     }
 }
 
@@ -191,7 +259,9 @@ impl IndexingAgreementVoucher {
 }
 
 impl SignedIndexingAgreementVoucher {
-    // TODO: Validate all values
+    /// Checks the voucher is signed for `domain` by the payer or one of its
+    /// authorized signers, that the payer is allowed and that the indexer is the
+    /// recipient
     pub fn validate(
         &self,
         signer_validator: &Arc<dyn signers::SignerValidator>,
@@ -199,7 +269,7 @@ impl SignedIndexingAgreementVoucher {
         expected_payee: &Address,
         allowed_payers: impl AsRef<[Address]>,
     ) -> Result<(), DipsError> {
-        let sig = Signature::from_str(&self.signature.to_string())
+        let sig = Signature::try_from(self.signature.as_ref())
             .map_err(|err| DipsError::InvalidSignature(err.to_string()))?;
 
         let payer = self.voucher.payer;
@@ -248,13 +318,13 @@ impl CancellationRequest {
 }
 
 impl SignedCancellationRequest {
-    // TODO: Validate all values
+    /// Checks the cancellation is signed for `domain` by `expected_signer`
     pub fn validate(
         &self,
         domain: &Eip712Domain,
         expected_signer: &Address,
     ) -> Result<(), DipsError> {
-        let sig = Signature::from_str(&self.signature.to_string())
+        let sig = Signature::try_from(self.signature.as_ref())
             .map_err(|err| DipsError::InvalidSignature(err.to_string()))?;
 
         let signer = sig
@@ -293,29 +363,67 @@ impl CollectionRequest {
     }
 }
 
-pub async fn validate_and_create_agreement(
-    ctx: Arc<DipsServerContext>,
+/// Decodes the voucher metadata of a proposal of `version`
+pub fn decode_voucher_metadata(
+    version: u64,
+    metadata: &[u8],
+) -> Result<(SubgraphIndexingVoucherMetadata, Option<PriceEscalation>), DipsError> {
+    match version {
+        1 => SubgraphIndexingVoucherMetadata::abi_decode(metadata, true)
+            .map(|metadata| (metadata, None)),
+        ESCALATING_VOUCHER_VERSION => {
+            EscalatingSubgraphIndexingVoucherMetadata::abi_decode(metadata, true)
+                .map(|metadata| (metadata.terms, Some(metadata.escalation)))
+        }
+        _ => return Err(DipsError::UnsupportedVersion(version)),
+    }
+    .map_err(|e| DipsError::AbiDecoding(e.to_string()))
+}
+
+/// A voucher that passed the validations, with its decoded metadata
+struct ValidProposal {
+    voucher: SignedIndexingAgreementVoucher,
+    metadata: SubgraphIndexingVoucherMetadata,
+    escalation: Option<PriceEscalation>,
+}
+
+async fn validate_proposal(
+    ctx: &DipsServerContext,
     domain: &Eip712Domain,
     expected_payee: &Address,
     allowed_payers: impl AsRef<[Address]>,
+    version: u64,
     voucher: Vec<u8>,
-) -> Result<Uuid, DipsError> {
+) -> Result<ValidProposal, DipsError> {
     let DipsServerContext {
-        store,
         ipfs_fetcher,
         price_calculator,
         signer_validator,
-    } = ctx.as_ref();
+        ..
+    } = ctx;
     let decoded_voucher = SignedIndexingAgreementVoucher::abi_decode(voucher.as_ref(), true)
         .map_err(|e| DipsError::AbiDecoding(e.to_string()))?;
-    let metadata = SubgraphIndexingVoucherMetadata::abi_decode(
-        decoded_voucher.voucher.metadata.as_ref(),
-        true,
-    )
-    .map_err(|e| DipsError::AbiDecoding(e.to_string()))?;
+    let (metadata, escalation) =
+        decode_voucher_metadata(version, decoded_voucher.voucher.metadata.as_ref())?;
 
     decoded_voucher.validate(signer_validator, domain, expected_payee, allowed_payers)?;
 
+    let expected_network = protocol_network(domain);
+    if metadata.protocolNetwork != expected_network {
+        return Err(DipsError::UnexpectedProtocolNetwork {
+            expected: expected_network,
+            actual: metadata.protocolNetwork,
+        });
+    }
+    if escalation
+        .as_ref()
+        .is_some_and(|escalation| escalation.periodSeconds == 0)
+    {
+        return Err(DipsError::InvalidVoucher(
+            "escalation period must be positive".to_string(),
+        ));
+    }
+
     let manifest = ipfs_fetcher.fetch(&metadata.subgraphDeploymentId).await?;
     match manifest.network() {
         Some(chain_id) if chain_id == metadata.chainId => {}
@@ -345,13 +453,102 @@ pub async fn validate_and_create_agreement(
         None => return Err(DipsError::UnsupportedChainId(chain_id)),
     }
 
-    store
-        .create_agreement(decoded_voucher.clone(), metadata)
+    Ok(ValidProposal {
+        voucher: decoded_voucher,
+        metadata,
+        escalation,
+    })
+}
+
+pub async fn validate_and_create_agreement(
+    ctx: Arc<DipsServerContext>,
+    domain: &Eip712Domain,
+    expected_payee: &Address,
+    allowed_payers: impl AsRef<[Address]>,
+    version: u64,
+    voucher: Vec<u8>,
+) -> Result<Uuid, DipsError> {
+    let ValidProposal {
+        voucher,
+        metadata,
+        escalation,
+    } = validate_proposal(
+        &ctx,
+        domain,
+        expected_payee,
+        allowed_payers,
+        version,
+        voucher,
+    )
+    .await?;
+
+    let id = Uuid::from_bytes(voucher.voucher.agreement_id.into());
+    ctx.store
+        .create_agreement(voucher, metadata, escalation)
+        .await?;
+
+    Ok(id)
+}
+
+/// Replaces the terms of an agreement with the ones of a new voucher, e.g. when
+/// the agreement is no longer profitable
+///
+/// The voucher must be for the same agreement id, payer, service and deployment,
+/// and newer than the accepted one, with a later deadline, so that a voucher
+/// signed earlier can't be replayed to restore older terms.
+pub async fn validate_and_amend_agreement(
+    ctx: Arc<DipsServerContext>,
+    domain: &Eip712Domain,
+    expected_payee: &Address,
+    allowed_payers: impl AsRef<[Address]>,
+    version: u64,
+    voucher: Vec<u8>,
+) -> Result<Uuid, DipsError> {
+    let ValidProposal {
+        voucher,
+        metadata,
+        escalation,
+    } = validate_proposal(
+        &ctx,
+        domain,
+        expected_payee,
+        allowed_payers,
+        version,
+        voucher,
+    )
+    .await?;
+
+    let id = Uuid::from_bytes(voucher.voucher.agreement_id.into());
+    let stored_agreement = ctx
+        .store
+        .get_by_id(id)
+        .await?
+        .ok_or(DipsError::AgreementNotFound)?;
+    if stored_agreement.cancelled {
+        return Err(DipsError::AgreementCancelled);
+    }
+    if stored_agreement.voucher.voucher.payer != voucher.voucher.payer {
+        return Err(DipsError::AmendmentMismatch("payer"));
+    }
+    if stored_agreement.voucher.voucher.service != voucher.voucher.service {
+        return Err(DipsError::AmendmentMismatch("service"));
+    }
+    if stored_agreement.metadata.subgraphDeploymentId != metadata.subgraphDeploymentId {
+        return Err(DipsError::AmendmentMismatch("subgraphDeploymentId"));
+    }
+    let accepted_deadline = stored_agreement.voucher.voucher.deadline;
+    if voucher.voucher.deadline <= accepted_deadline {
+        return Err(DipsError::AmendmentNotNewer {
+            accepted_deadline,
+            deadline: voucher.voucher.deadline,
+        });
+    }
+
+    ctx.store
+        .amend_agreement(voucher, metadata, escalation)
         .await?;
 
-    Ok(Uuid::from_bytes(
-        decoded_voucher.voucher.agreement_id.into(),
-    ))
+    Ok(id)
 }
 
 pub async fn validate_and_cancel_agreement(
@@ -381,6 +578,47 @@ pub async fn validate_and_cancel_agreement(
     Ok(id)
 }
 
+/// Agreement with a dummy signature, between `0x0101..` and `0x0202..`, and
+/// its metadata, for the tests that don't validate the voucher, see
+/// `VoucherContext` for the signed ones
+#[cfg(any(test, feature = "test"))]
+pub fn test_agreement(
+    id: Uuid,
+    price_per_entity: u64,
+    deployment_id: &str,
+    deadline: u64,
+) -> (
+    SignedIndexingAgreementVoucher,
+    SubgraphIndexingVoucherMetadata,
+) {
+    use thegraph_core::alloy::primitives::U256;
+
+    let metadata = SubgraphIndexingVoucherMetadata {
+        basePricePerEpoch: U256::from(10000_u64),
+        pricePerEntity: U256::from(price_per_entity),
+        protocolNetwork: "eip155:42161".to_string(),
+        chainId: "mainnet".to_string(),
+        subgraphDeploymentId: deployment_id.to_string(),
+    };
+    let voucher = SignedIndexingAgreementVoucher {
+        signature: vec![1, 2, 3].into(),
+        voucher: IndexingAgreementVoucher {
+            agreement_id: id.as_bytes().into(),
+            payer: Address::repeat_byte(1),
+            recipient: Address::repeat_byte(2),
+            service: Address::ZERO,
+            durationEpochs: 100,
+            maxInitialAmount: U256::from(1000000_u64),
+            maxOngoingAmountPerEpoch: U256::from(10000_u64),
+            minEpochsPerCollection: 1,
+            maxEpochsPerCollection: 10,
+            deadline,
+            metadata: metadata.abi_encode().into(),
+        },
+    };
+    (voucher, metadata)
+}
+
 #[cfg(test)]
 mod test {
     use std::{
@@ -399,9 +637,15 @@ mod test {
 
     pub use crate::store::{AgreementStore, InMemoryAgreementStore};
     use crate::{
-        dips_agreement_eip712_domain, dips_cancellation_eip712_domain, server::DipsServerContext,
-        CancellationRequest, DipsError, IndexingAgreementVoucher, SignedIndexingAgreementVoucher,
-        SubgraphIndexingVoucherMetadata,
+        dips_agreement_eip712_domain, dips_cancellation_eip712_domain,
+        proto::indexer::graphprotocol::indexer::dips::{
+            indexer_dips_service_server::IndexerDipsService, GetAgreementRequest,
+            ListAgreementsRequest, ProposalResponse, ProposeAmendmentRequest,
+        },
+        server::{DipsServer, DipsServerContext},
+        CancellationRequest, DipsError, EscalatingSubgraphIndexingVoucherMetadata,
+        IndexingAgreementVoucher, PriceEscalation, SignedIndexingAgreementVoucher,
+        SubgraphIndexingVoucherMetadata, CHAIN_ID_ARBITRUM_ONE, ESCALATING_VOUCHER_VERSION,
     };
 
     #[tokio::test]
@@ -433,7 +677,7 @@ mod test {
             deadline: 10000000,
             metadata: metadata.abi_encode().into(),
         };
-        let domain = dips_agreement_eip712_domain();
+        let domain = dips_agreement_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
 
         let voucher = voucher.sign(&domain, payer)?;
         let abi_voucher = voucher.abi_encode();
@@ -445,6 +689,7 @@ mod test {
             &domain,
             &payee_addr,
             vec![payer_addr],
+            1,
             abi_voucher,
         )
         .await
@@ -458,6 +703,151 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_validate_and_amend_agreement() -> anyhow::Result<()> {
+        let payee = PrivateKeySigner::random();
+        let payee_addr = payee.address();
+        let payer = PrivateKeySigner::random();
+        let payer_addr = payer.address();
+
+        let metadata = SubgraphIndexingVoucherMetadata {
+            basePricePerEpoch: U256::from(10000_u64),
+            pricePerEntity: U256::from(100_u64),
+            protocolNetwork: "eip155:42161".to_string(),
+            chainId: "mainnet".to_string(),
+            subgraphDeploymentId: "Qmbg1qF4YgHjiVfsVt6a13ddrVcRtWyJQfD4LA3CwHM29f".to_string(),
+        };
+        let voucher = IndexingAgreementVoucher {
+            agreement_id: Uuid::now_v7().as_bytes().into(),
+            payer: payer_addr,
+            recipient: payee_addr,
+            service: Address(FixedBytes::ZERO),
+            maxInitialAmount: U256::from(10000_u64),
+            maxOngoingAmountPerEpoch: U256::from(10000_u64),
+            maxEpochsPerCollection: 1000,
+            minEpochsPerCollection: 1000,
+            durationEpochs: 1000,
+            deadline: 10000000,
+            metadata: metadata.abi_encode().into(),
+        };
+        let domain = dips_agreement_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
+        let ctx = DipsServerContext::for_testing();
+
+        // the agreement doesn't exist yet
+        let amendment = voucher.sign(&domain, payer.clone())?.abi_encode();
+        let res = super::validate_and_amend_agreement(
+            ctx.clone(),
+            &domain,
+            &payee_addr,
+            vec![payer_addr],
+            1,
+            amendment,
+        )
+        .await;
+        assert!(matches!(res, Err(DipsError::AgreementNotFound)));
+
+        let id = super::validate_and_create_agreement(
+            ctx.clone(),
+            &domain,
+            &payee_addr,
+            vec![payer_addr],
+            1,
+            voucher.sign(&domain, payer.clone())?.abi_encode(),
+        )
+        .await?;
+
+        let escalation = PriceEscalation {
+            periodSeconds: 3600,
+            basisPointsPerPeriod: 100,
+        };
+        let amended = IndexingAgreementVoucher {
+            metadata: EscalatingSubgraphIndexingVoucherMetadata {
+                terms: SubgraphIndexingVoucherMetadata {
+                    pricePerEntity: U256::from(200_u64),
+                    ..metadata.clone()
+                },
+                escalation: escalation.clone(),
+            }
+            .abi_encode()
+            .into(),
+            deadline: voucher.deadline + 1,
+            ..voucher.clone()
+        };
+
+        // the metadata must match the version
+        let res = super::validate_and_amend_agreement(
+            ctx.clone(),
+            &domain,
+            &payee_addr,
+            vec![payer_addr],
+            1,
+            amended.sign(&domain, payer.clone())?.abi_encode(),
+        )
+        .await;
+        assert!(matches!(res, Err(DipsError::AbiDecoding(_))));
+
+        // the deployment can't be changed
+        let other_deployment = IndexingAgreementVoucher {
+            metadata: SubgraphIndexingVoucherMetadata {
+                subgraphDeploymentId: "QmUhiH6Z5xo6o3GNzsSvqpGKLmCt6w5WzKQ1yHk6C8AA8S".to_string(),
+                ..metadata.clone()
+            }
+            .abi_encode()
+            .into(),
+            deadline: voucher.deadline + 1,
+            ..voucher.clone()
+        };
+        let res = super::validate_and_amend_agreement(
+            ctx.clone(),
+            &domain,
+            &payee_addr,
+            vec![payer_addr],
+            1,
+            other_deployment.sign(&domain, payer.clone())?.abi_encode(),
+        )
+        .await;
+        assert!(matches!(
+            res,
+            Err(DipsError::AmendmentMismatch("subgraphDeploymentId"))
+        ));
+
+        let signed_amendment = amended.sign(&domain, payer.clone())?;
+        let amended_id = super::validate_and_amend_agreement(
+            ctx.clone(),
+            &domain,
+            &payee_addr,
+            vec![payer_addr],
+            ESCALATING_VOUCHER_VERSION,
+            signed_amendment.abi_encode(),
+        )
+        .await?;
+        assert_eq!(amended_id, id);
+
+        let stored_agreement = ctx.store.get_by_id(id).await?.unwrap();
+        assert_eq!(stored_agreement.voucher, signed_amendment);
+        assert_eq!(
+            stored_agreement.metadata.pricePerEntity,
+            U256::from(200_u64)
+        );
+        assert_eq!(stored_agreement.escalation, Some(escalation));
+
+        // the voucher accepted first can't be replayed to restore its terms,
+        // nor can the accepted one be proposed again
+        for (version, replayed) in [(1, &voucher), (ESCALATING_VOUCHER_VERSION, &amended)] {
+            let res = super::validate_and_amend_agreement(
+                ctx.clone(),
+                &domain,
+                &payee_addr,
+                vec![payer_addr],
+                version,
+                replayed.sign(&domain, payer.clone())?.abi_encode(),
+            )
+            .await;
+            assert!(matches!(res, Err(DipsError::AmendmentNotNewer { .. })));
+        }
+        Ok(())
+    }
+
     #[test]
     fn voucher_signature_verification() {
         let ctx = DipsServerContext::for_testing();
@@ -489,7 +879,7 @@ mod test {
             metadata: metadata.abi_encode().into(),
         };
 
-        let domain = dips_agreement_eip712_domain();
+        let domain = dips_agreement_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
         let signed = voucher.sign(&domain, payer).unwrap();
         assert_eq!(
             signed
@@ -543,7 +933,7 @@ mod test {
             deadline: 10000000,
             metadata: metadata.abi_encode().into(),
         };
-        let domain = dips_agreement_eip712_domain();
+        let domain = dips_agreement_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
 
         let mut signed = voucher.sign(&domain, payer).unwrap();
         signed.voucher.service = Address::repeat_byte(9);
@@ -595,7 +985,7 @@ mod test {
             let voucher = CancellationRequest {
                 agreement_id: Uuid::now_v7().as_bytes().into(),
             };
-            let domain = dips_cancellation_eip712_domain();
+            let domain = dips_cancellation_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
 
             let signed = voucher.sign(&domain, signer).unwrap();
 
@@ -625,7 +1015,7 @@ mod test {
             }
         }
         pub fn domain(&self) -> Eip712Domain {
-            dips_agreement_eip712_domain()
+            dips_agreement_eip712_domain(CHAIN_ID_ARBITRUM_ONE)
         }
 
         pub fn test_voucher_with_signer(
@@ -635,7 +1025,7 @@ mod test {
         ) -> SignedIndexingAgreementVoucher {
             let agreement_id = Uuid::now_v7();
 
-            let domain = dips_agreement_eip712_domain();
+            let domain = dips_agreement_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
 
             let voucher = IndexingAgreementVoucher {
                 agreement_id: agreement_id.as_bytes().into(),
@@ -686,12 +1076,13 @@ mod test {
             &voucher_ctx.domain(),
             &voucher_ctx.payee.address(),
             vec![voucher_ctx.payer.address()],
+            1,
             signed_voucher.encode_vec(),
         )
         .await?;
 
         // Create and sign cancellation request
-        let cancel_domain = dips_cancellation_eip712_domain();
+        let cancel_domain = dips_cancellation_eip712_domain(CHAIN_ID_ARBITRUM_ONE);
         let cancel_request = CancellationRequest {
             agreement_id: agreement_id.as_bytes().into(),
         };
@@ -714,6 +1105,243 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_chain_id_checks() -> anyhow::Result<()> {
+        let voucher_ctx = VoucherContext::random();
+        let ctx = DipsServerContext::for_testing_mocked_accounts(EscrowAccounts::new(
+            HashMap::default(),
+            HashMap::from_iter(vec![(
+                voucher_ctx.payer.address(),
+                vec![voucher_ctx.payer.address()],
+            )]),
+        ))
+        .await;
+
+        // signed for the configured chain, for another protocol network
+        let metadata = SubgraphIndexingVoucherMetadata {
+            basePricePerEpoch: U256::from(10000_u64),
+            pricePerEntity: U256::from(100_u64),
+            protocolNetwork: "eip155:421614".to_string(),
+            chainId: "mainnet".to_string(),
+            subgraphDeploymentId: voucher_ctx.deployment_id.clone(),
+        };
+        let voucher = voucher_ctx.test_voucher(metadata);
+        let err = super::validate_and_create_agreement(
+            ctx.clone(),
+            &voucher_ctx.domain(),
+            &voucher_ctx.payee.address(),
+            vec![voucher_ctx.payer.address()],
+            1,
+            voucher.encode_vec(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, DipsError::UnexpectedProtocolNetwork { .. }));
+
+        // signed for another chain, the signer can't be recovered
+        let metadata = SubgraphIndexingVoucherMetadata {
+            basePricePerEpoch: U256::from(10000_u64),
+            pricePerEntity: U256::from(100_u64),
+            protocolNetwork: "eip155:42161".to_string(),
+            chainId: "mainnet".to_string(),
+            subgraphDeploymentId: voucher_ctx.deployment_id.clone(),
+        };
+        let voucher = voucher_ctx.test_voucher(metadata);
+        let err = super::validate_and_create_agreement(
+            ctx.clone(),
+            &dips_agreement_eip712_domain(421614),
+            &voucher_ctx.payee.address(),
+            vec![voucher_ctx.payer.address()],
+            1,
+            voucher.encode_vec(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, DipsError::SignerNotAuthorised(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_status() {
+        let status = |error: DipsError| tonic::Status::from(error).code();
+        assert_eq!(
+            status(DipsError::AbiDecoding("buffer overrun".to_string())),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            status(DipsError::PayerNotAuthorised(Address::ZERO)),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            status(DipsError::AgreementCancelled),
+            tonic::Code::FailedPrecondition
+        );
+        assert_eq!(status(DipsError::AgreementNotFound), tonic::Code::NotFound);
+        assert_eq!(
+            status(DipsError::UnknownError(anyhow::anyhow!("database is down"))),
+            tonic::Code::Internal
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_and_get_agreements() -> anyhow::Result<()> {
+        let ctx = DipsServerContext::for_testing();
+        let voucher_ctx = VoucherContext::random();
+        let metadata = SubgraphIndexingVoucherMetadata {
+            basePricePerEpoch: U256::from(10000_u64),
+            pricePerEntity: U256::from(100_u64),
+            protocolNetwork: "eip155:42161".to_string(),
+            chainId: "mainnet".to_string(),
+            subgraphDeploymentId: voucher_ctx.deployment_id.clone(),
+        };
+        let signed_voucher = voucher_ctx.test_voucher(metadata);
+        let agreement_id = super::validate_and_create_agreement(
+            ctx.clone(),
+            &voucher_ctx.domain(),
+            &voucher_ctx.payee.address(),
+            vec![voucher_ctx.payer.address()],
+            1,
+            signed_voucher.encode_vec(),
+        )
+        .await?;
+
+        let server = DipsServer {
+            ctx,
+            expected_payee: voucher_ctx.payee.address(),
+            allowed_payers: vec![voucher_ctx.payer.address()],
+            domain: voucher_ctx.domain(),
+            cancellation_domain: dips_cancellation_eip712_domain(CHAIN_ID_ARBITRUM_ONE),
+            agreements_auth_token: Some("agreements-token".to_string()),
+        };
+
+        let authorized = |request| {
+            let mut request = tonic::Request::new(request);
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer agreements-token".parse().unwrap());
+            request
+        };
+
+        // the agreements are not served without the token
+        let unauthenticated = server
+            .get_agreement(tonic::Request::new(GetAgreementRequest {
+                version: 1,
+                agreement_id: agreement_id.as_bytes().to_vec(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(unauthenticated.code(), tonic::Code::Unauthenticated);
+        let unauthenticated = server
+            .list_agreements(tonic::Request::new(ListAgreementsRequest {
+                version: 1,
+                payer: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(unauthenticated.code(), tonic::Code::Unauthenticated);
+
+        let agreement = server
+            .get_agreement(authorized(GetAgreementRequest {
+                version: 1,
+                agreement_id: agreement_id.as_bytes().to_vec(),
+            }))
+            .await?
+            .into_inner()
+            .agreement
+            .unwrap();
+        assert_eq!(agreement.signed_voucher, signed_voucher.encode_vec());
+        assert!(!agreement.status.unwrap().cancelled);
+
+        let missing = server
+            .get_agreement(authorized(GetAgreementRequest {
+                version: 1,
+                agreement_id: Uuid::now_v7().as_bytes().to_vec(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        let list = |payer: Address| {
+            let mut request = tonic::Request::new(ListAgreementsRequest {
+                version: 1,
+                payer: Some(payer.to_vec()),
+            });
+            request
+                .metadata_mut()
+                .insert("authorization", "Bearer agreements-token".parse().unwrap());
+            server.list_agreements(request)
+        };
+        let agreements = list(voucher_ctx.payer.address())
+            .await?
+            .into_inner()
+            .agreements;
+        assert_eq!(agreements.len(), 1);
+        assert_eq!(agreements[0].agreement_id, agreement_id.as_bytes().to_vec());
+        let agreements = list(Address::repeat_byte(1)).await?.into_inner().agreements;
+        assert!(agreements.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_propose_amendment_rejected() -> anyhow::Result<()> {
+        let ctx = DipsServerContext::for_testing();
+        let voucher_ctx = VoucherContext::random();
+        let metadata = SubgraphIndexingVoucherMetadata {
+            basePricePerEpoch: U256::from(10000_u64),
+            pricePerEntity: U256::from(100_u64),
+            protocolNetwork: "eip155:42161".to_string(),
+            chainId: "mainnet".to_string(),
+            subgraphDeploymentId: voucher_ctx.deployment_id.clone(),
+        };
+        let signed_voucher = voucher_ctx.test_voucher(metadata.clone());
+        super::validate_and_create_agreement(
+            ctx.clone(),
+            &voucher_ctx.domain(),
+            &voucher_ctx.payee.address(),
+            vec![voucher_ctx.payer.address()],
+            1,
+            signed_voucher.encode_vec(),
+        )
+        .await?;
+        let server = DipsServer {
+            ctx,
+            expected_payee: voucher_ctx.payee.address(),
+            allowed_payers: vec![voucher_ctx.payer.address()],
+            domain: voucher_ctx.domain(),
+            cancellation_domain: dips_cancellation_eip712_domain(CHAIN_ID_ARBITRUM_ONE),
+            agreements_auth_token: Some("agreements-token".to_string()),
+        };
+        let propose = |price: u64| {
+            let amended = IndexingAgreementVoucher {
+                metadata: SubgraphIndexingVoucherMetadata {
+                    pricePerEntity: U256::from(price),
+                    ..metadata.clone()
+                }
+                .abi_encode()
+                .into(),
+                deadline: signed_voucher.voucher.deadline + 1,
+                ..signed_voucher.voucher.clone()
+            };
+            let signed_voucher = amended
+                .sign(&voucher_ctx.domain(), voucher_ctx.payer.clone())
+                .unwrap()
+                .encode_vec();
+            server.propose_amendment(tonic::Request::new(ProposeAmendmentRequest {
+                version: 1,
+                signed_voucher,
+            }))
+        };
+
+        // below the minimum price of 100, the current terms are kept
+        let response = propose(50).await?.into_inner().response;
+        assert_eq!(response, ProposalResponse::Reject as i32);
+        let response = propose(150).await?.into_inner().response;
+        assert_eq!(response, ProposalResponse::Accept as i32);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_create_validations_errors() -> anyhow::Result<()> {
         let voucher_ctx = VoucherContext::random();
@@ -789,6 +1417,7 @@ mod test {
                 &voucher_ctx.domain(),
                 &voucher_ctx.payee.address(),
                 vec![voucher_ctx.payer.address()],
+                1,
                 voucher.encode_vec(),
             )
             .await;
@@ -18,6 +18,11 @@ impl PriceCalculator {
         }
     }
 
+    /// No minimum price is configured, every chain is unsupported
+    pub fn is_empty(&self) -> bool {
+        self.prices_per_chain.is_empty() && self.default_price.is_none()
+    }
+
     pub fn is_supported(&self, chain_id: &str) -> bool {
         self.get_minimum_price(chain_id).is_some()
     }
@@ -5,6 +5,7 @@
 /// See the `DipsService.SubmitAgreementProposal` method.
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct SubmitAgreementProposalRequest {
+    /// / 2 if the voucher metadata has price escalation terms
     #[prost(uint64, tag = "1")]
     pub version: u64,
     /// / An ERC-712 signed indexing agreement voucher
@@ -42,6 +43,102 @@ pub struct CancelAgreementRequest {
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct CancelAgreementResponse {}
 /// *
+/// A request to list the _indexing agreements_.
+///
+/// See the `DipsService.ListAgreements` method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListAgreementsRequest {
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+    /// / Only list the agreements of this payer
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub payer: ::core::option::Option<::prost::alloc::vec::Vec<u8>>,
+}
+/// *
+/// A response to a request to list the _indexing agreements_.
+///
+/// See the `DipsService.ListAgreements` method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListAgreementsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub agreements: ::prost::alloc::vec::Vec<Agreement>,
+}
+/// *
+/// A request to fetch an _indexing agreement_.
+///
+/// See the `DipsService.GetAgreement` method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetAgreementRequest {
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+    /// / The 16 bytes id of the agreement
+    #[prost(bytes = "vec", tag = "2")]
+    pub agreement_id: ::prost::alloc::vec::Vec<u8>,
+}
+/// *
+/// A response to a request to fetch an _indexing agreement_.
+///
+/// See the `DipsService.GetAgreement` method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetAgreementResponse {
+    #[prost(message, optional, tag = "1")]
+    pub agreement: ::core::option::Option<Agreement>,
+}
+/// *
+/// An _indexing agreement_ stored by the _indexer_.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Agreement {
+    #[prost(bytes = "vec", tag = "1")]
+    pub agreement_id: ::prost::alloc::vec::Vec<u8>,
+    /// / The ERC-712 signed voucher the agreement was proposed with
+    #[prost(bytes = "vec", tag = "2")]
+    pub signed_voucher: ::prost::alloc::vec::Vec<u8>,
+    #[prost(message, optional, tag = "3")]
+    pub status: ::core::option::Option<AgreementStatus>,
+}
+/// *
+/// The fulfillment and collection status of an _indexing agreement_.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AgreementStatus {
+    #[prost(bool, tag = "1")]
+    pub cancelled: bool,
+    /// / The allocation currently indexing the deployment
+    #[prost(string, optional, tag = "2")]
+    pub current_allocation_id: ::core::option::Option<::prost::alloc::string::String>,
+    /// / The last allocation closed for the agreement
+    #[prost(string, optional, tag = "3")]
+    pub last_allocation_id: ::core::option::Option<::prost::alloc::string::String>,
+    /// / Unix timestamp of the last collection, in seconds
+    #[prost(uint64, optional, tag = "4")]
+    pub last_payment_collected_at: ::core::option::Option<u64>,
+    /// / The current price is below the minimum price of the indexer
+    #[prost(bool, tag = "5")]
+    pub unprofitable: bool,
+}
+/// *
+/// A request to amend an _indexing agreement_.
+///
+/// See the `DipsService.ProposeAmendment` method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ProposeAmendmentRequest {
+    /// / 2 if the voucher metadata has price escalation terms
+    #[prost(uint64, tag = "1")]
+    pub version: u64,
+    /// / An ERC-712 signed voucher with the id of the agreement and its new terms
+    #[prost(bytes = "vec", tag = "2")]
+    pub signed_voucher: ::prost::alloc::vec::Vec<u8>,
+}
+/// *
+/// A response to a request to amend an _indexing agreement_.
+///
+/// See the `DipsService.ProposeAmendment` method.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ProposeAmendmentResponse {
+    /// / The response to the amendment proposal.
+    #[prost(enumeration = "ProposalResponse", tag = "1")]
+    pub response: i32,
+}
+/// *
 /// The response to an _indexing agreement_ proposal.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
@@ -226,6 +323,102 @@ pub mod indexer_dips_service_client {
                 );
             self.inner.unary(req, path, codec).await
         }
+        /// *
+        /// List the _indexing agreements_ known by the _indexer_, with their status.
+        pub async fn list_agreements(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListAgreementsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListAgreementsResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/graphprotocol.indexer.dips.IndexerDipsService/ListAgreements",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "graphprotocol.indexer.dips.IndexerDipsService",
+                        "ListAgreements",
+                    ),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// *
+        /// Fetch an _indexing agreement_ with its signed voucher and status.
+        pub async fn get_agreement(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetAgreementRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetAgreementResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/graphprotocol.indexer.dips.IndexerDipsService/GetAgreement",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "graphprotocol.indexer.dips.IndexerDipsService",
+                        "GetAgreement",
+                    ),
+                );
+            self.inner.unary(req, path, codec).await
+        }
+        /// *
+        /// Propose new terms for an existing _indexing agreement_, e.g. when it is no
+        /// longer profitable.
+        ///
+        /// The _indexer_ can `ACCEPT` or `REJECT` the amendment.
+        pub async fn propose_amendment(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ProposeAmendmentRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ProposeAmendmentResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/graphprotocol.indexer.dips.IndexerDipsService/ProposeAmendment",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "graphprotocol.indexer.dips.IndexerDipsService",
+                        "ProposeAmendment",
+                    ),
+                );
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -261,6 +454,36 @@ pub mod indexer_dips_service_server {
             tonic::Response<super::CancelAgreementResponse>,
             tonic::Status,
         >;
+        /// *
+        /// List the _indexing agreements_ known by the _indexer_, with their status.
+        async fn list_agreements(
+            &self,
+            request: tonic::Request<super::ListAgreementsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListAgreementsResponse>,
+            tonic::Status,
+        >;
+        /// *
+        /// Fetch an _indexing agreement_ with its signed voucher and status.
+        async fn get_agreement(
+            &self,
+            request: tonic::Request<super::GetAgreementRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::GetAgreementResponse>,
+            tonic::Status,
+        >;
+        /// *
+        /// Propose new terms for an existing _indexing agreement_, e.g. when it is no
+        /// longer profitable.
+        ///
+        /// The _indexer_ can `ACCEPT` or `REJECT` the amendment.
+        async fn propose_amendment(
+            &self,
+            request: tonic::Request<super::ProposeAmendmentRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ProposeAmendmentResponse>,
+            tonic::Status,
+        >;
     }
     #[derive(Debug)]
     pub struct IndexerDipsServiceServer<T> {
@@ -435,6 +658,144 @@ pub mod indexer_dips_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/graphprotocol.indexer.dips.IndexerDipsService/ListAgreements" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListAgreementsSvc<T: IndexerDipsService>(pub Arc<T>);
+                    impl<
+                        T: IndexerDipsService,
+                    > tonic::server::UnaryService<super::ListAgreementsRequest>
+                    for ListAgreementsSvc<T> {
+                        type Response = super::ListAgreementsResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListAgreementsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as IndexerDipsService>::list_agreements(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ListAgreementsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/graphprotocol.indexer.dips.IndexerDipsService/GetAgreement" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetAgreementSvc<T: IndexerDipsService>(pub Arc<T>);
+                    impl<
+                        T: IndexerDipsService,
+                    > tonic::server::UnaryService<super::GetAgreementRequest>
+                    for GetAgreementSvc<T> {
+                        type Response = super::GetAgreementResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetAgreementRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as IndexerDipsService>::get_agreement(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = GetAgreementSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/graphprotocol.indexer.dips.IndexerDipsService/ProposeAmendment" => {
+                    #[allow(non_camel_case_types)]
+                    struct ProposeAmendmentSvc<T: IndexerDipsService>(pub Arc<T>);
+                    impl<
+                        T: IndexerDipsService,
+                    > tonic::server::UnaryService<super::ProposeAmendmentRequest>
+                    for ProposeAmendmentSvc<T> {
+                        type Response = super::ProposeAmendmentResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ProposeAmendmentRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as IndexerDipsService>::propose_amendment(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = ProposeAmendmentSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         let mut response = http::Response::new(empty_body());
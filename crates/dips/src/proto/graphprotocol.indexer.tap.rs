@@ -0,0 +1,397 @@
+// This file is @generated by prost-build.
+/// *
+/// A request to subscribe to the state changes of the _senders_.
+///
+/// See the `SenderStatsService.SubscribeSenderStats` method.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SubscribeSenderStatsRequest {
+    /// / Only stream the changes of these senders, all of them if empty
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub senders: ::prost::alloc::vec::Vec<::prost::alloc::vec::Vec<u8>>,
+}
+/// *
+/// A state change of a _sender_.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SenderStatsEvent {
+    /// / The 20 bytes address of the sender
+    #[prost(bytes = "vec", tag = "1")]
+    pub sender: ::prost::alloc::vec::Vec<u8>,
+    /// / Unix timestamp of the change, in milliseconds
+    #[prost(uint64, tag = "2")]
+    pub timestamp_ms: u64,
+    #[prost(oneof = "sender_stats_event::Event", tags = "3, 4, 5")]
+    pub event: ::core::option::Option<sender_stats_event::Event>,
+}
+/// Nested message and enum types in `SenderStatsEvent`.
+pub mod sender_stats_event {
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Event {
+        #[prost(message, tag = "3")]
+        UnaggregatedFees(super::UnaggregatedFees),
+        #[prost(message, tag = "4")]
+        DenyStatus(super::DenyStatus),
+        #[prost(message, tag = "5")]
+        RavResult(super::RavResult),
+    }
+}
+/// *
+/// The fees of a _sender_ not redeemed yet, as decimal strings of GRT wei.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UnaggregatedFees {
+    /// / The value of the receipts not aggregated into a RAV
+    #[prost(string, tag = "1")]
+    pub unaggregated_fees: ::prost::alloc::string::String,
+    /// / The value of the RAVs not redeemed
+    #[prost(string, tag = "2")]
+    pub pending_ravs: ::prost::alloc::string::String,
+    /// / The escrow balance of the sender
+    #[prost(string, tag = "3")]
+    pub escrow_balance: ::prost::alloc::string::String,
+}
+/// *
+/// A _sender_ was denied or allowed again.
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct DenyStatus {
+    #[prost(bool, tag = "1")]
+    pub denied: bool,
+}
+/// *
+/// The result of a RAV request for an allocation of a _sender_.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RavResult {
+    /// / The 20 bytes address of the allocation
+    #[prost(bytes = "vec", tag = "1")]
+    pub allocation_id: ::prost::alloc::vec::Vec<u8>,
+    #[prost(bool, tag = "2")]
+    pub success: bool,
+    /// / The value of the RAV received, in GRT wei
+    #[prost(string, optional, tag = "3")]
+    pub value_aggregate: ::core::option::Option<::prost::alloc::string::String>,
+    /// / Why the RAV request failed
+    #[prost(string, optional, tag = "4")]
+    pub error: ::core::option::Option<::prost::alloc::string::String>,
+}
+/// Generated client implementations.
+pub mod sender_stats_service_client {
+    #![allow(
+        unused_variables,
+        dead_code,
+        missing_docs,
+        clippy::wildcard_imports,
+        clippy::let_unit_value,
+    )]
+    use tonic::codegen::*;
+    use tonic::codegen::http::Uri;
+    #[derive(Debug, Clone)]
+    pub struct SenderStatsServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl SenderStatsServiceClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> SenderStatsServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + std::marker::Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + std::marker::Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_origin(inner: T, origin: Uri) -> Self {
+            let inner = tonic::client::Grpc::with_origin(inner, origin);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> SenderStatsServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+            >>::Error: Into<StdError> + std::marker::Send + std::marker::Sync,
+        {
+            SenderStatsServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with the given encoding.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.send_compressed(encoding);
+            self
+        }
+        /// Enable decompressing responses.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.accept_compressed(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.inner = self.inner.max_decoding_message_size(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.inner = self.inner.max_encoding_message_size(limit);
+            self
+        }
+        /// *
+        /// Stream the state changes of the _senders_ followed by tap-agent.
+        ///
+        /// The last known state of each _sender_ is sent first, then every change as
+        /// it happens.
+        pub async fn subscribe_sender_stats(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SubscribeSenderStatsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::SenderStatsEvent>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::unknown(
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/graphprotocol.indexer.tap.SenderStatsService/SubscribeSenderStats",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new(
+                        "graphprotocol.indexer.tap.SenderStatsService",
+                        "SubscribeSenderStats",
+                    ),
+                );
+            self.inner.server_streaming(req, path, codec).await
+        }
+    }
+}
+/// Generated server implementations.
+pub mod sender_stats_service_server {
+    #![allow(
+        unused_variables,
+        dead_code,
+        missing_docs,
+        clippy::wildcard_imports,
+        clippy::let_unit_value,
+    )]
+    use tonic::codegen::*;
+    /// Generated trait containing gRPC methods that should be implemented for use with SenderStatsServiceServer.
+    #[async_trait]
+    pub trait SenderStatsService: std::marker::Send + std::marker::Sync + 'static {
+        /// Server streaming response type for the SubscribeSenderStats method.
+        type SubscribeSenderStatsStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::SenderStatsEvent, tonic::Status>,
+            >
+            + std::marker::Send
+            + 'static;
+        /// *
+        /// Stream the state changes of the _senders_ followed by tap-agent.
+        ///
+        /// The last known state of each _sender_ is sent first, then every change as
+        /// it happens.
+        async fn subscribe_sender_stats(
+            &self,
+            request: tonic::Request<super::SubscribeSenderStatsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<Self::SubscribeSenderStatsStream>,
+            tonic::Status,
+        >;
+    }
+    #[derive(Debug)]
+    pub struct SenderStatsServiceServer<T> {
+        inner: Arc<T>,
+        accept_compression_encodings: EnabledCompressionEncodings,
+        send_compression_encodings: EnabledCompressionEncodings,
+        max_decoding_message_size: Option<usize>,
+        max_encoding_message_size: Option<usize>,
+    }
+    impl<T> SenderStatsServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+                max_decoding_message_size: None,
+                max_encoding_message_size: None,
+            }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+        /// Enable decompressing requests with the given encoding.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.accept_compression_encodings.enable(encoding);
+            self
+        }
+        /// Compress responses with the given encoding, if the client supports it.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.send_compression_encodings.enable(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.max_decoding_message_size = Some(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.max_encoding_message_size = Some(limit);
+            self
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for SenderStatsServiceServer<T>
+    where
+        T: SenderStatsService,
+        B: Body + std::marker::Send + 'static,
+        B::Error: Into<StdError> + std::marker::Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            match req.uri().path() {
+                "/graphprotocol.indexer.tap.SenderStatsService/SubscribeSenderStats" => {
+                    #[allow(non_camel_case_types)]
+                    struct SubscribeSenderStatsSvc<T: SenderStatsService>(pub Arc<T>);
+                    impl<
+                        T: SenderStatsService,
+                    > tonic::server::ServerStreamingService<
+                        super::SubscribeSenderStatsRequest,
+                    > for SubscribeSenderStatsSvc<T> {
+                        type Response = super::SenderStatsEvent;
+                        type ResponseStream = T::SubscribeSenderStatsStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SubscribeSenderStatsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as SenderStatsService>::subscribe_sender_stats(
+                                        &inner,
+                                        request,
+                                    )
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let method = SubscribeSenderStatsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => {
+                    Box::pin(async move {
+                        let mut response = http::Response::new(empty_body());
+                        let headers = response.headers_mut();
+                        headers
+                            .insert(
+                                tonic::Status::GRPC_STATUS,
+                                (tonic::Code::Unimplemented as i32).into(),
+                            );
+                        headers
+                            .insert(
+                                http::header::CONTENT_TYPE,
+                                tonic::metadata::GRPC_CONTENT_TYPE,
+                            );
+                        Ok(response)
+                    })
+                }
+            }
+        }
+    }
+    impl<T> Clone for SenderStatsServiceServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+                max_decoding_message_size: self.max_decoding_message_size,
+                max_encoding_message_size: self.max_encoding_message_size,
+            }
+        }
+    }
+    /// Generated gRPC service name
+    pub const SERVICE_NAME: &str = "graphprotocol.indexer.tap.SenderStatsService";
+    impl<T> tonic::server::NamedService for SenderStatsServiceServer<T> {
+        const NAME: &'static str = SERVICE_NAME;
+    }
+}
@@ -1,2 +1,3 @@
 pub mod gateway;
 pub mod indexer;
+pub mod sender_stats;
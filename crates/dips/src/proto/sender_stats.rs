@@ -0,0 +1,8 @@
+// This file is @generated by prost-build.
+pub mod graphprotocol {
+    pub mod indexer {
+        pub mod tap {
+            include!("graphprotocol.indexer.tap.rs");
+        }
+    }
+}
@@ -6,20 +6,23 @@ use std::sync::Arc;
 use async_trait::async_trait;
 #[cfg(test)]
 use indexer_monitor::EscrowAccounts;
+use subtle::ConstantTimeEq;
 use thegraph_core::alloy::{primitives::Address, sol_types::Eip712Domain};
 use tonic::{Request, Response, Status};
+use uuid::Uuid;
 
 use crate::{
     ipfs::IpfsFetcher,
     price::PriceCalculator,
     proto::indexer::graphprotocol::indexer::dips::{
-        indexer_dips_service_server::IndexerDipsService, CancelAgreementRequest,
-        CancelAgreementResponse, ProposalResponse, SubmitAgreementProposalRequest,
-        SubmitAgreementProposalResponse,
+        indexer_dips_service_server::IndexerDipsService, Agreement, AgreementStatus,
+        CancelAgreementRequest, CancelAgreementResponse, GetAgreementRequest, GetAgreementResponse,
+        ListAgreementsRequest, ListAgreementsResponse, ProposalResponse, ProposeAmendmentRequest,
+        ProposeAmendmentResponse, SubmitAgreementProposalRequest, SubmitAgreementProposalResponse,
     },
     signers::SignerValidator,
-    store::AgreementStore,
-    validate_and_cancel_agreement, validate_and_create_agreement,
+    store::{AgreementStore, StoredIndexingAgreement},
+    validate_and_amend_agreement, validate_and_cancel_agreement, validate_and_create_agreement,
 };
 
 #[derive(Debug)]
@@ -63,7 +66,53 @@ pub struct DipsServer {
     pub ctx: Arc<DipsServerContext>,
     pub expected_payee: Address,
     pub allowed_payers: Vec<Address>,
+    /// domain the agreement vouchers are signed for
     pub domain: Eip712Domain,
+    /// domain the cancellation requests are signed for
+    pub cancellation_domain: Eip712Domain,
+    /// bearer token required to list and get the agreements, which are not
+    /// served without it
+    pub agreements_auth_token: Option<String>,
+}
+
+impl DipsServer {
+    /// Checks the bearer token in the `authorization` metadata of a request
+    /// reading the agreements
+    #[allow(clippy::result_large_err)]
+    fn authorize_agreements_read<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(auth_token) = &self.agreements_auth_token else {
+            return Err(Status::permission_denied(
+                "agreements are only served with dips.agreements_auth_token",
+            ));
+        };
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if token.as_bytes().ct_eq(auth_token.as_bytes()).into() => Ok(()),
+            _ => Err(Status::unauthenticated("invalid agreements auth token")),
+        }
+    }
+}
+
+impl From<StoredIndexingAgreement> for Agreement {
+    fn from(agreement: StoredIndexingAgreement) -> Self {
+        Agreement {
+            agreement_id: agreement.voucher.voucher.agreement_id.to_vec(),
+            signed_voucher: agreement.voucher.encode_vec(),
+            status: Some(AgreementStatus {
+                cancelled: agreement.cancelled,
+                current_allocation_id: agreement.current_allocation_id,
+                last_allocation_id: agreement.last_allocation_id,
+                last_payment_collected_at: agreement
+                    .last_payment_collected_at
+                    .map(|collected_at| collected_at.timestamp() as u64),
+                unprofitable: agreement.unprofitable,
+            }),
+        }
+    }
 }
 
 #[async_trait]
@@ -77,11 +126,6 @@ impl IndexerDipsService for DipsServer {
             signed_voucher,
         } = request.into_inner();
 
-        // Ensure the version is 1
-        if version != 1 {
-            return Err(Status::invalid_argument("invalid version"));
-        }
-
         // TODO: Validate that:
         // - The price is over the configured minimum price
         // - The subgraph deployment is for a chain we support
@@ -91,6 +135,7 @@ impl IndexerDipsService for DipsServer {
             &self.domain,
             &self.expected_payee,
             &self.allowed_payers,
+            version,
             signed_voucher,
         )
         .await
@@ -115,10 +160,112 @@ impl IndexerDipsService for DipsServer {
             return Err(Status::invalid_argument("invalid version"));
         }
 
-        validate_and_cancel_agreement(self.ctx.store.clone(), &self.domain, signed_cancellation)
+        validate_and_cancel_agreement(
+            self.ctx.store.clone(),
+            &self.cancellation_domain,
+            signed_cancellation,
+        )
+        .await
+        .map_err(Into::<tonic::Status>::into)?;
+
+        Ok(tonic::Response::new(CancelAgreementResponse {}))
+    }
+
+    /// *
+    /// List the _indexing agreements_ known by the _indexer_, with their status.
+    /// Requires [DipsServer::agreements_auth_token].
+    async fn list_agreements(
+        &self,
+        request: Request<ListAgreementsRequest>,
+    ) -> Result<Response<ListAgreementsResponse>, Status> {
+        self.authorize_agreements_read(&request)?;
+        let ListAgreementsRequest { version, payer } = request.into_inner();
+
+        if version != 1 {
+            return Err(Status::invalid_argument("invalid version"));
+        }
+
+        let payer = payer
+            .map(|payer| Address::try_from(payer.as_slice()))
+            .transpose()
+            .map_err(|_| Status::invalid_argument("invalid payer"))?;
+
+        let agreements = self
+            .ctx
+            .store
+            .list_agreements(payer)
             .await
             .map_err(Into::<tonic::Status>::into)?;
 
-        Ok(tonic::Response::new(CancelAgreementResponse {}))
+        Ok(tonic::Response::new(ListAgreementsResponse {
+            agreements: agreements.into_iter().map(Into::into).collect(),
+        }))
+    }
+    /// *
+    /// Fetch an _indexing agreement_ with its signed voucher and status.
+    /// Requires [DipsServer::agreements_auth_token].
+    async fn get_agreement(
+        &self,
+        request: Request<GetAgreementRequest>,
+    ) -> Result<Response<GetAgreementResponse>, Status> {
+        self.authorize_agreements_read(&request)?;
+        let GetAgreementRequest {
+            version,
+            agreement_id,
+        } = request.into_inner();
+
+        if version != 1 {
+            return Err(Status::invalid_argument("invalid version"));
+        }
+
+        let id = Uuid::from_slice(&agreement_id)
+            .map_err(|_| Status::invalid_argument("invalid agreement id"))?;
+
+        let agreement = self
+            .ctx
+            .store
+            .get_by_id(id)
+            .await
+            .map_err(Into::<tonic::Status>::into)?
+            .ok_or_else(|| Status::not_found("agreement not found"))?;
+
+        Ok(tonic::Response::new(GetAgreementResponse {
+            agreement: Some(agreement.into()),
+        }))
+    }
+
+    /// *
+    /// Propose new terms for an existing _indexing agreement_.
+    async fn propose_amendment(
+        &self,
+        request: Request<ProposeAmendmentRequest>,
+    ) -> Result<Response<ProposeAmendmentResponse>, Status> {
+        let ProposeAmendmentRequest {
+            version,
+            signed_voucher,
+        } = request.into_inner();
+
+        let response = match validate_and_amend_agreement(
+            self.ctx.clone(),
+            &self.domain,
+            &self.expected_payee,
+            &self.allowed_payers,
+            version,
+            signed_voucher,
+        )
+        .await
+        {
+            Ok(_) => ProposalResponse::Accept,
+            // the agreement keeps its current terms
+            Err(error) if error.is_rejection() => {
+                tracing::info!(%error, "Rejected a DIPS agreement amendment");
+                ProposalResponse::Reject
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(tonic::Response::new(ProposeAmendmentResponse {
+            response: response.into(),
+        }))
     }
 }
@@ -5,10 +5,11 @@ use std::collections::HashMap;
 
 use async_trait::async_trait;
 use build_info::chrono::{DateTime, Utc};
+use thegraph_core::alloy::primitives::Address;
 use uuid::Uuid;
 
 use crate::{
-    DipsError, SignedCancellationRequest, SignedIndexingAgreementVoucher,
+    DipsError, PriceEscalation, SignedCancellationRequest, SignedIndexingAgreementVoucher,
     SubgraphIndexingVoucherMetadata,
 };
 
@@ -16,24 +17,52 @@ use crate::{
 pub struct StoredIndexingAgreement {
     pub voucher: SignedIndexingAgreementVoucher,
     pub metadata: SubgraphIndexingVoucherMetadata,
+    /// set for the vouchers proposed with version 2
+    pub escalation: Option<PriceEscalation>,
+    /// when the current terms were accepted, on creation or on the last amendment
+    pub accepted_at: DateTime<Utc>,
     pub cancelled: bool,
+    /// the current price is below the minimum price, see [crate::escalation]
+    pub unprofitable: bool,
     pub current_allocation_id: Option<String>,
     pub last_allocation_id: Option<String>,
     pub last_payment_collected_at: Option<DateTime<Utc>>,
+    /// when the indexing of the deployment was started, see [crate::indexing]
+    pub indexing_started_at: Option<DateTime<Utc>>,
+    /// when graph-node first reported the deployment as synced
+    pub synced_at: Option<DateTime<Utc>>,
 }
 
 #[async_trait]
 pub trait AgreementStore: Sync + Send + std::fmt::Debug {
     async fn get_by_id(&self, id: Uuid) -> Result<Option<StoredIndexingAgreement>, DipsError>;
+    /// Agreements ordered by id, only the ones of `payer` if set
+    async fn list_agreements(
+        &self,
+        payer: Option<Address>,
+    ) -> Result<Vec<StoredIndexingAgreement>, DipsError>;
     async fn create_agreement(
         &self,
         agreement: SignedIndexingAgreementVoucher,
         metadata: SubgraphIndexingVoucherMetadata,
+        escalation: Option<PriceEscalation>,
+    ) -> Result<(), DipsError>;
+    /// Replaces the voucher and terms of the agreement with the same id
+    async fn amend_agreement(
+        &self,
+        agreement: SignedIndexingAgreementVoucher,
+        metadata: SubgraphIndexingVoucherMetadata,
+        escalation: Option<PriceEscalation>,
     ) -> Result<(), DipsError>;
     async fn cancel_agreement(
         &self,
         signed_cancellation: SignedCancellationRequest,
     ) -> Result<Uuid, DipsError>;
+    async fn set_unprofitable(&self, id: Uuid, unprofitable: bool) -> Result<(), DipsError>;
+    /// Records that the indexing of the deployment of the agreement was started
+    async fn set_indexing_started(&self, id: Uuid) -> Result<(), DipsError>;
+    /// Records that the deployment of the agreement is synced
+    async fn set_synced(&self, id: Uuid) -> Result<(), DipsError>;
 }
 
 #[derive(Default, Debug)]
@@ -51,19 +80,40 @@ impl AgreementStore for InMemoryAgreementStore {
             .get(&id)
             .cloned())
     }
+    async fn list_agreements(
+        &self,
+        payer: Option<Address>,
+    ) -> Result<Vec<StoredIndexingAgreement>, DipsError> {
+        let mut agreements: Vec<StoredIndexingAgreement> = self
+            .data
+            .try_read()
+            .map_err(|e| DipsError::UnknownError(e.into()))?
+            .values()
+            .filter(|agreement| payer.is_none() || payer == Some(agreement.voucher.voucher.payer))
+            .cloned()
+            .collect();
+        agreements.sort_by_key(|agreement| agreement.voucher.voucher.agreement_id);
+        Ok(agreements)
+    }
     async fn create_agreement(
         &self,
         agreement: SignedIndexingAgreementVoucher,
         metadata: SubgraphIndexingVoucherMetadata,
+        escalation: Option<PriceEscalation>,
     ) -> Result<(), DipsError> {
         let id = Uuid::from_bytes(agreement.voucher.agreement_id.into());
         let stored_agreement = StoredIndexingAgreement {
             voucher: agreement,
             metadata,
+            escalation,
+            accepted_at: Utc::now(),
             cancelled: false,
+            unprofitable: false,
             current_allocation_id: None,
             last_allocation_id: None,
             last_payment_collected_at: None,
+            indexing_started_at: None,
+            synced_at: None,
         };
         self.data
             .try_write()
@@ -72,6 +122,31 @@ impl AgreementStore for InMemoryAgreementStore {
 
         Ok(())
     }
+    async fn amend_agreement(
+        &self,
+        agreement: SignedIndexingAgreementVoucher,
+        metadata: SubgraphIndexingVoucherMetadata,
+        escalation: Option<PriceEscalation>,
+    ) -> Result<(), DipsError> {
+        let id = Uuid::from_bytes(agreement.voucher.agreement_id.into());
+        let mut write_lock = self
+            .data
+            .try_write()
+            .map_err(|e| DipsError::UnknownError(e.into()))?;
+        let stored_agreement = write_lock
+            .get_mut(&id)
+            .ok_or(DipsError::AgreementNotFound)?;
+        if stored_agreement.cancelled {
+            return Err(DipsError::AgreementCancelled);
+        }
+        stored_agreement.voucher = agreement;
+        stored_agreement.metadata = metadata;
+        stored_agreement.escalation = escalation;
+        stored_agreement.accepted_at = Utc::now();
+        stored_agreement.unprofitable = false;
+
+        Ok(())
+    }
     async fn cancel_agreement(
         &self,
         signed_cancellation: SignedCancellationRequest,
@@ -103,4 +178,36 @@ impl AgreementStore for InMemoryAgreementStore {
 
         Ok(id)
     }
+    async fn set_unprofitable(&self, id: Uuid, unprofitable: bool) -> Result<(), DipsError> {
+        self.data
+            .try_write()
+            .map_err(|e| DipsError::UnknownError(e.into()))?
+            .get_mut(&id)
+            .ok_or(DipsError::AgreementNotFound)?
+            .unprofitable = unprofitable;
+
+        Ok(())
+    }
+    async fn set_indexing_started(&self, id: Uuid) -> Result<(), DipsError> {
+        self.data
+            .try_write()
+            .map_err(|e| DipsError::UnknownError(e.into()))?
+            .get_mut(&id)
+            .ok_or(DipsError::AgreementNotFound)?
+            .indexing_started_at
+            .get_or_insert_with(Utc::now);
+
+        Ok(())
+    }
+    async fn set_synced(&self, id: Uuid) -> Result<(), DipsError> {
+        self.data
+            .try_write()
+            .map_err(|e| DipsError::UnknownError(e.into()))?
+            .get_mut(&id)
+            .ok_or(DipsError::AgreementNotFound)?
+            .synced_at
+            .get_or_insert_with(Utc::now);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,347 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Indexer error codes
+//!
+//! Maps the errors coming from tap-core, the database and the subgraphs
+//! into stable `IE` codes. Each code tells if the operation that failed
+//! can be retried, so both indexer-service and tap-agent take the same
+//! decision for the same kind of failure.
+//!
+//! Both also count their errors by code in `indexer_errors_total`, see
+//! [IndexerErrorCode::record].
+
+use std::fmt;
+
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use tap_core::receipt::ReceiptError;
+use tonic::Code;
+
+lazy_static! {
+    static ref ERRORS: CounterVec = register_counter_vec!(
+        "indexer_errors_total",
+        "Errors of indexer-service and tap-agent by indexer error code",
+        &["code", "retriable"]
+    )
+    .unwrap();
+}
+
+/// Postgres error codes of transactions that can be replayed as is
+const SERIALIZATION_FAILURE: &str = "40001";
+const DEADLOCK_DETECTED: &str = "40P01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IndexerErrorCode {
+    /// IE001: paid query without a receipt
+    ReceiptNotFound,
+    /// IE002: receipt that can't be decoded or whose signature is invalid
+    InvalidReceipt,
+    /// IE003: receipt rejected by one of the checks
+    ReceiptCheckFailed,
+    /// IE004: signer of the receipt is not part of any escrow account
+    UnknownSigner,
+    /// IE005: RAV returned by the aggregator doesn't match the request
+    InvalidRav,
+    /// IE006: RAV request without any valid receipt
+    NoValidReceipts,
+    /// IE007: tap-core storage adapter failure
+    TapAdapter,
+    /// IE008: database could not be reached
+    DatabaseUnavailable,
+    /// IE009: transaction aborted by a concurrent one
+    DatabaseConflict,
+    /// IE010: query rejected or result not decoded by the database
+    DatabaseQuery,
+    /// IE011: subgraph or graph-node could not be reached
+    SubgraphUnavailable,
+    /// IE012: subgraph query rejected or result not decoded
+    SubgraphQuery,
+    /// IE013: sender aggregator could not be reached
+    AggregatorUnavailable,
+    /// IE014: request rejected by the sender aggregator
+    AggregatorRejected,
+    /// IE015: API key reached its daily quota
+    ApiKeyQuotaExceeded,
+    /// IE016: API key can't query the deployment
+    ApiKeyDeploymentNotAllowed,
+    /// IE017: block constraint of the query can't be parsed
+    InvalidBlockConstraint,
+    /// IE018: query was not executed at the requested block
+    BlockConstraintMismatch,
+    /// IE019: receipt timestamp too far in the future or too old
+    ReceiptTimestampOutOfRange,
+    /// IE020: graph-node returned errors alongside data, the query was refused
+    PartialResponseRefused,
+    /// IE021: deployment uses a feature or data source kind that is not supported
+    UnsupportedDeployment,
+    /// IE022: deployment denied by the network or by the indexer
+    DeploymentDenied,
+    /// IE023: query not served before the deadline set by the gateway
+    DeadlineExceeded,
+    /// IE024: receipt was already received for another query
+    ReceiptReplayed,
+    /// IE025: request body larger than the limit of the route
+    RequestBodyTooLarge,
+    /// IE026: query nested too deep or too complex for the deployment
+    QueryLimitsExceeded,
+    /// IE027: query refused by the blocklist of the indexer
+    QueryBlocked,
+    /// IE028: WebAssembly plugin of the indexer failed on the query
+    PluginFailed,
+    /// IE029: no query node reached the block required by the query
+    BlockNotAvailable,
+    /// IE030: dispute manager of the network differs from the configured one
+    DisputeManagerChanged,
+    /// IE031: resource requested doesn't exist
+    NotFound,
+    /// IE032: parameters of the request are invalid
+    InvalidRequest,
+    /// IE033: resource to create already exists
+    AlreadyExists,
+    /// IE034: escrow accounts could not be refreshed, the last ones read are used
+    EscrowAccountsStale,
+    /// IE035: last RAVs of the allocation are requested, receipts for another
+    /// allocation are required
+    AllocationClosing,
+    /// IE099: not classified
+    Unknown,
+}
+
+impl IndexerErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        use IndexerErrorCode as C;
+        match self {
+            C::ReceiptNotFound => "IE001",
+            C::InvalidReceipt => "IE002",
+            C::ReceiptCheckFailed => "IE003",
+            C::UnknownSigner => "IE004",
+            C::InvalidRav => "IE005",
+            C::NoValidReceipts => "IE006",
+            C::TapAdapter => "IE007",
+            C::DatabaseUnavailable => "IE008",
+            C::DatabaseConflict => "IE009",
+            C::DatabaseQuery => "IE010",
+            C::SubgraphUnavailable => "IE011",
+            C::SubgraphQuery => "IE012",
+            C::AggregatorUnavailable => "IE013",
+            C::AggregatorRejected => "IE014",
+            C::ApiKeyQuotaExceeded => "IE015",
+            C::ApiKeyDeploymentNotAllowed => "IE016",
+            C::InvalidBlockConstraint => "IE017",
+            C::BlockConstraintMismatch => "IE018",
+            C::ReceiptTimestampOutOfRange => "IE019",
+            C::PartialResponseRefused => "IE020",
+            C::UnsupportedDeployment => "IE021",
+            C::DeploymentDenied => "IE022",
+            C::DeadlineExceeded => "IE023",
+            C::ReceiptReplayed => "IE024",
+            C::RequestBodyTooLarge => "IE025",
+            C::QueryLimitsExceeded => "IE026",
+            C::QueryBlocked => "IE027",
+            C::PluginFailed => "IE028",
+            C::BlockNotAvailable => "IE029",
+            C::DisputeManagerChanged => "IE030",
+            C::NotFound => "IE031",
+            C::InvalidRequest => "IE032",
+            C::AlreadyExists => "IE033",
+            C::EscrowAccountsStale => "IE034",
+            C::AllocationClosing => "IE035",
+            C::Unknown => "IE099",
+        }
+    }
+
+    /// Returns `true` if the same operation may succeed when tried again
+    ///
+    /// Errors that are not classified are considered retriable, which is
+    /// how every error was handled before the codes were introduced.
+    pub fn retriable(&self) -> bool {
+        use IndexerErrorCode as C;
+        match self {
+            C::TapAdapter
+            | C::DatabaseUnavailable
+            | C::DatabaseConflict
+            | C::SubgraphUnavailable
+            | C::AggregatorUnavailable
+            | C::BlockConstraintMismatch
+            | C::PartialResponseRefused
+            | C::DeadlineExceeded
+            | C::BlockNotAvailable
+            | C::EscrowAccountsStale
+            | C::Unknown => true,
+            C::ReceiptNotFound
+            | C::InvalidReceipt
+            | C::ReceiptCheckFailed
+            | C::UnknownSigner
+            | C::InvalidRav
+            | C::NoValidReceipts
+            | C::DatabaseQuery
+            | C::SubgraphQuery
+            | C::AggregatorRejected
+            | C::ApiKeyQuotaExceeded
+            | C::ApiKeyDeploymentNotAllowed
+            | C::InvalidBlockConstraint
+            | C::ReceiptTimestampOutOfRange
+            | C::UnsupportedDeployment
+            | C::DeploymentDenied
+            | C::ReceiptReplayed
+            | C::RequestBodyTooLarge
+            | C::QueryLimitsExceeded
+            | C::QueryBlocked
+            | C::PluginFailed
+            | C::DisputeManagerChanged
+            | C::NotFound
+            | C::InvalidRequest
+            | C::AlreadyExists
+            | C::AllocationClosing => false,
+        }
+    }
+
+    /// Counts an error of this code in `indexer_errors_total`
+    pub fn record(&self) {
+        ERRORS
+            .with_label_values(&[self.as_str(), &self.retriable().to_string()])
+            .inc();
+    }
+}
+
+impl fmt::Display for IndexerErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Errors that can be classified with an [IndexerErrorCode]
+pub trait ErrorCode {
+    fn error_code(&self) -> IndexerErrorCode;
+
+    fn retriable(&self) -> bool {
+        self.error_code().retriable()
+    }
+}
+
+impl ErrorCode for tap_core::Error {
+    fn error_code(&self) -> IndexerErrorCode {
+        use tap_core::Error as E;
+        match self {
+            E::ReceiptError(ReceiptError::CheckFailure(_)) => IndexerErrorCode::ReceiptCheckFailed,
+            E::ReceiptError(_) | E::SignatureError(_) => IndexerErrorCode::InvalidReceipt,
+            E::InvalidReceivedRav { .. } | E::InvalidRecoveredSigner { .. } => {
+                IndexerErrorCode::InvalidRav
+            }
+            E::AdapterError { .. } => IndexerErrorCode::TapAdapter,
+            _ => IndexerErrorCode::Unknown,
+        }
+    }
+}
+
+impl ErrorCode for sqlx::Error {
+    fn error_code(&self) -> IndexerErrorCode {
+        use sqlx::Error as E;
+        match self {
+            E::Io(_) | E::Tls(_) | E::PoolTimedOut | E::PoolClosed | E::WorkerCrashed => {
+                IndexerErrorCode::DatabaseUnavailable
+            }
+            E::Database(error)
+                if error.code().is_some_and(|code| {
+                    code == SERIALIZATION_FAILURE || code == DEADLOCK_DETECTED
+                }) =>
+            {
+                IndexerErrorCode::DatabaseConflict
+            }
+            _ => IndexerErrorCode::DatabaseQuery,
+        }
+    }
+}
+
+impl ErrorCode for reqwest::Error {
+    fn error_code(&self) -> IndexerErrorCode {
+        let server_error = self.status().is_some_and(|status| status.is_server_error());
+        if self.is_connect() || self.is_timeout() || server_error {
+            IndexerErrorCode::SubgraphUnavailable
+        } else {
+            IndexerErrorCode::SubgraphQuery
+        }
+    }
+}
+
+impl ErrorCode for tonic::Status {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self.code() {
+            Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::Cancelled => IndexerErrorCode::AggregatorUnavailable,
+            _ => IndexerErrorCode::AggregatorRejected,
+        }
+    }
+}
+
+impl ErrorCode for anyhow::Error {
+    /// Uses the first error of the chain that can be classified
+    fn error_code(&self) -> IndexerErrorCode {
+        self.chain()
+            .find_map(|error| {
+                if let Some(error) = error.downcast_ref::<tap_core::Error>() {
+                    Some(error.error_code())
+                } else if let Some(error) = error.downcast_ref::<sqlx::Error>() {
+                    Some(error.error_code())
+                } else if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+                    Some(error.error_code())
+                } else {
+                    error
+                        .downcast_ref::<tonic::Status>()
+                        .map(ErrorCode::error_code)
+                }
+            })
+            .unwrap_or(IndexerErrorCode::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use tonic::Status;
+
+    use super::{ErrorCode, IndexerErrorCode};
+
+    #[test]
+    fn test_sqlx_error_codes() {
+        assert_eq!(
+            sqlx::Error::PoolTimedOut.error_code(),
+            IndexerErrorCode::DatabaseUnavailable
+        );
+        assert!(sqlx::Error::PoolTimedOut.retriable());
+
+        assert_eq!(
+            sqlx::Error::RowNotFound.error_code(),
+            IndexerErrorCode::DatabaseQuery
+        );
+        assert!(!sqlx::Error::RowNotFound.retriable());
+    }
+
+    #[test]
+    fn test_tonic_status_codes() {
+        assert!(Status::unavailable("aggregator down").retriable());
+        assert!(!Status::invalid_argument("invalid receipt").retriable());
+    }
+
+    #[test]
+    fn test_anyhow_uses_error_chain() {
+        let error = Err::<(), _>(sqlx::Error::PoolClosed)
+            .context("Failed to store RAV")
+            .unwrap_err();
+        assert_eq!(error.error_code(), IndexerErrorCode::DatabaseUnavailable);
+
+        let error = anyhow::anyhow!("Something unexpected");
+        assert_eq!(error.error_code(), IndexerErrorCode::Unknown);
+        assert!(error.retriable());
+    }
+
+    #[test]
+    fn test_code_format() {
+        assert_eq!(IndexerErrorCode::ReceiptNotFound.to_string(), "IE001");
+        assert_eq!(IndexerErrorCode::Unknown.to_string(), "IE099");
+    }
+}
@@ -9,7 +9,14 @@ use tap_core::{
     },
     signed_message::SignatureBytes,
 };
-use thegraph_core::alloy::{dyn_abi::Eip712Domain, primitives::Address, signers::Signature};
+use thegraph_core::alloy::{
+    dyn_abi::Eip712Domain,
+    primitives::{Address, B256},
+    signers::Signature,
+    sol_types::SolStruct,
+};
+
+pub mod store;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TapReceipt {
@@ -145,6 +152,14 @@ impl TapReceipt {
             TapReceipt::V2(receipt) => receipt.recover_signer(domain_separator),
         }
     }
+
+    /// EIP-712 hash of the receipt, which is signed
+    pub fn signing_hash(&self, domain_separator: &Eip712Domain) -> B256 {
+        match self {
+            TapReceipt::V1(receipt) => receipt.message.eip712_signing_hash(domain_separator),
+            TapReceipt::V2(receipt) => receipt.message.eip712_signing_hash(domain_separator),
+        }
+    }
 }
 
 impl WithValueAndTimestamp for TapReceipt {
@@ -173,3 +188,103 @@ impl WithUniqueId for TapReceipt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use tap_core::{signed_message::Eip712SignedMessage, tap_eip712_domain};
+    use test_assets::{
+        INDEXER_ADDRESS, TAP_EIP712_DOMAIN, TAP_SENDER, TAP_SIGNER, VERIFIER_ADDRESS,
+    };
+    use thegraph_core::alloy::{primitives::Address, signers::Signature};
+
+    use super::TapReceipt;
+
+    /// The same receipt in both versions, signed for [TAP_EIP712_DOMAIN]
+    fn receipts(
+        allocation_id: Address,
+        nonce: u64,
+        timestamp_ns: u64,
+        value: u128,
+    ) -> [TapReceipt; 2] {
+        let v1 = tap_graph::Receipt {
+            allocation_id,
+            nonce,
+            timestamp_ns,
+            value,
+        };
+        let v2 = tap_graph::v2::Receipt {
+            payer: TAP_SENDER.1,
+            service_provider: INDEXER_ADDRESS,
+            data_service: Address::ZERO,
+            allocation_id,
+            nonce,
+            timestamp_ns,
+            value,
+        };
+        [
+            TapReceipt::V1(
+                Eip712SignedMessage::new(&TAP_EIP712_DOMAIN, v1, &TAP_SIGNER.0).unwrap(),
+            ),
+            TapReceipt::V2(
+                Eip712SignedMessage::new(&TAP_EIP712_DOMAIN, v2, &TAP_SIGNER.0).unwrap(),
+            ),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_recover_signer(
+            allocation_id in any::<[u8; 20]>().prop_map(Address::from),
+            nonce in any::<u64>(),
+            timestamp_ns in any::<u64>(),
+            value in any::<u128>(),
+        ) {
+            for receipt in receipts(allocation_id, nonce, timestamp_ns, value) {
+                prop_assert_eq!(receipt.recover_signer(&TAP_EIP712_DOMAIN).unwrap(), TAP_SIGNER.1);
+            }
+        }
+
+        #[test]
+        fn test_recover_signer_with_another_domain(
+            chain_id in any::<u64>(),
+            verifier in any::<[u8; 20]>().prop_map(Address::from),
+            allocation_id in any::<[u8; 20]>().prop_map(Address::from),
+            nonce in any::<u64>(),
+        ) {
+            prop_assume!(chain_id != 1 || verifier != VERIFIER_ADDRESS);
+            let domain = tap_eip712_domain(chain_id, verifier);
+            for receipt in receipts(allocation_id, nonce, 0, 1) {
+                prop_assert_ne!(
+                    receipt.signing_hash(&domain),
+                    receipt.signing_hash(&TAP_EIP712_DOMAIN)
+                );
+                // the signature is valid for another signer
+                prop_assert_ne!(receipt.recover_signer(&domain).ok(), Some(TAP_SIGNER.1));
+            }
+        }
+
+        #[test]
+        fn test_recover_signer_of_arbitrary_signature(
+            signature in prop::collection::vec(any::<u8>(), 65),
+            chain_id in any::<u64>(),
+            verifier in any::<[u8; 20]>().prop_map(Address::from),
+        ) {
+            let Ok(signature) = Signature::try_from(signature.as_slice()) else {
+                return Ok(());
+            };
+            let domain = tap_eip712_domain(chain_id, verifier);
+            let [TapReceipt::V1(v1), TapReceipt::V2(v2)] = receipts(Address::ZERO, 0, 0, 1) else {
+                unreachable!();
+            };
+            for receipt in [
+                TapReceipt::V1(Eip712SignedMessage { signature, ..v1 }),
+                TapReceipt::V2(Eip712SignedMessage { signature, ..v2 }),
+            ] {
+                let _ = receipt.recover_signer(&domain);
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Storage of the receipts accepted by indexer-service
+//!
+//! [PgReceiptStore] writes them to the TAP tables of the indexer database,
+//! where tap-agent aggregates them into RAVs. `SqliteReceiptStore`, built
+//! with the `sqlite` feature, keeps them in a local file instead, so the
+//! service can run against a devnet without the tables created by
+//! indexer-agent migrations.
+
+use thegraph_core::alloy::primitives::Address;
+use uuid::Uuid;
+
+use crate::TapReceipt;
+
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use postgres::PgReceiptStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteReceiptStore;
+
+/// Receipt accepted by the service, along with the address it is stored
+/// for: the key that signed it, or the contract wallet the key signs for
+#[derive(Debug, Clone)]
+pub struct StoredReceipt {
+    pub receipt: TapReceipt,
+    pub signer: Address,
+    /// Request the receipt paid for, if known. Retries of the request are
+    /// sent with the same id and the same receipts
+    pub request_id: Option<Uuid>,
+}
+
+#[async_trait::async_trait]
+pub trait ReceiptStore: Send + Sync {
+    /// Stores the receipts, skipping the ones already stored, and returns
+    /// the number of receipts stored
+    async fn store_receipts(&self, receipts: Vec<StoredReceipt>) -> anyhow::Result<u64>;
+
+    /// Deletes the stored receipts with these signatures
+    async fn discard_receipts(&self, signatures: &[Vec<u8>]) -> anyhow::Result<()>;
+
+    /// Whether the receipt was stored, e.g. by another replica of the service
+    async fn contains_receipt(&self, receipt: &TapReceipt) -> anyhow::Result<bool>;
+
+    /// Request the stored receipt paid for, if the receipt and its request
+    /// id were stored
+    async fn receipt_request_id(&self, receipt: &TapReceipt) -> anyhow::Result<Option<Uuid>>;
+
+    /// Whether the request ids are stored along with the receipts, the
+    /// receipts of a request paid with more than one of them can't be told
+    /// apart from the ones of its retries otherwise
+    fn stores_request_ids(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Context;
+use bigdecimal::num_bigint::BigInt;
+use sqlx::{types::BigDecimal, PgPool};
+use tap_core::receipt::WithValueAndTimestamp;
+use thegraph_core::alloy::hex::ToHexExt;
+use uuid::Uuid;
+
+use super::{ReceiptStore, StoredReceipt};
+use crate::TapReceipt;
+
+/// Receipts stored in `scalar_tap_receipts` (v1) and
+/// `tap_horizon_receipts` (v2)
+#[derive(Clone)]
+pub struct PgReceiptStore {
+    pgpool: PgPool,
+    request_ids: bool,
+}
+
+impl PgReceiptStore {
+    pub fn new(pgpool: PgPool) -> Self {
+        Self {
+            pgpool,
+            request_ids: true,
+        }
+    }
+
+    /// Whether the `request_id` columns exist. They are added by a migration
+    /// of this repository that indexer-agent may not have run yet, the
+    /// request ids are not stored without them.
+    pub fn with_request_ids(mut self, request_ids: bool) -> Self {
+        self.request_ids = request_ids;
+        self
+    }
+
+    async fn store_receipts_v1(&self, receipts: Vec<StoredReceipt>) -> anyhow::Result<u64> {
+        if receipts.is_empty() {
+            return Ok(0);
+        }
+        let receipts_len = receipts.len();
+        let mut signers = Vec::with_capacity(receipts_len);
+        let mut signatures = Vec::with_capacity(receipts_len);
+        let mut allocation_ids = Vec::with_capacity(receipts_len);
+        let mut timestamps = Vec::with_capacity(receipts_len);
+        let mut nonces = Vec::with_capacity(receipts_len);
+        let mut values = Vec::with_capacity(receipts_len);
+        let mut request_ids = Vec::with_capacity(receipts_len);
+
+        for StoredReceipt {
+            receipt,
+            signer,
+            request_id,
+        } in receipts
+        {
+            let Some(receipt) = receipt.as_v1() else {
+                continue;
+            };
+            signers.push(signer.encode_hex());
+            signatures.push(receipt.signature.as_bytes().to_vec());
+            allocation_ids.push(receipt.message.allocation_id.encode_hex());
+            timestamps.push(BigDecimal::from(receipt.timestamp_ns()));
+            nonces.push(BigDecimal::from(receipt.message.nonce));
+            values.push(BigDecimal::from(BigInt::from(receipt.value())));
+            request_ids.push(request_id);
+        }
+        // receipts already stored by another replica are dropped
+        if !self.request_ids {
+            let stored = sqlx::query!(
+                r#"INSERT INTO scalar_tap_receipts (
+                    signer_address,
+                    signature,
+                    allocation_id,
+                    timestamp_ns,
+                    nonce,
+                    value
+                ) SELECT * FROM UNNEST(
+                    $1::CHAR(40)[],
+                    $2::BYTEA[],
+                    $3::CHAR(40)[],
+                    $4::NUMERIC(20)[],
+                    $5::NUMERIC(20)[],
+                    $6::NUMERIC(40)[]
+                )
+                ON CONFLICT DO NOTHING"#,
+                &signers,
+                &signatures,
+                &allocation_ids,
+                &timestamps,
+                &nonces,
+                &values
+            )
+            .execute(&self.pgpool)
+            .await
+            .context("Failed to store v1 receipts")?
+            .rows_affected();
+            return Ok(stored);
+        }
+        let stored = sqlx::query!(
+            r#"INSERT INTO scalar_tap_receipts (
+                signer_address,
+                signature,
+                allocation_id,
+                timestamp_ns,
+                nonce,
+                value,
+                request_id
+            ) SELECT * FROM UNNEST(
+                $1::CHAR(40)[],
+                $2::BYTEA[],
+                $3::CHAR(40)[],
+                $4::NUMERIC(20)[],
+                $5::NUMERIC(20)[],
+                $6::NUMERIC(40)[],
+                $7::UUID[]
+            )
+            ON CONFLICT DO NOTHING"#,
+            &signers,
+            &signatures,
+            &allocation_ids,
+            &timestamps,
+            &nonces,
+            &values,
+            &request_ids[..] as &[Option<Uuid>]
+        )
+        .execute(&self.pgpool)
+        .await
+        .context("Failed to store v1 receipts")?
+        .rows_affected();
+
+        Ok(stored)
+    }
+
+    async fn store_receipts_v2(&self, receipts: Vec<StoredReceipt>) -> anyhow::Result<u64> {
+        if receipts.is_empty() {
+            return Ok(0);
+        }
+        let receipts_len = receipts.len();
+        let mut signers = Vec::with_capacity(receipts_len);
+        let mut signatures = Vec::with_capacity(receipts_len);
+        let mut allocation_ids = Vec::with_capacity(receipts_len);
+        let mut payers = Vec::with_capacity(receipts_len);
+        let mut data_services = Vec::with_capacity(receipts_len);
+        let mut service_providers = Vec::with_capacity(receipts_len);
+        let mut timestamps = Vec::with_capacity(receipts_len);
+        let mut nonces = Vec::with_capacity(receipts_len);
+        let mut values = Vec::with_capacity(receipts_len);
+        let mut request_ids = Vec::with_capacity(receipts_len);
+
+        for StoredReceipt {
+            receipt,
+            signer,
+            request_id,
+        } in receipts
+        {
+            let Some(receipt) = receipt.as_v2() else {
+                continue;
+            };
+            signers.push(signer.encode_hex());
+            signatures.push(receipt.signature.as_bytes().to_vec());
+            allocation_ids.push(receipt.message.allocation_id.encode_hex());
+            payers.push(receipt.message.payer.encode_hex());
+            data_services.push(receipt.message.data_service.encode_hex());
+            service_providers.push(receipt.message.service_provider.encode_hex());
+            timestamps.push(BigDecimal::from(receipt.timestamp_ns()));
+            nonces.push(BigDecimal::from(receipt.message.nonce));
+            values.push(BigDecimal::from(BigInt::from(receipt.value())));
+            request_ids.push(request_id);
+        }
+        if !self.request_ids {
+            let stored = sqlx::query!(
+                r#"INSERT INTO tap_horizon_receipts (
+                    signer_address,
+                    signature,
+                    allocation_id,
+                    payer,
+                    data_service,
+                    service_provider,
+                    timestamp_ns,
+                    nonce,
+                    value
+                ) SELECT * FROM UNNEST(
+                    $1::CHAR(40)[],
+                    $2::BYTEA[],
+                    $3::CHAR(40)[],
+                    $4::CHAR(40)[],
+                    $5::CHAR(40)[],
+                    $6::CHAR(40)[],
+                    $7::NUMERIC(20)[],
+                    $8::NUMERIC(20)[],
+                    $9::NUMERIC(40)[]
+                )
+                ON CONFLICT DO NOTHING"#,
+                &signers,
+                &signatures,
+                &allocation_ids,
+                &payers,
+                &data_services,
+                &service_providers,
+                &timestamps,
+                &nonces,
+                &values
+            )
+            .execute(&self.pgpool)
+            .await
+            .context("Failed to store v2 receipts")?
+            .rows_affected();
+            return Ok(stored);
+        }
+        let stored = sqlx::query!(
+            r#"INSERT INTO tap_horizon_receipts (
+                signer_address,
+                signature,
+                allocation_id,
+                payer,
+                data_service,
+                service_provider,
+                timestamp_ns,
+                nonce,
+                value,
+                request_id
+            ) SELECT * FROM UNNEST(
+                $1::CHAR(40)[],
+                $2::BYTEA[],
+                $3::CHAR(40)[],
+                $4::CHAR(40)[],
+                $5::CHAR(40)[],
+                $6::CHAR(40)[],
+                $7::NUMERIC(20)[],
+                $8::NUMERIC(20)[],
+                $9::NUMERIC(40)[],
+                $10::UUID[]
+            )
+            ON CONFLICT DO NOTHING"#,
+            &signers,
+            &signatures,
+            &allocation_ids,
+            &payers,
+            &data_services,
+            &service_providers,
+            &timestamps,
+            &nonces,
+            &values,
+            &request_ids[..] as &[Option<Uuid>]
+        )
+        .execute(&self.pgpool)
+        .await
+        .context("Failed to store v2 receipts")?
+        .rows_affected();
+
+        Ok(stored)
+    }
+}
+
+#[async_trait::async_trait]
+impl ReceiptStore for PgReceiptStore {
+    async fn store_receipts(&self, receipts: Vec<StoredReceipt>) -> anyhow::Result<u64> {
+        let (v1_receipts, v2_receipts): (Vec<_>, Vec<_>) = receipts
+            .into_iter()
+            .partition(|stored| matches!(stored.receipt, TapReceipt::V1(_)));
+        let (stored_v1, stored_v2) = tokio::join!(
+            self.store_receipts_v1(v1_receipts),
+            self.store_receipts_v2(v2_receipts)
+        );
+        match (stored_v1, stored_v2) {
+            (Ok(stored_v1), Ok(stored_v2)) => Ok(stored_v1 + stored_v2),
+            (Err(e1), Err(e2)) => Err(anyhow::anyhow!("{e1:#}. {e2:#}")),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }
+    }
+
+    async fn discard_receipts(&self, signatures: &[Vec<u8>]) -> anyhow::Result<()> {
+        sqlx::query!(
+            "DELETE FROM scalar_tap_receipts WHERE signature = ANY($1)",
+            signatures
+        )
+        .execute(&self.pgpool)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM tap_horizon_receipts WHERE signature = ANY($1)",
+            signatures
+        )
+        .execute(&self.pgpool)
+        .await?;
+        Ok(())
+    }
+
+    async fn contains_receipt(&self, receipt: &TapReceipt) -> anyhow::Result<bool> {
+        let signature = receipt.signature().as_bytes().to_vec();
+        let timestamp_ns = BigDecimal::from(receipt.timestamp_ns());
+        let exists = match receipt {
+            TapReceipt::V1(_) => {
+                sqlx::query_scalar!(
+                    r#"SELECT EXISTS(SELECT 1 FROM scalar_tap_receipts
+                    WHERE signature = $1 AND timestamp_ns = $2) AS "exists!""#,
+                    signature,
+                    timestamp_ns
+                )
+                .fetch_one(&self.pgpool)
+                .await?
+            }
+            TapReceipt::V2(_) => {
+                sqlx::query_scalar!(
+                    r#"SELECT EXISTS(SELECT 1 FROM tap_horizon_receipts
+                    WHERE signature = $1 AND timestamp_ns = $2) AS "exists!""#,
+                    signature,
+                    timestamp_ns
+                )
+                .fetch_one(&self.pgpool)
+                .await?
+            }
+        };
+        Ok(exists)
+    }
+
+    async fn receipt_request_id(&self, receipt: &TapReceipt) -> anyhow::Result<Option<Uuid>> {
+        if !self.request_ids {
+            return Ok(None);
+        }
+        let signature = receipt.signature().as_bytes().to_vec();
+        let timestamp_ns = BigDecimal::from(receipt.timestamp_ns());
+        let request_id = match receipt {
+            TapReceipt::V1(_) => {
+                sqlx::query_scalar!(
+                    "SELECT request_id FROM scalar_tap_receipts \
+                    WHERE signature = $1 AND timestamp_ns = $2",
+                    signature,
+                    timestamp_ns
+                )
+                .fetch_optional(&self.pgpool)
+                .await?
+            }
+            TapReceipt::V2(_) => {
+                sqlx::query_scalar!(
+                    "SELECT request_id FROM tap_horizon_receipts \
+                    WHERE signature = $1 AND timestamp_ns = $2",
+                    signature,
+                    timestamp_ns
+                )
+                .fetch_optional(&self.pgpool)
+                .await?
+            }
+        };
+        Ok(request_id.flatten())
+    }
+
+    fn stores_request_ids(&self) -> bool {
+        self.request_ids
+    }
+}
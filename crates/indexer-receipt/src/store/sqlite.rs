@@ -0,0 +1,218 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    SqlitePool,
+};
+use tap_core::receipt::WithValueAndTimestamp;
+use thegraph_core::alloy::hex::ToHexExt;
+use uuid::Uuid;
+
+use super::{ReceiptStore, StoredReceipt};
+use crate::TapReceipt;
+
+/// Both receipt versions share a table, the v2 fields are NULL for v1
+/// receipts. Nonces and values don't fit in an INTEGER and are stored as
+/// decimal strings.
+///
+/// The offline data of sqlx is for the Postgres database, so the queries of
+/// this store are checked at runtime.
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS tap_receipts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        version INTEGER NOT NULL,
+        signer_address TEXT NOT NULL,
+        signature BLOB NOT NULL UNIQUE,
+        allocation_id TEXT NOT NULL,
+        payer TEXT,
+        data_service TEXT,
+        service_provider TEXT,
+        timestamp_ns INTEGER NOT NULL,
+        nonce TEXT NOT NULL,
+        value TEXT NOT NULL,
+        request_id TEXT
+    );
+    CREATE INDEX IF NOT EXISTS tap_receipts_allocation_id_idx
+        ON tap_receipts (allocation_id, signer_address);
+"#;
+
+/// Receipts stored in a local SQLite file, for development and tests
+///
+/// Nothing reads them back to request RAVs, so they are never redeemed.
+#[derive(Clone)]
+pub struct SqliteReceiptStore {
+    pool: SqlitePool,
+}
+
+impl SqliteReceiptStore {
+    /// Opens the file at `path`, creating it and its table if needed
+    pub async fn connect(path: &Path) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        Self::open(options, 4).await
+    }
+
+    async fn open(options: SqliteConnectOptions, max_connections: u32) -> anyhow::Result<Self> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+        sqlx::raw_sql(SCHEMA).execute(&pool).await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait::async_trait]
+impl ReceiptStore for SqliteReceiptStore {
+    async fn store_receipts(&self, receipts: Vec<StoredReceipt>) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut stored = 0;
+        for StoredReceipt {
+            receipt,
+            signer,
+            request_id,
+        } in receipts
+        {
+            let (version, payer, data_service, service_provider) = match &receipt {
+                TapReceipt::V1(_) => (1, None, None, None),
+                TapReceipt::V2(receipt) => (
+                    2,
+                    Some(receipt.message.payer.encode_hex()),
+                    Some(receipt.message.data_service.encode_hex()),
+                    Some(receipt.message.service_provider.encode_hex()),
+                ),
+            };
+            stored += sqlx::query(
+                r#"
+                    INSERT OR IGNORE INTO tap_receipts (
+                        version,
+                        signer_address,
+                        signature,
+                        allocation_id,
+                        payer,
+                        data_service,
+                        service_provider,
+                        timestamp_ns,
+                        nonce,
+                        value,
+                        request_id
+                    ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(version)
+            .bind(signer.encode_hex())
+            .bind(receipt.signature().as_bytes().to_vec())
+            .bind(receipt.allocation_id().encode_hex())
+            .bind(payer)
+            .bind(data_service)
+            .bind(service_provider)
+            .bind(i64::try_from(receipt.timestamp_ns())?)
+            .bind(receipt.nonce().to_string())
+            .bind(receipt.value().to_string())
+            .bind(request_id.map(|request_id| request_id.to_string()))
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(stored)
+    }
+
+    async fn discard_receipts(&self, signatures: &[Vec<u8>]) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for signature in signatures {
+            sqlx::query("DELETE FROM tap_receipts WHERE signature = ?")
+                .bind(signature)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn contains_receipt(&self, receipt: &TapReceipt) -> anyhow::Result<bool> {
+        let exists =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM tap_receipts WHERE signature = ?)")
+                .bind(receipt.signature().as_bytes().to_vec())
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(exists)
+    }
+
+    async fn receipt_request_id(&self, receipt: &TapReceipt) -> anyhow::Result<Option<Uuid>> {
+        let request_id: Option<Option<String>> =
+            sqlx::query_scalar("SELECT request_id FROM tap_receipts WHERE signature = ?")
+                .bind(receipt.signature().as_bytes().to_vec())
+                .fetch_optional(&self.pool)
+                .await?;
+        request_id
+            .flatten()
+            .map(|request_id| Uuid::parse_str(&request_id))
+            .transpose()
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::sqlite::SqliteConnectOptions;
+    use test_assets::{
+        create_signed_receipt, create_signed_receipt_v2, SignedReceiptRequest, TAP_SIGNER,
+    };
+    use uuid::Uuid;
+
+    use super::SqliteReceiptStore;
+    use crate::{
+        store::{ReceiptStore, StoredReceipt},
+        TapReceipt,
+    };
+
+    #[tokio::test]
+    async fn test_store_and_discard_receipts() {
+        // a single connection, every connection opens its own in-memory database
+        let options = SqliteConnectOptions::from_str("sqlite::memory:").unwrap();
+        let store = SqliteReceiptStore::open(options, 1).await.unwrap();
+
+        let v1 = TapReceipt::V1(
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+        );
+        let v2 = TapReceipt::V2(create_signed_receipt_v2().nonce(2).call().await);
+        let request_id = Uuid::now_v7();
+        let stored = |receipt: &TapReceipt| StoredReceipt {
+            receipt: receipt.clone(),
+            signer: TAP_SIGNER.1,
+            request_id: Some(request_id).filter(|_| matches!(receipt, TapReceipt::V1(_))),
+        };
+
+        assert_eq!(
+            store
+                .store_receipts(vec![stored(&v1), stored(&v2)])
+                .await
+                .unwrap(),
+            2
+        );
+        // receipts stored before are skipped
+        assert_eq!(store.store_receipts(vec![stored(&v1)]).await.unwrap(), 0);
+        assert!(store.contains_receipt(&v1).await.unwrap());
+        assert!(store.contains_receipt(&v2).await.unwrap());
+        assert_eq!(
+            store.receipt_request_id(&v1).await.unwrap(),
+            Some(request_id)
+        );
+        assert_eq!(store.receipt_request_id(&v2).await.unwrap(), None);
+
+        store
+            .discard_receipts(&[v1.signature().as_bytes().to_vec()])
+            .await
+            .unwrap();
+        assert!(!store.contains_receipt(&v1).await.unwrap());
+        assert!(store.contains_receipt(&v2).await.unwrap());
+    }
+}
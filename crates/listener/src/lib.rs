@@ -0,0 +1,393 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Listeners of the HTTP servers
+//!
+//! The servers listen on a TCP port unless configured to use a Unix domain
+//! socket, e.g. for a sidecar proxy or to keep the metrics private, or a
+//! socket passed by systemd socket activation.
+//!
+//! A server can listen on several addresses, IPv4 and IPv6. An IPv6 address
+//! accepts the IPv4 clients too, unless the server also listens on an IPv4
+//! address.
+
+use std::{
+    convert::Infallible,
+    fmt,
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::unix::{
+        fs::FileTypeExt,
+        io::{FromRawFd, IntoRawFd, RawFd},
+    },
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{anyhow, ensure, Context};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    response::Response,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use indexer_config::ListenerConfig;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::watch,
+};
+use tower::{Service, ServiceExt};
+
+/// Connections waiting to be accepted, the default of the standard library
+const BACKLOG: i32 = 128;
+
+/// First file descriptor passed by systemd, see `sd_listen_fds(3)`
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// `ConnectInfo` of the connections on a Unix domain socket, which have no
+/// peer address
+const UNIX_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the socket configured in `config`, or `host_and_port` if none is
+    pub async fn bind(config: &ListenerConfig, host_and_port: SocketAddr) -> anyhow::Result<Self> {
+        if let Some(path) = &config.unix_socket_path {
+            return bind_unix(path);
+        }
+        if let Some(name) = &config.systemd_socket_name {
+            return systemd_listener(name);
+        }
+        bind_tcp(host_and_port, false)
+    }
+
+    /// Binds the socket configured in `config`, or every address of
+    /// `hosts_and_ports` if none is
+    pub async fn bind_all(
+        config: &ListenerConfig,
+        hosts_and_ports: &[SocketAddr],
+    ) -> anyhow::Result<Vec<Self>> {
+        if config.unix_socket_path.is_some() || config.systemd_socket_name.is_some() {
+            return Ok(vec![Self::bind(config, hosts_and_ports[0]).await?]);
+        }
+        // the IPv4 clients are accepted by the IPv4 addresses then
+        let only_v6 = hosts_and_ports.iter().any(SocketAddr::is_ipv4);
+        hosts_and_ports
+            .iter()
+            .map(|host_and_port| bind_tcp(*host_and_port, only_v6))
+            .collect()
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "tcp socket"),
+            },
+            Listener::Unix(listener) => {
+                match listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                {
+                    Some(path) => write!(f, "unix:{path}"),
+                    None => write!(f, "unix socket"),
+                }
+            }
+        }
+    }
+}
+
+/// Binds a TCP socket, an IPv6 one accepting the IPv4 clients too unless
+/// `only_v6`
+fn bind_tcp(host_and_port: SocketAddr, only_v6: bool) -> anyhow::Result<Listener> {
+    let bind = || -> io::Result<TcpListener> {
+        let socket = Socket::new(
+            Domain::for_address(host_and_port),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        if host_and_port.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        // rebind right away after a restart, as `TcpListener::bind` does
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&host_and_port.into())?;
+        socket.listen(BACKLOG)?;
+        TcpListener::from_std(socket.into())
+    };
+    bind()
+        .map(Listener::Tcp)
+        .with_context(|| format!("Failed to bind to {host_and_port}"))
+}
+
+fn bind_unix(path: &Path) -> anyhow::Result<Listener> {
+    // the socket of a previous run is not removed when it stops
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove the socket {}", path.display()))?;
+    }
+    UnixListener::bind(path)
+        .map(Listener::Unix)
+        .with_context(|| format!("Failed to bind to {}", path.display()))
+}
+
+/// The socket named `name` passed by systemd, see `sd_listen_fds_with_names(3)`
+fn systemd_listener(name: &str) -> anyhow::Result<Listener> {
+    let pid = std::env::var("LISTEN_PID")
+        .context("LISTEN_PID is not set, the process is not socket activated")?;
+    ensure!(
+        pid.parse::<u32>().ok() == Some(std::process::id()),
+        "The sockets passed by systemd are for process {pid}"
+    );
+    let fds: usize = std::env::var("LISTEN_FDS")
+        .context("LISTEN_FDS is not set")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+    let index = std::env::var("LISTEN_FDNAMES")
+        .unwrap_or_default()
+        .split(':')
+        .position(|fd_name| fd_name == name)
+        .filter(|index| *index < fds)
+        .ok_or_else(|| anyhow!("No socket named {name} was passed by systemd"))?;
+    let fd = SD_LISTEN_FDS_START + index as RawFd;
+
+    // SAFETY: the file descriptors from SD_LISTEN_FDS_START are the listening
+    // sockets passed by systemd, owned by this process
+    let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    // the address of a TCP socket is not a Unix domain socket address
+    if listener.local_addr().is_ok() {
+        listener.set_nonblocking(true)?;
+        return Ok(Listener::Unix(UnixListener::from_std(listener)?));
+    }
+    // SAFETY: the same socket, which is not a Unix domain socket
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(listener.into_raw_fd()) };
+    listener.set_nonblocking(true)?;
+    Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+}
+
+/// Serves `service` until `shutdown` completes, then waits for the open
+/// connections to be closed
+///
+/// The requests get the peer address as `ConnectInfo<SocketAddr>`, the
+/// unspecified address on a Unix domain socket, so rate limiting relies
+/// on the `X-Forwarded-For` header of the proxy there.
+pub async fn serve<S>(
+    listener: Listener,
+    service: S,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    match listener {
+        Listener::Tcp(listener) => {
+            let make_service = axum::ServiceExt::<Request>::into_make_service_with_connect_info::<
+                SocketAddr,
+            >(service);
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        Listener::Unix(listener) => {
+            serve_unix(listener, service, shutdown).await;
+            Ok(())
+        }
+    }
+}
+
+/// Serves `service` on every listener until `shutdown` completes, see [serve]
+pub async fn serve_all<S>(
+    listeners: Vec<Listener>,
+    service: S,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown.await;
+        let _ = shutdown_tx.send(true);
+    });
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let mut shutdown_rx = shutdown_rx.clone();
+            tokio::spawn(serve(listener, service.clone(), async move {
+                let _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+            }))
+        })
+        .collect();
+    for server in servers {
+        server.await.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
+
+async fn serve_unix<S>(
+    listener: UnixListener,
+    service: S,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    // the connections are closed gracefully when the value changes, and
+    // hold a receiver until they are closed
+    let (close_tx, close_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    // e.g. too many open files, which may be closed soon
+                    tracing::warn!(%error, "Failed to accept a connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = service.clone();
+        let mut close_rx = close_rx.clone();
+        tokio::spawn(async move {
+            let hyper_service =
+                hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(UNIX_PEER));
+                    service.clone().oneshot(request.map(Body::new))
+                });
+            let builder = Builder::new(TokioExecutor::new());
+            let connection =
+                builder.serve_connection_with_upgrades(TokioIo::new(stream), hyper_service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = close_rx.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(error) = result {
+                tracing::debug!(%error, "Connection closed with an error");
+            }
+        });
+    }
+
+    drop(listener);
+    drop(close_rx);
+    let _ = close_tx.send(());
+    close_tx.closed().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use indexer_config::ListenerConfig;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UnixStream},
+        sync::oneshot,
+    };
+
+    use super::{serve, serve_all, Listener};
+
+    #[tokio::test]
+    async fn test_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("service.sock");
+        let config = ListenerConfig {
+            unix_socket_path: Some(path.clone()),
+            systemd_socket_name: None,
+        };
+        let listener = Listener::bind(&config, "0.0.0.0:0".parse().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(listener, Listener::Unix(_)));
+
+        let router = Router::new().route(
+            "/",
+            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.to_string() }),
+        );
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, router, async move {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("0.0.0.0:0"));
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        // the socket left by the previous server is replaced
+        Listener::bind(&config, "0.0.0.0:0".parse().unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_several_addresses() {
+        let addresses = [
+            "127.0.0.1:0".parse().unwrap(),
+            "127.0.0.1:0".parse().unwrap(),
+        ];
+        let listeners = Listener::bind_all(&ListenerConfig::default(), &addresses)
+            .await
+            .unwrap();
+        let local_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(listener) => listener.local_addr().unwrap(),
+                Listener::Unix(_) => panic!("Expected a TCP listener"),
+            })
+            .collect();
+
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_all(listeners, router, async move {
+            let _ = shutdown_rx.await;
+        }));
+
+        for addr in local_addrs {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        }
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
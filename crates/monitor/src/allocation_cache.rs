@@ -0,0 +1,342 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use indexer_query::allocation_query::{self, AllocationQuery, AllocationStatus};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use thegraph_core::{alloy::primitives::Address, DeploymentId};
+
+use crate::{client::SubgraphClient, AllocationWatcher, AttestationWatcher};
+
+lazy_static! {
+    static ref ALLOCATION_CACHE_MISSES: IntCounterVec = register_int_counter_vec!(
+        "indexer_allocation_cache_misses_total",
+        "Allocations of receipts missing from the cache, looked up in the network subgraph",
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+/// How long an allocation that is not one of the indexers', or that couldn't
+/// be looked up, is remembered as such, so the network subgraph isn't queried
+/// for every receipt
+const UNKNOWN_ALLOCATION_TTL: Duration = Duration::from_secs(60);
+
+/// Unknown allocations remembered at most, the ones expiring first are
+/// forgotten beyond, so receipts for random allocations can't exhaust the
+/// memory
+const MAX_UNKNOWN_ALLOCATIONS: usize = 10_000;
+
+/// Deployment and indexer of an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationEntry {
+    pub deployment: DeploymentId,
+    pub indexer: Address,
+    /// opened in a Horizon provision, its receipts are TAP v2 receipts
+    pub horizon: bool,
+}
+
+type Entries = HashMap<Address, AllocationEntry>;
+
+/// Allocations of the indexers served, kept up to date with the allocations
+/// watcher and read without locking
+///
+/// The allocations created since the watcher last read the network subgraph
+/// are looked up on demand, and kept until the next update of the watcher.
+/// They are only accepted once their attestation signer is derived, so their
+/// queries are never served without attestation.
+#[derive(Clone)]
+pub struct AllocationCache {
+    entries: Arc<ArcSwap<Entries>>,
+    /// Allocations looked up whose attestation signer is not derived yet
+    pending: Arc<Mutex<Entries>>,
+    unknown: Arc<Mutex<HashMap<Address, Instant>>>,
+    indexers: Arc<HashSet<Address>>,
+    network_subgraph: Option<&'static SubgraphClient>,
+    attestation_signers: Option<AttestationWatcher>,
+}
+
+impl AllocationCache {
+    /// Caches the allocations of `allocations`, the ones missing are looked
+    /// up in `network_subgraph`, if any, and kept if they belong to `indexers`
+    /// and have a signer in `attestation_signers`
+    pub fn new(
+        mut allocations: AllocationWatcher,
+        indexers: HashSet<Address>,
+        network_subgraph: Option<&'static SubgraphClient>,
+        attestation_signers: Option<AttestationWatcher>,
+    ) -> Self {
+        let entries = Arc::new(ArcSwap::from_pointee(Self::entries(&allocations)));
+        let pending: Arc<Mutex<Entries>> = Default::default();
+        let cache = Arc::downgrade(&entries);
+        let cache_pending = Arc::downgrade(&pending);
+        tokio::spawn(async move {
+            while allocations.changed().await.is_ok() {
+                let (Some(cache), Some(pending)) = (cache.upgrade(), cache_pending.upgrade())
+                else {
+                    break;
+                };
+                cache.store(Arc::new(Self::entries(&allocations)));
+                pending.lock().unwrap().clear();
+            }
+        });
+        Self {
+            entries,
+            pending,
+            unknown: Default::default(),
+            indexers: Arc::new(indexers),
+            network_subgraph,
+            attestation_signers,
+        }
+    }
+
+    fn entries(allocations: &AllocationWatcher) -> Entries {
+        allocations
+            .borrow()
+            .iter()
+            .map(|(id, allocation)| {
+                (
+                    *id,
+                    AllocationEntry {
+                        deployment: allocation.subgraph_deployment.id,
+                        indexer: allocation.indexer,
+                        horizon: allocation.provision.is_some(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Cached allocation, without querying the network subgraph
+    pub fn get(&self, allocation_id: &Address) -> Option<AllocationEntry> {
+        self.entries.load().get(allocation_id).copied()
+    }
+
+    /// Cached allocation, looked up in the network subgraph if missing
+    pub async fn lookup(&self, allocation_id: &Address) -> Option<AllocationEntry> {
+        if let Some(entry) = self.get(allocation_id) {
+            return Some(entry);
+        }
+        let network_subgraph = self.network_subgraph?;
+        let pending = self.pending.lock().unwrap().get(allocation_id).copied();
+        if let Some(entry) = pending {
+            return self.accept(allocation_id, entry);
+        }
+        {
+            let mut unknown = self.unknown.lock().unwrap();
+            let now = Instant::now();
+            unknown.retain(|_, expires_at| *expires_at > now);
+            if unknown.contains_key(allocation_id) {
+                return None;
+            }
+        }
+
+        let outcome = match self.query(network_subgraph, allocation_id).await {
+            Ok(Some(entry)) => {
+                ALLOCATION_CACHE_MISSES.with_label_values(&["found"]).inc();
+                return self.accept(allocation_id, entry);
+            }
+            Ok(None) => "unknown",
+            Err(error) => {
+                tracing::warn!(
+                    %error,
+                    %allocation_id,
+                    "Failed to look up an allocation in the network subgraph"
+                );
+                "error"
+            }
+        };
+        ALLOCATION_CACHE_MISSES.with_label_values(&[outcome]).inc();
+        let mut unknown = self.unknown.lock().unwrap();
+        if unknown.len() >= MAX_UNKNOWN_ALLOCATIONS {
+            let first_expiring = unknown
+                .iter()
+                .min_by_key(|(_, expires_at)| **expires_at)
+                .map(|(id, _)| *id);
+            if let Some(id) = first_expiring {
+                unknown.remove(&id);
+            }
+        }
+        unknown.insert(*allocation_id, Instant::now() + UNKNOWN_ALLOCATION_TTL);
+        None
+    }
+
+    /// Caches an allocation looked up, once its attestation signer is derived
+    fn accept(&self, allocation_id: &Address, entry: AllocationEntry) -> Option<AllocationEntry> {
+        let signed = self
+            .attestation_signers
+            .as_ref()
+            .map_or(true, |signers| signers.borrow().contains_key(allocation_id));
+        if !signed {
+            self.pending.lock().unwrap().insert(*allocation_id, entry);
+            return None;
+        }
+        self.pending.lock().unwrap().remove(allocation_id);
+        self.entries.rcu(|entries| {
+            let mut entries = Entries::clone(entries);
+            entries.insert(*allocation_id, entry);
+            entries
+        });
+        Some(entry)
+    }
+
+    /// The allocation, if it's an active allocation of one of the indexers
+    async fn query(
+        &self,
+        network_subgraph: &SubgraphClient,
+        allocation_id: &Address,
+    ) -> anyhow::Result<Option<AllocationEntry>> {
+        let response = network_subgraph
+            .query::<AllocationQuery, _>(allocation_query::Variables {
+                id: allocation_id.to_string().to_ascii_lowercase(),
+            })
+            .await?;
+        let Some(allocation) = response?.allocation else {
+            return Ok(None);
+        };
+        let indexer = Address::from_str(&allocation.indexer.id)?;
+        if !matches!(allocation.status, AllocationStatus::Active)
+            || !self.indexers.contains(&indexer)
+        {
+            return Ok(None);
+        }
+        Ok(Some(AllocationEntry {
+            deployment: DeploymentId::from_str(&allocation.subgraph_deployment.id)?,
+            indexer,
+            horizon: allocation.provision.is_some(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use indexer_attestation::AttestationSigner;
+    use serde_json::json;
+    use test_assets::{
+        DISPUTE_MANAGER_ADDRESS, INDEXER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC,
+        NETWORK_SUBGRAPH_DEPLOYMENT,
+    };
+    use thegraph_core::alloy::primitives::Address;
+    use tokio::sync::watch;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{AllocationCache, AllocationEntry};
+    use crate::client::{DeploymentDetails, SubgraphClient};
+
+    #[tokio::test]
+    async fn test_allocation_cache() {
+        let new_allocation = Address::repeat_byte(0x42);
+        let other_allocation = Address::repeat_byte(0x43);
+        let deployment = NETWORK_SUBGRAPH_DEPLOYMENT;
+
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains(
+                        new_allocation.to_string().to_ascii_lowercase(),
+                    ))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                        "data": {
+                            "allocation": {
+                                "id": new_allocation.to_string().to_ascii_lowercase(),
+                                "status": "Active",
+                                "indexer": { "id": INDEXER_ADDRESS.to_string() },
+                                "subgraphDeployment": { "id": deployment.to_string() },
+                                "provision": { "id": "0x01-0x02" },
+                            }
+                        }
+                    })))
+                    // kept while waiting for its signer
+                    .expect(1),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains(
+                        other_allocation.to_string().to_ascii_lowercase(),
+                    ))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_json(json!({ "data": { "allocation": null } })),
+                    )
+                    .expect(1),
+            )
+            .await;
+        let network_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+
+        let (allocations_tx, allocations) = watch::channel(INDEXER_ALLOCATIONS.clone());
+        let (signers_tx, signers) = watch::channel(HashMap::new());
+        let cache = AllocationCache::new(
+            allocations,
+            HashSet::from([INDEXER_ADDRESS]),
+            Some(network_subgraph),
+            Some(signers),
+        );
+
+        let (id, allocation) = INDEXER_ALLOCATIONS.iter().next().unwrap();
+        assert_eq!(
+            cache.get(id),
+            Some(AllocationEntry {
+                deployment: allocation.subgraph_deployment.id,
+                indexer: allocation.indexer,
+                horizon: false,
+            })
+        );
+
+        // created after the allocations were read, refused until it can be
+        // attested
+        assert_eq!(cache.get(&new_allocation), None);
+        assert_eq!(cache.lookup(&new_allocation).await, None);
+        let signer = AttestationSigner::new(
+            &INDEXER_MNEMONIC.to_string(),
+            allocation,
+            1,
+            DISPUTE_MANAGER_ADDRESS,
+        )
+        .unwrap();
+        signers_tx.send_modify(|signers| {
+            signers.insert(new_allocation, signer);
+        });
+        assert_eq!(
+            cache.lookup(&new_allocation).await,
+            Some(AllocationEntry {
+                deployment,
+                indexer: INDEXER_ADDRESS,
+                horizon: true,
+            })
+        );
+        assert!(cache.get(&new_allocation).is_some());
+
+        // the unknown allocations are only queried once
+        assert_eq!(cache.lookup(&other_allocation).await, None);
+        assert_eq!(cache.lookup(&other_allocation).await, None);
+
+        // replaced on the next update of the watcher
+        allocations_tx.send(HashMap::new()).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(cache.get(id), None);
+        assert_eq!(cache.get(&new_allocation), None);
+    }
+}
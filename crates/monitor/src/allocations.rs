@@ -2,44 +2,221 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use indexer_allocation::Allocation;
+use anyhow::bail;
+use indexer_allocation::{Allocation, AllocationId};
 use indexer_query::allocations_query::{self, AllocationsQuery};
-use indexer_watcher::new_watcher;
+use indexer_watcher::{join_and_map_watcher, map_watcher, Watcher};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
 use thegraph_core::alloy::primitives::{Address, TxHash};
 use tokio::sync::watch::Receiver;
 
-use crate::client::SubgraphClient;
+use crate::{client::SubgraphClient, subgraph_cache::SubgraphCache};
+
+lazy_static! {
+    static ref ALLOCATIONS_BLOCK_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_allocations_block_lag",
+        "Blocks the last allocations read from the network subgraph lag behind the latest block seen",
+        &["indexer"]
+    )
+    .unwrap();
+    static ref STALE_ALLOCATIONS: IntCounterVec = register_int_counter_vec!(
+        "indexer_allocations_stale_total",
+        "Allocations read from the network subgraph ignored because they were stale",
+        &["indexer"]
+    )
+    .unwrap();
+}
 
 /// Receiver of Map between allocation id and allocation struct
+///
+/// Allocations are keyed by their address, which is also the address of the
+/// collection id of a Horizon allocation.
 pub type AllocationWatcher = Receiver<HashMap<Address, Allocation>>;
 
+/// Receiver of the ids of the allocations, telling the legacy ones from the
+/// Horizon ones
+pub type AllocationIdsWatcher = Receiver<HashSet<AllocationId>>;
+
 /// An always up-to-date list of an indexer's active and recently closed allocations.
+///
+/// Allocations read at a block more than `max_block_lag` blocks behind the
+/// latest block seen, or older than `max_block_age`, are ignored and the
+/// previous ones kept, an allocation missing from stale data is not closed.
+///
+/// With a `cache`, the allocations are saved on disk and the last ones saved
+/// are used if they can't be read at startup.
+///
+/// The watcher is named `allocations-<indexer address>` in the metrics.
 pub async fn indexer_allocations(
     network_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     recently_closed_allocation_buffer: Duration,
-) -> anyhow::Result<AllocationWatcher> {
-    new_watcher(interval, move || async move {
-        get_allocations(
-            network_subgraph,
-            indexer_address,
-            recently_closed_allocation_buffer,
-        )
-        .await
+    max_block_lag: u64,
+    max_block_age: Option<Duration>,
+    cache: Option<SubgraphCache>,
+) -> anyhow::Result<Watcher<HashMap<Address, Allocation>>> {
+    let latest_block = Arc::new(AtomicU64::new(0));
+    let name = format!(
+        "allocations-{}",
+        indexer_address.to_string().to_ascii_lowercase()
+    );
+    let cache = cache.map(|cache| cache.entry(name.clone()));
+    let fetch_allocations = move || {
+        let latest_block = latest_block.clone();
+        let cache = cache.clone();
+        async move {
+            let allocations = async {
+                let (allocations, block) = query_allocations(
+                    network_subgraph,
+                    indexer_address,
+                    recently_closed_allocation_buffer,
+                )
+                .await?;
+                if let Some(block_number) = block.number {
+                    check_block_lag(indexer_address, &latest_block, block_number, max_block_lag)?;
+                }
+                if let (Some(timestamp), Some(max_block_age)) = (block.timestamp, max_block_age) {
+                    check_block_age(indexer_address, timestamp, max_block_age, SystemTime::now())?;
+                }
+                anyhow::Ok(allocations)
+            }
+            .await;
+            match cache {
+                Some(cache) => cache.or_cached(allocations).await,
+                None => allocations,
+            }
+        }
+    };
+    if !network_subgraph.supports_subscriptions() {
+        return Watcher::poll(name, interval, fetch_allocations).await;
+    }
+
+    // Only used as a change notification, allocations are then queried with pagination
+    let subscription = format!(
+        r#"subscription {{
+            allocations(where: {{ indexer: "{}", status: Active }}, first: 1000) {{ id }}
+        }}"#,
+        indexer_address.to_string().to_ascii_lowercase()
+    );
+    Watcher::subscription(name, interval, fetch_allocations, move || {
+        network_subgraph.subscribe(subscription.clone())
     })
     .await
 }
 
+/// Allocations of every indexer served by the same deployment in a single map,
+/// each [Allocation] still records the indexer it belongs to.
+pub fn merge_allocations(
+    allocations: AllocationWatcher,
+    others: Vec<AllocationWatcher>,
+) -> AllocationWatcher {
+    others.into_iter().fold(allocations, |merged, other| {
+        join_and_map_watcher(merged, other, |(mut merged, other)| {
+            merged.extend(other);
+            merged
+        })
+    })
+}
+
+/// Ids of the allocations of `allocations`, a [AllocationId::Horizon] for the
+/// allocations opened in a provision
+pub fn allocation_ids(allocations: AllocationWatcher) -> AllocationIdsWatcher {
+    map_watcher(allocations, |allocations| {
+        allocations
+            .values()
+            .map(Allocation::allocation_id)
+            .collect()
+    })
+}
+
+/// Fails if `block_number` is more than `max_block_lag` blocks behind the
+/// latest block the allocations were read at, e.g. after falling back from
+/// the local deployment to a lagging remote one
+fn check_block_lag(
+    indexer_address: Address,
+    latest_block: &AtomicU64,
+    block_number: u64,
+    max_block_lag: u64,
+) -> anyhow::Result<()> {
+    let latest = latest_block
+        .fetch_max(block_number, Ordering::Relaxed)
+        .max(block_number);
+    let lag = latest - block_number;
+    let indexer = indexer_address.to_string();
+    ALLOCATIONS_BLOCK_LAG
+        .with_label_values(&[&indexer])
+        .set(lag as i64);
+    if lag > max_block_lag {
+        STALE_ALLOCATIONS.with_label_values(&[&indexer]).inc();
+        bail!(
+            "Network subgraph allocations at block {block_number} are {lag} blocks behind \
+            block {latest}, keeping the previous allocations"
+        );
+    }
+    Ok(())
+}
+
+/// Fails if the block the allocations were read at, with the given
+/// `timestamp` in seconds, is older than `max_block_age`, e.g. while the
+/// network subgraph stopped syncing and no later block was ever seen
+fn check_block_age(
+    indexer_address: Address,
+    timestamp: i64,
+    max_block_age: Duration,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let age = now.saturating_sub(timestamp.max(0) as u64);
+    if age > max_block_age.as_secs() {
+        STALE_ALLOCATIONS
+            .with_label_values(&[&indexer_address.to_string()])
+            .inc();
+        bail!(
+            "Network subgraph allocations were read at a block {age}s old, \
+            keeping the previous allocations"
+        );
+    }
+    Ok(())
+}
+
+/// Block the allocations were read at
+#[derive(Default)]
+struct ReadAtBlock {
+    number: Option<u64>,
+    /// In seconds
+    timestamp: Option<i64>,
+}
+
 pub async fn get_allocations(
     network_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     recently_closed_allocation_buffer: Duration,
 ) -> Result<HashMap<Address, Allocation>, anyhow::Error> {
+    query_allocations(
+        network_subgraph,
+        indexer_address,
+        recently_closed_allocation_buffer,
+    )
+    .await
+    .map(|(allocations, _)| allocations)
+}
+
+/// Allocations and the block they were read at
+async fn query_allocations(
+    network_subgraph: &'static SubgraphClient,
+    indexer_address: Address,
+    recently_closed_allocation_buffer: Duration,
+) -> anyhow::Result<(HashMap<Address, Allocation>, ReadAtBlock)> {
     let start = SystemTime::now();
     let since_the_epoch = start
         .duration_since(UNIX_EPOCH)
@@ -47,6 +224,7 @@ pub async fn get_allocations(
     let closed_at_threshold = since_the_epoch - recently_closed_allocation_buffer;
 
     let mut hash: Option<TxHash> = None;
+    let mut block = ReadAtBlock::default();
     let mut last: Option<String> = None;
     let mut responses = vec![];
     let page_size = 200;
@@ -69,7 +247,13 @@ pub async fn get_allocations(
         let mut data = result?;
         let page_len = data.allocations.len();
 
-        hash = data.meta.and_then(|meta| meta.block.hash);
+        if let Some(meta) = data.meta {
+            hash = meta.block.hash;
+            if block.number.is_none() {
+                block.number = u64::try_from(meta.block.number).ok();
+                block.timestamp = meta.block.timestamp;
+            }
+        }
         last = data.allocations.last().map(|entry| entry.id.to_string());
 
         responses.append(&mut data.allocations);
@@ -82,17 +266,23 @@ pub async fn get_allocations(
         .map(|allocation| allocation.try_into())
         .collect::<Result<Vec<Allocation>, _>>()?;
 
-    Ok(responses
-        .into_iter()
-        .map(|allocation| (allocation.id, allocation))
-        .collect())
+    Ok((
+        responses
+            .into_iter()
+            .map(|allocation| (allocation.id, allocation))
+            .collect(),
+        block,
+    ))
 }
 
 #[cfg(test)]
 mod test {
     use std::time::Duration;
 
+    use indexer_allocation::{CollectionId, Provision};
+    use serde_json::json;
     use thegraph_core::alloy::primitives::address;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
 
     use super::*;
     use crate::client::{DeploymentDetails, SubgraphClient};
@@ -109,6 +299,142 @@ mod test {
         ))
     }
 
+    #[tokio::test]
+    async fn test_merge_allocations() {
+        let mut allocations = test_assets::INDEXER_ALLOCATIONS.clone();
+        let first = allocations.keys().next().copied().unwrap();
+        let other = HashMap::from([(first, allocations.remove(&first).unwrap())]);
+
+        let (_, allocations_rx) = tokio::sync::watch::channel(allocations);
+        let (other_tx, other_rx) = tokio::sync::watch::channel(HashMap::new());
+        let mut merged = merge_allocations(allocations_rx, vec![other_rx]);
+        assert_eq!(
+            merged.borrow().len(),
+            test_assets::INDEXER_ALLOCATIONS.len() - 1
+        );
+
+        other_tx.send(other).unwrap();
+        merged.changed().await.unwrap();
+        assert_eq!(*merged.borrow(), *test_assets::INDEXER_ALLOCATIONS);
+    }
+
+    #[tokio::test]
+    async fn test_allocation_ids() {
+        let mut allocations = test_assets::INDEXER_ALLOCATIONS.clone();
+        let horizon = allocations.keys().next().copied().unwrap();
+        allocations.get_mut(&horizon).unwrap().provision = Some(Provision {
+            service_provider: test_assets::INDEXER_ADDRESS,
+            data_service: address!("1111111111111111111111111111111111111111"),
+        });
+
+        let (_, allocations_rx) = tokio::sync::watch::channel(allocations);
+        let ids = allocation_ids(allocations_rx);
+        let ids = ids.borrow();
+        assert_eq!(ids.len(), test_assets::INDEXER_ALLOCATIONS.len());
+        for id in test_assets::INDEXER_ALLOCATIONS.keys() {
+            let expected = if *id == horizon {
+                AllocationId::Horizon(CollectionId::from(*id))
+            } else {
+                AllocationId::Legacy(*id)
+            };
+            assert!(ids.contains(&expected));
+        }
+        let horizon_id = ids.iter().find(|id| id.is_horizon()).unwrap();
+        assert_eq!(horizon_id.address(), horizon);
+    }
+
+    #[tokio::test]
+    async fn test_horizon_allocation_query() {
+        let mock_server = MockServer::start().await;
+        let legacy = address!("1111111111111111111111111111111111111111");
+        let horizon = address!("2222222222222222222222222222222222222222");
+        let data_service = address!("3333333333333333333333333333333333333333");
+        let allocation = |id: Address, provision: serde_json::Value| {
+            json!({
+                "id": id,
+                "indexer": { "id": test_assets::INDEXER_ADDRESS },
+                "allocatedTokens": "1000",
+                "createdAtBlockHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "createdAtEpoch": 1,
+                "closedAtEpoch": null,
+                "subgraphDeployment": {
+                    "id": "QmUhiH6Z5xo6o3GNzsSvqpGKLmCt6w5WzKQ1yHk6C8AA8S",
+                    "deniedAt": 0,
+                },
+                "provision": provision,
+            })
+        };
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                    "allocations": [
+                        allocation(legacy, serde_json::Value::Null),
+                        allocation(horizon, json!({
+                            "indexer": { "id": test_assets::INDEXER_ADDRESS },
+                            "dataService": { "id": data_service },
+                        })),
+                    ],
+                }})),
+            ))
+            .await;
+        let network_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+
+        let allocations = get_allocations(
+            network_subgraph,
+            test_assets::INDEXER_ADDRESS,
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+        assert_eq!(allocations[&legacy].provision, None);
+        assert_eq!(
+            allocations[&legacy].allocation_id(),
+            AllocationId::Legacy(legacy)
+        );
+        assert_eq!(
+            allocations[&horizon].provision,
+            Some(Provision {
+                service_provider: test_assets::INDEXER_ADDRESS,
+                data_service,
+            })
+        );
+        assert_eq!(
+            allocations[&horizon].allocation_id(),
+            AllocationId::Horizon(CollectionId::from(horizon))
+        );
+    }
+
+    #[test]
+    fn test_check_block_lag() {
+        let indexer = test_assets::INDEXER_ADDRESS;
+        let latest_block = AtomicU64::new(0);
+        check_block_lag(indexer, &latest_block, 1000, 100).unwrap();
+        check_block_lag(indexer, &latest_block, 950, 100).unwrap();
+        assert!(check_block_lag(indexer, &latest_block, 800, 100).is_err());
+        assert_eq!(latest_block.load(Ordering::Relaxed), 1000);
+        check_block_lag(indexer, &latest_block, 1200, 100).unwrap();
+        assert!(check_block_lag(indexer, &latest_block, 1000, 100).is_err());
+    }
+
+    #[test]
+    fn test_check_block_age() {
+        let indexer = test_assets::INDEXER_ADDRESS;
+        let now = UNIX_EPOCH + Duration::from_secs(10_000);
+        let max_age = Duration::from_secs(600);
+        check_block_age(indexer, 10_000, max_age, now).unwrap();
+        check_block_age(indexer, 9_400, max_age, now).unwrap();
+        assert!(check_block_age(indexer, 9_000, max_age, now).is_err());
+        // slightly ahead of the local clock
+        check_block_age(indexer, 10_010, max_age, now).unwrap();
+    }
+
     #[tokio::test]
     #[test_with::env(NETWORK_SUBGRAPH_URL)]
     async fn test_network_query() {
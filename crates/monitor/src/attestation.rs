@@ -1,17 +1,13 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::{collections::HashMap, sync::Arc};
 
 use bip39::Mnemonic;
 use indexer_allocation::Allocation;
 use indexer_attestation::AttestationSigner;
-use indexer_watcher::join_and_map_watcher;
 use thegraph_core::alloy::primitives::{Address, ChainId};
-use tokio::sync::watch::Receiver;
+use tokio::sync::watch::{self, Receiver};
 
 use crate::{AllocationWatcher, DisputeManagerWatcher};
 
@@ -19,53 +15,152 @@ use crate::{AllocationWatcher, DisputeManagerWatcher};
 pub type AttestationWatcher = Receiver<HashMap<Address, AttestationSigner>>;
 
 /// An always up-to-date list of attestation signers, one for each of the indexer's allocations.
+///
+/// Signers are derived as soon as the allocations change, including for the
+/// recently closed allocations that can still be disputed, and derived again
+/// if the dispute manager changes. The signers of the Horizon allocations are
+/// keyed by the address of their collection id, like the legacy ones.
 pub fn attestation_signers(
     indexer_allocations_rx: AllocationWatcher,
     indexer_mnemonic: Mnemonic,
     chain_id: ChainId,
     dispute_manager_rx: DisputeManagerWatcher,
 ) -> AttestationWatcher {
-    let attestation_signers_map: &'static Mutex<HashMap<Address, AttestationSigner>> =
-        Box::leak(Box::new(Mutex::new(HashMap::new())));
-    let indexer_mnemonic = Arc::new(indexer_mnemonic.to_string());
+    spawn_attestation_signers(
+        indexer_allocations_rx,
+        Mnemonics::Single(indexer_mnemonic.to_string()),
+        chain_id,
+        dispute_manager_rx,
+    )
+}
 
-    join_and_map_watcher(
+/// Same as [attestation_signers] for the allocations of several indexers,
+/// each signer is derived from the mnemonic of the allocation's indexer.
+pub fn attestation_signers_by_indexer(
+    indexer_allocations_rx: AllocationWatcher,
+    indexer_mnemonics: HashMap<Address, Mnemonic>,
+    chain_id: ChainId,
+    dispute_manager_rx: DisputeManagerWatcher,
+) -> AttestationWatcher {
+    let indexer_mnemonics = indexer_mnemonics
+        .into_iter()
+        .map(|(indexer, mnemonic)| (indexer, mnemonic.to_string()))
+        .collect();
+    spawn_attestation_signers(
         indexer_allocations_rx,
+        Mnemonics::ByIndexer(indexer_mnemonics),
+        chain_id,
         dispute_manager_rx,
-        move |(allocation, dispute)| {
-            let indexer_mnemonic = indexer_mnemonic.clone();
-            modify_sigers(
-                &indexer_mnemonic,
-                chain_id,
-                attestation_signers_map,
-                &allocation,
-                &dispute,
-            )
-        },
     )
 }
-fn modify_sigers(
-    indexer_mnemonic: &str,
+
+/// Operator mnemonics the attestation signers are derived from
+enum Mnemonics {
+    /// Used for every allocation
+    Single(String),
+    /// Used for the allocations of each indexer
+    ByIndexer(HashMap<Address, String>),
+}
+
+impl Mnemonics {
+    fn get(&self, allocation: &Allocation) -> Option<&str> {
+        match self {
+            Mnemonics::Single(mnemonic) => Some(mnemonic.as_str()),
+            Mnemonics::ByIndexer(mnemonics) => {
+                mnemonics.get(&allocation.indexer).map(String::as_str)
+            }
+        }
+    }
+}
+
+fn spawn_attestation_signers(
+    mut indexer_allocations_rx: AllocationWatcher,
+    mnemonics: Mnemonics,
     chain_id: ChainId,
-    attestation_signers_map: &'static Mutex<HashMap<Address, AttestationSigner>>,
+    mut dispute_manager_rx: DisputeManagerWatcher,
+) -> AttestationWatcher {
+    let mnemonics = Arc::new(mnemonics);
+    let mut dispute_manager = *dispute_manager_rx.borrow();
+    let initial_signers = derive_signers(
+        &mnemonics,
+        chain_id,
+        HashMap::new(),
+        &indexer_allocations_rx.borrow(),
+        dispute_manager,
+    );
+    let (tx, rx) = watch::channel(initial_signers);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Ok(()) = indexer_allocations_rx.changed() => {},
+                Ok(()) = dispute_manager_rx.changed() => {},
+                else => break,
+            }
+
+            let allocations = indexer_allocations_rx.borrow_and_update().clone();
+            let mut signers = tx.borrow().clone();
+            // signers of the previous dispute manager sign for the wrong domain
+            let latest_dispute_manager = *dispute_manager_rx.borrow_and_update();
+            if latest_dispute_manager != dispute_manager {
+                dispute_manager = latest_dispute_manager;
+                signers.clear();
+            }
+
+            // The derivation tries many keys per allocation, it runs off the
+            // runtime so that queries keep being served meanwhile
+            let mnemonics = mnemonics.clone();
+            let derived = tokio::task::spawn_blocking(move || {
+                derive_signers(&mnemonics, chain_id, signers, &allocations, dispute_manager)
+            })
+            .await;
+            match derived {
+                Ok(signers) => {
+                    if tx.send(signers).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    tracing::error!(%error, "Failed to derive the attestation signers");
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Keeps the signers of the allocations still in `allocations`, active or
+/// recently closed and so still open to disputes, and derives the signers of
+/// the new allocations
+fn derive_signers(
+    mnemonics: &Mnemonics,
+    chain_id: ChainId,
+    mut signers: HashMap<Address, AttestationSigner>,
     allocations: &HashMap<Address, Allocation>,
-    dispute_manager: &Address,
+    dispute_manager: Address,
 ) -> HashMap<Address, AttestationSigner> {
-    let mut signers = attestation_signers_map.lock().unwrap();
-    // Remove signers for allocations that are no longer active or recently closed
     signers.retain(|id, _| allocations.contains_key(id));
 
-    // Create signers for new allocations
     for (id, allocation) in allocations.iter() {
         if !signers.contains_key(id) {
+            let Some(indexer_mnemonic) = mnemonics.get(allocation) else {
+                tracing::warn!(
+                    horizon = allocation.allocation_id().is_horizon(),
+                    "No operator mnemonic for indexer {} of allocation {}",
+                    allocation.indexer,
+                    allocation.id
+                );
+                continue;
+            };
             let signer =
-                AttestationSigner::new(indexer_mnemonic, allocation, chain_id, *dispute_manager);
+                AttestationSigner::new(indexer_mnemonic, allocation, chain_id, dispute_manager);
             match signer {
                 Ok(signer) => {
                     signers.insert(*id, signer);
                 }
                 Err(e) => {
                     tracing::warn!(
+                        horizon = allocation.allocation_id().is_horizon(),
                         "Failed to establish signer for allocation {}, deployment {}, createdAtEpoch {}: {}",
                         allocation.id, allocation.subgraph_deployment.id,
                         allocation.created_at_epoch, e
@@ -75,14 +170,16 @@ fn modify_sigers(
         }
     }
 
-    signers.clone()
+    signers
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
-    use test_assets::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use test_assets::{
+        DISPUTE_MANAGER_ADDRESS, INDEXER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC,
+    };
     use tokio::sync::watch;
 
     use super::*;
@@ -116,4 +213,72 @@ mod tests {
                 .any(|allocation_id| signer_allocation_id == allocation_id));
         }
     }
+
+    #[tokio::test]
+    async fn test_attestation_signers_of_horizon_allocations() {
+        let mut allocations = (*INDEXER_ALLOCATIONS).clone();
+        for allocation in allocations.values_mut() {
+            allocation.provision = Some(indexer_allocation::Provision {
+                service_provider: INDEXER_ADDRESS,
+                data_service: Address::repeat_byte(0x11),
+            });
+        }
+        let (_allocations_tx, allocations_rx) = watch::channel(allocations);
+        let (_, dispute_manager_rx) = watch::channel(DISPUTE_MANAGER_ADDRESS);
+        let signers = attestation_signers(
+            allocations_rx,
+            INDEXER_MNEMONIC.clone(),
+            1,
+            dispute_manager_rx,
+        );
+        let signers = signers.borrow();
+        assert_eq!(signers.len(), INDEXER_ALLOCATIONS.len());
+        assert!(INDEXER_ALLOCATIONS
+            .keys()
+            .all(|id| signers.contains_key(id)));
+    }
+
+    #[tokio::test]
+    async fn test_attestation_signers_update_with_dispute_manager() {
+        let (_allocations_tx, allocations_rx) = watch::channel((*INDEXER_ALLOCATIONS).clone());
+        let (dispute_manager_tx, dispute_manager_rx) = watch::channel(DISPUTE_MANAGER_ADDRESS);
+        let mut signers = attestation_signers(
+            allocations_rx,
+            INDEXER_MNEMONIC.clone(),
+            1,
+            dispute_manager_rx,
+        );
+        let previous_signers = signers.borrow().clone();
+
+        dispute_manager_tx.send(Address::repeat_byte(0x42)).unwrap();
+        signers.changed().await.unwrap();
+        let latest_signers = signers.borrow().clone();
+        assert_eq!(latest_signers.len(), INDEXER_ALLOCATIONS.len());
+        for (allocation_id, signer) in latest_signers {
+            assert_ne!(previous_signers[&allocation_id], signer);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_attestation_signers_by_indexer() {
+        let (_, allocations_rx) = watch::channel((*INDEXER_ALLOCATIONS).clone());
+        let (_, dispute_manager_rx) = watch::channel(DISPUTE_MANAGER_ADDRESS);
+
+        let signers = attestation_signers_by_indexer(
+            allocations_rx.clone(),
+            HashMap::from([(INDEXER_ADDRESS, INDEXER_MNEMONIC.clone())]),
+            1,
+            dispute_manager_rx.clone(),
+        );
+        assert_eq!(signers.borrow().len(), INDEXER_ALLOCATIONS.len());
+
+        // allocations of an indexer without a mnemonic get no signer
+        let signers = attestation_signers_by_indexer(
+            allocations_rx,
+            HashMap::from([(Address::ZERO, INDEXER_MNEMONIC.clone())]),
+            1,
+            dispute_manager_rx,
+        );
+        assert!(signers.borrow().is_empty());
+    }
 }
@@ -3,5 +3,6 @@
 
 mod monitor;
 mod subgraph_client;
+mod subscription;
 
 pub use subgraph_client::{DeploymentDetails, SubgraphClient};
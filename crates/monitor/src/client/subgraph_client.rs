@@ -6,9 +6,12 @@ use axum::body::Bytes;
 use graphql_client::GraphQLQuery;
 use reqwest::{header, Url};
 use thegraph_core::DeploymentId;
-use tokio::sync::watch::Receiver;
+use tokio::sync::{mpsc, watch::Receiver};
 
-use super::monitor::{monitor_deployment_status, DeploymentStatus};
+use super::{
+    monitor::{monitor_deployment_status, DeploymentStatus},
+    subscription,
+};
 
 pub type ResponseResult<T> = Result<T, anyhow::Error>;
 
@@ -18,6 +21,7 @@ pub struct DeploymentDetails {
     status_url: Option<Url>,
     query_url: Url,
     query_auth_token: Option<String>,
+    subscription_url: Option<Url>,
 }
 
 impl DeploymentDetails {
@@ -45,6 +49,7 @@ impl DeploymentDetails {
                 .join(&format!("subgraphs/id/{deployment}"))
                 .expect("Must be correct"),
             query_auth_token: None,
+            subscription_url: None,
         }
     }
 
@@ -54,6 +59,7 @@ impl DeploymentDetails {
             status_url: None,
             query_url: Url::parse(query_url)?,
             query_auth_token: None,
+            subscription_url: None,
         })
     }
 
@@ -63,6 +69,15 @@ impl DeploymentDetails {
             status_url: None,
             query_url,
             query_auth_token,
+            subscription_url: None,
+        }
+    }
+
+    /// Enables subscriptions to changes in the deployment through a WebSocket url
+    pub fn with_subscription_url(self, subscription_url: Option<Url>) -> Self {
+        Self {
+            subscription_url,
+            ..self
         }
     }
 }
@@ -72,6 +87,7 @@ struct DeploymentClient {
     pub status: Option<Receiver<DeploymentStatus>>,
     pub query_url: Url,
     pub query_auth_token: Option<String>,
+    pub subscription_url: Option<Url>,
 }
 
 impl DeploymentClient {
@@ -93,6 +109,7 @@ impl DeploymentClient {
             },
             query_url: details.query_url,
             query_auth_token: details.query_auth_token,
+            subscription_url: details.subscription_url,
         }
     }
 
@@ -165,6 +182,28 @@ impl DeploymentClient {
 
         Ok(req.send().await?)
     }
+
+    pub async fn subscribe(&self, query: &str) -> Result<mpsc::Receiver<()>, anyhow::Error> {
+        let Some(ref subscription_url) = self.subscription_url else {
+            return Err(anyhow!(
+                "Subscriptions are not enabled for deployment `{}`",
+                self.query_url
+            ));
+        };
+
+        if let Some(ref status) = self.status {
+            let deployment_status = status.borrow();
+
+            if !deployment_status.synced || &deployment_status.health != "healthy" {
+                return Err(anyhow!(
+                    "Deployment `{}` is not ready or healthy to be subscribed to",
+                    self.query_url
+                ));
+            }
+        }
+
+        subscription::subscribe(subscription_url, self.query_auth_token.as_deref(), query).await
+    }
 }
 
 /// Client for a subgraph that can fall back from a local deployment to a remote query URL
@@ -247,6 +286,36 @@ impl SubgraphClient {
             err
         })
     }
+
+    /// Returns `true` if any of the deployments was configured with a subscription url
+    pub fn supports_subscriptions(&self) -> bool {
+        self.local_client
+            .as_ref()
+            .is_some_and(|client| client.subscription_url.is_some())
+            || self.remote_client.subscription_url.is_some()
+    }
+
+    /// Subscribes to the changes in the result of `query`
+    ///
+    /// The returned channel is notified every time the result changes and
+    /// closed when the subscription ends.
+    pub async fn subscribe(&self, query: String) -> Result<mpsc::Receiver<()>, anyhow::Error> {
+        // Try the local client first; if that fails, log the error and move on
+        // to the remote client
+        if let Some(ref local_client) = self.local_client {
+            if local_client.subscription_url.is_some() {
+                match local_client.subscribe(&query).await {
+                    Ok(notifications) => return Ok(notifications),
+                    Err(err) => tracing::warn!(
+                        "Failed to subscribe to local subgraph deployment `{}`, trying remote deployment next: {}",
+                        local_client.query_url, err
+                    ),
+                }
+            }
+        }
+
+        self.remote_client.subscribe(&query).await
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,226 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Subscriptions to subgraph changes over WebSocket
+//!
+//! Implements the client side of the `graphql-ws` protocol used by graph-node.
+//! The data pushed by the server is ignored: every message only signals that
+//! the subscribed entities changed, so the monitor can query them again.
+
+use anyhow::{anyhow, bail};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header, HeaderValue},
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+const GRAPHQL_WS_PROTOCOL: &str = "graphql-ws";
+const SUBSCRIPTION_ID: &str = "1";
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck,
+    ConnectionError { payload: serde_json::Value },
+    Ka,
+    Data,
+    Error { payload: serde_json::Value },
+    Complete,
+}
+
+/// Subscribes to `query` and returns a channel notified every time its result changes
+///
+/// The channel is closed once the subscription ends, for example because the
+/// connection was lost. Notifications are coalesced if the receiver is slower
+/// than the updates.
+pub async fn subscribe(
+    url: &Url,
+    auth_token: Option<&str>,
+    query: &str,
+) -> anyhow::Result<mpsc::Receiver<()>> {
+    let mut request = url.as_str().into_client_request()?;
+    request.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(GRAPHQL_WS_PROTOCOL),
+    );
+    if let Some(token) = auth_token {
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, format!("Bearer {token}").parse()?);
+    }
+    let (mut socket, _) = connect_async(request).await?;
+
+    send(
+        &mut socket,
+        json!({ "type": "connection_init", "payload": {} }),
+    )
+    .await?;
+    loop {
+        match next_message(&mut socket).await? {
+            ServerMessage::ConnectionAck => break,
+            ServerMessage::Ka => continue,
+            ServerMessage::ConnectionError { payload } => {
+                bail!("Subscription connection to `{url}` was rejected: {payload}")
+            }
+            message => bail!("Unexpected message before connection ack: {message:?}"),
+        }
+    }
+    send(
+        &mut socket,
+        json!({
+            "id": SUBSCRIPTION_ID,
+            "type": "start",
+            "payload": { "query": query },
+        }),
+    )
+    .await?;
+
+    let (tx, rx) = mpsc::channel(1);
+    let url = url.clone();
+    tokio::spawn(async move {
+        loop {
+            match next_message(&mut socket).await {
+                Ok(ServerMessage::Data) => {
+                    // a full channel already has a pending refresh
+                    if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(()) {
+                        break;
+                    }
+                }
+                Ok(ServerMessage::Ka) | Ok(ServerMessage::ConnectionAck) => {}
+                Ok(ServerMessage::Complete) => {
+                    tracing::info!(%url, "Subscription completed by the server");
+                    break;
+                }
+                Ok(ServerMessage::Error { payload })
+                | Ok(ServerMessage::ConnectionError { payload }) => {
+                    tracing::warn!(%url, %payload, "Subscription failed");
+                    break;
+                }
+                Err(error) => {
+                    tracing::warn!(%url, %error, "Subscription connection lost");
+                    break;
+                }
+            }
+        }
+        let _ = socket.close(None).await;
+    });
+
+    Ok(rx)
+}
+
+async fn send(socket: &mut Socket, message: serde_json::Value) -> anyhow::Result<()> {
+    socket.send(Message::Text(message.to_string())).await?;
+    Ok(())
+}
+
+async fn next_message(socket: &mut Socket) -> anyhow::Result<ServerMessage> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Close(frame))) => bail!("Connection closed: {frame:?}"),
+            // pings are answered by tungstenite on the next read
+            Some(Ok(_)) => continue,
+            Some(Err(error)) => return Err(error.into()),
+            None => return Err(anyhow!("Connection closed")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use reqwest::Url;
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, time::timeout};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    use super::subscribe;
+
+    /// Accepts a single connection and sends `updates` data messages after the subscription starts
+    async fn mock_graph_node(updates: usize) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+
+            let next = |message: Option<Result<Message, _>>| -> Value {
+                serde_json::from_str(message.unwrap().unwrap().to_text().unwrap()).unwrap()
+            };
+            assert_eq!(next(socket.next().await)["type"], "connection_init");
+            socket
+                .send(Message::Text(
+                    json!({ "type": "connection_ack" }).to_string(),
+                ))
+                .await
+                .unwrap();
+            let start = next(socket.next().await);
+            assert_eq!(start["type"], "start");
+            assert!(start["payload"]["query"]
+                .as_str()
+                .unwrap()
+                .starts_with("subscription"));
+
+            for _ in 0..updates {
+                socket
+                    .send(Message::Text(
+                        json!({ "id": start["id"], "type": "data", "payload": {} }).to_string(),
+                    ))
+                    .await
+                    .unwrap();
+                // let the client consume the notification before the next one
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            socket.close(None).await.unwrap();
+        });
+        format!("ws://{addr}/subgraphs/id/test").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_notifies_changes_until_connection_is_closed() {
+        let url = mock_graph_node(2).await;
+        let mut notifications =
+            subscribe(&url, None, "subscription { _meta { hasIndexingErrors } }")
+                .await
+                .unwrap();
+
+        for _ in 0..2 {
+            timeout(Duration::from_secs(1), notifications.recv())
+                .await
+                .expect("should be notified")
+                .expect("subscription should be active");
+        }
+        let closed = timeout(Duration::from_secs(1), notifications.recv())
+            .await
+            .unwrap();
+        assert!(closed.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fails_if_endpoint_is_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let result = subscribe(
+            &url.parse().unwrap(),
+            None,
+            "subscription { _meta { hasIndexingErrors } }",
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
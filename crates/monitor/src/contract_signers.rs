@@ -0,0 +1,242 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Receipt signers that are contract wallets, see EIP-1271
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use reqwest::Url;
+use thegraph_core::alloy::{
+    primitives::{fixed_bytes, Address, Bytes, FixedBytes, B256},
+    sol,
+};
+use tokio::sync::Semaphore;
+
+use crate::rpc::JsonRpcClient;
+
+sol! {
+    function isValidSignature(bytes32 hash, bytes signature) returns (bytes4 magicValue);
+}
+
+/// Returned by `isValidSignature` when the wallet accepts the signature
+const EIP1271_MAGIC_VALUE: FixedBytes<4> = fixed_bytes!("1626ba7e");
+
+/// How long a key no wallet accepted is refused without asking the wallets
+/// again
+const UNKNOWN_KEY_TTL: Duration = Duration::from_secs(60);
+
+/// Keys no wallet accepted remembered at most, the ones expiring first are
+/// forgotten beyond
+const MAX_UNKNOWN_KEYS: usize = 10_000;
+
+/// Lookups sent to the RPC endpoint at the same time, the receipts of other
+/// keys are refused meanwhile
+const MAX_CONCURRENT_LOOKUPS: usize = 8;
+
+struct CachedWallet {
+    wallet: Address,
+    expires_at: Instant,
+}
+
+/// Finds the contract wallet a receipt was signed for
+///
+/// A contract wallet can't sign, a key it accepts signs on its behalf and
+/// the ECDSA recovery of the signature gives the address of that key. The
+/// authorized signers that are contracts are asked if they accept the
+/// signature with `isValidSignature`, then the wallet that accepted a key
+/// is cached for the TTL so the next receipts signed by that key don't
+/// need an `eth_call`. A key removed from its wallet is still accepted
+/// until its entry expires.
+///
+/// The keys no wallet accepted are refused for a minute without asking the
+/// wallets again, and few lookups run at the same time, so receipts signed
+/// by random keys don't flood the RPC endpoint.
+#[derive(Clone)]
+pub struct ContractSigners {
+    rpc: JsonRpcClient,
+    ttl: Duration,
+    /// whether the signers are contracts, which is not expected to change
+    is_contract: Arc<Mutex<HashMap<Address, bool>>>,
+    /// wallets that accepted the signatures of a key
+    wallets: Arc<Mutex<HashMap<Address, CachedWallet>>>,
+    /// keys no wallet accepted, until when they are refused
+    unknown_keys: Arc<Mutex<HashMap<Address, Instant>>>,
+    lookups: Arc<Semaphore>,
+}
+
+impl ContractSigners {
+    pub fn new(http_client: reqwest::Client, rpc_url: Url, ttl: Duration) -> Self {
+        Self {
+            rpc: JsonRpcClient::new(http_client, rpc_url),
+            ttl,
+            is_contract: Default::default(),
+            wallets: Default::default(),
+            unknown_keys: Default::default(),
+            lookups: Arc::new(Semaphore::new(MAX_CONCURRENT_LOOKUPS)),
+        }
+    }
+
+    /// Wallet that recently accepted the signatures of `key`
+    pub fn cached_wallet(&self, key: &Address) -> Option<Address> {
+        self.wallets
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|cached| cached.expires_at > Instant::now())
+            .map(|cached| cached.wallet)
+    }
+
+    /// Finds which of the `signers` accepts the `signature` of `hash`, made
+    /// with `key`
+    pub async fn find_wallet(
+        &self,
+        signers: &[Address],
+        hash: B256,
+        signature: Bytes,
+        key: Address,
+    ) -> anyhow::Result<Address> {
+        if let Some(wallet) = self.cached_wallet(&key) {
+            if signers.contains(&wallet) {
+                return Ok(wallet);
+            }
+        }
+        {
+            let mut unknown_keys = self.unknown_keys.lock().unwrap();
+            let now = Instant::now();
+            unknown_keys.retain(|_, expires_at| *expires_at > now);
+            if unknown_keys.contains_key(&key) {
+                bail!("No contract signer accepted the signatures of {key} recently")
+            }
+        }
+        let _permit = self
+            .lookups
+            .try_acquire()
+            .map_err(|_| anyhow!("Too many contract signers lookups in progress"))?;
+
+        for signer in signers {
+            if !self.is_contract(*signer).await? {
+                continue;
+            }
+            let call = isValidSignatureCall {
+                hash,
+                signature: signature.clone(),
+            };
+            // most wallets revert instead of returning another value
+            let accepted = self
+                .rpc
+                .call(*signer, call)
+                .await
+                .is_ok_and(|result| result.magicValue == EIP1271_MAGIC_VALUE);
+            if accepted {
+                let now = Instant::now();
+                let mut wallets = self.wallets.lock().unwrap();
+                wallets.retain(|_, cached| cached.expires_at > now);
+                wallets.insert(
+                    key,
+                    CachedWallet {
+                        wallet: *signer,
+                        expires_at: now + self.ttl,
+                    },
+                );
+                return Ok(*signer);
+            }
+        }
+
+        let mut unknown_keys = self.unknown_keys.lock().unwrap();
+        if unknown_keys.len() >= MAX_UNKNOWN_KEYS {
+            let first_expiring = unknown_keys
+                .iter()
+                .min_by_key(|(_, expires_at)| **expires_at)
+                .map(|(key, _)| *key);
+            if let Some(key) = first_expiring {
+                unknown_keys.remove(&key);
+            }
+        }
+        unknown_keys.insert(key, Instant::now() + UNKNOWN_KEY_TTL);
+        bail!("No contract signer accepts the signatures of {key}")
+    }
+
+    async fn is_contract(&self, address: Address) -> anyhow::Result<bool> {
+        let cached = self.is_contract.lock().unwrap().get(&address).copied();
+        if let Some(is_contract) = cached {
+            return Ok(is_contract);
+        }
+        let is_contract = !self.rpc.get_code(address).await?.is_empty();
+        self.is_contract
+            .lock()
+            .unwrap()
+            .insert(address, is_contract);
+        Ok(is_contract)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use test_assets::mock_rpc::MockRpcServer;
+    use thegraph_core::alloy::{
+        primitives::{address, b256, Bytes},
+        sol_types::{SolCall, SolValue},
+    };
+
+    use super::{isValidSignatureCall, ContractSigners, EIP1271_MAGIC_VALUE};
+
+    #[tokio::test]
+    async fn test_find_wallet() {
+        let eoa = address!("1111111111111111111111111111111111111111");
+        let wallet = address!("2222222222222222222222222222222222222222");
+        let key = address!("3333333333333333333333333333333333333333");
+        let hash = b256!("4444444444444444444444444444444444444444444444444444444444444444");
+
+        let rpc = MockRpcServer::start().await;
+        rpc.set_code(wallet, Bytes::from_static(&[0x60, 0x80]));
+        rpc.set_call_result(
+            wallet,
+            isValidSignatureCall::SELECTOR.into(),
+            (EIP1271_MAGIC_VALUE,).abi_encode().into(),
+        );
+        let contract_signers = ContractSigners::new(
+            reqwest::Client::new(),
+            rpc.url().parse().unwrap(),
+            Duration::from_secs(60),
+        );
+
+        let signers = [eoa, wallet];
+        let found = contract_signers
+            .find_wallet(&signers, hash, Bytes::from_static(&[1; 65]), key)
+            .await
+            .unwrap();
+        assert_eq!(found, wallet);
+        assert_eq!(contract_signers.cached_wallet(&key), Some(wallet));
+        // the code of both signers and the signature of the wallet
+        assert_eq!(rpc.received_requests().await, 3);
+
+        // the wallet is cached for the key
+        contract_signers
+            .find_wallet(&signers, hash, Bytes::from_static(&[2; 65]), key)
+            .await
+            .unwrap();
+        assert_eq!(rpc.received_requests().await, 3);
+
+        // none of the signers is a wallet
+        let other_key = address!("5555555555555555555555555555555555555555");
+        assert!(contract_signers
+            .find_wallet(&[eoa], hash, Bytes::from_static(&[1; 65]), other_key)
+            .await
+            .is_err());
+        assert_eq!(rpc.received_requests().await, 3);
+
+        // the key is refused without asking the wallets again
+        assert!(contract_signers
+            .find_wallet(&signers, hash, Bytes::from_static(&[1; 65]), other_key)
+            .await
+            .is_err());
+        assert_eq!(rpc.received_requests().await, 3);
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use indexer_watcher::map_watcher;
 use thegraph_core::{alloy::primitives::Address, DeploymentId};
@@ -12,6 +12,9 @@ use crate::AllocationWatcher;
 /// Watcher for Map of deployment id and allocation id
 pub type DeploymentToAllocationWatcher = Receiver<HashMap<DeploymentId, Address>>;
 
+/// Watcher for the set of deployments denied by the network
+pub type DeniedDeploymentsWatcher = Receiver<HashSet<DeploymentId>>;
+
 /// Watcher of indexer allocation
 /// returning a map of subgraph deployment to allocation id
 pub fn deployment_to_allocation(
@@ -25,11 +28,24 @@ pub fn deployment_to_allocation(
     })
 }
 
+/// Watcher of the allocated deployments with a `deniedAt` set in the network subgraph
+pub fn denied_deployments(indexer_allocations_rx: AllocationWatcher) -> DeniedDeploymentsWatcher {
+    map_watcher(indexer_allocations_rx, move |allocation| {
+        allocation
+            .values()
+            .map(|allocation| &allocation.subgraph_deployment)
+            // not denied deployments have a `deniedAt` of 0
+            .filter(|deployment| deployment.denied_at.is_some_and(|denied_at| denied_at > 0))
+            .map(|deployment| deployment.id)
+            .collect()
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use tokio::sync::watch;
 
-    use super::deployment_to_allocation;
+    use super::{denied_deployments, deployment_to_allocation};
 
     #[tokio::test]
     async fn test_deployment_to_allocation() {
@@ -45,4 +61,15 @@ mod tests {
             assert_eq!(allocations.get(val).unwrap().subgraph_deployment.id, *key);
         }
     }
+
+    #[tokio::test]
+    async fn test_denied_deployments() {
+        let mut allocations = test_assets::INDEXER_ALLOCATIONS.clone();
+        let denied = allocations.values_mut().next().unwrap();
+        denied.subgraph_deployment.denied_at = Some(1_700_000_000);
+        let denied = denied.subgraph_deployment.id;
+
+        let denied_deployments = denied_deployments(watch::channel(allocations).1);
+        assert_eq!(*denied_deployments.borrow(), [denied].into());
+    }
 }
@@ -1,39 +1,109 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::Error;
 use indexer_query::dispute_manager::{self, DisputeManager};
-use indexer_watcher::new_watcher;
+use indexer_watcher::Watcher;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge, Gauge};
 use thegraph_core::alloy::primitives::Address;
 use tokio::sync::watch::Receiver;
 
-use crate::client::SubgraphClient;
+use crate::{client::SubgraphClient, subgraph_cache::SubgraphCache};
+
+lazy_static! {
+    static ref MINIMUM_DISPUTE_DEPOSIT: Gauge = register_gauge!(
+        "indexer_dispute_manager_minimum_deposit_grt",
+        "Minimum deposit of a dispute against the indexer, in GRT"
+    )
+    .unwrap();
+}
+
+const GRT: f64 = 1e18;
 
 /// Watcher for Dispute Manager Address
 pub type DisputeManagerWatcher = Receiver<Address>;
 
 /// Monitors the subgraph for dispute manager address
+///
+/// The minimum deposit of a dispute is exported as a metric along the way.
+/// With a `cache`, the dispute manager is saved on disk and the last one
+/// saved is used if it can't be read at startup, else the `configured` one
+/// if any, until the subgraph can be read.
+///
+/// The watcher is named `dispute-manager` in the metrics.
 pub async fn dispute_manager(
     network_subgraph: &'static SubgraphClient,
     interval: Duration,
-) -> anyhow::Result<DisputeManagerWatcher> {
-    new_watcher(interval, move || async move {
-        let response = network_subgraph
-            .query::<DisputeManager, _>(dispute_manager::Variables {})
-            .await?;
-        response?
-            .graph_network
-            .map(|network| network.dispute_manager)
-            .ok_or_else(|| Error::msg("Network 1 not found in network subgraph"))
+    cache: Option<SubgraphCache>,
+    configured: Option<Address>,
+) -> anyhow::Result<Watcher<Address>> {
+    let cache = cache.map(|cache| cache.entry("dispute-manager".to_string()));
+    let read = Arc::new(AtomicBool::new(false));
+    Watcher::poll("dispute-manager", interval, move || {
+        let cache = cache.clone();
+        let read = read.clone();
+        async move {
+            let dispute_manager = query_dispute_manager(network_subgraph).await;
+            let dispute_manager = match cache {
+                Some(cache) => cache.or_cached(dispute_manager).await,
+                None => dispute_manager,
+            };
+            match (dispute_manager, configured) {
+                (Ok(dispute_manager), _) => {
+                    read.store(true, Ordering::Relaxed);
+                    Ok(dispute_manager)
+                }
+                (Err(error), Some(configured)) if !read.load(Ordering::Relaxed) => {
+                    tracing::warn!(
+                        %error,
+                        %configured,
+                        "Using the configured dispute manager until the network subgraph can be read"
+                    );
+                    Ok(configured)
+                }
+                (Err(error), _) => Err(error),
+            }
+        }
     })
     .await
 }
 
+async fn query_dispute_manager(network_subgraph: &SubgraphClient) -> anyhow::Result<Address> {
+    let response = network_subgraph
+        .query::<DisputeManager, _>(dispute_manager::Variables {})
+        .await?;
+    let network = response?
+        .graph_network
+        .ok_or_else(|| Error::msg("Network 1 not found in network subgraph"))?;
+    match network.minimum_dispute_deposit.parse::<f64>() {
+        Ok(deposit) => MINIMUM_DISPUTE_DEPOSIT.set(deposit / GRT),
+        Err(error) => tracing::warn!(
+            %error,
+            deposit = %network.minimum_dispute_deposit,
+            "Invalid minimum dispute deposit in the network subgraph"
+        ),
+    }
+    Ok(network.dispute_manager)
+}
+
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
     use serde_json::json;
     use test_assets::DISPUTE_MANAGER_ADDRESS;
@@ -70,7 +140,10 @@ mod test {
                         test_assets::NETWORK_SUBGRAPH_DEPLOYMENT
                     )))
                     .respond_with(ResponseTemplate::new(200).set_body_json(
-                        json!({ "data": { "graphNetwork": { "disputeManager": DISPUTE_MANAGER_ADDRESS }}}),
+                        json!({ "data": { "graphNetwork": {
+                            "disputeManager": DISPUTE_MANAGER_ADDRESS,
+                            "minimumDisputeDeposit": "10000000000000000000000",
+                        }}}),
                     )),
             )
             .await;
@@ -82,11 +155,46 @@ mod test {
     async fn test_parses_dispute_manager_from_network_subgraph_correctly() {
         let (network_subgraph, _mock_server) = setup_mock_network_subgraph().await;
 
-        let dispute_manager = dispute_manager(network_subgraph, Duration::from_secs(60))
-            .await
-            .unwrap();
+        let dispute_manager =
+            dispute_manager(network_subgraph, Duration::from_secs(60), None, None)
+                .await
+                .unwrap();
         sleep(Duration::from_millis(50)).await;
-        let result = *dispute_manager.borrow();
+        let result = *dispute_manager.receiver().borrow();
         assert_eq!(result, DISPUTE_MANAGER_ADDRESS);
+        assert_eq!(MINIMUM_DISPUTE_DEPOSIT.get(), 10_000.0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_configured_dispute_manager_on_cold_start() {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)))
+            .await;
+        let network_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+
+        // unreachable without a fallback
+        assert!(
+            dispute_manager(network_subgraph, Duration::from_secs(60), None, None)
+                .await
+                .is_err()
+        );
+        let configured = Address::repeat_byte(0x42);
+        let dispute_manager = dispute_manager(
+            network_subgraph,
+            Duration::from_secs(60),
+            None,
+            Some(configured),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*dispute_manager.receiver().borrow(), configured);
     }
 }
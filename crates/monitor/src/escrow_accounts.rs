@@ -4,16 +4,31 @@
 use std::{
     collections::{HashMap, HashSet},
     str::FromStr,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
+use indexer_error::IndexerErrorCode;
 use indexer_query::escrow_account::{self, EscrowAccountQuery};
+use indexer_watcher::Watcher;
+use serde::{Deserialize, Serialize};
 use thegraph_core::alloy::primitives::{Address, U256};
 use thiserror::Error;
 use tokio::sync::watch::Receiver;
 
-use crate::client::SubgraphClient;
+use crate::{client::SubgraphClient, escrow_rpc::EscrowRpcFallback, subgraph_cache::SubgraphCache};
+
+/// Number of escrow accounts fetched per query
+const ESCROW_ACCOUNTS_PAGE_SIZE: i64 = 200;
+
+/// Number of signers fetched per sender, the `first` of the signers in the
+/// escrow accounts query
+const MAX_SIGNERS_PER_SENDER: usize = 1000;
+
+/// Number of times all the pages are fetched again when the block the
+/// first page was read at is not available anymore
+const MAX_REORG_RETRIES: u32 = 3;
 
 #[derive(Error, Debug)]
 pub enum EscrowAccountsError {
@@ -23,13 +38,54 @@ pub enum EscrowAccountsError {
     NoBalanceFound { sender: Address },
     #[error("No sender found for signer {signer}")]
     NoSenderFound { signer: Address },
+    #[error("Signer {signer} was revoked at {revoked_at}, before the receipt was signed")]
+    SignerRevoked { signer: Address, revoked_at: u64 },
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Signer no longer authorized by its sender
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevokedSigner {
+    pub sender: Address,
+    /// Thaw end of the signer in seconds, it can't be revoked before, so the
+    /// receipts signed earlier are still honored by the sender
+    pub revoked_at: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "SerializedEscrowAccounts", into = "SerializedEscrowAccounts")]
 pub struct EscrowAccounts {
     senders_balances: HashMap<Address, U256>,
     signers_to_senders: HashMap<Address, Address>,
     senders_to_signers: HashMap<Address, Vec<Address>>,
+    /// revoked signers, kept to aggregate the receipts they signed before
+    revoked_signers: HashMap<Address, RevokedSigner>,
+}
+
+/// [EscrowAccounts] without the senders of the signers, found again from
+/// the signers of the senders
+#[derive(Serialize, Deserialize)]
+struct SerializedEscrowAccounts {
+    senders_balances: HashMap<Address, U256>,
+    senders_to_signers: HashMap<Address, Vec<Address>>,
+    #[serde(default)]
+    revoked_signers: HashMap<Address, RevokedSigner>,
+}
+
+impl From<SerializedEscrowAccounts> for EscrowAccounts {
+    fn from(accounts: SerializedEscrowAccounts) -> Self {
+        Self::new(accounts.senders_balances, accounts.senders_to_signers)
+            .with_revoked_signers(accounts.revoked_signers)
+    }
+}
+
+impl From<EscrowAccounts> for SerializedEscrowAccounts {
+    fn from(accounts: EscrowAccounts) -> Self {
+        Self {
+            senders_balances: accounts.senders_balances,
+            senders_to_signers: accounts.senders_to_signers,
+            revoked_signers: accounts.revoked_signers,
+        }
+    }
 }
 
 impl EscrowAccounts {
@@ -46,9 +102,23 @@ impl EscrowAccounts {
             senders_balances,
             signers_to_senders,
             senders_to_signers,
+            revoked_signers: HashMap::new(),
         }
     }
 
+    /// Accounts with the `revoked_signers`, a signer authorized again is not
+    /// revoked anymore
+    pub fn with_revoked_signers(
+        mut self,
+        revoked_signers: HashMap<Address, RevokedSigner>,
+    ) -> Self {
+        self.revoked_signers = revoked_signers
+            .into_iter()
+            .filter(|(signer, _)| !self.signers_to_senders.contains_key(signer))
+            .collect();
+        self
+    }
+
     pub fn get_signers_for_sender(&self, sender: &Address) -> Vec<Address> {
         self.senders_to_signers
             .get(sender)
@@ -67,6 +137,55 @@ impl EscrowAccounts {
             .copied()
     }
 
+    /// Sender of an authorized or revoked signer, whose receipts may still
+    /// have to be aggregated
+    pub fn get_sender_for_any_signer(
+        &self,
+        signer: &Address,
+    ) -> Result<Address, EscrowAccountsError> {
+        match self.revoked_signers.get(signer) {
+            Some(revoked) => Ok(revoked.sender),
+            None => self.get_sender_for_signer(signer),
+        }
+    }
+
+    /// Sender of the signer of a receipt signed at `timestamp_ns`, the
+    /// receipts of a revoked signer are only honored if signed before it was
+    /// revoked
+    pub fn get_sender_for_receipt_signer(
+        &self,
+        signer: &Address,
+        timestamp_ns: u64,
+    ) -> Result<Address, EscrowAccountsError> {
+        match self.revoked_signers.get(signer) {
+            Some(revoked) if timestamp_ns / 1_000_000_000 < revoked.revoked_at => {
+                Ok(revoked.sender)
+            }
+            Some(revoked) => Err(EscrowAccountsError::SignerRevoked {
+                signer: *signer,
+                revoked_at: revoked.revoked_at,
+            }),
+            None => self.get_sender_for_signer(signer),
+        }
+    }
+
+    /// Authorized and revoked signers of `sender`, the receipts of the
+    /// revoked ones signed before they were revoked are still aggregated
+    pub fn get_receipt_signers_for_sender(&self, sender: &Address) -> Vec<Address> {
+        let mut signers = self.get_signers_for_sender(sender);
+        signers.extend(
+            self.revoked_signers
+                .iter()
+                .filter(|(_, revoked)| revoked.sender == *sender)
+                .map(|(signer, _)| *signer),
+        );
+        signers
+    }
+
+    pub fn get_revoked_signers(&self) -> &HashMap<Address, RevokedSigner> {
+        &self.revoked_signers
+    }
+
     pub fn get_balance_for_sender(&self, sender: &Address) -> Result<U256, EscrowAccountsError> {
         self.senders_balances
             .get(sender)
@@ -84,29 +203,165 @@ impl EscrowAccounts {
     pub fn get_senders(&self) -> HashSet<Address> {
         self.senders_balances.keys().copied().collect()
     }
+
+    pub fn get_signers(&self) -> Vec<Address> {
+        self.signers_to_senders.keys().copied().collect()
+    }
+
+    /// Accounts of both `self` and `other`, the balances of a sender found
+    /// in both are summed up.
+    pub fn merge(mut self, other: &Self) -> Self {
+        for (sender, balance) in &other.senders_balances {
+            let merged = self.senders_balances.entry(*sender).or_default();
+            *merged = merged.saturating_add(*balance);
+        }
+        for (sender, signers) in &other.senders_to_signers {
+            let merged = self.senders_to_signers.entry(*sender).or_default();
+            for signer in signers {
+                if !merged.contains(signer) {
+                    merged.push(*signer);
+                }
+            }
+        }
+        self.signers_to_senders
+            .extend(other.signers_to_senders.iter().map(|(k, v)| (*k, *v)));
+        self.revoked_signers.extend(
+            other
+                .revoked_signers
+                .iter()
+                .map(|(signer, revoked)| (*signer, *revoked)),
+        );
+        let signers_to_senders = &self.signers_to_senders;
+        self.revoked_signers
+            .retain(|signer, _| !signers_to_senders.contains_key(signer));
+        self
+    }
 }
 
 pub type EscrowAccountsWatcher = Receiver<EscrowAccounts>;
 
+/// Escrow accounts of every indexer served by the same deployment, used to
+/// find the sender of a signer. The balances are summed up across indexers,
+/// so they must be checked against the accounts of the receipt's indexer.
+pub fn merge_escrow_accounts(
+    escrow_accounts: EscrowAccountsWatcher,
+    others: Vec<EscrowAccountsWatcher>,
+) -> EscrowAccountsWatcher {
+    others.into_iter().fold(escrow_accounts, |merged, other| {
+        indexer_watcher::join_and_map_watcher(merged, other, |(merged, other)| merged.merge(&other))
+    })
+}
+
+/// Watches the escrow accounts of `indexer_address`
+///
+/// With a `fallback`, the balances and signers are read from the Escrow
+/// contract while the subgraph is unreachable or lags behind by more than
+/// `max_subgraph_lag`. Only the senders and signers last seen in the
+/// subgraph are refreshed, new ones are picked up once it has recovered.
+/// Until the subgraph was read once, the senders of the signers of the
+/// `fallback` are read instead.
+///
+/// With a `cache`, the accounts are saved on disk and the last ones saved
+/// are used if they can't be read at startup.
+///
+/// With a `revoked_signers_max_age`, only the signers revoked within it are
+/// kept, the receipts signed before older revocations are too old to be
+/// accepted anyway.
+///
+/// The watcher is named `escrow-accounts-v1-<indexer address>` in the
+/// metrics, with a `-without-thawing-signers` suffix if they are rejected.
 pub async fn escrow_accounts_v1(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     reject_thawing_signers: bool,
-) -> Result<EscrowAccountsWatcher, anyhow::Error> {
-    indexer_watcher::new_watcher(interval, move || {
-        get_escrow_accounts_v1(escrow_subgraph, indexer_address, reject_thawing_signers)
+    fallback: Option<EscrowRpcFallback>,
+    cache: Option<SubgraphCache>,
+    revoked_signers_max_age: Option<Duration>,
+) -> Result<Watcher<EscrowAccounts>, anyhow::Error> {
+    let fallback =
+        fallback.map(|fallback| Arc::new((fallback, Mutex::new(FallbackState::default()))));
+    // the accounts differ with the thawing signers
+    let name = format!(
+        "escrow-accounts-v1-{}{}",
+        indexer_address.to_string().to_ascii_lowercase(),
+        if reject_thawing_signers {
+            "-without-thawing-signers"
+        } else {
+            ""
+        }
+    );
+    let cache = cache.map(|cache| cache.entry(name.clone()));
+    let fetch_escrow_accounts = move || {
+        let fallback = fallback.clone();
+        let cache = cache.clone();
+        async move {
+            let accounts = match fallback.as_deref() {
+                Some((fallback, state)) => {
+                    get_escrow_accounts_v1_with_fallback(
+                        escrow_subgraph,
+                        indexer_address,
+                        reject_thawing_signers,
+                        revoked_signers_max_age,
+                        fallback,
+                        state,
+                    )
+                    .await
+                }
+                None => get_escrow_accounts_v1(
+                    escrow_subgraph,
+                    indexer_address,
+                    reject_thawing_signers,
+                    revoked_signers_max_age,
+                )
+                .await
+                .map(|(accounts, _)| accounts),
+            };
+            let accounts = match cache {
+                Some(cache) => cache.or_cached(accounts).await,
+                None => accounts,
+            };
+            // the watcher keeps the accounts last read
+            if accounts.is_err() {
+                IndexerErrorCode::EscrowAccountsStale.record();
+            }
+            accounts
+        }
+    };
+    if !escrow_subgraph.supports_subscriptions() {
+        return Watcher::poll(name, interval, fetch_escrow_accounts).await;
+    }
+
+    // Only used as a change notification, the filters on signers are applied by the query
+    let subscription = format!(
+        r#"subscription {{
+            escrowAccounts(where: {{ receiver_: {{ id: "{:x?}" }} }}) {{
+                balance
+                totalAmountThawing
+                sender {{ signers {{ id isAuthorized thawEndTimestamp }} }}
+            }}
+        }}"#,
+        indexer_address
+    );
+    Watcher::subscription(name, interval, fetch_escrow_accounts, move || {
+        escrow_subgraph.subscribe(subscription.clone())
     })
     .await
 }
 
+/// Watches the escrow accounts v2 of `indexer_address`, named
+/// `escrow-accounts-v2-<indexer address>` in the metrics
 pub async fn escrow_accounts_v2(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     interval: Duration,
     reject_thawing_signers: bool,
-) -> Result<EscrowAccountsWatcher, anyhow::Error> {
-    indexer_watcher::new_watcher(interval, move || {
+) -> Result<Watcher<EscrowAccounts>, anyhow::Error> {
+    let name = format!(
+        "escrow-accounts-v2-{}",
+        indexer_address.to_string().to_ascii_lowercase()
+    );
+    Watcher::poll(name, interval, move || {
         get_escrow_accounts_v2(escrow_subgraph, indexer_address, reject_thawing_signers)
     })
     .await
@@ -121,31 +376,209 @@ async fn get_escrow_accounts_v2(
     Ok(EscrowAccounts::new(HashMap::new(), HashMap::new()))
 }
 
-async fn get_escrow_accounts_v1(
+#[derive(Debug, Default)]
+struct FallbackState {
+    /// accounts of the last subgraph response, refreshed from the contract
+    last_subgraph_accounts: Option<EscrowAccounts>,
+    reading_contract: bool,
+}
+
+async fn get_escrow_accounts_v1_with_fallback(
     escrow_subgraph: &'static SubgraphClient,
     indexer_address: Address,
     reject_thawing_signers: bool,
+    revoked_signers_max_age: Option<Duration>,
+    fallback: &EscrowRpcFallback,
+    state: &Mutex<FallbackState>,
 ) -> anyhow::Result<EscrowAccounts> {
-    // thawEndTimestamp == 0 means that the signer is not thawing. This also means
-    // that we don't wait for the thawing period to end before stopping serving
-    // queries for this signer.
-    // isAuthorized == true means that the signer is still authorized to sign
-    // payments in the name of the sender.
-    let response = escrow_subgraph
-        .query::<EscrowAccountQuery, _>(escrow_account::Variables {
-            indexer: format!("{:x?}", indexer_address),
-            thaw_end_timestamp: if reject_thawing_signers {
-                U256::ZERO.to_string()
-            } else {
-                U256::MAX.to_string()
-            },
-        })
-        .await?;
+    let error = match get_escrow_accounts_v1(
+        escrow_subgraph,
+        indexer_address,
+        reject_thawing_signers,
+        revoked_signers_max_age,
+    )
+    .await
+    {
+        Ok((accounts, block_timestamp)) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64;
+            let lag = block_timestamp.map_or(0, |timestamp| now.saturating_sub(timestamp));
+            let mut state = state.lock().unwrap();
+            state.last_subgraph_accounts = Some(accounts.clone());
+            if lag <= fallback.max_subgraph_lag.as_secs() as i64 {
+                if state.reading_contract {
+                    tracing::info!(
+                        "Escrow subgraph is synced again, reading the escrow accounts from it"
+                    );
+                    state.reading_contract = false;
+                }
+                return Ok(accounts);
+            }
+            anyhow!("Escrow subgraph is {lag}s behind")
+        }
+        Err(error) => error,
+    };
 
-    let response = response?;
+    let last_accounts = state.lock().unwrap().last_subgraph_accounts.clone();
+    let last_accounts = match last_accounts {
+        Some(last_accounts) => last_accounts,
+        None if !fallback.signers.is_empty() => {
+            configured_accounts(fallback).await.map_err(|rpc_error| {
+                anyhow!("{error:#}, and reading the configured signers failed: {rpc_error:#}")
+            })?
+        }
+        None => return Err(error.context("No escrow accounts to read from the Escrow contract")),
+    };
+    let accounts = get_escrow_accounts_from_contract(
+        fallback,
+        &last_accounts,
+        indexer_address,
+        reject_thawing_signers,
+    )
+    .await
+    .map_err(|rpc_error| {
+        anyhow!("{error:#}, and reading the Escrow contract failed: {rpc_error:#}")
+    })?;
+
+    let mut state = state.lock().unwrap();
+    if !state.reading_contract {
+        tracing::warn!(%error, "Reading the escrow accounts from the Escrow contract");
+        state.reading_contract = true;
+    }
+    Ok(accounts)
+}
+
+/// Senders of the signers of `fallback`, to read from the contract before
+/// the subgraph was read once. The balances are left at zero
+async fn configured_accounts(fallback: &EscrowRpcFallback) -> anyhow::Result<EscrowAccounts> {
+    let mut senders_to_signers: HashMap<Address, Vec<Address>> = HashMap::new();
+    for signer in &fallback.signers {
+        let authorization = fallback.contract.authorized_signer(*signer).await?;
+        // not authorized by any sender
+        if authorization.sender.is_zero() {
+            continue;
+        }
+        senders_to_signers
+            .entry(authorization.sender)
+            .or_default()
+            .push(*signer);
+    }
+    let senders_balances = senders_to_signers
+        .keys()
+        .map(|sender| (*sender, U256::ZERO))
+        .collect();
+    Ok(EscrowAccounts::new(senders_balances, senders_to_signers))
+}
 
-    let senders_balances: HashMap<Address, U256> = response
-        .escrow_accounts
+/// Balances and signers of the senders of `accounts`, read from the contract
+async fn get_escrow_accounts_from_contract(
+    fallback: &EscrowRpcFallback,
+    accounts: &EscrowAccounts,
+    indexer_address: Address,
+    reject_thawing_signers: bool,
+) -> anyhow::Result<EscrowAccounts> {
+    let mut senders_balances = HashMap::new();
+    let mut senders_to_signers = HashMap::new();
+    for sender in accounts.get_senders() {
+        let account = fallback
+            .contract
+            .escrow_account(sender, indexer_address)
+            .await?;
+        senders_balances.insert(
+            sender,
+            account.balance.saturating_sub(account.amountThawing),
+        );
+
+        let mut signers = vec![];
+        for signer in accounts.get_signers_for_sender(&sender) {
+            let authorization = fallback.contract.authorized_signer(signer).await?;
+            if authorization.sender == sender
+                && (!reject_thawing_signers || authorization.thawEndTimestamp.is_zero())
+            {
+                signers.push(signer);
+            }
+        }
+        senders_to_signers.insert(sender, signers);
+    }
+    // the contract forgets the revoked signers, the last ones seen in the
+    // subgraph are kept
+    Ok(EscrowAccounts::new(senders_balances, senders_to_signers)
+        .with_revoked_signers(accounts.get_revoked_signers().clone()))
+}
+
+/// Escrow accounts and the timestamp of the block they were read at
+async fn get_escrow_accounts_v1(
+    escrow_subgraph: &'static SubgraphClient,
+    indexer_address: Address,
+    reject_thawing_signers: bool,
+    revoked_signers_max_age: Option<Duration>,
+) -> anyhow::Result<(EscrowAccounts, Option<i64>)> {
+    let revoked_since = revoked_signers_max_age.map_or(0, |max_age| {
+        SystemTime::now()
+            .checked_sub(max_age)
+            .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs())
+    });
+    // All the pages are read at the block of the first one, so that an account
+    // moved between pages by a reorg is neither missed nor counted twice
+    let mut block_hash: Option<String> = None;
+    let mut block_timestamp = None;
+    let mut last = String::new();
+    let mut escrow_accounts = vec![];
+    let mut retries = 0;
+    loop {
+        let result = escrow_subgraph
+            .query::<EscrowAccountQuery, _>(escrow_account::Variables {
+                indexer: format!("{:x?}", indexer_address),
+                block: block_hash.clone().map(|hash| escrow_account::Block_height {
+                    hash: Some(hash),
+                    number: None,
+                    number_gte: None,
+                }),
+                first: ESCROW_ACCOUNTS_PAGE_SIZE,
+                last: last.clone(),
+                revoked_since: revoked_since.to_string(),
+            })
+            .await
+            .and_then(|response| response);
+
+        let mut data = match result {
+            Ok(data) => data,
+            Err(error) if block_hash.is_some() && retries < MAX_REORG_RETRIES => {
+                retries += 1;
+                tracing::warn!(
+                    %error,
+                    retries,
+                    "Failed to query escrow accounts at the block of the first page, \
+                    it may have been reorged. Querying all pages again"
+                );
+                block_hash = None;
+                last = String::new();
+                escrow_accounts.clear();
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+
+        let page_len = data.escrow_accounts.len();
+        if block_hash.is_none() {
+            if let Some(meta) = data.meta {
+                block_hash = meta.block.hash;
+                block_timestamp = meta.block.timestamp;
+            }
+        }
+        if let Some(account) = data.escrow_accounts.last() {
+            last = account.id.clone();
+        }
+        escrow_accounts.append(&mut data.escrow_accounts);
+        if (page_len as i64) < ESCROW_ACCOUNTS_PAGE_SIZE {
+            break;
+        }
+    }
+
+    let senders_balances: HashMap<Address, U256> = escrow_accounts
         .iter()
         .map(|account| {
             let balance = U256::checked_sub(
@@ -165,23 +598,59 @@ async fn get_escrow_accounts_v1(
         })
         .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
 
-    let senders_to_signers = response
-        .escrow_accounts
-        .into_iter()
-        .map(|account| {
-            let sender = Address::from_str(&account.sender.id)?;
-            let signers = account
-                .sender
-                .signers
-                .ok_or(anyhow!("Could not find any signers for sender {sender}"))?
-                .iter()
-                .map(|signer| Address::from_str(&signer.id))
-                .collect::<Result<Vec<_>, _>>()?;
-            Ok((sender, signers))
-        })
-        .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
+    // isAuthorized == true means that the signer is still authorized to sign
+    // payments in the name of the sender.
+    // thawEndTimestamp == 0 means that the signer is not thawing. This also means
+    // that we don't wait for the thawing period to end before stopping serving
+    // queries for this signer.
+    let mut senders_to_signers = HashMap::new();
+    let mut revoked_signers = HashMap::new();
+    for account in escrow_accounts {
+        let sender = Address::from_str(&account.sender.id)?;
+        let account_signers = account
+            .sender
+            .signers
+            .ok_or(anyhow!("Could not find any signers for sender {sender}"))?;
+        if account_signers.len() >= MAX_SIGNERS_PER_SENDER {
+            tracing::warn!(
+                %sender,
+                "Sender has more than {MAX_SIGNERS_PER_SENDER} signers, the receipts of the \
+                ones not read are rejected"
+            );
+        }
+        let mut signers = vec![];
+        for signer in account_signers {
+            let id = Address::from_str(&signer.id)?;
+            let thaw_end_timestamp =
+                U256::from_str(&signer.thaw_end_timestamp)?.saturating_to::<u64>();
+            // a signer is revoked at the end of its thawing, without one it
+            // can't tell which of its receipts were signed before
+            if !signer.is_authorized && thaw_end_timestamp == 0 {
+                tracing::warn!(
+                    %sender,
+                    signer = %id,
+                    "Revoked signer without a thawing end, its receipts are rejected"
+                );
+            } else if !signer.is_authorized {
+                revoked_signers.insert(
+                    id,
+                    RevokedSigner {
+                        sender,
+                        revoked_at: thaw_end_timestamp,
+                    },
+                );
+            } else if !reject_thawing_signers || thaw_end_timestamp == 0 {
+                signers.push(id);
+            }
+        }
+        senders_to_signers.insert(sender, signers);
+    }
 
-    Ok(EscrowAccounts::new(senders_balances, senders_to_signers))
+    Ok((
+        EscrowAccounts::new(senders_balances, senders_to_signers)
+            .with_revoked_signers(revoked_signers),
+        block_timestamp,
+    ))
 }
 
 #[cfg(test)]
@@ -189,17 +658,24 @@ mod tests {
     use std::time::Duration;
 
     use test_assets::{
-        ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
+        mock_rpc::MockRpcServer, ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
         ESCROW_ACCOUNTS_SIGNERS_TO_SENDERS,
     };
     use test_log::test;
+    use thegraph_core::alloy::{
+        primitives::address,
+        sol_types::{SolCall, SolValue},
+    };
     use wiremock::{
-        matchers::{method, path},
+        matchers::{body_partial_json, method, path},
         Mock, MockServer, ResponseTemplate,
     };
 
     use super::*;
-    use crate::client::{DeploymentDetails, SubgraphClient};
+    use crate::{
+        client::{DeploymentDetails, SubgraphClient},
+        escrow_rpc::{authorizedSignersCall, escrowAccountsCall},
+    };
 
     #[test]
     fn test_new_escrow_accounts() {
@@ -214,6 +690,30 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_merge_escrow_accounts() {
+        let escrow_accounts = EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+        let merged = escrow_accounts.clone().merge(&escrow_accounts);
+
+        assert_eq!(
+            merged.signers_to_senders,
+            ESCROW_ACCOUNTS_SIGNERS_TO_SENDERS.to_owned()
+        );
+        assert_eq!(
+            merged.senders_to_signers,
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned()
+        );
+        for (sender, balance) in ESCROW_ACCOUNTS_BALANCES.iter() {
+            assert_eq!(
+                merged.get_balance_for_sender(sender).unwrap(),
+                balance.saturating_mul(U256::from(2))
+            );
+        }
+    }
+
     #[test(tokio::test)]
     async fn test_current_accounts() {
         // Set up a mock escrow subgraph
@@ -243,21 +743,247 @@ mod tests {
             );
         mock_server.register(mock).await;
 
-        let mut accounts = escrow_accounts_v1(
+        let interval = Duration::from_millis(100);
+        let accounts = escrow_accounts_v1(
+            escrow_subgraph,
+            test_assets::INDEXER_ADDRESS,
+            interval,
+            true,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let mut receiver = accounts.receiver();
+        assert_eq!(
+            receiver.borrow_and_update().clone(),
+            EscrowAccounts::new(
+                ESCROW_ACCOUNTS_BALANCES.to_owned(),
+                ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+            )
+        );
+
+        // the accounts are read again every interval
+        mock_server.reset().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(
+                ResponseTemplate::new(200).set_body_raw(
+                    r#"{ "data": { "escrowAccounts": [] } }"#,
+                    "application/json",
+                ),
+            ))
+            .await;
+        tokio::time::timeout(Duration::from_secs(5), receiver.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            receiver.borrow().clone(),
+            EscrowAccounts::new(HashMap::new(), HashMap::new())
+        );
+        assert!(accounts.is_fresh(interval * 2));
+    }
+
+    #[test(tokio::test)]
+    async fn test_revoked_signers() {
+        let sender = address!("9858EfFD232B4033E47d90003D41EC34EcaEda94");
+        let signer = address!("533661F0fb14d2E8B26223C86a610Dd7D2260892");
+        let revoked = address!("2740f6fA9188cF53ffB6729DDD21575721dE92ce");
+        let revoked_unknown = address!("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    // all the revoked signers without a max age
+                    .and(body_partial_json(
+                        serde_json::json!({ "variables": { "revokedSince": "0" } }),
+                    ))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "data": {
+                        "escrowAccounts": [{
+                            "id": "0",
+                            "balance": "34",
+                            "totalAmountThawing": "0",
+                            "sender": {
+                                "id": sender,
+                                "signers": [
+                                    { "id": signer, "isAuthorized": true, "thawEndTimestamp": "0" },
+                                    { "id": revoked, "isAuthorized": false, "thawEndTimestamp": "1000" },
+                                    { "id": revoked_unknown, "isAuthorized": false, "thawEndTimestamp": "0" }
+                                ]
+                            }
+                        }]
+                    }
+                }))),
+            )
+            .await;
+
+        let (accounts, _) =
+            get_escrow_accounts_v1(escrow_subgraph, test_assets::INDEXER_ADDRESS, true, None)
+                .await
+                .unwrap();
+        // revoked at an unknown time, none of its receipts are honored
+        assert!(accounts
+            .get_sender_for_any_signer(&revoked_unknown)
+            .is_err());
+        // no new receipts are accepted from the revoked signer
+        assert!(accounts.get_sender_for_signer(&revoked).is_err());
+        assert_eq!(accounts.get_signers_for_sender(&sender), vec![signer]);
+
+        // but the ones signed before it was revoked are still aggregated
+        assert_eq!(
+            accounts.get_sender_for_any_signer(&revoked).unwrap(),
+            sender
+        );
+        assert_eq!(
+            accounts
+                .get_sender_for_receipt_signer(&revoked, 999_000_000_000)
+                .unwrap(),
+            sender
+        );
+        assert!(matches!(
+            accounts.get_sender_for_receipt_signer(&revoked, 1_000_000_000_000),
+            Err(EscrowAccountsError::SignerRevoked {
+                revoked_at: 1000,
+                ..
+            })
+        ));
+        assert_eq!(
+            accounts.get_receipt_signers_for_sender(&sender),
+            vec![signer, revoked]
+        );
+
+        // kept by the watcher cache
+        let cached: EscrowAccounts =
+            serde_json::from_str(&serde_json::to_string(&accounts).unwrap()).unwrap();
+        assert_eq!(cached, accounts);
+    }
+
+    #[test(tokio::test)]
+    async fn test_rpc_fallback() {
+        let escrow_address = address!("3333333333333333333333333333333333333333");
+        let sender = address!("9858EfFD232B4033E47d90003D41EC34EcaEda94");
+        let signer = address!("533661F0fb14d2E8B26223C86a610Dd7D2260892");
+        let rpc = MockRpcServer::start().await;
+        rpc.set_call_result(
+            escrow_address,
+            escrowAccountsCall::SELECTOR.into(),
+            (U256::from(100), U256::from(10), U256::ZERO)
+                .abi_encode()
+                .into(),
+        );
+        // only the signers of `sender` are still authorized
+        rpc.set_call_result(
+            escrow_address,
+            authorizedSignersCall::SELECTOR.into(),
+            (sender, U256::ZERO).abi_encode().into(),
+        );
+        let fallback = EscrowRpcFallback::new(
+            reqwest::Client::new(),
+            rpc.url().parse().unwrap(),
+            escrow_address,
+            Duration::from_secs(300),
+        );
+
+        let mock_server = MockServer::start().await;
+        let escrow_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+        let state = Mutex::new(FallbackState::default());
+        let (fallback, state) = (&fallback, &state);
+        let fetch = move || {
+            get_escrow_accounts_v1_with_fallback(
+                escrow_subgraph,
+                test_assets::INDEXER_ADDRESS,
+                true,
+                None,
+                fallback,
+                state,
+            )
+        };
+
+        // nothing to refresh before the subgraph has been read once
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)))
+            .await;
+        assert!(fetch().await.is_err());
+
+        // but the configured signers
+        let configured = fallback.clone().with_signers(vec![signer]);
+        let cold_start_state = Mutex::new(FallbackState::default());
+        let accounts = get_escrow_accounts_v1_with_fallback(
             escrow_subgraph,
             test_assets::INDEXER_ADDRESS,
-            Duration::from_secs(60),
             true,
+            None,
+            &configured,
+            &cold_start_state,
         )
         .await
         .unwrap();
-        accounts.changed().await.unwrap();
+        assert_eq!(accounts.get_senders(), HashSet::from([sender]));
+        assert_eq!(
+            accounts.get_balance_for_sender(&sender).unwrap(),
+            U256::from(90)
+        );
+        assert_eq!(accounts.get_sender_for_signer(&signer).unwrap(), sender);
+        // the accounts of the subgraph are refreshed once it was read
+        assert!(cold_start_state
+            .lock()
+            .unwrap()
+            .last_subgraph_accounts
+            .is_none());
+
+        mock_server.reset().await;
+        mock_server
+            .register(
+                Mock::given(method("POST")).respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_raw(test_assets::ESCROW_QUERY_RESPONSE, "application/json"),
+                ),
+            )
+            .await;
+        let accounts = fetch().await.unwrap();
         assert_eq!(
-            accounts.borrow().clone(),
+            accounts,
             EscrowAccounts::new(
                 ESCROW_ACCOUNTS_BALANCES.to_owned(),
                 ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
             )
         );
+        assert_eq!(rpc.received_requests().await, 0);
+
+        mock_server.reset().await;
+        mock_server
+            .register(Mock::given(method("POST")).respond_with(ResponseTemplate::new(500)))
+            .await;
+        let accounts = fetch().await.unwrap();
+        assert_eq!(
+            accounts.get_senders(),
+            ESCROW_ACCOUNTS_BALANCES.keys().copied().collect()
+        );
+        assert_eq!(
+            accounts.get_balance_for_sender(&sender).unwrap(),
+            U256::from(90)
+        );
+        assert_eq!(accounts.get_sender_for_signer(&signer).unwrap(), sender);
+        assert_eq!(accounts.get_signers_for_sender(&sender).len(), 2);
+        assert!(accounts
+            .get_signers_for_sender(&address!("22d491bde2303f2f43325b2108d26f1eaba1e32b"))
+            .is_empty());
     }
 }
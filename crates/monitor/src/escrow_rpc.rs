@@ -0,0 +1,82 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads the escrow accounts from the TAP Escrow contract, used while the
+//! escrow subgraph is stale or unreachable
+
+use std::time::Duration;
+
+use reqwest::Url;
+use thegraph_core::alloy::{primitives::Address, sol};
+
+use crate::rpc::JsonRpcClient;
+
+sol! {
+    function escrowAccounts(address sender, address receiver)
+        returns (uint256 balance, uint256 amountThawing, uint256 thawEndTimestamp);
+    function authorizedSigners(address signer)
+        returns (address sender, uint256 thawEndTimestamp);
+}
+
+/// Escrow contract read through an Ethereum JSON-RPC endpoint
+#[derive(Debug, Clone)]
+pub(crate) struct EscrowContract {
+    rpc: JsonRpcClient,
+    address: Address,
+}
+
+/// Fallback of the escrow accounts watcher, see [`crate::escrow_accounts_v1`]
+#[derive(Debug, Clone)]
+pub struct EscrowRpcFallback {
+    pub(crate) contract: EscrowContract,
+    /// the subgraph is considered stale when its latest block is older than this
+    pub(crate) max_subgraph_lag: Duration,
+    /// signers read from the contract until the subgraph was read once
+    pub(crate) signers: Vec<Address>,
+}
+
+impl EscrowRpcFallback {
+    pub fn new(
+        http_client: reqwest::Client,
+        rpc_url: Url,
+        escrow_address: Address,
+        max_subgraph_lag: Duration,
+    ) -> Self {
+        Self {
+            contract: EscrowContract {
+                rpc: JsonRpcClient::new(http_client, rpc_url),
+                address: escrow_address,
+            },
+            max_subgraph_lag,
+            signers: Vec::new(),
+        }
+    }
+
+    /// Reads the accounts of the senders of `signers` from the contract
+    /// until the subgraph was read once
+    pub fn with_signers(mut self, signers: Vec<Address>) -> Self {
+        self.signers = signers;
+        self
+    }
+}
+
+impl EscrowContract {
+    pub(crate) async fn escrow_account(
+        &self,
+        sender: Address,
+        receiver: Address,
+    ) -> anyhow::Result<escrowAccountsReturn> {
+        self.rpc
+            .call(self.address, escrowAccountsCall { sender, receiver })
+            .await
+    }
+
+    pub(crate) async fn authorized_signer(
+        &self,
+        signer: Address,
+    ) -> anyhow::Result<authorizedSignersReturn> {
+        self.rpc
+            .call(self.address, authorizedSignersCall { signer })
+            .await
+    }
+}
@@ -1,21 +1,38 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod allocation_cache;
 mod allocations;
 mod attestation;
 mod client;
+mod contract_signers;
 mod deployment_to_allocation;
 mod dispute_manager;
 mod escrow_accounts;
+mod escrow_rpc;
+mod manifests;
+mod rpc;
+mod subgraph_cache;
 
 pub use crate::{
-    allocations::{indexer_allocations, AllocationWatcher},
-    attestation::{attestation_signers, AttestationWatcher},
+    allocation_cache::{AllocationCache, AllocationEntry},
+    allocations::{
+        allocation_ids, indexer_allocations, merge_allocations, AllocationIdsWatcher,
+        AllocationWatcher,
+    },
+    attestation::{attestation_signers, attestation_signers_by_indexer, AttestationWatcher},
     client::{DeploymentDetails, SubgraphClient},
-    deployment_to_allocation::{deployment_to_allocation, DeploymentToAllocationWatcher},
+    contract_signers::ContractSigners,
+    deployment_to_allocation::{
+        denied_deployments, deployment_to_allocation, DeniedDeploymentsWatcher,
+        DeploymentToAllocationWatcher,
+    },
     dispute_manager::{dispute_manager, DisputeManagerWatcher},
     escrow_accounts::{
-        escrow_accounts_v1, escrow_accounts_v2, EscrowAccounts, EscrowAccountsError,
-        EscrowAccountsWatcher,
+        escrow_accounts_v1, escrow_accounts_v2, merge_escrow_accounts, EscrowAccounts,
+        EscrowAccountsError, EscrowAccountsWatcher, RevokedSigner,
     },
+    escrow_rpc::EscrowRpcFallback,
+    manifests::{subgraph_manifests, IpfsClient, SubgraphManifest, SubgraphManifestsWatcher},
+    subgraph_cache::SubgraphCache,
 };
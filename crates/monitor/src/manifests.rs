@@ -0,0 +1,253 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::Duration,
+};
+
+use anyhow::Context;
+use reqwest::Url;
+use serde::Deserialize;
+use thegraph_core::DeploymentId;
+use tokio::sync::watch::{self, Receiver};
+
+use crate::AllocationWatcher;
+
+/// Watcher of the manifests of the deployments the indexer is allocated to
+pub type SubgraphManifestsWatcher = Receiver<HashMap<DeploymentId, SubgraphManifest>>;
+
+/// How long to wait before fetching the manifests that could not be fetched again
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time given to the IPFS node to return a file, a missing file is never
+/// returned by some nodes
+const IPFS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parts of a subgraph manifest describing what a deployment needs from graph-node
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubgraphManifest {
+    pub spec_version: String,
+    /// Features declared by the manifest, e.g. `grafting` or `fullTextSearch`
+    pub features: BTreeSet<String>,
+    /// Kinds of the data sources and templates, e.g. `ethereum/contract`
+    pub data_source_kinds: BTreeSet<String>,
+}
+
+impl SubgraphManifest {
+    pub fn from_yaml(content: &[u8]) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct DataSource {
+            kind: String,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Manifest {
+            spec_version: String,
+            #[serde(default)]
+            features: BTreeSet<String>,
+            #[serde(default)]
+            data_sources: Vec<DataSource>,
+            #[serde(default)]
+            templates: Vec<DataSource>,
+        }
+
+        let manifest: Manifest = serde_yaml::from_slice(content)?;
+        Ok(Self {
+            spec_version: manifest.spec_version,
+            features: manifest.features,
+            data_source_kinds: manifest
+                .data_sources
+                .into_iter()
+                .chain(manifest.templates)
+                .map(|data_source| data_source.kind)
+                .collect(),
+        })
+    }
+
+    /// Features and data source kinds used by the deployment
+    pub fn uses(&self) -> impl Iterator<Item = &str> {
+        self.features
+            .iter()
+            .chain(&self.data_source_kinds)
+            .map(String::as_str)
+    }
+}
+
+/// Client of the HTTP API of an IPFS node or gateway, shared by the
+/// manifests watcher and the DIPS agreements
+#[derive(Debug, Clone)]
+pub struct IpfsClient {
+    http_client: reqwest::Client,
+    url: Url,
+}
+
+impl IpfsClient {
+    pub fn new(http_client: reqwest::Client, url: Url) -> Self {
+        Self { http_client, url }
+    }
+
+    /// Content of the file at `path`, e.g. the hash of a deployment
+    pub async fn cat(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let content = self
+            .http_client
+            .post(self.url.join("api/v0/cat")?)
+            .query(&[("arg", path)])
+            .timeout(IPFS_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch {path} from IPFS"))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to fetch {path} from IPFS"))?;
+        Ok(content.to_vec())
+    }
+
+    pub async fn fetch_manifest(
+        &self,
+        deployment: &DeploymentId,
+    ) -> anyhow::Result<SubgraphManifest> {
+        let content = self
+            .cat(&deployment.to_string())
+            .await
+            .with_context(|| format!("Failed to fetch the manifest of {deployment}"))?;
+        SubgraphManifest::from_yaml(&content)
+            .with_context(|| format!("Invalid manifest for {deployment}"))
+    }
+}
+
+/// Watcher of the manifests of the deployments in `indexer_allocations`
+///
+/// Manifests are immutable, each one is fetched once when the indexer
+/// allocates to its deployment. The manifests that could not be fetched
+/// are missing from the map until they are fetched again.
+pub fn subgraph_manifests(
+    mut indexer_allocations: AllocationWatcher,
+    ipfs_client: IpfsClient,
+) -> SubgraphManifestsWatcher {
+    let (tx, rx) = watch::channel(HashMap::new());
+    tokio::spawn(async move {
+        let mut manifests: HashMap<DeploymentId, SubgraphManifest> = HashMap::new();
+        loop {
+            let deployments: HashSet<DeploymentId> = indexer_allocations
+                .borrow_and_update()
+                .values()
+                .map(|allocation| allocation.subgraph_deployment.id)
+                .collect();
+            manifests.retain(|deployment, _| deployments.contains(deployment));
+
+            let mut missing = false;
+            for deployment in deployments {
+                if manifests.contains_key(&deployment) {
+                    continue;
+                }
+                match ipfs_client.fetch_manifest(&deployment).await {
+                    Ok(manifest) => {
+                        manifests.insert(deployment, manifest);
+                    }
+                    Err(error) => {
+                        tracing::warn!(%deployment, "{error:#}");
+                        missing = true;
+                    }
+                }
+            }
+            if tx.send(manifests.clone()).is_err() {
+                break;
+            }
+
+            tokio::select! {
+                changed = indexer_allocations.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep(RETRY_INTERVAL), if missing => {}
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use reqwest::Url;
+    use test_assets::INDEXER_ALLOCATIONS;
+    use tokio::sync::watch;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{subgraph_manifests, IpfsClient, SubgraphManifest};
+
+    const MANIFEST: &str = "
+specVersion: 0.0.5
+features:
+  - fullTextSearch
+  - grafting
+dataSources:
+  - kind: ethereum/contract
+    name: Factory
+    network: mainnet
+  - kind: ethereum/contract
+    name: Pool
+    network: mainnet
+templates:
+  - kind: file/ipfs
+    name: Metadata
+";
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = SubgraphManifest::from_yaml(MANIFEST.as_bytes()).unwrap();
+        assert_eq!(manifest.spec_version, "0.0.5");
+        assert_eq!(
+            manifest.uses().collect::<Vec<_>>(),
+            vec![
+                "fullTextSearch",
+                "grafting",
+                "ethereum/contract",
+                "file/ipfs"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subgraph_manifests() {
+        let mock_server = MockServer::start().await;
+        let allocations = INDEXER_ALLOCATIONS.clone();
+        let deployment = allocations.values().next().unwrap().subgraph_deployment.id;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(path("/api/v0/cat"))
+                    .and(query_param("arg", deployment.to_string()))
+                    .respond_with(ResponseTemplate::new(200).set_body_string(MANIFEST)),
+            )
+            .await;
+
+        let ipfs_client = IpfsClient::new(
+            reqwest::Client::new(),
+            Url::parse(&mock_server.uri()).unwrap(),
+        );
+        let (_tx, allocations_rx) = watch::channel(allocations);
+        let mut manifests = subgraph_manifests(allocations_rx, ipfs_client);
+        tokio::time::timeout(Duration::from_secs(5), manifests.changed())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the manifests of the other deployments could not be fetched
+        assert_eq!(
+            *manifests.borrow(),
+            HashMap::from([(
+                deployment,
+                SubgraphManifest::from_yaml(MANIFEST.as_bytes()).unwrap()
+            )])
+        );
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal Ethereum JSON-RPC client, for the few contract reads the
+//! watchers make
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thegraph_core::alloy::{
+    primitives::{Address, Bytes},
+    sol_types::SolCall,
+};
+
+/// Time given to the RPC endpoint to answer a request, the receipts being
+/// verified wait for some of them
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub(crate) struct JsonRpcClient {
+    http_client: reqwest::Client,
+    rpc_url: Url,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcResponse<T> {
+    result: Option<T>,
+    error: Option<Value>,
+}
+
+impl JsonRpcClient {
+    pub(crate) fn new(http_client: reqwest::Client, rpc_url: Url) -> Self {
+        Self {
+            http_client,
+            rpc_url,
+        }
+    }
+
+    /// Calls `call` on the contract at `to`, at the latest block
+    pub(crate) async fn call<C: SolCall>(&self, to: Address, call: C) -> anyhow::Result<C::Return> {
+        let input = Bytes::from(call.abi_encode());
+        let result: Bytes = self
+            .request("eth_call", json!([{ "to": to, "input": input }, "latest"]))
+            .await
+            .with_context(|| format!("Failed to call {} on {to}", C::SIGNATURE))?;
+        C::abi_decode_returns(&result, true)
+            .with_context(|| format!("Invalid result of {}", C::SIGNATURE))
+    }
+
+    /// Code of the account at `address`, empty if it is not a contract
+    pub(crate) async fn get_code(&self, address: Address) -> anyhow::Result<Bytes> {
+        self.request("eth_getCode", json!([address, "latest"]))
+            .await
+            .with_context(|| format!("Failed to get the code of {address}"))
+    }
+
+    async fn request<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> anyhow::Result<T> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let response: JsonRpcResponse<T> = self
+            .http_client
+            .post(self.rpc_url.clone())
+            .timeout(RPC_TIMEOUT)
+            .json(&request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())?
+            .json()
+            .await
+            .context("Invalid JSON-RPC response")?;
+        match (response.result, response.error) {
+            (Some(result), _) => Ok(result),
+            (None, error) => Err(anyhow!("{}", error.unwrap_or_default())),
+        }
+    }
+}
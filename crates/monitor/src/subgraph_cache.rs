@@ -0,0 +1,220 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Last allocations, escrow accounts and dispute manager read from the
+//! subgraphs, saved on disk so that a service started while the subgraphs
+//! are down can serve with them instead of waiting
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+use serde::{de::DeserializeOwned, Serialize};
+
+lazy_static! {
+    static ref SUBGRAPH_CACHE_STALE: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_subgraph_cache_stale",
+        "Whether the values saved on disk are used, the subgraph not having been read since the start",
+        &["cache"]
+    )
+    .unwrap();
+}
+
+/// Directory the last values read from the subgraphs are saved in, one JSON
+/// file per watcher
+///
+/// The values saved more than `max_age` ago are not used, the subgraph is
+/// waited for instead.
+#[derive(Debug, Clone)]
+pub struct SubgraphCache {
+    directory: PathBuf,
+    max_age: Duration,
+}
+
+impl SubgraphCache {
+    pub fn new(directory: impl Into<PathBuf>, max_age: Duration) -> Self {
+        Self {
+            directory: directory.into(),
+            max_age,
+        }
+    }
+
+    /// Entry of a watcher, `name` must be unique to it
+    ///
+    /// The indexer-service and tap-agent watching the same indexer share
+    /// their entries, each writes to its own temporary file.
+    pub(crate) fn entry(&self, name: String) -> CacheEntry {
+        static ENTRIES: AtomicUsize = AtomicUsize::new(0);
+        let entry = ENTRIES.fetch_add(1, Ordering::Relaxed);
+        CacheEntry {
+            path: self.directory.join(format!("{name}.json")),
+            tmp_path: self
+                .directory
+                .join(format!("{name}.json.{}-{entry}.tmp", std::process::id())),
+            name,
+            max_age: self.max_age,
+            read: Default::default(),
+            saved: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CacheEntry {
+    name: String,
+    path: PathBuf,
+    /// written then renamed to `path`, so that a file being written is never read
+    tmp_path: PathBuf,
+    max_age: Duration,
+    /// whether a value was read, from the subgraph or from the disk
+    read: Arc<AtomicBool>,
+    /// hash of the value last saved, not saved again while it doesn't change
+    saved: Arc<Mutex<Option<u64>>>,
+}
+
+impl CacheEntry {
+    /// Saves the value read from the subgraph. If the first read failed, the
+    /// value last saved is returned instead, and reported as stale until the
+    /// subgraph is read.
+    pub(crate) async fn or_cached<T>(&self, result: anyhow::Result<T>) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let error = match result {
+            Ok(value) => {
+                self.read.store(true, Ordering::Relaxed);
+                SUBGRAPH_CACHE_STALE.with_label_values(&[&self.name]).set(0);
+                if let Err(error) = self.save(&value).await {
+                    tracing::warn!(%error, cache = %self.path.display(), "Failed to save to the subgraph cache");
+                }
+                return Ok(value);
+            }
+            Err(error) => error,
+        };
+        if self.read.load(Ordering::Relaxed) {
+            return Err(error);
+        }
+
+        match self.load().await {
+            Ok(Some(value)) => {
+                tracing::warn!(
+                    %error,
+                    cache = %self.path.display(),
+                    "Using the values saved in the subgraph cache until the subgraph can be read"
+                );
+                self.read.store(true, Ordering::Relaxed);
+                SUBGRAPH_CACHE_STALE.with_label_values(&[&self.name]).set(1);
+                Ok(value)
+            }
+            Ok(None) => Err(error),
+            Err(cache_error) => Err(error.context(format!(
+                "Failed to read the subgraph cache: {cache_error:#}"
+            ))),
+        }
+    }
+
+    async fn save<T: Serialize>(&self, value: &T) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(value)?;
+        let mut hasher = DefaultHasher::new();
+        json.hash(&mut hasher);
+        let hash = hasher.finish();
+        if *self.saved.lock().unwrap() == Some(hash) {
+            return Ok(());
+        }
+
+        if let Some(directory) = self.path.parent() {
+            tokio::fs::create_dir_all(directory).await?;
+        }
+        tokio::fs::write(&self.tmp_path, json).await?;
+        tokio::fs::rename(&self.tmp_path, &self.path).await?;
+        *self.saved.lock().unwrap() = Some(hash);
+        Ok(())
+    }
+
+    /// Value last saved, `None` if there is none or it's older than `max_age`
+    async fn load<T: DeserializeOwned>(&self) -> anyhow::Result<Option<T>> {
+        let saved_at = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.modified()?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let age = SystemTime::now()
+            .duration_since(saved_at)
+            .unwrap_or_default();
+        if age > self.max_age {
+            tracing::warn!(
+                cache = %self.path.display(),
+                age_secs = age.as_secs(),
+                "The values saved in the subgraph cache are too old to be used"
+            );
+            return Ok(None);
+        }
+        let json = tokio::fs::read(&self.path).await?;
+        serde_json::from_slice(&json)
+            .map(Some)
+            .with_context(|| format!("Invalid subgraph cache {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use anyhow::anyhow;
+    use indexer_allocation::Allocation;
+    use thegraph_core::alloy::primitives::Address;
+
+    use super::SubgraphCache;
+
+    #[tokio::test]
+    async fn test_subgraph_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = SubgraphCache::new(directory.path().join("cache"), Duration::from_secs(60));
+        let allocations = test_assets::INDEXER_ALLOCATIONS.clone();
+
+        // nothing saved yet
+        let entry = cache.entry("allocations".to_string());
+        let result = entry
+            .or_cached::<HashMap<Address, Allocation>>(Err(anyhow!("unreachable")))
+            .await;
+        assert!(result.is_err());
+        let result = entry.or_cached(Ok(allocations.clone())).await;
+        assert_eq!(result.unwrap(), allocations);
+
+        // after a restart, used if the first read fails
+        let entry = cache.entry("allocations".to_string());
+        let result = entry
+            .or_cached::<HashMap<Address, Allocation>>(Err(anyhow!("unreachable")))
+            .await;
+        assert_eq!(result.unwrap(), allocations);
+        // then the watcher keeps its value
+        let result = entry
+            .or_cached::<HashMap<Address, Allocation>>(Err(anyhow!("unreachable")))
+            .await;
+        assert!(result.is_err());
+
+        // other watchers have their own entries
+        let entry = cache.entry("other".to_string());
+        let result = entry
+            .or_cached::<HashMap<Address, Allocation>>(Err(anyhow!("unreachable")))
+            .await;
+        assert!(result.is_err());
+
+        // too old to be used
+        let cache = SubgraphCache::new(directory.path().join("cache"), Duration::ZERO);
+        let entry = cache.entry("allocations".to_string());
+        let result = entry
+            .or_cached::<HashMap<Address, Allocation>>(Err(anyhow!("unreachable")))
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -7,6 +7,7 @@ pub mod dispute_manager {
     use graphql_client::GraphQLQuery;
     use thegraph_core::alloy::primitives::Address;
     type Bytes = Address;
+    type BigInt = String;
 
     #[derive(GraphQLQuery)]
     #[graphql(
@@ -23,6 +24,7 @@ pub mod dispute_manager {
 pub mod escrow_account {
     use graphql_client::GraphQLQuery;
     type BigInt = String;
+    type Bytes = String;
 
     #[derive(GraphQLQuery)]
     #[graphql(
@@ -33,7 +35,7 @@ pub mod escrow_account {
     )]
     pub struct EscrowAccountQuery;
 
-    pub use escrow_account_query::Variables;
+    pub use escrow_account_query::{Block_height, Variables};
 }
 
 pub mod allocations_query {
@@ -54,6 +56,21 @@ pub mod allocations_query {
     pub use allocations_query::*;
 }
 
+pub mod allocation_query {
+    use graphql_client::GraphQLQuery;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "graphql/network.schema.graphql",
+        query_path = "graphql/allocation.query.graphql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct AllocationQuery;
+
+    pub use allocation_query::*;
+}
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "graphql/indexing_status.schema.graphql",
@@ -63,6 +80,54 @@ pub mod allocations_query {
 )]
 pub struct HealthQuery;
 
+pub mod chain_heads_query {
+    use graphql_client::GraphQLQuery;
+    type BigInt = String;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "graphql/indexing_status.schema.graphql",
+        query_path = "graphql/chain_heads.query.graphql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct ChainHeadsQuery;
+
+    pub use chain_heads_query::*;
+}
+
+pub mod indexed_blocks_query {
+    use graphql_client::GraphQLQuery;
+    type BigInt = String;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "graphql/indexing_status.schema.graphql",
+        query_path = "graphql/indexed_blocks.query.graphql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct IndexedBlocksQuery;
+
+    pub use indexed_blocks_query::*;
+}
+
+pub mod sync_progress_query {
+    use graphql_client::GraphQLQuery;
+    type BigInt = String;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "graphql/indexing_status.schema.graphql",
+        query_path = "graphql/sync_progress.query.graphql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct SyncProgressQuery;
+
+    pub use sync_progress_query::*;
+}
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "graphql/network.schema.graphql",
@@ -72,6 +137,15 @@ pub struct HealthQuery;
 )]
 pub struct CurrentEpoch;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/network.schema.graphql",
+    query_path = "graphql/network_meta.query.graphql",
+    response_derives = "Debug",
+    variables_derives = "Clone"
+)]
+pub struct NetworkSubgraphMeta;
+
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "graphql/test.schema.graphql",
@@ -90,14 +164,21 @@ pub struct UserQuery;
 )]
 pub struct DeploymentStatusQuery;
 
-#[derive(GraphQLQuery)]
-#[graphql(
-    schema_path = "graphql/tap.schema.graphql",
-    query_path = "graphql/unfinalized_tx.query.graphql",
-    response_derives = "Debug",
-    variables_derives = "Clone"
-)]
-pub struct UnfinalizedTransactions;
+pub mod unfinalized_transactions {
+    use graphql_client::GraphQLQuery;
+
+    type Bytes = String;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "graphql/tap.schema.graphql",
+        query_path = "graphql/unfinalized_tx.query.graphql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct UnfinalizedTransactions;
+    pub use unfinalized_transactions::*;
+}
 
 pub mod closed_allocations {
     use graphql_client::GraphQLQuery;
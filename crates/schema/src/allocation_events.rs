@@ -0,0 +1,192 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Timeline of the allocations, stored in `allocation_events`
+//!
+//! The service records the events observed by the allocations monitor, and
+//! tap-agent the ones of the RAVs. An event is only stored once per
+//! allocation, and per sender for the RAV events, so every replica can
+//! record the events it observes.
+
+use std::{fmt, str::FromStr};
+
+use serde::Serialize;
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
+
+/// Event of the lifecycle of an allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationEvent {
+    /// Seen for the first time by the allocations monitor
+    Created,
+    /// Seen open with a deployment that is not denied, its receipts are
+    /// accepted
+    Eligible,
+    /// Seen closed, or no longer returned by the network subgraph
+    Closed,
+    /// Last RAV of a sender requested by tap-agent
+    FinalRavRequested,
+    /// Last RAV of a sender redeemed
+    RavRedeemed,
+}
+
+impl AllocationEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AllocationEvent::Created => "created",
+            AllocationEvent::Eligible => "eligible",
+            AllocationEvent::Closed => "closed",
+            AllocationEvent::FinalRavRequested => "final_rav_requested",
+            AllocationEvent::RavRedeemed => "rav_redeemed",
+        }
+    }
+}
+
+impl fmt::Display for AllocationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AllocationEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(event: &str) -> Result<Self, Self::Err> {
+        match event {
+            "created" => Ok(AllocationEvent::Created),
+            "eligible" => Ok(AllocationEvent::Eligible),
+            "closed" => Ok(AllocationEvent::Closed),
+            "final_rav_requested" => Ok(AllocationEvent::FinalRavRequested),
+            "rav_redeemed" => Ok(AllocationEvent::RavRedeemed),
+            _ => Err(anyhow::anyhow!("Unknown allocation event `{event}`")),
+        }
+    }
+}
+
+/// Event stored in `allocation_events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedAllocationEvent {
+    pub event: AllocationEvent,
+    /// Sender of the RAV events
+    pub sender: Option<Address>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Whether the `allocation_events` table exists, it may not on a database
+/// migrated by an older indexer-agent
+pub async fn allocation_events_table_exists(pgpool: &PgPool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(r#"SELECT to_regclass('allocation_events') IS NOT NULL AS "exists!""#)
+        .fetch_one(pgpool)
+        .await
+}
+
+/// Stores `event`, unless it was already stored for the allocation and
+/// `sender`. Returns whether it was stored
+///
+/// The event is stored as happening at `observed_at`, e.g. the timestamp of
+/// the block it was observed at, or now if not set.
+pub async fn record_allocation_event(
+    pgpool: &PgPool,
+    allocation_id: Address,
+    event: AllocationEvent,
+    sender: Option<Address>,
+    observed_at: Option<DateTime<Utc>>,
+) -> anyhow::Result<bool> {
+    let result = sqlx::query!(
+        r#"
+            INSERT INTO allocation_events (allocation_id, event, sender_address, recorded_at)
+            VALUES ($1, $2, $3, COALESCE($4, NOW()))
+            ON CONFLICT (allocation_id, event, sender_address) DO NOTHING
+        "#,
+        allocation_id.encode_hex(),
+        event.as_str(),
+        sender.map(|sender| sender.encode_hex()).unwrap_or_default(),
+        observed_at
+    )
+    .execute(pgpool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Events of the allocation, oldest first
+pub async fn allocation_events(
+    pgpool: &PgPool,
+    allocation_id: Address,
+) -> anyhow::Result<Vec<RecordedAllocationEvent>> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT event, sender_address, recorded_at
+            FROM allocation_events
+            WHERE allocation_id = $1
+            ORDER BY recorded_at, id
+        "#,
+        allocation_id.encode_hex()
+    )
+    .fetch_all(pgpool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            Ok(RecordedAllocationEvent {
+                event: row.event.parse()?,
+                sender: match row.sender_address.as_str() {
+                    "" => None,
+                    sender => Some(sender.parse()?),
+                },
+                recorded_at: row.recorded_at,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{
+        types::chrono::{TimeZone, Utc},
+        PgPool,
+    };
+    use thegraph_core::alloy::primitives::address;
+
+    use super::{
+        allocation_events, allocation_events_table_exists, record_allocation_event, AllocationEvent,
+    };
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_recorded_at_observed_block(pgpool: PgPool) {
+        let allocation_id = address!("fa44c72b753a66591f241c7dc04e8178c30e13af");
+        let observed_at = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        assert!(allocation_events_table_exists(&pgpool).await.unwrap());
+        assert!(record_allocation_event(
+            &pgpool,
+            allocation_id,
+            AllocationEvent::Created,
+            None,
+            Some(observed_at)
+        )
+        .await
+        .unwrap());
+        // already recorded by another replica, the first block is kept
+        assert!(!record_allocation_event(
+            &pgpool,
+            allocation_id,
+            AllocationEvent::Created,
+            None,
+            None
+        )
+        .await
+        .unwrap());
+
+        let events = allocation_events(&pgpool, allocation_id).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].recorded_at, observed_at);
+
+        sqlx::query!("DROP TABLE allocation_events")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert!(!allocation_events_table_exists(&pgpool).await.unwrap());
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Database schema compatibility
+//!
+//! The migrations are run by indexer-agent, so indexer-service and tap-agent
+//! may start against a database that is behind them. The schema is checked
+//! at startup against the migrations the binaries are built with and the
+//! tables they use, so a mismatch is reported as such instead of failing
+//! queries later on.
+//!
+//! Operators without indexer-agent can have the binaries run the migrations
+//! instead, see [run_migrations].
+//!
+//! The storage of the tables written by both binaries, like the timeline of
+//! the allocations, lives here too, so the crates they share don't depend on
+//! the database.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use sqlx::{migrate::Migrator, PgPool};
+
+mod allocation_events;
+mod migrate;
+
+pub use allocation_events::{
+    allocation_events, allocation_events_table_exists, record_allocation_event, AllocationEvent,
+    RecordedAllocationEvent,
+};
+pub use migrate::{run_migrations, AGENT_MIGRATIONS};
+
+/// Migrations of this release, embedded at build time
+pub static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
+
+/// Table, or view, read or written by a binary along with the columns it uses
+#[derive(Debug, Clone, Copy)]
+pub struct RequiredTable {
+    pub name: &'static str,
+    pub columns: &'static [&'static str],
+}
+
+/// Differences between the database schema and the one expected
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    /// Migrations of this release that were not applied, as `(version, description)`
+    pub missing_migrations: Vec<(i64, String)>,
+    /// Applied migrations that are not part of this release, the database
+    /// was migrated by a newer release
+    pub unknown_migrations: Vec<i64>,
+    /// Migrations applied with a different content than the one of this release
+    pub modified_migrations: Vec<i64>,
+    pub missing_tables: Vec<String>,
+    /// Missing columns of existing tables, as `(table, column)`
+    pub missing_columns: Vec<(String, String)>,
+}
+
+impl SchemaDiff {
+    /// The binary can't run against this schema. A database migrated by a
+    /// newer release is fine, as long as the tables and columns are there.
+    pub fn is_incompatible(&self) -> bool {
+        !self.missing_migrations.is_empty()
+            || !self.missing_tables.is_empty()
+            || !self.missing_columns.is_empty()
+    }
+}
+
+impl fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (version, description) in &self.missing_migrations {
+            writeln!(f, "- migration {version} ({description}) is not applied")?;
+        }
+        for version in &self.unknown_migrations {
+            writeln!(f, "~ migration {version} is not part of this release")?;
+        }
+        for version in &self.modified_migrations {
+            writeln!(
+                f,
+                "~ migration {version} was applied with a different content"
+            )?;
+        }
+        for table in &self.missing_tables {
+            writeln!(f, "- table {table} is missing")?;
+        }
+        for (table, column) in &self.missing_columns {
+            writeln!(f, "- column {table}.{column} is missing")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error(
+        "The database schema is not compatible with this release, upgrade \
+        indexer-agent so it runs the missing migrations:\n{0}"
+    )]
+    Incompatible(SchemaDiff),
+    #[error("Failed to read the database schema: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Compares the schema of the database with [MIGRATOR] and `required`
+///
+/// The migrations are only compared if the database has a `_sqlx_migrations`
+/// table, the tables and columns are always checked.
+pub async fn check_schema(
+    pgpool: &PgPool,
+    required: &[RequiredTable],
+) -> Result<SchemaDiff, sqlx::Error> {
+    let mut diff = SchemaDiff::default();
+
+    let has_migrations =
+        sqlx::query_scalar!(r#"SELECT to_regclass('_sqlx_migrations') IS NOT NULL AS "exists!""#)
+            .fetch_one(pgpool)
+            .await?;
+    if has_migrations {
+        let applied: HashMap<i64, Vec<u8>> =
+            sqlx::query!("SELECT version, checksum FROM _sqlx_migrations WHERE success")
+                .fetch_all(pgpool)
+                .await?
+                .into_iter()
+                .map(|row| (row.version, row.checksum))
+                .collect();
+        let mut expected = HashSet::new();
+        for migration in MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+        {
+            expected.insert(migration.version);
+            match applied.get(&migration.version) {
+                None => diff
+                    .missing_migrations
+                    .push((migration.version, migration.description.to_string())),
+                Some(checksum) if *checksum != *migration.checksum => {
+                    diff.modified_migrations.push(migration.version)
+                }
+                Some(_) => {}
+            }
+        }
+        diff.unknown_migrations = applied
+            .into_keys()
+            .filter(|version| !expected.contains(version))
+            .collect();
+        diff.unknown_migrations.sort();
+    }
+
+    let mut columns: HashMap<String, HashSet<String>> = HashMap::new();
+    let rows = sqlx::query!(
+        r#"
+            SELECT table_name::TEXT AS "table_name!", column_name::TEXT AS "column_name!"
+            FROM information_schema.columns
+            WHERE table_schema = current_schema()
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+    for row in rows {
+        columns
+            .entry(row.table_name)
+            .or_default()
+            .insert(row.column_name);
+    }
+    for table in required {
+        let Some(existing) = columns.get(table.name) else {
+            diff.missing_tables.push(table.name.to_string());
+            continue;
+        };
+        for column in table.columns {
+            if !existing.contains(*column) {
+                diff.missing_columns
+                    .push((table.name.to_string(), column.to_string()));
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Checks the schema when a binary starts, see [check_schema]
+///
+/// An incompatible schema is only logged unless `strict` is set, as the
+/// binary may not use the missing parts.
+pub async fn verify_schema(
+    pgpool: &PgPool,
+    required: &[RequiredTable],
+    strict: bool,
+) -> Result<(), SchemaError> {
+    let diff = match check_schema(pgpool, required).await {
+        Ok(diff) => diff,
+        Err(error) if !strict => {
+            tracing::warn!(%error, "Failed to read the database schema, not checking it");
+            return Ok(());
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    if diff.is_incompatible() {
+        if strict {
+            return Err(SchemaError::Incompatible(diff));
+        }
+        tracing::warn!(
+            "The database schema is not compatible with this release, upgrade \
+            indexer-agent so it runs the missing migrations, or start with \
+            --strict-schema to refuse to run:\n{diff}"
+        );
+    } else if diff != SchemaDiff::default() {
+        tracing::info!("The database schema differs from this release:\n{diff}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{check_schema, RequiredTable, SchemaDiff};
+
+    const REQUIRED: &[RequiredTable] = &[
+        RequiredTable {
+            name: "scalar_tap_ravs",
+            columns: &["sender_address", "allocation_id", "last", "final"],
+        },
+        RequiredTable {
+            name: "tap_fees_receipts_daily",
+            columns: &["allocation_id", "value"],
+        },
+    ];
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_migrated_schema(pgpool: PgPool) {
+        let diff = check_schema(&pgpool, REQUIRED).await.unwrap();
+        assert_eq!(diff, SchemaDiff::default());
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_outdated_schema(pgpool: PgPool) {
+        sqlx::query!("ALTER TABLE scalar_tap_denylist DROP COLUMN sender_address")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query!("DELETE FROM _sqlx_migrations WHERE version = 20250401120000")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query!("INSERT INTO _sqlx_migrations SELECT 99990101000000, 'newer', installed_on, success, checksum, execution_time FROM _sqlx_migrations LIMIT 1")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+
+        let required = [
+            RequiredTable {
+                name: "scalar_tap_denylist",
+                columns: &["sender_address"],
+            },
+            RequiredTable {
+                name: "tap_agent_state_v2",
+                columns: &[],
+            },
+        ];
+        let diff = check_schema(&pgpool, &[REQUIRED, &required].concat())
+            .await
+            .unwrap();
+        assert!(diff.is_incompatible());
+        assert_eq!(
+            diff,
+            SchemaDiff {
+                missing_migrations: vec![(20250401120000, "tap agent state".to_string())],
+                unknown_migrations: vec![99990101000000],
+                modified_migrations: vec![],
+                missing_tables: vec!["tap_agent_state_v2".to_string()],
+                missing_columns: vec![(
+                    "scalar_tap_denylist".to_string(),
+                    "sender_address".to_string()
+                )],
+            }
+        );
+    }
+}
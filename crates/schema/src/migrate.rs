@@ -0,0 +1,129 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Migrations run by the binaries themselves, for the operators that don't
+//! run indexer-agent
+//!
+//! Only the migrations of the TAP and DIPS tables are run, the ones of the
+//! tables owned by indexer-agent, like the cost models, are left to it.
+//!
+//! The migrations are run holding the advisory lock of sqlx, the one of
+//! `sqlx migrate run`, derived from the name of the database. indexer-agent
+//! takes no lock while migrating, so the binaries must not run their
+//! migrations while indexer-agent runs its own.
+
+use std::collections::HashMap;
+
+use sqlx::{
+    migrate::{Migrate, MigrateError},
+    PgConnection, PgPool,
+};
+
+use crate::MIGRATOR;
+
+/// Versions of the migrations of the tables owned by indexer-agent
+pub const AGENT_MIGRATIONS: &[i64] = &[
+    20230901142040, // cost models
+    20241024191258, // cost model notifications
+];
+
+/// Runs the migrations of [crate::MIGRATOR] that were not applied yet, but
+/// [AGENT_MIGRATIONS], and returns their versions
+///
+/// Fails if an applied migration has a different content than the one of this
+/// release, or if a previous run failed halfway.
+pub async fn run_migrations(pgpool: &PgPool) -> Result<Vec<i64>, MigrateError> {
+    let mut conn = pgpool.acquire().await?;
+    // same lock as sqlx-cli, so replicas starting together and `sqlx migrate
+    // run` don't run the same migrations concurrently
+    conn.lock().await?;
+    let result = apply_migrations(&mut conn).await;
+    // the lock is held by the session, it must be released before the
+    // connection goes back to the pool
+    conn.unlock().await?;
+    result
+}
+
+async fn apply_migrations(conn: &mut PgConnection) -> Result<Vec<i64>, MigrateError> {
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(MigrateError::Dirty(version));
+    }
+    let applied: HashMap<_, _> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect();
+
+    let mut versions = Vec::new();
+    for migration in MIGRATOR.iter().filter(|migration| {
+        !migration.migration_type.is_down_migration()
+            && !AGENT_MIGRATIONS.contains(&migration.version)
+    }) {
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version));
+            }
+            Some(_) => {}
+            None => {
+                let elapsed = conn.apply(migration).await?;
+                tracing::info!(
+                    version = migration.version,
+                    description = %migration.description,
+                    ?elapsed,
+                    "Applied migration"
+                );
+                versions.push(migration.version);
+            }
+        }
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::{migrate::Migrate, PgPool};
+
+    use super::{run_migrations, AGENT_MIGRATIONS};
+    use crate::check_schema;
+
+    #[sqlx::test(migrations = false)]
+    async fn test_run_migrations(pgpool: PgPool) {
+        let applied = run_migrations(&pgpool).await.unwrap();
+        assert!(!applied.is_empty());
+        assert!(applied
+            .iter()
+            .all(|version| !AGENT_MIGRATIONS.contains(version)));
+
+        // only the migrations of indexer-agent are left
+        let diff = check_schema(&pgpool, &[]).await.unwrap();
+        let missing: Vec<_> = diff
+            .missing_migrations
+            .iter()
+            .map(|(version, _)| *version)
+            .collect();
+        assert_eq!(missing, AGENT_MIGRATIONS);
+
+        assert!(run_migrations(&pgpool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrations_wait_for_sqlx_lock(pgpool: PgPool) {
+        // held like `sqlx migrate run` does
+        let mut conn = pgpool.acquire().await.unwrap();
+        conn.lock().await.unwrap();
+
+        let migrations = tokio::spawn({
+            let pgpool = pgpool.clone();
+            async move { run_migrations(&pgpool).await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!migrations.is_finished());
+
+        conn.unlock().await.unwrap();
+        assert!(!migrations.await.unwrap().unwrap().is_empty());
+    }
+}
@@ -1,8 +1,42 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{path::Path, process::Command};
+
 use build_info_build::DependencyDepth;
 
+/// Commit the service is built from, `GIT_SHA` where the repository is not
+/// available (e.g. docker builds)
+fn git_sha() -> Option<String> {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    if let Some(sha) = std::env::var("GIT_SHA").ok().filter(|sha| !sha.is_empty()) {
+        return Some(sha);
+    }
+    let git_dir = Path::new("../../.git");
+    for file in ["HEAD", "refs", "packed-refs"] {
+        if git_dir.join(file).exists() {
+            println!("cargo:rerun-if-changed={}", git_dir.join(file).display());
+        }
+    }
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|sha| sha.trim().to_string())
+}
+
 fn main() {
     build_info_build::build_script().collect_dependencies(DependencyDepth::Depth(1));
+    if let Some(sha) = git_sha() {
+        println!("cargo:rustc-env=INDEXER_SERVICE_GIT_SHA={sha}");
+    }
+
+    println!("cargo:rerun-if-changed=proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/receipt_ingest.proto"], &["proto"])
+        .expect("Failed to compile the receipt ingest RPC proto");
 }
@@ -3,7 +3,7 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(version)]
@@ -12,4 +12,38 @@ pub struct Cli {
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/service for examples.
     #[arg(long, value_name = "FILE", verbatim_doc_comment)]
     pub config: Option<PathBuf>,
+
+    /// Refuse to start if the database schema is not compatible with this release,
+    /// otherwise the differences are only logged.
+    #[arg(long)]
+    pub strict_schema: bool,
+
+    /// Also run tap-agent in this process, sharing the database pool and the
+    /// configuration. Its routes are served on the metrics port. Several
+    /// replicas need `tap.leader_election`, so only one runs the agent,
+    /// a second replica exits otherwise.
+    #[arg(long)]
+    pub with_tap_agent: bool,
+
+    /// Run the TAP and DIPS migrations of this release before starting, for
+    /// databases that are not migrated by indexer-agent.
+    #[arg(long)]
+    pub migrate: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Checks the configuration and prints it with the defaults and environment
+    /// variables applied, secrets redacted, without starting the service
+    ValidateConfig {
+        /// Also check that every configured URL accepts connections
+        #[arg(long)]
+        online: bool,
+    },
+    /// Runs the TAP and DIPS migrations of this release and exits, for
+    /// databases that are not migrated by indexer-agent
+    Migrate,
 }
@@ -0,0 +1,274 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fees earned per allocation, sender and day
+//!
+//! Reads the `tap_fees_receipts_daily` and `tap_fees_ravs_daily` views.
+//! The receipts still stored are the ones not aggregated in a RAV yet,
+//! so the fees not redeemed are their value plus the value of the RAVs
+//! that indexer-agent didn't set the `redeemed_at` of yet.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::Context;
+use indexer_monitor::EscrowAccounts;
+use serde::Serialize;
+use sqlx::PgPool;
+use thegraph_core::alloy::primitives::Address;
+
+struct ReceiptsRow {
+    version: String,
+    allocation_id: String,
+    signer_address: String,
+    sender_address: Option<String>,
+    day: String,
+    receipts: i64,
+    value: String,
+}
+
+struct RavRow {
+    version: String,
+    allocation_id: String,
+    sender_address: String,
+    day: String,
+    value_aggregate: String,
+    redeemed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct FeesKey {
+    version: String,
+    allocation_id: Address,
+    sender: Option<Address>,
+    day: String,
+}
+
+#[derive(Debug, Default)]
+struct Fees {
+    receipts: i64,
+    receipts_value: u128,
+    unredeemed_rav_value: u128,
+    redeemed_rav_value: u128,
+}
+
+/// Fees of an allocation and sender for a UTC day, values in GRT wei
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DailyFees {
+    pub version: String,
+    pub allocation_id: Address,
+    /// `None` for receipts whose signer is not part of any escrow account
+    pub sender: Option<Address>,
+    pub day: String,
+    pub receipts: i64,
+    pub receipts_value: String,
+    pub unredeemed_rav_value: String,
+    pub redeemed_rav_value: String,
+}
+
+/// Totals of every allocation and sender, values in GRT wei
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeesTotals {
+    pub receipts_value: String,
+    pub unredeemed_rav_value: String,
+    pub redeemed_rav_value: String,
+    /// earned but not redeemed yet, receipts plus RAVs that are not redeemed
+    pub unredeemed_value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeesSummary {
+    pub totals: FeesTotals,
+    pub fees: Vec<DailyFees>,
+}
+
+/// Aggregates the receipts and RAVs per allocation, sender and day
+///
+/// The sender of v1 receipts is found from their signer in the escrow
+/// accounts, v2 receipts carry their payer.
+pub async fn fees_summary(
+    pgpool: &PgPool,
+    escrow_accounts_v1: &EscrowAccounts,
+) -> anyhow::Result<FeesSummary> {
+    // the columns of a view are all nullable for sqlx
+    let receipts = sqlx::query_as!(
+        ReceiptsRow,
+        r#"
+            SELECT
+                version AS "version!",
+                allocation_id AS "allocation_id!",
+                signer_address AS "signer_address!",
+                sender_address,
+                day::TEXT AS "day!",
+                receipts AS "receipts!",
+                value::TEXT AS "value!"
+            FROM tap_fees_receipts_daily
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+    let ravs = sqlx::query_as!(
+        RavRow,
+        r#"
+            SELECT
+                version AS "version!",
+                allocation_id AS "allocation_id!",
+                sender_address AS "sender_address!",
+                day::TEXT AS "day!",
+                value_aggregate::TEXT AS "value_aggregate!",
+                redeemed AS "redeemed!"
+            FROM tap_fees_ravs_daily
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+
+    let mut fees: BTreeMap<FeesKey, Fees> = BTreeMap::new();
+    for row in receipts {
+        let sender = match row.sender_address {
+            Some(sender) => Some(parse_address(&sender)?),
+            None => escrow_accounts_v1
+                .get_sender_for_any_signer(&parse_address(&row.signer_address)?)
+                .ok(),
+        };
+        let key = FeesKey {
+            version: row.version,
+            allocation_id: parse_address(&row.allocation_id)?,
+            sender,
+            day: row.day,
+        };
+        let entry = fees.entry(key).or_default();
+        entry.receipts += row.receipts;
+        entry.receipts_value += parse_value(&row.value)?;
+    }
+    for row in ravs {
+        let key = FeesKey {
+            version: row.version,
+            allocation_id: parse_address(&row.allocation_id)?,
+            sender: Some(parse_address(&row.sender_address)?),
+            day: row.day,
+        };
+        let entry = fees.entry(key).or_default();
+        let value = parse_value(&row.value_aggregate)?;
+        if row.redeemed {
+            entry.redeemed_rav_value += value;
+        } else {
+            entry.unredeemed_rav_value += value;
+        }
+    }
+
+    let (receipts_value, unredeemed_rav_value, redeemed_rav_value) = fees.values().fold(
+        (0u128, 0u128, 0u128),
+        |(receipts, unredeemed, redeemed), fees| {
+            (
+                receipts + fees.receipts_value,
+                unredeemed + fees.unredeemed_rav_value,
+                redeemed + fees.redeemed_rav_value,
+            )
+        },
+    );
+    let totals = FeesTotals {
+        receipts_value: receipts_value.to_string(),
+        unredeemed_rav_value: unredeemed_rav_value.to_string(),
+        redeemed_rav_value: redeemed_rav_value.to_string(),
+        unredeemed_value: (receipts_value + unredeemed_rav_value).to_string(),
+    };
+    let fees = fees
+        .into_iter()
+        .map(|(key, fees)| DailyFees {
+            version: key.version,
+            allocation_id: key.allocation_id,
+            sender: key.sender,
+            day: key.day,
+            receipts: fees.receipts,
+            receipts_value: fees.receipts_value.to_string(),
+            unredeemed_rav_value: fees.unredeemed_rav_value.to_string(),
+            redeemed_rav_value: fees.redeemed_rav_value.to_string(),
+        })
+        .collect();
+
+    Ok(FeesSummary { totals, fees })
+}
+
+fn parse_address(address: &str) -> anyhow::Result<Address> {
+    Address::from_str(address.trim()).with_context(|| format!("Invalid address `{address}`"))
+}
+
+fn parse_value(value: &str) -> anyhow::Result<u128> {
+    value
+        .parse()
+        .with_context(|| format!("Invalid GRT value `{value}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use indexer_monitor::EscrowAccounts;
+    use sqlx::{types::BigDecimal, PgPool};
+    use test_assets::{
+        ALLOCATION_ID_0, ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, TAP_SENDER,
+        TAP_SIGNER,
+    };
+    use thegraph_core::alloy::hex::ToHexExt;
+
+    use super::fees_summary;
+
+    // 2025-03-18T12:00:00Z
+    const TIMESTAMP_NS: i64 = 1_742_299_200_000_000_000;
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_fees_summary(pgpool: PgPool) {
+        for (nonce, value) in [(1i64, 10i64), (2, 20)] {
+            sqlx::query!(
+                r#"
+                    INSERT INTO scalar_tap_receipts
+                        (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+                TAP_SIGNER.1.encode_hex(),
+                vec![nonce as u8],
+                ALLOCATION_ID_0.encode_hex(),
+                BigDecimal::from(TIMESTAMP_NS),
+                BigDecimal::from(nonce),
+                BigDecimal::from(value)
+            )
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_ravs
+                    (sender_address, signature, allocation_id, timestamp_ns, value_aggregate)
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+            TAP_SENDER.1.encode_hex(),
+            vec![0u8],
+            ALLOCATION_ID_0.encode_hex(),
+            BigDecimal::from(TIMESTAMP_NS - 1),
+            BigDecimal::from(100)
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let escrow_accounts = EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        );
+        let summary = fees_summary(&pgpool, &escrow_accounts).await.unwrap();
+
+        assert_eq!(summary.fees.len(), 1);
+        let fees = &summary.fees[0];
+        assert_eq!(fees.version, "v1");
+        assert_eq!(fees.allocation_id, ALLOCATION_ID_0);
+        assert_eq!(fees.sender, Some(TAP_SENDER.1));
+        assert_eq!(fees.day, "2025-03-18");
+        assert_eq!(fees.receipts, 2);
+        assert_eq!(fees.receipts_value, "30");
+        assert_eq!(fees.unredeemed_rav_value, "100");
+        assert_eq!(summary.totals.unredeemed_value, "130");
+        assert_eq!(summary.totals.redeemed_rav_value, "0");
+    }
+}
@@ -2,21 +2,273 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod cost_model;
+pub mod fees;
 
-use std::time::Duration;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use indexer_config::DatabaseConfig;
+use indexer_schema::RequiredTable;
+use lazy_static::lazy_static;
+use prometheus::{register_histogram, register_int_gauge_vec, Histogram, IntGaugeVec};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    PgPool,
+};
 
+/// Used when `database.pool.acquire_timeout_secs` is not set
 const DATABASE_TIMEOUT: Duration = Duration::from_secs(30);
-const DATABASE_MAX_CONNECTIONS: u32 = 50;
 
-pub async fn connect(url: &str) -> PgPool {
+/// How often the usage of the connection pool is recorded
+const POOL_METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Connections of the pool
+    ///
+    /// Labels: "state", either "in_use" or "idle"
+    static ref POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_database_pool_connections",
+        "Connections of the database pool",
+        &["state"]
+    )
+    .unwrap();
+
+    /// Time waited for a connection of the pool, sampled every
+    /// [POOL_METRICS_INTERVAL]
+    static ref POOL_ACQUIRE: Histogram = register_histogram!(
+        "indexer_database_pool_acquire_seconds",
+        "Time waited for a connection of the database pool, sampled"
+    )
+    .unwrap();
+}
+
+/// Tables of [REQUIRED_TABLES] the receipts are stored in, unless they are
+/// stored in SQLite
+pub const RECEIPT_TABLES: &[&str] = &["scalar_tap_receipts", "tap_horizon_receipts"];
+
+/// Tables and columns indexer-service always reads or writes, all of them
+/// created by indexer-agent
+pub const REQUIRED_TABLES: &[RequiredTable] = &[
+    RequiredTable {
+        name: "CostModels",
+        columns: &["deployment", "model", "variables"],
+    },
+    RequiredTable {
+        name: "scalar_tap_receipts",
+        columns: &[
+            "signer_address",
+            "signature",
+            "allocation_id",
+            "timestamp_ns",
+            "nonce",
+            "value",
+        ],
+    },
+    RequiredTable {
+        name: "tap_horizon_receipts",
+        columns: &[
+            "signer_address",
+            "signature",
+            "allocation_id",
+            "payer",
+            "data_service",
+            "service_provider",
+            "timestamp_ns",
+            "nonce",
+            "value",
+        ],
+    },
+    RequiredTable {
+        name: "scalar_tap_denylist",
+        columns: &["sender_address"],
+    },
+    RequiredTable {
+        name: "tap_horizon_denylist",
+        columns: &["sender_address"],
+    },
+];
+
+/// Columns of the request ids of the receipts, added by a migration of this
+/// repository. Databases migrated by an indexer-agent release without it
+/// don't have them, the requests paid with more than one receipt are refused
+/// until they are added, see [indexer_receipt::store::PgReceiptStore::with_request_ids]
+pub const RECEIPT_REQUEST_ID_TABLES: &[RequiredTable] = &[
+    RequiredTable {
+        name: "scalar_tap_receipts",
+        columns: &["request_id"],
+    },
+    RequiredTable {
+        name: "tap_horizon_receipts",
+        columns: &["request_id"],
+    },
+];
+
+/// Tables of the free query API keys, see
+/// [indexer_config::ServiceConfig::api_key_admin_token]
+pub const API_KEY_TABLES: &[RequiredTable] = &[
+    RequiredTable {
+        name: "free_query_api_keys",
+        columns: &[
+            "name",
+            "key_hash",
+            "daily_quota",
+            "allowed_deployments",
+            "revoked_at",
+        ],
+    },
+    RequiredTable {
+        name: "free_query_api_key_usage",
+        columns: &["name", "day", "query_count"],
+    },
+];
+
+/// Tables of the allocation eligibility overrides and of the allocation
+/// events, see [indexer_config::ServiceConfig::allocation_override_admin_token]
+pub const ALLOCATION_OVERRIDE_TABLES: &[RequiredTable] = &[
+    RequiredTable {
+        name: "allocation_eligibility_overrides",
+        columns: &[
+            "allocation_id",
+            "eligible",
+            "reason",
+            "created_at",
+            "expires_at",
+        ],
+    },
+    RequiredTable {
+        name: "allocation_events",
+        columns: &["allocation_id", "event", "sender_address", "recorded_at"],
+    },
+];
+
+/// Views of the fees summary, see [indexer_config::FeesSummaryConfig]
+pub const FEES_SUMMARY_TABLES: &[RequiredTable] = &[
+    RequiredTable {
+        name: "tap_fees_receipts_daily",
+        columns: &[],
+    },
+    RequiredTable {
+        name: "tap_fees_ravs_daily",
+        columns: &["redeemed"],
+    },
+];
+
+/// Table used to serve queries without a receipt, see [indexer_config::ReceiptIngestConfig]
+pub const DEFERRED_QUERIES_TABLE: RequiredTable = RequiredTable {
+    name: "deferred_queries",
+    columns: &[
+        "query_id",
+        "deployment_id",
+        "query",
+        "variables",
+        "created_at",
+        "paid_at",
+        "expired",
+    ],
+};
+
+/// Table of the entries of the query blocklist added at runtime, see
+/// [indexer_config::QueryBlocklistConfig]
+pub const QUERY_BLOCKLIST_TABLE: RequiredTable = RequiredTable {
+    name: "query_blocklist",
+    columns: &["id", "kind", "pattern", "reason", "created_at"],
+};
+
+/// Columns of the DIPS agreements their price escalation and amendments are
+/// stored in, see [indexer_config::DipsConfig]
+pub const DIPS_AGREEMENTS_TABLE: RequiredTable = RequiredTable {
+    name: "indexing_agreements",
+    columns: &["version", "amended_at", "unprofitable_at"],
+};
+
+/// Columns of the DIPS agreements the indexing of their deployment is
+/// followed with, see [indexer_config::DipsIndexingConfig]
+pub const DIPS_INDEXING_TABLE: RequiredTable = RequiredTable {
+    name: "indexing_agreements",
+    columns: &["indexing_started_at", "synced_at"],
+};
+
+/// Table of indexer-agent the deployments of the DIPS agreements are
+/// scheduled in, see [indexer_config::DipsIndexingConfig::IndexingRules]
+pub const INDEXING_RULES_TABLE: RequiredTable = RequiredTable {
+    name: "IndexingRules",
+    columns: &[
+        "identifier",
+        "identifierType",
+        "decisionBasis",
+        "protocolNetwork",
+        "createdAt",
+        "updatedAt",
+    ],
+};
+
+pub async fn connect(config: &DatabaseConfig) -> PgPool {
     tracing::debug!("Connecting to database");
 
+    let pool = config.pool();
+    let url = config.clone().get_formated_postgres_url();
+    let mut options =
+        PgConnectOptions::from_str(url.as_str()).expect("Should be able to parse the database URL");
+    if let Some(statement_timeout) = pool.statement_timeout_secs {
+        options = options.options([("statement_timeout", statement_timeout.as_millis())]);
+    }
     PgPoolOptions::new()
-        .max_connections(DATABASE_MAX_CONNECTIONS)
-        .acquire_timeout(DATABASE_TIMEOUT)
-        .connect(url)
+        .max_connections(pool.max_connections)
+        .acquire_timeout(pool.acquire_timeout_secs.unwrap_or(DATABASE_TIMEOUT))
+        .idle_timeout(pool.idle_timeout_secs)
+        .connect_with(options)
         .await
         .expect("Should be able to connect to the database")
 }
+
+/// Records the connections of the pool in use and idle, and how long it takes
+/// to get one, every [POOL_METRICS_INTERVAL]. Never returns.
+pub async fn monitor_pool(pgpool: PgPool) {
+    let mut interval = tokio::time::interval(POOL_METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let size = pgpool.size() as i64;
+        let idle = pgpool.num_idle() as i64;
+        POOL_CONNECTIONS
+            .with_label_values(&["in_use"])
+            .set(size - idle);
+        POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+
+        let start = Instant::now();
+        match pgpool.acquire().await {
+            Ok(_) => POOL_ACQUIRE.observe(start.elapsed().as_secs_f64()),
+            Err(error) => tracing::warn!(%error, "Failed to acquire a database connection"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{
+        ALLOCATION_OVERRIDE_TABLES, API_KEY_TABLES, DEFERRED_QUERIES_TABLE, DIPS_AGREEMENTS_TABLE,
+        DIPS_INDEXING_TABLE, FEES_SUMMARY_TABLES, QUERY_BLOCKLIST_TABLE, REQUIRED_TABLES,
+    };
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_required_tables_migrated(pgpool: PgPool) {
+        // IndexingRules is created by indexer-agent
+        let mut required = REQUIRED_TABLES.to_vec();
+        required.extend(API_KEY_TABLES);
+        required.extend(ALLOCATION_OVERRIDE_TABLES);
+        required.extend(FEES_SUMMARY_TABLES);
+        required.extend([
+            DEFERRED_QUERIES_TABLE,
+            QUERY_BLOCKLIST_TABLE,
+            DIPS_AGREEMENTS_TABLE,
+            DIPS_INDEXING_TABLE,
+        ]);
+        let diff = indexer_schema::check_schema(&pgpool, &required)
+            .await
+            .unwrap();
+        assert!(!diff.is_incompatible(), "{diff}");
+    }
+}
@@ -1,20 +1,30 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::convert::Infallible;
+use std::{convert::Infallible, fmt, time::Duration};
 
 use anyhow::Error;
 use axum::{
     response::{IntoResponse, Response},
     Json,
 };
+use indexer_error::{ErrorCode, IndexerErrorCode};
 use indexer_monitor::EscrowAccountsError;
 use reqwest::StatusCode;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tap_core::{receipt::ReceiptError, Error as TapError};
-use thegraph_core::DeploymentId;
+use thegraph_core::{
+    alloy::primitives::{Address, BlockNumber},
+    DeploymentId,
+};
 use thiserror::Error;
 
+use crate::{
+    middleware::{DeploymentAccessError, QueryLimitsError, ReceiptTimestampError, RefundReceipt},
+    service::{self, BlockConstraint, IndexedBlock},
+};
+
 #[derive(Debug, Error)]
 pub enum IndexerServiceError {
     #[error("No Tap receipt was found in the request")]
@@ -35,6 +45,54 @@ pub enum IndexerServiceError {
 
     #[error("There was an error while accessing escrow account: {0}")]
     EscrowAccount(#[from] EscrowAccountsError),
+
+    #[error("API key `{0}` exceeded its daily query quota")]
+    ApiKeyQuotaExceeded(String),
+
+    #[error("API key `{0}` is not allowed to query deployment {1}")]
+    ApiKeyDeploymentNotAllowed(String, DeploymentId),
+
+    #[error("There was an error while accessing the database: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    ReceiptTimestamp(#[from] ReceiptTimestampError),
+
+    #[error("Deployment {0} uses `{1}`, which is not supported by this indexer")]
+    UnsupportedDeployment(DeploymentId, String),
+
+    #[error(transparent)]
+    DeploymentAccess(#[from] DeploymentAccessError),
+
+    #[error("Receipt was already received for another query")]
+    ReceiptReplayed,
+
+    #[error("Request body is larger than the {0} bytes accepted")]
+    BodyTooLarge(usize),
+
+    #[error(transparent)]
+    QueryLimits(#[from] QueryLimitsError),
+
+    #[error("Query is refused by the blocklist of the indexer")]
+    QueryBlocked,
+
+    #[error("WebAssembly plugin failed: {0}")]
+    PluginFailed(Error),
+
+    #[error("Dispute manager changed to {current}, attestations are only signed for {expected}")]
+    DisputeManagerChanged { expected: Address, current: Address },
+
+    #[error("Allocation {0} is closing, receipts for another allocation are required")]
+    AllocationClosing(Address),
+
+    #[error("Receipts of sender {found} can't pay a request along with the ones of {expected}")]
+    MixedReceiptSenders { expected: Address, found: Address },
+
+    #[error("Query id `{0}` was already used by another query")]
+    QueryIdReused(String),
+
+    #[error("Requests can only be paid with a single receipt by this indexer")]
+    MultipleReceiptsUnsupported,
 }
 
 impl StatusCodeExt for IndexerServiceError {
@@ -45,29 +103,124 @@ impl StatusCodeExt for IndexerServiceError {
                 TapError::ReceiptError(ReceiptError::CheckFailure(_)) => StatusCode::BAD_REQUEST,
                 _ => StatusCode::INTERNAL_SERVER_ERROR,
             },
-            E::EscrowAccount(_) | E::ReceiptNotFound => StatusCode::PAYMENT_REQUIRED,
+            E::EscrowAccount(_) | E::ReceiptNotFound | E::ReceiptTimestamp(_) => {
+                StatusCode::PAYMENT_REQUIRED
+            }
             E::DeploymentIdNotFound => StatusCode::INTERNAL_SERVER_ERROR,
             E::AxumError(_) | E::SerializationError(_) => StatusCode::BAD_GATEWAY,
             E::Eip712Error(_) => StatusCode::BAD_REQUEST,
+            E::ApiKeyQuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            E::ApiKeyDeploymentNotAllowed(..)
+            | E::UnsupportedDeployment(..)
+            | E::DeploymentAccess(_) => StatusCode::FORBIDDEN,
+            E::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            E::ReceiptReplayed => StatusCode::CONFLICT,
+            E::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            E::QueryLimits(_) => StatusCode::BAD_REQUEST,
+            E::QueryBlocked => StatusCode::FORBIDDEN,
+            E::PluginFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            E::DisputeManagerChanged { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            E::AllocationClosing(_) => StatusCode::GONE,
+            E::MixedReceiptSenders { .. } => StatusCode::BAD_REQUEST,
+            E::QueryIdReused(_) => StatusCode::CONFLICT,
+            E::MultipleReceiptsUnsupported => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl ErrorCode for IndexerServiceError {
+    fn error_code(&self) -> IndexerErrorCode {
+        use IndexerServiceError as E;
+        match &self {
+            E::ReceiptNotFound => IndexerErrorCode::ReceiptNotFound,
+            E::TapCoreError(error) => error.error_code(),
+            E::Eip712Error(_) => IndexerErrorCode::InvalidReceipt,
+            E::EscrowAccount(_) => IndexerErrorCode::UnknownSigner,
+            E::ApiKeyQuotaExceeded(_) => IndexerErrorCode::ApiKeyQuotaExceeded,
+            E::ApiKeyDeploymentNotAllowed(..) => IndexerErrorCode::ApiKeyDeploymentNotAllowed,
+            E::Database(error) => error.error_code(),
+            E::AxumError(_) | E::SerializationError(_) => IndexerErrorCode::SubgraphQuery,
+            E::DeploymentIdNotFound => IndexerErrorCode::Unknown,
+            E::ReceiptTimestamp(_) => IndexerErrorCode::ReceiptTimestampOutOfRange,
+            E::UnsupportedDeployment(..) => IndexerErrorCode::UnsupportedDeployment,
+            E::DeploymentAccess(_) => IndexerErrorCode::DeploymentDenied,
+            E::ReceiptReplayed => IndexerErrorCode::ReceiptReplayed,
+            E::BodyTooLarge(_) => IndexerErrorCode::RequestBodyTooLarge,
+            E::QueryLimits(_) => IndexerErrorCode::QueryLimitsExceeded,
+            E::QueryBlocked => IndexerErrorCode::QueryBlocked,
+            E::PluginFailed(_) => IndexerErrorCode::PluginFailed,
+            E::DisputeManagerChanged { .. } => IndexerErrorCode::DisputeManagerChanged,
+            E::AllocationClosing(_) => IndexerErrorCode::AllocationClosing,
+            E::MixedReceiptSenders { .. } => IndexerErrorCode::InvalidReceipt,
+            E::QueryIdReused(_) => IndexerErrorCode::AlreadyExists,
+            E::MultipleReceiptsUnsupported => IndexerErrorCode::InvalidReceipt,
+        }
+    }
+}
+
+impl RouteError for IndexerServiceError {
+    fn data(&self) -> Option<Value> {
+        use IndexerServiceError as E;
+        match self {
+            E::ApiKeyDeploymentNotAllowed(_, deployment) => Some(json!({
+                "deployment": deployment,
+            })),
+            E::ReceiptTimestamp(ReceiptTimestampError::InFuture { skew, max }) => Some(json!({
+                "skewMs": skew.as_millis() as u64,
+                "maxMs": max.as_millis() as u64,
+            })),
+            E::ReceiptTimestamp(ReceiptTimestampError::TooOld { age, max }) => Some(json!({
+                "ageMs": age.as_millis() as u64,
+                "maxMs": max.as_millis() as u64,
+            })),
+            E::UnsupportedDeployment(deployment, feature) => Some(json!({
+                "deployment": deployment,
+                "feature": feature,
+            })),
+            E::DeploymentAccess(
+                DeploymentAccessError::DeniedByNetwork(deployment)
+                | DeploymentAccessError::DeniedByIndexer(deployment),
+            ) => Some(json!({ "deployment": deployment })),
+            E::BodyTooLarge(limit) => Some(json!({ "limitBytes": limit })),
+            E::QueryLimits(QueryLimitsError::Depth(max, deployment)) => Some(json!({
+                "limit": "depth",
+                "max": max,
+                "deployment": deployment,
+            })),
+            E::QueryLimits(QueryLimitsError::Complexity(max, deployment)) => Some(json!({
+                "limit": "complexity",
+                "max": max,
+                "deployment": deployment,
+            })),
+            E::DisputeManagerChanged { expected, current } => Some(json!({
+                "expected": expected,
+                "current": current,
+            })),
+            E::AllocationClosing(allocation) => Some(json!({ "allocation": allocation })),
+            E::MixedReceiptSenders { expected, found } => Some(json!({
+                "expected": expected,
+                "found": found,
+            })),
+            E::QueryIdReused(query_id) => Some(json!({ "queryId": query_id })),
+            _ => None,
         }
     }
 }
 
 impl IntoResponse for IndexerServiceError {
     fn into_response(self) -> Response {
-        #[derive(Serialize)]
-        struct ErrorResponse {
-            message: String,
+        tracing::error!(%self, code = %self.error_code(), "An IndexerServiceError occoured.");
+        // the plugin and the attestation run once the receipt is stored
+        let refund = matches!(
+            self,
+            IndexerServiceError::PluginFailed(_)
+                | IndexerServiceError::DisputeManagerChanged { .. }
+        );
+        let mut response = error_response(&self);
+        if refund {
+            response.extensions_mut().insert(RefundReceipt);
         }
-
-        tracing::error!(%self, "An IndexerServiceError occoured.");
-        (
-            self.status_code(),
-            Json(ErrorResponse {
-                message: self.to_string(),
-            }),
-        )
-            .into_response()
+        response
     }
 }
 
@@ -83,6 +236,19 @@ pub enum SubgraphServiceError {
     InvalidDeployment(DeploymentId),
     #[error("Failed to process query: {0}")]
     QueryForwardingError(reqwest::Error),
+    #[error(transparent)]
+    InvalidBlockConstraint(service::InvalidBlockConstraint),
+    #[error("Query was expected to run at block {expected}, graph-node reported {reported:?}")]
+    BlockConstraintMismatch {
+        expected: BlockConstraint,
+        reported: Option<IndexedBlock>,
+    },
+    #[error("graph-node returned errors alongside data, the query was refused")]
+    PartialResponseRefused,
+    #[error("Query was not served within the {0:?} deadline of the request")]
+    DeadlineExceeded(Duration),
+    #[error("No query node reached block {0} of the deployment yet")]
+    BlockNotAvailable(BlockNumber),
 }
 
 impl StatusCodeExt for SubgraphServiceError {
@@ -93,6 +259,48 @@ impl StatusCodeExt for SubgraphServiceError {
             InvalidDeployment(_) => StatusCode::INTERNAL_SERVER_ERROR,
             StatusQueryError(_) => StatusCode::BAD_GATEWAY,
             QueryForwardingError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            InvalidBlockConstraint(_) => StatusCode::BAD_REQUEST,
+            BlockConstraintMismatch { .. } => StatusCode::PRECONDITION_FAILED,
+            PartialResponseRefused => StatusCode::BAD_GATEWAY,
+            DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+            BlockNotAvailable(_) => StatusCode::PRECONDITION_FAILED,
+        }
+    }
+}
+
+impl ErrorCode for SubgraphServiceError {
+    fn error_code(&self) -> IndexerErrorCode {
+        use SubgraphServiceError::*;
+        match self {
+            InvalidStatusQuery(_) | UnsupportedStatusQueryFields(_) | StatusQueryError(_) => {
+                IndexerErrorCode::SubgraphQuery
+            }
+            InvalidDeployment(_) => IndexerErrorCode::Unknown,
+            QueryForwardingError(error) => error.error_code(),
+            InvalidBlockConstraint(_) => IndexerErrorCode::InvalidBlockConstraint,
+            BlockConstraintMismatch { .. } => IndexerErrorCode::BlockConstraintMismatch,
+            PartialResponseRefused => IndexerErrorCode::PartialResponseRefused,
+            DeadlineExceeded(_) => IndexerErrorCode::DeadlineExceeded,
+            BlockNotAvailable(_) => IndexerErrorCode::BlockNotAvailable,
+        }
+    }
+}
+
+impl RouteError for SubgraphServiceError {
+    fn data(&self) -> Option<Value> {
+        use SubgraphServiceError::*;
+        match self {
+            UnsupportedStatusQueryFields(fields) => Some(json!({ "fields": fields })),
+            InvalidDeployment(deployment) => Some(json!({ "deployment": deployment })),
+            BlockConstraintMismatch { expected, reported } => Some(json!({
+                "expected": expected.to_string(),
+                "reported": reported.as_ref().and_then(|block| block.number),
+            })),
+            DeadlineExceeded(deadline) => Some(json!({
+                "deadlineMs": deadline.as_millis() as u64,
+            })),
+            BlockNotAvailable(block) => Some(json!({ "block": block })),
+            _ => None,
         }
     }
 }
@@ -100,10 +308,55 @@ impl StatusCodeExt for SubgraphServiceError {
 // Tell axum how to convert `SubgraphServiceError` into a response.
 impl IntoResponse for SubgraphServiceError {
     fn into_response(self) -> Response {
-        (self.status_code(), self.to_string()).into_response()
+        let refund = matches!(
+            self,
+            SubgraphServiceError::PartialResponseRefused
+                | SubgraphServiceError::DeadlineExceeded(_)
+                | SubgraphServiceError::BlockNotAvailable(_)
+        );
+        let mut response = error_response(&self);
+        if refund {
+            response.extensions_mut().insert(RefundReceipt);
+        }
+        response
+    }
+}
+
+/// Error returned by a route, answered with an [ErrorResponse]
+pub trait RouteError: ErrorCode + StatusCodeExt + fmt::Display {
+    /// Details of the error, so that clients don't have to parse the message
+    fn data(&self) -> Option<Value> {
+        None
     }
 }
 
+/// Body of the error responses of every route
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// indexer error code, see [IndexerErrorCode]
+    pub code: String,
+    pub message: String,
+    pub data: Option<Value>,
+    pub retriable: bool,
+}
+
+/// Answers `error` with its status code and an [ErrorResponse], counting it
+/// by code
+pub fn error_response(error: &impl RouteError) -> Response {
+    let code = error.error_code();
+    code.record();
+    (
+        error.status_code(),
+        Json(ErrorResponse {
+            code: code.to_string(),
+            message: error.to_string(),
+            data: error.data(),
+            retriable: code.retriable(),
+        }),
+    )
+        .into_response()
+}
+
 pub trait StatusCodeExt {
     fn status_code(&self) -> StatusCode;
 }
@@ -132,3 +385,30 @@ impl StatusCodeExt for Infallible {
         unreachable!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, response::IntoResponse};
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    use super::{ErrorResponse, SubgraphServiceError};
+    use crate::middleware::RefundReceipt;
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let response = SubgraphServiceError::BlockNotAvailable(1234).into_response();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert!(response.extensions().get::<RefundReceipt>().is_some());
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, "IE029");
+        assert_eq!(
+            body.message,
+            "No query node reached block 1234 of the deployment yet"
+        );
+        assert_eq!(body.data, Some(json!({ "block": 1234 })));
+        assert!(body.retriable);
+    }
+}
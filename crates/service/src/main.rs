@@ -4,31 +4,12 @@
 use std::process::ExitCode;
 
 use indexer_service_rs::service::run;
-use tracing::{level_filters::LevelFilter, subscriber::set_global_default};
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    init_tracing();
     if let Err(e) = run().await {
         tracing::error!("Indexer service error: {e}");
         return ExitCode::from(1);
     }
     ExitCode::SUCCESS
 }
-
-fn init_tracing() {
-    // Tracing setup
-    let filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy();
-    let subscriber_builder: tracing_subscriber::fmt::SubscriberBuilder<
-        tracing_subscriber::fmt::format::DefaultFields,
-        tracing_subscriber::fmt::format::Format,
-        EnvFilter,
-    > = FmtSubscriber::builder().with_env_filter(filter);
-    set_global_default(subscriber_builder.with_ansi(true).pretty().finish()).expect(
-        "Could not set up global default subscriber for logger, check \
-        environmental variable `RUST_LOG`",
-    );
-}
@@ -1,15 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::OnceLock};
 
-use axum::{routing::get, serve, Router};
+use axum::{routing::get, Router};
+use indexer_config::ListenerConfig;
+use indexer_listener::Listener;
 use lazy_static::lazy_static;
 use prometheus::{
-    register_counter_vec, register_histogram_vec, CounterVec, HistogramVec, TextEncoder,
+    register_counter, register_counter_vec, register_histogram_vec, register_int_gauge_vec,
+    Counter, CounterVec, HistogramVec, IntGaugeVec, TextEncoder,
 };
 use reqwest::StatusCode;
-use tokio::net::TcpListener;
 
 lazy_static! {
     /// Metric registered in global registry for
@@ -33,13 +35,180 @@ lazy_static! {
     )
     .unwrap();
 
+    /// Metric registered in global registry for
+    /// Free queries authorized by an API key
+    ///
+    /// Labels: "key"
+    pub static ref API_KEY_QUERIES: CounterVec = register_counter_vec!(
+        "indexer_api_key_queries_total",
+        "Free queries authorized by an API key",
+        &["key"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Attestations served from the cache of the signer
+    ///
+    /// Labels: "deployment", "result"
+    pub static ref ATTESTATION_CACHE: CounterVec = register_counter_vec!(
+        "indexer_attestation_cache_total",
+        "Attestation cache lookups by result, either hit or miss",
+        &["deployment", "result"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries for an allocation without an attestation signer yet
+    ///
+    /// Labels: "allocation"
+    pub static ref ATTESTATION_SIGNER_MISSING: CounterVec = register_counter_vec!(
+        "indexer_attestation_signer_missing_total",
+        "Queries for an allocation without an attestation signer yet, served without attestation",
+        &["allocation"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries served without a receipt, waiting for it
+    ///
+    /// Labels: "deployment"
+    pub static ref DEFERRED_QUERIES: CounterVec = register_counter_vec!(
+        "indexer_deferred_queries_total",
+        "Queries served without a receipt, to be paid by a receipt submitted later",
+        &["deployment"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries whose receipt was not submitted in time
+    ///
+    /// Labels: "deployment"
+    pub static ref DEFERRED_QUERIES_UNPAID: CounterVec = register_counter_vec!(
+        "indexer_deferred_queries_unpaid_total",
+        "Queries served without a receipt whose receipt was not submitted in time",
+        &["deployment"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Receipts submitted to the receipt ingest service
+    ///
+    /// Labels: "status"
+    pub static ref DEFERRED_RECEIPTS: CounterVec = register_counter_vec!(
+        "indexer_deferred_receipts_total",
+        "Receipts submitted to the receipt ingest service by status",
+        &["status"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Receipts that were already received, by this or another replica
+    ///
+    /// Labels: "stage"
+    pub static ref REPLAYED_RECEIPTS: CounterVec = register_counter_vec!(
+        "indexer_receipts_replayed_total",
+        "Replayed receipts, either rejected before the query, accepted for a retry of the same \
+         request or dropped when stored",
+        &["stage"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries refused by the depth and complexity limits
+    ///
+    /// Labels: "deployment", "sender", "limit"
+    pub static ref QUERY_LIMITS_EXCEEDED: CounterVec = register_counter_vec!(
+        "indexer_query_limits_exceeded_total",
+        "Queries refused for exceeding the depth or complexity limit of the deployment",
+        &["deployment", "sender", "limit"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries refused by the query blocklist
+    ///
+    /// Labels: "deployment", "entry"
+    pub static ref BLOCKED_QUERIES: CounterVec = register_counter_vec!(
+        "indexer_queries_blocked_total",
+        "Queries refused by the query blocklist, by the fingerprint or regex they matched",
+        &["deployment", "entry"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Failures of the WebAssembly plugin
+    ///
+    /// Labels: "hook"
+    pub static ref WASM_PLUGIN_FAILURES: CounterVec = register_counter_vec!(
+        "indexer_wasm_plugin_failures_total",
+        "Calls of the WebAssembly plugin that failed, timed out or ran out of memory",
+        &["hook"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries forwarded to graph-node
+    pub static ref GRAPH_NODE_REQUESTS: Counter = register_counter!(
+        "indexer_graph_node_requests_total",
+        "Queries forwarded to graph-node"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Connections opened to graph-node, the other requests reused a connection
+    pub static ref GRAPH_NODE_CONNECTIONS: Counter = register_counter!(
+        "indexer_graph_node_connections_total",
+        "Connections opened to graph-node"
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Queries sent to a second query node, by the request that answered first
+    ///
+    /// Labels: "winner"
+    pub static ref HEDGED_QUERIES: CounterVec = register_counter_vec!(
+        "indexer_graph_node_hedged_queries_total",
+        "Slow queries sent to a second query node, by the request whose response was served",
+        &["winner"]
+    )
+    .unwrap();
+
+    /// Metric registered in global registry for
+    /// Health of the graph-node query nodes, 1 if healthy
+    ///
+    /// Labels: "node"
+    pub static ref GRAPH_NODE_QUERY_NODE_HEALTHY: IntGaugeVec = register_int_gauge_vec!(
+        "indexer_graph_node_query_node_healthy",
+        "Whether the graph-node query node answered its last health check",
+        &["node"]
+    )
+    .unwrap();
 }
 
-pub fn serve_metrics(host_and_port: SocketAddr) {
-    tracing::info!(address = %host_and_port, "Serving prometheus metrics");
+static RECEIPT_VALUE: OnceLock<HistogramVec> = OnceLock::new();
 
+/// Metric registered in global registry for
+/// Value of the receipts accepted, in GRT, registered with `buckets` by
+/// the first call
+///
+/// Labels: "deployment", "sender"
+pub fn receipt_value_histogram(buckets: Vec<f64>) -> &'static HistogramVec {
+    RECEIPT_VALUE.get_or_init(|| {
+        register_histogram_vec!(
+            "indexer_receipt_value_grt",
+            "Value of the receipts accepted, in GRT",
+            &["deployment", "sender"],
+            buckets
+        )
+        .unwrap()
+    })
+}
+
+/// Serves `/metrics` along with the extra `routes` on every address of
+/// `hosts_and_ports`, or on the socket of `listener`
+pub fn serve_metrics(listener: ListenerConfig, hosts_and_ports: Vec<SocketAddr>, routes: Router) {
     tokio::spawn(async move {
-        let router = Router::new().route(
+        let router = Router::new().merge(routes).route(
             "/metrics",
             get(|| async {
                 let metric_families = prometheus::gather();
@@ -58,13 +227,15 @@ pub fn serve_metrics(host_and_port: SocketAddr) {
             }),
         );
 
-        serve(
-            TcpListener::bind(host_and_port)
-                .await
-                .expect("Failed to bind to metrics port"),
-            router.into_make_service(),
-        )
-        .await
-        .expect("Failed to serve metrics")
+        let listeners = Listener::bind_all(&listener, &hosts_and_ports)
+            .await
+            .expect("Failed to bind to metrics port");
+        for listener in &listeners {
+            tracing::info!(address = %listener, "Serving prometheus metrics");
+        }
+
+        indexer_listener::serve_all(listeners, router, std::future::pending())
+            .await
+            .expect("Failed to serve metrics")
     });
 }
@@ -2,22 +2,60 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod allocation;
+mod allocation_closing;
 mod attestation;
 mod attestation_signer;
 pub mod auth;
+mod body_limit;
+mod compression;
+mod cors;
 mod deployment;
+mod deployment_access;
 mod labels;
+mod legacy_route;
+mod manifest;
 mod prometheus_metrics;
+mod query_blocklist;
+mod query_limits;
+mod query_stats;
+mod receipt_refund;
+mod receipt_replay;
+mod receipt_timestamp;
+mod request_id;
+mod response_signature;
 mod sender;
 mod tap_context;
 mod tap_receipt;
+#[cfg(feature = "wasm-plugin")]
+mod wasm_plugin;
 
 pub use allocation::{allocation_middleware, Allocation, AllocationState};
+pub use allocation_closing::{allocation_closing_middleware, ClosingAllocations};
 pub use attestation::{attestation_middleware, AttestationInput};
 pub use attestation_signer::{signer_middleware, AttestationState};
+pub use body_limit::body_limit_middleware;
+pub use compression::{compression_layer, decompression_layer};
+pub use cors::{cors_layer, security_headers_middleware};
 pub use deployment::deployment_middleware;
+pub use deployment_access::{
+    deployment_access_middleware, DeploymentAccessError, DeploymentAccessState,
+};
 pub use labels::labels_middleware;
+pub use legacy_route::{legacy_route_middleware, LegacyRoutes};
+pub use manifest::{manifest_middleware, ManifestState};
 pub use prometheus_metrics::PrometheusMetricsMiddlewareLayer;
-pub use sender::{sender_middleware, Sender, SenderState};
-pub use tap_context::{context_middleware, QueryBody};
-pub use tap_receipt::receipt_middleware;
+pub use query_blocklist::{query_blocklist_middleware, query_fingerprint, QueryBlocklist};
+pub use query_limits::{query_limits_middleware, QueryLimitsError};
+pub use query_stats::query_stats_middleware;
+pub use receipt_refund::{receipt_refund_middleware, RefundReceipt};
+pub use receipt_replay::{receipt_replay_middleware, ReceiptReplayState, ReceiptsAccepted};
+pub use receipt_timestamp::{
+    receipt_timestamp_middleware, ReceiptTimestampError, ReceiptTimestampState,
+};
+pub use request_id::{request_id_middleware, RequestId, REQUEST_ID_HEADER};
+pub use response_signature::response_signature_middleware;
+pub use sender::{recover_sender, sender_middleware, Sender, SenderState};
+pub use tap_context::{context_middleware, route_context_middleware, QueryBody};
+pub use tap_receipt::{receipt_middleware, request_receipts, MultipleReceipts, RequestReceipts};
+#[cfg(feature = "wasm-plugin")]
+pub use wasm_plugin::{wasm_plugin_middleware, WasmPlugin};
@@ -0,0 +1,204 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use sqlx::{postgres::PgListener, PgPool};
+use thegraph_core::alloy::primitives::Address;
+
+use crate::{error::IndexerServiceError, middleware::request_receipts};
+
+const CLOSING_NOTIFICATION_CHANNEL: &str = "closing_allocation_notification";
+
+/// Allocations whose last RAVs are requested by tap-agent, from the
+/// `closing_allocations` table, updated with the row of every notification
+/// and reloaded only when notifications may have been lost
+///
+/// The receipts of these allocations would be received after the last RAV
+/// and never redeemed.
+#[derive(Clone)]
+pub struct ClosingAllocations {
+    closing: Arc<RwLock<HashSet<Address>>>,
+}
+
+impl ClosingAllocations {
+    pub async fn new(pgpool: PgPool) -> anyhow::Result<Self> {
+        // Listen before loading the allocations so that no update is missed
+        let mut pglistener = PgListener::connect_with(&pgpool).await?;
+        pglistener.listen(CLOSING_NOTIFICATION_CHANNEL).await?;
+
+        let closing_allocations = Self {
+            closing: Arc::new(RwLock::new(HashSet::new())),
+        };
+        closing_allocations.reload(&pgpool).await?;
+        tokio::spawn(closing_allocations.clone().watch(pgpool, pglistener));
+        Ok(closing_allocations)
+    }
+
+    async fn reload(&self, pgpool: &PgPool) -> anyhow::Result<()> {
+        let rows = sqlx::query_scalar!("SELECT allocation_id FROM closing_allocations")
+            .fetch_all(pgpool)
+            .await?;
+        let closing = rows
+            .iter()
+            .map(|allocation_id| Address::from_str(allocation_id))
+            .collect::<Result<HashSet<_>, _>>()?;
+        *self.closing.write().unwrap() = closing;
+        Ok(())
+    }
+
+    /// Never refuses a receipt, used when the `closing_allocations` table
+    /// can't be watched
+    pub fn none() -> Self {
+        Self {
+            closing: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    /// Applies the change of every notification, `<operation> <allocation_id>`
+    async fn watch(self, pgpool: PgPool, mut pglistener: PgListener) {
+        loop {
+            let applied = match pglistener.recv().await {
+                Ok(notification) => self.apply(notification.payload()),
+                // the listener reconnects on the next call, notifications sent
+                // in between are lost
+                Err(error) => {
+                    tracing::warn!(%error, "Lost the closing allocation notifications");
+                    false
+                }
+            };
+            if applied {
+                continue;
+            }
+            if let Err(error) = self.reload(&pgpool).await {
+                tracing::error!(%error, "Failed to reload the closing allocations");
+            }
+        }
+    }
+
+    /// Applies a notification, false if the table must be reloaded
+    fn apply(&self, payload: &str) -> bool {
+        let Some((operation, allocation_id)) = payload.split_once(' ') else {
+            return false;
+        };
+        let Ok(allocation_id) = Address::from_str(allocation_id.trim()) else {
+            return false;
+        };
+        let mut closing = self.closing.write().unwrap();
+        match operation {
+            "INSERT" | "UPDATE" => closing.insert(allocation_id),
+            "DELETE" => closing.remove(&allocation_id),
+            _ => return false,
+        };
+        true
+    }
+
+    fn is_closing(&self, allocation_id: &Address) -> bool {
+        self.closing.read().unwrap().contains(allocation_id)
+    }
+}
+
+/// Refuses the receipts of the [ClosingAllocations], before they are stored
+///
+/// The gateway is told to pay the query with a receipt for another
+/// allocation.
+///
+/// Requires Receipt extension, every receipt of a request paid with several
+/// of them is checked
+pub async fn allocation_closing_middleware(
+    State(closing_allocations): State<ClosingAllocations>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    for receipt in request_receipts(request.extensions()) {
+        let allocation_id = receipt.allocation_id();
+        if closing_allocations.is_closing(&allocation_id) {
+            return Err(IndexerServiceError::AllocationClosing(allocation_id));
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use sqlx::PgPool;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, ALLOCATION_ID_0};
+    use thegraph_core::alloy::hex::ToHexExt;
+    use tower::ServiceExt;
+
+    use super::{allocation_closing_middleware, ClosingAllocations};
+    use crate::tap::TapReceipt;
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_closing_allocations_are_refused(pgpool: PgPool) {
+        let closing_allocations = ClosingAllocations::new(pgpool.clone()).await.unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(from_fn_with_state(
+                closing_allocations.clone(),
+                allocation_closing_middleware,
+            ));
+        let send = || async {
+            let receipt = create_signed_receipt(
+                SignedReceiptRequest::builder()
+                    .allocation_id(ALLOCATION_ID_0)
+                    .build(),
+            )
+            .await;
+            app.clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .extension(TapReceipt::V1(receipt))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(send().await, StatusCode::OK);
+
+        // closed by tap-agent, loaded once notified
+        sqlx::query!(
+            "INSERT INTO closing_allocations (allocation_id) VALUES ($1)",
+            ALLOCATION_ID_0.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        for _ in 0..50 {
+            if closing_allocations.is_closing(&ALLOCATION_ID_0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(send().await, StatusCode::GONE);
+
+        // removed by tap-agent once old enough
+        sqlx::query!("DELETE FROM closing_allocations")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if !closing_allocations.is_closing(&ALLOCATION_ID_0) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(send().await, StatusCode::OK);
+    }
+}
@@ -6,15 +6,28 @@ use std::string::FromUtf8Error;
 use axum::{
     body::to_bytes,
     extract::Request,
+    http::HeaderValue,
     middleware::Next,
     response::{IntoResponse, Response},
 };
 use indexer_attestation::AttestationSigner;
+use indexer_error::{ErrorCode, IndexerErrorCode};
 use reqwest::StatusCode;
 use serde::Serialize;
 use thegraph_core::attestation::Attestation;
 
-use crate::error::StatusCodeExt;
+use super::{auth::FeeCharged, Allocation, RefundReceipt};
+use crate::{
+    error::{error_response, RouteError, StatusCodeExt},
+    metrics::ATTESTATION_CACHE,
+};
+
+/// Value of the receipts paying for the query, in GRT wei
+const GRAPH_FEE_CHARGED: &str = "graph-fee-charged";
+/// Whether the response is attested
+const GRAPH_ATTESTABLE: &str = "graph-attestable";
+/// Allocation the query was served for
+const GRAPH_ALLOCATION: &str = "graph-allocation";
 
 #[derive(Clone)]
 pub enum AttestationInput {
@@ -40,25 +53,52 @@ pub struct IndexerResponsePayload {
 /// else:
 ///     - return with no attestation
 ///
-/// Requires AttestationSigner
+/// The `graph-fee-charged`, `graph-attestable` and `graph-allocation` headers
+/// of the response let the gateways reconcile the fees of each response.
+///
+/// Requires AttestationSigner, and FeeCharged and Allocation for the headers
 pub async fn attestation_middleware(
     request: Request,
     next: Next,
 ) -> Result<Response, AttestationError> {
     let signer = request.extensions().get::<AttestationSigner>().cloned();
+    let fee_charged = request.extensions().get::<FeeCharged>().copied();
+    let allocation = request.extensions().get::<Allocation>().cloned();
 
-    let (parts, graphql_response) = next.run(request).await.into_parts();
+    let response = next.run(request).await;
+    // refused queries keep their error status
+    if response.extensions().get::<RefundReceipt>().is_some() {
+        return Ok(response);
+    }
+
+    let (parts, graphql_response) = response.into_parts();
     let attestation_response = parts.extensions.get::<AttestationInput>();
     let bytes = to_bytes(graphql_response, usize::MAX).await?;
     let res = String::from_utf8(bytes.into())?;
 
     let attestation = match (signer, attestation_response) {
         (Some(signer), Some(AttestationInput::Attestable { req })) => {
-            Some(signer.create_attestation(req, &res))
+            let deployment = signer.deployment().to_string();
+            let attestation = match signer.cached_attestation(req, &res) {
+                Ok(attestation) => {
+                    ATTESTATION_CACHE
+                        .with_label_values(&[&deployment, "hit"])
+                        .inc();
+                    attestation
+                }
+                Err(key) => {
+                    ATTESTATION_CACHE
+                        .with_label_values(&[&deployment, "miss"])
+                        .inc();
+                    signer.sign_attestation(key, req, &res)
+                }
+            };
+            Some(attestation)
         }
         _ => None,
     };
 
+    let attested = attestation.is_some();
     let response = serde_json::to_string(&IndexerResponsePayload {
         graphql_response: res,
         attestation,
@@ -66,9 +106,24 @@ pub async fn attestation_middleware(
 
     let mut response = Response::new(response.into());
     *response.headers_mut() = parts.headers;
+    let headers = response.headers_mut();
+    headers.insert(
+        GRAPH_ATTESTABLE,
+        HeaderValue::from_static(if attested { "true" } else { "false" }),
+    );
+    if let Some(FeeCharged(fee)) = fee_charged {
+        headers.insert(GRAPH_FEE_CHARGED, header_value(fee));
+    }
+    if let Some(Allocation(allocation_id)) = allocation {
+        headers.insert(GRAPH_ALLOCATION, header_value(allocation_id));
+    }
     Ok(response)
 }
 
+fn header_value(value: impl ToString) -> HeaderValue {
+    HeaderValue::try_from(value.to_string()).expect("Numbers and addresses are valid header values")
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum AttestationError {
     #[error("There was an AxumError: {0}")]
@@ -91,9 +146,18 @@ impl StatusCodeExt for AttestationError {
     }
 }
 
+impl ErrorCode for AttestationError {
+    fn error_code(&self) -> IndexerErrorCode {
+        // the response of graph-node can't be read
+        IndexerErrorCode::SubgraphQuery
+    }
+}
+
+impl RouteError for AttestationError {}
+
 impl IntoResponse for AttestationError {
     fn into_response(self) -> Response {
-        self.status_code().into_response()
+        error_response(&self)
     }
 }
 
@@ -114,7 +178,8 @@ mod tests {
     use tower::ServiceExt;
 
     use crate::middleware::{
-        attestation::IndexerResponsePayload, attestation_middleware, AttestationInput,
+        attestation::IndexerResponsePayload, attestation_middleware, auth::FeeCharged,
+        AttestationInput,
     };
 
     const REQUEST: &str = "request";
@@ -179,6 +244,44 @@ mod tests {
             .is_ok());
     }
 
+    #[tokio::test]
+    async fn test_fee_insight_headers() {
+        let (allocation, signer) = allocation_signer();
+        let handle = move |_: Request<Body>| async move {
+            let mut res = Response::new(RESPONSE.to_string());
+            res.extensions_mut().insert(AttestationInput::Attestable {
+                req: REQUEST.to_string(),
+            });
+            res
+        };
+        let app = Router::new()
+            .route("/", get(handle))
+            .layer(from_fn(attestation_middleware));
+
+        let request = Request::builder()
+            .uri("/")
+            .extension(signer)
+            .extension(FeeCharged(42))
+            .extension(crate::middleware::Allocation(allocation.id))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(request).await.unwrap();
+        let headers = res.headers();
+        assert_eq!(headers.get("graph-attestable").unwrap(), "true");
+        assert_eq!(headers.get("graph-fee-charged").unwrap(), "42");
+        assert_eq!(
+            headers.get("graph-allocation").unwrap(),
+            allocation.id.to_string().as_str()
+        );
+
+        // free query
+        let res = send_request(app, None).await;
+        let headers = res.headers();
+        assert_eq!(headers.get("graph-attestable").unwrap(), "false");
+        assert!(headers.get("graph-fee-charged").is_none());
+        assert!(headers.get("graph-allocation").is_none());
+    }
+
     #[tokio::test]
     async fn test_non_assignable() {
         let (_, signer) = allocation_signer();
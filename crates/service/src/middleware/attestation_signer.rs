@@ -9,31 +9,54 @@ use axum::{
     response::Response,
 };
 use indexer_attestation::AttestationSigner;
+use indexer_monitor::DisputeManagerWatcher;
 use thegraph_core::alloy::primitives::Address;
 use tokio::sync::watch;
 
 use super::Allocation;
+use crate::{error::IndexerServiceError, metrics::ATTESTATION_SIGNER_MISSING};
 
 #[derive(Clone)]
 pub struct AttestationState {
     pub attestation_signers: watch::Receiver<HashMap<Address, AttestationSigner>>,
+    pub dispute_manager: DisputeManagerWatcher,
+    /// dispute manager the attestations are signed for, any if not set
+    pub expected_dispute_manager: Option<Address>,
 }
 
 /// Injects the attestation signer to be used in the attestation
 ///
-/// Needs Allocation Extension
+/// Needs Allocation Extension, queries for an allocation without a signer
+/// yet are counted and served without attestation. Queries for an allocation
+/// are refused while the dispute manager differs from the expected one.
 pub async fn signer_middleware(
     State(state): State<AttestationState>,
     mut request: Request,
     next: Next,
-) -> Response {
+) -> Result<Response, IndexerServiceError> {
     if let Some(Allocation(allocation_id)) = request.extensions().get::<Allocation>() {
-        if let Some(signer) = state.attestation_signers.borrow().get(allocation_id) {
-            request.extensions_mut().insert(signer.clone());
+        if let Some(expected) = state.expected_dispute_manager {
+            let current = *state.dispute_manager.borrow();
+            if current != expected {
+                return Err(IndexerServiceError::DisputeManagerChanged { expected, current });
+            }
+        }
+        let signer = state
+            .attestation_signers
+            .borrow()
+            .get(allocation_id)
+            .cloned();
+        match signer {
+            Some(signer) => {
+                request.extensions_mut().insert(signer);
+            }
+            None => ATTESTATION_SIGNER_MISSING
+                .with_label_values(&[&allocation_id.to_string()])
+                .inc(),
         }
     }
 
-    next.run(request).await
+    Ok(next.run(request).await)
 }
 
 #[cfg(test)]
@@ -43,6 +66,7 @@ mod tests {
     use indexer_monitor::attestation_signers;
     use reqwest::StatusCode;
     use test_assets::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use thegraph_core::alloy::primitives::Address;
     use tokio::sync::{mpsc::channel, watch};
     use tower::Service;
 
@@ -55,12 +79,12 @@ mod tests {
         let allocation = **allocations.keys().collect::<Vec<_>>().first().unwrap();
 
         let (_, allocations_rx) = watch::channel(allocations);
-        let (_, dispute_manager_rx) = watch::channel(DISPUTE_MANAGER_ADDRESS);
+        let (dispute_manager_tx, dispute_manager_rx) = watch::channel(DISPUTE_MANAGER_ADDRESS);
         let attestation_signers = attestation_signers(
             allocations_rx,
             INDEXER_MNEMONIC.clone(),
             1,
-            dispute_manager_rx,
+            dispute_manager_rx.clone(),
         );
 
         let expected_signer = attestation_signers
@@ -71,6 +95,8 @@ mod tests {
 
         let state = AttestationState {
             attestation_signers,
+            dispute_manager: dispute_manager_rx,
+            expected_dispute_manager: Some(DISPUTE_MANAGER_ADDRESS),
         };
 
         let middleware = from_fn_with_state(state, signer_middleware);
@@ -110,5 +136,20 @@ mod tests {
 
         let req = rx.recv().await.unwrap();
         assert!(req.extensions().get::<AttestationSigner>().is_none());
+
+        // with another dispute manager
+        dispute_manager_tx.send(Address::repeat_byte(0x42)).unwrap();
+        let res = app
+            .call(
+                Request::builder()
+                    .uri("/")
+                    .extension(Allocation(allocation))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rx.try_recv().is_err());
     }
 }
@@ -1,13 +1,17 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod api_key;
 mod bearer;
+mod deferred_receipt;
 mod or;
 mod tap;
 
+pub use api_key::{api_key_authorize, hash_api_key};
 pub use bearer::Bearer;
+pub use deferred_receipt::{deferred_receipt_authorize, QUERY_ID_HEADER};
 pub use or::OrExt;
-pub use tap::tap_receipt_authorize;
+pub use tap::{tap_receipt_authorize, FeeCharged};
 
 #[cfg(test)]
 mod tests {
@@ -17,6 +21,7 @@ mod tests {
         body::Body,
         http::{Request, Response},
     };
+    use indexer_receipt::store::PgReceiptStore;
     use reqwest::{header, StatusCode};
     use sqlx::PgPool;
     use tap_core::{manager::Manager, receipt::checks::CheckList};
@@ -36,7 +41,11 @@ mod tests {
     async fn service(
         pgpool: PgPool,
     ) -> impl Service<Request<Body>, Response = Response<Body>, Error = impl std::fmt::Debug> {
-        let context = IndexerTapContext::new(pgpool.clone(), TAP_EIP712_DOMAIN.clone()).await;
+        let context = IndexerTapContext::new(
+            Arc::new(PgReceiptStore::new(pgpool.clone())),
+            TAP_EIP712_DOMAIN.clone(),
+        )
+        .await;
         let tap_manager = Arc::new(Manager::new(
             TAP_EIP712_DOMAIN.clone(),
             context,
@@ -53,8 +62,17 @@ mod tests {
             )
             .unwrap(),
         ));
+        let value_metric = Box::leak(Box::new(
+            prometheus::register_histogram_vec_with_registry!(
+                "merge_checks_value_test",
+                "Value of the receipts accepted",
+                &["deployment", "sender"],
+                registry,
+            )
+            .unwrap(),
+        ));
         let free_query = Bearer::new(BEARER_TOKEN);
-        let tap_auth = auth::tap_receipt_authorize(tap_manager, metric);
+        let tap_auth = auth::tap_receipt_authorize(tap_manager, metric, value_metric);
         let authorize_requests = free_query.or(tap_auth);
 
         let authorization_middleware = AsyncRequireAuthorizationLayer::new(authorize_requests);
@@ -0,0 +1,284 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validates free query API keys
+//!
+//! API keys are stored hashed in the database, each one with an optional
+//! daily quota and an optional list of deployments it can query.
+//!
+//! Requests without a bearer token, or with a token that doesn't match
+//! any active key, are forwarded to the next authorizer (usually tap).
+
+use std::future::Future;
+
+use axum::{
+    body::Body,
+    http::{Request, Response},
+    response::IntoResponse,
+};
+use reqwest::header;
+use sqlx::PgPool;
+use thegraph_core::{
+    alloy::{hex::ToHexExt, primitives::keccak256},
+    DeploymentId,
+};
+use tower_http::auth::AsyncAuthorizeRequest;
+
+use crate::{error::IndexerServiceError, metrics::API_KEY_QUERIES};
+
+/// Returns the hash used to store and look up an API key token
+pub fn hash_api_key(token: &str) -> String {
+    keccak256(token.as_bytes()).encode_hex()
+}
+
+/// Middleware to authorize free queries using API keys
+///
+/// If the request is not authorized by an API key, it's forwarded to `next`,
+/// every request is if `pgpool` is `None`
+///
+/// Uses the DeploymentId extension to check the allowed deployments
+pub fn api_key_authorize<A, B>(
+    pgpool: Option<PgPool>,
+    next: A,
+) -> impl AsyncAuthorizeRequest<
+    B,
+    RequestBody = B,
+    ResponseBody = Body,
+    Future = impl Future<Output = Result<Request<B>, Response<Body>>> + Send,
+> + Clone
+       + Send
+where
+    A: AsyncAuthorizeRequest<B, RequestBody = B, ResponseBody = Body> + Clone + Send + 'static,
+    A::Future: Send,
+    B: Send + 'static,
+{
+    move |request: Request<B>| {
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(ToString::to_string);
+        let deployment_id = request.extensions().get::<DeploymentId>().copied();
+        let pgpool = pgpool.clone();
+        let mut next = next.clone();
+
+        async move {
+            let (Some(pgpool), Some(token)) = (pgpool, token) else {
+                return next.authorize(request).await;
+            };
+            match authorize_api_key(&pgpool, &token, deployment_id).await {
+                Ok(true) => Ok(request),
+                Ok(false) => next.authorize(request).await,
+                Err(error) => Err(error.into_response()),
+            }
+        }
+    }
+}
+
+/// Returns `true` if the token belongs to an active key that is
+/// allowed to run this query, `false` if the token is unknown.
+async fn authorize_api_key(
+    pgpool: &PgPool,
+    token: &str,
+    deployment_id: Option<DeploymentId>,
+) -> Result<bool, IndexerServiceError> {
+    let Some(api_key) = sqlx::query!(
+        r#"
+            SELECT name, daily_quota, allowed_deployments
+            FROM free_query_api_keys
+            WHERE key_hash = $1 AND revoked_at IS NULL
+        "#,
+        hash_api_key(token),
+    )
+    .fetch_optional(pgpool)
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    if let (Some(allowed_deployments), Some(deployment_id)) =
+        (&api_key.allowed_deployments, deployment_id)
+    {
+        let deployment = deployment_id.to_string();
+        if !allowed_deployments.contains(&deployment) {
+            return Err(IndexerServiceError::ApiKeyDeploymentNotAllowed(
+                api_key.name,
+                deployment_id,
+            ));
+        }
+    }
+
+    if api_key.daily_quota.is_some_and(|quota| quota <= 0) {
+        return Err(IndexerServiceError::ApiKeyQuotaExceeded(api_key.name));
+    }
+
+    // the update is skipped once the quota is reached, so no row is returned
+    let usage = sqlx::query!(
+        r#"
+            INSERT INTO free_query_api_key_usage (name, day, query_count)
+            VALUES ($1, (NOW() AT TIME ZONE 'UTC')::DATE, 1)
+            ON CONFLICT (name, day) DO UPDATE
+            SET query_count = free_query_api_key_usage.query_count + 1
+            WHERE $2::BIGINT IS NULL OR free_query_api_key_usage.query_count < $2
+            RETURNING query_count
+        "#,
+        api_key.name,
+        api_key.daily_quota,
+    )
+    .fetch_optional(pgpool)
+    .await?;
+
+    if usage.is_none() {
+        return Err(IndexerServiceError::ApiKeyQuotaExceeded(api_key.name));
+    }
+
+    API_KEY_QUERIES.with_label_values(&[&api_key.name]).inc();
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, Response},
+    };
+    use reqwest::{header, StatusCode};
+    use sqlx::PgPool;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+    use thegraph_core::DeploymentId;
+    use tower::{Service, ServiceBuilder, ServiceExt};
+    use tower_http::auth::AsyncRequireAuthorizationLayer;
+
+    use super::{api_key_authorize, hash_api_key};
+
+    const TOKEN: &str = "dashboard-token";
+
+    async fn service(
+        pgpool: PgPool,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = impl std::fmt::Debug> {
+        // reject everything that is not authorized by an api key
+        let deny_all = |_: Request<Body>| async {
+            let mut res = Response::new(Body::default());
+            *res.status_mut() = StatusCode::PAYMENT_REQUIRED;
+            Err::<Request<Body>, _>(res)
+        };
+        let authorization_middleware =
+            AsyncRequireAuthorizationLayer::new(api_key_authorize(Some(pgpool), deny_all));
+
+        let mut service = ServiceBuilder::new()
+            .layer(authorization_middleware)
+            .service_fn(|_: Request<Body>| async {
+                Ok::<_, anyhow::Error>(Response::new(Body::default()))
+            });
+
+        service.ready().await.unwrap();
+        service
+    }
+
+    async fn create_key(
+        pgpool: &PgPool,
+        daily_quota: Option<i64>,
+        allowed_deployments: Option<Vec<String>>,
+    ) {
+        sqlx::query!(
+            r#"
+                INSERT INTO free_query_api_keys (name, key_hash, daily_quota, allowed_deployments)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (name) DO NOTHING
+            "#,
+            "dashboard",
+            hash_api_key(TOKEN),
+            daily_quota,
+            allowed_deployments.as_deref(),
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    fn request(token: &str, deployment: DeploymentId) -> Request<Body> {
+        let mut req = Request::new(Body::default());
+        req.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        req.extensions_mut().insert(deployment);
+        req
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_unknown_key_is_forwarded(pgpool: PgPool) {
+        let mut service = service(pgpool).await;
+        let res = service
+            .call(request("unknown", ESCROW_SUBGRAPH_DEPLOYMENT))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_daily_quota(pgpool: PgPool) {
+        create_key(&pgpool, Some(2), None).await;
+        let mut service = service(pgpool).await;
+
+        for _ in 0..2 {
+            let res = service
+                .call(request(TOKEN, ESCROW_SUBGRAPH_DEPLOYMENT))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = service
+            .call(request(TOKEN, ESCROW_SUBGRAPH_DEPLOYMENT))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_allowed_deployments(pgpool: PgPool) {
+        create_key(
+            &pgpool,
+            None,
+            Some(vec![ESCROW_SUBGRAPH_DEPLOYMENT.to_string()]),
+        )
+        .await;
+        let mut service = service(pgpool).await;
+
+        let res = service
+            .call(request(TOKEN, ESCROW_SUBGRAPH_DEPLOYMENT))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = service
+            .call(request(TOKEN, NETWORK_SUBGRAPH_DEPLOYMENT))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_revoked_key_is_forwarded(pgpool: PgPool) {
+        create_key(&pgpool, None, None).await;
+        sqlx::query!(
+            r#"
+                UPDATE free_query_api_keys
+                SET revoked_at = NOW()
+                WHERE name = $1 AND revoked_at IS NULL
+            "#,
+            "dashboard",
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let mut service = service(pgpool).await;
+
+        let res = service
+            .call(request(TOKEN, ESCROW_SUBGRAPH_DEPLOYMENT))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+    }
+}
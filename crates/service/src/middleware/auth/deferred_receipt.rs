@@ -0,0 +1,236 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serves the queries of gateways delivering their receipts separately
+//!
+//! These queries are sent with the token of the receipt ingest service and a
+//! `Graph-Query-Id` header instead of a receipt. They are logged in
+//! `deferred_queries` until their receipt is submitted to the receipt ingest
+//! service, which checks it against the logged query.
+//!
+//! Other requests are forwarded to the next authorizer (usually tap).
+
+use std::{future::Future, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{HeaderValue, Request, Response},
+    response::IntoResponse,
+};
+use reqwest::header;
+use sqlx::PgPool;
+use tap_core::receipt::Context;
+use tower_http::auth::AsyncAuthorizeRequest;
+
+use crate::{
+    error::IndexerServiceError,
+    metrics::DEFERRED_QUERIES,
+    tap::{AgoraQuery, TapReceipt},
+};
+
+/// Header identifying a query whose receipt is submitted later
+pub const QUERY_ID_HEADER: &str = "graph-query-id";
+
+/// Size of the `query_id` column
+const MAX_QUERY_ID_LENGTH: usize = 255;
+
+/// Middleware to authorize the queries of gateways delivering their receipts
+/// separately
+///
+/// If the request has a receipt, or isn't sent with `auth_token` and a query
+/// id, it's forwarded to `next`. Every request is forwarded if `auth_token`
+/// is not set.
+///
+/// Requires the Arc<Context> extension
+pub fn deferred_receipt_authorize<A, B>(
+    pgpool: PgPool,
+    auth_token: Option<&str>,
+    next: A,
+) -> impl AsyncAuthorizeRequest<
+    B,
+    RequestBody = B,
+    ResponseBody = Body,
+    Future = impl Future<Output = Result<Request<B>, Response<Body>>> + Send,
+> + Clone
+       + Send
+where
+    A: AsyncAuthorizeRequest<B, RequestBody = B, ResponseBody = Body> + Clone + Send + 'static,
+    A::Future: Send,
+    B: Send + 'static,
+{
+    let bearer: Option<HeaderValue> = auth_token.map(|auth_token| {
+        format!("Bearer {}", auth_token)
+            .parse()
+            .expect("token is not a valid header value")
+    });
+    move |request: Request<B>| {
+        let deferred = bearer
+            .as_ref()
+            .is_some_and(|bearer| request.headers().get(header::AUTHORIZATION) == Some(bearer))
+            && request.extensions().get::<TapReceipt>().is_none();
+        let query_id = request
+            .headers()
+            .get(QUERY_ID_HEADER)
+            .filter(|_| deferred)
+            .and_then(|value| value.to_str().ok())
+            .filter(|query_id| !query_id.is_empty() && query_id.len() <= MAX_QUERY_ID_LENGTH)
+            .map(ToString::to_string);
+        let ctx = request.extensions().get::<Arc<Context>>().cloned();
+        let pgpool = pgpool.clone();
+        let mut next = next.clone();
+
+        async move {
+            let Some(query_id) = query_id else {
+                return next.authorize(request).await;
+            };
+            match log_query(&pgpool, &query_id, ctx.as_deref()).await {
+                Ok(()) => Ok(request),
+                Err(error) => Err(error.into_response()),
+            }
+        }
+    }
+}
+
+/// Logs the query until its receipt is submitted
+///
+/// A query id can only be used once, since a single receipt is matched with
+/// it: the query would be served for free otherwise.
+async fn log_query(
+    pgpool: &PgPool,
+    query_id: &str,
+    ctx: Option<&Context>,
+) -> Result<(), IndexerServiceError> {
+    let query = ctx
+        .and_then(|ctx| ctx.get::<AgoraQuery>())
+        .ok_or(IndexerServiceError::DeploymentIdNotFound)?;
+    let deployment = query.deployment_id.to_string();
+    let inserted = sqlx::query!(
+        r#"
+            INSERT INTO deferred_queries (query_id, deployment_id, query, variables)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (query_id) DO NOTHING
+        "#,
+        query_id,
+        &deployment,
+        &query.query,
+        &query.variables
+    )
+    .execute(pgpool)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+        return Err(IndexerServiceError::QueryIdReused(query_id.to_string()));
+    }
+
+    DEFERRED_QUERIES.with_label_values(&[&deployment]).inc();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{Request, Response},
+    };
+    use reqwest::{header, StatusCode};
+    use sqlx::PgPool;
+    use tap_core::receipt::Context;
+    use test_assets::ESCROW_SUBGRAPH_DEPLOYMENT;
+    use tower::{Service, ServiceBuilder, ServiceExt};
+    use tower_http::auth::AsyncRequireAuthorizationLayer;
+
+    use super::{deferred_receipt_authorize, QUERY_ID_HEADER};
+    use crate::tap::AgoraQuery;
+
+    const TOKEN: &str = "gateway-token";
+
+    async fn service(
+        pgpool: PgPool,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = impl std::fmt::Debug> {
+        // reject everything that is not a deferred query
+        let deny_all = |_: Request<Body>| async {
+            let mut res = Response::new(Body::default());
+            *res.status_mut() = StatusCode::PAYMENT_REQUIRED;
+            Err::<Request<Body>, _>(res)
+        };
+        let authorization_middleware = AsyncRequireAuthorizationLayer::new(
+            deferred_receipt_authorize(pgpool, Some(TOKEN), deny_all),
+        );
+
+        let mut service = ServiceBuilder::new()
+            .layer(authorization_middleware)
+            .service_fn(|_: Request<Body>| async {
+                Ok::<_, anyhow::Error>(Response::new(Body::default()))
+            });
+
+        service.ready().await.unwrap();
+        service
+    }
+
+    fn request(token: &str, query_id: Option<&str>) -> Request<Body> {
+        let mut req = Request::new(Body::default());
+        req.headers_mut().insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        if let Some(query_id) = query_id {
+            req.headers_mut()
+                .insert(QUERY_ID_HEADER, query_id.parse().unwrap());
+        }
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: ESCROW_SUBGRAPH_DEPLOYMENT,
+            query: "{ _meta { block { number } } }".to_string(),
+            variables: "".to_string(),
+        });
+        req.extensions_mut().insert(Arc::new(ctx));
+        req
+    }
+
+    async fn logged_queries(pgpool: &PgPool) -> Vec<String> {
+        sqlx::query_scalar!("SELECT query_id FROM deferred_queries ORDER BY query_id")
+            .fetch_all(pgpool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_deferred_query_is_logged(pgpool: PgPool) {
+        let mut service = service(pgpool.clone()).await;
+
+        let res = service.call(request(TOKEN, Some("query-1"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(logged_queries(&pgpool).await, vec!["query-1".to_string()]);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_query_id_is_not_reused(pgpool: PgPool) {
+        let mut service = service(pgpool.clone()).await;
+
+        let res = service.call(request(TOKEN, Some("query-1"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // a single receipt pays for the id, the second query would be free
+        let res = service.call(request(TOKEN, Some("query-1"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(logged_queries(&pgpool).await, vec!["query-1".to_string()]);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_other_queries_are_forwarded(pgpool: PgPool) {
+        let mut service = service(pgpool.clone()).await;
+
+        let res = service.call(request(TOKEN, None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+
+        let res = service
+            .call(request("wrong-token", Some("query-1")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYMENT_REQUIRED);
+
+        assert!(logged_queries(&pgpool).await.is_empty());
+    }
+}
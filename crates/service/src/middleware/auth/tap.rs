@@ -7,7 +7,8 @@
 //! as part of the checks.
 //!
 //! This also uses MetricLabels injected in the receipts to provide
-//! metrics related to receipt check failure
+//! metrics related to receipt check failure, and records the value of the
+//! receipts accepted by deployment and sender
 
 use std::{future::Future, sync::Arc};
 
@@ -18,22 +19,42 @@ use axum::{
 };
 use tap_core::{
     manager::{adapters::ReceiptStore, Manager},
-    receipt::Context,
+    receipt::{Context, WithValueAndTimestamp},
 };
 use tower_http::auth::AsyncAuthorizeRequest;
+use tracing::Instrument;
 
 use crate::{
-    error::IndexerServiceError, middleware::prometheus_metrics::MetricLabels, tap::TapReceipt,
+    error::IndexerServiceError,
+    middleware::{
+        labels::{NO_DEPLOYMENT_ID, NO_SENDER},
+        prometheus_metrics::MetricLabels,
+        ReceiptsAccepted, RequestId, RequestReceipts, Sender,
+    },
+    tap::{with_request_batch, AgoraQuery, TapReceipt},
 };
 
+/// GRT wei in a GRT
+const GRT: f64 = 1e18;
+
+/// Value of the receipts accepted for a request, in GRT wei
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeCharged(pub u128);
+
 /// Middleware to verify and store TAP receipts
 ///
-/// It also optionally updates a failed receipt metric if Labels are provided
+/// It also optionally updates a failed receipt metric if Labels are provided,
+/// and observes the value of the receipts accepted in GRT
 ///
-/// Requires TapReceipt, MetricLabels and Arc<Context> extensions
+/// Requires TapReceipt, MetricLabels and Arc<Context> extensions. All the
+/// receipts of a request with RequestReceipts are verified, and stored
+/// only if they all pass the checks. Receipts are stored with the
+/// RequestId extension, if any. Injects the FeeCharged extension, and
+/// marks the ReceiptsAccepted extension, if any.
 pub fn tap_receipt_authorize<T, B>(
     tap_manager: Arc<Manager<T, TapReceipt>>,
     failed_receipt_metric: &'static prometheus::CounterVec,
+    receipt_value_metric: &'static prometheus::HistogramVec,
 ) -> impl AsyncAuthorizeRequest<
     B,
     RequestBody = B,
@@ -47,29 +68,85 @@ where
 {
     move |mut request: Request<B>| {
         let receipt = request.extensions_mut().remove::<TapReceipt>();
+        let request_receipts = request.extensions_mut().remove::<RequestReceipts>();
+        let request_id = request.extensions().get::<RequestId>().copied();
         // load labels from previous middlewares
         let labels = request.extensions().get::<MetricLabels>().cloned();
         // load context from previous middlewares
         let ctx = request.extensions().get::<Arc<Context>>().cloned();
         let tap_manager = tap_manager.clone();
+        let span = tracing::info_span!("tap_receipt_validation");
 
         async move {
             let execute = || async {
                 let receipt = receipt.ok_or(IndexerServiceError::ReceiptNotFound)?;
-                // Verify the receipt and store it in the database
-                tap_manager
-                    .verify_and_store_receipt(&ctx.unwrap_or_default(), receipt)
-                    .await
-                    .inspect_err(|_| {
-                        if let Some(labels) = labels {
-                            failed_receipt_metric
-                                .with_label_values(&labels.get_labels())
-                                .inc()
+                let ctx = ctx.unwrap_or_default();
+                let values: Vec<u128> = match &request_receipts {
+                    Some(RequestReceipts { receipts, .. }) => {
+                        receipts.iter().map(WithValueAndTimestamp::value).collect()
+                    }
+                    None => vec![receipt.value()],
+                };
+                // Verify the receipts and store them in the database
+                let verified = match request_receipts {
+                    Some(RequestReceipts {
+                        request_id,
+                        receipts,
+                    }) => {
+                        let expected = receipts.len();
+                        with_request_batch(request_id, expected, async {
+                            for receipt in receipts {
+                                tap_manager.verify_and_store_receipt(&ctx, receipt).await?;
+                            }
+                            Ok::<_, tap_core::Error>(())
+                        })
+                        .await
+                    }
+                    None => match request_id {
+                        Some(RequestId(request_id)) => {
+                            with_request_batch(
+                                request_id,
+                                1,
+                                tap_manager.verify_and_store_receipt(&ctx, receipt),
+                            )
+                            .await
                         }
-                    })?;
+                        None => tap_manager.verify_and_store_receipt(&ctx, receipt).await,
+                    },
+                };
+                verified.inspect_err(|_| {
+                    if let Some(labels) = labels {
+                        failed_receipt_metric
+                            .with_label_values(&labels.get_labels())
+                            .inc()
+                    }
+                })?;
+
+                let deployment = ctx
+                    .get::<AgoraQuery>()
+                    .map(|query| query.deployment_id.to_string());
+                let sender = ctx.get::<Sender>().map(|sender| sender.0.to_string());
+                let labels = [
+                    deployment.as_deref().unwrap_or(NO_DEPLOYMENT_ID),
+                    sender.as_deref().unwrap_or(NO_SENDER),
+                ];
+                for value in &values {
+                    receipt_value_metric
+                        .with_label_values(&labels)
+                        .observe(*value as f64 / GRT);
+                }
+                if let Some(accepted) = request.extensions().get::<ReceiptsAccepted>() {
+                    accepted.accept();
+                }
+                request
+                    .extensions_mut()
+                    .insert(FeeCharged(values.iter().sum()));
                 Ok::<_, IndexerServiceError>(request)
             };
-            execute().await.map_err(|error| error.into_response())
+            execute()
+                .instrument(span)
+                .await
+                .map_err(|error| error.into_response())
         }
     }
 }
@@ -84,6 +161,7 @@ mod tests {
         body::Body,
         http::{Request, Response},
     };
+    use indexer_receipt::store::PgReceiptStore;
     use prometheus::core::Collector;
     use reqwest::StatusCode;
     use rstest::*;
@@ -121,13 +199,33 @@ mod tests {
         metric
     }
 
+    #[fixture]
+    fn value_metric() -> &'static prometheus::HistogramVec {
+        let registry = prometheus::Registry::new();
+        let metric = Box::leak(Box::new(
+            prometheus::register_histogram_vec_with_registry!(
+                "tap_middleware_value_test",
+                "Value of the receipts accepted",
+                &["deployment", "sender"],
+                registry,
+            )
+            .unwrap(),
+        ));
+        metric
+    }
+
     const FAILED_NONCE: u64 = 99;
 
     async fn service(
         metric: &'static prometheus::CounterVec,
+        value_metric: &'static prometheus::HistogramVec,
         pgpool: PgPool,
     ) -> impl Service<Request<Body>, Response = Response<Body>, Error = impl std::fmt::Debug> {
-        let context = IndexerTapContext::new(pgpool, TAP_EIP712_DOMAIN.clone()).await;
+        let context = IndexerTapContext::new(
+            Arc::new(PgReceiptStore::new(pgpool)),
+            TAP_EIP712_DOMAIN.clone(),
+        )
+        .await;
 
         struct MyCheck;
         #[async_trait::async_trait]
@@ -150,7 +248,7 @@ mod tests {
             context,
             CheckList::new(vec![Arc::new(MyCheck)]),
         ));
-        let tap_auth = tap_receipt_authorize(manager, metric);
+        let tap_auth = tap_receipt_authorize(manager, metric, value_metric);
         let authorization_middleware = AsyncRequireAuthorizationLayer::new(tap_auth);
 
         let mut service = ServiceBuilder::new()
@@ -167,9 +265,10 @@ mod tests {
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_tap_valid_receipt(
         metric: &'static prometheus::CounterVec,
+        value_metric: &'static prometheus::HistogramVec,
         #[ignore] pgpool: PgPool,
     ) {
-        let mut service = service(metric, pgpool.clone()).await;
+        let mut service = service(metric, value_metric, pgpool.clone()).await;
 
         let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
 
@@ -179,6 +278,11 @@ mod tests {
         let res = service.call(req).await.unwrap();
         assert_eq!(res.status(), StatusCode::OK);
 
+        // observed with the default labels, the request has no context
+        let values = value_metric.collect();
+        let histogram = values.first().unwrap().get_metric().first().unwrap();
+        assert_eq!(histogram.get_histogram().get_sample_count(), 1);
+
         // verify receipts
         assert_while_retry!({
             sqlx::query!("SELECT * FROM scalar_tap_receipts")
@@ -193,9 +297,10 @@ mod tests {
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_invalid_receipt_with_failed_metric(
         metric: &'static prometheus::CounterVec,
+        value_metric: &'static prometheus::HistogramVec,
         #[ignore] pgpool: PgPool,
     ) {
-        let mut service = service(metric, pgpool.clone()).await;
+        let mut service = service(metric, value_metric, pgpool.clone()).await;
         // if it fails tap receipt, should return failed to process payment + tap message
 
         assert_eq!(metric.collect().first().unwrap().get_metric().len(), 0);
@@ -227,9 +332,10 @@ mod tests {
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_tap_missing_signed_receipt(
         metric: &'static prometheus::CounterVec,
+        value_metric: &'static prometheus::HistogramVec,
         #[ignore] pgpool: PgPool,
     ) {
-        let mut service = service(metric, pgpool.clone()).await;
+        let mut service = service(metric, value_metric, pgpool.clone()).await;
         // if it doesnt contain the signed receipt
         // should return payment required
         let req = Request::new(Body::default());
@@ -0,0 +1,109 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header::CONTENT_LENGTH,
+    middleware::Next,
+    response::Response,
+};
+use http_body_util::LengthLimitError;
+
+use crate::error::IndexerServiceError;
+
+/// Refuses the requests with a body larger than `limit` bytes with
+/// `413 Payload Too Large`
+///
+/// A request announcing a larger `Content-Length` is refused before its body
+/// is read, otherwise the body is read up to the limit. Must wrap the layers
+/// taking the receipt, so that the receipt of a refused query is not stored.
+pub async fn body_limit_middleware(
+    State(limit): State<usize>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let request = match content_length {
+        Some(content_length) if content_length > limit as u64 => {
+            return Err(IndexerServiceError::BodyTooLarge(limit));
+        }
+        // hyper doesn't read more than the announced length
+        Some(_) => request,
+        // chunked or decompressed
+        None => {
+            let (parts, body) = request.into_parts();
+            let body = axum::body::to_bytes(body, limit).await.map_err(|error| {
+                if is_length_limit(&error) {
+                    IndexerServiceError::BodyTooLarge(limit)
+                } else {
+                    IndexerServiceError::AxumError(error)
+                }
+            })?;
+            Request::from_parts(parts, Body::from(body))
+        }
+    };
+    Ok(next.run(request).await)
+}
+
+/// Whether reading the body failed because of its length
+fn is_length_limit(error: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if error.is::<LengthLimitError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_LENGTH, Request},
+        middleware::from_fn_with_state,
+        routing::post,
+        Router,
+    };
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::body_limit_middleware;
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let app = Router::new()
+            .route("/", post(|body: String| async move { body }))
+            .layer(from_fn_with_state(8, body_limit_middleware));
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+
+        let response = send(Request::post("/").body(Body::from("small")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // refused from the content length
+        let request = Request::post("/")
+            .header(CONTENT_LENGTH, 14)
+            .body(Body::from("much too large"))
+            .unwrap();
+        let response = send(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // without a content length, the body is read up to the limit
+        let response = send(
+            Request::post("/")
+                .body(Body::from("much too large"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use indexer_config::{CompressionAlgorithm, CompressionConfig};
+use tower_http::{
+    compression::{predicate::SizeAbove, CompressionLayer},
+    decompression::RequestDecompressionLayer,
+};
+
+/// Compresses the responses as negotiated by `Accept-Encoding`
+///
+/// Must wrap the attestation middleware, the attestation is signed over
+/// the uncompressed response.
+pub fn compression_layer(config: &CompressionConfig) -> CompressionLayer<SizeAbove> {
+    CompressionLayer::new()
+        .gzip(config.algorithms.contains(&CompressionAlgorithm::Gzip))
+        .br(config.algorithms.contains(&CompressionAlgorithm::Brotli))
+        .compress_when(SizeAbove::new(config.min_size_bytes))
+}
+
+/// Decompresses the request bodies, requests with another `Content-Encoding`
+/// are refused with `415 Unsupported Media Type`
+pub fn decompression_layer(config: &CompressionConfig) -> RequestDecompressionLayer {
+    RequestDecompressionLayer::new()
+        .gzip(config.algorithms.contains(&CompressionAlgorithm::Gzip))
+        .br(config.algorithms.contains(&CompressionAlgorithm::Brotli))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        routing::post,
+        Router,
+    };
+    use indexer_config::{CompressionAlgorithm, CompressionConfig};
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{compression_layer, decompression_layer};
+
+    #[tokio::test]
+    async fn test_compression() {
+        let config = CompressionConfig {
+            min_size_bytes: 1024,
+            algorithms: HashSet::from([CompressionAlgorithm::Gzip]),
+        };
+        let app = Router::new()
+            .route("/small", post(|| async { "ok" }))
+            .route("/large", post(|| async { "a".repeat(2048) }))
+            .layer(decompression_layer(&config))
+            .layer(compression_layer(&config));
+
+        let send = |uri: &str, content_encoding: Option<&str>| {
+            let mut request = Request::post(uri).header(header::ACCEPT_ENCODING, "br, gzip");
+            if let Some(content_encoding) = content_encoding {
+                request = request.header(header::CONTENT_ENCODING, content_encoding);
+            }
+            app.clone().oneshot(request.body(Body::from("{}")).unwrap())
+        };
+
+        let res = send("/large", None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let res = send("/small", None).await.unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+        // brotli is not allowed
+        let res = send("/small", Some("br")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
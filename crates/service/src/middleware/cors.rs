@@ -0,0 +1,169 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Context;
+use axum::{
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use indexer_config::CorsConfig;
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+
+const ANY: &str = "*";
+
+/// Headers added to every response, unless the handler already set them
+const SECURITY_HEADERS: [(HeaderName, &str); 4] = [
+    (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (header::X_FRAME_OPTIONS, "DENY"),
+    (header::REFERRER_POLICY, "no-referrer"),
+    (
+        header::CONTENT_SECURITY_POLICY,
+        "default-src 'none'; frame-ancestors 'none'",
+    ),
+];
+
+/// Builds the CORS layer of the public routes
+///
+/// Browsers don't accept a wildcard for the `Authorization` header, so
+/// `"*"` in `allowed_headers` mirrors the headers of the preflight request.
+pub fn cors_layer(config: &CorsConfig) -> anyhow::Result<CorsLayer> {
+    let allow_origin = if config.allowed_origins.iter().any(|origin| origin == ANY) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .map(|origin| {
+                    HeaderValue::from_str(origin)
+                        .with_context(|| format!("Invalid CORS origin {origin}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )
+    };
+    let allow_headers = if config.allowed_headers.iter().any(|name| name == ANY) {
+        AllowHeaders::mirror_request()
+    } else {
+        AllowHeaders::list(
+            config
+                .allowed_headers
+                .iter()
+                .map(|name| {
+                    HeaderName::try_from(name.as_str())
+                        .with_context(|| format!("Invalid CORS header {name}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )
+    };
+    let allow_methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.as_bytes())
+                .with_context(|| format!("Invalid CORS method {method}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_headers(allow_headers)
+        .allow_methods(allow_methods))
+}
+
+/// Adds the standard security headers to the responses
+pub async fn security_headers_middleware(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for (name, value) in SECURITY_HEADERS {
+        headers
+            .entry(name)
+            .or_insert(HeaderValue::from_static(value));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        middleware::from_fn,
+        routing::get,
+        Router,
+    };
+    use indexer_config::CorsConfig;
+    use reqwest::{Method, StatusCode};
+    use tower::ServiceExt;
+
+    use super::{cors_layer, security_headers_middleware};
+
+    fn app(allowed_origins: &[&str]) -> Router {
+        let config = CorsConfig {
+            allowed_origins: allowed_origins.iter().map(|s| s.to_string()).collect(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["*".to_string()],
+            security_headers: true,
+        };
+        Router::new()
+            .route("/status", get(|| async { "ok" }))
+            .layer(from_fn(security_headers_middleware))
+            .layer(cors_layer(&config).unwrap())
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/status")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preflight() {
+        let res = app(&["*"])
+            .oneshot(preflight("https://dashboard.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization"
+        );
+
+        let app = app(&["https://dashboard.example.com"]);
+        let res = app
+            .clone()
+            .oneshot(preflight("https://dashboard.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dashboard.example.com"
+        );
+
+        let res = app
+            .oneshot(preflight("https://other.example.com"))
+            .await
+            .unwrap();
+        assert!(!res
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_security_headers() {
+        let res = app(&["*"])
+            .oneshot(Request::get("/status").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(res.headers()[header::X_FRAME_OPTIONS], "DENY");
+    }
+}
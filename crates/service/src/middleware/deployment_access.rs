@@ -0,0 +1,113 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use indexer_monitor::DeniedDeploymentsWatcher;
+use thegraph_core::DeploymentId;
+
+use crate::error::IndexerServiceError;
+
+/// State used by the deployment access middleware
+#[derive(Clone)]
+pub struct DeploymentAccessState {
+    /// deployments denied by the network subgraph
+    pub denied_by_network: DeniedDeploymentsWatcher,
+    /// deployments that can be queried, any deployment if empty
+    pub allowed: Arc<HashSet<DeploymentId>>,
+    /// deployments that can't be queried
+    pub denied: Arc<HashSet<DeploymentId>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeploymentAccessError {
+    #[error("Deployment {0} is denied by the network")]
+    DeniedByNetwork(DeploymentId),
+    #[error("Deployment {0} is not served by this indexer")]
+    DeniedByIndexer(DeploymentId),
+}
+
+impl DeploymentAccessState {
+    fn validate(&self, deployment_id: &DeploymentId) -> Result<(), DeploymentAccessError> {
+        if self.denied.contains(deployment_id)
+            || (!self.allowed.is_empty() && !self.allowed.contains(deployment_id))
+        {
+            return Err(DeploymentAccessError::DeniedByIndexer(*deployment_id));
+        }
+        if self.denied_by_network.borrow().contains(deployment_id) {
+            return Err(DeploymentAccessError::DeniedByNetwork(*deployment_id));
+        }
+        Ok(())
+    }
+}
+
+/// Refuses queries to the deployments denied by the network or by the operator
+///
+/// The query is refused before the receipt is stored and the response
+/// is attested, the sender doesn't pay for it.
+///
+/// Requires Deployment Id extension
+pub async fn deployment_access_middleware(
+    State(state): State<DeploymentAccessState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    if let Some(deployment_id) = request.extensions().get::<DeploymentId>() {
+        state.validate(deployment_id)?;
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+    use thegraph_core::{deployment_id, DeploymentId};
+    use tokio::sync::watch;
+
+    use super::{DeploymentAccessError, DeploymentAccessState};
+
+    const OTHER_DEPLOYMENT: DeploymentId =
+        deployment_id!("QmAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+
+    fn access_state(allowed: &[DeploymentId], denied: &[DeploymentId]) -> DeploymentAccessState {
+        DeploymentAccessState {
+            denied_by_network: watch::channel(HashSet::from([NETWORK_SUBGRAPH_DEPLOYMENT])).1,
+            allowed: Arc::new(allowed.iter().copied().collect()),
+            denied: Arc::new(denied.iter().copied().collect()),
+        }
+    }
+
+    #[test]
+    fn test_denied_by_network() {
+        let state = access_state(&[], &[]);
+        assert!(matches!(
+            state.validate(&NETWORK_SUBGRAPH_DEPLOYMENT),
+            Err(DeploymentAccessError::DeniedByNetwork(_))
+        ));
+        assert!(state.validate(&ESCROW_SUBGRAPH_DEPLOYMENT).is_ok());
+    }
+
+    #[test]
+    fn test_local_lists() {
+        let state = access_state(&[], &[ESCROW_SUBGRAPH_DEPLOYMENT]);
+        assert!(matches!(
+            state.validate(&ESCROW_SUBGRAPH_DEPLOYMENT),
+            Err(DeploymentAccessError::DeniedByIndexer(_))
+        ));
+        assert!(state.validate(&OTHER_DEPLOYMENT).is_ok());
+
+        let state = access_state(&[ESCROW_SUBGRAPH_DEPLOYMENT], &[]);
+        assert!(state.validate(&ESCROW_SUBGRAPH_DEPLOYMENT).is_ok());
+        assert!(matches!(
+            state.validate(&OTHER_DEPLOYMENT),
+            Err(DeploymentAccessError::DeniedByIndexer(_))
+        ));
+    }
+}
@@ -12,9 +12,9 @@ use super::{
     sender::Sender,
 };
 
-const NO_DEPLOYMENT_ID: &str = "no-deployment";
+pub(crate) const NO_DEPLOYMENT_ID: &str = "no-deployment";
 const NO_ALLOCATION: &str = "no-allocation";
-const NO_SENDER: &str = "no-sender";
+pub(crate) const NO_SENDER: &str = "no-sender";
 
 /// Labels used by metrics which implements MetricLabelProvider
 ///
@@ -0,0 +1,110 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+#[derive(Clone)]
+pub struct LegacyRoutes {
+    /// API version prefix the routes are served under
+    pub prefix: &'static str,
+    /// Date after which the routes without the prefix can be removed,
+    /// see [indexer_config::ServiceConfig::legacy_routes_sunset]
+    pub sunset: Option<HeaderValue>,
+}
+
+/// Marks the responses of the routes served without the API version prefix
+/// as deprecated, with a link to the same route under the prefix
+pub async fn legacy_route_middleware(
+    State(LegacyRoutes { prefix, sunset }): State<LegacyRoutes>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let successor = format!(
+        "<{prefix}{}>; rel=\"successor-version\"",
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION, HeaderValue::from_static("true"));
+    if let Some(sunset) = sunset {
+        headers.insert(SUNSET, sunset);
+    }
+    if let Ok(successor) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, successor);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{HeaderValue, Request},
+        middleware::from_fn_with_state,
+        routing::get,
+        Router,
+    };
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{legacy_route_middleware, LegacyRoutes};
+
+    const SUNSET: &str = "Thu, 31 Dec 2026 23:59:59 GMT";
+
+    fn app(sunset: Option<&'static str>) -> Router {
+        let routes = Router::new().route("/info", get(|| async { "ok" }));
+        let legacy_routes = LegacyRoutes {
+            prefix: "/v1",
+            sunset: sunset.map(HeaderValue::from_static),
+        };
+        Router::new()
+            .nest("/v1", routes.clone())
+            .merge(routes.layer(from_fn_with_state(legacy_routes, legacy_route_middleware)))
+    }
+
+    #[tokio::test]
+    async fn test_legacy_route_headers() {
+        let app = app(Some(SUNSET));
+
+        let send = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                app.oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let res = send("/info").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["deprecation"], "true");
+        assert_eq!(res.headers()["sunset"], SUNSET);
+        assert_eq!(
+            res.headers()["link"],
+            "</v1/info>; rel=\"successor-version\""
+        );
+
+        let res = send("/v1/info").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("deprecation").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_route_without_sunset() {
+        let res = app(None)
+            .oneshot(Request::get("/info").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["deprecation"], "true");
+        assert!(res.headers().get("sunset").is_none());
+    }
+}
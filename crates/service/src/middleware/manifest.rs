@@ -0,0 +1,102 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{collections::HashSet, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use indexer_monitor::SubgraphManifestsWatcher;
+use thegraph_core::DeploymentId;
+
+use crate::error::IndexerServiceError;
+
+/// State used by the manifest middleware
+#[derive(Clone)]
+pub struct ManifestState {
+    pub manifests: SubgraphManifestsWatcher,
+    /// features and data source kinds that can't be served
+    pub unsupported: Arc<HashSet<String>>,
+}
+
+/// Refuses queries to deployments using an unsupported feature or data source kind
+///
+/// The check happens before the receipt is stored, so the sender doesn't
+/// pay for a query that is not served. Deployments whose manifest is not
+/// known are served as usual.
+///
+/// Requires Deployment Id extension
+pub async fn manifest_middleware(
+    State(state): State<ManifestState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    if let Some(deployment_id) = request.extensions().get::<DeploymentId>() {
+        let unsupported = state
+            .manifests
+            .borrow()
+            .get(deployment_id)
+            .and_then(|manifest| {
+                manifest
+                    .uses()
+                    .find(|name| state.unsupported.contains(*name))
+                    .map(str::to_string)
+            });
+        if let Some(unsupported) = unsupported {
+            return Err(IndexerServiceError::UnsupportedDeployment(
+                *deployment_id,
+                unsupported,
+            ));
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use indexer_monitor::SubgraphManifest;
+    use reqwest::StatusCode;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{manifest_middleware, ManifestState};
+
+    #[tokio::test]
+    async fn test_manifest_middleware() {
+        let manifest = SubgraphManifest {
+            spec_version: "0.0.5".to_string(),
+            features: ["grafting".to_string()].into(),
+            data_source_kinds: ["substreams".to_string()].into(),
+        };
+        let state = ManifestState {
+            manifests: watch::channel(HashMap::from([(NETWORK_SUBGRAPH_DEPLOYMENT, manifest)])).1,
+            unsupported: Arc::new(HashSet::from(["substreams".to_string()])),
+        };
+
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(from_fn_with_state(state, manifest_middleware));
+
+        let send = |deployment| {
+            let mut request = Request::builder().uri("/").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(deployment);
+            app.clone().oneshot(request)
+        };
+
+        let res = send(NETWORK_SUBGRAPH_DEPLOYMENT).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // manifest not known
+        let res = send(ESCROW_SUBGRAPH_DEPLOYMENT).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use graphql::graphql_parser::query as q;
+use indexer_config::QueryBlocklistConfig;
+use regex::Regex;
+use sqlx::{postgres::PgListener, PgPool};
+use tap_core::receipt::Context;
+use thegraph_core::alloy::primitives::{keccak256, B256};
+
+use super::sender::Sender;
+use crate::{
+    error::IndexerServiceError, metrics::BLOCKED_QUERIES, service::query_nesting, tap::AgoraQuery,
+};
+
+const BLOCKLIST_NOTIFICATION_CHANNEL: &str = "query_blocklist_notification";
+
+/// Queries nested deeper than this are hashed without being parsed, the
+/// parser could run out of stack
+const MAX_PARSED_NESTING: usize = 256;
+
+const NO_SENDER: &str = "no-sender";
+
+/// Fingerprint of `query`, the keccak256 hash of the query printed without
+/// its comments and extra whitespace, so that the formatting of a query
+/// doesn't change its fingerprint
+///
+/// Queries that can't be parsed are hashed as is.
+pub fn query_fingerprint(query: &str) -> B256 {
+    if query_nesting(query) > MAX_PARSED_NESTING {
+        return keccak256(query.trim());
+    }
+    match q::parse_query::<String>(query) {
+        Ok(document) => keccak256(document.to_string()),
+        Err(_) => keccak256(query.trim()),
+    }
+}
+
+/// Entries of the blocklist
+#[derive(Default)]
+struct Entries {
+    hashes: HashSet<B256>,
+    regexes: Vec<Regex>,
+}
+
+impl Entries {
+    /// The entry matched by `query`, its fingerprint or its regex
+    fn matching(&self, query: &str) -> Option<String> {
+        if !self.hashes.is_empty() {
+            let fingerprint = query_fingerprint(query);
+            if self.hashes.contains(&fingerprint) {
+                return Some(fingerprint.to_string());
+            }
+        }
+        self.regexes
+            .iter()
+            .find(|regex| regex.is_match(query))
+            .map(|regex| regex.as_str().to_string())
+    }
+}
+
+/// Queries refused by the service, from the configuration and from the
+/// `query_blocklist` table, reloaded every time the table is modified
+#[derive(Clone)]
+pub struct QueryBlocklist {
+    configured: Arc<Entries>,
+    stored: Arc<RwLock<Entries>>,
+}
+
+impl QueryBlocklist {
+    pub async fn new(pgpool: PgPool, config: &QueryBlocklistConfig) -> anyhow::Result<Self> {
+        let configured = Entries {
+            hashes: config.hashes.clone(),
+            regexes: config
+                .regexes
+                .iter()
+                .map(|regex| Regex::new(regex))
+                .collect::<Result<_, _>>()?,
+        };
+
+        // Listen before loading the entries so that no update is missed
+        let mut pglistener = PgListener::connect_with(&pgpool).await?;
+        pglistener.listen(BLOCKLIST_NOTIFICATION_CHANNEL).await?;
+
+        let blocklist = Self {
+            configured: Arc::new(configured),
+            stored: Arc::new(RwLock::new(Entries::default())),
+        };
+        blocklist.reload(&pgpool).await?;
+        tokio::spawn(blocklist.clone().watch(pgpool, pglistener));
+        Ok(blocklist)
+    }
+
+    async fn reload(&self, pgpool: &PgPool) -> anyhow::Result<()> {
+        let rows = sqlx::query!("SELECT kind, pattern FROM query_blocklist")
+            .fetch_all(pgpool)
+            .await?;
+        let mut entries = Entries::default();
+        for row in rows {
+            let (kind, pattern) = (row.kind, row.pattern);
+            let entry = match kind.as_str() {
+                "hash" => B256::from_str(&pattern)
+                    .map(|hash| {
+                        entries.hashes.insert(hash);
+                    })
+                    .map_err(anyhow::Error::from),
+                _ => Regex::new(&pattern)
+                    .map(|regex| entries.regexes.push(regex))
+                    .map_err(anyhow::Error::from),
+            };
+            // entries are validated when added, skip the ones that were not
+            if let Err(error) = entry {
+                tracing::warn!(%kind, %pattern, %error, "Invalid query blocklist entry");
+            }
+        }
+        *self.stored.write().unwrap() = entries;
+        Ok(())
+    }
+
+    /// Reloads the entries every time the table is modified
+    async fn watch(self, pgpool: PgPool, mut pglistener: PgListener) {
+        loop {
+            // the listener reconnects on the next call, notifications sent in
+            // between are lost so the entries are reloaded anyway
+            if let Err(error) = pglistener.recv().await {
+                tracing::warn!(%error, "Lost the query blocklist notifications");
+            }
+            if let Err(error) = self.reload(&pgpool).await {
+                tracing::error!(%error, "Failed to reload the query blocklist");
+            }
+        }
+    }
+
+    fn matching(&self, query: &str) -> Option<String> {
+        self.configured
+            .matching(query)
+            .or_else(|| self.stored.read().unwrap().matching(query))
+    }
+}
+
+/// Refuses the queries matching an entry of the [QueryBlocklist]
+///
+/// The query is refused before the receipt is stored and before it reaches
+/// graph-node. Refused queries are counted by deployment and entry, and
+/// logged with their sender.
+///
+/// Requires the tap context, and the Sender extension to log the sender
+pub async fn query_blocklist_middleware(
+    State(blocklist): State<QueryBlocklist>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let query = request
+        .extensions()
+        .get::<Arc<Context>>()
+        .and_then(|ctx| ctx.get::<AgoraQuery>());
+    if let Some(query) = query {
+        if let Some(entry) = blocklist.matching(&query.query) {
+            let sender = request
+                .extensions()
+                .get::<Sender>()
+                .map(|sender| sender.0.to_string());
+            tracing::warn!(
+                deployment = %query.deployment_id,
+                sender = sender.as_deref().unwrap_or(NO_SENDER),
+                entry,
+                "Query refused by the query blocklist"
+            );
+            BLOCKED_QUERIES
+                .with_label_values(&[&query.deployment_id.to_string(), &entry])
+                .inc();
+            return Err(IndexerServiceError::QueryBlocked);
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use indexer_config::QueryBlocklistConfig;
+    use sqlx::PgPool;
+
+    use super::{query_fingerprint, QueryBlocklist};
+
+    #[test]
+    fn test_query_fingerprint() {
+        assert_eq!(
+            query_fingerprint("{ tokens(first: 10) { id } }"),
+            query_fingerprint("# all the tokens\n{tokens(first:10){\n  id\n}}")
+        );
+        assert_ne!(
+            query_fingerprint("{ tokens(first: 10) { id } }"),
+            query_fingerprint("{ tokens(first: 20) { id } }")
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_query_blocklist(pgpool: PgPool) {
+        let config = QueryBlocklistConfig {
+            hashes: HashSet::from([query_fingerprint("{ tokens { id } }")]),
+            regexes: vec![r"_meta\s*\{".to_string()],
+            admin_token: None,
+        };
+        let blocklist = QueryBlocklist::new(pgpool.clone(), &config).await.unwrap();
+
+        assert!(blocklist.matching("{tokens{ id }}").is_some());
+        assert!(blocklist
+            .matching("{ _meta { block { number } } }")
+            .is_some());
+        assert!(blocklist.matching("{ accounts { id } }").is_none());
+
+        // entries added at runtime are loaded once notified
+        sqlx::query!("INSERT INTO query_blocklist (kind, pattern) VALUES ('regex', 'accounts')")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let mut blocked = false;
+        for _ in 0..50 {
+            blocked = blocklist.matching("{ accounts { id } }").is_some();
+            if blocked {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(blocked);
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use indexer_config::QueryLimitsConfig;
+use tap_core::receipt::Context;
+use thegraph_core::DeploymentId;
+
+use super::sender::Sender;
+use crate::{
+    error::IndexerServiceError, metrics::QUERY_LIMITS_EXCEEDED, service::QueryComplexity,
+    tap::AgoraQuery,
+};
+
+const NO_SENDER: &str = "no-sender";
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryLimitsError {
+    #[error("Query is nested deeper than the {0} fields accepted for deployment {1}")]
+    Depth(usize, DeploymentId),
+    #[error("Query is more complex than the {0} accepted for deployment {1}")]
+    Complexity(u64, DeploymentId),
+}
+
+impl QueryLimitsError {
+    fn limit(&self) -> &'static str {
+        match self {
+            QueryLimitsError::Depth(..) => "depth",
+            QueryLimitsError::Complexity(..) => "complexity",
+        }
+    }
+}
+
+fn validate(limits: &QueryLimitsConfig, query: &AgoraQuery) -> Result<(), QueryLimitsError> {
+    let deployment = query.deployment_id;
+    let max_depth = limits.max_depth_for(&deployment);
+    let max_complexity = limits.max_complexity_for(&deployment);
+    // queries that can't be parsed are refused by graph-node
+    let Some(estimate) = QueryComplexity::estimate(&query.query, &query.variables, max_depth)
+    else {
+        return Ok(());
+    };
+    if estimate.depth > max_depth {
+        return Err(QueryLimitsError::Depth(max_depth, deployment));
+    }
+    if estimate.complexity > max_complexity {
+        return Err(QueryLimitsError::Complexity(max_complexity, deployment));
+    }
+    Ok(())
+}
+
+/// Refuses the queries nested too deep or estimated too complex for their
+/// deployment, see [QueryComplexity]
+///
+/// The query is refused before the receipt is stored and before it reaches
+/// graph-node. Refused queries are counted by deployment and sender.
+///
+/// Requires the tap context, and the Sender extension to record the sender
+pub async fn query_limits_middleware(
+    State(limits): State<Arc<QueryLimitsConfig>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let query = request
+        .extensions()
+        .get::<Arc<Context>>()
+        .and_then(|ctx| ctx.get::<AgoraQuery>());
+    if let Some(query) = query {
+        if let Err(error) = validate(&limits, query) {
+            let sender = request
+                .extensions()
+                .get::<Sender>()
+                .map(|sender| sender.0.to_string());
+            let sender = sender.as_deref().unwrap_or(NO_SENDER);
+            tracing::warn!(
+                deployment = %query.deployment_id,
+                sender,
+                %error,
+                "Query refused by the query limits"
+            );
+            QUERY_LIMITS_EXCEEDED
+                .with_label_values(&[&query.deployment_id.to_string(), sender, error.limit()])
+                .inc();
+            return Err(error.into());
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use indexer_config::{DeploymentQueryLimitsConfig, QueryLimitsConfig};
+    use reqwest::StatusCode;
+    use tap_core::receipt::Context;
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+    use thegraph_core::DeploymentId;
+    use tower::ServiceExt;
+
+    use super::query_limits_middleware;
+    use crate::tap::AgoraQuery;
+
+    #[tokio::test]
+    async fn test_query_limits_middleware() {
+        let limits = QueryLimitsConfig {
+            max_depth: 2,
+            max_complexity: 50,
+            deployments: HashMap::from([(
+                ESCROW_SUBGRAPH_DEPLOYMENT,
+                DeploymentQueryLimitsConfig {
+                    max_depth: Some(3),
+                    max_complexity: None,
+                },
+            )]),
+        };
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(from_fn_with_state(
+                Arc::new(limits),
+                query_limits_middleware,
+            ));
+
+        let send = |deployment_id: DeploymentId, query: &str| {
+            let mut ctx = Context::new();
+            ctx.insert(AgoraQuery {
+                deployment_id,
+                query: query.to_string(),
+                variables: String::new(),
+            });
+            let request = Request::builder()
+                .uri("/")
+                .extension(Arc::new(ctx))
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let res = send(NETWORK_SUBGRAPH_DEPLOYMENT, "{ tokens(first: 10) { id } }")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let deep_query = "{ tokens(first: 2) { holders(first: 2) { id } } }";
+        let res = send(NETWORK_SUBGRAPH_DEPLOYMENT, deep_query).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        // deeper queries accepted for this deployment
+        let res = send(ESCROW_SUBGRAPH_DEPLOYMENT, deep_query).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(NETWORK_SUBGRAPH_DEPLOYMENT, "{ tokens(first: 100) { id } }")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // 100 entities without `first`
+        let res = send(NETWORK_SUBGRAPH_DEPLOYMENT, "{ tokens { id } }")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
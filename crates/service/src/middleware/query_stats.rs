@@ -0,0 +1,32 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{Instant, SystemTime};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use thegraph_core::DeploymentId;
+
+use crate::service::QueryStats;
+
+/// Counts the queries answered successfully and their latency
+///
+/// Requires DeploymentId extension
+pub async fn query_stats_middleware(
+    State(stats): State<QueryStats>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let deployment = request.extensions().get::<DeploymentId>().copied();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    if let Some(deployment) = deployment {
+        if response.status().is_success() {
+            stats.record(deployment, start.elapsed(), SystemTime::now());
+        }
+    }
+    response
+}
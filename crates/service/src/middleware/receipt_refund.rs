@@ -0,0 +1,33 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::tap::ReceiptRefunds;
+
+/// Marks a response for which the receipt of the query must not be kept
+#[derive(Clone, Copy)]
+pub struct RefundReceipt;
+
+/// Drops the receipts of the query if the response is marked with
+/// [RefundReceipt]
+///
+/// The receipts are stored by the tap authorization before the query is
+/// executed, this has to run before it so they are held until the response
+/// is known. The receipts dropped are never written, tap-agent doesn't see
+/// them.
+pub async fn receipt_refund_middleware(
+    State(refunds): State<ReceiptRefunds>,
+    request: Request,
+    next: Next,
+) -> Response {
+    refunds
+        .hold(next.run(request), |response| {
+            response.extensions().get::<RefundReceipt>().is_some()
+        })
+        .await
+}
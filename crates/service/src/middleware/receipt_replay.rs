@@ -0,0 +1,496 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use indexer_receipt::store::ReceiptStore;
+use lru::LruCache;
+use tap_core::receipt::{Context, WithValueAndTimestamp};
+use thegraph_core::alloy::primitives::{keccak256, B256};
+use uuid::Uuid;
+
+use crate::{
+    error::IndexerServiceError,
+    metrics::REPLAYED_RECEIPTS,
+    middleware::{request_receipts, RefundReceipt, RequestId},
+    tap::{AgoraQuery, TapReceipt},
+};
+
+/// Signatures of the receipts recently received by this replica
+const MAX_RECENT_RECEIPTS: NonZeroUsize = match NonZeroUsize::new(100_000) {
+    Some(size) => size,
+    None => unreachable!(),
+};
+
+/// Retries of a request accepted with the same receipts, by each replica
+const MAX_RETRIES: u32 = 2;
+
+/// Time a request can be retried with the same receipts, from when they were
+/// first received, or signed if they were received by another replica
+const RETRY_WINDOW: Duration = Duration::from_secs(30);
+
+/// How a receipt was received before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Received {
+    Never,
+    /// For the same request, retried by the gateway
+    SameRequest,
+    /// For another request, a request without id, or retried too often
+    OtherRequest,
+}
+
+/// First request a receipt was received for
+#[derive(Debug, Clone, Copy)]
+struct FirstRequest {
+    request_id: Option<Uuid>,
+    /// Hash of the query, unknown if the receipt was received by another
+    /// replica until it's retried here
+    query_hash: Option<B256>,
+    received_at_ns: u64,
+    retries: u32,
+}
+
+impl FirstRequest {
+    /// Whether the request is a retry of the first one: same request id,
+    /// same query, within [RETRY_WINDOW] and at most [MAX_RETRIES] times.
+    ///
+    /// The request id is chosen by the client, so it can't be enough to
+    /// accept a receipt again.
+    fn retried(&mut self, request_id: Option<Uuid>, query_hash: Option<B256>) -> Received {
+        let same_request = matches!(
+            (self.request_id, request_id),
+            (Some(first), Some(current)) if first == current
+        );
+        let same_query = match (self.query_hash, query_hash) {
+            (Some(first), Some(current)) => first == current,
+            _ => true,
+        };
+        let elapsed = now_ns().saturating_sub(self.received_at_ns);
+        if !same_request
+            || !same_query
+            || elapsed > RETRY_WINDOW.as_nanos() as u64
+            || self.retries >= MAX_RETRIES
+        {
+            return Received::OtherRequest;
+        }
+        self.retries += 1;
+        if self.query_hash.is_none() {
+            self.query_hash = query_hash;
+        }
+        Received::SameRequest
+    }
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Hash of the query a receipt pays for, retries must send the same one
+fn query_hash(query: &AgoraQuery) -> B256 {
+    let query = serde_json::to_vec(&(
+        query.deployment_id.to_string(),
+        &query.query,
+        &query.variables,
+    ))
+    .expect("strings are serialized");
+    keccak256(query)
+}
+
+/// Set by the TAP auth layer once the receipts of the request are accepted
+///
+/// The receipts of a request that was not authorized with them are not
+/// kept as received, so they can be sent again.
+#[derive(Clone, Debug, Default)]
+pub struct ReceiptsAccepted(Arc<AtomicBool>);
+
+impl ReceiptsAccepted {
+    pub fn accept(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn accepted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Receipts received before, by this replica or by another one sharing
+/// the database, with the id of the request they paid for
+#[derive(Clone)]
+pub struct ReceiptReplayState {
+    storage: Arc<dyn ReceiptStore>,
+    recent: Arc<Mutex<LruCache<Vec<u8>, FirstRequest>>>,
+}
+
+impl ReceiptReplayState {
+    pub fn new(storage: Arc<dyn ReceiptStore>) -> Self {
+        Self {
+            storage,
+            recent: Arc::new(Mutex::new(LruCache::new(MAX_RECENT_RECEIPTS))),
+        }
+    }
+
+    /// Whether the receipt was already received, it's recorded as received
+    /// for the request otherwise
+    async fn received(
+        &self,
+        receipt: &TapReceipt,
+        request_id: Option<Uuid>,
+        query_hash: Option<B256>,
+    ) -> Received {
+        let signature = receipt.signature().as_bytes().to_vec();
+        {
+            let mut recent = self.recent.lock().unwrap();
+            // the first request is kept
+            if let Some(first) = recent.get_mut(&signature) {
+                return first.retried(request_id, query_hash);
+            }
+            recent.put(
+                signature.clone(),
+                FirstRequest {
+                    request_id,
+                    query_hash,
+                    received_at_ns: now_ns(),
+                    retries: 0,
+                },
+            );
+        }
+
+        // receipts are only stored after being checked, the unique index
+        // drops the ones received by two replicas at the same time
+        match self.stored_request_id(receipt).await {
+            Ok(Some(stored_request_id)) => {
+                // retries are compared to the request it was stored for
+                let mut first = FirstRequest {
+                    request_id: stored_request_id,
+                    query_hash: None,
+                    received_at_ns: receipt.timestamp_ns(),
+                    retries: 0,
+                };
+                let received = first.retried(request_id, query_hash);
+                self.recent.lock().unwrap().put(signature, first);
+                received
+            }
+            Ok(None) => Received::Never,
+            Err(error) => {
+                tracing::warn!(%error, "Failed to look for a replayed receipt");
+                Received::Never
+            }
+        }
+    }
+
+    /// Request id the receipt was stored with, `None` if it wasn't stored
+    async fn stored_request_id(
+        &self,
+        receipt: &TapReceipt,
+    ) -> anyhow::Result<Option<Option<Uuid>>> {
+        if !self.storage.contains_receipt(receipt).await? {
+            return Ok(None);
+        }
+        Ok(Some(self.storage.receipt_request_id(receipt).await?))
+    }
+
+    /// Refunded receipts can be sent again
+    fn forget(&self, receipt: &TapReceipt) {
+        self.recent
+            .lock()
+            .unwrap()
+            .pop(receipt.signature().as_bytes().as_slice());
+    }
+}
+
+/// Rejects the receipts that were already received for another query
+///
+/// Without it, a receipt sent to several replicas of the service would be
+/// accepted by each of them and only stored once. Retries of a request,
+/// sent with the same `x-request-id`, the same query and the same receipts,
+/// are accepted [MAX_RETRIES] times within [RETRY_WINDOW]: their receipts
+/// are only stored once.
+///
+/// The receipts are held as received while the request is authorized, so
+/// that the same receipts sent concurrently are rejected, and forgotten if
+/// the request was not authorized with them.
+///
+/// Requires Receipt extension, every receipt of a request paid with several
+/// of them is checked. Uses the RequestId extension and the tap context, if
+/// any. Injects the ReceiptsAccepted extension
+pub async fn receipt_replay_middleware(
+    State(state): State<ReceiptReplayState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let receipts = request_receipts(request.extensions());
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|RequestId(request_id)| *request_id);
+    let query_hash = request
+        .extensions()
+        .get::<Arc<Context>>()
+        .and_then(|ctx| ctx.get::<AgoraQuery>())
+        .map(query_hash);
+    // receipts received for the first time by this request
+    let mut first_received = Vec::with_capacity(receipts.len());
+    for receipt in &receipts {
+        match state.received(receipt, request_id, query_hash).await {
+            Received::Never => first_received.push(receipt),
+            Received::SameRequest => {
+                REPLAYED_RECEIPTS.with_label_values(&["retried"]).inc();
+            }
+            Received::OtherRequest => {
+                // the other receipts of the request can be sent again
+                first_received
+                    .iter()
+                    .for_each(|receipt| state.forget(receipt));
+                REPLAYED_RECEIPTS.with_label_values(&["rejected"]).inc();
+                return Err(IndexerServiceError::ReceiptReplayed);
+            }
+        }
+    }
+    let accepted = ReceiptsAccepted::default();
+    request.extensions_mut().insert(accepted.clone());
+    let response = next.run(request).await;
+    // refused by the auth layer, served for free or refunded
+    if !accepted.accepted() || response.extensions().get::<RefundReceipt>().is_some() {
+        first_received
+            .iter()
+            .for_each(|receipt| state.forget(receipt));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body, http::Request, middleware::from_fn_with_state, routing::get, Extension, Router,
+    };
+    use indexer_receipt::store::PgReceiptStore;
+    use reqwest::StatusCode;
+    use sqlx::{types::BigDecimal, PgPool};
+    use tap_core::receipt::Context;
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, NETWORK_SUBGRAPH_DEPLOYMENT, TAP_SIGNER,
+    };
+    use thegraph_core::alloy::hex::ToHexExt;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::{receipt_replay_middleware, ReceiptReplayState, ReceiptsAccepted, MAX_RETRIES};
+    use crate::{
+        middleware::RequestId,
+        tap::{AgoraQuery, TapReceipt},
+    };
+
+    async fn send(app: &Router, receipt: &TapReceipt) -> StatusCode {
+        send_request(app, receipt, None).await
+    }
+
+    async fn send_request(
+        app: &Router,
+        receipt: &TapReceipt,
+        request_id: Option<Uuid>,
+    ) -> StatusCode {
+        send_query(app, receipt, request_id, None).await
+    }
+
+    async fn send_query(
+        app: &Router,
+        receipt: &TapReceipt,
+        request_id: Option<Uuid>,
+        query: Option<&str>,
+    ) -> StatusCode {
+        let mut request = Request::builder().uri("/").extension(receipt.clone());
+        if let Some(request_id) = request_id {
+            request = request.extension(RequestId(request_id));
+        }
+        if let Some(query) = query {
+            let mut ctx = Context::new();
+            ctx.insert(AgoraQuery {
+                deployment_id: NETWORK_SUBGRAPH_DEPLOYMENT,
+                query: query.to_string(),
+                variables: String::new(),
+            });
+            request = request.extension(Arc::new(ctx));
+        }
+        app.clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    fn app(pgpool: PgPool) -> Router {
+        let middleware = from_fn_with_state(
+            ReceiptReplayState::new(Arc::new(PgReceiptStore::new(pgpool))),
+            receipt_replay_middleware,
+        );
+        Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(accepted): Extension<ReceiptsAccepted>| async move {
+                        accepted.accept();
+                        Body::empty()
+                    },
+                ),
+            )
+            .route(
+                "/unauthorized",
+                get(|| async { StatusCode::PAYMENT_REQUIRED }),
+            )
+            .layer(middleware)
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_unauthorized_receipts_are_forgotten(pgpool: PgPool) {
+        let app = app(pgpool);
+        let receipt = TapReceipt::V1(
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+        );
+        let unauthorized = Request::builder()
+            .uri("/unauthorized")
+            .extension(receipt.clone())
+            .body(Body::empty())
+            .unwrap();
+        let status = app.clone().oneshot(unauthorized).await.unwrap().status();
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+
+        // not received, since the request was not authorized with it
+        assert_eq!(send(&app, &receipt).await, StatusCode::OK);
+        assert_eq!(send(&app, &receipt).await, StatusCode::CONFLICT);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_replayed_receipts_are_rejected(pgpool: PgPool) {
+        let app = app(pgpool.clone());
+
+        // replayed to this replica
+        let receipt = TapReceipt::V1(
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+        );
+        assert_eq!(send(&app, &receipt).await, StatusCode::OK);
+        assert_eq!(send(&app, &receipt).await, StatusCode::CONFLICT);
+
+        // stored by another replica
+        let stored = create_signed_receipt(SignedReceiptRequest::builder().nonce(2).build()).await;
+        sqlx::query!(
+            "INSERT INTO scalar_tap_receipts
+                (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            TAP_SIGNER.1.encode_hex(),
+            stored.signature.as_bytes().to_vec(),
+            stored.message.allocation_id.encode_hex(),
+            BigDecimal::from(stored.message.timestamp_ns),
+            BigDecimal::from(stored.message.nonce),
+            BigDecimal::from(stored.message.value)
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(
+            send(&app, &TapReceipt::V1(stored)).await,
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_retried_requests_are_accepted(pgpool: PgPool) {
+        let app = app(pgpool.clone());
+
+        // retried by the gateway
+        let receipt = TapReceipt::V1(
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+        );
+        let request_id = Uuid::now_v7();
+        assert_eq!(
+            send_request(&app, &receipt, Some(request_id)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            send_request(&app, &receipt, Some(request_id)).await,
+            StatusCode::OK
+        );
+        // but not for another request
+        assert_eq!(
+            send_request(&app, &receipt, Some(Uuid::now_v7())).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(send(&app, &receipt).await, StatusCode::CONFLICT);
+
+        // stored by another replica for the same request
+        let stored = create_signed_receipt(SignedReceiptRequest::builder().nonce(2).build()).await;
+        sqlx::query!(
+            "INSERT INTO scalar_tap_receipts
+                (signer_address, signature, allocation_id, timestamp_ns, nonce, value, request_id)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            TAP_SIGNER.1.encode_hex(),
+            stored.signature.as_bytes().to_vec(),
+            stored.message.allocation_id.encode_hex(),
+            BigDecimal::from(stored.message.timestamp_ns),
+            BigDecimal::from(stored.message.nonce),
+            BigDecimal::from(stored.message.value),
+            request_id
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let stored = TapReceipt::V1(stored);
+        assert_eq!(
+            send_request(&app, &stored, Some(Uuid::now_v7())).await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            send_request(&app, &stored, Some(request_id)).await,
+            StatusCode::OK
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_retries_are_bound_to_the_query(pgpool: PgPool) {
+        let app = app(pgpool);
+        let receipt = TapReceipt::V1(
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+        );
+        let request_id = Uuid::now_v7();
+        let query = Some("{ _meta { block { number } } }");
+        assert_eq!(
+            send_query(&app, &receipt, Some(request_id), query).await,
+            StatusCode::OK
+        );
+
+        // the request id is chosen by the client, another query can't be
+        // paid with the same receipt
+        assert_eq!(
+            send_query(&app, &receipt, Some(request_id), Some("{ tokens { id } }")).await,
+            StatusCode::CONFLICT
+        );
+
+        // and the same query is only retried a few times
+        for _ in 0..MAX_RETRIES {
+            assert_eq!(
+                send_query(&app, &receipt, Some(request_id), query).await,
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            send_query(&app, &receipt, Some(request_id), query).await,
+            StatusCode::CONFLICT
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use indexer_config::ReceiptTimestampConfig;
+use tap_core::receipt::WithValueAndTimestamp;
+
+use crate::{error::IndexerServiceError, middleware::request_receipts};
+
+/// Bounds of the receipt timestamps, relative to the local clock
+#[derive(Clone)]
+pub struct ReceiptTimestampState {
+    pub max_future_skew: Duration,
+    pub max_age: Duration,
+}
+
+impl From<ReceiptTimestampConfig> for ReceiptTimestampState {
+    fn from(config: ReceiptTimestampConfig) -> Self {
+        Self {
+            max_future_skew: config.max_future_skew_secs,
+            max_age: config.max_age_secs,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReceiptTimestampError {
+    #[error(
+        "Receipt timestamp is {}ms in the future, at most {}ms is accepted, \
+        check the clock of the gateway",
+        skew.as_millis(),
+        max.as_millis()
+    )]
+    InFuture { skew: Duration, max: Duration },
+    #[error(
+        "Receipt timestamp is {}ms old, at most {}ms is accepted, \
+        check the clock of the gateway",
+        age.as_millis(),
+        max.as_millis()
+    )]
+    TooOld { age: Duration, max: Duration },
+}
+
+impl ReceiptTimestampState {
+    fn validate(&self, timestamp_ns: u64, now: SystemTime) -> Result<(), ReceiptTimestampError> {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let timestamp = Duration::from_nanos(timestamp_ns);
+        if timestamp > now {
+            let skew = timestamp - now;
+            if skew > self.max_future_skew {
+                return Err(ReceiptTimestampError::InFuture {
+                    skew,
+                    max: self.max_future_skew,
+                });
+            }
+        } else {
+            let age = now - timestamp;
+            if age > self.max_age {
+                return Err(ReceiptTimestampError::TooOld {
+                    age,
+                    max: self.max_age,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects receipts with a timestamp too far from the local clock
+///
+/// These receipts would be stored and only found invalid by tap-agent
+/// once the RAV is requested, so they are never paid for.
+///
+/// Requires Receipt extension, the timestamps of all the receipts of the
+/// request are checked
+pub async fn receipt_timestamp_middleware(
+    State(state): State<ReceiptTimestampState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let now = SystemTime::now();
+    for receipt in request_receipts(request.extensions()) {
+        state.validate(receipt.timestamp_ns(), now)?;
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use axum::{body::Body, http::Request, middleware::from_fn_with_state, routing::get, Router};
+    use reqwest::StatusCode;
+    use test_assets::{create_signed_receipt, SignedReceiptRequest};
+    use tower::ServiceExt;
+
+    use super::{receipt_timestamp_middleware, ReceiptTimestampError, ReceiptTimestampState};
+    use crate::tap::TapReceipt;
+
+    fn state() -> ReceiptTimestampState {
+        ReceiptTimestampState {
+            max_future_skew: Duration::from_secs(5),
+            max_age: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_validate_timestamp() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let timestamp_ns =
+            |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        let state = state();
+
+        assert!(state.validate(timestamp_ns(now), now).is_ok());
+        assert!(state
+            .validate(timestamp_ns(now + Duration::from_secs(5)), now)
+            .is_ok());
+        assert!(state
+            .validate(timestamp_ns(now - Duration::from_secs(30)), now)
+            .is_ok());
+        assert!(matches!(
+            state.validate(timestamp_ns(now + Duration::from_secs(6)), now),
+            Err(ReceiptTimestampError::InFuture { .. })
+        ));
+        assert!(matches!(
+            state.validate(timestamp_ns(now - Duration::from_secs(31)), now),
+            Err(ReceiptTimestampError::TooOld { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_receipt_timestamp_middleware() {
+        let middleware = from_fn_with_state(state(), receipt_timestamp_middleware);
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(middleware);
+
+        let send = |timestamp_ns: u64| {
+            let app = app.clone();
+            async move {
+                let receipt = create_signed_receipt(
+                    SignedReceiptRequest::builder()
+                        .timestamp_ns(timestamp_ns)
+                        .build(),
+                )
+                .await;
+                app.oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .extension(TapReceipt::V1(receipt))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+                .status()
+            }
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(send(now.as_nanos() as u64).await, StatusCode::OK);
+        let in_future = now + Duration::from_secs(60);
+        assert_eq!(
+            send(in_future.as_nanos() as u64).await,
+            StatusCode::PAYMENT_REQUIRED
+        );
+        let too_old = now - Duration::from_secs(60);
+        assert_eq!(
+            send(too_old.as_nanos() as u64).await,
+            StatusCode::PAYMENT_REQUIRED
+        );
+    }
+}
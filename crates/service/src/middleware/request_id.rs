@@ -0,0 +1,111 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+/// Header of the id of the query, shared by the gateway, the service and
+/// graph-node logs
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Id of the query, stored with its receipts
+///
+/// Retries of the gateway are sent with the same id and the same receipts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(pub Uuid);
+
+/// Injects the RequestId extension, the `x-request-id` of the gateway if it
+/// is a UUID, or a new one
+///
+/// The request forwarded to graph-node and the response carry it in the
+/// `x-request-id` header, it's recorded in the `http_request` span.
+pub async fn request_id_middleware(mut request: Request, next: Next) -> Response {
+    let sent = request.headers().get(&REQUEST_ID_HEADER);
+    let request_id = match sent
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Uuid::parse_str(value).ok())
+    {
+        Some(request_id) => request_id,
+        None => {
+            let request_id = Uuid::now_v7();
+            if let Some(sent) = sent {
+                tracing::debug!(?sent, %request_id, "Replacing a request id that is not a UUID");
+            }
+            request_id
+        }
+    };
+    tracing::Span::current().record("request_id", tracing::field::display(request_id));
+
+    let header =
+        HeaderValue::from_str(&request_id.to_string()).expect("A UUID is a valid header value");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header.clone());
+    request.extensions_mut().insert(RequestId(request_id));
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Extensions, HeaderMap, Request},
+        middleware::from_fn,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::{request_id_middleware, RequestId, REQUEST_ID_HEADER};
+
+    async fn send(request_id: Option<&str>) -> (Uuid, Uuid) {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|extensions: Extensions, headers: HeaderMap| async move {
+                    let RequestId(request_id) = *extensions.get::<RequestId>().unwrap();
+                    // forwarded to graph-node with the headers of the request
+                    assert_eq!(headers[REQUEST_ID_HEADER], request_id.to_string());
+                    request_id.to_string()
+                }),
+            )
+            .layer(from_fn(request_id_middleware));
+        let mut request = Request::builder().uri("/");
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let header = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        let header = Uuid::parse_str(header).unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let extension = Uuid::parse_str(std::str::from_utf8(&body).unwrap()).unwrap();
+        (header, extension)
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        // the id of the gateway is kept
+        let sent = Uuid::now_v7();
+        assert_eq!(send(Some(&sent.to_string())).await, (sent, sent));
+
+        // or generated
+        let (header, extension) = send(None).await;
+        assert_eq!(header, extension);
+        let (header, extension) = send(Some("not a uuid")).await;
+        assert_eq!(header, extension);
+        assert_ne!(header.to_string(), "not a uuid");
+    }
+}
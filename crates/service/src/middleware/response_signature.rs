@@ -0,0 +1,144 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::to_bytes,
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use indexer_attestation::ResponseSigner;
+use thegraph_core::alloy::hex;
+
+use super::auth::QUERY_ID_HEADER;
+use crate::{error::IndexerServiceError, tap::TapReceipt};
+
+/// EIP-712 signature of the operator over the request, the response and the
+/// timestamp
+pub const SIGNATURE_HEADER: &str = "graph-indexer-signature";
+/// Milliseconds since the unix epoch at which the response was signed
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "graph-indexer-signature-timestamp";
+
+/// Signs the successful responses of free queries with the operator key
+///
+/// The queries paid with a receipt, or with a receipt sent later, are
+/// attested instead. The bodies are signed uncompressed.
+///
+/// Requires the Receipt extension, if any, to be set
+pub async fn response_signature_middleware(
+    State(signer): State<ResponseSigner>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let paid = request.extensions().get::<TapReceipt>().is_some()
+        || request.headers().contains_key(QUERY_ID_HEADER);
+    if paid {
+        return Ok(next.run(request).await);
+    }
+
+    let (parts, body) = request.into_parts();
+    let request_body = to_bytes(body, usize::MAX).await?;
+    let response = next
+        .run(Request::from_parts(parts, request_body.clone().into()))
+        .await;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let response_body = to_bytes(body, usize::MAX).await?;
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    match signer.sign(&request_body, &response_body, timestamp_ms) {
+        Ok(signature) => {
+            let signature = hex::encode_prefixed(signature.as_bytes());
+            parts.headers.insert(
+                SIGNATURE_HEADER,
+                HeaderValue::from_str(&signature).expect("hex is a valid header value"),
+            );
+            parts
+                .headers
+                .insert(SIGNATURE_TIMESTAMP_HEADER, HeaderValue::from(timestamp_ms));
+        }
+        Err(error) => tracing::warn!(%error, "Failed to sign the response of a free query"),
+    }
+    Ok(Response::from_parts(parts, response_body.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+        middleware::from_fn_with_state,
+        routing::post,
+        Router,
+    };
+    use indexer_attestation::{recover_response_signer, ResponseSigner};
+    use reqwest::StatusCode;
+    use thegraph_core::alloy::{
+        primitives::PrimitiveSignature as Signature, signers::local::PrivateKeySigner,
+    };
+    use tower::ServiceExt;
+
+    use super::{response_signature_middleware, SIGNATURE_HEADER, SIGNATURE_TIMESTAMP_HEADER};
+    use crate::middleware::auth::QUERY_ID_HEADER;
+
+    #[tokio::test]
+    async fn test_free_query_response_is_signed() {
+        let signer = ResponseSigner::new(PrivateKeySigner::random(), 1337);
+        let app = Router::new()
+            .route("/status", post(|| async { r#"{"data":{}}"# }))
+            .layer(from_fn_with_state(
+                signer.clone(),
+                response_signature_middleware,
+            ));
+        let query = r#"{"query":"{ indexingStatuses { subgraph } }"}"#;
+
+        let res = app
+            .clone()
+            .oneshot(Request::post("/status").body(Body::from(query)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let signature: Signature = res.headers()[SIGNATURE_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let timestamp_ms: u64 = res.headers()[SIGNATURE_TIMESTAMP_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            recover_response_signer(
+                signer.domain(),
+                &signature,
+                query.as_bytes(),
+                &body,
+                timestamp_ms
+            )
+            .unwrap(),
+            signer.address()
+        );
+
+        // paid later, attested instead
+        let res = app
+            .oneshot(
+                Request::post("/status")
+                    .header(QUERY_ID_HEADER, "query-1")
+                    .body(Body::from(query))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(res.headers().get(SIGNATURE_HEADER).is_none());
+    }
+}
@@ -6,11 +6,18 @@ use axum::{
     middleware::Next,
     response::Response,
 };
-use indexer_monitor::EscrowAccounts;
-use thegraph_core::alloy::{primitives::Address, sol_types::Eip712Domain};
+use indexer_monitor::{ContractSigners, EscrowAccounts};
+use thegraph_core::alloy::{
+    primitives::{Address, Bytes},
+    sol_types::Eip712Domain,
+};
 use tokio::sync::watch;
 
-use crate::{error::IndexerServiceError, tap::TapReceipt};
+use crate::{
+    error::IndexerServiceError,
+    middleware::tap_receipt::request_receipts,
+    tap::{SignerCache, TapReceipt},
+};
 
 /// Stated used by sender middleware
 #[derive(Clone)]
@@ -21,6 +28,12 @@ pub struct SenderState {
     pub escrow_accounts_v1: watch::Receiver<EscrowAccounts>,
     /// Used to get the sender address given the signer address if v2 receipt
     pub escrow_accounts_v2: watch::Receiver<EscrowAccounts>,
+    /// Used to find the contract wallet a receipt was signed for, if enabled
+    pub contract_signers: Option<ContractSigners>,
+    /// Senders of the recent signers of v1 receipts
+    pub signers_v1: SignerCache,
+    /// Senders of the recent signers of v2 receipts
+    pub signers_v2: SignerCache,
 }
 
 /// The current query Sender address
@@ -33,38 +46,100 @@ impl From<Sender> for String {
     }
 }
 
-/// Injects the sender found from the signer in the receipt
+/// Injects the sender found from the signer in the receipts
 ///
 /// A request won't always have a receipt because they might be
 /// free queries.
 /// That's why we don't fail with 400.
 ///
+/// The signer of every receipt of a request paid with several receipts is
+/// recovered, they must all belong to the same sender since the checks only
+/// see that one.
+///
 /// Requires Receipt extension
 pub async fn sender_middleware(
     State(state): State<SenderState>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, IndexerServiceError> {
-    if let Some(receipt) = request.extensions().get::<TapReceipt>() {
-        let signer = receipt.recover_signer(&state.domain_separator)?;
-        let sender = match receipt {
-            TapReceipt::V1(_) => state
-                .escrow_accounts_v1
-                .borrow()
-                .get_sender_for_signer(&signer)?,
-            TapReceipt::V2(_) => state
-                .escrow_accounts_v2
-                .borrow()
-                .get_sender_for_signer(&signer)?,
-        };
+    let mut sender = None;
+    for receipt in request_receipts(request.extensions()) {
+        let receipt_sender = recover_sender(&state, &receipt).await?;
+        match sender {
+            None => sender = Some(receipt_sender),
+            Some(expected) if expected != receipt_sender => {
+                return Err(IndexerServiceError::MixedReceiptSenders {
+                    expected,
+                    found: receipt_sender,
+                });
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(sender) = sender {
         request.extensions_mut().insert(Sender(sender));
     }
 
     Ok(next.run(request).await)
 }
 
+/// Sender of the receipt signer, or of the contract wallet the receipt
+/// was signed for if the signer is unknown
+///
+/// The senders found are cached by signer, see [SignerCache].
+pub async fn recover_sender(
+    state: &SenderState,
+    receipt: &TapReceipt,
+) -> Result<Address, IndexerServiceError> {
+    let (escrow_accounts, signers) = match receipt {
+        TapReceipt::V1(_) => (&state.escrow_accounts_v1, &state.signers_v1),
+        TapReceipt::V2(_) => (&state.escrow_accounts_v2, &state.signers_v2),
+    };
+    let signer = receipt.recover_signer(&state.domain_separator)?;
+    let miss = match signers.get(signer) {
+        Ok(sender) => return Ok(sender),
+        Err(miss) => miss,
+    };
+    let sender = lookup_sender(state, escrow_accounts, receipt, signer).await?;
+    signers.insert(miss, sender);
+    Ok(sender)
+}
+
+async fn lookup_sender(
+    state: &SenderState,
+    escrow_accounts: &watch::Receiver<EscrowAccounts>,
+    receipt: &TapReceipt,
+    signer: Address,
+) -> Result<Address, IndexerServiceError> {
+    let error = match escrow_accounts.borrow().get_sender_for_signer(&signer) {
+        Ok(sender) => return Ok(sender),
+        Err(error) => error,
+    };
+    let Some(contract_signers) = &state.contract_signers else {
+        return Err(error.into());
+    };
+    let signers = escrow_accounts.borrow().get_signers();
+    let wallet = contract_signers
+        .find_wallet(
+            &signers,
+            receipt.signing_hash(&state.domain_separator),
+            Bytes::copy_from_slice(&receipt.signature().as_bytes()),
+            signer,
+        )
+        .await;
+    match wallet {
+        Ok(wallet) => Ok(escrow_accounts.borrow().get_sender_for_signer(&wallet)?),
+        Err(wallet_error) => {
+            tracing::debug!(%signer, error = %wallet_error, "Receipt signer is not a wallet key");
+            Err(error.into())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{collections::HashMap, time::Duration};
+
     use axum::{
         body::Body,
         http::{Extensions, Request},
@@ -74,58 +149,136 @@ mod tests {
     };
     use indexer_monitor::EscrowAccounts;
     use reqwest::StatusCode;
+    use tap_core::signed_message::Eip712SignedMessage;
+    use tap_graph::Receipt;
     use test_assets::{
         create_signed_receipt, SignedReceiptRequest, ESCROW_ACCOUNTS_BALANCES,
-        ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS,
+        ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, TAP_EIP712_DOMAIN, TAP_SENDER,
+    };
+    use thegraph_core::alloy::{
+        primitives::{address, Address, U256},
+        signers::local::PrivateKeySigner,
     };
     use tokio::sync::watch;
     use tower::ServiceExt;
+    use uuid::Uuid;
 
     use super::{sender_middleware, Sender};
-    use crate::{middleware::sender::SenderState, tap::TapReceipt};
+    use crate::{
+        middleware::{sender::SenderState, RequestReceipts},
+        tap::{SignerCache, TapReceipt},
+    };
 
-    #[tokio::test]
-    async fn test_sender_middleware() {
-        let escrow_accounts_v1 = watch::channel(EscrowAccounts::new(
-            ESCROW_ACCOUNTS_BALANCES.to_owned(),
-            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
-        ))
-        .1;
-
-        let escrow_accounts_v2 = watch::channel(EscrowAccounts::new(
-            ESCROW_ACCOUNTS_BALANCES.to_owned(),
-            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
-        ))
-        .1;
-
-        let state = SenderState {
-            domain_separator: test_assets::TAP_EIP712_DOMAIN.clone(),
+    const OTHER_SENDER: Address = address!("22d491bde2303f2f43325b2108d26f1eaba1e32b");
+
+    /// Sender state with the test escrow accounts, where `other_signer`
+    /// signs for [OTHER_SENDER]
+    fn sender_state(other_signer: Address) -> SenderState {
+        let mut senders_to_signers = ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned();
+        senders_to_signers.insert(OTHER_SENDER, vec![other_signer]);
+        let mut balances: HashMap<Address, U256> = ESCROW_ACCOUNTS_BALANCES.to_owned();
+        balances.insert(OTHER_SENDER, U256::from(42));
+        let escrow_accounts = || {
+            watch::channel(EscrowAccounts::new(
+                balances.clone(),
+                senders_to_signers.clone(),
+            ))
+            .1
+        };
+
+        let (escrow_accounts_v1, escrow_accounts_v2) = (escrow_accounts(), escrow_accounts());
+        SenderState {
+            domain_separator: TAP_EIP712_DOMAIN.clone(),
+            signers_v1: SignerCache::new(escrow_accounts_v1.clone(), Duration::from_secs(60)),
+            signers_v2: SignerCache::new(escrow_accounts_v2.clone(), Duration::from_secs(60)),
             escrow_accounts_v1,
             escrow_accounts_v2,
-        };
+            contract_signers: None,
+        }
+    }
 
-        let middleware = from_fn_with_state(state, sender_middleware);
+    fn signed_receipt(wallet: &PrivateKeySigner, nonce: u64) -> TapReceipt {
+        TapReceipt::V1(
+            Eip712SignedMessage::new(
+                &TAP_EIP712_DOMAIN,
+                Receipt {
+                    allocation_id: Address::ZERO,
+                    nonce,
+                    timestamp_ns: 1,
+                    value: 1,
+                },
+                wallet,
+            )
+            .unwrap(),
+        )
+    }
 
-        async fn handle(extensions: Extensions) -> Body {
-            let sender = extensions.get::<Sender>().expect("Should contain sender");
-            assert_eq!(sender.0, test_assets::TAP_SENDER.1);
-            Body::empty()
-        }
+    async fn handle(extensions: Extensions) -> Body {
+        let sender = extensions.get::<Sender>().expect("Should contain sender");
+        assert_eq!(sender.0, TAP_SENDER.1);
+        Body::empty()
+    }
 
-        let app = Router::new().route("/", get(handle)).layer(middleware);
+    async fn send(state: SenderState, receipts: Vec<TapReceipt>) -> StatusCode {
+        let app = Router::new()
+            .route("/", get(handle))
+            .layer(from_fn_with_state(state, sender_middleware));
+        let mut request = Request::builder().uri("/").extension(receipts[0].clone());
+        if receipts.len() > 1 {
+            request = request.extension(RequestReceipts {
+                request_id: Uuid::now_v7(),
+                receipts,
+            });
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
 
+    #[tokio::test]
+    async fn test_sender_middleware() {
         let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
+        let status = send(sender_state(Address::ZERO), vec![TapReceipt::V1(receipt)]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
 
-        let res = app
-            .oneshot(
-                Request::builder()
-                    .uri("/")
-                    .extension(TapReceipt::V1(receipt))
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
+    #[tokio::test]
+    async fn test_every_receipt_signer_is_checked() {
+        let tap_signer = &test_assets::TAP_SIGNER.0;
+        let other_signer = PrivateKeySigner::random();
+        let unknown_signer = PrivateKeySigner::random();
+        let state = sender_state(other_signer.address());
+
+        // several receipts of the same sender pay the request together
+        let status = send(
+            state.clone(),
+            vec![signed_receipt(tap_signer, 1), signed_receipt(tap_signer, 2)],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // the receipts after the first one can't be signed by an unknown key
+        let status = send(
+            state.clone(),
+            vec![
+                signed_receipt(tap_signer, 1),
+                signed_receipt(&unknown_signer, 2),
+            ],
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+
+        // nor by the key of another sender, whose balance and deny status
+        // are not checked
+        let status = send(
+            state,
+            vec![
+                signed_receipt(tap_signer, 1),
+                signed_receipt(&other_signer, 2),
+            ],
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
     }
 }
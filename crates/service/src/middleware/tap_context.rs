@@ -9,7 +9,7 @@ use std::sync::Arc;
 
 use axum::{
     body::to_bytes,
-    extract::{Path, Request},
+    extract::{Path, Request, State},
     middleware::Next,
     response::Response,
     RequestExt,
@@ -18,8 +18,11 @@ use serde_json::value::RawValue;
 use tap_core::receipt::Context;
 use thegraph_core::DeploymentId;
 
-use super::sender::Sender;
-use crate::{error::IndexerServiceError, tap::AgoraQuery};
+use super::{sender::Sender, tap_receipt::RequestReceipts};
+use crate::{
+    error::IndexerServiceError,
+    tap::{AgoraQuery, RequestValue, RoutePrice},
+};
 
 /// Graphql query body to be decoded and passed to agora context
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -41,6 +44,10 @@ pub async fn context_middleware(
         },
     };
     let sender = request.extensions().get::<Sender>().cloned();
+    let request_value = request
+        .extensions()
+        .get::<RequestReceipts>()
+        .map(|receipts| RequestValue(receipts.total_value()));
 
     let (mut parts, body) = request.into_parts();
     let bytes = to_bytes(body, usize::MAX).await?;
@@ -62,11 +69,33 @@ pub async fn context_middleware(
     if let Some(sender) = sender {
         ctx.insert(sender);
     }
+    if let Some(request_value) = request_value {
+        ctx.insert(request_value);
+    }
     parts.extensions.insert(Arc::new(ctx));
     let request = Request::from_parts(parts, bytes.into());
     Ok(next.run(request).await)
 }
 
+/// Injects the tap context of the queries of a route paid at a fixed price,
+/// like `/status`, instead of the one of the cost models
+pub async fn route_context_middleware(
+    State(price): State<RoutePrice>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let mut ctx = Context::new();
+    ctx.insert(price);
+    if let Some(sender) = request.extensions().get::<Sender>().cloned() {
+        ctx.insert(sender);
+    }
+    if let Some(receipts) = request.extensions().get::<RequestReceipts>() {
+        ctx.insert(RequestValue(receipts.total_value()));
+    }
+    request.extensions_mut().insert(Arc::new(ctx));
+    next.run(request).await
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
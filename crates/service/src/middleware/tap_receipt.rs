@@ -1,10 +1,56 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::{extract::Request, middleware::Next, response::Response, RequestExt};
+use axum::{
+    extract::{Request, State},
+    http::Extensions,
+    middleware::Next,
+    response::Response,
+    RequestExt,
+};
 use axum_extra::TypedHeader;
+use tap_core::receipt::WithValueAndTimestamp;
+use uuid::Uuid;
 
-use crate::service::TapHeader;
+use crate::{
+    error::IndexerServiceError, middleware::RequestId, service::TapHeader, tap::TapReceipt,
+};
+
+/// Receipts of a request paid with more than one receipt
+///
+/// The first of them is also inserted as the `TapReceipt` extension, for the
+/// middlewares that only need the sender and the allocation. They are
+/// checked against the value of the query together and stored with
+/// `request_id`.
+#[derive(Debug, Clone)]
+pub struct RequestReceipts {
+    pub request_id: Uuid,
+    pub receipts: Vec<TapReceipt>,
+}
+
+impl RequestReceipts {
+    pub fn total_value(&self) -> u128 {
+        self.receipts.iter().map(TapReceipt::value).sum()
+    }
+}
+
+/// Whether requests can be paid with more than one receipt, only if the
+/// receipt store keeps their request ids, see
+/// [indexer_receipt::store::ReceiptStore::stores_request_ids]
+#[derive(Debug, Clone, Copy)]
+pub struct MultipleReceipts(pub bool);
+
+/// All the receipts of a request, empty for free queries
+pub fn request_receipts(extensions: &Extensions) -> Vec<TapReceipt> {
+    match extensions.get::<RequestReceipts>() {
+        Some(request_receipts) => request_receipts.receipts.clone(),
+        None => extensions
+            .get::<TapReceipt>()
+            .cloned()
+            .into_iter()
+            .collect(),
+    }
+}
 
 /// Injects tap receipts in the extensions
 ///
@@ -13,13 +59,33 @@ use crate::service::TapHeader;
 /// That's why we don't fail with 400.
 ///
 /// This is useful to not deserialize multiple times the same receipt
-pub async fn receipt_middleware(mut request: Request, next: Next) -> Response {
-    if let Ok(TypedHeader(TapHeader(receipt))) =
+///
+/// The receipts of a request are stored with its RequestId extension, if any
+pub async fn receipt_middleware(
+    State(MultipleReceipts(multiple_receipts)): State<MultipleReceipts>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    if let Ok(TypedHeader(TapHeader(receipts))) =
         request.extract_parts::<TypedHeader<TapHeader>>().await
     {
-        request.extensions_mut().insert(receipt);
+        // the header is only decoded with at least one receipt
+        request.extensions_mut().insert(receipts[0].clone());
+        if receipts.len() > 1 {
+            if !multiple_receipts {
+                return Err(IndexerServiceError::MultipleReceiptsUnsupported);
+            }
+            let request_id = match request.extensions().get::<RequestId>() {
+                Some(RequestId(request_id)) => *request_id,
+                None => Uuid::now_v7(),
+            };
+            request.extensions_mut().insert(RequestReceipts {
+                request_id,
+                receipts,
+            });
+        }
     }
-    next.run(request).await
+    Ok(next.run(request).await)
 }
 
 #[cfg(test)]
@@ -27,7 +93,7 @@ mod tests {
     use axum::{
         body::Body,
         http::{Extensions, Request},
-        middleware::from_fn,
+        middleware::from_fn_with_state,
         routing::get,
         Router,
     };
@@ -36,11 +102,15 @@ mod tests {
     use test_assets::{create_signed_receipt, SignedReceiptRequest};
     use tower::ServiceExt;
 
-    use crate::{middleware::tap_receipt::receipt_middleware, service::TapHeader, tap::TapReceipt};
+    use crate::{
+        middleware::tap_receipt::{receipt_middleware, MultipleReceipts, RequestReceipts},
+        service::TapHeader,
+        tap::TapReceipt,
+    };
 
     #[tokio::test]
     async fn test_receipt_middleware() {
-        let middleware = from_fn(receipt_middleware);
+        let middleware = from_fn_with_state(MultipleReceipts(true), receipt_middleware);
 
         let receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
         let receipt_json = serde_json::to_string(&receipt).unwrap();
@@ -69,4 +139,68 @@ mod tests {
             .unwrap();
         assert_eq!(res.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_receipt_middleware_multiple_receipts() {
+        let middleware = from_fn_with_state(MultipleReceipts(true), receipt_middleware);
+
+        let receipts = vec![
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).value(1).build()).await,
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(2).value(2).build()).await,
+        ];
+        let receipts_json = serde_json::to_string(&receipts).unwrap();
+        let receipts: Vec<_> = receipts.into_iter().map(TapReceipt::V1).collect();
+
+        let handle = move |extensions: Extensions| async move {
+            let request_receipts = extensions
+                .get::<RequestReceipts>()
+                .expect("Should decode all the receipts");
+            assert_eq!(request_receipts.receipts, receipts);
+            assert_eq!(request_receipts.total_value(), 3);
+            // the sender and allocation are found with the first receipt
+            assert_eq!(extensions.get::<TapReceipt>(), receipts.first());
+            Body::empty()
+        };
+
+        let app = Router::new().route("/", get(handle)).layer(middleware);
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(TapHeader::name(), receipts_json)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_receipt_middleware_refuses_multiple_receipts() {
+        let middleware = from_fn_with_state(MultipleReceipts(false), receipt_middleware);
+
+        let receipts = vec![
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).value(1).build()).await,
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(2).value(2).build()).await,
+        ];
+        let receipts_json = serde_json::to_string(&receipts).unwrap();
+
+        let app = Router::new()
+            .route("/", get(|| async { Body::empty() }))
+            .layer(middleware);
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(TapHeader::name(), receipts_json)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
 }
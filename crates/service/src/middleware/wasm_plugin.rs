@@ -0,0 +1,569 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runs a WebAssembly plugin of the operator on the queries and their
+//! responses
+//!
+//! The plugin is a module without imports, exporting its `memory`, an
+//! `alloc(len: i32) -> i32` function returning `len` bytes of its memory, and
+//! at least one of the hooks
+//!
+//! - `on_request(ptr: i32, len: i32) -> i64`, called with the JSON encoded
+//!   `{"deployment", "headers", "body"}` of the query
+//! - `on_response(ptr: i32, len: i32) -> i64`, called with the JSON encoded
+//!   `{"deployment", "status", "headers", "body", "attested"}` of the
+//!   response
+//! - `check_receipt(ptr: i32, len: i32) -> i64`, called with the JSON encoded
+//!   `{"version", "allocation_id", "sender", "deployment", "value",
+//!   "timestamp_ns", "nonce"}` of a receipt paying for a query
+//!
+//! A hook returns `0` to leave the query or the response unchanged, or the
+//! pointer and the length of a JSON encoded `{"headers", "body"}`, packed as
+//! `ptr << 32 | len`, with the headers to set and the body replacing the
+//! original one, both optional. The body of a query can't be replaced, the
+//! attestation would sign a query the consumer didn't send, nor the body of
+//! an attested response, the attestation signs the original one.
+//! `check_receipt` returns `0` to accept the receipt, or the packed pointer
+//! and length of the reason it is refused.
+//!
+//! Every call runs in its own instance of the module, interrupted after the
+//! configured timeout and limited to the configured memory. A response larger
+//! than the memory of the plugin is served without calling `on_response`.
+
+use std::{collections::HashMap, sync::Arc, thread, time::Duration};
+
+use anyhow::{anyhow, Context as _};
+use axum::{
+    body::{to_bytes, Body, HttpBody},
+    extract::{Request, State},
+    http::{header::CONTENT_LENGTH, HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use indexer_config::WasmPluginConfig;
+use serde::{Deserialize, Serialize};
+use thegraph_core::DeploymentId;
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use super::{AttestationInput, RefundReceipt};
+use crate::{error::IndexerServiceError, metrics::WASM_PLUGIN_FAILURES};
+
+/// Period of the epochs interrupting the plugin, the precision of the timeout
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy)]
+enum Hook {
+    OnRequest,
+    OnResponse,
+    CheckReceipt,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::OnRequest => "on_request",
+            Hook::OnResponse => "on_response",
+            Hook::CheckReceipt => "check_receipt",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RequestInput<'a> {
+    deployment: Option<String>,
+    headers: HashMap<&'a str, &'a str>,
+    body: &'a str,
+}
+
+#[derive(Serialize)]
+struct ResponseInput<'a> {
+    deployment: Option<String>,
+    status: u16,
+    headers: HashMap<&'a str, &'a str>,
+    body: &'a str,
+    attested: bool,
+}
+
+#[derive(Deserialize)]
+struct PluginOutput {
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+/// Changes returned by a hook, validated
+struct Changes {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Option<String>,
+}
+
+impl Changes {
+    fn parse(output: &[u8]) -> anyhow::Result<Self> {
+        let output: PluginOutput = serde_json::from_slice(output)?;
+        let headers = output
+            .headers
+            .into_iter()
+            .map(|(name, value)| {
+                Ok((
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(&value)?,
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            headers,
+            body: output.body,
+        })
+    }
+
+    fn apply_headers(&mut self, headers: &mut HeaderMap) {
+        for (name, value) in self.headers.drain(..) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+struct PluginState {
+    limits: StoreLimits,
+}
+
+struct Inner {
+    engine: Engine,
+    instance_pre: InstancePre<PluginState>,
+    on_request: bool,
+    on_response: bool,
+    check_receipt: bool,
+    /// epochs before a call is interrupted
+    deadline: u64,
+    max_memory_bytes: usize,
+    /// largest query read to be passed to the plugin
+    body_limit: usize,
+    reject_on_error: bool,
+}
+
+/// Compiled plugin, instantiated for every call
+#[derive(Clone)]
+pub struct WasmPlugin {
+    inner: Arc<Inner>,
+}
+
+impl WasmPlugin {
+    /// Compiles the plugin at the configured path, the queries passed to it
+    /// are at most `body_limit` bytes
+    pub fn from_config(config: &WasmPluginConfig, body_limit: usize) -> anyhow::Result<Self> {
+        let module = std::fs::read(&config.path).with_context(|| {
+            format!(
+                "Failed to read the WebAssembly plugin {}",
+                config.path.display()
+            )
+        })?;
+        Self::new(&module, config, body_limit)
+    }
+
+    /// Compiles `module`, in the binary or the text format
+    pub fn new(
+        module: &[u8],
+        config: &WasmPluginConfig,
+        body_limit: usize,
+    ) -> anyhow::Result<Self> {
+        let mut engine_config = Config::new();
+        engine_config.epoch_interruption(true);
+        let engine = Engine::new(&engine_config)?;
+        let module = Module::new(&engine, module)?;
+
+        let exports = |name| module.get_export(name).is_some();
+        if !exports("memory") || !exports("alloc") {
+            return Err(anyhow!(
+                "The WebAssembly plugin must export `memory` and `alloc`"
+            ));
+        }
+        let (on_request, on_response, check_receipt) = (
+            exports("on_request"),
+            exports("on_response"),
+            exports("check_receipt"),
+        );
+        if !on_request && !on_response && !check_receipt {
+            return Err(anyhow!(
+                "The WebAssembly plugin must export `on_request`, `on_response` or \
+                `check_receipt`"
+            ));
+        }
+        // no host function is exposed to the plugin
+        let instance_pre = Linker::new(&engine).instantiate_pre(&module)?;
+
+        // stops once the plugin is dropped
+        let ticker = engine.weak();
+        thread::spawn(move || {
+            while let Some(engine) = ticker.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                thread::sleep(EPOCH_TICK);
+            }
+        });
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                engine,
+                instance_pre,
+                on_request,
+                on_response,
+                check_receipt,
+                deadline: config
+                    .timeout_secs
+                    .as_micros()
+                    .div_ceil(EPOCH_TICK.as_micros())
+                    .max(1) as u64,
+                max_memory_bytes: config.max_memory_bytes,
+                body_limit,
+                reject_on_error: config.reject_on_error,
+            }),
+        })
+    }
+
+    /// Calls `hook` with `input` in a new instance of the module, returns
+    /// the output of the hook if it changes anything
+    fn call(&self, hook: Hook, input: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.inner.max_memory_bytes)
+            .instances(1)
+            .trap_on_grow_failure(true)
+            .build();
+        let mut store = Store::new(&self.inner.engine, PluginState { limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_epoch_deadline(self.inner.deadline);
+
+        let instance = self.inner.instance_pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("`memory` is not a memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let hook_fn = instance.get_typed_func::<(i32, i32), i64>(&mut store, hook.name())?;
+
+        let len = i32::try_from(input.len()).context("Input too large for the plugin")?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+        let output = hook_fn.call(&mut store, (ptr, len))? as u64;
+        if output == 0 {
+            return Ok(None);
+        }
+        let (ptr, len) = ((output >> 32) as usize, (output & 0xffff_ffff) as usize);
+        let mut buffer = vec![0; len];
+        memory.read(&store, ptr, &mut buffer)?;
+        Ok(Some(buffer))
+    }
+
+    /// Calls `hook` outside of the async runtime
+    async fn call_blocking(&self, hook: Hook, input: Vec<u8>) -> anyhow::Result<Option<Vec<u8>>> {
+        let plugin = self.clone();
+        tokio::task::spawn_blocking(move || plugin.call(hook, &input))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+    }
+
+    /// Runs `hook` outside of the async runtime
+    ///
+    /// Returns `None` if the hook changes nothing, or if it fails and the
+    /// query must be served as if there was no plugin.
+    async fn run(
+        &self,
+        hook: Hook,
+        input: Vec<u8>,
+    ) -> Result<Option<Changes>, IndexerServiceError> {
+        let result = self
+            .call_blocking(hook, input)
+            .await
+            .and_then(|output| output.as_deref().map(Changes::parse).transpose());
+        match result {
+            Ok(changes) => Ok(changes),
+            Err(error) => {
+                WASM_PLUGIN_FAILURES.with_label_values(&[hook.name()]).inc();
+                tracing::warn!(hook = hook.name(), %error, "WebAssembly plugin failed");
+                if self.inner.reject_on_error {
+                    Err(IndexerServiceError::PluginFailed(error))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    /// Whether the plugin exports `check_receipt`
+    pub fn checks_receipts(&self) -> bool {
+        self.inner.check_receipt
+    }
+
+    /// Runs `check_receipt` on the JSON encoded `receipt`
+    ///
+    /// The receipt is accepted if the plugin fails, unless `reject_on_error`
+    /// is set.
+    pub async fn check_receipt(&self, receipt: Vec<u8>) -> anyhow::Result<()> {
+        let hook = Hook::CheckReceipt;
+        match self.call_blocking(hook, receipt).await {
+            Ok(None) => Ok(()),
+            Ok(Some(reason)) => Err(anyhow!(
+                "Receipt refused by the WebAssembly plugin: {}",
+                String::from_utf8_lossy(&reason)
+            )),
+            Err(error) => {
+                WASM_PLUGIN_FAILURES.with_label_values(&[hook.name()]).inc();
+                tracing::warn!(hook = hook.name(), %error, "WebAssembly plugin failed");
+                if self.inner.reject_on_error {
+                    Err(error.context("WebAssembly plugin failed"))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+fn header_values(headers: &HeaderMap) -> HashMap<&str, &str> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect()
+}
+
+/// Runs the [WasmPlugin] hooks on the query before it is executed, and on
+/// its response before it is attested
+///
+/// Responses marked with [RefundReceipt] are errors of the service, they
+/// are not passed to the plugin.
+///
+/// Requires the DeploymentId extension to pass the deployment to the plugin
+pub async fn wasm_plugin_middleware(
+    State(plugin): State<WasmPlugin>,
+    request: Request,
+    next: Next,
+) -> Result<Response, IndexerServiceError> {
+    let deployment = request
+        .extensions()
+        .get::<DeploymentId>()
+        .map(ToString::to_string);
+
+    let request = if plugin.inner.on_request {
+        let (mut parts, body) = request.into_parts();
+        let bytes = to_bytes(body, plugin.inner.body_limit).await?;
+        let changes = match std::str::from_utf8(&bytes) {
+            Ok(body) => {
+                let input = serde_json::to_vec(&RequestInput {
+                    deployment: deployment.clone(),
+                    headers: header_values(&parts.headers),
+                    body,
+                })?;
+                plugin.run(Hook::OnRequest, input).await?
+            }
+            // graph-node refuses the query anyway
+            Err(_) => None,
+        };
+        if let Some(mut changes) = changes {
+            changes.apply_headers(&mut parts.headers);
+            if changes.body.is_some() {
+                tracing::warn!("The WebAssembly plugin can't replace the body of a query");
+            }
+        }
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    if !plugin.inner.on_response || response.extensions().get::<RefundReceipt>().is_some() {
+        return Ok(response);
+    }
+
+    // the response must fit in the memory of the plugin
+    let max_len = plugin.inner.max_memory_bytes;
+    let too_large = response
+        .body()
+        .size_hint()
+        .upper()
+        .map_or(true, |len| len > max_len as u64);
+    if too_large {
+        tracing::debug!("Response too large for the WebAssembly plugin");
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = to_bytes(body, max_len).await?;
+    let Ok(body) = std::str::from_utf8(&bytes) else {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    };
+    let attested = matches!(
+        parts.extensions.get::<AttestationInput>(),
+        Some(AttestationInput::Attestable { .. })
+    );
+    let input = serde_json::to_vec(&ResponseInput {
+        deployment,
+        status: parts.status.as_u16(),
+        headers: header_values(&parts.headers),
+        body,
+        attested,
+    })?;
+
+    let body = match plugin.run(Hook::OnResponse, input).await? {
+        Some(mut changes) => {
+            changes.apply_headers(&mut parts.headers);
+            match changes.body {
+                Some(_) if attested => {
+                    tracing::warn!(
+                        "The WebAssembly plugin can't replace the body of an attested response"
+                    );
+                    Body::from(bytes)
+                }
+                Some(body) => {
+                    parts.headers.remove(CONTENT_LENGTH);
+                    Body::from(body)
+                }
+                None => Body::from(bytes),
+            }
+        }
+        None => Body::from(bytes),
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{HeaderMap, Request},
+        middleware::from_fn_with_state,
+        response::Response,
+        routing::post,
+        Router,
+    };
+    use indexer_config::WasmPluginConfig;
+    use reqwest::StatusCode;
+    use tower::ServiceExt;
+
+    use super::{wasm_plugin_middleware, WasmPlugin};
+    use crate::middleware::AttestationInput;
+
+    /// Sets `x-billing-tag` on the queries, and `x-plugin` and the body
+    /// `redacted` on the responses
+    const PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (data (i32.const 0) "{\"headers\":{\"x-billing-tag\":\"plugin\"}}")
+          (data (i32.const 512) "{\"headers\":{\"x-plugin\":\"1\"},\"body\":\"redacted\"}")
+          (func (export "on_request") (param i32 i32) (result i64)
+            (i64.const 38))
+          (func (export "on_response") (param i32 i32) (result i64)
+            (i64.const 2199023255598)))
+    "#;
+
+    const LOOPING_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "on_request") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))
+    "#;
+
+    /// Replaces the body of the queries with `rewritten`
+    const REWRITING_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (local.get $ptr))
+          (data (i32.const 0) "{\"body\":\"rewritten\"}")
+          (func (export "on_request") (param i32 i32) (result i64)
+            (i64.const 20)))
+    "#;
+
+    fn config(reject_on_error: bool) -> WasmPluginConfig {
+        WasmPluginConfig {
+            path: PathBuf::new(),
+            timeout_secs: Duration::from_millis(50),
+            max_memory_bytes: 1 << 20,
+            reject_on_error,
+        }
+    }
+
+    fn router(plugin: &str, reject_on_error: bool, attested: bool) -> Router {
+        let plugin = WasmPlugin::new(plugin.as_bytes(), &config(reject_on_error), 1 << 20).unwrap();
+        let handler = move |headers: HeaderMap| async move {
+            let tag = headers
+                .get("x-billing-tag")
+                .map(|tag| tag.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            let mut res = Response::new(Body::from(format!("tag: {tag}")));
+            if attested {
+                res.extensions_mut()
+                    .insert(AttestationInput::Attestable { req: String::new() });
+            }
+            res
+        };
+        Router::new()
+            .route("/", post(handler))
+            .layer(from_fn_with_state(plugin, wasm_plugin_middleware))
+    }
+
+    async fn send(router: Router) -> (StatusCode, HeaderMap, String) {
+        let res = router
+            .oneshot(Request::post("/").body(Body::from("{}")).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_plugin_changes() {
+        let (status, headers, body) = send(router(PLUGIN, false, false)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("x-plugin").unwrap(), "1");
+        assert_eq!(body, "redacted");
+
+        // the attested body is kept, the headers are still set
+        let (_, headers, body) = send(router(PLUGIN, false, true)).await;
+        assert_eq!(headers.get("x-plugin").unwrap(), "1");
+        assert_eq!(body, "tag: plugin");
+    }
+
+    #[tokio::test]
+    async fn test_plugin_timeout() {
+        assert!(WasmPlugin::new(b"(module)", &config(false), 1 << 20).is_err());
+
+        let (status, _, body) = send(router(LOOPING_PLUGIN, false, false)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "tag: ");
+
+        let (status, _, _) = send(router(LOOPING_PLUGIN, true, false)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_query_body_is_not_replaced() {
+        let plugin = WasmPlugin::new(REWRITING_PLUGIN.as_bytes(), &config(false), 1 << 20).unwrap();
+        let router = Router::new()
+            .route("/", post(|body: String| async move { body }))
+            .layer(from_fn_with_state(plugin, wasm_plugin_middleware));
+
+        // the attestation must sign the query sent by the consumer
+        let (status, _, body) = send(router).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "{}");
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use indexer_schema::{allocation_events, AllocationEvent, RecordedAllocationEvent};
+use reqwest::StatusCode;
+use serde::Serialize;
+use sqlx::PgPool;
+use thegraph_core::alloy::primitives::Address;
+
+use crate::error::{error_response, RouteError, StatusCodeExt};
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to load the allocation events: {0:#}")]
+pub struct AllocationEventsError(anyhow::Error);
+
+impl StatusCodeExt for AllocationEventsError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl ErrorCode for AllocationEventsError {
+    fn error_code(&self) -> IndexerErrorCode {
+        self.0.error_code()
+    }
+}
+
+impl RouteError for AllocationEventsError {}
+
+impl IntoResponse for AllocationEventsError {
+    fn into_response(self) -> Response {
+        tracing::error!(error = %self.0, "Failed to load the allocation events");
+        error_response(&self)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationEventEntry {
+    event: AllocationEvent,
+    sender: Option<Address>,
+    recorded_at: String,
+}
+
+impl From<RecordedAllocationEvent> for AllocationEventEntry {
+    fn from(
+        RecordedAllocationEvent {
+            event,
+            sender,
+            recorded_at,
+        }: RecordedAllocationEvent,
+    ) -> Self {
+        Self {
+            event,
+            sender,
+            recorded_at: recorded_at.to_rfc3339(),
+        }
+    }
+}
+
+/// Management route to read the timeline of an allocation, oldest event
+/// first
+pub fn allocation_events_router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/:allocation_id", get(list_events))
+        .with_state(pgpool)
+}
+
+async fn list_events(
+    State(pgpool): State<PgPool>,
+    Path(allocation_id): Path<Address>,
+) -> Result<Json<Vec<AllocationEventEntry>>, AllocationEventsError> {
+    let events = allocation_events(&pgpool, allocation_id)
+        .await
+        .map_err(AllocationEventsError)?;
+    Ok(Json(events.into_iter().map(Into::into).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use indexer_schema::{record_allocation_event, AllocationEvent};
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use sqlx::PgPool;
+    use test_assets::{ALLOCATION_ID_0, TAP_SENDER};
+    use tower::ServiceExt;
+
+    use super::allocation_events_router;
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_list_events(pgpool: PgPool) {
+        for (event, sender) in [
+            (AllocationEvent::Created, None),
+            (AllocationEvent::Eligible, None),
+            (AllocationEvent::FinalRavRequested, Some(TAP_SENDER.1)),
+            // already recorded by another replica
+            (AllocationEvent::Created, None),
+        ] {
+            record_allocation_event(&pgpool, ALLOCATION_ID_0, event, sender, None)
+                .await
+                .unwrap();
+        }
+
+        let res = allocation_events_router(pgpool)
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/{ALLOCATION_ID_0:#x}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let events: Vec<Value> = serde_json::from_slice(&bytes).unwrap();
+        let timeline: Vec<(&str, &Value)> = events
+            .iter()
+            .map(|event| (event["event"].as_str().unwrap(), &event["sender"]))
+            .collect();
+        assert_eq!(
+            timeline,
+            vec![
+                ("created", &Value::Null),
+                ("eligible", &Value::Null),
+                (
+                    "final_rav_requested",
+                    &serde_json::to_value(TAP_SENDER.1).unwrap()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
+use thiserror::Error;
+
+use crate::error::{error_response, RouteError, StatusCodeExt};
+
+/// Overrides are meant for emergencies, not to replace the network subgraph
+const MAX_OVERRIDE_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+#[derive(Debug, Error)]
+pub enum AllocationOverrideError {
+    #[error("No override is set for allocation {0}")]
+    NotFound(Address),
+    #[error("The duration of an override must be positive and at most 30 days")]
+    InvalidTtl,
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl StatusCodeExt for AllocationOverrideError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AllocationOverrideError::NotFound(_) => StatusCode::NOT_FOUND,
+            AllocationOverrideError::InvalidTtl => StatusCode::BAD_REQUEST,
+            AllocationOverrideError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl ErrorCode for AllocationOverrideError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            AllocationOverrideError::NotFound(_) => IndexerErrorCode::NotFound,
+            AllocationOverrideError::InvalidTtl => IndexerErrorCode::InvalidRequest,
+            AllocationOverrideError::Database(error) => error.error_code(),
+        }
+    }
+}
+
+impl RouteError for AllocationOverrideError {
+    fn data(&self) -> Option<Value> {
+        match self {
+            AllocationOverrideError::NotFound(allocation) => {
+                Some(json!({ "allocation": allocation }))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for AllocationOverrideError {
+    fn into_response(self) -> Response {
+        error_response(&self)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetAllocationOverride {
+    /// Whether the receipts for the allocation are accepted
+    eligible: bool,
+    /// The override is ignored afterwards
+    ttl_secs: f64,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocationOverride {
+    allocation_id: Address,
+    eligible: bool,
+    reason: Option<String>,
+    created_at: String,
+    expires_at: String,
+}
+
+struct AllocationOverrideRow {
+    allocation_id: String,
+    eligible: bool,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl TryFrom<AllocationOverrideRow> for AllocationOverride {
+    type Error = sqlx::Error;
+
+    fn try_from(row: AllocationOverrideRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            allocation_id: row
+                .allocation_id
+                .parse()
+                .map_err(|error| sqlx::Error::Decode(Box::new(error)))?,
+            eligible: row.eligible,
+            reason: row.reason,
+            created_at: row.created_at.to_rfc3339(),
+            expires_at: row.expires_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Management routes to force allocations in or out of the allocations
+/// receipts are accepted for, until the override expires
+pub fn allocation_overrides_router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/", get(list_overrides))
+        .route("/:allocation_id", put(set_override).delete(remove_override))
+        .with_state(pgpool)
+}
+
+async fn list_overrides(
+    State(pgpool): State<PgPool>,
+) -> Result<Json<Vec<AllocationOverride>>, AllocationOverrideError> {
+    let rows = sqlx::query_as!(
+        AllocationOverrideRow,
+        r#"
+            SELECT allocation_id, eligible, reason, created_at, expires_at
+            FROM allocation_eligibility_overrides
+            WHERE expires_at > NOW()
+            ORDER BY allocation_id
+        "#
+    )
+    .fetch_all(&pgpool)
+    .await?;
+    let overrides = rows
+        .into_iter()
+        .map(AllocationOverride::try_from)
+        .collect::<Result<_, _>>()?;
+
+    Ok(Json(overrides))
+}
+
+async fn set_override(
+    State(pgpool): State<PgPool>,
+    Path(allocation_id): Path<Address>,
+    Json(SetAllocationOverride {
+        eligible,
+        ttl_secs,
+        reason,
+    }): Json<SetAllocationOverride>,
+) -> Result<Json<AllocationOverride>, AllocationOverrideError> {
+    let ttl = Duration::try_from_secs_f64(ttl_secs)
+        .ok()
+        .filter(|ttl| !ttl.is_zero() && *ttl <= MAX_OVERRIDE_TTL)
+        .ok_or(AllocationOverrideError::InvalidTtl)?;
+
+    let row = sqlx::query_as!(
+        AllocationOverrideRow,
+        r#"
+            INSERT INTO allocation_eligibility_overrides
+                (allocation_id, eligible, reason, expires_at)
+            VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))
+            ON CONFLICT (allocation_id) DO UPDATE SET
+                eligible = EXCLUDED.eligible,
+                reason = EXCLUDED.reason,
+                created_at = NOW(),
+                expires_at = EXCLUDED.expires_at
+            RETURNING allocation_id, eligible, reason, created_at, expires_at
+        "#,
+        allocation_id.encode_hex(),
+        eligible,
+        reason.as_deref(),
+        ttl.as_secs_f64()
+    )
+    .fetch_one(&pgpool)
+    .await?;
+    tracing::warn!(
+        %allocation_id,
+        eligible,
+        ttl_secs,
+        reason = reason.as_deref().unwrap_or_default(),
+        "Overriding the eligibility of an allocation"
+    );
+
+    Ok(Json(row.try_into()?))
+}
+
+async fn remove_override(
+    State(pgpool): State<PgPool>,
+    Path(allocation_id): Path<Address>,
+) -> Result<StatusCode, AllocationOverrideError> {
+    let result = sqlx::query!(
+        "DELETE FROM allocation_eligibility_overrides WHERE allocation_id = $1",
+        allocation_id.encode_hex()
+    )
+    .execute(&pgpool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AllocationOverrideError::NotFound(allocation_id));
+    }
+    tracing::info!(%allocation_id, "Removed the eligibility override of an allocation");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use reqwest::{header, Method, StatusCode};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use super::allocation_overrides_router;
+
+    const ALLOCATION: &str = "0xfa44c72b753a66591f241c7dc04e8178c30e13af";
+
+    async fn call(
+        pgpool: &PgPool,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        let res = allocation_overrides_router(pgpool.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_set_list_remove(pgpool: PgPool) {
+        let uri = format!("/{ALLOCATION}");
+        let (status, _) = call(
+            &pgpool,
+            Method::PUT,
+            &uri,
+            Some(json!({ "eligible": true, "ttlSecs": 0 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, set) = call(
+            &pgpool,
+            Method::PUT,
+            &uri,
+            Some(json!({ "eligible": false, "ttlSecs": 3600, "reason": "closed on chain" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            set["allocationId"].as_str().unwrap().to_lowercase(),
+            ALLOCATION
+        );
+        assert_eq!(set["eligible"], false);
+
+        let (status, overrides) = call(&pgpool, Method::GET, "/", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(overrides.as_array().unwrap().len(), 1);
+        assert_eq!(overrides[0]["reason"], "closed on chain");
+
+        let (status, _) = call(&pgpool, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&pgpool, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
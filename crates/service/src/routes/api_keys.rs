@@ -0,0 +1,283 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use rand::{distr::Alphanumeric, Rng};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use thegraph_core::DeploymentId;
+use thiserror::Error;
+
+use crate::{
+    error::{error_response, RouteError, StatusCodeExt},
+    middleware::auth::hash_api_key,
+};
+
+const API_KEY_LENGTH: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum ApiKeyError {
+    #[error("API key `{0}` already exists")]
+    AlreadyExists(String),
+    #[error("API key `{0}` not found")]
+    NotFound(String),
+    #[error("Daily quota cannot be negative")]
+    NegativeQuota,
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl StatusCodeExt for ApiKeyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiKeyError::AlreadyExists(_) => StatusCode::CONFLICT,
+            ApiKeyError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiKeyError::NegativeQuota => StatusCode::BAD_REQUEST,
+            ApiKeyError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl ErrorCode for ApiKeyError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            ApiKeyError::AlreadyExists(_) => IndexerErrorCode::AlreadyExists,
+            ApiKeyError::NotFound(_) => IndexerErrorCode::NotFound,
+            ApiKeyError::NegativeQuota => IndexerErrorCode::InvalidRequest,
+            ApiKeyError::Database(error) => error.error_code(),
+        }
+    }
+}
+
+impl RouteError for ApiKeyError {
+    fn data(&self) -> Option<Value> {
+        match self {
+            ApiKeyError::AlreadyExists(name) | ApiKeyError::NotFound(name) => {
+                Some(json!({ "name": name }))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for ApiKeyError {
+    fn into_response(self) -> Response {
+        error_response(&self)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateApiKey {
+    name: String,
+    /// Maximum number of queries per UTC day, unlimited if not set
+    daily_quota: Option<i64>,
+    /// Deployments the key can query, any deployment if not set
+    allowed_deployments: Option<Vec<DeploymentId>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreatedApiKey {
+    name: String,
+    /// Only returned once, the database only stores its hash
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyInfo {
+    name: String,
+    daily_quota: Option<i64>,
+    allowed_deployments: Option<Vec<String>>,
+    revoked: bool,
+    queries_today: i64,
+}
+
+/// Management routes to list, create and revoke free query API keys
+pub fn api_keys_router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/", get(list_api_keys).post(create_api_key))
+        .route("/:name", delete(revoke_api_key))
+        .with_state(pgpool)
+}
+
+async fn list_api_keys(State(pgpool): State<PgPool>) -> Result<Json<Vec<ApiKeyInfo>>, ApiKeyError> {
+    let keys = sqlx::query!(
+        r#"
+            SELECT
+                k.name,
+                k.daily_quota,
+                k.allowed_deployments,
+                k.revoked_at IS NOT NULL AS "revoked!",
+                COALESCE(u.query_count, 0) AS "queries_today!"
+            FROM free_query_api_keys k
+            LEFT JOIN free_query_api_key_usage u
+                ON u.name = k.name AND u.day = (NOW() AT TIME ZONE 'UTC')::DATE
+            ORDER BY k.name
+        "#
+    )
+    .fetch_all(&pgpool)
+    .await?
+    .into_iter()
+    .map(|row| ApiKeyInfo {
+        name: row.name,
+        daily_quota: row.daily_quota,
+        allowed_deployments: row.allowed_deployments,
+        revoked: row.revoked,
+        queries_today: row.queries_today,
+    })
+    .collect();
+
+    Ok(Json(keys))
+}
+
+async fn create_api_key(
+    State(pgpool): State<PgPool>,
+    Json(CreateApiKey {
+        name,
+        daily_quota,
+        allowed_deployments,
+    }): Json<CreateApiKey>,
+) -> Result<impl IntoResponse, ApiKeyError> {
+    if daily_quota.is_some_and(|quota| quota < 0) {
+        return Err(ApiKeyError::NegativeQuota);
+    }
+    let token: String = rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(API_KEY_LENGTH)
+        .map(char::from)
+        .collect();
+    let allowed_deployments: Option<Vec<String>> = allowed_deployments
+        .map(|deployments| deployments.iter().map(ToString::to_string).collect());
+
+    let result = sqlx::query!(
+        r#"
+            INSERT INTO free_query_api_keys (name, key_hash, daily_quota, allowed_deployments)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (name) DO NOTHING
+        "#,
+        name,
+        hash_api_key(&token),
+        daily_quota,
+        allowed_deployments.as_deref(),
+    )
+    .execute(&pgpool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiKeyError::AlreadyExists(name));
+    }
+    tracing::info!(%name, "Created free query API key");
+
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { name, token })))
+}
+
+async fn revoke_api_key(
+    State(pgpool): State<PgPool>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiKeyError> {
+    let result = sqlx::query!(
+        r#"
+            UPDATE free_query_api_keys
+            SET revoked_at = NOW()
+            WHERE name = $1 AND revoked_at IS NULL
+        "#,
+        name,
+    )
+    .execute(&pgpool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiKeyError::NotFound(name));
+    }
+    tracing::info!(%name, "Revoked free query API key");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use reqwest::{header, Method, StatusCode};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use super::api_keys_router;
+
+    async fn call(
+        pgpool: &PgPool,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        let res = api_keys_router(pgpool.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_create_list_revoke(pgpool: PgPool) {
+        let (status, created) = call(
+            &pgpool,
+            Method::POST,
+            "/",
+            Some(json!({ "name": "dashboard", "dailyQuota": 100 })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["token"].as_str().unwrap().len(), 32);
+
+        let (status, _) = call(
+            &pgpool,
+            Method::POST,
+            "/",
+            Some(json!({ "name": "dashboard" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, keys) = call(&pgpool, Method::GET, "/", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            keys,
+            json!([{
+                "name": "dashboard",
+                "dailyQuota": 100,
+                "allowedDeployments": null,
+                "revoked": false,
+                "queriesToday": 0,
+            }])
+        );
+
+        let (status, _) = call(&pgpool, Method::DELETE, "/dashboard", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&pgpool, Method::DELETE, "/dashboard", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
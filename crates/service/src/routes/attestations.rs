@@ -0,0 +1,267 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reference verifier for the attestations of the indexer
+//!
+//! The signer of an attestation is the key of the allocation it was
+//! created for, so the attestation is valid if its signature recovers
+//! one of the allocations of the indexer for the attested deployment.
+//! Each signature check recovers a key, so an attestation is only checked
+//! against the allocations of its deployment, at most
+//! [MAX_VERIFIED_ALLOCATIONS] of them.
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use indexer_monitor::AttestationWatcher;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thegraph_core::{alloy::primitives::Address, attestation::Attestation, DeploymentId};
+use thiserror::Error;
+
+use crate::error::{error_response, RouteError, StatusCodeExt};
+
+/// Allocations an attestation is checked against without an `allocation`
+const MAX_VERIFIED_ALLOCATIONS: usize = 16;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyAttestation {
+    /// request as it was attested, usually the query body
+    request: String,
+    /// `graphQLResponse` field of the response
+    response: String,
+    attestation: Attestation,
+    /// only check this allocation, any allocation of the indexer if not set
+    allocation: Option<Address>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiedAttestation {
+    signer: Address,
+    allocation: Address,
+    deployment: DeploymentId,
+}
+
+#[derive(Debug, Error)]
+pub enum AttestationVerificationError {
+    #[error("Allocation {0} is not an allocation of the indexer")]
+    UnknownAllocation(Address),
+    #[error("Attestation was not signed by allocation {0}, or the request or response differ")]
+    InvalidSignature(Address),
+    #[error("Attestation was not signed by any allocation of the indexer, or the request or response differ")]
+    NoMatchingAllocation,
+    #[error("Deployment {0} has more than {MAX_VERIFIED_ALLOCATIONS} allocations, set the allocation to check")]
+    TooManyAllocations(DeploymentId),
+}
+
+impl StatusCodeExt for AttestationVerificationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AttestationVerificationError::UnknownAllocation(_) => StatusCode::NOT_FOUND,
+            AttestationVerificationError::InvalidSignature(_)
+            | AttestationVerificationError::NoMatchingAllocation
+            | AttestationVerificationError::TooManyAllocations(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl ErrorCode for AttestationVerificationError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            AttestationVerificationError::UnknownAllocation(_) => IndexerErrorCode::NotFound,
+            AttestationVerificationError::InvalidSignature(_)
+            | AttestationVerificationError::NoMatchingAllocation
+            | AttestationVerificationError::TooManyAllocations(_) => {
+                IndexerErrorCode::InvalidRequest
+            }
+        }
+    }
+}
+
+impl RouteError for AttestationVerificationError {
+    fn data(&self) -> Option<Value> {
+        match self {
+            AttestationVerificationError::UnknownAllocation(allocation)
+            | AttestationVerificationError::InvalidSignature(allocation) => {
+                Some(json!({ "allocation": allocation }))
+            }
+            AttestationVerificationError::NoMatchingAllocation => None,
+            AttestationVerificationError::TooManyAllocations(deployment) => {
+                Some(json!({ "deployment": deployment }))
+            }
+        }
+    }
+}
+
+impl IntoResponse for AttestationVerificationError {
+    fn into_response(self) -> Response {
+        error_response(&self)
+    }
+}
+
+/// Verifies an attestation against the signers of the allocations
+/// of the indexer, returning the allocation that signed it
+pub async fn verify_attestation(
+    State(attestation_signers): State<AttestationWatcher>,
+    Json(VerifyAttestation {
+        request,
+        response,
+        attestation,
+        allocation,
+    }): Json<VerifyAttestation>,
+) -> Result<Json<VerifiedAttestation>, AttestationVerificationError> {
+    let signers = attestation_signers.borrow().clone();
+    let candidates: Vec<_> = match allocation {
+        Some(allocation) => {
+            let signer = signers
+                .get(&allocation)
+                .ok_or(AttestationVerificationError::UnknownAllocation(allocation))?;
+            vec![(allocation, signer)]
+        }
+        None => {
+            let deployment = DeploymentId::new(attestation.deployment);
+            let candidates: Vec<_> = signers
+                .iter()
+                .filter(|(_, signer)| signer.deployment() == deployment)
+                .map(|(allocation, signer)| (*allocation, signer))
+                .collect();
+            if candidates.len() > MAX_VERIFIED_ALLOCATIONS {
+                return Err(AttestationVerificationError::TooManyAllocations(deployment));
+            }
+            candidates
+        }
+    };
+
+    candidates
+        .into_iter()
+        .find(|(allocation, signer)| {
+            signer
+                .verify(&attestation, &request, &response, allocation)
+                .is_ok()
+        })
+        .map(|(allocation, signer)| {
+            Json(VerifiedAttestation {
+                signer: allocation,
+                allocation,
+                deployment: signer.deployment(),
+            })
+        })
+        .ok_or(match allocation {
+            Some(allocation) => AttestationVerificationError::InvalidSignature(allocation),
+            None => AttestationVerificationError::NoMatchingAllocation,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+        routing::post,
+        Router,
+    };
+    use indexer_attestation::AttestationSigner;
+    use reqwest::{header, StatusCode};
+    use serde_json::{json, Value};
+    use test_assets::{INDEXER_ALLOCATIONS, INDEXER_MNEMONIC};
+    use thegraph_core::alloy::primitives::{Address, B256};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::verify_attestation;
+
+    const REQUEST: &str = r#"{"query":"{ _meta { block { number } } }"}"#;
+    const RESPONSE: &str = r#"{"data":{"_meta":{"block":{"number":1}}}}"#;
+
+    async fn verify(app: Router, body: Value) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_verify_attestation() {
+        let signers: HashMap<_, _> = INDEXER_ALLOCATIONS
+            .values()
+            .filter_map(|allocation| {
+                let signer = AttestationSigner::new(
+                    &INDEXER_MNEMONIC.to_string(),
+                    allocation,
+                    1,
+                    Address::ZERO,
+                )
+                .ok()?;
+                Some((allocation.id, signer))
+            })
+            .collect();
+        let (allocation, signer) = signers.iter().next().unwrap();
+        let (allocation, attestation) = (*allocation, signer.create_attestation(REQUEST, RESPONSE));
+
+        let app = Router::new()
+            .route("/", post(verify_attestation))
+            .with_state(watch::channel(signers).1);
+
+        let (status, body) = verify(
+            app.clone(),
+            json!({ "request": REQUEST, "response": RESPONSE, "attestation": attestation }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["allocation"], json!(allocation));
+        assert_eq!(body["signer"], json!(allocation));
+
+        // response was tampered with
+        let (status, _) = verify(
+            app.clone(),
+            json!({
+                "request": REQUEST,
+                "response": "{}",
+                "attestation": attestation,
+                "allocation": allocation,
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = verify(
+            app.clone(),
+            json!({
+                "request": REQUEST,
+                "response": RESPONSE,
+                "attestation": attestation,
+                "allocation": Address::ZERO,
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // only checked against the allocations of the attested deployment
+        let mut other_deployment = attestation.clone();
+        other_deployment.deployment = B256::ZERO;
+        let (status, _) = verify(
+            app,
+            json!({
+                "request": REQUEST,
+                "response": RESPONSE,
+                "attestation": other_deployment,
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read-only view of the DIPS indexing agreements, for gateway-side tooling
+//! reconciling its state with the indexer's
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use build_info::chrono::Utc;
+use indexer_dips::{
+    store::{AgreementStore, StoredIndexingAgreement},
+    DipsError,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
+use uuid::Uuid;
+
+use crate::error::{error_response, RouteError, StatusCodeExt};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgreementsError {
+    #[error("Invalid agreement id {0}")]
+    InvalidId(String),
+    #[error("Agreement {0} not found")]
+    NotFound(Uuid),
+    #[error(transparent)]
+    Store(#[from] DipsError),
+}
+
+impl StatusCodeExt for AgreementsError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AgreementsError::InvalidId(_) => StatusCode::BAD_REQUEST,
+            AgreementsError::NotFound(_) => StatusCode::NOT_FOUND,
+            AgreementsError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl ErrorCode for AgreementsError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            AgreementsError::InvalidId(_) => IndexerErrorCode::InvalidRequest,
+            AgreementsError::NotFound(_) => IndexerErrorCode::NotFound,
+            AgreementsError::Store(DipsError::UnknownError(error)) => error.error_code(),
+            AgreementsError::Store(_) => IndexerErrorCode::Unknown,
+        }
+    }
+}
+
+impl RouteError for AgreementsError {
+    fn data(&self) -> Option<Value> {
+        match self {
+            AgreementsError::NotFound(id) => Some(json!({ "id": id.to_string() })),
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for AgreementsError {
+    fn into_response(self) -> Response {
+        if let AgreementsError::Store(ref error) = self {
+            tracing::error!(%error, "Failed to load the agreements");
+        }
+        error_response(&self)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgreementInfo {
+    id: String,
+    payer: Address,
+    recipient: Address,
+    service: Address,
+    subgraph_deployment_id: String,
+    protocol_network: String,
+    chain_id: String,
+    base_price_per_epoch: String,
+    price_per_entity: String,
+    /// price per entity after the escalation periods elapsed
+    current_price_per_entity: String,
+    duration_epochs: u32,
+    max_initial_amount: String,
+    max_ongoing_amount_per_epoch: String,
+    min_epochs_per_collection: u32,
+    max_epochs_per_collection: u32,
+    deadline: u64,
+    /// ABI encoded ERC-712 signed voucher, hex encoded
+    signed_voucher: String,
+    cancelled: bool,
+    current_allocation_id: Option<String>,
+    last_allocation_id: Option<String>,
+    /// unix timestamp in seconds
+    last_payment_collected_at: Option<i64>,
+    /// the current price is below the minimum price of the indexer
+    unprofitable: bool,
+    /// unix timestamp in seconds the indexing of the deployment was started at
+    indexing_started_at: Option<i64>,
+    /// unix timestamp in seconds the deployment was first seen synced at
+    synced_at: Option<i64>,
+}
+
+impl From<StoredIndexingAgreement> for AgreementInfo {
+    fn from(agreement: StoredIndexingAgreement) -> Self {
+        let current_price_per_entity = agreement.current_price_per_entity(Utc::now());
+        let voucher = &agreement.voucher.voucher;
+        let metadata = agreement.metadata;
+        Self {
+            id: Uuid::from_bytes(voucher.agreement_id.into()).to_string(),
+            payer: voucher.payer,
+            recipient: voucher.recipient,
+            service: voucher.service,
+            subgraph_deployment_id: metadata.subgraphDeploymentId,
+            protocol_network: metadata.protocolNetwork,
+            chain_id: metadata.chainId,
+            base_price_per_epoch: metadata.basePricePerEpoch.to_string(),
+            price_per_entity: metadata.pricePerEntity.to_string(),
+            current_price_per_entity: current_price_per_entity.to_string(),
+            duration_epochs: voucher.durationEpochs,
+            max_initial_amount: voucher.maxInitialAmount.to_string(),
+            max_ongoing_amount_per_epoch: voucher.maxOngoingAmountPerEpoch.to_string(),
+            min_epochs_per_collection: voucher.minEpochsPerCollection,
+            max_epochs_per_collection: voucher.maxEpochsPerCollection,
+            deadline: voucher.deadline,
+            signed_voucher: agreement.voucher.encode_vec().encode_hex_with_prefix(),
+            cancelled: agreement.cancelled,
+            current_allocation_id: agreement.current_allocation_id,
+            last_allocation_id: agreement.last_allocation_id,
+            last_payment_collected_at: agreement
+                .last_payment_collected_at
+                .map(|collected_at| collected_at.timestamp()),
+            unprofitable: agreement.unprofitable,
+            indexing_started_at: agreement
+                .indexing_started_at
+                .map(|started_at| started_at.timestamp()),
+            synced_at: agreement.synced_at.map(|synced_at| synced_at.timestamp()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AgreementsFilter {
+    payer: Option<Address>,
+}
+
+/// Agreements routes, mounted at `/dips`
+pub fn agreements_router(store: Arc<dyn AgreementStore>) -> Router {
+    Router::new()
+        .route("/agreements", get(list_agreements))
+        .route("/agreements/:id", get(get_agreement))
+        .with_state(store)
+}
+
+async fn list_agreements(
+    State(store): State<Arc<dyn AgreementStore>>,
+    Query(filter): Query<AgreementsFilter>,
+) -> Result<Json<Vec<AgreementInfo>>, AgreementsError> {
+    let agreements = store.list_agreements(filter.payer).await?;
+    Ok(Json(agreements.into_iter().map(Into::into).collect()))
+}
+
+async fn get_agreement(
+    State(store): State<Arc<dyn AgreementStore>>,
+    Path(id): Path<String>,
+) -> Result<Json<AgreementInfo>, AgreementsError> {
+    let id = Uuid::parse_str(&id).map_err(|_| AgreementsError::InvalidId(id))?;
+    let agreement = store
+        .get_by_id(id)
+        .await?
+        .ok_or(AgreementsError::NotFound(id))?;
+    Ok(Json(agreement.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::{to_bytes, Body};
+    use indexer_dips::{
+        store::{AgreementStore, InMemoryAgreementStore},
+        test_agreement,
+    };
+    use reqwest::StatusCode;
+    use thegraph_core::alloy::primitives::Address;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use super::agreements_router;
+
+    #[tokio::test]
+    async fn test_agreements_routes() {
+        let store = Arc::new(InMemoryAgreementStore::default());
+        let id = Uuid::now_v7();
+        let (voucher, metadata) = test_agreement(
+            id,
+            100,
+            "Qmbg1qF4YgHjiVfsVt6a13ddrVcRtWyJQfD4LA3CwHM29f",
+            10000000,
+        );
+        store
+            .create_agreement(voucher, metadata, None)
+            .await
+            .unwrap();
+
+        let app = agreements_router(store);
+        let get = |uri: String| {
+            app.clone()
+                .oneshot(axum::http::Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let res = get(format!("/agreements/{id}")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["id"], id.to_string());
+        assert_eq!(body["pricePerEntity"], "100");
+        assert_eq!(body["currentPricePerEntity"], "100");
+        assert_eq!(body["cancelled"], false);
+        assert_eq!(body["unprofitable"], false);
+
+        let res = get(format!("/agreements/{}", Uuid::now_v7()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = get("/agreements/not-an-id".to_string()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = get(format!("/agreements?payer={}", Address::repeat_byte(1)))
+            .await
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+
+        let res = get(format!("/agreements?payer={}", Address::repeat_byte(2)))
+            .await
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert!(body.as_array().unwrap().is_empty());
+    }
+}
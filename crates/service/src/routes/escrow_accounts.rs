@@ -0,0 +1,231 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Escrow accounts the receipts are checked against, as last read by the
+//! watchers of the service
+//!
+//! Lets operators find out why the receipts of a sender are refused, e.g. an
+//! empty balance or a signer that isn't authorized anymore, without querying
+//! the escrow subgraph themselves.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
+};
+use indexer_monitor::{EscrowAccounts, EscrowAccountsWatcher};
+use serde::{Deserialize, Serialize};
+use thegraph_core::alloy::primitives::Address;
+
+/// Accounts of a watcher and when it last read them
+#[derive(Clone)]
+struct TrackedAccounts {
+    accounts: EscrowAccountsWatcher,
+    updated_at: Arc<Mutex<SystemTime>>,
+}
+
+impl TrackedAccounts {
+    fn new(accounts: EscrowAccountsWatcher) -> Self {
+        let updated_at = Arc::new(Mutex::new(SystemTime::now()));
+        let mut changes = accounts.clone();
+        let last_update = updated_at.clone();
+        // the watchers send the accounts on every successful refresh, even
+        // if they didn't change
+        tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                *last_update.lock().unwrap() = SystemTime::now();
+            }
+        });
+        Self {
+            accounts,
+            updated_at,
+        }
+    }
+
+    fn snapshot(&self, filter: &AccountsFilter, now: SystemTime) -> AccountsSnapshot {
+        let updated_at = *self.updated_at.lock().unwrap();
+        let accounts = self.accounts.borrow().clone();
+        let mut senders: Vec<_> = accounts
+            .get_senders()
+            .into_iter()
+            .filter(|sender| filter.matches(&accounts, sender))
+            .map(|sender| SenderAccount {
+                sender,
+                balance: accounts
+                    .get_balance_for_sender(&sender)
+                    .unwrap_or_default()
+                    .to_string(),
+                signers: accounts.get_signers_for_sender(&sender),
+            })
+            .collect();
+        senders.sort_by_key(|account| account.sender);
+        AccountsSnapshot {
+            updated_at: updated_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            seconds_since_update: now.duration_since(updated_at).unwrap_or_default().as_secs(),
+            senders,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EscrowAccountsState {
+    indexers: Arc<Vec<(Address, TrackedAccounts, TrackedAccounts)>>,
+}
+
+impl EscrowAccountsState {
+    /// Tracks the v1 and v2 escrow accounts of every indexer served
+    pub fn new(
+        escrow_accounts_v1: &HashMap<Address, EscrowAccountsWatcher>,
+        escrow_accounts_v2: &HashMap<Address, EscrowAccountsWatcher>,
+    ) -> Self {
+        let mut indexers: Vec<_> = escrow_accounts_v1
+            .iter()
+            .filter_map(|(indexer, v1)| {
+                let v2 = escrow_accounts_v2.get(indexer)?;
+                Some((
+                    *indexer,
+                    TrackedAccounts::new(v1.clone()),
+                    TrackedAccounts::new(v2.clone()),
+                ))
+            })
+            .collect();
+        indexers.sort_by_key(|(indexer, _, _)| *indexer);
+        Self {
+            indexers: Arc::new(indexers),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountsFilter {
+    sender: Option<Address>,
+    /// the sender this signer is authorized for
+    signer: Option<Address>,
+}
+
+impl AccountsFilter {
+    fn matches(&self, accounts: &EscrowAccounts, sender: &Address) -> bool {
+        self.sender.iter().all(|filter| filter == sender)
+            && self
+                .signer
+                .iter()
+                .all(|signer| accounts.get_sender_for_signer(signer).ok() == Some(*sender))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SenderAccount {
+    sender: Address,
+    /// balance left once the thawing amount is withdrawn, in wei
+    balance: String,
+    /// signers the receipts of the sender are accepted from
+    signers: Vec<Address>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccountsSnapshot {
+    /// unix timestamp of the last refresh of the watcher
+    updated_at: u64,
+    seconds_since_update: u64,
+    senders: Vec<SenderAccount>,
+}
+
+#[derive(Serialize)]
+struct IndexerAccounts {
+    indexer: Address,
+    v1: AccountsSnapshot,
+    v2: AccountsSnapshot,
+}
+
+/// Escrow accounts route, mounted at `/escrow`
+pub fn escrow_accounts_router(state: EscrowAccountsState) -> Router {
+    Router::new()
+        .route("/accounts", get(escrow_accounts))
+        .with_state(state)
+}
+
+async fn escrow_accounts(
+    State(state): State<EscrowAccountsState>,
+    Query(filter): Query<AccountsFilter>,
+) -> Json<Vec<IndexerAccounts>> {
+    let now = SystemTime::now();
+    Json(
+        state
+            .indexers
+            .iter()
+            .map(|(indexer, v1, v2)| IndexerAccounts {
+                indexer: *indexer,
+                v1: v1.snapshot(&filter, now),
+                v2: v2.snapshot(&filter, now),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::body::{to_bytes, Body};
+    use indexer_monitor::EscrowAccounts;
+    use reqwest::StatusCode;
+    use thegraph_core::alloy::primitives::{Address, U256};
+    use tokio::sync::watch;
+    use tower::ServiceExt;
+
+    use super::{escrow_accounts_router, EscrowAccountsState};
+
+    #[tokio::test]
+    async fn test_escrow_accounts_route() {
+        let indexer = Address::repeat_byte(1);
+        let (sender_1, signer_1) = (Address::repeat_byte(2), Address::repeat_byte(3));
+        let (sender_2, signer_2) = (Address::repeat_byte(4), Address::repeat_byte(5));
+        let accounts = EscrowAccounts::new(
+            HashMap::from([(sender_1, U256::from(100_u64)), (sender_2, U256::ZERO)]),
+            HashMap::from([(sender_1, vec![signer_1]), (sender_2, vec![signer_2])]),
+        );
+        let (_v1_tx, v1) = watch::channel(accounts);
+        let (_v2_tx, v2) = watch::channel(EscrowAccounts::default());
+        let app = escrow_accounts_router(EscrowAccountsState::new(
+            &HashMap::from([(indexer, v1)]),
+            &HashMap::from([(indexer, v2)]),
+        ));
+        let get = |uri: String| {
+            let app = app.clone();
+            async move {
+                let res = app
+                    .oneshot(axum::http::Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let body = get("/accounts".to_string()).await;
+        assert_eq!(body[0]["indexer"], indexer.to_string());
+        let senders = body[0]["v1"]["senders"].as_array().unwrap();
+        assert_eq!(senders.len(), 2);
+        assert_eq!(senders[0]["balance"], "100");
+        assert_eq!(body[0]["v1"]["secondsSinceUpdate"], 0);
+        assert!(body[0]["v2"]["senders"].as_array().unwrap().is_empty());
+
+        let body = get(format!("/accounts?signer={signer_2}")).await;
+        let senders = body[0]["v1"]["senders"].as_array().unwrap();
+        assert_eq!(senders.len(), 1);
+        assert_eq!(senders[0]["sender"], sender_2.to_string());
+        assert_eq!(senders[0]["balance"], "0");
+    }
+}
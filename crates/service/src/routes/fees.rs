@@ -0,0 +1,99 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fees earned and not redeemed yet, for indexer-agent and dashboards
+//!
+//! Aggregating every receipt is expensive, so the summary is computed at
+//! most once per TTL and shared by the requests received in between.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use indexer_monitor::EscrowAccountsWatcher;
+use reqwest::StatusCode;
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+
+use crate::{
+    database::fees::{self, FeesSummary},
+    error::{error_response, RouteError, StatusCodeExt},
+};
+
+#[derive(Clone)]
+pub struct FeesSummaryState {
+    pgpool: PgPool,
+    escrow_accounts_v1: EscrowAccountsWatcher,
+    ttl: Duration,
+    cache: Arc<Mutex<Option<(Instant, FeesSummary)>>>,
+}
+
+impl FeesSummaryState {
+    pub fn new(pgpool: PgPool, escrow_accounts_v1: EscrowAccountsWatcher, ttl: Duration) -> Self {
+        Self {
+            pgpool,
+            escrow_accounts_v1,
+            ttl,
+            cache: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to aggregate the fees: {0:#}")]
+pub struct FeesSummaryError(anyhow::Error);
+
+impl StatusCodeExt for FeesSummaryError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl ErrorCode for FeesSummaryError {
+    fn error_code(&self) -> IndexerErrorCode {
+        self.0.error_code()
+    }
+}
+
+impl RouteError for FeesSummaryError {}
+
+impl IntoResponse for FeesSummaryError {
+    fn into_response(self) -> Response {
+        tracing::error!(error = %self.0, "Failed to aggregate the fees");
+        error_response(&self)
+    }
+}
+
+/// Fees summary route, mounted at `/fees`
+pub fn fees_router(state: FeesSummaryState) -> Router {
+    Router::new()
+        .route("/summary", get(fees_summary))
+        .with_state(state)
+}
+
+async fn fees_summary(
+    State(state): State<FeesSummaryState>,
+) -> Result<Json<FeesSummary>, FeesSummaryError> {
+    // held while aggregating, so concurrent requests wait for the same rollup
+    let mut cache = state.cache.lock().await;
+    if let Some((computed_at, summary)) = cache.as_ref() {
+        if computed_at.elapsed() < state.ttl {
+            return Ok(Json(summary.clone()));
+        }
+    }
+
+    let escrow_accounts_v1 = state.escrow_accounts_v1.borrow().clone();
+    let summary = fees::fees_summary(&state.pgpool, &escrow_accounts_v1)
+        .await
+        .map_err(FeesSummaryError)?;
+    *cache = Some((Instant::now(), summary.clone()));
+    Ok(Json(summary))
+}
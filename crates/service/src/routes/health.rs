@@ -7,12 +7,16 @@ use axum::{
     Json,
 };
 use graphql_client::GraphQLQuery;
+use indexer_error::{ErrorCode, IndexerErrorCode};
 use indexer_query::{health_query, HealthQuery};
 use reqwest::StatusCode;
 use serde_json::json;
 use thiserror::Error;
 
-use crate::service::GraphNodeState;
+use crate::{
+    error::{error_response, RouteError, StatusCodeExt},
+    service::{BlockConstraint, CacheKey, GraphNodeState},
+};
 
 #[derive(Debug, Error)]
 pub enum CheckHealthError {
@@ -26,19 +30,35 @@ pub enum CheckHealthError {
     InvalidHealthStatus,
 }
 
-impl IntoResponse for CheckHealthError {
-    fn into_response(self) -> AxumResponse {
-        let status = match &self {
+impl StatusCodeExt for CheckHealthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
             CheckHealthError::DeploymentNotFound => StatusCode::NOT_FOUND,
             CheckHealthError::InvalidHealthStatus | CheckHealthError::BadResponse => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
             CheckHealthError::RequestFailed => StatusCode::BAD_GATEWAY,
-        };
-        let body = serde_json::json!({
-            "errors": [self.to_string()],
-        });
-        (status, Json(body)).into_response()
+        }
+    }
+}
+
+impl ErrorCode for CheckHealthError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            CheckHealthError::RequestFailed => IndexerErrorCode::SubgraphUnavailable,
+            CheckHealthError::BadResponse | CheckHealthError::InvalidHealthStatus => {
+                IndexerErrorCode::SubgraphQuery
+            }
+            CheckHealthError::DeploymentNotFound => IndexerErrorCode::NotFound,
+        }
+    }
+}
+
+impl RouteError for CheckHealthError {}
+
+impl IntoResponse for CheckHealthError {
+    fn into_response(self) -> AxumResponse {
+        error_response(&self)
     }
 }
 
@@ -46,6 +66,18 @@ pub async fn health(
     Path(deployment_id): Path<String>,
     State(graph_node): State<GraphNodeState>,
 ) -> Result<impl IntoResponse, CheckHealthError> {
+    let cache = graph_node.response_cache.as_ref().map(|cache| {
+        let key = CacheKey::new(
+            Some(deployment_id.clone()),
+            b"health",
+            BlockConstraint::Latest,
+        );
+        (cache, key)
+    });
+    if let Some(response) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        return Ok(Json(response));
+    }
+
     let req_body = HealthQuery::build_query(health_query::Variables {
         ids: vec![deployment_id],
     });
@@ -86,5 +118,8 @@ pub async fn health(
         }
         health_query::Health::Other(_) => return Err(CheckHealthError::InvalidHealthStatus),
     };
+    if let Some((cache, key)) = cache {
+        cache.insert(key, health_response.clone());
+    }
     Ok(Json(health_response))
 }
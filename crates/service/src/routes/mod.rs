@@ -1,13 +1,33 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+mod allocation_events;
+mod allocation_overrides;
+mod api_keys;
+mod attestations;
 pub mod cost;
+mod dips;
+mod escrow_accounts;
+mod fees;
 mod health;
+mod query_blocklist;
+mod query_stats;
 mod request_handler;
+mod service_health;
 mod static_subgraph;
 mod status;
 
+pub use allocation_events::allocation_events_router;
+pub use allocation_overrides::allocation_overrides_router;
+pub use api_keys::api_keys_router;
+pub use attestations::verify_attestation;
+pub use dips::agreements_router;
+pub use escrow_accounts::{escrow_accounts_router, EscrowAccountsState};
+pub use fees::{fees_router, FeesSummaryState};
 pub use health::health;
+pub use query_blocklist::query_blocklist_router;
+pub use query_stats::query_stats;
 pub use request_handler::request_handler;
+pub use service_health::{check_ready, healthz, readyz, ServiceHealthState, WatcherHealth};
 pub use static_subgraph::static_subgraph_request_handler;
 pub use status::status;
@@ -0,0 +1,275 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use regex::Regex;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use thegraph_core::alloy::primitives::B256;
+use thiserror::Error;
+
+use crate::{
+    error::{error_response, RouteError, StatusCodeExt},
+    middleware::query_fingerprint,
+};
+
+#[derive(Debug, Error)]
+pub enum QueryBlocklistError {
+    #[error("No query blocklist entry has the id {0}")]
+    NotFound(i64),
+    #[error("Invalid regex: {0}")]
+    InvalidRegex(#[from] regex::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl StatusCodeExt for QueryBlocklistError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QueryBlocklistError::NotFound(_) => StatusCode::NOT_FOUND,
+            QueryBlocklistError::InvalidRegex(_) => StatusCode::BAD_REQUEST,
+            QueryBlocklistError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl ErrorCode for QueryBlocklistError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            QueryBlocklistError::NotFound(_) => IndexerErrorCode::NotFound,
+            QueryBlocklistError::InvalidRegex(_) => IndexerErrorCode::InvalidRequest,
+            QueryBlocklistError::Database(error) => error.error_code(),
+        }
+    }
+}
+
+impl RouteError for QueryBlocklistError {
+    fn data(&self) -> Option<Value> {
+        match self {
+            QueryBlocklistError::NotFound(id) => Some(json!({ "id": id })),
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for QueryBlocklistError {
+    fn into_response(self) -> Response {
+        error_response(&self)
+    }
+}
+
+/// Queries refused by an entry
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BlockedQueries {
+    /// The queries with this fingerprint
+    Hash(B256),
+    /// The queries matching this regex
+    Regex(String),
+    /// The queries with the fingerprint of this query
+    Query(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddQueryBlocklistEntry {
+    #[serde(flatten)]
+    blocked: BlockedQueries,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryBlocklistEntry {
+    id: i64,
+    kind: String,
+    pattern: String,
+    reason: Option<String>,
+    created_at: String,
+}
+
+struct QueryBlocklistRow {
+    id: i64,
+    kind: String,
+    pattern: String,
+    reason: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<QueryBlocklistRow> for QueryBlocklistEntry {
+    fn from(row: QueryBlocklistRow) -> Self {
+        Self {
+            id: row.id,
+            kind: row.kind,
+            pattern: row.pattern,
+            reason: row.reason,
+            created_at: row.created_at.to_rfc3339(),
+        }
+    }
+}
+
+/// Management routes to add queries to the blocklist at runtime, the
+/// service reloads the blocklist as soon as it is modified
+pub fn query_blocklist_router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/", get(list_entries).post(add_entry))
+        .route("/:id", delete(remove_entry))
+        .with_state(pgpool)
+}
+
+async fn list_entries(
+    State(pgpool): State<PgPool>,
+) -> Result<Json<Vec<QueryBlocklistEntry>>, QueryBlocklistError> {
+    let rows = sqlx::query_as!(
+        QueryBlocklistRow,
+        r#"
+            SELECT id, kind, pattern, reason, created_at
+            FROM query_blocklist
+            ORDER BY id
+        "#
+    )
+    .fetch_all(&pgpool)
+    .await?;
+
+    Ok(Json(rows.into_iter().map(Into::into).collect()))
+}
+
+async fn add_entry(
+    State(pgpool): State<PgPool>,
+    Json(AddQueryBlocklistEntry { blocked, reason }): Json<AddQueryBlocklistEntry>,
+) -> Result<Json<QueryBlocklistEntry>, QueryBlocklistError> {
+    let (kind, pattern) = match blocked {
+        BlockedQueries::Hash(hash) => ("hash", hash.to_string()),
+        BlockedQueries::Query(query) => ("hash", query_fingerprint(&query).to_string()),
+        BlockedQueries::Regex(regex) => {
+            Regex::new(&regex)?;
+            ("regex", regex)
+        }
+    };
+
+    let row = sqlx::query_as!(
+        QueryBlocklistRow,
+        r#"
+            INSERT INTO query_blocklist (kind, pattern, reason)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (kind, pattern) DO UPDATE SET reason = EXCLUDED.reason
+            RETURNING id, kind, pattern, reason, created_at
+        "#,
+        kind,
+        &pattern,
+        reason.as_deref()
+    )
+    .fetch_one(&pgpool)
+    .await?;
+    tracing::warn!(
+        kind,
+        %pattern,
+        reason = reason.as_deref().unwrap_or_default(),
+        "Added an entry to the query blocklist"
+    );
+
+    Ok(Json(row.into()))
+}
+
+async fn remove_entry(
+    State(pgpool): State<PgPool>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, QueryBlocklistError> {
+    let result = sqlx::query!("DELETE FROM query_blocklist WHERE id = $1", id)
+        .execute(&pgpool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(QueryBlocklistError::NotFound(id));
+    }
+    tracing::info!(id, "Removed an entry of the query blocklist");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+    };
+    use reqwest::{header, Method, StatusCode};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use super::query_blocklist_router;
+    use crate::middleware::query_fingerprint;
+
+    async fn call(
+        pgpool: &PgPool,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        let res = query_blocklist_router(pgpool.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = res.status();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_add_list_remove(pgpool: PgPool) {
+        let (status, _) = call(&pgpool, Method::POST, "/", Some(json!({ "regex": "(" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let query = "{ tokens(first: 1000) { id } }";
+        let (status, added) = call(
+            &pgpool,
+            Method::POST,
+            "/",
+            Some(json!({ "query": query, "reason": "scraping" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(added["kind"], "hash");
+        assert_eq!(added["pattern"], query_fingerprint(query).to_string());
+
+        let (status, _) = call(
+            &pgpool,
+            Method::POST,
+            "/",
+            Some(json!({ "regex": r"_meta\s*\{" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, entries) = call(&pgpool, Method::GET, "/", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(entries.as_array().unwrap().len(), 2);
+        assert_eq!(entries[0]["reason"], "scraping");
+
+        let uri = format!("/{}", added["id"]);
+        let (status, _) = call(&pgpool, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&pgpool, Method::DELETE, &uri, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
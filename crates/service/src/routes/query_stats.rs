@@ -0,0 +1,14 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::SystemTime;
+
+use axum::{extract::State, Json};
+
+use crate::service::{QueryStats, QueryStatsSummary};
+
+/// Publishes the number of queries served and their median latency
+/// for each deployment over the last 24 hours
+pub async fn query_stats(State(stats): State<QueryStats>) -> Json<QueryStatsSummary> {
+    Json(stats.summary(SystemTime::now()))
+}
@@ -1,51 +1,166 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::time::Duration;
+
 use axum::{
     extract::{Path, State},
-    http::{HeaderValue, Response},
+    http::{HeaderMap, HeaderValue, Response},
     response::IntoResponse,
 };
-use reqwest::header::CONTENT_TYPE;
+use indexer_config::PartialResponsePolicy;
+use reqwest::{header::CONTENT_TYPE, Url};
+use serde::{de::IgnoredAny, Deserialize};
 use thegraph_core::DeploymentId;
+use tracing::Instrument;
 
-use crate::{error::SubgraphServiceError, middleware::AttestationInput, service::GraphNodeState};
+use crate::{
+    error::SubgraphServiceError,
+    metrics::GRAPH_NODE_REQUESTS,
+    middleware::{AttestationInput, REQUEST_ID_HEADER},
+    service::{
+        constrain_request, hedged, required_block, BlockConstraint, GraphNodeState, Hedging,
+        IndexedBlock,
+    },
+};
 
 const GRAPH_ATTESTABLE: &str = "graph-attestable";
 const GRAPH_INDEXED: &str = "graph-indexed";
+/// Block the gateway expects the query to be executed at
+const GRAPH_BLOCK_CONSTRAINT: &str = "graph-block-constraint";
+/// Time left to the gateway to serve the query, in milliseconds
+const DEADLINE_MS: &str = "x-deadline-ms";
+/// Time left to the gateway to serve the query, in the format of gRPC
+const GRPC_TIMEOUT: &str = "grpc-timeout";
 
 pub async fn request_handler(
     Path(deployment): Path<DeploymentId>,
     State(state): State<GraphNodeState>,
+    headers: HeaderMap,
     req: String,
 ) -> Result<impl IntoResponse, SubgraphServiceError> {
     tracing::trace!("Handling request for deployment `{deployment}`");
 
-    let deployment_url = state
-        .graph_node_query_base_url
-        .join(&format!("subgraphs/id/{deployment}"))
-        .map_err(|_| SubgraphServiceError::InvalidDeployment(deployment))?;
-
-    let response = state
-        .graph_node_client
-        .post(deployment_url)
-        .body(req.clone())
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .send()
-        .await
-        .map_err(SubgraphServiceError::QueryForwardingError)?;
-
-    let attestable = response
-        .headers()
-        .get(GRAPH_ATTESTABLE)
-        .is_some_and(|value| value.to_str().map(|value| value == "true").unwrap_or(false));
-
-    let graph_indexed = response.headers().get(GRAPH_INDEXED).cloned();
-    let body = response
-        .text()
-        .await
-        .map_err(SubgraphServiceError::QueryForwardingError)?;
-    let attestation_input = if attestable {
+    let expected_block: BlockConstraint = match headers.get(GRAPH_BLOCK_CONSTRAINT) {
+        Some(value) => value
+            .to_str()
+            .unwrap_or_default()
+            .parse()
+            .map_err(SubgraphServiceError::InvalidBlockConstraint)?,
+        None => BlockConstraint::Latest,
+    };
+
+    // queries asking for a block only go to the query nodes that reached it
+    let required_block = if state.query_nodes.tracks_blocks() {
+        let header_block = match &expected_block {
+            BlockConstraint::Number(number) => Some(*number),
+            _ => None,
+        };
+        required_block(&req).max(header_block)
+    } else {
+        None
+    };
+    let query_url = state.query_nodes.query_url(&deployment, required_block)?;
+    let deployment_url = |query_url: &Url| {
+        query_url
+            .join(&format!("subgraphs/id/{deployment}"))
+            .map_err(|_| SubgraphServiceError::InvalidDeployment(deployment))
+    };
+
+    // graph-node only knows about the `block` argument of the query, the
+    // attestation is still for the request sent by the gateway. A query that
+    // can't be pinned is sent as is and fails the verification of its block.
+    let forwarded_req = constrain_request(&req, &expected_block).unwrap_or_else(|| req.clone());
+
+    let span = tracing::info_span!("graph_node_query", %deployment);
+    // graph-node spans are attached to the trace of the query
+    let mut forwarded_headers = HeaderMap::new();
+    indexer_telemetry::inject_context(&span, &mut forwarded_headers);
+    // the logs of graph-node are correlated with the ones of the gateway
+    if let Some(request_id) = headers.get(&REQUEST_ID_HEADER) {
+        forwarded_headers.insert(REQUEST_ID_HEADER, request_id.clone());
+    }
+
+    let deadline = request_deadline(&headers);
+    let (state_ref, req_ref, expected_block_ref) = (&state, &forwarded_req, &expected_block);
+    // only the latencies of the primary requests are recorded, including
+    // when they fail or are cancelled by a faster hedge
+    let forward = |deployment_url: Url, primary: bool| {
+        let forwarded_headers = forwarded_headers.clone();
+        let span = span.clone();
+        async move {
+            GRAPH_NODE_REQUESTS.inc();
+            let _latency = state_ref
+                .hedging
+                .as_ref()
+                .filter(|_| primary)
+                .map(Hedging::start);
+            let response = state_ref
+                .graph_node_client
+                .post(deployment_url)
+                .body(req_ref.clone())
+                .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+                .headers(forwarded_headers)
+                .send()
+                .instrument(span)
+                .await
+                .map_err(SubgraphServiceError::QueryForwardingError)?;
+
+            let attestable = response
+                .headers()
+                .get(GRAPH_ATTESTABLE)
+                .is_some_and(|value| value.to_str().map(|value| value == "true").unwrap_or(false));
+
+            let graph_indexed = response.headers().get(GRAPH_INDEXED).cloned();
+            verify_block_constraint(expected_block_ref, graph_indexed.as_ref())?;
+            let body = response
+                .text()
+                .await
+                .map_err(SubgraphServiceError::QueryForwardingError)?;
+            Ok::<_, SubgraphServiceError>((attestable, graph_indexed, body))
+        }
+    };
+
+    // a slow query is sent to a second query node, both go to the same
+    // deployment so that either response can be attested for the receipt
+    let hedge = state.hedging.as_ref().and_then(|hedging| {
+        let delay = hedging.delay()?;
+        let hedge_url = state
+            .query_nodes
+            .hedge_url(&deployment, required_block, query_url)?;
+        Some((hedging, delay, hedge_url))
+    });
+    let primary = forward(deployment_url(query_url)?, true);
+    let query = async {
+        match hedge {
+            Some((hedging, delay, hedge_url)) => {
+                let hedge_url = deployment_url(hedge_url)?;
+                let hedge = || hedging.try_hedge().then(|| forward(hedge_url, false));
+                hedged(primary, hedge, delay).await
+            }
+            None => primary.await,
+        }
+    };
+    // the query to graph-node is dropped, and so cancelled, once the gateway
+    // stopped waiting for the response
+    let (attestable, graph_indexed, body) = match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, query)
+            .await
+            .map_err(|_| SubgraphServiceError::DeadlineExceeded(deadline))??,
+        None => query.await?,
+    };
+
+    // the response is only parsed if partial responses are not attested
+    let unattested = match state.partial_response {
+        PartialResponsePolicy::Attest => false,
+        PartialResponsePolicy::Unattested => is_partial_response(&body),
+        PartialResponsePolicy::Refuse if is_partial_response(&body) => {
+            return Err(SubgraphServiceError::PartialResponseRefused);
+        }
+        PartialResponsePolicy::Refuse => false,
+    };
+
+    let attestation_input = if attestable && !unattested {
         AttestationInput::Attestable { req }
     } else {
         AttestationInput::NotAttestable
@@ -57,6 +172,168 @@ pub async fn request_handler(
     if let Some(graph_indexed) = graph_indexed {
         response.headers_mut().append(GRAPH_INDEXED, graph_indexed);
     }
+    if unattested {
+        response
+            .headers_mut()
+            .insert(GRAPH_ATTESTABLE, HeaderValue::from_static("false"));
+    }
 
     Ok(response)
 }
+
+/// Time left to serve the query, from the `x-deadline-ms` header or else the
+/// `grpc-timeout` header. Values that can't be parsed are ignored.
+fn request_deadline(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    header(DEADLINE_MS)
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_millis)
+        .or_else(|| header(GRPC_TIMEOUT).and_then(parse_grpc_timeout))
+}
+
+/// Parses a `grpc-timeout` value: at most 8 digits followed by a unit
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Whether graph-node returned GraphQL errors alongside data
+///
+/// Only the responses mentioning `"errors"` are parsed, the others can't be
+/// partial.
+fn is_partial_response(body: &str) -> bool {
+    if !body.contains("\"errors\"") {
+        return false;
+    }
+
+    #[derive(Deserialize)]
+    struct GraphQLResponse {
+        data: Option<IgnoredAny>,
+        #[serde(default)]
+        errors: Vec<IgnoredAny>,
+    }
+
+    serde_json::from_str::<GraphQLResponse>(body)
+        .is_ok_and(|response| response.data.is_some() && !response.errors.is_empty())
+}
+
+/// Makes sure the response was executed at the block requested by the gateway
+/// before it gets attested, using the block reported by graph-node
+fn verify_block_constraint(
+    expected: &BlockConstraint,
+    graph_indexed: Option<&HeaderValue>,
+) -> Result<(), SubgraphServiceError> {
+    if *expected == BlockConstraint::Latest {
+        return Ok(());
+    }
+    let reported = graph_indexed
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| serde_json::from_str::<IndexedBlock>(value).ok());
+    match reported {
+        Some(block) if expected.is_satisfied_by(&block) => Ok(()),
+        reported => Err(SubgraphServiceError::BlockConstraintMismatch {
+            expected: expected.clone(),
+            reported,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{is_partial_response, request_deadline, verify_block_constraint};
+    use crate::{error::SubgraphServiceError, service::BlockConstraint};
+
+    #[test]
+    fn test_verify_block_constraint() {
+        let graph_indexed = HeaderValue::from_static(
+            r#"{"hash":"0xe0a5c8b9e1e1a4d6d3bc1b2a5d0e8c7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d","number":100,"timestamp":1700000000}"#,
+        );
+
+        assert!(verify_block_constraint(&BlockConstraint::Latest, None).is_ok());
+        assert!(
+            verify_block_constraint(&BlockConstraint::Number(100), Some(&graph_indexed)).is_ok()
+        );
+        assert!(matches!(
+            verify_block_constraint(&BlockConstraint::Number(99), Some(&graph_indexed)),
+            Err(SubgraphServiceError::BlockConstraintMismatch {
+                reported: Some(_),
+                ..
+            })
+        ));
+        // responses without a reported block can't be verified
+        assert!(matches!(
+            verify_block_constraint(&BlockConstraint::Number(100), None),
+            Err(SubgraphServiceError::BlockConstraintMismatch { reported: None, .. })
+        ));
+    }
+
+    #[test]
+    fn test_is_partial_response() {
+        assert!(is_partial_response(
+            r#"{"data":{"tokens":[]},"errors":[{"message":"indexing error"}]}"#
+        ));
+        assert!(!is_partial_response(r#"{"data":{"tokens":[]}}"#));
+        assert!(!is_partial_response(
+            r#"{"data":{"tokens":[]},"errors":[]}"#
+        ));
+        assert!(!is_partial_response(
+            r#"{"data":null,"errors":[{"message":"query failed"}]}"#
+        ));
+        assert!(!is_partial_response("not json"));
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        let deadline = |headers: &[(&'static str, &'static str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, HeaderValue::from_static(value));
+            }
+            request_deadline(&map)
+        };
+
+        assert_eq!(deadline(&[]), None);
+        assert_eq!(
+            deadline(&[("x-deadline-ms", "1500")]),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            deadline(&[("grpc-timeout", "2S")]),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            deadline(&[("grpc-timeout", "250m")]),
+            Some(Duration::from_millis(250))
+        );
+        // x-deadline-ms is preferred over grpc-timeout
+        assert_eq!(
+            deadline(&[("x-deadline-ms", "100"), ("grpc-timeout", "1H")]),
+            Some(Duration::from_millis(100))
+        );
+        // invalid values are ignored
+        assert_eq!(deadline(&[("x-deadline-ms", "soon")]), None);
+        assert_eq!(deadline(&[("grpc-timeout", "10")]), None);
+        assert_eq!(deadline(&[("grpc-timeout", "-1S")]), None);
+        assert_eq!(deadline(&[("grpc-timeout", "123456789S")]), None);
+    }
+}
@@ -0,0 +1,323 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Liveness and readiness of the service
+//!
+//! Both routes check the dependencies of the service on every call and
+//! report their status. `/healthz` always answers `200 OK` as long as the
+//! service is running, while `/readyz` answers `503 Service Unavailable`
+//! if any of the dependencies is down. The allocations and escrow accounts
+//! are down once their watcher missed [MAX_MISSED_UPDATES] updates, the
+//! network subgraph once its latest block is older than
+//! `subgraphs.network.health_max_block_age_secs`.
+//!
+//! Every call queries graph-node, the database and the network subgraph, so
+//! both routes are rate limited.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use indexer_allocation::Allocation;
+use indexer_monitor::{EscrowAccounts, SubgraphClient};
+use indexer_query::{network_subgraph_meta, NetworkSubgraphMeta};
+use indexer_watcher::Watcher;
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::json;
+use thegraph_core::alloy::primitives::Address;
+
+/// Time after which a dependency is considered down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Updates a watcher can miss before its value is considered stale
+const MAX_MISSED_UPDATES: u32 = 3;
+
+/// Time a stale watcher is given to be updated, e.g. by a retry, while
+/// checking it
+const WATCHER_WAIT: Duration = Duration::from_secs(1);
+
+/// Watcher checked by `/readyz`, updated every `interval`
+#[derive(Clone)]
+pub struct WatcherHealth<T> {
+    watcher: Watcher<T>,
+    interval: Duration,
+}
+
+impl<T> WatcherHealth<T> {
+    pub fn new(watcher: Watcher<T>, interval: Duration) -> Self {
+        Self { watcher, interval }
+    }
+
+    /// Reports the seconds since the last update, fails once the watcher
+    /// missed [MAX_MISSED_UPDATES] updates
+    async fn check(&self) -> DependencyHealth {
+        check(async {
+            let max_age = self.interval * MAX_MISSED_UPDATES;
+            let fresh =
+                tokio::time::timeout(WATCHER_WAIT, self.watcher.wait_until_fresh(max_age)).await;
+            let age = self.watcher.last_success().elapsed().as_secs();
+            match fresh {
+                Ok(result) => result.map(|()| Some(age)),
+                Err(_) => bail!("Last updated {age}s ago"),
+            }
+        })
+        .await
+    }
+}
+
+#[derive(Clone)]
+pub struct ServiceHealthState {
+    pub database: sqlx::PgPool,
+    pub graph_node_client: reqwest::Client,
+    pub graph_node_status_url: Url,
+    pub graph_node_query_base_url: Url,
+    /// `None` if the allocations are not monitored from the network subgraph
+    pub network_subgraph: Option<&'static SubgraphClient>,
+    /// Maximum age of the latest block indexed by the network subgraph
+    pub network_subgraph_max_age: Duration,
+    /// `None` if the allocations are not monitored from the network subgraph
+    pub allocations: Option<WatcherHealth<HashMap<Address, Allocation>>>,
+    /// `None` if the escrow accounts are not monitored from the escrow subgraph
+    pub escrow_accounts: Option<WatcherHealth<EscrowAccounts>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+struct DependencyHealth {
+    status: Status,
+    latency_ms: u64,
+    /// Seconds since the latest block, for the network subgraph, or since the
+    /// last update, for the watchers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ServiceHealth {
+    status: Status,
+    dependencies: Dependencies,
+}
+
+#[derive(Debug, Serialize)]
+struct Dependencies {
+    graph_node_query: DependencyHealth,
+    graph_node_status: DependencyHealth,
+    database: DependencyHealth,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    network_subgraph: Option<DependencyHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allocations: Option<DependencyHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    escrow_accounts: Option<DependencyHealth>,
+}
+
+impl Dependencies {
+    fn status(&self) -> Status {
+        let all_ok = [
+            Some(&self.graph_node_query),
+            Some(&self.graph_node_status),
+            Some(&self.database),
+            self.network_subgraph.as_ref(),
+            self.allocations.as_ref(),
+            self.escrow_accounts.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .all(|dependency| dependency.status == Status::Ok);
+        if all_ok {
+            Status::Ok
+        } else {
+            Status::Error
+        }
+    }
+}
+
+pub async fn healthz(State(state): State<ServiceHealthState>) -> impl IntoResponse {
+    Json(check_dependencies(&state).await)
+}
+
+pub async fn readyz(State(state): State<ServiceHealthState>) -> impl IntoResponse {
+    let health = check_dependencies(&state).await;
+    let status = match health.status {
+        Status::Ok => StatusCode::OK,
+        Status::Error => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
+}
+
+/// Checks the dependencies once, failing with the health report
+/// if any of them is down
+pub async fn check_ready(state: &ServiceHealthState) -> anyhow::Result<()> {
+    let health = check_dependencies(state).await;
+    match health.status {
+        Status::Ok => Ok(()),
+        Status::Error => Err(anyhow!(
+            "Dependencies are not ready: {}",
+            serde_json::to_string(&health.dependencies)?
+        )),
+    }
+}
+
+async fn check_dependencies(state: &ServiceHealthState) -> ServiceHealth {
+    let (
+        graph_node_query,
+        graph_node_status,
+        database,
+        network_subgraph,
+        allocations,
+        escrow_accounts,
+    ) = tokio::join!(
+        check(async {
+            let response = state
+                .graph_node_client
+                .get(state.graph_node_query_base_url.clone())
+                .send()
+                .await?;
+            if response.status().is_server_error() {
+                bail!("Graph node query endpoint returned {}", response.status());
+            }
+            Ok(None)
+        }),
+        check(async {
+            state
+                .graph_node_client
+                .post(state.graph_node_status_url.clone())
+                .json(&json!({ "query": "{ version { version } }" }))
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(None)
+        }),
+        check(async {
+            sqlx::query!("SELECT 1 AS one")
+                .execute(&state.database)
+                .await?;
+            Ok(None)
+        }),
+        async {
+            match state.network_subgraph {
+                Some(network_subgraph) => Some(
+                    check(async {
+                        let response = network_subgraph
+                            .query::<NetworkSubgraphMeta, _>(network_subgraph_meta::Variables)
+                            .await??;
+                        let timestamp = response
+                            .meta
+                            .and_then(|meta| meta.block.timestamp)
+                            .ok_or_else(|| anyhow!("Network subgraph has no block timestamp"))?;
+                        network_subgraph_age(
+                            timestamp,
+                            SystemTime::now(),
+                            state.network_subgraph_max_age,
+                        )
+                        .map(Some)
+                    })
+                    .await,
+                ),
+                None => None,
+            }
+        },
+        async {
+            match &state.allocations {
+                Some(allocations) => Some(allocations.check().await),
+                None => None,
+            }
+        },
+        async {
+            match &state.escrow_accounts {
+                Some(escrow_accounts) => Some(escrow_accounts.check().await),
+                None => None,
+            }
+        },
+    );
+
+    let dependencies = Dependencies {
+        graph_node_query,
+        graph_node_status,
+        database,
+        network_subgraph,
+        allocations,
+        escrow_accounts,
+    };
+    ServiceHealth {
+        status: dependencies.status(),
+        dependencies,
+    }
+}
+
+/// Runs a check that may report an age, failing it after [CHECK_TIMEOUT]
+async fn check(check: impl Future<Output = anyhow::Result<Option<u64>>>) -> DependencyHealth {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("Timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    };
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(age_secs) => DependencyHealth {
+            status: Status::Ok,
+            latency_ms,
+            age_secs,
+            error: None,
+        },
+        Err(error) => DependencyHealth {
+            status: Status::Error,
+            latency_ms,
+            age_secs: None,
+            error: Some(error.to_string()),
+        },
+    }
+}
+
+/// Seconds between the latest block of the network subgraph and `now`,
+/// fails if it is older than `max_age`
+fn network_subgraph_age(
+    block_timestamp: i64,
+    now: SystemTime,
+    max_age: Duration,
+) -> anyhow::Result<u64> {
+    let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+    let age = now.saturating_sub(block_timestamp.max(0) as u64);
+    if age > max_age.as_secs() {
+        bail!("Latest block of the network subgraph is {age}s old");
+    }
+    Ok(age)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::network_subgraph_age;
+
+    #[test]
+    fn test_network_subgraph_age() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let max_age = Duration::from_secs(30 * 60);
+
+        assert_eq!(
+            network_subgraph_age(1_700_000_000 - 60, now, max_age).unwrap(),
+            60
+        );
+        // block timestamps slightly ahead of the local clock
+        assert_eq!(
+            network_subgraph_age(1_700_000_010, now, max_age).unwrap(),
+            0
+        );
+        assert!(network_subgraph_age(1_700_000_000 - 2 * 60 * 60, now, max_age).is_err());
+        assert!(network_subgraph_age(1_700_000_000 - 2 * 60 * 60, now, 3 * max_age).is_ok());
+    }
+}
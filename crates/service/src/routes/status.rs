@@ -3,6 +3,7 @@
 
 use std::collections::HashSet;
 
+use async_graphql::{Name, Variables};
 use async_graphql_axum::GraphQLRequest;
 use axum::{extract::State, response::IntoResponse, Json};
 use graphql::graphql_parser::query as q;
@@ -12,7 +13,10 @@ use thegraph_graphql_http::{
     http_client::{ReqwestExt, ResponseError},
 };
 
-use crate::{error::SubgraphServiceError, service::GraphNodeState};
+use crate::{
+    error::SubgraphServiceError,
+    service::{BlockConstraint, CacheKey, GraphNodeState},
+};
 
 lazy_static::lazy_static! {
     static ref SUPPORTED_ROOT_FIELDS: HashSet<&'static str> =
@@ -101,6 +105,17 @@ pub async fn status(
         ));
     }
 
+    let cache = state.response_cache.as_ref().map(|cache| {
+        let block = block_constraint(&query, &request.variables);
+        let key =
+            serde_json::to_vec(&(&request.query, &request.operation_name, &request.variables))
+                .expect("Status request must be serializable");
+        (cache, CacheKey::new(None, &key, block))
+    });
+    if let Some(response) = cache.as_ref().and_then(|(cache, key)| cache.get(key)) {
+        return Ok(Json(response));
+    }
+
     let result = state
         .graph_node_client
         .post(state.graph_node_status_url.clone())
@@ -108,12 +123,124 @@ pub async fn status(
         .await
         .map_err(|e| SubgraphServiceError::StatusQueryError(e.into()))?;
 
-    result
-        .map(|data| Json(json!({"data": data})))
-        .or_else(|e| match e {
-            ResponseError::Failure { errors } => Ok(Json(json!({
-                "errors": errors,
-            }))),
-            ResponseError::Empty => todo!(),
+    let response = match result {
+        Ok(data) => {
+            let response = json!({"data": data});
+            if let Some((cache, key)) = cache {
+                cache.insert(key, response.clone());
+            }
+            response
+        }
+        Err(ResponseError::Failure { errors }) => json!({
+            "errors": errors,
+        }),
+        Err(ResponseError::Empty) => todo!(),
+    };
+    Ok(Json(response))
+}
+
+/// Returns the block all the root fields of the query are pinned to,
+/// using their `blockNumber` or `blockHash` arguments
+fn block_constraint(query: &q::Document<String>, variables: &Variables) -> BlockConstraint {
+    let mut constraints = query
+        .definitions
+        .iter()
+        .filter_map(|def| match def {
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                Some(&query.selection_set)
+            }
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                Some(selection_set)
+            }
+            _ => None,
         })
+        .flat_map(|selection_set| selection_set.items.iter())
+        .map(|item| match item {
+            q::Selection::Field(field) => field
+                .arguments
+                .iter()
+                .find_map(|(name, value)| block_argument(name, value, variables)),
+            _ => None,
+        });
+
+    let Some(Some(block)) = constraints.next() else {
+        return BlockConstraint::Latest;
+    };
+    if constraints.all(|constraint| constraint.as_ref() == Some(&block)) {
+        block
+    } else {
+        BlockConstraint::Latest
+    }
+}
+
+fn block_argument(
+    name: &str,
+    value: &q::Value<String>,
+    variables: &Variables,
+) -> Option<BlockConstraint> {
+    let value = match value {
+        q::Value::Variable(variable) => variables
+            .get(&Name::new(variable))?
+            .clone()
+            .into_json()
+            .ok()?,
+        q::Value::Int(number) => Value::from(number.as_i64()?),
+        q::Value::String(hash) => Value::from(hash.as_str()),
+        _ => return None,
+    };
+    match (name, value) {
+        ("blockNumber", Value::Number(number)) => number.as_u64().map(BlockConstraint::Number),
+        ("blockHash", Value::String(hash)) => hash.parse().ok().map(BlockConstraint::Hash),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::Variables;
+    use graphql::graphql_parser::query as q;
+    use serde_json::json;
+
+    use super::block_constraint;
+    use crate::service::BlockConstraint;
+
+    const HASH: &str = "0xe0a5c8b9e1e1a4d6d3bc1b2a5d0e8c7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d";
+
+    fn constraint(query: &str, variables: serde_json::Value) -> BlockConstraint {
+        let query: q::Document<String> = q::parse_query(query).unwrap();
+        block_constraint(&query, &Variables::from_json(variables))
+    }
+
+    #[test]
+    fn test_block_constraint() {
+        assert_eq!(
+            constraint("{ indexingStatuses { health } }", json!({})),
+            BlockConstraint::Latest
+        );
+        assert_eq!(
+            constraint(
+                r#"{ blockHashFromNumber(network: "mainnet", blockNumber: 100) }"#,
+                json!({})
+            ),
+            BlockConstraint::Number(100)
+        );
+        assert_eq!(
+            constraint(
+                "query($hash: Bytes!) { blockData(network: \"mainnet\", blockHash: $hash) }",
+                json!({ "hash": HASH })
+            ),
+            BlockConstraint::Hash(HASH.parse().unwrap())
+        );
+        // the response also depends on the latest indexing statuses
+        assert_eq!(
+            constraint(
+                r#"{
+                    blockHashFromNumber(network: "mainnet", blockNumber: 100)
+                    indexingStatuses { health }
+                }"#,
+                json!({})
+            ),
+            BlockConstraint::Latest
+        );
+    }
 }
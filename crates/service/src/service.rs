@@ -1,14 +1,27 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    iter,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use axum::{extract::Request, serve, ServiceExt};
+use axum::{extract::Request, Router, ServiceExt};
+use build_info::chrono::Utc;
 use clap::Parser;
-use indexer_config::{Config, DipsConfig, GraphNodeConfig, SubgraphConfig};
+use indexer_config::{
+    Config, ConfigPrefix, DipsConfig, DipsIndexingConfig, GraphNodeConfig, LogConfig,
+    OpenTelemetryConfig, PartialResponsePolicy, ReceiptStorageConfig, SubgraphConfig,
+};
 use indexer_dips::{
     database::PsqlAgreementStore,
+    dips_agreement_eip712_domain, dips_cancellation_eip712_domain,
+    escalation::flag_unprofitable_agreements,
+    indexing::{GraphNodeScheduler, IndexingRulesScheduler, IndexingScheduler, IndexingTracker},
     ipfs::{IpfsClient, IpfsFetcher},
     price::PriceCalculator,
     proto::indexer::graphprotocol::indexer::dips::indexer_dips_service_server::{
@@ -17,48 +30,125 @@ use indexer_dips::{
     server::{DipsServer, DipsServerContext},
     signers::EscrowSignerValidator,
 };
-use indexer_monitor::{escrow_accounts_v1, DeploymentDetails, SubgraphClient};
+use indexer_listener::Listener;
+use indexer_monitor::{
+    escrow_accounts_v1, DeploymentDetails, EscrowRpcFallback, SubgraphCache, SubgraphClient,
+};
+#[cfg(feature = "sqlite")]
+use indexer_receipt::store::SqliteReceiptStore;
+use indexer_receipt::store::{PgReceiptStore, ReceiptStore};
+use indexer_tap_agent::embedded::{self as tap_agent, EmbeddedAgent};
 use release::IndexerServiceRelease;
 use reqwest::Url;
 use tap_core::tap_eip712_domain;
-use tokio::{net::TcpListener, signal};
+use tokio::signal;
 use tower_http::normalize_path::NormalizePath;
-use tracing::info;
+use tracing::{info, level_filters::LevelFilter, subscriber::set_global_default};
+use tracing_subscriber::{layer::SubscriberExt, FmtSubscriber};
 
-use crate::{cli::Cli, database, metrics::serve_metrics};
+use crate::{
+    cli::{Cli, Command},
+    database,
+    metrics::serve_metrics,
+};
 
+mod allocation_events;
+mod block_constraint;
+mod graph_node_client;
+mod hedging;
+mod prewarm;
+mod query_complexity;
+mod query_nodes;
+mod query_stats;
+mod receipt_ingest;
 mod release;
+mod response_cache;
 mod router;
 mod tap_receipt_header;
+mod tls;
 
+pub use block_constraint::{
+    constrain_request, BlockConstraint, IndexedBlock, InvalidBlockConstraint,
+};
+pub use hedging::{hedged, Hedging};
+pub use query_complexity::{query_nesting, QueryComplexity};
+pub use query_nodes::{required_block, QueryNodes};
+pub use query_stats::{DeploymentStats, QueryStats, QueryStatsSummary};
+pub use response_cache::{CacheKey, ResponseCache};
 pub use router::ServiceRouter;
-pub use tap_receipt_header::TapHeader;
+pub use tap_receipt_header::{decode_receipts, TapHeader};
 
 #[derive(Clone)]
 pub struct GraphNodeState {
     pub graph_node_client: reqwest::Client,
     pub graph_node_status_url: Url,
-    pub graph_node_query_base_url: Url,
+    /// query nodes the queries are routed to
+    pub query_nodes: QueryNodes,
+    /// second request for the slow queries, to another query node
+    pub hedging: Option<Hedging>,
+    /// cache for the responses of the free status and health queries
+    pub response_cache: Option<ResponseCache>,
+    /// what to do with paid queries answered with errors alongside data
+    pub partial_response: PartialResponsePolicy,
 }
 
 const HTTP_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the profitability of the DIPS agreements is checked
+const PROFITABILITY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often the indexing of the DIPS agreements is started and their sync checked
+const INDEXING_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Prints the diagnostics of the configuration, fails if it's invalid
+fn validate_config(config_path: Option<&PathBuf>, online: bool) -> anyhow::Result<()> {
+    let diagnostics = Config::diagnose(ConfigPrefix::Service, config_path, online);
+    if let Some(config) = &diagnostics.config {
+        let domain = tap_eip712_domain(
+            config.blockchain.chain_id as u64,
+            config.blockchain.receipts_verifier_address,
+        );
+        println!(
+            "# TAP EIP-712 domain separator {}, tap-agent must report the same",
+            domain.separator()
+        );
+    }
+    print!("{diagnostics}");
+    if !diagnostics.is_valid() {
+        return Err(anyhow!("Invalid configuration"));
+    }
+    Ok(())
+}
 
 /// Run the subgraph indexer service
 pub async fn run() -> anyhow::Result<()> {
     // Parse command line and environment arguments
     let cli = Cli::parse();
 
+    if let Some(Command::ValidateConfig { online }) = cli.command {
+        return validate_config(cli.config.as_ref(), online);
+    }
+
     // Load the service configuration
-    let config = Config::parse(indexer_config::ConfigPrefix::Service, cli.config.as_ref())
-        .map_err(|e| {
-            tracing::error!(
-                "Invalid configuration file `{}`: {}, if a value is missing you can also use \
+    let config = Config::parse(ConfigPrefix::Service, cli.config.as_ref());
+
+    // Tracing depends on the configuration to know where spans are exported,
+    // configuration errors are logged once it's set up
+    init_tracing(
+        config
+            .as_ref()
+            .ok()
+            .and_then(|config| config.opentelemetry.as_ref()),
+        config.as_ref().ok().and_then(|config| config.log.as_ref()),
+    )?;
+
+    let config = config.map_err(|e| {
+        tracing::error!(
+            "Invalid configuration file `{}`: {}, if a value is missing you can also use \
                 --config to fill the rest of the values",
-                cli.config.unwrap_or_default().display(),
-                e
-            );
-            anyhow!(e)
-        })?;
+            cli.config.clone().unwrap_or_default().display(),
+            e
+        );
+        anyhow!(e)
+    })?;
 
     // Parse basic configurations
     build_info::build_info!(fn build_info);
@@ -86,27 +176,151 @@ pub async fn run() -> anyhow::Result<()> {
 
     // Establish Database connection necessary for serving indexer management
     // requests with defined schema
-    // Note: Typically, you'd call `sqlx::migrate!();` here to sync the models
-    // which defaults to files in  "./migrations" to sync the database;
-    // however, this can cause conflicts with the migrations run by indexer
-    // agent. Hence we leave syncing and migrating entirely to the agent and
-    // assume the models are up to date in the service.
-    let database =
-        database::connect(config.database.clone().get_formated_postgres_url().as_ref()).await;
-
-    let domain_separator = tap_eip712_domain(
-        config.blockchain.chain_id as u64,
-        config.blockchain.receipts_verifier_address,
-    );
+    // Note: the migrations are left to indexer agent unless asked for, they
+    // could conflict with the ones it runs otherwise.
+    let database = database::connect(&config.database).await;
+    if cli.migrate || matches!(cli.command, Some(Command::Migrate)) {
+        let applied = indexer_schema::run_migrations(&database).await?;
+        info!(count = applied.len(), "Database migrated");
+        if matches!(cli.command, Some(Command::Migrate)) {
+            return Ok(());
+        }
+    }
+    tokio::spawn(database::monitor_pool(database.clone()));
+    let mut required_tables = database::REQUIRED_TABLES.to_vec();
+    if config.service.api_key_admin_token.is_some() {
+        required_tables.extend(database::API_KEY_TABLES);
+    }
+    if config.service.allocation_override_admin_token.is_some() {
+        required_tables.extend(database::ALLOCATION_OVERRIDE_TABLES);
+    }
+    if config.service.fees_summary.is_some() {
+        required_tables.extend(database::FEES_SUMMARY_TABLES);
+    }
+    if config.service.receipt_ingest.is_some() {
+        required_tables.push(database::DEFERRED_QUERIES_TABLE);
+    }
+    if config.service.query_blocklist.is_some() {
+        required_tables.push(database::QUERY_BLOCKLIST_TABLE);
+    }
+    if config.dips.is_some() {
+        required_tables.push(database::DIPS_AGREEMENTS_TABLE);
+    }
+    if let Some(indexing) = config.dips.as_ref().and_then(|dips| dips.indexing.as_ref()) {
+        required_tables.push(database::DIPS_INDEXING_TABLE);
+        if matches!(indexing, DipsIndexingConfig::IndexingRules) {
+            required_tables.push(database::INDEXING_RULES_TABLE);
+        }
+    }
+    let receipt_store: Arc<dyn ReceiptStore> = match &config.service.receipt_storage {
+        ReceiptStorageConfig::Postgres => {
+            let diff = indexer_schema::check_schema(&database, database::RECEIPT_REQUEST_ID_TABLES)
+                .await?;
+            let request_ids = diff.missing_tables.is_empty() && diff.missing_columns.is_empty();
+            if !request_ids {
+                tracing::warn!(
+                    "The receipts tables have no request_id column, refusing the requests \
+                    paid with more than one receipt until indexer-agent runs the migration \
+                    adding it, or indexer-service is started with --migrate"
+                );
+            }
+            Arc::new(PgReceiptStore::new(database.clone()).with_request_ids(request_ids))
+        }
+        ReceiptStorageConfig::Sqlite { path } => {
+            if cli.with_tap_agent {
+                return Err(anyhow!(
+                    "tap-agent can't read the receipts stored in SQLite, \
+                    remove --with-tap-agent or [service.receipt_storage]"
+                ));
+            }
+            let store = sqlite_receipt_store(path).await?;
+            tracing::warn!(
+                path = %path.display(),
+                "Storing the receipts in SQLite, they will never be redeemed"
+            );
+            required_tables.retain(|table| !database::RECEIPT_TABLES.contains(&table.name));
+            store
+        }
+    };
+    indexer_schema::verify_schema(&database, &required_tables, cli.strict_schema).await?;
+    // every receipt would fail to be stored and its fees would be lost, even
+    // without --strict-schema
+    if matches!(
+        config.service.receipt_storage,
+        ReceiptStorageConfig::Postgres
+    ) {
+        let receipt_tables: Vec<_> = required_tables
+            .iter()
+            .filter(|table| database::RECEIPT_TABLES.contains(&table.name))
+            .copied()
+            .collect();
+        let diff = indexer_schema::check_schema(&database, &receipt_tables).await?;
+        if !diff.missing_tables.is_empty() || !diff.missing_columns.is_empty() {
+            return Err(anyhow!(
+                "The receipts can't be stored in this database, upgrade indexer-agent \
+                so it runs the missing migrations or start with --migrate:\n{}",
+                indexer_schema::SchemaDiff {
+                    missing_tables: diff.missing_tables,
+                    missing_columns: diff.missing_columns,
+                    ..Default::default()
+                }
+            ));
+        }
+    }
 
-    let host_and_port = config.service.host_and_port;
-    let indexer_address = config.indexer.indexer_address;
+    // The configuration is parsed again for the agent, which keeps its own
+    let tap_agent = if cli.with_tap_agent {
+        let agent_config =
+            Config::parse(ConfigPrefix::Service, cli.config.as_ref()).map_err(|e| anyhow!(e))?;
+        Some(tap_agent::start(agent_config, database.clone(), cli.strict_schema).await)
+    } else {
+        None
+    };
 
+    let chain_id = config.blockchain.chain_id as u64;
+    let domain_separator = tap_eip712_domain(chain_id, config.blockchain.receipts_verifier_address);
+
+    let hosts_and_ports: Vec<SocketAddr> = iter::once(config.service.host_and_port)
+        .chain(config.service.additional_hosts_and_ports.iter().copied())
+        .collect();
+    let prewarm = config.service.prewarm;
+    let revoked_signers_max_age = config
+        .service
+        .tap
+        .receipt_timestamp
+        .as_ref()
+        .map(|receipt_timestamp| receipt_timestamp.max_age_secs);
+    let tls_config = config.service.tls.clone();
+    let listener_config = config.service.listener.clone();
+    let indexer_address = config.indexer.indexer_address;
+    let escrow_rpc_fallback = config
+        .subgraphs
+        .escrow
+        .rpc_fallback
+        .as_ref()
+        .map(|fallback| {
+            EscrowRpcFallback::new(
+                http_client.clone(),
+                fallback.rpc_url.clone(),
+                fallback.escrow_address,
+                fallback.max_subgraph_lag_secs,
+            )
+            .with_signers(fallback.signers.clone())
+        });
+    let subgraph_cache_max_age = config.subgraphs.cache_max_age_secs;
+    let subgraph_cache = config
+        .subgraphs
+        .cache_directory
+        .clone()
+        .map(|directory| SubgraphCache::new(directory, subgraph_cache_max_age));
+
+    let graph_node_status_url = config.graph_node.status_url.clone();
     let router = ServiceRouter::builder()
         .database(database.clone())
-        .domain_separator(domain_separator.clone())
+        .receipt_store(receipt_store)
+        .domain_separator(domain_separator)
         .graph_node(config.graph_node)
-        .http_client(http_client)
+        .http_client(http_client.clone())
         .release(release)
         .indexer(config.indexer)
         .service(config.service)
@@ -114,27 +328,53 @@ pub async fn run() -> anyhow::Result<()> {
         .timestamp_buffer_secs(config.tap.rav_request.timestamp_buffer_secs)
         .network_subgraph(network_subgraph, config.subgraphs.network)
         .escrow_subgraph(escrow_subgraph, config.subgraphs.escrow)
+        .maybe_subgraph_cache(subgraph_cache.clone())
+        .maybe_dips_agreements_auth_token(
+            config
+                .dips
+                .as_ref()
+                .and_then(|dips| dips.agreements_auth_token.clone()),
+        )
+        .maybe_log_admin_token(config.log.and_then(|log| log.admin_token))
         .build();
 
-    serve_metrics(config.metrics.get_socket_addr());
-
-    tracing::info!(
-        address = %host_and_port,
-        "Serving requests",
+    serve_metrics(
+        config.metrics.listener.clone(),
+        config.metrics.get_socket_addrs(),
+        tap_agent
+            .as_ref()
+            .map_or_else(Router::new, EmbeddedAgent::routes),
     );
+
     if let Some(dips) = config.dips.as_ref() {
         let DipsConfig {
             host,
             port,
             allowed_payers,
+            agreements_auth_token,
+            ipfs_url,
+            indexing,
         } = dips;
 
         let addr = format!("{}:{}", host, port)
             .parse()
             .expect("invalid dips host port");
 
+        let ipfs_url = ipfs_url
+            .as_ref()
+            .or(config
+                .service
+                .subgraph_manifests
+                .as_ref()
+                .map(|manifests| &manifests.ipfs_url))
+            .ok_or_else(|| {
+                anyhow!(
+                    "The manifests of the DIPS agreements can't be read, \
+                    set dips.ipfs_url or service.subgraph_manifests.ipfs_url"
+                )
+            })?;
         let ipfs_fetcher: Arc<dyn IpfsFetcher> =
-            Arc::new(IpfsClient::new("https://api.thegraph.com/ipfs/").unwrap());
+            Arc::new(IpfsClient::new(http_client, ipfs_url.clone()));
 
         // TODO: Try to re-use the same watcher for both DIPS and TAP
         let watcher = escrow_accounts_v1(
@@ -142,24 +382,45 @@ pub async fn run() -> anyhow::Result<()> {
             indexer_address,
             Duration::from_secs(500),
             true,
+            escrow_rpc_fallback,
+            subgraph_cache,
+            revoked_signers_max_age,
         )
         .await
-        .expect("Failed to create escrow accounts watcher");
+        .expect("Failed to create escrow accounts watcher")
+        .into_receiver();
 
-        let ctx = DipsServerContext {
+        let ctx = Arc::new(DipsServerContext {
             store: Arc::new(PsqlAgreementStore {
                 pool: database.clone(),
             }),
             ipfs_fetcher,
             price_calculator: PriceCalculator::default(),
             signer_validator: Arc::new(EscrowSignerValidator::new(watcher)),
-        };
+        });
+
+        tokio::spawn(watch_agreements_profitability(ctx.clone()));
+
+        if let Some(indexing) = indexing {
+            let scheduler: Arc<dyn IndexingScheduler> = match indexing {
+                DipsIndexingConfig::IndexingRules => Arc::new(IndexingRulesScheduler {
+                    pool: database.clone(),
+                }),
+                DipsIndexingConfig::GraphNode { admin_url } => {
+                    Arc::new(GraphNodeScheduler::new(admin_url.clone()))
+                }
+            };
+            let tracker = IndexingTracker::new(ctx.store.clone(), scheduler, graph_node_status_url);
+            tokio::spawn(tracker.run(INDEXING_CHECK_INTERVAL));
+        }
 
         let dips = DipsServer {
-            ctx: Arc::new(ctx),
+            ctx,
             expected_payee: indexer_address,
             allowed_payers: allowed_payers.clone(),
-            domain: domain_separator,
+            domain: dips_agreement_eip712_domain(chain_id),
+            cancellation_domain: dips_cancellation_eip712_domain(chain_id),
+            agreements_auth_token: agreements_auth_token.clone(),
         };
 
         info!("starting dips grpc server on {}", addr);
@@ -171,17 +432,135 @@ pub async fn run() -> anyhow::Result<()> {
         });
     }
 
-    let listener = TcpListener::bind(&host_and_port)
-        .await
-        .expect("Failed to bind to indexer-service port");
+    let rustls_config = match tls_config {
+        Some(tls_config) => Some(tls::rustls_config(tls_config).await?),
+        None => None,
+    };
 
-    let app = router.create_router().await?;
+    // When pre-warming, the ports are only bound once everything needed by
+    // paid queries is loaded, so no query is accepted before that
+    let (listeners, app) = if prewarm {
+        let app = router.create_router().await?;
+        (
+            Listener::bind_all(&listener_config, &hosts_and_ports).await?,
+            app,
+        )
+    } else {
+        let listeners = Listener::bind_all(&listener_config, &hosts_and_ports).await?;
+        (listeners, router.create_router().await?)
+    };
+    for listener in &listeners {
+        tracing::info!(address = %listener, "Serving requests");
+    }
     let router = NormalizePath::trim_trailing_slash(app);
-    //
-    let service = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(router);
-    Ok(serve(listener, service)
-        .with_graceful_shutdown(shutdown_handler())
-        .await?)
+    let result = match rustls_config {
+        Some(rustls_config) => {
+            let service =
+                ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(router);
+            // shared by the servers of every address, shut down together
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_handler().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            let mut servers = Vec::new();
+            for listener in listeners {
+                let Listener::Tcp(listener) = listener else {
+                    return Err(anyhow!("TLS is not supported on a Unix domain socket"));
+                };
+                servers.push(tokio::spawn(
+                    axum_server::from_tcp_rustls(listener.into_std()?, rustls_config.clone())
+                        .handle(handle.clone())
+                        .serve(service.clone()),
+                ));
+            }
+            let mut result = Ok(());
+            for server in servers {
+                result = result.and(server.await?);
+            }
+            result
+        }
+        None => indexer_listener::serve_all(listeners, router, shutdown_handler()).await,
+    };
+
+    if let Some(tap_agent) = tap_agent {
+        tap_agent.stop().await;
+    }
+
+    // Export the spans that are still buffered
+    indexer_telemetry::shutdown();
+
+    Ok(result?)
+}
+
+/// Flags the DIPS agreements whose escalated price is below the minimum price
+/// Receipt store of `[service.receipt_storage] backend = "sqlite"`
+#[cfg(feature = "sqlite")]
+async fn sqlite_receipt_store(path: &Path) -> anyhow::Result<Arc<dyn ReceiptStore>> {
+    Ok(Arc::new(SqliteReceiptStore::connect(path).await?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn sqlite_receipt_store(_: &Path) -> anyhow::Result<Arc<dyn ReceiptStore>> {
+    Err(anyhow!(
+        "The receipts can't be stored in SQLite, indexer-service was built \
+        without the `sqlite` feature"
+    ))
+}
+
+async fn watch_agreements_profitability(ctx: Arc<DipsServerContext>) {
+    let mut interval = tokio::time::interval(PROFITABILITY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        match flag_unprofitable_agreements(ctx.store.as_ref(), &ctx.price_calculator, Utc::now())
+            .await
+        {
+            Ok(flagged) => {
+                for agreement_id in flagged {
+                    tracing::warn!(%agreement_id, "DIPS agreement is no longer profitable");
+                }
+            }
+            Err(error) => {
+                tracing::error!(%error, "Failed to check the profitability of the DIPS agreements")
+            }
+        }
+    }
+}
+
+fn init_tracing(
+    opentelemetry: Option<&OpenTelemetryConfig>,
+    log: Option<&LogConfig>,
+) -> anyhow::Result<()> {
+    // Tracing setup, the levels are filtered by a layer reloaded when they
+    // are overridden at runtime
+    let subscriber_builder: tracing_subscriber::fmt::SubscriberBuilder<
+        tracing_subscriber::fmt::format::DefaultFields,
+        tracing_subscriber::fmt::format::Format,
+        LevelFilter,
+    > = FmtSubscriber::builder().with_max_level(LevelFilter::TRACE);
+    let otlp_layer = opentelemetry
+        .map(|config| indexer_telemetry::init_tracer(config, "indexer-service"))
+        .transpose()?
+        .map(indexer_telemetry::layer);
+    set_global_default(
+        subscriber_builder
+            .with_ansi(true)
+            .pretty()
+            .finish()
+            .with(indexer_telemetry::reloadable_filter())
+            .with(indexer_telemetry::throttle_layer(
+                log.and_then(|log| log.throttle.as_ref()),
+            ))
+            .with(otlp_layer),
+    )
+    .expect(
+        "Could not set up global default subscriber for logger, check \
+        environmental variable `RUST_LOG`",
+    );
+    Ok(())
 }
 async fn start_dips_server(addr: SocketAddr, service: impl IndexerDipsService) {
     tonic::transport::Server::builder()
@@ -205,11 +584,13 @@ async fn create_subgraph_client(
                     graph_node.query_url.clone(),
                     deployment,
                 )
+                .with_subscription_url(subgraph_config.local_subscription_url(graph_node))
             }),
             DeploymentDetails::for_query_url_with_token(
                 subgraph_config.query_url.clone(),
                 subgraph_config.query_auth_token.clone(),
-            ),
+            )
+            .with_subscription_url(subgraph_config.remote_subscription_url()),
         )
         .await,
     ))
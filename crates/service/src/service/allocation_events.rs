@@ -0,0 +1,219 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recording of the lifecycle of the allocations observed by the allocations
+//! monitor, see [indexer_schema::AllocationEvent]
+
+use std::collections::{HashMap, HashSet};
+
+use indexer_allocation::{Allocation, AllocationStatus};
+use indexer_monitor::{AllocationWatcher, SubgraphClient};
+use indexer_query::{network_subgraph_meta, NetworkSubgraphMeta};
+use indexer_schema::{allocation_events_table_exists, record_allocation_event, AllocationEvent};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use thegraph_core::alloy::primitives::Address;
+
+/// Events implied by the allocations returned by the network subgraph,
+/// along with the ones of the allocations no longer returned
+fn observed_events(
+    previous: &HashMap<Address, Allocation>,
+    current: &HashMap<Address, Allocation>,
+) -> Vec<(Address, AllocationEvent)> {
+    let mut events = Vec::new();
+    for allocation in current.values() {
+        events.push((allocation.id, AllocationEvent::Created));
+        // not denied deployments have a `deniedAt` of 0
+        let denied = allocation
+            .subgraph_deployment
+            .denied_at
+            .is_some_and(|denied_at| denied_at > 0);
+        match allocation.status {
+            AllocationStatus::Active if !denied => {
+                events.push((allocation.id, AllocationEvent::Eligible))
+            }
+            AllocationStatus::Active | AllocationStatus::Null => {}
+            _ => events.push((allocation.id, AllocationEvent::Closed)),
+        }
+    }
+    events.extend(
+        previous
+            .keys()
+            .filter(|allocation_id| !current.contains_key(allocation_id))
+            .map(|allocation_id| (*allocation_id, AllocationEvent::Closed)),
+    );
+    events
+}
+
+/// Timestamp of the latest block of the network subgraph, the events are
+/// recorded at the block they were observed at rather than when the
+/// allocations were polled
+async fn observed_at(network_subgraph: Option<&SubgraphClient>) -> Option<DateTime<Utc>> {
+    let response = network_subgraph?
+        .query::<NetworkSubgraphMeta, _>(network_subgraph_meta::Variables)
+        .await;
+    let timestamp = match response {
+        Ok(Ok(data)) => data.meta.and_then(|meta| meta.block.timestamp),
+        Ok(Err(error)) => {
+            tracing::debug!(%error, "Failed to read the block of the network subgraph");
+            None
+        }
+        Err(error) => {
+            tracing::debug!(%error, "Failed to read the block of the network subgraph");
+            None
+        }
+    };
+    DateTime::from_timestamp(timestamp?, 0)
+}
+
+/// Records the events of the allocations observed by `allocations`. Never
+/// returns while the watcher is open
+///
+/// Nothing is recorded if the database has no `allocation_events` table,
+/// e.g. while indexer-agent has not run its migration yet.
+pub(super) async fn record_allocation_events(
+    pgpool: PgPool,
+    network_subgraph: Option<&'static SubgraphClient>,
+    mut allocations: AllocationWatcher,
+) {
+    match allocation_events_table_exists(&pgpool).await {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::info!(
+                "The database has no allocation_events table, not recording the \
+                allocation events"
+            );
+            return;
+        }
+        Err(error) => {
+            tracing::warn!(%error, "Failed to check the allocation_events table, not recording the allocation events");
+            return;
+        }
+    }
+
+    let mut previous = HashMap::new();
+    // events stored, or already stored by another replica, of the allocations
+    // still returned by the network subgraph
+    let mut recorded = HashSet::new();
+    loop {
+        let current = allocations.borrow_and_update().clone();
+        let events: Vec<_> = observed_events(&previous, &current)
+            .into_iter()
+            .filter(|event| !recorded.contains(event))
+            .collect();
+        let observed_at = match events.is_empty() {
+            true => None,
+            false => observed_at(network_subgraph).await,
+        };
+        for (allocation_id, event) in events {
+            match record_allocation_event(&pgpool, allocation_id, event, None, observed_at).await {
+                Ok(_) => {
+                    recorded.insert((allocation_id, event));
+                }
+                Err(error) => {
+                    // the others are retried with the next allocations
+                    tracing::warn!(%error, %allocation_id, %event, "Failed to record an allocation event");
+                    break;
+                }
+            }
+        }
+        // the allocations no longer returned are not observed again
+        recorded.retain(|(allocation_id, _)| current.contains_key(allocation_id));
+        previous = current;
+        if allocations.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use indexer_allocation::AllocationStatus;
+    use indexer_schema::{allocation_events, AllocationEvent};
+    use sqlx::PgPool;
+    use test_assets::{ALLOCATION_ID_0, INDEXER_ALLOCATIONS};
+    use tokio::sync::watch;
+
+    use super::{observed_events, record_allocation_events};
+
+    #[test]
+    fn test_observed_events() {
+        let mut allocation = INDEXER_ALLOCATIONS[&ALLOCATION_ID_0].clone();
+        allocation.status = AllocationStatus::Active;
+        allocation.subgraph_deployment.denied_at = Some(0);
+        let open = HashMap::from([(ALLOCATION_ID_0, allocation.clone())]);
+        assert_eq!(
+            observed_events(&HashMap::new(), &open),
+            vec![
+                (ALLOCATION_ID_0, AllocationEvent::Created),
+                (ALLOCATION_ID_0, AllocationEvent::Eligible)
+            ]
+        );
+
+        allocation.status = AllocationStatus::Closed;
+        let closed = HashMap::from([(ALLOCATION_ID_0, allocation)]);
+        assert_eq!(
+            observed_events(&open, &closed),
+            vec![
+                (ALLOCATION_ID_0, AllocationEvent::Created),
+                (ALLOCATION_ID_0, AllocationEvent::Closed)
+            ]
+        );
+
+        // no longer returned once the recently closed buffer is over
+        assert_eq!(
+            observed_events(&open, &HashMap::new()),
+            vec![(ALLOCATION_ID_0, AllocationEvent::Closed)]
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_record_allocation_events(pgpool: PgPool) {
+        let mut allocation = INDEXER_ALLOCATIONS[&ALLOCATION_ID_0].clone();
+        allocation.status = AllocationStatus::Active;
+        allocation.subgraph_deployment.denied_at = Some(0);
+        let (tx, rx) = watch::channel(HashMap::from([(ALLOCATION_ID_0, allocation)]));
+        let recorder = tokio::spawn(record_allocation_events(pgpool.clone(), None, rx));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.send(HashMap::new()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(tx);
+        recorder.await.unwrap();
+
+        let events: Vec<_> = allocation_events(&pgpool, ALLOCATION_ID_0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                AllocationEvent::Created,
+                AllocationEvent::Eligible,
+                AllocationEvent::Closed
+            ]
+        );
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_no_allocation_events_table(pgpool: PgPool) {
+        sqlx::query!("DROP TABLE allocation_events")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let (_tx, rx) = watch::channel(HashMap::new());
+        // returns right away instead of failing on every change
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            record_allocation_events(pgpool, None, rx),
+        )
+        .await
+        .unwrap();
+    }
+}
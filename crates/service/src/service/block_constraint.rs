@@ -0,0 +1,222 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{fmt, str::FromStr};
+
+use graphql::graphql_parser::query as q;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thegraph_core::alloy::primitives::{BlockHash, BlockNumber};
+
+use super::query_nesting;
+
+/// Queries nested deeper than this are not pinned to a block, the parser
+/// could run out of stack
+const MAX_PARSED_NESTING: usize = 256;
+
+/// Block the response of a query depends on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BlockConstraint {
+    /// Response may change with every new block
+    Latest,
+    Number(BlockNumber),
+    Hash(BlockHash),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid block constraint `{0}`, expected a block number or a block hash")]
+pub struct InvalidBlockConstraint(String);
+
+/// Parses the value of the `graph-block-constraint` header, either
+/// a block number or a `0x` prefixed block hash
+impl FromStr for BlockConstraint {
+    type Err = InvalidBlockConstraint;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidBlockConstraint(value.to_string());
+        if value.starts_with("0x") {
+            BlockHash::from_str(value)
+                .map(BlockConstraint::Hash)
+                .map_err(|_| invalid())
+        } else {
+            BlockNumber::from_str(value)
+                .map(BlockConstraint::Number)
+                .map_err(|_| invalid())
+        }
+    }
+}
+
+impl fmt::Display for BlockConstraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockConstraint::Latest => f.write_str("latest"),
+            BlockConstraint::Number(number) => write!(f, "{number}"),
+            BlockConstraint::Hash(hash) => write!(f, "{hash}"),
+        }
+    }
+}
+
+/// Block reported by graph-node in the `graph-indexed` header
+/// of a query response
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct IndexedBlock {
+    pub hash: Option<BlockHash>,
+    pub number: Option<BlockNumber>,
+}
+
+impl BlockConstraint {
+    /// Returns `true` if the response was executed at the block
+    /// of the constraint
+    pub fn is_satisfied_by(&self, block: &IndexedBlock) -> bool {
+        match self {
+            BlockConstraint::Latest => true,
+            BlockConstraint::Number(number) => block.number == Some(*number),
+            BlockConstraint::Hash(hash) => block.hash == Some(*hash),
+        }
+    }
+}
+
+/// Pins a query request to the block of `constraint`
+///
+/// graph-node doesn't know about the `graph-block-constraint` header, so the
+/// `block` argument of the top-level fields of the query is set instead,
+/// replacing the one of the query if any. The introspection fields don't take
+/// a `block` argument and are left as they are. Returns `None` if the query
+/// can't be parsed.
+pub fn constrain_request(request: &str, constraint: &BlockConstraint) -> Option<String> {
+    let block = match constraint {
+        BlockConstraint::Latest => return Some(request.to_string()),
+        // block numbers are a GraphQL `Int`
+        BlockConstraint::Number(number) => {
+            ("number", q::Value::Int(i32::try_from(*number).ok()?.into()))
+        }
+        BlockConstraint::Hash(hash) => ("hash", q::Value::String(hash.to_string())),
+    };
+    let block = q::Value::Object([(block.0.to_string(), block.1)].into());
+
+    let mut request: Map<String, Value> = serde_json::from_str(request).ok()?;
+    let query = request.get("query")?.as_str()?;
+    if query_nesting(query) > MAX_PARSED_NESTING {
+        return None;
+    }
+    let mut document: q::Document<String> = q::parse_query(query).ok()?;
+    for definition in &mut document.definitions {
+        let selection_set = match definition {
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                &mut query.selection_set
+            }
+            q::Definition::Fragment(fragment) if matches!(&fragment.type_condition, q::TypeCondition::On(name) if name == "Query") => {
+                &mut fragment.selection_set
+            }
+            _ => continue,
+        };
+        constrain_selection_set(selection_set, &block);
+    }
+    let query = document.to_string();
+    request.insert("query".to_string(), Value::String(query));
+    serde_json::to_string(&request).ok()
+}
+
+fn constrain_selection_set<'a>(
+    selection_set: &mut q::SelectionSet<'a, String>,
+    block: &q::Value<'a, String>,
+) {
+    for selection in &mut selection_set.items {
+        match selection {
+            q::Selection::Field(field) if !field.name.starts_with("__") => {
+                field.arguments.retain(|(name, _)| name != "block");
+                field.arguments.push(("block".to_string(), block.clone()));
+            }
+            q::Selection::InlineFragment(fragment) => {
+                constrain_selection_set(&mut fragment.selection_set, block)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use thegraph_core::alloy::primitives::b256;
+
+    use super::{constrain_request, BlockConstraint, IndexedBlock};
+
+    const HASH: &str = "0xe0a5c8b9e1e1a4d6d3bc1b2a5d0e8c7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d";
+
+    #[test]
+    fn test_parse_block_constraint() {
+        assert_eq!(
+            "100".parse::<BlockConstraint>().unwrap(),
+            BlockConstraint::Number(100)
+        );
+        assert_eq!(
+            HASH.parse::<BlockConstraint>().unwrap(),
+            BlockConstraint::Hash(b256!(
+                "e0a5c8b9e1e1a4d6d3bc1b2a5d0e8c7f6a5b4c3d2e1f0a9b8c7d6e5f4a3b2c1d"
+            ))
+        );
+        assert!("0x01".parse::<BlockConstraint>().is_err());
+        assert!("latest".parse::<BlockConstraint>().is_err());
+    }
+
+    #[test]
+    fn test_indexed_block_satisfies_constraint() {
+        let block: IndexedBlock =
+            serde_json::from_str(&format!(r#"{{"hash":"{HASH}","number":100}}"#)).unwrap();
+
+        assert!(BlockConstraint::Number(100).is_satisfied_by(&block));
+        assert!(!BlockConstraint::Number(101).is_satisfied_by(&block));
+        assert!(HASH
+            .parse::<BlockConstraint>()
+            .unwrap()
+            .is_satisfied_by(&block));
+
+        let unknown_block = IndexedBlock {
+            hash: None,
+            number: None,
+        };
+        assert!(!BlockConstraint::Number(100).is_satisfied_by(&unknown_block));
+        assert!(BlockConstraint::Latest.is_satisfied_by(&unknown_block));
+    }
+
+    #[test]
+    fn test_constrain_request() {
+        let request = json!({
+            "query": "query($block: Block_height) { tokens(block: $block) { id } ...Meta __schema { types { name } } } fragment Meta on Query { _meta { block { number } } }",
+            "variables": { "block": { "number": 5 } },
+        })
+        .to_string();
+
+        let constrained = constrain_request(&request, &BlockConstraint::Number(100)).unwrap();
+        let constrained: serde_json::Value = serde_json::from_str(&constrained).unwrap();
+        let query = constrained["query"].as_str().unwrap();
+        assert_eq!(query.matches("block: {number: 100}").count(), 2);
+        assert!(!query.contains("$block)"));
+        assert!(!query.contains("__schema("));
+        assert_eq!(
+            constrained["variables"],
+            json!({ "block": { "number": 5 } })
+        );
+
+        let constrained = constrain_request(&request, &HASH.parse().unwrap()).unwrap();
+        assert_eq!(
+            constrained
+                .matches(&format!(r#"block: {{hash: \"{HASH}\"}}"#))
+                .count(),
+            2
+        );
+
+        assert_eq!(
+            constrain_request(&request, &BlockConstraint::Latest).unwrap(),
+            request
+        );
+        assert!(
+            constrain_request(r#"{"query": "{ tokens {"}"#, &BlockConstraint::Number(1)).is_none()
+        );
+        assert!(constrain_request(&request, &BlockConstraint::Number(u64::MAX)).is_none());
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use indexer_config::GraphNodeClientConfig;
+use tower::{Layer, Service};
+
+use crate::metrics::GRAPH_NODE_CONNECTIONS;
+
+/// Queries taking longer are aborted
+const GRAPH_NODE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client shared by the routes forwarding requests to graph-node
+///
+/// Connections are kept open and reused by the next queries, with keepalives
+/// so that idle connections are not dropped by graph-node or a proxy.
+pub fn graph_node_client(config: &GraphNodeClientConfig) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .tcp_nodelay(true)
+        .timeout(GRAPH_NODE_TIMEOUT)
+        .pool_max_idle_per_host(config.max_idle_connections)
+        .pool_idle_timeout(config.idle_timeout_secs)
+        .tcp_keepalive(config.keepalive_interval_secs)
+        .http2_keep_alive_interval(config.keepalive_interval_secs)
+        .http2_keep_alive_timeout(config.keepalive_timeout_secs)
+        .http2_keep_alive_while_idle(true)
+        .connector_layer(CountConnectionsLayer);
+    if config.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(path) = &config.unix_socket_path {
+        tracing::info!(path = %path.display(), "Connecting to graph-node through a Unix socket");
+        builder = builder.unix_socket(path.clone());
+    }
+    Ok(builder.build()?)
+}
+
+/// Counts the connections opened by the client in [GRAPH_NODE_CONNECTIONS]
+#[derive(Clone)]
+struct CountConnectionsLayer;
+
+impl<S> Layer<S> for CountConnectionsLayer {
+    type Service = CountConnections<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CountConnections { inner }
+    }
+}
+
+#[derive(Clone)]
+struct CountConnections<S> {
+    inner: S,
+}
+
+impl<S, R> Service<R> for CountConnections<S>
+where
+    S: Service<R>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        GRAPH_NODE_CONNECTIONS.inc();
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indexer_config::GraphNodeClientConfig;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use super::graph_node_client;
+    use crate::metrics::GRAPH_NODE_CONNECTIONS;
+
+    #[tokio::test]
+    async fn test_connections_are_reused() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{}"))
+            .mount(&server)
+            .await;
+
+        let client = graph_node_client(&GraphNodeClientConfig {
+            max_idle_connections: 10,
+            idle_timeout_secs: Duration::from_secs(60),
+            keepalive_interval_secs: Duration::from_secs(30),
+            keepalive_timeout_secs: Duration::from_secs(20),
+            http2_prior_knowledge: false,
+            unix_socket_path: None,
+        })
+        .unwrap();
+
+        let opened = GRAPH_NODE_CONNECTIONS.get();
+        for _ in 0..3 {
+            let response = client.post(server.uri()).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "{}");
+        }
+        assert_eq!(GRAPH_NODE_CONNECTIONS.get() - opened, 1.0);
+    }
+}
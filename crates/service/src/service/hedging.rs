@@ -0,0 +1,276 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hedges the slow queries with a second request to another query node
+//!
+//! The delay after which a query is hedged is the configured percentile of
+//! the latencies of the recent queries, so that only the slowest queries
+//! are sent twice. The latencies of the failed queries, and of the queries
+//! cancelled by a faster hedge, are recorded too. At most 10% of the queries
+//! are hedged, so a slow query node doesn't get every query sent twice.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use indexer_config::HedgingConfig;
+
+use crate::metrics::HEDGED_QUERIES;
+
+/// Latencies of the recent queries the percentile is computed from
+const SAMPLES: usize = 1000;
+
+/// The delay is computed again every time this many queries are recorded,
+/// queries are not hedged until then
+const REFRESH_EVERY: usize = 100;
+
+/// Queries recorded for each hedge allowed
+const QUERIES_PER_HEDGE: u32 = 10;
+
+/// Hedges that can be sent in a burst, out of the budget saved
+const MAX_HEDGE_BURST: u32 = 10;
+
+struct Latencies {
+    samples: VecDeque<Duration>,
+    recorded: usize,
+    delay: Option<Duration>,
+    /// queries recorded that were not spent on a hedge yet
+    budget: u32,
+}
+
+#[derive(Clone)]
+pub struct Hedging {
+    latencies: Arc<Mutex<Latencies>>,
+    percentile: f64,
+    min_delay: Duration,
+}
+
+impl Hedging {
+    pub fn new(config: &HedgingConfig) -> Self {
+        Self {
+            latencies: Arc::new(Mutex::new(Latencies {
+                samples: VecDeque::with_capacity(SAMPLES),
+                recorded: 0,
+                delay: None,
+                budget: 0,
+            })),
+            percentile: config.percentile,
+            min_delay: config.min_delay_secs,
+        }
+    }
+
+    /// Records the latency of a query sent to graph-node
+    pub fn record(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.samples.len() == SAMPLES {
+            latencies.samples.pop_front();
+        }
+        latencies.samples.push_back(latency);
+        latencies.recorded += 1;
+        latencies.budget = (latencies.budget + 1).min(QUERIES_PER_HEDGE * MAX_HEDGE_BURST);
+        if latencies.recorded % REFRESH_EVERY == 0 {
+            let mut sorted: Vec<Duration> = latencies.samples.iter().copied().collect();
+            sorted.sort_unstable();
+            let index = ((sorted.len() - 1) as f64 * self.percentile).round() as usize;
+            latencies.delay = Some(sorted[index].max(self.min_delay));
+        }
+    }
+
+    /// Delay after which a query is hedged, `None` until enough queries
+    /// were recorded
+    pub fn delay(&self) -> Option<Duration> {
+        self.latencies.lock().unwrap().delay
+    }
+
+    /// Takes a hedge out of the budget, `false` if 10% of the queries were
+    /// already hedged
+    pub fn try_hedge(&self) -> bool {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.budget < QUERIES_PER_HEDGE {
+            return false;
+        }
+        latencies.budget -= QUERIES_PER_HEDGE;
+        true
+    }
+
+    /// Records the time elapsed since now once the returned guard is
+    /// dropped, when the query completed, failed or was cancelled
+    pub fn start(&self) -> LatencyGuard {
+        LatencyGuard {
+            hedging: self.clone(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Records the latency of a query when dropped, see [Hedging::start]
+pub struct LatencyGuard {
+    hedging: Hedging,
+    started: Instant,
+}
+
+impl Drop for LatencyGuard {
+    fn drop(&mut self) {
+        self.hedging.record(self.started.elapsed());
+    }
+}
+
+/// Runs `primary`, and the future returned by `hedge` too if `primary`
+/// didn't complete after `delay`
+///
+/// The first successful result is returned, the other future is dropped
+/// and so cancelled. `hedge` returns `None` if the query can't be hedged,
+/// the primary result is awaited then. Hedged queries are counted by winner.
+pub async fn hedged<T, E, P, H, F>(primary: P, hedge: H, delay: Duration) -> Result<T, E>
+where
+    P: Future<Output = Result<T, E>>,
+    H: FnOnce() -> Option<F>,
+    F: Future<Output = Result<T, E>>,
+{
+    tokio::pin!(primary);
+    tokio::select! {
+        result = &mut primary => return result,
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    let Some(hedge) = hedge() else {
+        return primary.await;
+    };
+    tokio::pin!(hedge);
+    let (winner, result) = tokio::select! {
+        result = &mut primary => match result {
+            Ok(result) => ("primary", Ok(result)),
+            Err(_) => ("hedge", hedge.await),
+        },
+        result = &mut hedge => match result {
+            Ok(result) => ("hedge", Ok(result)),
+            Err(_) => ("primary", primary.await),
+        },
+    };
+    HEDGED_QUERIES.with_label_values(&[winner]).inc();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indexer_config::HedgingConfig;
+
+    use super::{hedged, Hedging};
+
+    #[test]
+    fn test_hedging_delay() {
+        let hedging = Hedging::new(&HedgingConfig {
+            percentile: 0.9,
+            min_delay_secs: Duration::from_millis(5),
+        });
+        for latency in 1..100 {
+            hedging.record(Duration::from_millis(latency));
+        }
+        assert_eq!(hedging.delay(), None);
+
+        hedging.record(Duration::from_millis(100));
+        assert_eq!(hedging.delay(), Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn test_hedging_budget() {
+        let hedging = Hedging::new(&HedgingConfig {
+            percentile: 0.9,
+            min_delay_secs: Duration::from_millis(5),
+        });
+        // one hedge for every 10 queries, saved up to 10
+        for _ in 0..200 {
+            drop(hedging.start());
+        }
+        let hedges = (0..20).filter(|_| hedging.try_hedge()).count();
+        assert_eq!(hedges, 10);
+        for _ in 0..10 {
+            hedging.record(Duration::from_millis(1));
+        }
+        assert!(hedging.try_hedge());
+        assert!(!hedging.try_hedge());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_query_is_recorded() {
+        let hedging = Hedging::new(&HedgingConfig {
+            percentile: 0.5,
+            min_delay_secs: Duration::from_millis(1),
+        });
+        for _ in 0..super::REFRESH_EVERY {
+            let hedging = hedging.clone();
+            let query = async move {
+                let _latency = hedging.start();
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            };
+            // cancelled like a primary query that lost to its hedge
+            let _ = tokio::time::timeout(Duration::from_millis(20), query).await;
+        }
+        assert!(hedging.delay().unwrap() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn test_hedged() {
+        let answer = |after: u64, result: Result<&'static str, &'static str>| async move {
+            tokio::time::sleep(Duration::from_millis(after)).await;
+            result
+        };
+        let delay = Duration::from_millis(50);
+
+        // not hedged
+        assert_eq!(
+            hedged(
+                answer(10, Ok("primary")),
+                || Some(answer(0, Ok("hedge"))),
+                delay
+            )
+            .await,
+            Ok("primary")
+        );
+        // the hedge answers first
+        assert_eq!(
+            hedged(
+                answer(500, Ok("primary")),
+                || Some(answer(10, Ok("hedge"))),
+                delay
+            )
+            .await,
+            Ok("hedge")
+        );
+        // the primary request answers first, after the hedge was sent
+        assert_eq!(
+            hedged(
+                answer(100, Ok("primary")),
+                || Some(answer(500, Ok("hedge"))),
+                delay
+            )
+            .await,
+            Ok("primary")
+        );
+        // a failed hedge doesn't replace the primary response
+        assert_eq!(
+            hedged(
+                answer(200, Ok("primary")),
+                || Some(answer(1, Err("hedge"))),
+                delay
+            )
+            .await,
+            Ok("primary")
+        );
+        // out of budget, the primary response is awaited
+        assert_eq!(
+            hedged(
+                answer(100, Ok("primary")),
+                || None::<std::future::Ready<Result<&'static str, &'static str>>>,
+                delay
+            )
+            .await,
+            Ok("primary")
+        );
+    }
+}
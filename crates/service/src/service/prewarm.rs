@@ -0,0 +1,84 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Warm-up of the service before it accepts queries
+//!
+//! The watchers wait for their first value and the cost models are loaded
+//! while the router is created. With `service.prewarm` enabled, the router
+//! is created before the query port is bound and the connections to the
+//! database and graph-node are opened ahead of time, so the first paid
+//! queries after a deploy don't pay for any of it.
+
+use std::time::Instant;
+
+use indexer_monitor::{AllocationWatcher, AttestationWatcher, EscrowAccountsWatcher};
+
+use crate::routes::{check_ready, ServiceHealthState};
+
+/// Database connections opened before accepting queries
+const PREWARM_DB_CONNECTIONS: usize = 10;
+
+/// What was loaded while the router was created
+pub(super) struct Prewarm {
+    started_at: Instant,
+    allocations: usize,
+    attestation_signers: usize,
+    escrow_senders_v1: usize,
+    escrow_senders_v2: usize,
+}
+
+impl Prewarm {
+    pub(super) fn new(
+        started_at: Instant,
+        allocations: &AllocationWatcher,
+        attestation_signers: &AttestationWatcher,
+        escrow_accounts_v1: &EscrowAccountsWatcher,
+        escrow_accounts_v2: &EscrowAccountsWatcher,
+    ) -> Self {
+        Self {
+            started_at,
+            allocations: allocations.borrow().len(),
+            attestation_signers: attestation_signers.borrow().len(),
+            escrow_senders_v1: escrow_accounts_v1.borrow().get_senders().len(),
+            escrow_senders_v2: escrow_accounts_v2.borrow().get_senders().len(),
+        }
+    }
+
+    /// Opens the connections used by paid queries and logs what was loaded
+    ///
+    /// Dependencies that are down are only reported, the service still
+    /// starts and `/readyz` keeps failing until they are back.
+    pub(super) async fn finish(self, health: &ServiceHealthState) {
+        if let Err(error) = open_db_connections(&health.database).await {
+            tracing::warn!(%error, "Failed to open database connections while pre-warming");
+        }
+        if let Err(error) = check_ready(health).await {
+            tracing::warn!(%error, "Pre-warm finished with dependencies down");
+        }
+        if self.allocations > 0 && self.attestation_signers == 0 {
+            tracing::warn!(
+                allocations = self.allocations,
+                "No attestation signer could be derived for the allocations"
+            );
+        }
+
+        tracing::info!(
+            allocations = self.allocations,
+            attestation_signers = self.attestation_signers,
+            escrow_senders_v1 = self.escrow_senders_v1,
+            escrow_senders_v2 = self.escrow_senders_v2,
+            elapsed_ms = self.started_at.elapsed().as_millis() as u64,
+            "Caches pre-warmed",
+        );
+    }
+}
+
+/// Fills the pool with idle connections, they are returned to
+/// it when dropped
+async fn open_db_connections(database: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+    let mut connections = Vec::with_capacity(PREWARM_DB_CONNECTIONS);
+    for _ in 0..PREWARM_DB_CONNECTIONS {
+        connections.push(database.acquire().await?);
+    }
+    Ok(())
+}
@@ -0,0 +1,324 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Estimates how much work a query asks from graph-node, without its schema
+//!
+//! The depth is the deepest nesting of fields. The complexity counts every
+//! field once for each entity its parents may return: the selection of a
+//! field counts `first` times, 100 times without `first` like graph-node
+//! returns 100 entities by default. The fields with an `id` argument and the
+//! fields of `_meta` return a single entity, their selection counts once.
+//! Fragments count as if their fields were written in place.
+
+use std::collections::HashMap;
+
+use graphql::graphql_parser::query as q;
+use serde_json::{Map, Value};
+
+/// `first` of the collections whose `first` is a variable that isn't set,
+/// the largest value accepted by graph-node by default
+const UNKNOWN_FIRST: u64 = 1000;
+
+/// Entities returned by graph-node for the collections without `first`
+const DEFAULT_FIRST: u64 = 100;
+
+/// Fragments can spread fragments up to this nesting
+const MAX_FRAGMENT_NESTING: usize = 64;
+
+/// Nesting of input objects and lists allowed on top of the fields before
+/// the query is refused without being parsed
+const MAX_VALUE_NESTING: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueryComplexity {
+    /// deepest nesting of fields
+    pub depth: usize,
+    /// fields resolved, for every entity returned by their parents
+    pub complexity: u64,
+}
+
+impl QueryComplexity {
+    /// Complexity refused by any limit, for the queries that can't be
+    /// estimated without risking the stack
+    const UNBOUNDED: Self = Self {
+        depth: usize::MAX,
+        complexity: u64::MAX,
+    };
+
+    /// Estimates the complexity of the most complex operation of `query`,
+    /// `variables` being the JSON encoded variables of the request
+    ///
+    /// Queries nested far deeper than `max_depth` are estimated as unbounded
+    /// without being parsed. Returns `None` if the query can't be parsed,
+    /// graph-node refuses it anyway.
+    pub fn estimate(query: &str, variables: &str, max_depth: usize) -> Option<Self> {
+        if query_nesting(query) > max_depth.saturating_add(MAX_VALUE_NESTING) {
+            return Some(Self::UNBOUNDED);
+        }
+        let document: q::Document<String> = q::parse_query(query).ok()?;
+        let variables: Map<String, Value> = serde_json::from_str(variables).unwrap_or_default();
+
+        let mut estimator = Estimator {
+            fragments: document
+                .definitions
+                .iter()
+                .filter_map(|definition| match definition {
+                    q::Definition::Fragment(fragment) => {
+                        Some((fragment.name.as_str(), &fragment.selection_set))
+                    }
+                    q::Definition::Operation(_) => None,
+                })
+                .collect(),
+            variables: &variables,
+            estimated: HashMap::new(),
+            spreading: Vec::new(),
+            single: false,
+        };
+        let complexity = document
+            .definitions
+            .iter()
+            .filter_map(|definition| match definition {
+                q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                    Some(selection_set)
+                }
+                q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                    Some(&query.selection_set)
+                }
+                q::Definition::Operation(q::OperationDefinition::Mutation(mutation)) => {
+                    Some(&mutation.selection_set)
+                }
+                q::Definition::Operation(q::OperationDefinition::Subscription(subscription)) => {
+                    Some(&subscription.selection_set)
+                }
+                q::Definition::Fragment(_) => None,
+            })
+            .map(|selection_set| estimator.selection_set(selection_set))
+            .fold(Self::default(), Self::max);
+        Some(complexity)
+    }
+
+    fn max(self, other: Self) -> Self {
+        Self {
+            depth: self.depth.max(other.depth),
+            complexity: self.complexity.max(other.complexity),
+        }
+    }
+}
+
+/// Walks the document of a query, `'d` being the lifetime of the document
+/// and `'q` the one of the query it was parsed from
+struct Estimator<'d, 'q> {
+    fragments: HashMap<&'d str, &'d q::SelectionSet<'q, String>>,
+    variables: &'d Map<String, Value>,
+    /// fragments already estimated, so that a fragment spread many times
+    /// is only walked once, inside `_meta` or not
+    estimated: HashMap<(&'d str, bool), QueryComplexity>,
+    /// fragments being estimated, to stop at cycles
+    spreading: Vec<&'d str>,
+    /// the fields being estimated are inside `_meta`
+    single: bool,
+}
+
+impl<'d, 'q> Estimator<'d, 'q> {
+    fn selection_set(&mut self, selection_set: &'d q::SelectionSet<'q, String>) -> QueryComplexity {
+        let mut total = QueryComplexity::default();
+        for selection in &selection_set.items {
+            let estimate = match selection {
+                q::Selection::Field(field) => {
+                    let single = self.single;
+                    self.single = single || field.name == "_meta";
+                    let selection = self.selection_set(&field.selection_set);
+                    self.single = single;
+                    QueryComplexity {
+                        depth: selection.depth.saturating_add(1),
+                        complexity: self
+                            .first(field)
+                            .saturating_mul(selection.complexity)
+                            .saturating_add(1),
+                    }
+                }
+                q::Selection::FragmentSpread(spread) => self.fragment(&spread.fragment_name),
+                q::Selection::InlineFragment(fragment) => {
+                    self.selection_set(&fragment.selection_set)
+                }
+            };
+            total.depth = total.depth.max(estimate.depth);
+            total.complexity = total.complexity.saturating_add(estimate.complexity);
+        }
+        total
+    }
+
+    fn fragment(&mut self, name: &'d str) -> QueryComplexity {
+        if let Some(estimate) = self.estimated.get(&(name, self.single)) {
+            return *estimate;
+        }
+        // graph-node refuses unknown fragments and cycles
+        let Some(selection_set) = self.fragments.get(name).copied() else {
+            return QueryComplexity::default();
+        };
+        if self.spreading.contains(&name) {
+            return QueryComplexity::default();
+        }
+        if self.spreading.len() >= MAX_FRAGMENT_NESTING {
+            return QueryComplexity::UNBOUNDED;
+        }
+        self.spreading.push(name);
+        let estimate = self.selection_set(selection_set);
+        self.spreading.pop();
+        self.estimated.insert((name, self.single), estimate);
+        estimate
+    }
+
+    /// Entities the field may return, from its `first` argument
+    fn first(&self, field: &q::Field<'q, String>) -> u64 {
+        let single = self.single
+            || field.name == "_meta"
+            || field.arguments.iter().any(|(name, _)| name == "id");
+        if single {
+            return 1;
+        }
+        let first = field
+            .arguments
+            .iter()
+            .find(|(name, _)| name == "first")
+            .map(|(_, value)| value);
+        match first {
+            None => DEFAULT_FIRST,
+            Some(q::Value::Int(first)) => first.as_i64().unwrap_or(0).max(0) as u64,
+            Some(q::Value::Variable(variable)) => self
+                .variables
+                .get(variable)
+                .and_then(Value::as_u64)
+                .unwrap_or(UNKNOWN_FIRST),
+            Some(_) => UNKNOWN_FIRST,
+        }
+    }
+}
+
+/// Deepest nesting of braces, brackets and parentheses outside of strings
+/// and comments, an upper bound of the depth of the fields that doesn't
+/// require parsing the query
+pub fn query_nesting(query: &str) -> usize {
+    let mut bytes = query.bytes();
+    let (mut nesting, mut max_nesting) = (0usize, 0usize);
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'{' | b'[' | b'(' => {
+                nesting += 1;
+                max_nesting = max_nesting.max(nesting);
+            }
+            b'}' | b']' | b')' => nesting = nesting.saturating_sub(1),
+            b'"' => {
+                while let Some(byte) = bytes.next() {
+                    match byte {
+                        b'\\' => {
+                            bytes.next();
+                        }
+                        b'"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            b'#' => {
+                for byte in bytes.by_ref() {
+                    if byte == b'\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    max_nesting
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{query_nesting, QueryComplexity};
+
+    fn estimate(query: &str, variables: &str) -> QueryComplexity {
+        QueryComplexity::estimate(query, variables, 16).unwrap()
+    }
+
+    #[test]
+    fn test_estimate_complexity() {
+        assert_eq!(
+            estimate("{ _meta { block { number } } }", ""),
+            QueryComplexity {
+                depth: 3,
+                complexity: 3
+            }
+        );
+        // 1 + 10 * (1 + 1 + 5 * 1)
+        assert_eq!(
+            estimate("{ tokens(first: 10) { id holders(first: 5) { id } } }", ""),
+            QueryComplexity {
+                depth: 3,
+                complexity: 71
+            }
+        );
+        // from the variables, or the largest `first` if not set
+        let query = "query($n: Int) { tokens(first: $n) { id } }";
+        assert_eq!(estimate(query, r#"{"n": 3}"#).complexity, 4);
+        assert_eq!(estimate(query, "").complexity, 1001);
+        // 100 entities without `first`, a single one with an `id`
+        assert_eq!(estimate("{ tokens { id } }", "").complexity, 101);
+        assert_eq!(
+            estimate(r#"{ token(id: "0x1") { id owner { id } } }"#, "").complexity,
+            103
+        );
+        // the most complex operation
+        assert_eq!(
+            estimate(
+                "query a { tokens(first: 1) { id } } query b { tokens(first: 2) { id } }",
+                ""
+            )
+            .complexity,
+            3
+        );
+        assert!(QueryComplexity::estimate("{ tokens {", "", 16).is_none());
+    }
+
+    #[test]
+    fn test_estimate_fragments() {
+        let query = r#"
+            { tokens(first: 10) { ...token ... on Token { name } } }
+            fragment token on Token { id owner { ...account } }
+            fragment account on Account { id }
+        "#;
+        // 1 + 10 * (1 + (1 + 100 * 1) + 1)
+        assert_eq!(
+            estimate(query, ""),
+            QueryComplexity {
+                depth: 3,
+                complexity: 1031
+            }
+        );
+
+        // cycles are refused by graph-node, they don't count
+        let query = r#"
+            { tokens(first: 1) { ...a } }
+            fragment a on Token { id ...b }
+            fragment b on Token { ...a }
+        "#;
+        assert_eq!(estimate(query, "").complexity, 2);
+    }
+
+    #[test]
+    fn test_estimate_too_deep_without_parsing() {
+        let query = format!("{}{}", "{ a ".repeat(100_000), "}".repeat(100_000));
+        assert_eq!(
+            QueryComplexity::estimate(&query, "", 16),
+            Some(QueryComplexity::UNBOUNDED)
+        );
+    }
+
+    #[test]
+    fn test_query_nesting() {
+        assert_eq!(
+            query_nesting(r#"{ a(where: { b: "{{{" }) { c } } # {{{"#),
+            3
+        );
+        assert_eq!(query_nesting(r#"{ a(b: "\"{") }"#), 2);
+    }
+}
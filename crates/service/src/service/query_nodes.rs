@@ -0,0 +1,591 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Routes the queries to the graph-node query nodes
+//!
+//! With the hash routing, a deployment goes to the node with the highest
+//! weighted rendezvous score for it, so that only the deployments of a node
+//! move when it becomes unhealthy, and move back once it recovers.
+//!
+//! The query nodes may read from database replicas lagging behind each
+//! other. The queries asking for a block a node didn't reach yet for their
+//! deployment only go to the nodes that reached it, if any.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use graphql::graphql_parser::query as q;
+use graphql_client::GraphQLQuery;
+use indexer_config::{QueryNodeRouting, QueryNodesConfig};
+use indexer_query::indexed_blocks_query::{self, IndexedBlocksQuery};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use thegraph_core::{
+    alloy::primitives::{keccak256, BlockNumber},
+    DeploymentId,
+};
+
+use super::query_nesting;
+use crate::{error::SubgraphServiceError, metrics::GRAPH_NODE_QUERY_NODE_HEALTHY};
+
+/// Queries nested deeper than this are not parsed for their block, the
+/// parser could run out of stack
+const MAX_PARSED_NESTING: usize = 256;
+
+struct QueryNode {
+    url: Url,
+    status_url: Option<Url>,
+    weight: u32,
+    healthy: AtomicBool,
+    /// latest block of the deployments on the node, from its status endpoint
+    indexed_blocks: RwLock<HashMap<DeploymentId, BlockNumber>>,
+}
+
+impl QueryNode {
+    fn new(url: Url, status_url: Option<Url>, weight: u32) -> Self {
+        Self {
+            url,
+            status_url,
+            weight,
+            healthy: AtomicBool::new(true),
+            indexed_blocks: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the node may serve `deployment` at `block`, nodes whose
+    /// latest block is unknown are assumed to have reached it
+    fn reached(&self, deployment: &DeploymentId, block: Option<BlockNumber>) -> bool {
+        let Some(block) = block else {
+            return true;
+        };
+        !matches!(
+            self.indexed_blocks.read().unwrap().get(deployment),
+            Some(latest) if *latest < block
+        )
+    }
+}
+
+struct Inner {
+    nodes: Vec<QueryNode>,
+    routing: QueryNodeRouting,
+    /// nodes of the deployments mapped in the configuration
+    deployments: HashMap<DeploymentId, usize>,
+    /// turn of the round robin routing
+    next: AtomicUsize,
+}
+
+/// Query nodes the queries are routed to, by deployment
+#[derive(Clone)]
+pub struct QueryNodes {
+    inner: Arc<Inner>,
+}
+
+impl QueryNodes {
+    /// A single node, always used
+    pub fn single(url: Url) -> Self {
+        Self::from_nodes(
+            vec![QueryNode::new(url, None, 1)],
+            QueryNodeRouting::Hash,
+            HashMap::new(),
+        )
+    }
+
+    pub fn new(config: &QueryNodesConfig) -> Self {
+        let nodes = config
+            .nodes
+            .iter()
+            .map(|node| QueryNode::new(node.url.clone(), node.status_url.clone(), node.weight()))
+            .collect();
+        Self::from_nodes(nodes, config.routing, config.deployments.clone())
+    }
+
+    fn from_nodes(
+        nodes: Vec<QueryNode>,
+        routing: QueryNodeRouting,
+        deployments: HashMap<DeploymentId, Url>,
+    ) -> Self {
+        let deployments = deployments
+            .into_iter()
+            .filter_map(|(deployment, url)| {
+                let index = nodes.iter().position(|node| node.url == url)?;
+                Some((deployment, index))
+            })
+            .collect();
+        Self {
+            inner: Arc::new(Inner {
+                nodes,
+                routing,
+                deployments,
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Whether the latest block of the deployments is known for some nodes,
+    /// so that the block of the queries matters
+    pub fn tracks_blocks(&self) -> bool {
+        self.inner
+            .nodes
+            .iter()
+            .any(|node| node.status_url.is_some())
+    }
+
+    /// Query endpoint of the node serving `deployment`, at `block` or later
+    /// if set
+    ///
+    /// Unhealthy nodes are skipped, unless no node is healthy. Fails if no
+    /// node reached `block`.
+    pub fn query_url(
+        &self,
+        deployment: &DeploymentId,
+        block: Option<BlockNumber>,
+    ) -> Result<&Url, SubgraphServiceError> {
+        self.route(deployment, block, None)
+            .ok_or_else(|| SubgraphServiceError::BlockNotAvailable(block.unwrap_or_default()))
+    }
+
+    /// Query endpoint of another node than `primary` serving `deployment`,
+    /// to hedge a slow query, if any
+    pub fn hedge_url(
+        &self,
+        deployment: &DeploymentId,
+        block: Option<BlockNumber>,
+        primary: &Url,
+    ) -> Option<&Url> {
+        self.route(deployment, block, Some(primary))
+    }
+
+    fn route(
+        &self,
+        deployment: &DeploymentId,
+        block: Option<BlockNumber>,
+        except: Option<&Url>,
+    ) -> Option<&Url> {
+        let inner = &self.inner;
+        if let [node] = inner.nodes.as_slice() {
+            if node.status_url.is_none() {
+                return Some(&node.url).filter(|url| Some(*url) != except);
+            }
+        }
+        let usable =
+            |node: &&QueryNode| Some(&node.url) != except && node.reached(deployment, block);
+        if let Some(node) = inner.deployments.get(deployment).map(|i| &inner.nodes[*i]) {
+            if node.healthy.load(Ordering::Relaxed) && usable(&node) {
+                return Some(&node.url);
+            }
+        }
+
+        let healthy: Vec<&QueryNode> = inner
+            .nodes
+            .iter()
+            .filter(|node| node.healthy.load(Ordering::Relaxed))
+            .collect();
+        let candidates: Vec<&QueryNode> = if healthy.is_empty() {
+            inner.nodes.iter().collect()
+        } else {
+            healthy
+        }
+        .into_iter()
+        .filter(usable)
+        .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let node = match inner.routing {
+            QueryNodeRouting::Hash => candidates
+                .into_iter()
+                .max_by(|a, b| {
+                    rendezvous_score(deployment, a).total_cmp(&rendezvous_score(deployment, b))
+                })
+                .unwrap(),
+            QueryNodeRouting::RoundRobin => {
+                let total: usize = candidates.iter().map(|node| node.weight as usize).sum();
+                let mut turn = inner.next.fetch_add(1, Ordering::Relaxed) % total;
+                candidates
+                    .into_iter()
+                    .find(|node| {
+                        let found = turn < node.weight as usize;
+                        turn = turn.saturating_sub(node.weight as usize);
+                        found
+                    })
+                    .unwrap()
+            }
+        };
+        Some(&node.url)
+    }
+
+    /// Requests the query endpoint of every node every `interval`, nodes not
+    /// answering with a success are unhealthy until the next check
+    ///
+    /// The latest block of the deployments on the healthy nodes with a
+    /// status endpoint is refreshed at the same time.
+    pub fn watch_health(&self, client: reqwest::Client, interval: Duration) {
+        let query_nodes = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                for node in &query_nodes.inner.nodes {
+                    let healthy = client
+                        .get(node.url.clone())
+                        .send()
+                        .await
+                        .is_ok_and(|response| response.status().is_success());
+                    if node.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                        if healthy {
+                            tracing::info!(node = %node.url, "Query node recovered");
+                        } else {
+                            tracing::warn!(node = %node.url, "Query node is unhealthy");
+                        }
+                    }
+                    GRAPH_NODE_QUERY_NODE_HEALTHY
+                        .with_label_values(&[node.url.as_str()])
+                        .set(healthy as i64);
+
+                    let Some(status_url) = node.status_url.as_ref().filter(|_| healthy) else {
+                        continue;
+                    };
+                    let indexed_blocks = match query_indexed_blocks(&client, status_url).await {
+                        Ok(indexed_blocks) => indexed_blocks,
+                        Err(error) => {
+                            tracing::warn!(
+                                node = %node.url,
+                                %error,
+                                "Failed to query the latest blocks of the query node"
+                            );
+                            HashMap::new()
+                        }
+                    };
+                    *node.indexed_blocks.write().unwrap() = indexed_blocks;
+                }
+            }
+        });
+    }
+}
+
+async fn query_indexed_blocks(
+    client: &reqwest::Client,
+    status_url: &Url,
+) -> anyhow::Result<HashMap<DeploymentId, BlockNumber>> {
+    let response: graphql_client::Response<indexed_blocks_query::ResponseData> = client
+        .post(status_url.clone())
+        .json(&IndexedBlocksQuery::build_query(
+            indexed_blocks_query::Variables,
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let data = response
+        .data
+        .ok_or_else(|| anyhow!("No data in indexed blocks response: {:?}", response.errors))?;
+
+    Ok(data
+        .indexing_statuses
+        .into_iter()
+        .filter_map(|status| {
+            let deployment = status.subgraph.parse().ok()?;
+            let block = status
+                .chains
+                .into_iter()
+                .filter_map(|chain| chain.latest_block?.number.parse().ok())
+                .max()?;
+            Some((deployment, block))
+        })
+        .collect())
+}
+
+/// Weighted rendezvous score of `node` for `deployment`, the node with the
+/// highest score serves the deployment
+fn rendezvous_score(deployment: &DeploymentId, node: &QueryNode) -> f64 {
+    let hash = keccak256(format!("{deployment}{}", node.url));
+    let hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    // uniform in (0, 1)
+    let uniform = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    node.weight as f64 / -uniform.ln()
+}
+
+/// Latest block a query asks for with the `block: { number_gte }` or
+/// `block: { number }` argument of its fields, `request` being the JSON body
+/// of the query
+pub fn required_block(request: &str) -> Option<BlockNumber> {
+    #[derive(Deserialize)]
+    struct QueryRequest {
+        query: String,
+        #[serde(default)]
+        variables: Option<Map<String, Value>>,
+    }
+
+    let request: QueryRequest = serde_json::from_str(request).ok()?;
+    if query_nesting(&request.query) > MAX_PARSED_NESTING {
+        return None;
+    }
+    let document: q::Document<String> = q::parse_query(&request.query).ok()?;
+    let variables = request.variables.unwrap_or_default();
+    // fragments are walked where they are defined rather than where they
+    // are spread, the block of a field doesn't depend on its parents
+    document
+        .definitions
+        .iter()
+        .map(|definition| match definition {
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => &query.selection_set,
+            q::Definition::Operation(q::OperationDefinition::Mutation(mutation)) => {
+                &mutation.selection_set
+            }
+            q::Definition::Operation(q::OperationDefinition::Subscription(subscription)) => {
+                &subscription.selection_set
+            }
+            q::Definition::Fragment(fragment) => &fragment.selection_set,
+        })
+        .filter_map(|selection_set| selection_set_block(selection_set, &variables))
+        .max()
+}
+
+fn selection_set_block(
+    selection_set: &q::SelectionSet<'_, String>,
+    variables: &Map<String, Value>,
+) -> Option<BlockNumber> {
+    selection_set
+        .items
+        .iter()
+        .filter_map(|selection| match selection {
+            q::Selection::Field(field) => {
+                let block = field
+                    .arguments
+                    .iter()
+                    .find(|(name, _)| name == "block")
+                    .and_then(|(_, value)| block_number(value, variables));
+                block.max(selection_set_block(&field.selection_set, variables))
+            }
+            q::Selection::InlineFragment(fragment) => {
+                selection_set_block(&fragment.selection_set, variables)
+            }
+            q::Selection::FragmentSpread(_) => None,
+        })
+        .max()
+}
+
+/// Block number of a `block` argument, set inline or by variables
+fn block_number(
+    block: &q::Value<'_, String>,
+    variables: &Map<String, Value>,
+) -> Option<BlockNumber> {
+    match block {
+        q::Value::Object(block) => {
+            ["number_gte", "number"]
+                .iter()
+                .find_map(|name| match block.get(*name)? {
+                    q::Value::Int(number) => number.as_i64().and_then(|n| n.try_into().ok()),
+                    q::Value::Variable(variable) => variables.get(variable)?.as_u64(),
+                    _ => None,
+                })
+        }
+        q::Value::Variable(variable) => {
+            let block = variables.get(variable)?.as_object()?;
+            ["number_gte", "number"]
+                .iter()
+                .find_map(|name| block.get(*name)?.as_u64())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::atomic::Ordering};
+
+    use indexer_config::QueryNodeRouting;
+    use reqwest::Url;
+    use thegraph_core::{alloy::primitives::B256, DeploymentId};
+
+    use super::{required_block, QueryNode, QueryNodes};
+
+    fn deployment(i: u8) -> DeploymentId {
+        DeploymentId::new(B256::repeat_byte(i))
+    }
+
+    fn url(i: usize) -> Url {
+        format!("http://query-node-{i}:8000").parse().unwrap()
+    }
+
+    fn nodes(routing: QueryNodeRouting, mapped: HashMap<DeploymentId, Url>) -> QueryNodes {
+        let nodes = vec![
+            QueryNode::new(url(0), None, 1),
+            QueryNode::new(url(1), None, 1),
+            QueryNode::new(url(2), None, 2),
+        ];
+        QueryNodes::from_nodes(nodes, routing, mapped)
+    }
+
+    #[test]
+    fn test_hash_routing() {
+        let query_nodes = nodes(QueryNodeRouting::Hash, HashMap::new());
+        let routed: Vec<Url> = (0..100)
+            .map(|i| query_nodes.query_url(&deployment(i), None).unwrap().clone())
+            .collect();
+
+        // a deployment always goes to the same node
+        assert_eq!(
+            query_nodes.query_url(&deployment(7), None).unwrap(),
+            &routed[7]
+        );
+        // the heavier node gets more deployments
+        let count = |node: &Url| routed.iter().filter(|url| *url == node).count();
+        assert!(count(&url(2)) > count(&url(0)));
+
+        // only the deployments of an unhealthy node move
+        query_nodes.inner.nodes[2]
+            .healthy
+            .store(false, Ordering::Relaxed);
+        for (i, routed) in routed.iter().enumerate() {
+            let node = query_nodes.query_url(&deployment(i as u8), None).unwrap();
+            assert_ne!(node, &url(2));
+            if *routed != url(2) {
+                assert_eq!(node, routed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mapped_deployments() {
+        let query_nodes = nodes(
+            QueryNodeRouting::Hash,
+            HashMap::from([(deployment(1), url(0))]),
+        );
+        assert_eq!(
+            query_nodes.query_url(&deployment(1), None).unwrap(),
+            &url(0)
+        );
+
+        // mapped to another node while its node is unhealthy
+        query_nodes.inner.nodes[0]
+            .healthy
+            .store(false, Ordering::Relaxed);
+        assert_ne!(
+            query_nodes.query_url(&deployment(1), None).unwrap(),
+            &url(0)
+        );
+    }
+
+    #[test]
+    fn test_hedge_url() {
+        let query_nodes = nodes(QueryNodeRouting::Hash, HashMap::new());
+        let primary = query_nodes.query_url(&deployment(1), None).unwrap().clone();
+        let hedge = query_nodes
+            .hedge_url(&deployment(1), None, &primary)
+            .unwrap();
+        assert_ne!(hedge, &primary);
+        // the next node of the deployment if the primary one is unhealthy
+        let index = query_nodes
+            .inner
+            .nodes
+            .iter()
+            .position(|node| node.url == primary)
+            .unwrap();
+        query_nodes.inner.nodes[index]
+            .healthy
+            .store(false, Ordering::Relaxed);
+        assert_eq!(query_nodes.query_url(&deployment(1), None).unwrap(), hedge);
+
+        let single = QueryNodes::single(url(0));
+        assert!(single.hedge_url(&deployment(1), None, &url(0)).is_none());
+    }
+
+    #[test]
+    fn test_round_robin_routing() {
+        let query_nodes = nodes(QueryNodeRouting::RoundRobin, HashMap::new());
+        let routed: Vec<Url> = (0..8)
+            .map(|_| query_nodes.query_url(&deployment(1), None).unwrap().clone())
+            .collect();
+        assert_eq!(
+            routed,
+            [
+                url(0),
+                url(1),
+                url(2),
+                url(2),
+                url(0),
+                url(1),
+                url(2),
+                url(2)
+            ]
+        );
+
+        // every node is used if none is healthy
+        for node in &query_nodes.inner.nodes {
+            node.healthy.store(false, Ordering::Relaxed);
+        }
+        assert_eq!(
+            query_nodes.query_url(&deployment(1), None).unwrap(),
+            &url(0)
+        );
+    }
+
+    #[test]
+    fn test_block_routing() {
+        let query_nodes = nodes(QueryNodeRouting::Hash, HashMap::new());
+        let indexed_blocks = |node: usize, block| {
+            *query_nodes.inner.nodes[node]
+                .indexed_blocks
+                .write()
+                .unwrap() = HashMap::from([(deployment(1), block)]);
+        };
+        indexed_blocks(0, 120);
+        indexed_blocks(1, 90);
+        indexed_blocks(2, 100);
+
+        assert_eq!(
+            query_nodes.query_url(&deployment(1), Some(110)).unwrap(),
+            &url(0)
+        );
+        assert!(query_nodes.query_url(&deployment(1), Some(130)).is_err());
+        // the latest block of the other deployments is not known
+        assert!(query_nodes.query_url(&deployment(2), Some(130)).is_ok());
+    }
+
+    #[test]
+    fn test_required_block() {
+        let request = |query: &str, variables: &str| {
+            serde_json::json!({ "query": query, "variables": serde_json::from_str::<serde_json::Value>(variables).unwrap() })
+                .to_string()
+        };
+
+        assert_eq!(required_block(&request("{ tokens { id } }", "null")), None);
+        assert_eq!(
+            required_block(&request(
+                "{ tokens(block: { number_gte: 100 }) { id } }",
+                "null"
+            )),
+            Some(100)
+        );
+        // the latest block of all the fields, set by variables
+        assert_eq!(
+            required_block(&request(
+                r#"
+                    query($n: Int, $b: Block_height) {
+                        a: tokens(block: { number: $n }) { id }
+                        b: tokens(block: $b) { id }
+                        ...f
+                    }
+                    fragment f on Query { c: tokens(block: { number_gte: 150 }) { id } }
+                "#,
+                r#"{ "n": 120, "b": { "number_gte": 200 } }"#
+            )),
+            Some(200)
+        );
+        assert_eq!(required_block("not json"), None);
+    }
+}
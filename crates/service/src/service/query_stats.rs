@@ -0,0 +1,220 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Coarse statistics of the queries served for each deployment
+//!
+//! Queries are counted in time buckets of a configurable width together
+//! with a histogram of their latency. Only complete buckets of the last
+//! 24 hours are published, so the statistics can't be used to follow the
+//! traffic of the indexer in real time, and the median latency is only
+//! known up to the bounds of [LATENCY_BUCKETS_MS].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use thegraph_core::DeploymentId;
+
+/// Period covered by the published statistics
+const STATS_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Upper bounds of the latency histogram
+const LATENCY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+struct Bucket {
+    index: u64,
+    queries: u64,
+    /// queries per latency bucket, the last one counts the queries
+    /// slower than all of [LATENCY_BUCKETS_MS]
+    latencies: [u64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct DeploymentStats {
+    pub queries: u64,
+    /// upper bound of the latency bucket of the median query,
+    /// `None` if it is slower than all of them
+    pub median_latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryStatsSummary {
+    pub window_secs: u64,
+    pub granularity_secs: u64,
+    pub deployments: HashMap<DeploymentId, DeploymentStats>,
+}
+
+/// Statistics of the paid queries, shared by the query route and `/stats`
+#[derive(Clone)]
+pub struct QueryStats {
+    granularity: Duration,
+    min_queries: u64,
+    deployments: Arc<Mutex<HashMap<DeploymentId, VecDeque<Bucket>>>>,
+}
+
+impl QueryStats {
+    pub fn new(granularity: Duration, min_queries: u64) -> Self {
+        Self {
+            // a zero width would put every query in its own bucket
+            granularity: granularity.max(Duration::from_secs(1)),
+            min_queries,
+            deployments: Default::default(),
+        }
+    }
+
+    fn bucket_index(&self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        secs / self.granularity.as_secs()
+    }
+
+    /// Number of complete buckets published
+    fn window_buckets(&self) -> u64 {
+        STATS_WINDOW.as_secs().div_ceil(self.granularity.as_secs())
+    }
+
+    pub fn record(&self, deployment: DeploymentId, latency: Duration, now: SystemTime) {
+        let index = self.bucket_index(now);
+        let oldest = index.saturating_sub(self.window_buckets());
+        let latency_bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency.as_millis() <= *bound as u128)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        let mut deployments = self.deployments.lock().unwrap();
+        let buckets = deployments.entry(deployment).or_default();
+        while buckets.front().is_some_and(|bucket| bucket.index < oldest) {
+            buckets.pop_front();
+        }
+        if buckets.back().map(|bucket| bucket.index) != Some(index) {
+            buckets.push_back(Bucket {
+                index,
+                queries: 0,
+                latencies: Default::default(),
+            });
+        }
+        let bucket = buckets.back_mut().expect("bucket was just inserted");
+        bucket.queries += 1;
+        bucket.latencies[latency_bucket] += 1;
+    }
+
+    /// Statistics of the complete buckets of the last 24 hours
+    pub fn summary(&self, now: SystemTime) -> QueryStatsSummary {
+        let current = self.bucket_index(now);
+        let oldest = current.saturating_sub(self.window_buckets());
+
+        let mut deployments = self.deployments.lock().unwrap();
+        deployments.retain(|_, buckets| {
+            buckets.retain(|bucket| bucket.index >= oldest);
+            !buckets.is_empty()
+        });
+
+        let stats = deployments
+            .iter()
+            .filter_map(|(deployment, buckets)| {
+                let mut queries = 0;
+                let mut latencies = [0; LATENCY_BUCKETS_MS.len() + 1];
+                for bucket in buckets.iter().filter(|bucket| bucket.index < current) {
+                    queries += bucket.queries;
+                    for (total, count) in latencies.iter_mut().zip(bucket.latencies) {
+                        *total += count;
+                    }
+                }
+                if queries == 0 || queries < self.min_queries {
+                    return None;
+                }
+                let stats = DeploymentStats {
+                    queries,
+                    median_latency_ms: median_latency_ms(&latencies, queries),
+                };
+                Some((*deployment, stats))
+            })
+            .collect();
+
+        QueryStatsSummary {
+            window_secs: self.window_buckets() * self.granularity.as_secs(),
+            granularity_secs: self.granularity.as_secs(),
+            deployments: stats,
+        }
+    }
+}
+
+fn median_latency_ms(latencies: &[u64], queries: u64) -> Option<u64> {
+    let median = queries.div_ceil(2);
+    let mut seen = 0;
+    for (index, count) in latencies.iter().enumerate() {
+        seen += count;
+        if seen >= median {
+            return LATENCY_BUCKETS_MS.get(index).copied();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use test_assets::{ESCROW_SUBGRAPH_DEPLOYMENT, NETWORK_SUBGRAPH_DEPLOYMENT};
+
+    use super::{DeploymentStats, QueryStats};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn test_only_complete_buckets_are_published() {
+        let stats = QueryStats::new(HOUR, 0);
+        let start = UNIX_EPOCH + 1000 * HOUR;
+
+        stats.record(ESCROW_SUBGRAPH_DEPLOYMENT, Duration::from_millis(20), start);
+        stats.record(ESCROW_SUBGRAPH_DEPLOYMENT, Duration::from_millis(40), start);
+        stats.record(
+            ESCROW_SUBGRAPH_DEPLOYMENT,
+            Duration::from_millis(700),
+            start,
+        );
+        assert!(stats.summary(start).deployments.is_empty());
+
+        let summary = stats.summary(start + HOUR);
+        assert_eq!(summary.window_secs, 24 * 60 * 60);
+        assert_eq!(
+            summary.deployments[&ESCROW_SUBGRAPH_DEPLOYMENT],
+            DeploymentStats {
+                queries: 3,
+                median_latency_ms: Some(50),
+            }
+        );
+
+        // older than 24 hours
+        assert!(stats.summary(start + 26 * HOUR).deployments.is_empty());
+    }
+
+    #[test]
+    fn test_min_queries() {
+        let stats = QueryStats::new(HOUR, 2);
+        let start = UNIX_EPOCH + 1000 * HOUR;
+
+        stats.record(ESCROW_SUBGRAPH_DEPLOYMENT, Duration::from_millis(20), start);
+        stats.record(ESCROW_SUBGRAPH_DEPLOYMENT, Duration::from_secs(20), start);
+        stats.record(
+            NETWORK_SUBGRAPH_DEPLOYMENT,
+            Duration::from_millis(20),
+            start,
+        );
+
+        let summary = stats.summary(start + HOUR);
+        assert_eq!(summary.deployments.len(), 1);
+        assert_eq!(
+            summary.deployments[&ESCROW_SUBGRAPH_DEPLOYMENT],
+            DeploymentStats {
+                queries: 2,
+                median_latency_ms: Some(25),
+            }
+        );
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! gRPC service receiving the receipts of queries served without one
+//!
+//! Gateways delivering their receipts in batches send their queries with the
+//! token of this service and a `Graph-Query-Id` header, see
+//! [crate::middleware::auth::deferred_receipt_authorize]. The receipts are then
+//! submitted here with the id of their query, and checked against the logged
+//! query as if they were sent along with it.
+//!
+//! Queries whose receipt isn't submitted in time are reported as unpaid, a
+//! receipt submitted later is still accepted until the query is forgotten.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use sqlx::PgPool;
+use tap_core::{manager::Manager, receipt::Context};
+use thiserror::Error;
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    Request, Response, Status,
+};
+
+use self::proto::{
+    receipt_ingest_service_server::{ReceiptIngestService, ReceiptIngestServiceServer},
+    QueryReceipt, ReceiptResult, ReceiptStatus, SubmitReceiptsRequest, SubmitReceiptsResponse,
+};
+use super::tap_receipt_header::decode_receipt;
+use crate::{
+    metrics::{DEFERRED_QUERIES_UNPAID, DEFERRED_RECEIPTS},
+    middleware::{recover_sender, Sender, SenderState},
+    tap::{AgoraQuery, IndexerTapContext, TapReceipt},
+};
+
+pub mod proto {
+    tonic::include_proto!("graphprotocol.indexer.receipts");
+}
+
+/// Most receipts accepted in a single request
+const MAX_RECEIPTS_PER_REQUEST: usize = 1000;
+
+/// Upper bound of the time between two reconciliations of the queries with
+/// their receipts
+const MAX_RECONCILIATION_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+enum IngestError {
+    #[error("No query with this id is waiting for a receipt")]
+    UnknownQuery,
+    #[error("A receipt was already accepted for this query")]
+    AlreadyPaid,
+    #[error("Invalid receipt: {0}")]
+    InvalidReceipt(String),
+    #[error("Could not process the receipt: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl IngestError {
+    fn status(&self) -> ReceiptStatus {
+        match self {
+            IngestError::UnknownQuery => ReceiptStatus::UnknownQuery,
+            IngestError::AlreadyPaid => ReceiptStatus::AlreadyPaid,
+            IngestError::InvalidReceipt(_) => ReceiptStatus::Invalid,
+            IngestError::Database(_) => ReceiptStatus::Failed,
+        }
+    }
+}
+
+pub struct ReceiptIngest {
+    pgpool: PgPool,
+    auth_token: MetadataValue<Ascii>,
+    tap_manager: Arc<Manager<IndexerTapContext, TapReceipt>>,
+    sender_state: SenderState,
+}
+
+impl ReceiptIngest {
+    pub fn new(
+        pgpool: PgPool,
+        auth_token: &str,
+        tap_manager: Arc<Manager<IndexerTapContext, TapReceipt>>,
+        sender_state: SenderState,
+    ) -> Self {
+        Self {
+            pgpool,
+            auth_token: format!("Bearer {}", auth_token)
+                .parse()
+                .expect("token is not a valid metadata value"),
+            tap_manager,
+            sender_state,
+        }
+    }
+
+    /// Serves the receipt ingest service on `host_and_port`
+    pub async fn serve(self, host_and_port: SocketAddr) {
+        tracing::info!(%host_and_port, "Serving the receipt ingest gRPC service");
+        if let Err(error) = tonic::transport::Server::builder()
+            .add_service(ReceiptIngestServiceServer::new(self))
+            .serve(host_and_port)
+            .await
+        {
+            tracing::error!(%error, "The receipt ingest gRPC service stopped");
+        }
+    }
+
+    /// Checks and stores the receipt of the query `query_id`
+    async fn ingest(&self, query_id: &str, raw_receipt: &str) -> Result<(), IngestError> {
+        let receipt = decode_receipt(raw_receipt.as_bytes())
+            .map_err(|error| IngestError::InvalidReceipt(error.to_string()))?;
+
+        // the query is claimed first, so that it's paid by a single receipt
+        let claimed = sqlx::query!(
+            r#"
+                UPDATE deferred_queries
+                SET paid_at = NOW()
+                WHERE query_id = $1 AND paid_at IS NULL
+                RETURNING deployment_id, query, variables
+            "#,
+            query_id
+        )
+        .fetch_optional(&self.pgpool)
+        .await?;
+        let Some(claimed) = claimed else {
+            let logged = sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM deferred_queries WHERE query_id = $1) AS "logged!""#,
+                query_id
+            )
+            .fetch_one(&self.pgpool)
+            .await?;
+            return Err(match logged {
+                true => IngestError::AlreadyPaid,
+                false => IngestError::UnknownQuery,
+            });
+        };
+
+        let result = self
+            .verify_and_store(
+                &claimed.deployment_id,
+                claimed.query,
+                claimed.variables,
+                receipt,
+            )
+            .await;
+        if result.is_err() {
+            sqlx::query!(
+                "UPDATE deferred_queries SET paid_at = NULL WHERE query_id = $1",
+                query_id
+            )
+            .execute(&self.pgpool)
+            .await?;
+        }
+        result
+    }
+
+    async fn verify_and_store(
+        &self,
+        deployment_id: &str,
+        query: String,
+        variables: String,
+        receipt: TapReceipt,
+    ) -> Result<(), IngestError> {
+        let invalid =
+            |error: &dyn std::fmt::Display| IngestError::InvalidReceipt(error.to_string());
+        let sender = recover_sender(&self.sender_state, &receipt)
+            .await
+            .map_err(|error| invalid(&error))?;
+        let mut ctx = Context::new();
+        ctx.insert(AgoraQuery {
+            deployment_id: deployment_id.parse().map_err(|error| invalid(&error))?,
+            query,
+            variables,
+        });
+        ctx.insert(Sender(sender));
+        self.tap_manager
+            .verify_and_store_receipt(&ctx, receipt)
+            .await
+            .map_err(|error| invalid(&error))
+    }
+}
+
+#[tonic::async_trait]
+impl ReceiptIngestService for ReceiptIngest {
+    async fn submit_receipts(
+        &self,
+        request: Request<SubmitReceiptsRequest>,
+    ) -> Result<Response<SubmitReceiptsResponse>, Status> {
+        if request.metadata().get("authorization") != Some(&self.auth_token) {
+            return Err(Status::unauthenticated("Invalid token"));
+        }
+        let SubmitReceiptsRequest { receipts } = request.into_inner();
+        if receipts.len() > MAX_RECEIPTS_PER_REQUEST {
+            return Err(Status::invalid_argument(format!(
+                "At most {MAX_RECEIPTS_PER_REQUEST} receipts can be submitted at once"
+            )));
+        }
+
+        let mut results = Vec::with_capacity(receipts.len());
+        for QueryReceipt { query_id, receipt } in receipts {
+            let (status, error) = match self.ingest(&query_id, &receipt).await {
+                Ok(()) => (ReceiptStatus::Accepted, None),
+                Err(error) => {
+                    tracing::debug!(%query_id, %error, "Submitted receipt was not accepted");
+                    (error.status(), Some(error.to_string()))
+                }
+            };
+            DEFERRED_RECEIPTS
+                .with_label_values(&[status.as_str_name()])
+                .inc();
+            results.push(ReceiptResult {
+                query_id,
+                status: status.into(),
+                error,
+            });
+        }
+        Ok(Response::new(SubmitReceiptsResponse { results }))
+    }
+}
+
+/// Reports the queries whose receipt wasn't submitted within
+/// `receipt_timeout` as unpaid, and forgets the queries older than
+/// `retention`. Never returns.
+pub async fn reconcile_deferred_queries(
+    pgpool: PgPool,
+    receipt_timeout: Duration,
+    retention: Duration,
+) {
+    let mut interval = tokio::time::interval(receipt_timeout.min(MAX_RECONCILIATION_INTERVAL));
+    loop {
+        interval.tick().await;
+        if let Err(error) = reconcile(&pgpool, receipt_timeout, retention).await {
+            tracing::warn!(%error, "Failed to reconcile the deferred queries with their receipts");
+        }
+    }
+}
+
+async fn reconcile(
+    pgpool: &PgPool,
+    receipt_timeout: Duration,
+    retention: Duration,
+) -> Result<(), sqlx::Error> {
+    let unpaid = sqlx::query!(
+        r#"
+            WITH expired AS (
+                UPDATE deferred_queries
+                SET expired = TRUE
+                WHERE paid_at IS NULL
+                    AND NOT expired
+                    AND created_at < NOW() - make_interval(secs => $1)
+                RETURNING deployment_id
+            )
+            SELECT deployment_id AS "deployment_id!", COUNT(*) AS "count!"
+            FROM expired
+            GROUP BY deployment_id
+        "#,
+        receipt_timeout.as_secs_f64()
+    )
+    .fetch_all(pgpool)
+    .await?;
+    for row in unpaid {
+        let (deployment, count) = (row.deployment_id, row.count);
+        tracing::warn!(
+            %deployment,
+            count,
+            "Queries were served without receiving their receipt in time"
+        );
+        DEFERRED_QUERIES_UNPAID
+            .with_label_values(&[&deployment])
+            .inc_by(count as f64);
+    }
+
+    sqlx::query!(
+        "DELETE FROM deferred_queries WHERE created_at < NOW() - make_interval(secs => $1)",
+        retention.as_secs_f64()
+    )
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+
+    use super::reconcile;
+
+    async fn log_query(pgpool: &PgPool, query_id: &str, age: Duration, paid: bool) {
+        sqlx::query!(
+            "INSERT INTO deferred_queries (query_id, deployment_id, query, variables, created_at, paid_at)
+             VALUES ($1, 'QmDeployment', '{ a }', '', NOW() - make_interval(secs => $2),
+                     CASE WHEN $3 THEN NOW() END)",
+            query_id,
+            age.as_secs_f64(),
+            paid
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_reconcile(pgpool: PgPool) {
+        log_query(&pgpool, "recent", Duration::from_secs(1), false).await;
+        log_query(&pgpool, "paid", Duration::from_secs(3600), true).await;
+        log_query(&pgpool, "unpaid", Duration::from_secs(3600), false).await;
+        log_query(&pgpool, "forgotten", Duration::from_secs(2 * 86400), false).await;
+
+        reconcile(
+            &pgpool,
+            Duration::from_secs(300),
+            Duration::from_secs(86400),
+        )
+        .await
+        .unwrap();
+
+        let queries: Vec<(String, bool)> =
+            sqlx::query!("SELECT query_id, expired FROM deferred_queries ORDER BY query_id")
+                .fetch_all(&pgpool)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| (row.query_id, row.expired))
+                .collect();
+        assert_eq!(
+            queries,
+            vec![
+                ("paid".to_string(), false),
+                ("recent".to_string(), false),
+                ("unpaid".to_string(), true),
+            ]
+        );
+    }
+}
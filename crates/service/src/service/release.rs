@@ -6,9 +6,19 @@ use std::collections::HashMap;
 use build_info::BuildInfo;
 use serde::Serialize;
 
+use super::router::API_VERSIONS;
+
+/// Receipt versions accepted in the `Tap-Receipt` header
+const TAP_VERSIONS: &[&str] = &["v1", "v2"];
+
+/// Served at `/version`, for gateways to find out what the service supports
 #[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct IndexerServiceRelease {
     version: String,
+    git_sha: Option<&'static str>,
+    api_versions: &'static [&'static str],
+    tap_versions: &'static [&'static str],
     dependencies: HashMap<String, String>,
 }
 
@@ -16,6 +26,9 @@ impl From<&BuildInfo> for IndexerServiceRelease {
     fn from(value: &BuildInfo) -> Self {
         Self {
             version: value.crate_info.version.to_string(),
+            git_sha: option_env!("INDEXER_SERVICE_GIT_SHA"),
+            api_versions: API_VERSIONS,
+            tap_versions: TAP_VERSIONS,
             dependencies: HashMap::from_iter(
                 value
                     .crate_info
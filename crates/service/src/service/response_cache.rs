@@ -0,0 +1,218 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache for the responses of free queries
+//!
+//! Monitoring dashboards send the same status and health queries over and
+//! over. Their responses are kept for a TTL so that only the first one
+//! reaches graph-node. Responses that are not pinned to a block are dropped
+//! as soon as the chain head of one of the indexed networks moves.
+
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use graphql_client::GraphQLQuery;
+use indexer_query::chain_heads_query::{self, ChainHeadsQuery};
+use lru::LruCache;
+use reqwest::Url;
+use serde_json::Value;
+use thegraph_core::alloy::primitives::{keccak256, B256};
+use tokio::time::{self, MissedTickBehavior};
+
+use super::BlockConstraint;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    deployment: Option<String>,
+    query_hash: B256,
+    block: BlockConstraint,
+}
+
+impl CacheKey {
+    pub fn new(deployment: Option<String>, query: &[u8], block: BlockConstraint) -> Self {
+        Self {
+            deployment,
+            query_hash: keccak256(query),
+            block,
+        }
+    }
+}
+
+struct CacheEntry {
+    response: Value,
+    expires_at: Instant,
+}
+
+/// LRU cache of query responses, shared by the free query routes
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<LruCache<CacheKey, CacheEntry>>>,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(LruCache::new(max_entries))),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let expired = match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => {
+                return Some(entry.response.clone())
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            entries.pop(key);
+        }
+        None
+    }
+
+    pub fn insert(&self, key: CacheKey, response: Value) {
+        let entry = CacheEntry {
+            response,
+            expires_at: Instant::now() + self.ttl,
+        };
+        self.entries.lock().unwrap().put(key, entry);
+    }
+
+    /// Drops the responses that are not pinned to a block
+    pub fn invalidate_latest(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let keys: Vec<_> = entries
+            .iter()
+            .filter(|(key, _)| key.block == BlockConstraint::Latest)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            entries.pop(&key);
+        }
+    }
+
+    /// Polls graph-node for the chain heads of the indexed networks and
+    /// drops the responses that are not pinned to a block when one moves
+    pub fn watch_chain_heads(
+        &self,
+        graph_node_client: reqwest::Client,
+        graph_node_status_url: Url,
+        interval: Duration,
+    ) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut chain_heads = HashMap::new();
+            let mut interval = time::interval(interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                match query_chain_heads(&graph_node_client, &graph_node_status_url).await {
+                    Ok(latest_chain_heads) => {
+                        if latest_chain_heads != chain_heads {
+                            cache.invalidate_latest();
+                            chain_heads = latest_chain_heads;
+                        }
+                    }
+                    Err(error) => tracing::warn!(
+                        %error,
+                        "Failed to query chain heads, cached responses only expire after their TTL"
+                    ),
+                }
+            }
+        });
+    }
+}
+
+async fn query_chain_heads(
+    graph_node_client: &reqwest::Client,
+    graph_node_status_url: &Url,
+) -> anyhow::Result<HashMap<String, String>> {
+    let response: graphql_client::Response<chain_heads_query::ResponseData> = graph_node_client
+        .post(graph_node_status_url.clone())
+        .json(&ChainHeadsQuery::build_query(chain_heads_query::Variables))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let data = response
+        .data
+        .ok_or_else(|| anyhow!("No data in chain heads response: {:?}", response.errors))?;
+
+    Ok(data
+        .indexing_statuses
+        .into_iter()
+        .flat_map(|status| status.chains)
+        .filter_map(|chain| {
+            chain
+                .chain_head_block
+                .map(|block| (chain.network, block.number))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use serde_json::json;
+
+    use super::{CacheKey, ResponseCache};
+    use crate::service::BlockConstraint;
+
+    fn cache(ttl: Duration) -> ResponseCache {
+        ResponseCache::new(NonZeroUsize::new(2).unwrap(), ttl)
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = cache(Duration::from_secs(60));
+        let keys: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|query| CacheKey::new(None, query.as_bytes(), BlockConstraint::Latest))
+            .collect();
+
+        cache.insert(keys[0].clone(), json!(0));
+        cache.insert(keys[1].clone(), json!(1));
+        // mark the first one as recently used
+        assert_eq!(cache.get(&keys[0]), Some(json!(0)));
+        cache.insert(keys[2].clone(), json!(2));
+
+        assert_eq!(cache.get(&keys[0]), Some(json!(0)));
+        assert_eq!(cache.get(&keys[1]), None);
+        assert_eq!(cache.get(&keys[2]), Some(json!(2)));
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let cache = cache(Duration::from_millis(50));
+        let key = CacheKey::new(None, b"query", BlockConstraint::Latest);
+        cache.insert(key.clone(), json!({ "data": {} }));
+        assert!(cache.get(&key).is_some());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.get(&key), None);
+    }
+
+    #[test]
+    fn test_invalidate_latest() {
+        let cache = cache(Duration::from_secs(60));
+        let latest = CacheKey::new(None, b"query", BlockConstraint::Latest);
+        let pinned = CacheKey::new(None, b"query", BlockConstraint::Number(100));
+        cache.insert(latest.clone(), json!("latest"));
+        cache.insert(pinned.clone(), json!("pinned"));
+
+        cache.invalidate_latest();
+
+        assert_eq!(cache.get(&latest), None);
+        assert_eq!(cache.get(&pinned), Some(json!("pinned")));
+    }
+}
@@ -1,62 +1,93 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use anyhow::Context;
 use async_graphql_axum::GraphQL;
 use axum::{
-    extract::MatchedPath,
-    http::Request,
+    extract::{DefaultBodyLimit, MatchedPath},
+    http::{HeaderValue, Request},
     middleware::{from_fn, from_fn_with_state},
-    routing::{get, post, post_service},
+    routing::{get, post, post_service, MethodRouter},
     Json, Router,
 };
 use governor::{clock::QuantaInstant, middleware::NoOpMiddleware};
+use indexer_attestation::ResponseSigner;
 use indexer_config::{
     BlockchainConfig, EscrowSubgraphConfig, GraphNodeConfig, IndexerConfig, NetworkSubgraphConfig,
-    ServiceConfig, ServiceTapConfig,
+    NonZeroGRT, ServiceConfig, ServiceTapConfig, SignedRoute,
 };
+use indexer_dips::database::PsqlAgreementStore;
 use indexer_monitor::{
-    attestation_signers, deployment_to_allocation, dispute_manager, escrow_accounts_v1,
-    escrow_accounts_v2, indexer_allocations, AllocationWatcher, DisputeManagerWatcher,
-    EscrowAccountsWatcher, SubgraphClient,
+    attestation_signers, attestation_signers_by_indexer, deployment_to_allocation, dispute_manager,
+    escrow_accounts_v1, escrow_accounts_v2, indexer_allocations, merge_allocations,
+    merge_escrow_accounts, subgraph_manifests, AllocationCache, AllocationWatcher, ContractSigners,
+    DisputeManagerWatcher, EscrowAccountsWatcher, EscrowRpcFallback, IpfsClient, SubgraphCache,
+    SubgraphClient,
 };
-use reqwest::Method;
+use indexer_receipt::store::{PgReceiptStore, ReceiptStore};
+use indexer_watcher::Watcher;
 use tap_core::{manager::Manager, receipt::checks::CheckList};
-use thegraph_core::alloy::sol_types::Eip712Domain;
+use thegraph_core::alloy::{primitives::Address, sol_types::Eip712Domain};
 use tower::ServiceBuilder;
 use tower_governor::{
     governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor, GovernorLayer,
 };
 use tower_http::{
-    auth::AsyncRequireAuthorizationLayer,
-    cors::{self, CorsLayer},
-    trace::TraceLayer,
+    auth::AsyncRequireAuthorizationLayer, trace::TraceLayer,
     validate_request::ValidateRequestHeaderLayer,
 };
 
-use super::{release::IndexerServiceRelease, GraphNodeState};
+use super::{
+    allocation_events::record_allocation_events,
+    graph_node_client::graph_node_client,
+    prewarm::Prewarm,
+    receipt_ingest::{reconcile_deferred_queries, ReceiptIngest},
+    release::IndexerServiceRelease,
+    GraphNodeState, Hedging, QueryNodes, QueryStats, ResponseCache,
+};
+#[cfg(feature = "wasm-plugin")]
+use crate::middleware::{wasm_plugin_middleware, WasmPlugin};
 use crate::{
-    metrics::{FAILED_RECEIPT, HANDLER_HISTOGRAM},
+    metrics::{receipt_value_histogram, FAILED_RECEIPT, HANDLER_HISTOGRAM},
     middleware::{
-        allocation_middleware, attestation_middleware,
+        allocation_closing_middleware, allocation_middleware, attestation_middleware,
         auth::{self, Bearer, OrExt},
-        context_middleware, deployment_middleware, labels_middleware, receipt_middleware,
-        sender_middleware, signer_middleware, AllocationState, AttestationState,
-        PrometheusMetricsMiddlewareLayer, SenderState,
+        body_limit_middleware, compression_layer, context_middleware, cors_layer,
+        decompression_layer, deployment_access_middleware, deployment_middleware,
+        labels_middleware, legacy_route_middleware, manifest_middleware,
+        query_blocklist_middleware, query_limits_middleware, query_stats_middleware,
+        receipt_middleware, receipt_refund_middleware, receipt_replay_middleware,
+        receipt_timestamp_middleware, request_id_middleware, response_signature_middleware,
+        route_context_middleware, security_headers_middleware, sender_middleware,
+        signer_middleware, AllocationState, AttestationState, ClosingAllocations,
+        DeploymentAccessState, LegacyRoutes, ManifestState, MultipleReceipts,
+        PrometheusMetricsMiddlewareLayer, QueryBlocklist, ReceiptReplayState,
+        ReceiptTimestampState, SenderState,
+    },
+    routes::{
+        self, health, healthz, readyz, request_handler, static_subgraph_request_handler,
+        EscrowAccountsState, FeesSummaryState, ServiceHealthState, WatcherHealth,
     },
-    routes::{self, health, request_handler, static_subgraph_request_handler},
-    tap::IndexerTapContext,
-    wallet::public_key,
+    tap::{FeaturePrices, IndexerTapContext, RoutePrice, SignerCache},
+    wallet::{build_wallet, public_key},
 };
 
 #[derive(bon::Builder)]
 pub struct ServiceRouter {
     // database
     database: sqlx::PgPool,
+    // where the accepted receipts are stored, the database if not set
+    receipt_store: Option<Arc<dyn ReceiptStore>>,
     // tap domain
     domain_separator: Eip712Domain,
-    // graphnode client
+    // client of the network services, graph-node has its own
     http_client: reqwest::Client,
     // release info
     release: Option<IndexerServiceRelease>,
@@ -85,6 +116,14 @@ pub struct ServiceRouter {
     network_subgraph: Option<(&'static SubgraphClient, NetworkSubgraphConfig)>,
     allocations: Option<AllocationWatcher>,
     dispute_manager: Option<DisputeManagerWatcher>,
+    // where the watchers of the subgraphs save what they read, to start
+    // with it while the subgraphs are down
+    subgraph_cache: Option<SubgraphCache>,
+
+    // serve the DIPS agreements to the holders of this token
+    dips_agreements_auth_token: Option<String>,
+    // override the log levels at runtime with this token
+    log_admin_token: Option<String>,
 }
 
 const MISC_BURST_SIZE: u32 = 10;
@@ -97,97 +136,373 @@ const DISPUTE_MANAGER_INTERVAL: Duration = Duration::from_secs(3600);
 
 const DEFAULT_ROUTE: &str = "/";
 
+/// Prefix of the current version of the routes, they are also served without
+/// it, with deprecation headers
+const API_PREFIX: &str = "/v1";
+/// Versions of the routes, reported at `/version`
+pub(super) const API_VERSIONS: &[&str] = &["v1"];
+
 impl ServiceRouter {
     pub async fn create_router(self) -> anyhow::Result<Router> {
+        let started_at = Instant::now();
         let IndexerConfig {
             indexer_address,
             operator_mnemonic,
+            additional_indexers,
         } = self.indexer;
         let ServiceConfig {
             serve_network_subgraph,
             serve_escrow_subgraph,
             serve_auth_token,
             url_prefix,
-            tap: ServiceTapConfig {
-                max_receipt_value_grt,
-            },
+            tap:
+                ServiceTapConfig {
+                    max_receipt_value_grt,
+                    receipt_timestamp,
+                    receipt_value_buckets_grt,
+                    signer_cache_ttl_secs,
+                    checks: receipt_checks,
+                },
             free_query_auth_token,
+            api_key_admin_token,
+            allocation_override_admin_token,
+            response_cache,
+            public_stats,
+            fees_summary,
+            prewarm,
+            partial_response,
+            subgraph_manifests: manifests_config,
+            allowed_deployments,
+            denied_deployments,
+            cors,
+            compression,
+            body_limits,
+            receipt_ingest,
+            free_query_signature,
+            query_limits,
+            query_blocklist,
+            wasm_plugin,
+            paid_routes,
+            legacy_routes_sunset,
             ..
         } = self.service;
 
+        // sign the responses of the free queries of the configured routes
+        let response_signer = match &free_query_signature {
+            Some(config) if !config.routes.is_empty() => {
+                let signer = ResponseSigner::new(
+                    build_wallet(&operator_mnemonic.to_string())?,
+                    self.blockchain.chain_id as u64,
+                );
+                tracing::info!(
+                    signer = %signer.address(),
+                    routes = ?config.routes,
+                    "Signing the responses of free queries"
+                );
+                Some(signer)
+            }
+            _ => None,
+        };
+        let signer_for = |route: SignedRoute| {
+            free_query_signature
+                .as_ref()
+                .filter(|config| config.routes.contains(&route))
+                .and(response_signer.clone())
+        };
+
+        // forwards the queries to graph-node, reusing its connections
+        let graph_node_client = graph_node_client(&self.graph_node.client)?;
+
         // COST
         let cost_schema = routes::cost::build_schema(self.database.clone()).await;
-        let post_cost = post_service(GraphQL::new(cost_schema));
+        let post_cost = limit_body(
+            post_service(GraphQL::new(cost_schema)),
+            body_limits.cost_bytes,
+        );
 
         // STATUS
-        let post_status = post(routes::status);
+        let mut post_status = limit_body(
+            sign_responses(post(routes::status), signer_for(SignedRoute::Status)),
+            body_limits.status_bytes,
+        );
 
-        // Monitor the indexer's own allocations
+        // Monitor the allocations of every indexer served
         // if not provided, create monitor from subgraph
-        let allocations = match (self.allocations, self.network_subgraph.as_ref()) {
-            (Some(allocations), _) => allocations,
-            (_, Some((network_subgraph, network))) => indexer_allocations(
-                network_subgraph,
-                indexer_address,
-                network.config.syncing_interval_secs,
-                network.recently_closed_allocation_buffer_secs,
+        // the watcher of the own allocations is checked by `/readyz`
+        let (allocations, allocations_watcher) =
+            match (self.allocations, self.network_subgraph.as_ref()) {
+                (Some(allocations), _) => (allocations, None),
+                (_, Some((network_subgraph, network))) => {
+                    let mut watchers = Vec::new();
+                    let indexers = iter::once(indexer_address).chain(
+                        additional_indexers
+                            .iter()
+                            .map(|indexer| indexer.indexer_address),
+                    );
+                    for indexer_address in indexers {
+                        watchers.push(
+                            indexer_allocations(
+                                network_subgraph,
+                                indexer_address,
+                                network.config.syncing_interval_secs,
+                                network.recently_closed_allocation_buffer_secs,
+                                network.max_block_lag,
+                                network.max_block_age_secs,
+                                self.subgraph_cache.clone(),
+                            )
+                            .await
+                            .expect("Failed to initialize indexer_allocations watcher"),
+                        );
+                    }
+                    let own_watcher = watchers[0].clone();
+                    let mut allocations: Vec<_> =
+                        watchers.into_iter().map(Watcher::into_receiver).collect();
+                    let own_allocations = allocations.remove(0);
+                    (
+                        merge_allocations(own_allocations, allocations),
+                        Some(WatcherHealth::new(
+                            own_watcher,
+                            network.config.syncing_interval_secs,
+                        )),
+                    )
+                }
+                (None, None) => panic!("No allocations or network subgraph was provided"),
+            };
+
+        // Record the lifecycle of the allocations, replicas record the same
+        // events only once
+        tokio::spawn(record_allocation_events(
+            self.database.clone(),
+            self.network_subgraph
+                .as_ref()
+                .map(|(network_subgraph, _)| *network_subgraph),
+            allocations.clone(),
+        ));
+
+        let indexer_addresses: HashSet<Address> = iter::once(indexer_address)
+            .chain(
+                additional_indexers
+                    .iter()
+                    .map(|indexer| indexer.indexer_address),
             )
-            .await
-            .expect("Failed to initialize indexer_allocations watcher"),
-            (None, None) => panic!("No allocations or network subgraph was provided"),
-        };
+            .collect();
+
+        // Fetch the manifests of the allocated deployments
+        let manifests = manifests_config.map(|config| {
+            tracing::info!(ipfs_url = %config.ipfs_url, "Fetching subgraph manifests");
+            let ipfs_client = IpfsClient::new(self.http_client.clone(), config.ipfs_url.clone());
+            (subgraph_manifests(allocations.clone(), ipfs_client), config)
+        });
+
+        // Read the escrow accounts from the contract while the subgraph is stale
+        let escrow_rpc_fallback = self
+            .escrow_subgraph
+            .as_ref()
+            .and_then(|(_, escrow)| escrow.rpc_fallback.as_ref())
+            .map(|fallback| {
+                EscrowRpcFallback::new(
+                    self.http_client.clone(),
+                    fallback.rpc_url.clone(),
+                    fallback.escrow_address,
+                    fallback.max_subgraph_lag_secs,
+                )
+                .with_signers(fallback.signers.clone())
+            });
+
+        // The receipts signed before older revocations are rejected as too old
+        let revoked_signers_max_age = receipt_timestamp
+            .as_ref()
+            .map(|receipt_timestamp| receipt_timestamp.max_age_secs);
+
+        // Verify the receipts signed for contract wallets
+        let contract_signers = self.blockchain.contract_signers.as_ref().map(|config| {
+            ContractSigners::new(
+                self.http_client.clone(),
+                config.rpc_url.clone(),
+                config.cache_ttl_secs,
+            )
+        });
 
         // Monitor escrow accounts v1
         // if not provided, create monitor from subgraph
-        let escrow_accounts_v1 = match (self.escrow_accounts_v1, self.escrow_subgraph.as_ref()) {
+        // the watcher is checked by `/readyz`
+        let (escrow_accounts_v1, escrow_accounts_watcher) =
+            match (self.escrow_accounts_v1, self.escrow_subgraph.as_ref()) {
+                (Some(escrow_account), _) => (escrow_account, None),
+                (_, Some((escrow_subgraph, escrow))) => {
+                    let watcher = escrow_accounts_v1(
+                        escrow_subgraph,
+                        indexer_address,
+                        escrow.config.syncing_interval_secs,
+                        true, // Reject thawing signers eagerly
+                        escrow_rpc_fallback.clone(),
+                        self.subgraph_cache.clone(),
+                        revoked_signers_max_age,
+                    )
+                    .await
+                    .expect("Error creating escrow_accounts channel");
+                    (
+                        watcher.receiver(),
+                        Some(WatcherHealth::new(
+                            watcher,
+                            escrow.config.syncing_interval_secs,
+                        )),
+                    )
+                }
+                (None, None) => panic!("No escrow accounts or escrow subgraph was provided"),
+            };
+
+        // Monitor escrow accounts v2
+        // if not provided, create monitor from subgraph
+        let escrow_accounts_v2 = match (self.escrow_accounts_v2, self.escrow_subgraph.as_ref()) {
             (Some(escrow_account), _) => escrow_account,
-            (_, Some((escrow_subgraph, escrow))) => escrow_accounts_v1(
+            (_, Some((escrow_subgraph, escrow))) => escrow_accounts_v2(
                 escrow_subgraph,
                 indexer_address,
                 escrow.config.syncing_interval_secs,
                 true, // Reject thawing signers eagerly
             )
             .await
-            .expect("Error creating escrow_accounts channel"),
+            .expect("Error creating escrow_accounts channel")
+            .into_receiver(),
             (None, None) => panic!("No escrow accounts or escrow subgraph was provided"),
         };
 
-        // Monitor escrow accounts v2
-        // if not provided, create monitor from subgraph
-        let escrow_accounts_v2 = match (self.escrow_accounts_v2, self.escrow_subgraph.as_ref()) {
-            (Some(escrow_account), _) => escrow_account,
-            (_, Some((escrow_subgraph, escrow))) => escrow_accounts_v2(
+        // Monitor the escrow accounts of the additional indexers, the balance
+        // of a sender is checked against the accounts of the receipt's indexer
+        let mut additional_escrow_accounts = Vec::new();
+        for indexer in &additional_indexers {
+            let Some((escrow_subgraph, escrow)) = self.escrow_subgraph.as_ref() else {
+                panic!("No escrow subgraph was provided for the additional indexers");
+            };
+            let v1 = indexer_monitor::escrow_accounts_v1(
                 escrow_subgraph,
-                indexer_address,
+                indexer.indexer_address,
                 escrow.config.syncing_interval_secs,
                 true, // Reject thawing signers eagerly
+                escrow_rpc_fallback.clone(),
+                self.subgraph_cache.clone(),
+                revoked_signers_max_age,
             )
             .await
-            .expect("Error creating escrow_accounts channel"),
-            (None, None) => panic!("No escrow accounts or escrow subgraph was provided"),
+            .expect("Error creating escrow_accounts channel")
+            .into_receiver();
+            let v2 = indexer_monitor::escrow_accounts_v2(
+                escrow_subgraph,
+                indexer.indexer_address,
+                escrow.config.syncing_interval_secs,
+                true, // Reject thawing signers eagerly
+            )
+            .await
+            .expect("Error creating escrow_accounts channel")
+            .into_receiver();
+            additional_escrow_accounts.push((indexer.indexer_address, v1, v2));
+        }
+        let indexers_escrow_accounts_v1: HashMap<Address, EscrowAccountsWatcher> =
+            iter::once((indexer_address, escrow_accounts_v1.clone()))
+                .chain(
+                    additional_escrow_accounts
+                        .iter()
+                        .map(|(indexer, v1, _)| (*indexer, v1.clone())),
+                )
+                .collect();
+        let indexers_escrow_accounts_v2: HashMap<Address, EscrowAccountsWatcher> =
+            iter::once((indexer_address, escrow_accounts_v2.clone()))
+                .chain(
+                    additional_escrow_accounts
+                        .iter()
+                        .map(|(indexer, _, v2)| (*indexer, v2.clone())),
+                )
+                .collect();
+        // Only used to find the sender of a signer from now on
+        let (escrow_accounts_v1, escrow_accounts_v2) = if additional_escrow_accounts.is_empty() {
+            (escrow_accounts_v1, escrow_accounts_v2)
+        } else {
+            let (others_v1, others_v2): (Vec<_>, Vec<_>) = additional_escrow_accounts
+                .into_iter()
+                .map(|(_, v1, v2)| (v1, v2))
+                .unzip();
+            (
+                merge_escrow_accounts(escrow_accounts_v1, others_v1),
+                merge_escrow_accounts(escrow_accounts_v2, others_v2),
+            )
         };
 
         // Monitor dispute manager address
         // if not provided, create monitor from subgraph
         let dispute_manager = match (self.dispute_manager, self.network_subgraph.as_ref()) {
             (Some(dispute_manager), _) => dispute_manager,
-            (_, Some((network_subgraph, _))) => {
-                dispute_manager(network_subgraph, DISPUTE_MANAGER_INTERVAL)
-                    .await
-                    .expect("Failed to initialize dispute manager")
-            }
+            (_, Some((network_subgraph, _))) => dispute_manager(
+                network_subgraph,
+                DISPUTE_MANAGER_INTERVAL,
+                self.subgraph_cache.clone(),
+                self.blockchain.dispute_manager_address,
+            )
+            .await
+            .expect("Failed to initialize dispute manager")
+            .into_receiver(),
             (None, None) => panic!("No dispute allocations or network subgraph was provided"),
         };
+        let expected_dispute_manager = self.blockchain.dispute_manager_address;
+        if let Some(expected) = expected_dispute_manager {
+            let current = *dispute_manager.borrow();
+            if current != expected {
+                tracing::warn!(
+                    %expected,
+                    %current,
+                    "The network subgraph reports another dispute manager than the \
+                    configured one, queries for allocations are refused meanwhile"
+                );
+            }
+        }
 
         // Maintain an up-to-date set of attestation signers, one for each
-        // allocation
-        let attestation_signers = attestation_signers(
+        // allocation, derived from the operator of the allocation's indexer
+        let attestation_signers = if additional_indexers.is_empty() {
+            attestation_signers(
+                allocations.clone(),
+                operator_mnemonic.clone(),
+                self.blockchain.chain_id as u64,
+                dispute_manager.clone(),
+            )
+        } else {
+            let indexer_mnemonics = iter::once((indexer_address, operator_mnemonic.clone()))
+                .chain(
+                    additional_indexers
+                        .into_iter()
+                        .map(|indexer| (indexer.indexer_address, indexer.operator_mnemonic)),
+                )
+                .collect();
+            attestation_signers_by_indexer(
+                allocations.clone(),
+                indexer_mnemonics,
+                self.blockchain.chain_id as u64,
+                dispute_manager.clone(),
+            )
+        };
+
+        // Allocations of the receipts, the ones created since the allocations
+        // were last read are looked up in the network subgraph, and accepted
+        // once they can be attested
+        let allocation_cache = AllocationCache::new(
             allocations.clone(),
-            operator_mnemonic.clone(),
-            self.blockchain.chain_id as u64,
-            dispute_manager,
+            indexer_addresses,
+            self.network_subgraph
+                .as_ref()
+                .map(|(network_subgraph, _)| *network_subgraph),
+            Some(attestation_signers.clone()),
         );
 
+        let prewarm = prewarm.then(|| {
+            Prewarm::new(
+                started_at,
+                &allocations,
+                &attestation_signers,
+                &escrow_accounts_v1,
+                &escrow_accounts_v2,
+            )
+        });
+
         // Rate limits by allowing bursts of 10 requests and requiring 100ms of
         // time between consecutive requests after that, effectively rate
         // limiting to 10 req/s.
@@ -212,10 +527,13 @@ impl ServiceRouter {
 
                 Router::new().route(
                     DEFAULT_ROUTE,
-                    post(static_subgraph_request_handler)
-                        .route_layer(auth_layer)
-                        .route_layer(static_subgraph_rate_limiter.clone())
-                        .with_state(network_subgraph),
+                    sign_responses(
+                        post(static_subgraph_request_handler),
+                        signer_for(SignedRoute::Network),
+                    )
+                    .route_layer(auth_layer)
+                    .route_layer(static_subgraph_rate_limiter.clone())
+                    .with_state(network_subgraph),
                 )
             }
             (_, true, _) => {
@@ -238,10 +556,13 @@ impl ServiceRouter {
 
                 Router::new().route(
                     DEFAULT_ROUTE,
-                    post(static_subgraph_request_handler)
-                        .route_layer(auth_layer)
-                        .route_layer(static_subgraph_rate_limiter)
-                        .with_state(escrow_subgraph),
+                    sign_responses(
+                        post(static_subgraph_request_handler),
+                        signer_for(SignedRoute::Escrow),
+                    )
+                    .route_layer(auth_layer)
+                    .route_layer(static_subgraph_rate_limiter)
+                    .with_state(escrow_subgraph),
                 )
             }
             (_, true, _) => {
@@ -251,76 +572,433 @@ impl ServiceRouter {
             _ => Router::new(),
         };
 
+        // load escrow accounts route, for the holders of the same token
+        let escrow_accounts = match serve_auth_token.as_ref() {
+            Some(auth_token) => {
+                tracing::info!("Serving escrow accounts at /escrow/accounts");
+                routes::escrow_accounts_router(EscrowAccountsState::new(
+                    &indexers_escrow_accounts_v1,
+                    &indexers_escrow_accounts_v2,
+                ))
+                .route_layer(ValidateRequestHeaderLayer::bearer(auth_token))
+            }
+            None => Router::new(),
+        };
+
+        let service_health_state = ServiceHealthState {
+            database: self.database.clone(),
+            graph_node_client: graph_node_client.clone(),
+            graph_node_status_url: self.graph_node.status_url.clone(),
+            graph_node_query_base_url: self.graph_node.query_url.clone(),
+            network_subgraph: self
+                .network_subgraph
+                .as_ref()
+                .map(|(network_subgraph, _)| *network_subgraph),
+            network_subgraph_max_age: self
+                .network_subgraph
+                .as_ref()
+                .map(|(_, network)| network.health_max_block_age_secs)
+                .unwrap_or_default(),
+            allocations: allocations_watcher,
+            escrow_accounts: escrow_accounts_watcher,
+        };
+
+        // load fees summary route
+        let fees = match fees_summary {
+            Some(config) => {
+                tracing::info!("Serving fees summary at /fees/summary");
+                routes::fees_router(FeesSummaryState::new(
+                    self.database.clone(),
+                    escrow_accounts_v1.clone(),
+                    config.cache_ttl_secs,
+                ))
+                .route_layer(ValidateRequestHeaderLayer::bearer(&config.auth_token))
+            }
+            None => Router::new(),
+        };
+
+        // load api keys management route
+        let api_keys = match api_key_admin_token.as_ref() {
+            Some(admin_token) => {
+                tracing::info!("Serving free query API keys management at /api-keys");
+                routes::api_keys_router(self.database.clone())
+                    .route_layer(ValidateRequestHeaderLayer::bearer(admin_token))
+            }
+            None => Router::new(),
+        };
+
+        // load allocation eligibility overrides management route
+        let allocation_overrides = match allocation_override_admin_token.as_ref() {
+            Some(admin_token) => {
+                tracing::info!(
+                    "Serving allocation eligibility overrides management at /allocation-overrides"
+                );
+                routes::allocation_overrides_router(self.database.clone())
+                    .route_layer(ValidateRequestHeaderLayer::bearer(admin_token))
+            }
+            None => Router::new(),
+        };
+
+        // load allocation events management route
+        let allocation_events = match allocation_override_admin_token.as_ref() {
+            Some(admin_token) => {
+                tracing::info!("Serving allocation events at /allocation-events");
+                routes::allocation_events_router(self.database.clone())
+                    .route_layer(ValidateRequestHeaderLayer::bearer(admin_token))
+            }
+            None => Router::new(),
+        };
+
+        // load query blocklist management route
+        let query_blocklist_routes = match query_blocklist
+            .as_ref()
+            .and_then(|config| config.admin_token.as_ref())
+        {
+            Some(admin_token) => {
+                tracing::info!("Serving query blocklist management at /query-blocklist");
+                routes::query_blocklist_router(self.database.clone())
+                    .route_layer(ValidateRequestHeaderLayer::bearer(admin_token))
+            }
+            None => Router::new(),
+        };
+        let query_blocklist = match &query_blocklist {
+            Some(config) => Some(QueryBlocklist::new(self.database.clone(), config).await?),
+            None => None,
+        };
+        #[cfg(feature = "wasm-plugin")]
+        let wasm_plugin = match &wasm_plugin {
+            Some(config) => {
+                tracing::info!(path = %config.path.display(), "Loaded the WebAssembly plugin");
+                Some(WasmPlugin::from_config(config, body_limits.query_bytes)?)
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "wasm-plugin"))]
+        if wasm_plugin.is_some() {
+            return Err(anyhow::anyhow!(
+                "The WebAssembly plugin can't be loaded, indexer-service was built \
+                without the `wasm-plugin` feature"
+            ));
+        }
+
+        // load dips agreements route
+        let dips_agreements = match self.dips_agreements_auth_token.as_ref() {
+            Some(auth_token) => {
+                tracing::info!("Serving DIPS agreements at /dips/agreements");
+                routes::agreements_router(Arc::new(PsqlAgreementStore {
+                    pool: self.database.clone(),
+                }))
+                .route_layer(ValidateRequestHeaderLayer::bearer(auth_token))
+            }
+            None => Router::new(),
+        };
+
+        // load log levels management route
+        let log_levels = match self.log_admin_token.as_ref() {
+            Some(admin_token) => {
+                tracing::info!("Serving log levels management at /log-levels");
+                indexer_telemetry::log_levels_router()
+                    .route_layer(ValidateRequestHeaderLayer::bearer(admin_token))
+            }
+            None => Router::new(),
+        };
+
+        let receipt_store = self
+            .receipt_store
+            .unwrap_or_else(|| Arc::new(PgReceiptStore::new(self.database.clone())));
+        let multiple_receipts = MultipleReceipts(receipt_store.stores_request_ids());
+
+        let query_stats = public_stats.as_ref().map(|config| {
+            tracing::info!("Serving query statistics at /stats");
+            QueryStats::new(config.granularity_secs, config.min_queries)
+        });
+
+        // HEALTH
+        let mut get_health = get(health);
+
         let post_request_handler = {
             // Create tap manager to validate receipts
-            let tap_manager = {
+            let (tap_manager, receipt_refunds) = {
                 // Create context
                 let indexer_context =
-                    IndexerTapContext::new(self.database.clone(), self.domain_separator.clone())
-                        .await;
+                    IndexerTapContext::new(receipt_store.clone(), self.domain_separator.clone())
+                        .await
+                        .with_contract_signers(contract_signers.clone());
+                let receipt_refunds = indexer_context.receipt_refunds();
 
                 let timestamp_error_tolerance = self.timestamp_buffer_secs;
                 let receipt_max_value = max_receipt_value_grt.get_value();
 
                 // Create checks
                 let checks = IndexerTapContext::get_checks(
-                    self.database,
-                    allocations.clone(),
-                    escrow_accounts_v1.clone(),
-                    escrow_accounts_v2.clone(),
+                    self.database.clone(),
+                    allocation_cache,
+                    indexers_escrow_accounts_v1,
+                    indexers_escrow_accounts_v2,
                     timestamp_error_tolerance,
                     receipt_max_value,
+                    manifests
+                        .as_ref()
+                        .filter(|(_, config)| !config.price_multipliers.is_empty())
+                        .map(|(manifests, config)| FeaturePrices {
+                            manifests: manifests.clone(),
+                            multipliers: config.price_multipliers.clone(),
+                        }),
+                    receipt_checks,
+                    allocation_override_admin_token.is_some(),
                 )
                 .await;
+                #[cfg(feature = "wasm-plugin")]
+                let checks: Vec<_> = checks
+                    .into_iter()
+                    .chain(IndexerTapContext::wasm_plugin_check(
+                        receipt_checks,
+                        wasm_plugin.clone(),
+                    ))
+                    .collect();
+                let disabled_checks = receipt_checks.disabled();
+                if !disabled_checks.is_empty() {
+                    tracing::warn!(
+                        ?disabled_checks,
+                        "Some receipt checks are disabled, the receipts failing them are accepted"
+                    );
+                }
                 // Returned static Manager
-                Arc::new(Manager::new(
+                let tap_manager = Arc::new(Manager::new(
                     self.domain_separator.clone(),
                     indexer_context,
                     CheckList::new(checks),
-                ))
+                ));
+                (tap_manager, receipt_refunds)
             };
 
             let attestation_state = AttestationState {
-                attestation_signers,
+                attestation_signers: attestation_signers.clone(),
+                dispute_manager,
+                expected_dispute_manager,
             };
 
             let mut handler = post(request_handler);
 
+            // run the plugin before the attestation, which signs the response
+            // as modified by the plugin
+            #[cfg(feature = "wasm-plugin")]
+            if let Some(wasm_plugin) = wasm_plugin {
+                handler =
+                    handler.route_layer(from_fn_with_state(wasm_plugin, wasm_plugin_middleware));
+            }
+
             handler = handler
                 // create attestation
                 .route_layer(from_fn(attestation_middleware))
                 // inject signer
                 .route_layer(from_fn_with_state(attestation_state, signer_middleware));
 
+            let sender_state = SenderState {
+                signers_v1: SignerCache::new(escrow_accounts_v1.clone(), signer_cache_ttl_secs),
+                signers_v2: SignerCache::new(escrow_accounts_v2.clone(), signer_cache_ttl_secs),
+                escrow_accounts_v1,
+                escrow_accounts_v2,
+                contract_signers,
+                domain_separator: self.domain_separator,
+            };
+
+            // receive the receipts of the queries served without one
+            if let Some(receipt_ingest) = &receipt_ingest {
+                let ingest = ReceiptIngest::new(
+                    self.database.clone(),
+                    &receipt_ingest.auth_token,
+                    tap_manager.clone(),
+                    sender_state.clone(),
+                );
+                tokio::spawn(ingest.serve(receipt_ingest.host_and_port));
+                tokio::spawn(reconcile_deferred_queries(
+                    self.database.clone(),
+                    receipt_ingest.receipt_timeout_secs,
+                    receipt_ingest.retention_secs,
+                ));
+            }
+
             // inject auth
             let failed_receipt_metric = Box::leak(Box::new(FAILED_RECEIPT.clone()));
-            let tap_auth = auth::tap_receipt_authorize(tap_manager, failed_receipt_metric);
+            let receipt_value_metric = receipt_value_histogram(receipt_value_buckets_grt);
+            let tap_auth = auth::tap_receipt_authorize(
+                tap_manager,
+                failed_receipt_metric,
+                receipt_value_metric,
+            );
+            // receipts received before by any paid route
+            let receipt_replay = ReceiptReplayState::new(receipt_store.clone());
+            // allocations whose last RAVs are requested by tap-agent
+            let closing_allocations = match ClosingAllocations::new(self.database.clone()).await {
+                Ok(closing_allocations) => closing_allocations,
+                // the table may not be created yet by indexer-agent
+                Err(error) => {
+                    tracing::warn!(
+                        %error,
+                        "The receipts of the allocations closed by tap-agent are accepted, \
+                         upgrade indexer-agent or start with --migrate"
+                    );
+                    ClosingAllocations::none()
+                }
+            };
+
+            // the status and health queries paid at a fixed price
+            if let Some(paid_routes) = &paid_routes {
+                let charge = |route: MethodRouter<GraphNodeState>, price: &NonZeroGRT| {
+                    // the free queries of a paid route require the free query token
+                    let route = match &free_query_auth_token {
+                        Some(free_auth_token) => {
+                            route.route_layer(AsyncRequireAuthorizationLayer::new(
+                                Bearer::new(free_auth_token).or(tap_auth.clone()),
+                            ))
+                        }
+                        None => {
+                            route.route_layer(AsyncRequireAuthorizationLayer::new(tap_auth.clone()))
+                        }
+                    };
+                    route
+                        .route_layer(from_fn_with_state(
+                            receipt_replay.clone(),
+                            receipt_replay_middleware,
+                        ))
+                        .route_layer(from_fn_with_state(
+                            closing_allocations.clone(),
+                            allocation_closing_middleware,
+                        ))
+                        .route_layer(
+                            ServiceBuilder::new()
+                                .layer(from_fn(request_id_middleware))
+                                .layer(from_fn_with_state(multiple_receipts, receipt_middleware))
+                                .layer(from_fn_with_state(sender_state.clone(), sender_middleware))
+                                .layer(from_fn_with_state(
+                                    RoutePrice(price.get_value()),
+                                    route_context_middleware,
+                                )),
+                        )
+                };
+                if let Some(price) = &paid_routes.status_min_value_grt {
+                    tracing::info!(price = price.get_value(), "Charging the /status queries");
+                    post_status = charge(post_status, price);
+                }
+                if let Some(price) = &paid_routes.health_min_value_grt {
+                    tracing::info!(price = price.get_value(), "Charging the health queries");
+                    get_health = charge(get_health, price);
+                }
+            }
+
+            // queries of gateways delivering their receipts separately
+            let deferred_auth = auth::deferred_receipt_authorize(
+                self.database.clone(),
+                receipt_ingest
+                    .as_ref()
+                    .map(|receipt_ingest| receipt_ingest.auth_token.as_str()),
+                tap_auth,
+            );
+            // free queries using api keys, falling back to tap receipts. The
+            // keys are only looked up if they can be managed, their tables
+            // are not created by indexer-agent
+            let api_key_auth = auth::api_key_authorize(
+                api_key_admin_token.is_some().then_some(self.database),
+                deferred_auth,
+            );
 
             if let Some(free_auth_token) = &free_query_auth_token {
                 let free_query = Bearer::new(free_auth_token);
-                let result = free_query.or(tap_auth);
+                let result = free_query.or(api_key_auth);
                 let auth_layer = AsyncRequireAuthorizationLayer::new(result);
                 handler = handler.route_layer(auth_layer);
             } else {
-                let auth_layer = AsyncRequireAuthorizationLayer::new(tap_auth);
+                let auth_layer = AsyncRequireAuthorizationLayer::new(api_key_auth);
                 handler = handler.route_layer(auth_layer);
             }
 
+            // hold the receipts stored by the auth layer until the query is served,
+            // the ones of the queries that were not (refused partial responses,
+            // deadline exceeded, failed plugin) are never stored
+            handler = handler.route_layer(from_fn_with_state(
+                receipt_refunds,
+                receipt_refund_middleware,
+            ));
+
+            // reject the receipts already received by this or another replica
+            handler = handler.route_layer(from_fn_with_state(
+                receipt_replay,
+                receipt_replay_middleware,
+            ));
+
+            // refuse the receipts of the allocations closed by tap-agent
+            // before they are stored, the gateway switches allocations
+            handler = handler.route_layer(from_fn_with_state(
+                closing_allocations,
+                allocation_closing_middleware,
+            ));
+
+            // reject receipts from gateways with a bad clock before they are stored
+            if let Some(receipt_timestamp) = receipt_timestamp {
+                handler = handler.route_layer(from_fn_with_state(
+                    ReceiptTimestampState::from(receipt_timestamp),
+                    receipt_timestamp_middleware,
+                ));
+            }
+
+            // refuse the denied deployments before the receipt is stored
+            let deployment_access_state = DeploymentAccessState {
+                denied_by_network: indexer_monitor::denied_deployments(allocations.clone()),
+                allowed: Arc::new(allowed_deployments),
+                denied: Arc::new(denied_deployments),
+            };
+            handler = handler.route_layer(from_fn_with_state(
+                deployment_access_state,
+                deployment_access_middleware,
+            ));
+
+            // refuse the deployments that can't be served before the receipt is stored
+            if let Some((manifests, config)) = &manifests {
+                if !config.unsupported.is_empty() {
+                    let manifest_state = ManifestState {
+                        manifests: manifests.clone(),
+                        unsupported: Arc::new(config.unsupported.clone()),
+                    };
+                    handler = handler
+                        .route_layer(from_fn_with_state(manifest_state, manifest_middleware));
+                }
+            }
+
+            // refuse the blocked queries before the receipt is stored
+            if let Some(query_blocklist) = query_blocklist {
+                handler = handler.route_layer(from_fn_with_state(
+                    query_blocklist,
+                    query_blocklist_middleware,
+                ));
+            }
+
+            // refuse the pathological queries before the receipt is stored,
+            // and before they are parsed by the blocklist
+            if let Some(query_limits) = query_limits {
+                handler = handler.route_layer(from_fn_with_state(
+                    Arc::new(query_limits),
+                    query_limits_middleware,
+                ));
+            }
+
+            // count the queries served for the public statistics
+            if let Some(query_stats) = query_stats.clone() {
+                handler =
+                    handler.route_layer(from_fn_with_state(query_stats, query_stats_middleware));
+            }
+
             let deployment_to_allocation = deployment_to_allocation(allocations);
             let allocation_state = AllocationState {
                 deployment_to_allocation,
             };
-            let sender_state = SenderState {
-                escrow_accounts_v1,
-                escrow_accounts_v2,
-                domain_separator: self.domain_separator,
-            };
-
             let service_builder = ServiceBuilder::new()
+                // inject request id
+                .layer(from_fn(request_id_middleware))
                 // inject deployment id
                 .layer(from_fn(deployment_middleware))
                 // inject receipt
-                .layer(from_fn(receipt_middleware))
+                .layer(from_fn_with_state(multiple_receipts, receipt_middleware))
                 // inject allocation id
                 .layer(from_fn_with_state(allocation_state, allocation_middleware))
                 // inject sender
@@ -334,14 +1012,20 @@ impl ServiceRouter {
                 // tap context
                 .layer(from_fn(context_middleware));
 
+            // sign the free queries, outside of the auth layer that takes the
+            // receipt but inside the one that injects it
+            handler = sign_responses(handler, signer_for(SignedRoute::Subgraphs));
+
             handler.route_layer(service_builder)
         };
 
+        // cost models are loaded by the checks of the tap manager
+        if let Some(prewarm) = prewarm {
+            prewarm.finish(&service_health_state).await;
+        }
+
         // setup cors
-        let cors_layer = CorsLayer::new()
-            .allow_origin(cors::Any)
-            .allow_headers(cors::Any)
-            .allow_methods([Method::OPTIONS, Method::POST, Method::GET]);
+        let cors_layer = cors_layer(&cors)?;
 
         // add tracing to all routes
         let tracing_layer = TraceLayer::new_for_http()
@@ -353,12 +1037,17 @@ impl ServiceRouter {
                     .get::<MatchedPath>()
                     .map(MatchedPath::as_str);
 
-                tracing::info_span!(
+                let span = tracing::info_span!(
                     "http_request",
                     %method,
                     %uri,
                     matched_path,
-                )
+                    // recorded by the request id middleware of the queries
+                    request_id = tracing::field::Empty,
+                );
+                // continue the trace of the gateway
+                indexer_telemetry::set_parent_from_headers(&span, req.headers());
+                span
             })
             // we disable failures here because we are doing our own error logging
             .on_failure(
@@ -375,16 +1064,50 @@ impl ServiceRouter {
         let operator_address =
             Json(serde_json::json!({ "publicKey": public_key(&operator_mnemonic)?}));
 
+        let response_cache = response_cache.map(|config| {
+            let cache = ResponseCache::new(config.max_entries, config.ttl_secs);
+            cache.watch_chain_heads(
+                graph_node_client.clone(),
+                self.graph_node.status_url.clone(),
+                config.chain_head_poll_interval_secs,
+            );
+            cache
+        });
+
+        let query_nodes = match &self.graph_node.query_nodes {
+            Some(config) => {
+                tracing::info!(
+                    nodes = config.nodes.len(),
+                    routing = ?config.routing,
+                    "Routing the queries to the graph-node query nodes"
+                );
+                let query_nodes = QueryNodes::new(config);
+                query_nodes
+                    .watch_health(graph_node_client.clone(), config.health_check_interval_secs);
+                query_nodes
+            }
+            None => QueryNodes::single(self.graph_node.query_url.clone()),
+        };
+
         // Graph node state
         let graphnode_state = GraphNodeState {
-            graph_node_client: self.http_client,
+            graph_node_client,
             graph_node_status_url: self.graph_node.status_url,
-            graph_node_query_base_url: self.graph_node.query_url,
+            query_nodes,
+            hedging: self.graph_node.hedging.as_ref().map(Hedging::new),
+            response_cache,
+            partial_response,
         };
 
         // data layer
         let data_routes = Router::new()
-            .route("/subgraphs/id/:id", post_request_handler)
+            // limited once decompressed, before the receipt is taken
+            .route(
+                "/subgraphs/id/:id",
+                limit_body(post_request_handler, body_limits.query_bytes),
+            )
+            .layer(decompression_layer(&compression))
+            .layer(compression_layer(&compression))
             .with_state(graphnode_state.clone());
 
         let subgraphs_route = Router::new().nest(&url_prefix, data_routes);
@@ -392,30 +1115,113 @@ impl ServiceRouter {
         let misc_routes = Router::new()
             .route("/", get("Service is up and running"))
             .route("/info", get(operator_address))
-            .nest("/version", version)
-            .nest("/escrow", serve_escrow_subgraph)
+            .nest("/escrow", serve_escrow_subgraph.merge(escrow_accounts))
             .nest("/network", serve_network_subgraph)
+            .nest("/api-keys", api_keys)
+            .nest("/allocation-overrides", allocation_overrides)
+            .nest("/allocation-events", allocation_events)
+            .nest("/query-blocklist", query_blocklist_routes)
+            .nest("/fees", fees)
+            .nest("/dips", dips_agreements)
+            .nest("/log-levels", log_levels)
+            // each check recovers a key, limited along with the other routes
+            .route(
+                "/attestations/verify",
+                post(routes::verify_attestation).with_state(attestation_signers),
+            )
             .route(
                 "/subgraph/health/:deployment_id",
-                get(health).with_state(graphnode_state.clone()),
+                get_health.with_state(graphnode_state.clone()),
             )
-            .layer(misc_rate_limiter);
+            .layer(misc_rate_limiter.clone());
+
+        // public statistics, rate limited as configured by the operator
+        let stats_routes = match (query_stats, public_stats) {
+            (Some(query_stats), Some(config)) => {
+                let requests_per_minute = config.requests_per_minute.get();
+                let stats_rate_limiter = create_rate_limiter(
+                    (60_000 / requests_per_minute as u64).max(1),
+                    requests_per_minute,
+                );
+                Router::new().route(
+                    "/stats",
+                    get(routes::query_stats)
+                        .route_layer(stats_rate_limiter)
+                        .with_state(query_stats),
+                )
+            }
+            _ => Router::new(),
+        };
 
         let extra_routes = Router::new()
             .route("/cost", post_cost)
-            .route("/status", post_status.with_state(graphnode_state));
+            .route("/status", post_status.with_state(graphnode_state))
+            // every check queries the dependencies
+            .route(
+                "/healthz",
+                get(healthz)
+                    .route_layer(misc_rate_limiter.clone())
+                    .with_state(service_health_state.clone()),
+            )
+            .route(
+                "/readyz",
+                get(readyz)
+                    .route_layer(misc_rate_limiter.clone())
+                    .with_state(service_health_state),
+            );
 
-        let router = Router::new()
+        let routes = Router::new()
             .merge(misc_routes)
             .merge(subgraphs_route)
             .merge(extra_routes)
-            .layer(cors_layer)
-            .layer(tracing_layer);
+            .merge(stats_routes);
+        let legacy_routes = LegacyRoutes {
+            prefix: API_PREFIX,
+            sunset: legacy_routes_sunset
+                .map(|sunset| HeaderValue::from_str(&sunset))
+                .transpose()
+                .context("Invalid `service.legacy_routes_sunset`")?,
+        };
+        // gateways look for the versions at the same path whatever they support
+        let router = Router::new()
+            .nest("/version", version.layer(misc_rate_limiter))
+            .nest(API_PREFIX, routes.clone())
+            .merge(routes.layer(from_fn_with_state(legacy_routes, legacy_route_middleware)));
+        let router = if cors.security_headers {
+            router.layer(from_fn(security_headers_middleware))
+        } else {
+            router
+        };
+        let router = router.layer(cors_layer).layer(tracing_layer);
 
         Ok(router)
     }
 }
 
+/// Signs the responses of the free queries of `route`, if a signer is set
+fn sign_responses<S>(route: MethodRouter<S>, signer: Option<ResponseSigner>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match signer {
+        Some(signer) => {
+            route.route_layer(from_fn_with_state(signer, response_signature_middleware))
+        }
+        None => route,
+    }
+}
+
+/// Refuses the requests to `route` with a body larger than `limit` bytes
+fn limit_body<S>(route: MethodRouter<S>, limit: usize) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    route
+        .layer(from_fn_with_state(limit, body_limit_middleware))
+        // the extractors of the handlers default to 2MB
+        .layer(DefaultBodyLimit::max(limit))
+}
+
 fn create_rate_limiter(
     burst_per_millisecond: u64,
     burst_size: u32,
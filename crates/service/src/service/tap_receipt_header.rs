@@ -3,6 +3,7 @@
 
 use axum_extra::headers::{self, Header, HeaderName, HeaderValue};
 use base64::prelude::*;
+use itertools::Itertools;
 use lazy_static::lazy_static;
 use prometheus::{register_counter, Counter};
 use prost::Message;
@@ -11,8 +12,16 @@ use tap_graph::SignedReceipt;
 
 use crate::tap::TapReceipt;
 
+/// Receipts of the `Tap-Receipt` header
+///
+/// A query can be paid with several receipts, e.g. one per operation of a
+/// batched query, sent in a single header or in several ones. They must be
+/// for the same allocation and of the same version.
 #[derive(Debug, PartialEq)]
-pub struct TapHeader(pub TapReceipt);
+pub struct TapHeader(pub Vec<TapReceipt>);
+
+/// Receipts a single query can be paid with
+pub const MAX_RECEIPTS_PER_REQUEST: usize = 32;
 
 lazy_static! {
     static ref TAP_RECEIPT: HeaderName = HeaderName::from_static("tap-receipt");
@@ -20,6 +29,43 @@ lazy_static! {
         register_counter!("indexer_tap_invalid_total", "Invalid tap receipt decode",).unwrap();
 }
 
+/// Decodes a receipt encoded as in the `Tap-Receipt` header, either a base64
+/// encoded protobuf v2 receipt or a JSON v1 receipt
+pub fn decode_receipt(raw_receipt: &[u8]) -> anyhow::Result<TapReceipt> {
+    // we first try to decode a v2 receipt since it's cheaper and fail earlier than using
+    // serde
+    match BASE64_STANDARD.decode(raw_receipt) {
+        Ok(raw_receipt) => {
+            tracing::debug!("Decoded v2");
+            let receipt = grpc::v2::SignedReceipt::decode(raw_receipt.as_ref())?;
+            Ok(TapReceipt::V2(receipt.try_into()?))
+        }
+        Err(_) => {
+            tracing::debug!("Could not decode v2, trying v1");
+            let parsed_receipt: SignedReceipt = serde_json::from_slice(raw_receipt)?;
+            Ok(TapReceipt::V1(parsed_receipt))
+        }
+    }
+}
+
+/// Decodes the receipts of a `Tap-Receipt` header value: a single receipt,
+/// a JSON array of v1 receipts or comma separated v2 receipts
+pub fn decode_receipts(raw_receipts: &[u8]) -> anyhow::Result<Vec<TapReceipt>> {
+    let raw_receipts = raw_receipts.trim_ascii();
+    match raw_receipts.first() {
+        Some(b'[') => {
+            let receipts: Vec<SignedReceipt> = serde_json::from_slice(raw_receipts)?;
+            Ok(receipts.into_iter().map(TapReceipt::V1).collect())
+        }
+        // a v1 receipt has commas of its own
+        Some(b'{') => Ok(vec![decode_receipt(raw_receipts)?]),
+        _ => raw_receipts
+            .split(|byte| *byte == b',')
+            .map(|raw_receipt| decode_receipt(raw_receipt.trim_ascii()))
+            .collect(),
+    }
+}
+
 impl Header for TapHeader {
     fn name() -> &'static HeaderName {
         &TAP_RECEIPT
@@ -30,23 +76,32 @@ impl Header for TapHeader {
         I: Iterator<Item = &'i HeaderValue>,
     {
         let mut execute = || -> anyhow::Result<TapHeader> {
-            let raw_receipt = values.next().ok_or(headers::Error::invalid())?;
-
-            // we first try to decode a v2 receipt since it's cheaper and fail earlier than using
-            // serde
-            match BASE64_STANDARD.decode(raw_receipt) {
-                Ok(raw_receipt) => {
-                    tracing::debug!("Decoded v2");
-                    let receipt = grpc::v2::SignedReceipt::decode(raw_receipt.as_ref())?;
-                    Ok(TapHeader(TapReceipt::V2(receipt.try_into()?)))
-                }
-                Err(_) => {
-                    tracing::debug!("Could not decode v2, trying v1");
-                    let parsed_receipt: SignedReceipt =
-                        serde_json::from_slice(raw_receipt.as_ref())?;
-                    Ok(TapHeader(TapReceipt::V1(parsed_receipt)))
-                }
+            let mut receipts = Vec::new();
+            for raw_receipts in values {
+                receipts.extend(decode_receipts(raw_receipts.as_bytes())?);
+            }
+            let Some(first) = receipts.first() else {
+                anyhow::bail!("No receipt");
+            };
+            if receipts.len() > MAX_RECEIPTS_PER_REQUEST {
+                anyhow::bail!("More than {MAX_RECEIPTS_PER_REQUEST} receipts");
             }
+            let allocation_id = first.allocation_id();
+            let is_v1 = matches!(first, TapReceipt::V1(_));
+            if receipts.iter().any(|receipt| {
+                receipt.allocation_id() != allocation_id
+                    || matches!(receipt, TapReceipt::V1(_)) != is_v1
+            }) {
+                anyhow::bail!("Receipts for different allocations or of different versions");
+            }
+            if !receipts
+                .iter()
+                .map(|receipt| receipt.signature().as_bytes())
+                .all_unique()
+            {
+                anyhow::bail!("Same receipt sent twice");
+            }
+            Ok(TapHeader(receipts))
         };
         execute()
             .map_err(|_| headers::Error::invalid())
@@ -66,13 +121,127 @@ mod test {
     use axum::http::HeaderValue;
     use axum_extra::headers::Header;
     use base64::prelude::*;
+    use proptest::prelude::*;
     use prost::Message;
     use tap_aggregator::grpc::v2::SignedReceipt;
-    use test_assets::{create_signed_receipt, create_signed_receipt_v2, SignedReceiptRequest};
+    use tap_core::signed_message::Eip712SignedMessage;
+    use test_assets::{
+        create_signed_receipt, create_signed_receipt_v2, SignedReceiptRequest, INDEXER_ADDRESS,
+        TAP_EIP712_DOMAIN, TAP_SENDER, TAP_SIGNER,
+    };
+    use thegraph_core::alloy::primitives::Address;
 
-    use super::TapHeader;
+    use super::{decode_receipts, TapHeader, MAX_RECEIPTS_PER_REQUEST};
     use crate::tap::TapReceipt;
 
+    fn v1_receipt(
+        allocation_id: Address,
+        nonce: u64,
+        timestamp_ns: u64,
+        value: u128,
+    ) -> TapReceipt {
+        let receipt = tap_graph::Receipt {
+            allocation_id,
+            nonce,
+            timestamp_ns,
+            value,
+        };
+        TapReceipt::V1(
+            Eip712SignedMessage::new(&TAP_EIP712_DOMAIN, receipt, &TAP_SIGNER.0).unwrap(),
+        )
+    }
+
+    fn v2_receipt(
+        allocation_id: Address,
+        nonce: u64,
+        timestamp_ns: u64,
+        value: u128,
+    ) -> TapReceipt {
+        let receipt = tap_graph::v2::Receipt {
+            payer: TAP_SENDER.1,
+            service_provider: INDEXER_ADDRESS,
+            data_service: Address::ZERO,
+            allocation_id,
+            nonce,
+            timestamp_ns,
+            value,
+        };
+        TapReceipt::V2(
+            Eip712SignedMessage::new(&TAP_EIP712_DOMAIN, receipt, &TAP_SIGNER.0).unwrap(),
+        )
+    }
+
+    /// Value of a `Tap-Receipt` header, as sent by the gateways
+    fn header_value(receipts: &[TapReceipt]) -> HeaderValue {
+        let value = match &receipts[0] {
+            TapReceipt::V1(_) => serde_json::to_string(
+                &receipts
+                    .iter()
+                    .map(|receipt| receipt.get_v1_receipt().unwrap())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap(),
+            TapReceipt::V2(_) => receipts
+                .iter()
+                .map(|receipt| {
+                    let receipt = SignedReceipt::from(receipt.get_v2_receipt().unwrap().clone());
+                    BASE64_STANDARD.encode(receipt.encode_to_vec())
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        HeaderValue::from_str(&value).unwrap()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_decode_arbitrary_bytes(raw in prop::collection::vec(any::<u8>(), 0..4096)) {
+            let _ = decode_receipts(&raw);
+            if let Ok(header_value) = HeaderValue::from_bytes(&raw) {
+                let _ = TapHeader::decode(&mut [&header_value].into_iter());
+            }
+        }
+
+        #[test]
+        fn test_decode_header_alphabet(raw in r#"[\[\]{}:,"_ A-Za-z0-9+/=]{0,1024}"#) {
+            let _ = decode_receipts(raw.as_bytes());
+        }
+
+        #[test]
+        fn test_decode_receipts(
+            v1 in any::<bool>(),
+            allocation_id in any::<[u8; 20]>().prop_map(Address::from),
+            nonces in prop::collection::hash_set(any::<u64>(), 1..=MAX_RECEIPTS_PER_REQUEST),
+            timestamp_ns in any::<u64>(),
+            value in any::<u128>(),
+        ) {
+            let receipt = if v1 { v1_receipt } else { v2_receipt };
+            let receipts: Vec<_> = nonces
+                .into_iter()
+                .map(|nonce| receipt(allocation_id, nonce, timestamp_ns, value))
+                .collect();
+            let decoded = TapHeader::decode(&mut [&header_value(&receipts)].into_iter());
+            prop_assert_eq!(decoded.unwrap(), TapHeader(receipts));
+        }
+
+        #[test]
+        fn test_decode_receipts_for_different_allocations(
+            v1 in any::<bool>(),
+            allocations in any::<([u8; 20], [u8; 20])>(),
+            nonce in any::<u64>(),
+        ) {
+            prop_assume!(allocations.0 != allocations.1);
+            let receipt = if v1 { v1_receipt } else { v2_receipt };
+            let receipts = [
+                receipt(Address::from(allocations.0), nonce, 0, 1),
+                receipt(Address::from(allocations.1), nonce, 0, 1),
+            ];
+            prop_assert!(TapHeader::decode(&mut [&header_value(&receipts)].into_iter()).is_err());
+        }
+    }
+
     #[tokio::test]
     async fn test_decode_valid_tap_v1_receipt_header() {
         let original_receipt = create_signed_receipt(SignedReceiptRequest::builder().build()).await;
@@ -82,7 +251,10 @@ mod test {
         let decoded_receipt = TapHeader::decode(&mut header_values.into_iter())
             .expect("tap receipt header value should be valid");
 
-        assert_eq!(decoded_receipt, TapHeader(TapReceipt::V1(original_receipt)));
+        assert_eq!(
+            decoded_receipt,
+            TapHeader(vec![TapReceipt::V1(original_receipt)])
+        );
     }
 
     #[test_log::test(tokio::test)]
@@ -96,7 +268,53 @@ mod test {
         let decoded_receipt = TapHeader::decode(&mut header_values.into_iter())
             .expect("tap receipt header value should be valid");
 
-        assert_eq!(decoded_receipt, TapHeader(TapReceipt::V2(original_receipt)));
+        assert_eq!(
+            decoded_receipt,
+            TapHeader(vec![TapReceipt::V2(original_receipt)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decode_multiple_tap_receipts_header() {
+        let v1_receipts = vec![
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(2).build()).await,
+        ];
+        let header_value =
+            HeaderValue::from_str(&serde_json::to_string(&v1_receipts).unwrap()).unwrap();
+        let decoded = TapHeader::decode(&mut [&header_value].into_iter())
+            .expect("array of v1 receipts should be valid");
+        assert_eq!(
+            decoded,
+            TapHeader(v1_receipts.into_iter().map(TapReceipt::V1).collect())
+        );
+
+        let v2_receipts = vec![
+            create_signed_receipt_v2().nonce(1).call().await,
+            create_signed_receipt_v2().nonce(2).call().await,
+        ];
+        let encoded: Vec<_> = v2_receipts
+            .iter()
+            .map(|receipt| {
+                BASE64_STANDARD.encode(SignedReceipt::from(receipt.clone()).encode_to_vec())
+            })
+            .collect();
+        // in a single header or one header per receipt
+        let single = HeaderValue::from_str(&encoded.join(", ")).unwrap();
+        let split: Vec<_> = encoded
+            .iter()
+            .map(|receipt| HeaderValue::from_str(receipt).unwrap())
+            .collect();
+        let expected = TapHeader(v2_receipts.into_iter().map(TapReceipt::V2).collect());
+        assert_eq!(
+            TapHeader::decode(&mut [&single].into_iter()).unwrap(),
+            expected
+        );
+        assert_eq!(TapHeader::decode(&mut split.iter()).unwrap(), expected);
+
+        // the same receipt can't pay twice
+        let twice = HeaderValue::from_str(&format!("{},{}", encoded[0], encoded[0])).unwrap();
+        assert!(TapHeader::decode(&mut [&twice].into_iter()).is_err());
     }
 
     #[test]
@@ -0,0 +1,63 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::SystemTime;
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use indexer_config::ServiceTlsConfig;
+
+/// Loads the certificate and private key, then reloads them whenever one of
+/// the files changes so renewed certificates are served without a restart
+///
+/// HTTP/2 and HTTP/1.1 are negotiated with ALPN.
+pub async fn rustls_config(config: ServiceTlsConfig) -> anyhow::Result<RustlsConfig> {
+    let modified = modified(&config);
+    let rustls_config = RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load the TLS certificate {} and key {}",
+                config.cert_path.display(),
+                config.key_path.display()
+            )
+        })?;
+    tokio::spawn(reload_on_change(config, rustls_config.clone(), modified));
+    Ok(rustls_config)
+}
+
+/// Last modification times of the certificate and key files
+fn modified(config: &ServiceTlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(&config.cert_path).and_then(|m| m.modified());
+    let key = std::fs::metadata(&config.key_path).and_then(|m| m.modified());
+    cert.ok().zip(key.ok())
+}
+
+async fn reload_on_change(
+    config: ServiceTlsConfig,
+    rustls_config: RustlsConfig,
+    mut last_modified: Option<(SystemTime, SystemTime)>,
+) {
+    let mut interval = tokio::time::interval(config.reload_interval_secs);
+    loop {
+        interval.tick().await;
+        let modified = modified(&config);
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+        // the previous certificate is kept if the new one can't be loaded,
+        // e.g. when only one of the files has been written yet
+        match rustls_config
+            .reload_from_pem_file(&config.cert_path, &config.key_path)
+            .await
+        {
+            Ok(()) => {
+                tracing::info!(cert_path = %config.cert_path.display(), "TLS certificate reloaded");
+                last_modified = modified;
+            }
+            Err(error) => {
+                tracing::warn!(%error, "Failed to reload the TLS certificate");
+            }
+        }
+    }
+}
@@ -1,11 +1,12 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{collections::HashMap, fmt::Debug, sync::Arc, time::Duration};
+use std::{collections::HashMap, fmt::Debug, future::Future, sync::Arc, time::Duration};
 
-use indexer_allocation::Allocation;
-use indexer_monitor::EscrowAccounts;
-use receipt_store::{DatabaseReceipt, InnerContext};
+use indexer_config::ServiceReceiptChecksConfig;
+use indexer_monitor::{AllocationCache, ContractSigners, EscrowAccounts};
+use indexer_receipt::store::ReceiptStore;
+use receipt_store::ReceiptStoreRequest;
 use sqlx::PgPool;
 use tap_core::receipt::{checks::ReceiptCheck, state::Checking, ReceiptWithState};
 use thegraph_core::alloy::{primitives::Address, sol_types::Eip712Domain};
@@ -20,12 +21,17 @@ use crate::tap::checks::{
     receipt_max_val_check::ReceiptMaxValueCheck, sender_balance_check::SenderBalanceCheck,
     timestamp_check::TimestampCheck, value_check::MinimumValue,
 };
+#[cfg(feature = "wasm-plugin")]
+use crate::{middleware::WasmPlugin, tap::checks::wasm_check::WasmCheck};
 
 mod checks;
 mod receipt_store;
+mod signer_cache;
 
 pub use ::indexer_receipt::TapReceipt;
-pub use checks::value_check::AgoraQuery;
+pub use checks::value_check::{AgoraQuery, FeaturePrices, RequestValue, RoutePrice};
+pub use receipt_store::with_request_batch;
+pub use signer_cache::{SignerCache, SignerCacheMiss};
 
 pub type CheckingReceipt = ReceiptWithState<Checking, TapReceipt>;
 
@@ -34,7 +40,8 @@ const GRACE_PERIOD: u64 = 60;
 #[derive(Clone)]
 pub struct IndexerTapContext {
     domain_separator: Arc<Eip712Domain>,
-    receipt_producer: Sender<DatabaseReceipt>,
+    contract_signers: Option<ContractSigners>,
+    receipt_producer: Sender<ReceiptStoreRequest>,
     cancelation_token: CancellationToken,
 }
 
@@ -45,39 +52,127 @@ pub enum AdapterError {
 }
 
 impl IndexerTapContext {
+    /// The receipt checks enabled in `enabled`, the eligibility of the
+    /// allocations being overridden by the database if `allocation_overrides`
+    #[allow(clippy::too_many_arguments)]
     pub async fn get_checks(
         pgpool: PgPool,
-        indexer_allocations: Receiver<HashMap<Address, Allocation>>,
-        escrow_accounts_v1: Receiver<EscrowAccounts>,
-        escrow_accounts_v2: Receiver<EscrowAccounts>,
+        allocations: AllocationCache,
+        escrow_accounts_v1: HashMap<Address, Receiver<EscrowAccounts>>,
+        escrow_accounts_v2: HashMap<Address, Receiver<EscrowAccounts>>,
         timestamp_error_tolerance: Duration,
         receipt_max_value: u128,
+        feature_prices: Option<FeaturePrices>,
+        enabled: ServiceReceiptChecksConfig,
+        allocation_overrides: bool,
     ) -> Vec<ReceiptCheck<TapReceipt>> {
-        vec![
-            Arc::new(AllocationEligible::new(indexer_allocations)),
-            Arc::new(SenderBalanceCheck::new(
+        let mut checks: Vec<ReceiptCheck<TapReceipt>> = Vec::new();
+        if enabled.allocation_eligible {
+            let mut allocation_eligible = AllocationEligible::new(allocations.clone());
+            if allocation_overrides {
+                allocation_eligible = allocation_eligible.with_overrides(pgpool.clone()).await;
+            }
+            checks.push(Arc::new(allocation_eligible));
+        }
+        if enabled.sender_balance {
+            checks.push(Arc::new(SenderBalanceCheck::new(
+                allocations,
                 escrow_accounts_v1,
                 escrow_accounts_v2,
-            )),
-            Arc::new(TimestampCheck::new(timestamp_error_tolerance)),
-            Arc::new(DenyListCheck::new(pgpool.clone()).await),
-            Arc::new(ReceiptMaxValueCheck::new(receipt_max_value)),
-            Arc::new(MinimumValue::new(pgpool, Duration::from_secs(GRACE_PERIOD)).await),
-        ]
+            )));
+        }
+        if enabled.timestamp {
+            checks.push(Arc::new(TimestampCheck::new(timestamp_error_tolerance)));
+        }
+        if enabled.deny_list {
+            checks.push(Arc::new(DenyListCheck::new(pgpool.clone()).await));
+        }
+        if enabled.max_receipt_value {
+            checks.push(Arc::new(ReceiptMaxValueCheck::new(receipt_max_value)));
+        }
+        if enabled.minimum_value {
+            let mut minimum_value =
+                MinimumValue::new(pgpool, Duration::from_secs(GRACE_PERIOD)).await;
+            if let Some(feature_prices) = feature_prices {
+                minimum_value = minimum_value.with_feature_prices(feature_prices);
+            }
+            checks.push(Arc::new(minimum_value));
+        }
+        checks
+    }
+
+    /// Check of `wasm_plugin`, if it is enabled in `enabled` and the plugin
+    /// exports `check_receipt`
+    #[cfg(feature = "wasm-plugin")]
+    pub fn wasm_plugin_check(
+        enabled: ServiceReceiptChecksConfig,
+        wasm_plugin: Option<WasmPlugin>,
+    ) -> Option<ReceiptCheck<TapReceipt>> {
+        let plugin = wasm_plugin
+            .filter(|_| enabled.wasm_plugin)
+            .filter(WasmPlugin::checks_receipts)?;
+        Some(Arc::new(WasmCheck::new(plugin)))
     }
 
-    pub async fn new(pgpool: PgPool, domain_separator: Eip712Domain) -> Self {
+    /// Stores the accepted receipts in `storage`, in batches
+    pub async fn new(storage: Arc<dyn ReceiptStore>, domain_separator: Eip712Domain) -> Self {
         const MAX_RECEIPT_QUEUE_SIZE: usize = 1000;
         let (tx, rx) = mpsc::channel(MAX_RECEIPT_QUEUE_SIZE);
         let cancelation_token = CancellationToken::new();
-        let inner = InnerContext { pgpool };
-        Self::spawn_store_receipt_task(inner, rx, cancelation_token.clone());
+        Self::spawn_store_receipt_task(storage, rx, cancelation_token.clone());
 
         Self {
             cancelation_token,
             receipt_producer: tx,
             domain_separator: Arc::new(domain_separator),
+            contract_signers: None,
+        }
+    }
+
+    /// Stores the receipts signed for contract wallets with the wallet as
+    /// the signer, see [ContractSigners]
+    pub fn with_contract_signers(mut self, contract_signers: Option<ContractSigners>) -> Self {
+        self.contract_signers = contract_signers;
+        self
+    }
+
+    /// Handle to hold the receipts stored by this context until their query
+    /// is served
+    pub fn receipt_refunds(&self) -> ReceiptRefunds {
+        ReceiptRefunds {
+            receipt_producer: self.receipt_producer.clone(),
+        }
+    }
+}
+
+/// Holds the receipts of queries until they are served
+///
+/// The receipts stored while a query runs are only queued for storage once
+/// its response is known, so the receipts of the queries that were not
+/// served are never written and tap-agent never counts them.
+#[derive(Clone)]
+pub struct ReceiptRefunds {
+    receipt_producer: Sender<ReceiptStoreRequest>,
+}
+
+impl ReceiptRefunds {
+    /// Runs `serve`, the receipts stored meanwhile are dropped if `refund`
+    /// returns `true` for its output, and queued for storage otherwise
+    pub async fn hold<F: Future>(
+        &self,
+        serve: F,
+        refund: impl FnOnce(&F::Output) -> bool,
+    ) -> F::Output {
+        let (output, held) = receipt_store::with_held_receipts(serve).await;
+        if refund(&output) {
+            return output;
         }
+        for request in held {
+            if let Err(e) = self.receipt_producer.send(request).await {
+                tracing::error!("Failed to queue receipt for storage: {}", e);
+            }
+        }
+        output
     }
 }
 
@@ -86,3 +181,72 @@ impl Drop for IndexerTapContext {
         self.cancelation_token.cancel();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    use indexer_config::ServiceReceiptChecksConfig;
+    use indexer_monitor::AllocationCache;
+    use sqlx::PgPool;
+    use test_assets::{INDEXER_ADDRESS, INDEXER_ALLOCATIONS};
+    use tokio::sync::watch;
+
+    use super::IndexerTapContext;
+
+    const ALL_CHECKS: ServiceReceiptChecksConfig = ServiceReceiptChecksConfig {
+        allocation_eligible: true,
+        sender_balance: true,
+        timestamp: true,
+        deny_list: true,
+        max_receipt_value: true,
+        minimum_value: true,
+        wasm_plugin: true,
+    };
+
+    const NO_CHECKS: ServiceReceiptChecksConfig = ServiceReceiptChecksConfig {
+        allocation_eligible: false,
+        sender_balance: false,
+        timestamp: false,
+        deny_list: false,
+        max_receipt_value: false,
+        minimum_value: false,
+        wasm_plugin: false,
+    };
+
+    async fn checks_len(pgpool: PgPool, enabled: ServiceReceiptChecksConfig) -> usize {
+        let (_tx, allocations) = watch::channel(INDEXER_ALLOCATIONS.clone());
+        let allocations =
+            AllocationCache::new(allocations, HashSet::from([INDEXER_ADDRESS]), None, None);
+        IndexerTapContext::get_checks(
+            pgpool,
+            allocations,
+            HashMap::new(),
+            HashMap::new(),
+            Duration::from_secs(30),
+            1,
+            None,
+            enabled,
+            false,
+        )
+        .await
+        .len()
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_checks_from_config(pgpool: PgPool) {
+        assert_eq!(checks_len(pgpool.clone(), ALL_CHECKS).await, 6);
+        assert_eq!(checks_len(pgpool.clone(), NO_CHECKS).await, 0);
+
+        let some_checks = ServiceReceiptChecksConfig {
+            timestamp: true,
+            max_receipt_value: true,
+            ..NO_CHECKS
+        };
+        assert_eq!(checks_len(pgpool, some_checks).await, 2);
+        assert_eq!(some_checks.disabled().len(), 5);
+    }
+}
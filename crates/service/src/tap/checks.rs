@@ -7,3 +7,5 @@ pub mod receipt_max_val_check;
 pub mod sender_balance_check;
 pub mod timestamp_check;
 pub mod value_check;
+#[cfg(feature = "wasm-plugin")]
+pub mod wasm_check;
@@ -1,27 +1,143 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::anyhow;
-use indexer_allocation::Allocation;
+use indexer_monitor::AllocationCache;
+use sqlx::{
+    postgres::PgListener,
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
 use tap_core::receipt::checks::{Check, CheckError, CheckResult};
 use thegraph_core::alloy::primitives::Address;
-use tokio::sync::watch::Receiver;
+use tokio_util::sync::CancellationToken;
 
 use crate::tap::{CheckingReceipt, TapReceipt};
 
+const OVERRIDE_NOTIFICATION_CHANNEL: &str = "allocation_eligibility_override_notification";
+
+/// Override of the eligibility of an allocation, set by the operator with
+/// the `/allocation-overrides` management routes
+struct EligibilityOverride {
+    eligible: bool,
+    expires_at: DateTime<Utc>,
+}
+
+type Overrides = Arc<RwLock<HashMap<Address, EligibilityOverride>>>;
+
 pub struct AllocationEligible {
-    indexer_allocations: Receiver<HashMap<Address, Allocation>>,
+    allocations: AllocationCache,
+    overrides: Overrides,
+    overrides_watcher_cancel_token: CancellationToken,
 }
 
 impl AllocationEligible {
-    pub fn new(indexer_allocations: Receiver<HashMap<Address, Allocation>>) -> Self {
+    /// Accepts the receipts of `allocations`, without overrides
+    pub fn new(allocations: AllocationCache) -> Self {
         Self {
-            indexer_allocations,
+            allocations,
+            overrides: Default::default(),
+            overrides_watcher_cancel_token: CancellationToken::new(),
+        }
+    }
+
+    /// Applies the eligibility overrides of the database, kept up to date as
+    /// they are modified
+    pub async fn with_overrides(self, pgpool: PgPool) -> Self {
+        // Listen before loading the overrides so that no update is missed
+        let mut pglistener = PgListener::connect_with(&pgpool).await.unwrap();
+        pglistener
+            .listen(OVERRIDE_NOTIFICATION_CHANNEL)
+            .await
+            .expect(
+                "should be able to subscribe to Postgres Notify events on the channel \
+                'allocation_eligibility_override_notification'",
+            );
+
+        Self::reload_overrides(&pgpool, &self.overrides)
+            .await
+            .expect("should be able to fetch the allocation eligibility overrides on startup");
+
+        tokio::spawn(Self::overrides_watcher(
+            pgpool,
+            pglistener,
+            self.overrides.clone(),
+            self.overrides_watcher_cancel_token.clone(),
+        ));
+        self
+    }
+
+    async fn reload_overrides(pgpool: &PgPool, overrides: &Overrides) -> anyhow::Result<()> {
+        let rows = sqlx::query!(
+            r#"
+                SELECT allocation_id, eligible, expires_at
+                FROM allocation_eligibility_overrides
+                WHERE expires_at > NOW()
+            "#
+        )
+        .fetch_all(pgpool)
+        .await?;
+        let reloaded = rows
+            .into_iter()
+            .map(|row| {
+                Ok((
+                    Address::from_str(&row.allocation_id)?,
+                    EligibilityOverride {
+                        eligible: row.eligible,
+                        expires_at: row.expires_at,
+                    },
+                ))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+
+        *overrides.write().unwrap() = reloaded;
+        Ok(())
+    }
+
+    /// Reloads the overrides every time the table is modified
+    async fn overrides_watcher(
+        pgpool: PgPool,
+        mut pglistener: PgListener,
+        overrides: Overrides,
+        cancel_token: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => {
+                    break;
+                }
+
+                notification = pglistener.recv() => {
+                    // the listener reconnects on the next call, notifications
+                    // sent in between are lost so the overrides are reloaded anyway
+                    if let Err(error) = notification {
+                        tracing::warn!(%error, "Lost the allocation eligibility override notifications");
+                    }
+                    if let Err(error) = Self::reload_overrides(&pgpool, &overrides).await {
+                        tracing::error!(%error, "Failed to reload the allocation eligibility overrides");
+                    }
+                }
+            }
         }
     }
+
+    /// Eligibility forced by an override that didn't expire yet
+    fn overridden_eligibility(&self, allocation_id: &Address) -> Option<bool> {
+        self.overrides
+            .read()
+            .unwrap()
+            .get(allocation_id)
+            .filter(|eligibility_override| eligibility_override.expires_at > Utc::now())
+            .map(|eligibility_override| eligibility_override.eligible)
+    }
 }
+
 #[async_trait::async_trait]
 impl Check<TapReceipt> for AllocationEligible {
     async fn check(
@@ -30,16 +146,137 @@ impl Check<TapReceipt> for AllocationEligible {
         receipt: &CheckingReceipt,
     ) -> CheckResult {
         let allocation_id = receipt.signed_receipt().allocation_id();
-        if !self
-            .indexer_allocations
-            .borrow()
-            .contains_key(&allocation_id)
-        {
-            return Err(CheckError::Failed(anyhow!(
-                "Receipt allocation ID `{}` is not eligible for this indexer",
+        match self.overridden_eligibility(&allocation_id) {
+            Some(true) => Ok(()),
+            Some(false) => Err(CheckError::Failed(anyhow!(
+                "Receipt allocation ID `{}` is excluded by an eligibility override",
                 allocation_id
-            )));
+            ))),
+            None => match self.allocations.lookup(&allocation_id).await {
+                // the receipts of a Horizon allocation are v2 receipts, and
+                // the ones of a legacy allocation v1 receipts
+                Some(allocation)
+                    if allocation.horizon != receipt.signed_receipt().get_v2_receipt().is_some() =>
+                {
+                    Err(CheckError::Failed(anyhow!(
+                        "Receipt allocation ID `{}` is a {} allocation, its receipts must be {} receipts",
+                        allocation_id,
+                        if allocation.horizon { "Horizon" } else { "legacy" },
+                        if allocation.horizon { "v2" } else { "v1" },
+                    )))
+                }
+                Some(_) => Ok(()),
+                None => Err(CheckError::Failed(anyhow!(
+                    "Receipt allocation ID `{}` is not eligible for this indexer",
+                    allocation_id
+                ))),
+            },
         }
-        Ok(())
+    }
+}
+
+impl Drop for AllocationEligible {
+    fn drop(&mut self) {
+        self.overrides_watcher_cancel_token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use indexer_allocation::Provision;
+    use indexer_monitor::AllocationCache;
+    use sqlx::PgPool;
+    use tap_core::receipt::{checks::Check, Context};
+    use test_assets::{
+        create_signed_receipt, SignedReceiptRequest, ALLOCATION_ID_0, ALLOCATION_ID_1,
+        ALLOCATION_ID_2, INDEXER_ADDRESS, INDEXER_ALLOCATIONS,
+    };
+    use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
+    use tokio::sync::watch;
+
+    use super::AllocationEligible;
+    use crate::tap::{CheckingReceipt, TapReceipt};
+
+    async fn add_override(pgpool: &PgPool, allocation_id: Address, eligible: bool, ttl_secs: f64) {
+        sqlx::query!(
+            "INSERT INTO allocation_eligibility_overrides (allocation_id, eligible, expires_at)
+             VALUES ($1, $2, NOW() + make_interval(secs => $3))",
+            allocation_id.encode_hex(),
+            eligible,
+            ttl_secs
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    async fn receipt(allocation_id: Address) -> CheckingReceipt {
+        let receipt = create_signed_receipt(
+            SignedReceiptRequest::builder()
+                .allocation_id(allocation_id)
+                .build(),
+        )
+        .await;
+        CheckingReceipt::new(TapReceipt::V1(receipt))
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_eligibility_overrides(pgpool: PgPool) {
+        let unknown_allocation = Address::repeat_byte(0x42);
+        add_override(&pgpool, unknown_allocation, true, 3600.0).await;
+        add_override(&pgpool, ALLOCATION_ID_0, false, 3600.0).await;
+        // expired overrides are ignored
+        add_override(&pgpool, ALLOCATION_ID_1, false, -3600.0).await;
+
+        let mut indexer_allocations = INDEXER_ALLOCATIONS.clone();
+        indexer_allocations
+            .get_mut(&ALLOCATION_ID_2)
+            .unwrap()
+            .provision = Some(Provision {
+            service_provider: INDEXER_ADDRESS,
+            data_service: Address::repeat_byte(0x11),
+        });
+        let (_tx, allocations) = watch::channel(indexer_allocations);
+        let allocations =
+            AllocationCache::new(allocations, HashSet::from([INDEXER_ADDRESS]), None, None);
+        let check = AllocationEligible::new(allocations.clone())
+            .with_overrides(pgpool)
+            .await;
+        let ctx = Context::new();
+
+        assert!(check
+            .check(&ctx, &receipt(unknown_allocation).await)
+            .await
+            .is_ok());
+        assert!(check
+            .check(&ctx, &receipt(ALLOCATION_ID_0).await)
+            .await
+            .is_err());
+        assert!(check
+            .check(&ctx, &receipt(ALLOCATION_ID_1).await)
+            .await
+            .is_ok());
+        assert!(check
+            .check(&ctx, &receipt(Address::repeat_byte(0x43)).await)
+            .await
+            .is_err());
+        // a v1 receipt for a Horizon allocation
+        assert!(check
+            .check(&ctx, &receipt(ALLOCATION_ID_2).await)
+            .await
+            .is_err());
+
+        // the overrides only apply if they are managed
+        let check = AllocationEligible::new(allocations);
+        assert!(check
+            .check(&ctx, &receipt(unknown_allocation).await)
+            .await
+            .is_err());
+        assert!(check
+            .check(&ctx, &receipt(ALLOCATION_ID_0).await)
+            .await
+            .is_ok());
     }
 }
@@ -1,10 +1,12 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
+
 use anyhow::anyhow;
-use indexer_monitor::EscrowAccounts;
+use indexer_monitor::{AllocationCache, EscrowAccounts};
 use tap_core::receipt::checks::{Check, CheckError, CheckResult};
-use thegraph_core::alloy::primitives::U256;
+use thegraph_core::alloy::primitives::{Address, U256};
 use tokio::sync::watch::Receiver;
 
 use crate::{
@@ -12,17 +14,22 @@ use crate::{
     tap::{CheckingReceipt, TapReceipt},
 };
 
+/// Checks the balance of the sender in the escrow accounts of the indexer
+/// the receipt's allocation belongs to
 pub struct SenderBalanceCheck {
-    escrow_accounts_v1: Receiver<EscrowAccounts>,
-    escrow_accounts_v2: Receiver<EscrowAccounts>,
+    allocations: AllocationCache,
+    escrow_accounts_v1: HashMap<Address, Receiver<EscrowAccounts>>,
+    escrow_accounts_v2: HashMap<Address, Receiver<EscrowAccounts>>,
 }
 
 impl SenderBalanceCheck {
     pub fn new(
-        escrow_accounts_v1: Receiver<EscrowAccounts>,
-        escrow_accounts_v2: Receiver<EscrowAccounts>,
+        allocations: AllocationCache,
+        escrow_accounts_v1: HashMap<Address, Receiver<EscrowAccounts>>,
+        escrow_accounts_v2: HashMap<Address, Receiver<EscrowAccounts>>,
     ) -> Self {
         Self {
+            allocations,
             escrow_accounts_v1,
             escrow_accounts_v2,
         }
@@ -36,18 +43,33 @@ impl Check<TapReceipt> for SenderBalanceCheck {
         ctx: &tap_core::receipt::Context,
         receipt: &CheckingReceipt,
     ) -> CheckResult {
-        let escrow_accounts_snapshot_v1 = self.escrow_accounts_v1.borrow();
-        let escrow_accounts_snapshot_v2 = self.escrow_accounts_v2.borrow();
-
         let Sender(receipt_sender) = ctx
             .get::<Sender>()
             .ok_or(CheckError::Failed(anyhow::anyhow!("Could not find sender")))?;
 
+        let allocation_id = receipt.signed_receipt().allocation_id();
+        let indexer = self
+            .allocations
+            .lookup(&allocation_id)
+            .await
+            .map(|allocation| allocation.indexer)
+            .ok_or(CheckError::Failed(anyhow!(
+                "Receipt allocation `{}` is not eligible for this indexer",
+                allocation_id
+            )))?;
+
         // get balance for escrow account given receipt type
-        let balance_result = match receipt.signed_receipt() {
-            TapReceipt::V1(_) => escrow_accounts_snapshot_v1.get_balance_for_sender(receipt_sender),
-            TapReceipt::V2(_) => escrow_accounts_snapshot_v2.get_balance_for_sender(receipt_sender),
-        };
+        let escrow_accounts = match receipt.signed_receipt() {
+            TapReceipt::V1(_) => self.escrow_accounts_v1.get(&indexer),
+            TapReceipt::V2(_) => self.escrow_accounts_v2.get(&indexer),
+        }
+        .ok_or(CheckError::Failed(anyhow!(
+            "No escrow accounts for indexer `{}`",
+            indexer
+        )))?;
+        let balance_result = escrow_accounts
+            .borrow()
+            .get_balance_for_sender(receipt_sender);
 
         // Check that the sender has a non-zero balance -- more advanced accounting is done in
         // `tap-agent`.
@@ -11,6 +11,7 @@ use std::{
 use ::cost_model::CostModel;
 use anyhow::anyhow;
 use bigdecimal::ToPrimitive;
+use indexer_monitor::SubgraphManifestsWatcher;
 use sqlx::{
     postgres::{PgListener, PgNotification},
     PgPool,
@@ -41,6 +42,37 @@ pub struct AgoraQuery {
     pub variables: String,
 }
 
+/// Sum of the values of the receipts a query is paid with, when there is
+/// more than one of them. Each receipt is then checked with this value
+/// instead of its own.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestValue(pub u128);
+
+/// Minimal value of a query to a route that is not priced by the cost
+/// models, e.g. `/status`. It replaces the AgoraQuery of the subgraph queries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutePrice(pub u128);
+
+/// Multipliers of the minimum value for the deployments using a feature
+/// or data source kind, see [SubgraphManifestsWatcher]
+pub struct FeaturePrices {
+    pub manifests: SubgraphManifestsWatcher,
+    pub multipliers: HashMap<String, f64>,
+}
+
+impl FeaturePrices {
+    fn apply(&self, deployment_id: &DeploymentId, value: u128) -> u128 {
+        let multiplier: f64 = match self.manifests.borrow().get(deployment_id) {
+            Some(manifest) => manifest
+                .uses()
+                .filter_map(|name| self.multipliers.get(name))
+                .product(),
+            None => return value,
+        };
+        (value as f64 * multiplier) as u128
+    }
+}
+
 type CostModelMap = Arc<RwLock<HashMap<DeploymentId, CostModel>>>;
 type GlobalModel = Arc<RwLock<Option<CostModel>>>;
 type GracePeriod = Arc<RwLock<Instant>>;
@@ -55,6 +87,7 @@ pub struct MinimumValue {
     watcher_cancel_token: tokio_util::sync::CancellationToken,
     updated_at: GracePeriod,
     grace_period: Duration,
+    feature_prices: Option<FeaturePrices>,
 
     #[cfg(test)]
     msg_receiver: mpsc::Receiver<()>,
@@ -233,11 +266,18 @@ impl MinimumValue {
             watcher_cancel_token,
             updated_at,
             grace_period,
+            feature_prices: None,
             #[cfg(test)]
             msg_receiver: receiver,
         }
     }
 
+    /// Applies the multipliers of `feature_prices` on top of the cost models
+    pub fn with_feature_prices(mut self, feature_prices: FeaturePrices) -> Self {
+        self.feature_prices = Some(feature_prices);
+        self
+    }
+
     fn inside_grace_period(&self) -> bool {
         let time_elapsed = Instant::now().duration_since(*self.updated_at.read().unwrap());
         time_elapsed < self.grace_period
@@ -258,7 +298,13 @@ impl MinimumValue {
             _ => None,
         };
 
-        Ok(expected_value.unwrap_or(MINIMAL_VALUE))
+        let expected_value = expected_value.unwrap_or(MINIMAL_VALUE);
+        Ok(match &self.feature_prices {
+            Some(feature_prices) => {
+                feature_prices.apply(&agora_query.deployment_id, expected_value)
+            }
+            None => expected_value,
+        })
     }
 
     async fn value_check_reload(
@@ -309,11 +355,26 @@ impl MinimumValue {
 #[async_trait::async_trait]
 impl Check<TapReceipt> for MinimumValue {
     async fn check(&self, ctx: &Context, receipt: &CheckingReceipt) -> CheckResult {
+        // get value, of all the receipts of the request if split
+        let value = match ctx.get::<RequestValue>() {
+            Some(RequestValue(value)) => *value,
+            None => receipt.signed_receipt().value(),
+        };
+        if let Some(RoutePrice(price)) = ctx.get::<RoutePrice>() {
+            return if value >= *price {
+                Ok(())
+            } else {
+                Err(CheckError::Failed(anyhow!(
+                    "Query receipt does not have the minimum value of the route. \
+                    Expected value: {}. Received value: {}.",
+                    price,
+                    value,
+                )))
+            };
+        }
         let agora_query = ctx
             .get()
             .ok_or(CheckError::Failed(anyhow!("Could not find agora query")))?;
-        // get value
-        let value = receipt.signed_receipt().value();
 
         if self.inside_grace_period() && value >= MINIMAL_VALUE {
             return Ok(());
@@ -366,19 +427,43 @@ enum CostModelNotification {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{collections::HashMap, time::Duration};
 
+    use indexer_monitor::SubgraphManifest;
     use sqlx::PgPool;
     use tap_core::receipt::{checks::Check, Context};
-    use test_assets::{create_signed_receipt, flush_messages, SignedReceiptRequest};
-    use tokio::time::sleep;
+    use test_assets::{
+        create_signed_receipt, flush_messages, SignedReceiptRequest, ESCROW_SUBGRAPH_DEPLOYMENT,
+        NETWORK_SUBGRAPH_DEPLOYMENT,
+    };
+    use tokio::{sync::watch, time::sleep};
 
-    use super::{AgoraQuery, MinimumValue};
+    use super::{AgoraQuery, FeaturePrices, MinimumValue, RoutePrice};
     use crate::{
         database::cost_model::test::{self, add_cost_models, global_cost_model, to_db_models},
         tap::{CheckingReceipt, TapReceipt},
     };
 
+    #[test]
+    fn test_feature_prices() {
+        let manifest = SubgraphManifest {
+            spec_version: "1.0.0".to_string(),
+            features: ["fullTextSearch".to_string()].into(),
+            data_source_kinds: ["ethereum/contract".to_string(), "file/ipfs".to_string()].into(),
+        };
+        let feature_prices = FeaturePrices {
+            manifests: watch::channel(HashMap::from([(NETWORK_SUBGRAPH_DEPLOYMENT, manifest)])).1,
+            multipliers: HashMap::from([
+                ("fullTextSearch".to_string(), 1.5),
+                ("file/ipfs".to_string(), 2.0),
+                ("grafting".to_string(), 10.0),
+            ]),
+        };
+        assert_eq!(feature_prices.apply(&NETWORK_SUBGRAPH_DEPLOYMENT, 100), 300);
+        // manifest not known
+        assert_eq!(feature_prices.apply(&ESCROW_SUBGRAPH_DEPLOYMENT, 100), 100);
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn initialize_check(pgpool: PgPool) {
         let check = MinimumValue::new(pgpool, Duration::from_secs(0)).await;
@@ -624,4 +709,30 @@ mod tests {
             .await
             .expect("should accept more than global");
     }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_check_route_price(pgpool: PgPool) {
+        // the global model is not used for the routes with a price
+        add_cost_models(&pgpool, vec![global_cost_model()]).await;
+        let check = MinimumValue::new(pgpool, Duration::from_secs(0)).await;
+
+        let mut ctx = Context::new();
+        ctx.insert(RoutePrice(100));
+
+        let signed_receipt =
+            create_signed_receipt(SignedReceiptRequest::builder().value(99).build()).await;
+        let receipt = CheckingReceipt::new(TapReceipt::V1(signed_receipt));
+        assert!(
+            check.check(&ctx, &receipt).await.is_err(),
+            "Should deny less than the route price"
+        );
+
+        let signed_receipt =
+            create_signed_receipt(SignedReceiptRequest::builder().value(100).build()).await;
+        let receipt = CheckingReceipt::new(TapReceipt::V1(signed_receipt));
+        check
+            .check(&ctx, &receipt)
+            .await
+            .expect("should accept the route price");
+    }
 }
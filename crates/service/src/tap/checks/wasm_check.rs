@@ -0,0 +1,202 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::Serialize;
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    WithValueAndTimestamp,
+};
+
+use super::value_check::AgoraQuery;
+use crate::{
+    middleware::{Sender, WasmPlugin},
+    tap::{CheckingReceipt, TapReceipt},
+};
+
+/// Receipt as passed to the `check_receipt` hook, the value is a string as
+/// it doesn't fit in a JSON number
+#[derive(Serialize)]
+struct ReceiptInput {
+    version: u8,
+    allocation_id: String,
+    sender: Option<String>,
+    deployment: Option<String>,
+    value: String,
+    timestamp_ns: u64,
+    nonce: u64,
+}
+
+/// Refuses the receipts refused by the `check_receipt` hook of the
+/// [WasmPlugin]
+pub struct WasmCheck {
+    plugin: WasmPlugin,
+}
+
+impl WasmCheck {
+    pub fn new(plugin: WasmPlugin) -> Self {
+        Self { plugin }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check<TapReceipt> for WasmCheck {
+    async fn check(
+        &self,
+        ctx: &tap_core::receipt::Context,
+        receipt: &CheckingReceipt,
+    ) -> CheckResult {
+        let receipt = receipt.signed_receipt();
+        let input = ReceiptInput {
+            version: match receipt {
+                TapReceipt::V1(_) => 1,
+                TapReceipt::V2(_) => 2,
+            },
+            allocation_id: receipt.allocation_id().to_string(),
+            sender: ctx.get::<Sender>().map(|Sender(sender)| sender.to_string()),
+            deployment: ctx
+                .get::<AgoraQuery>()
+                .map(|query| query.deployment_id.to_string()),
+            value: receipt.value().to_string(),
+            timestamp_ns: receipt.timestamp_ns(),
+            nonce: receipt.nonce(),
+        };
+        let input = serde_json::to_vec(&input).map_err(|e| CheckError::Failed(e.into()))?;
+        self.plugin
+            .check_receipt(input)
+            .await
+            .map_err(CheckError::Failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use indexer_config::{ServiceReceiptChecksConfig, WasmPluginConfig};
+    use tap_core::receipt::{checks::Check, Context};
+    use test_assets::{create_signed_receipt, SignedReceiptRequest, TAP_SENDER};
+
+    use super::*;
+    use crate::tap::IndexerTapContext;
+
+    /// Refuses every receipt, with the receipt as the reason
+    const ECHO_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "check_receipt") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    const ACCEPTING_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "check_receipt") (param i32 i32) (result i64)
+            (i64.const 0)))
+    "#;
+
+    const LOOPING_PLUGIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "check_receipt") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))
+    "#;
+
+    fn wasm_plugin(plugin: &str, reject_on_error: bool) -> WasmPlugin {
+        let config = WasmPluginConfig {
+            path: PathBuf::new(),
+            timeout_secs: Duration::from_millis(50),
+            max_memory_bytes: 1 << 20,
+            reject_on_error,
+        };
+        WasmPlugin::new(plugin.as_bytes(), &config, 1 << 20).unwrap()
+    }
+
+    fn wasm_check(plugin: &str, reject_on_error: bool) -> WasmCheck {
+        let plugin = wasm_plugin(plugin, reject_on_error);
+        assert!(plugin.checks_receipts());
+        WasmCheck::new(plugin)
+    }
+
+    async fn checking_receipt() -> CheckingReceipt {
+        let receipt =
+            create_signed_receipt(SignedReceiptRequest::builder().value(100).build()).await;
+        CheckingReceipt::new(TapReceipt::V1(receipt))
+    }
+
+    #[tokio::test]
+    async fn test_plugin_refuses_receipt() {
+        let receipt = checking_receipt().await;
+        let mut ctx = Context::new();
+        ctx.insert(Sender(TAP_SENDER.1));
+
+        let error = wasm_check(ECHO_PLUGIN, false)
+            .check(&ctx, &receipt)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(r#""version":1"#));
+        assert!(error.contains(r#""value":"100""#));
+        assert!(error.contains(&format!(r#""sender":"{}""#, TAP_SENDER.1)));
+        assert!(error.contains(r#""deployment":null"#));
+    }
+
+    #[tokio::test]
+    async fn test_plugin_accepts_receipt() {
+        let receipt = checking_receipt().await;
+        assert!(wasm_check(ACCEPTING_PLUGIN, false)
+            .check(&Context::new(), &receipt)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_plugin_failure() {
+        let receipt = checking_receipt().await;
+        assert!(wasm_check(LOOPING_PLUGIN, false)
+            .check(&Context::new(), &receipt)
+            .await
+            .is_ok());
+        assert!(wasm_check(LOOPING_PLUGIN, true)
+            .check(&Context::new(), &receipt)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wasm_plugin_check_from_config() {
+        let enabled = ServiceReceiptChecksConfig {
+            allocation_eligible: true,
+            sender_balance: true,
+            timestamp: true,
+            deny_list: true,
+            max_receipt_value: true,
+            minimum_value: true,
+            wasm_plugin: true,
+        };
+        let plugin = || Some(wasm_plugin(ACCEPTING_PLUGIN, false));
+        assert!(IndexerTapContext::wasm_plugin_check(enabled, plugin()).is_some());
+        assert!(IndexerTapContext::wasm_plugin_check(enabled, None).is_none());
+
+        let disabled = ServiceReceiptChecksConfig {
+            wasm_plugin: false,
+            ..enabled
+        };
+        assert!(IndexerTapContext::wasm_plugin_check(disabled, plugin()).is_none());
+
+        // the plugin doesn't check the receipts
+        let on_request = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "on_request") (param i32 i32) (result i64) (i64.const 0)))
+        "#;
+        let plugin = Some(wasm_plugin(on_request, false));
+        assert!(IndexerTapContext::wasm_plugin_check(enabled, plugin).is_none());
+    }
+}
@@ -1,174 +1,80 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::{cell::RefCell, future::Future, sync::Arc};
+
 use anyhow::anyhow;
-use bigdecimal::num_bigint::BigInt;
-use itertools::{Either, Itertools};
-use sqlx::{types::BigDecimal, PgPool};
-use tap_core::{manager::adapters::ReceiptStore, receipt::WithValueAndTimestamp};
-use thegraph_core::alloy::{hex::ToHexExt, sol_types::Eip712Domain};
+use indexer_monitor::ContractSigners;
+use indexer_receipt::store::{ReceiptStore as ReceiptStorage, StoredReceipt};
+use tap_core::manager::adapters::ReceiptStore;
+use thegraph_core::alloy::sol_types::Eip712Domain;
 use tokio::{sync::mpsc::Receiver, task::JoinHandle};
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use uuid::Uuid;
 
 use super::{AdapterError, CheckingReceipt, IndexerTapContext, TapReceipt};
-
-#[derive(Clone)]
-pub struct InnerContext {
-    pub pgpool: PgPool,
+use crate::metrics::REPLAYED_RECEIPTS;
+
+/// Counts the receipts that were not stored because they already were
+fn record_replayed_receipts(receipts: usize, stored: u64) {
+    let replayed = (receipts as u64).saturating_sub(stored);
+    if replayed > 0 {
+        tracing::warn!(replayed, "Dropped receipts that were already stored");
+        REPLAYED_RECEIPTS
+            .with_label_values(&["dropped"])
+            .inc_by(replayed as f64);
+    }
 }
 
-#[derive(thiserror::Error, Debug)]
-enum ProcessReceiptError {
-    #[error("Failed to store v1 receipts: {0}")]
-    V1(anyhow::Error),
-    #[error("Failed to store v2 receipts: {0}")]
-    V2(anyhow::Error),
-    #[error("Failed to receipts for v1 and v2. Error v1: {0}. Error v2: {1}")]
-    Both(anyhow::Error, anyhow::Error),
+tokio::task_local! {
+    static REQUEST_BATCH: RefCell<RequestBatch>;
+    static HELD_RECEIPTS: RefCell<Vec<ReceiptStoreRequest>>;
 }
 
-impl InnerContext {
-    async fn process_db_receipts(
-        &self,
-        buffer: Vec<DatabaseReceipt>,
-    ) -> Result<(), ProcessReceiptError> {
-        let (v1_receipts, v2_receipts): (Vec<_>, Vec<_>) =
-            buffer.into_iter().partition_map(|r| match r {
-                DatabaseReceipt::V1(db_receipt_v1) => Either::Left(db_receipt_v1),
-                DatabaseReceipt::V2(db_receipt_v2) => Either::Right(db_receipt_v2),
-            });
-        let (insert_v1, insert_v2) = tokio::join!(
-            self.store_receipts_v1(v1_receipts),
-            self.store_receipts_v2(v2_receipts)
-        );
-        match (insert_v1, insert_v2) {
-            (Err(e1), Err(e2)) => Err(ProcessReceiptError::Both(e1.into(), e2.into())),
-            (Err(e1), _) => Err(ProcessReceiptError::V1(e1.into())),
-            (_, Err(e2)) => Err(ProcessReceiptError::V2(e2.into())),
-            _ => Ok(()),
-        }
-    }
-
-    async fn store_receipts_v1(&self, receipts: Vec<DbReceiptV1>) -> Result<(), AdapterError> {
-        let receipts_len = receipts.len();
-        let mut signers = Vec::with_capacity(receipts_len);
-        let mut signatures = Vec::with_capacity(receipts_len);
-        let mut allocation_ids = Vec::with_capacity(receipts_len);
-        let mut timestamps = Vec::with_capacity(receipts_len);
-        let mut nonces = Vec::with_capacity(receipts_len);
-        let mut values = Vec::with_capacity(receipts_len);
-
-        for receipt in receipts {
-            signers.push(receipt.signer_address);
-            signatures.push(receipt.signature);
-            allocation_ids.push(receipt.allocation_id);
-            timestamps.push(receipt.timestamp_ns);
-            nonces.push(receipt.nonce);
-            values.push(receipt.value);
-        }
-        sqlx::query!(
-            r#"INSERT INTO scalar_tap_receipts (
-                signer_address,
-                signature,
-                allocation_id,
-                timestamp_ns,
-                nonce,
-                value
-            ) SELECT * FROM UNNEST(
-                $1::CHAR(40)[],
-                $2::BYTEA[],
-                $3::CHAR(40)[],
-                $4::NUMERIC(20)[],
-                $5::NUMERIC(20)[],
-                $6::NUMERIC(40)[]
-            )"#,
-            &signers,
-            &signatures,
-            &allocation_ids,
-            &timestamps,
-            &nonces,
-            &values,
-        )
-        .execute(&self.pgpool)
+/// Runs `serve`, the receipts stored meanwhile are returned instead of being
+/// queued for storage
+pub(super) async fn with_held_receipts<F: Future>(
+    serve: F,
+) -> (F::Output, Vec<ReceiptStoreRequest>) {
+    HELD_RECEIPTS
+        .scope(RefCell::new(Vec::new()), async {
+            let output = serve.await;
+            (output, HELD_RECEIPTS.with(RefCell::take))
+        })
         .await
-        .map_err(|e| {
-            tracing::error!("Failed to store receipt: {}", e);
-            anyhow!(e)
-        })?;
-
-        Ok(())
-    }
-
-    async fn store_receipts_v2(&self, receipts: Vec<DbReceiptV2>) -> Result<(), AdapterError> {
-        let receipts_len = receipts.len();
-        let mut signers = Vec::with_capacity(receipts_len);
-        let mut signatures = Vec::with_capacity(receipts_len);
-        let mut allocation_ids = Vec::with_capacity(receipts_len);
-        let mut payers = Vec::with_capacity(receipts_len);
-        let mut data_services = Vec::with_capacity(receipts_len);
-        let mut service_providers = Vec::with_capacity(receipts_len);
-        let mut timestamps = Vec::with_capacity(receipts_len);
-        let mut nonces = Vec::with_capacity(receipts_len);
-        let mut values = Vec::with_capacity(receipts_len);
+}
 
-        for receipt in receipts {
-            signers.push(receipt.signer_address);
-            signatures.push(receipt.signature);
-            allocation_ids.push(receipt.allocation_id);
-            payers.push(receipt.payer);
-            data_services.push(receipt.data_service);
-            service_providers.push(receipt.service_provider);
-            timestamps.push(receipt.timestamp_ns);
-            nonces.push(receipt.nonce);
-            values.push(receipt.value);
-        }
-        sqlx::query!(
-            r#"INSERT INTO tap_horizon_receipts (
-                signer_address,
-                signature,
-                allocation_id,
-                payer,
-                data_service,
-                service_provider,
-                timestamp_ns,
-                nonce,
-                value
-            ) SELECT * FROM UNNEST(
-                $1::CHAR(40)[],
-                $2::BYTEA[],
-                $3::CHAR(40)[],
-                $4::CHAR(40)[],
-                $5::CHAR(40)[],
-                $6::CHAR(40)[],
-                $7::NUMERIC(20)[],
-                $8::NUMERIC(20)[],
-                $9::NUMERIC(40)[]
-            )"#,
-            &signers,
-            &signatures,
-            &allocation_ids,
-            &payers,
-            &data_services,
-            &service_providers,
-            &timestamps,
-            &nonces,
-            &values,
-        )
-        .execute(&self.pgpool)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to store receipt: {}", e);
-            anyhow!(e)
-        })?;
+/// Receipts of a request paid with several receipts, held back until all of
+/// them passed the checks
+struct RequestBatch {
+    request_id: Uuid,
+    expected: usize,
+    receipts: Vec<StoredReceipt>,
+}
 
-        Ok(())
-    }
+/// Runs `verify`, in which the `expected` receipts of request `request_id`
+/// are verified and stored
+///
+/// The receipts are only queued for storage, together, once all of them
+/// passed the checks. If one of them fails, none of them is stored.
+pub async fn with_request_batch<F: Future>(
+    request_id: Uuid,
+    expected: usize,
+    verify: F,
+) -> F::Output {
+    let batch = RequestBatch {
+        request_id,
+        expected,
+        receipts: Vec::with_capacity(expected),
+    };
+    REQUEST_BATCH.scope(RefCell::new(batch), verify).await
 }
 
 impl IndexerTapContext {
     pub fn spawn_store_receipt_task(
-        inner_context: InnerContext,
-        mut receiver: Receiver<DatabaseReceipt>,
+        storage: Arc<dyn ReceiptStorage>,
+        mut receiver: Receiver<ReceiptStoreRequest>,
         cancelation_token: CancellationToken,
     ) -> JoinHandle<()> {
         const BUFFER_SIZE: usize = 100;
@@ -178,8 +84,20 @@ impl IndexerTapContext {
                 tokio::select! {
                     biased;
                     _ = receiver.recv_many(&mut buffer, BUFFER_SIZE) => {
-                        if let Err(e) = inner_context.process_db_receipts(buffer).await {
-                            tracing::error!("{e}");
+                        let receipts: Vec<_> = buffer
+                            .into_iter()
+                            .flat_map(|request| match request {
+                                ReceiptStoreRequest::Store(receipt) => vec![receipt],
+                                ReceiptStoreRequest::StoreAll(receipts) => receipts,
+                            })
+                            .collect();
+                        if !receipts.is_empty() {
+                            let receipts_len = receipts.len();
+                            let span = tracing::info_span!("store_receipts", receipts = receipts_len);
+                            match storage.store_receipts(receipts).instrument(span).await {
+                                Ok(stored) => record_replayed_receipts(receipts_len, stored),
+                                Err(e) => tracing::error!("Failed to store receipts: {e:#}"),
+                            }
                         }
                     }
                     _ = cancelation_token.cancelled() => { break },
@@ -194,8 +112,29 @@ impl ReceiptStore<TapReceipt> for IndexerTapContext {
     type AdapterError = AdapterError;
 
     async fn store_receipt(&self, receipt: CheckingReceipt) -> Result<u64, Self::AdapterError> {
-        let db_receipt = DatabaseReceipt::from_receipt(receipt, &self.domain_separator)?;
-        self.receipt_producer.send(db_receipt).await.map_err(|e| {
+        let mut stored = stored_receipt(
+            receipt,
+            &self.domain_separator,
+            self.contract_signers.as_ref(),
+        )?;
+        // receipts of a batch are sent once the last of them is verified
+        let request = match REQUEST_BATCH.try_with(|batch| {
+            let mut batch = batch.borrow_mut();
+            stored.request_id = Some(batch.request_id);
+            batch.receipts.push(stored.clone());
+            (batch.receipts.len() >= batch.expected).then(|| std::mem::take(&mut batch.receipts))
+        }) {
+            Ok(Some(receipts)) => ReceiptStoreRequest::StoreAll(receipts),
+            Ok(None) => return Ok(0),
+            Err(_) => ReceiptStoreRequest::Store(stored),
+        };
+        // held until the query is served, see [super::ReceiptRefunds]
+        let mut request = Some(request);
+        let _ = HELD_RECEIPTS.try_with(|held| held.borrow_mut().extend(request.take()));
+        let Some(request) = request else {
+            return Ok(0);
+        };
+        self.receipt_producer.send(request).await.map_err(|e| {
             tracing::error!("Failed to queue receipt for storage: {}", e);
             anyhow!(e)
         })?;
@@ -205,103 +144,108 @@ impl ReceiptStore<TapReceipt> for IndexerTapContext {
     }
 }
 
-pub enum DatabaseReceipt {
-    V1(DbReceiptV1),
-    V2(DbReceiptV2),
-}
-
-impl DatabaseReceipt {
-    fn from_receipt(receipt: CheckingReceipt, separator: &Eip712Domain) -> anyhow::Result<Self> {
-        Ok(match receipt.signed_receipt() {
-            TapReceipt::V1(receipt) => Self::V1(DbReceiptV1::from_receipt(receipt, separator)?),
-            TapReceipt::V2(receipt) => Self::V2(DbReceiptV2::from_receipt(receipt, separator)?),
-        })
-    }
+/// Requests handled in order by the receipt storage task
+pub enum ReceiptStoreRequest {
+    Store(StoredReceipt),
+    /// Receipts of a single request, stored in the same batch
+    StoreAll(Vec<StoredReceipt>),
 }
 
-pub struct DbReceiptV1 {
-    signer_address: String,
-    signature: Vec<u8>,
-    allocation_id: String,
-    timestamp_ns: BigDecimal,
-    nonce: BigDecimal,
-    value: BigDecimal,
+fn stored_receipt(
+    receipt: CheckingReceipt,
+    separator: &Eip712Domain,
+    contract_signers: Option<&ContractSigners>,
+) -> anyhow::Result<StoredReceipt> {
+    let receipt = receipt.signed_receipt();
+    let signer = receipt.recover_signer(separator).map_err(|e| {
+        tracing::error!("Failed to recover receipt signer: {}", e);
+        anyhow!(e)
+    })?;
+    // the wallet the key signed for, found by the sender middleware
+    let signer = contract_signers
+        .and_then(|contract_signers| contract_signers.cached_wallet(&signer))
+        .unwrap_or(signer);
+    Ok(StoredReceipt {
+        receipt: receipt.clone(),
+        signer,
+        request_id: None,
+    })
 }
 
-impl DbReceiptV1 {
-    fn from_receipt(
-        receipt: &tap_graph::SignedReceipt,
-        separator: &Eip712Domain,
-    ) -> anyhow::Result<Self> {
-        let allocation_id = receipt.message.allocation_id.encode_hex();
-        let signature = receipt.signature.as_bytes().to_vec();
-
-        let signer_address = receipt
-            .recover_signer(separator)
-            .map_err(|e| {
-                tracing::error!("Failed to recover receipt signer: {}", e);
-                anyhow!(e)
-            })?
-            .encode_hex();
-
-        let timestamp_ns = BigDecimal::from(receipt.timestamp_ns());
-        let nonce = BigDecimal::from(receipt.message.nonce);
-        let value = BigDecimal::from(BigInt::from(receipt.value()));
-        Ok(Self {
-            allocation_id,
-            nonce,
-            signature,
-            signer_address,
-            timestamp_ns,
-            value,
-        })
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, future::Future, sync::Arc};
+
+    use indexer_receipt::store::{PgReceiptStore, ReceiptStore as _, StoredReceipt};
+    use sqlx::PgPool;
+    use tap_core::manager::adapters::ReceiptStore;
+    use test_assets::{
+        assert_while_retry, create_signed_receipt, SignedReceiptRequest, TAP_EIP712_DOMAIN,
+        TAP_SIGNER,
+    };
+    use uuid::Uuid;
+
+    use crate::tap::{CheckingReceipt, IndexerTapContext, TapReceipt};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_refunded_receipt_is_not_stored(pgpool: PgPool) {
+        let context = IndexerTapContext::new(
+            Arc::new(PgReceiptStore::new(pgpool.clone())),
+            TAP_EIP712_DOMAIN.clone(),
+        )
+        .await;
+        let refunds = context.receipt_refunds();
+        let count = || async {
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap()
+        };
+
+        for (nonce, refund) in [(1, true), (2, false)] {
+            let receipt = TapReceipt::V1(
+                create_signed_receipt(SignedReceiptRequest::builder().nonce(nonce).build()).await,
+            );
+            let served = refunds.hold(
+                async {
+                    context
+                        .store_receipt(CheckingReceipt::new(receipt))
+                        .await
+                        .unwrap();
+                    // never written while the query is served
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    assert_eq!(count().await, 0);
+                },
+                |_| refund,
+            );
+            served.await;
+        }
+        // only the receipt of the query served is stored
+        assert_while_retry!(count().await == 0);
+        assert_eq!(count().await, 1);
     }
-}
 
-pub struct DbReceiptV2 {
-    signer_address: String,
-    signature: Vec<u8>,
-    allocation_id: String,
-    payer: String,
-    data_service: String,
-    service_provider: String,
-    timestamp_ns: BigDecimal,
-    nonce: BigDecimal,
-    value: BigDecimal,
-}
-
-impl DbReceiptV2 {
-    fn from_receipt(
-        receipt: &tap_graph::v2::SignedReceipt,
-        separator: &Eip712Domain,
-    ) -> anyhow::Result<Self> {
-        let allocation_id = receipt.message.allocation_id.encode_hex();
-        let payer = receipt.message.payer.encode_hex();
-        let data_service = receipt.message.data_service.encode_hex();
-        let service_provider = receipt.message.service_provider.encode_hex();
-        let signature = receipt.signature.as_bytes().to_vec();
-
-        let signer_address = receipt
-            .recover_signer(separator)
-            .map_err(|e| {
-                tracing::error!("Failed to recover receipt signer: {}", e);
-                anyhow!(e)
-            })?
-            .encode_hex();
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_receipts_stored_without_request_ids(pgpool: PgPool) {
+        // database migrated by an indexer-agent release without the column
+        sqlx::query("ALTER TABLE scalar_tap_receipts DROP COLUMN request_id")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let store = PgReceiptStore::new(pgpool.clone()).with_request_ids(false);
+        let receipt = TapReceipt::V1(
+            create_signed_receipt(SignedReceiptRequest::builder().nonce(1).build()).await,
+        );
 
-        let timestamp_ns = BigDecimal::from(receipt.timestamp_ns());
-        let nonce = BigDecimal::from(receipt.message.nonce);
-        let value = BigDecimal::from(BigInt::from(receipt.value()));
-        Ok(Self {
-            allocation_id,
-            payer,
-            data_service,
-            service_provider,
-            nonce,
-            signature,
-            signer_address,
-            timestamp_ns,
-            value,
-        })
+        let stored = store
+            .store_receipts(vec![StoredReceipt {
+                receipt: receipt.clone(),
+                signer: TAP_SIGNER.1,
+                request_id: Some(Uuid::now_v7()),
+            }])
+            .await
+            .unwrap();
+        assert_eq!(stored, 1);
+        assert_eq!(store.receipt_request_id(&receipt).await.unwrap(), None);
     }
 }
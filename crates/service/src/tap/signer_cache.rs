@@ -0,0 +1,152 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use indexer_monitor::EscrowAccounts;
+use lru::LruCache;
+use thegraph_core::alloy::primitives::Address;
+use tokio::sync::watch;
+
+/// Number of signers kept, a few per sender
+const MAX_ENTRIES: NonZeroUsize = match NonZeroUsize::new(10_000) {
+    Some(max_entries) => max_entries,
+    None => unreachable!(),
+};
+
+struct CacheEntry {
+    sender: Address,
+    expires_at: Instant,
+}
+
+struct Entries {
+    /// incremented whenever the escrow accounts are updated
+    generation: u64,
+    senders: LruCache<Address, CacheEntry>,
+}
+
+/// Signer of a receipt missing from a [SignerCache], to insert its sender with
+#[derive(Debug)]
+pub struct SignerCacheMiss {
+    signer: Address,
+    generation: u64,
+}
+
+/// Senders of the signers of the recent receipts, including the senders of
+/// the contract wallets found by [indexer_monitor::ContractSigners]
+///
+/// The entries expire after the TTL and are all dropped as soon as the
+/// escrow accounts are updated, a signer that was removed or started
+/// thawing is never accepted from the cache.
+#[derive(Clone)]
+pub struct SignerCache {
+    entries: Arc<Mutex<Entries>>,
+    ttl: Duration,
+}
+
+impl SignerCache {
+    pub fn new(escrow_accounts: watch::Receiver<EscrowAccounts>, ttl: Duration) -> Self {
+        let entries = Arc::new(Mutex::new(Entries {
+            generation: 0,
+            senders: LruCache::new(MAX_ENTRIES),
+        }));
+        tokio::spawn(clear_on_change(escrow_accounts, Arc::downgrade(&entries)));
+        Self { entries, ttl }
+    }
+
+    /// Sender of `signer` if it was looked up recently
+    pub fn get(&self, signer: Address) -> Result<Address, SignerCacheMiss> {
+        let mut entries = self.entries.lock().unwrap();
+        let generation = entries.generation;
+        match entries.senders.get(&signer) {
+            Some(entry) if entry.expires_at > Instant::now() => Ok(entry.sender),
+            _ => Err(SignerCacheMiss { signer, generation }),
+        }
+    }
+
+    /// Caches the sender of the signer of `miss`, unless the escrow accounts
+    /// were updated since it was looked up
+    pub fn insert(&self, miss: SignerCacheMiss, sender: Address) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation == miss.generation {
+            entries.senders.put(
+                miss.signer,
+                CacheEntry {
+                    sender,
+                    expires_at: Instant::now() + self.ttl,
+                },
+            );
+        }
+    }
+}
+
+async fn clear_on_change(
+    mut escrow_accounts: watch::Receiver<EscrowAccounts>,
+    entries: Weak<Mutex<Entries>>,
+) {
+    while escrow_accounts.changed().await.is_ok() {
+        let Some(entries) = entries.upgrade() else {
+            break;
+        };
+        let mut entries = entries.lock().unwrap();
+        entries.generation += 1;
+        entries.senders.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use indexer_monitor::EscrowAccounts;
+    use test_assets::{
+        ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, TAP_SENDER, TAP_SIGNER,
+    };
+    use thegraph_core::alloy::primitives::Address;
+    use tokio::sync::watch;
+
+    use super::SignerCache;
+
+    #[tokio::test]
+    async fn test_cleared_on_escrow_accounts_update() {
+        let (tx, rx) = watch::channel(EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        ));
+        let cache = SignerCache::new(rx, Duration::from_secs(60));
+        let miss = cache.get(TAP_SIGNER.1).unwrap_err();
+        cache.insert(miss, TAP_SENDER.1);
+        assert_eq!(cache.get(TAP_SIGNER.1).unwrap(), TAP_SENDER.1);
+
+        // looked up before the signers are removed from the escrow accounts
+        let stale = cache.get(Address::ZERO).unwrap_err();
+        tx.send(EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            HashMap::new(),
+        ))
+        .unwrap();
+        for _ in 0..100 {
+            if cache.get(TAP_SIGNER.1).is_err() {
+                // the sender found before the update is not cached
+                cache.insert(stale, TAP_SENDER.1);
+                assert!(cache.get(Address::ZERO).is_err());
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("The signer is still cached");
+    }
+
+    #[tokio::test]
+    async fn test_expired_after_ttl() {
+        let (_tx, rx) = watch::channel(EscrowAccounts::default());
+        let cache = SignerCache::new(rx, Duration::ZERO);
+        let miss = cache.get(TAP_SIGNER.1).unwrap_err();
+        cache.insert(miss, TAP_SENDER.1);
+        assert!(cache.get(TAP_SIGNER.1).is_err());
+    }
+}
@@ -1,11 +1,14 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
 
-use axum::{body::to_bytes, extract::ConnectInfo, http::Request, Extension};
+use axum::{body::to_bytes, extract::ConnectInfo, http::Request, Extension, Router};
 use axum_extra::headers::Header;
-use indexer_config::{BlockchainConfig, GraphNodeConfig, IndexerConfig, NonZeroGRT};
+use indexer_config::{
+    BlockchainConfig, GraphNodeClientConfig, GraphNodeConfig, IndexerConfig, NonZeroGRT,
+    PaidRoutesConfig,
+};
 use indexer_monitor::EscrowAccounts;
 use indexer_service_rs::{
     service::{ServiceRouter, TapHeader},
@@ -24,43 +27,90 @@ use wiremock::{
     Mock, MockServer, ResponseTemplate,
 };
 
-#[sqlx::test(migrations = "../../migrations")]
-async fn full_integration_test(database: PgPool) {
+fn service_config(
+    free_query_auth_token: Option<String>,
+    paid_routes: Option<PaidRoutesConfig>,
+) -> indexer_config::ServiceConfig {
+    indexer_config::ServiceConfig {
+        serve_network_subgraph: false,
+        serve_escrow_subgraph: false,
+        serve_auth_token: None,
+        host_and_port: "0.0.0.0:0".parse().unwrap(),
+        additional_hosts_and_ports: vec![],
+        url_prefix: "/".into(),
+        tap: indexer_config::ServiceTapConfig {
+            max_receipt_value_grt: NonZeroGRT::new(1000000000000).unwrap(),
+            receipt_timestamp: None,
+            receipt_value_buckets_grt: vec![0.00001, 0.0001, 0.001],
+            signer_cache_ttl_secs: Duration::from_secs(60),
+            checks: indexer_config::ServiceReceiptChecksConfig {
+                allocation_eligible: true,
+                sender_balance: true,
+                timestamp: true,
+                deny_list: true,
+                max_receipt_value: true,
+                minimum_value: true,
+                wasm_plugin: true,
+            },
+        },
+        free_query_auth_token,
+        api_key_admin_token: None,
+        allocation_override_admin_token: None,
+        response_cache: None,
+        public_stats: None,
+        fees_summary: None,
+        prewarm: false,
+        partial_response: indexer_config::PartialResponsePolicy::Attest,
+        subgraph_manifests: None,
+        allowed_deployments: HashSet::new(),
+        denied_deployments: HashSet::new(),
+        cors: indexer_config::CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["*".to_string()],
+            security_headers: true,
+        },
+        compression: indexer_config::CompressionConfig {
+            min_size_bytes: 1024,
+            algorithms: HashSet::new(),
+        },
+        body_limits: indexer_config::BodyLimitsConfig {
+            query_bytes: 1048576,
+            status_bytes: 65536,
+            cost_bytes: 65536,
+        },
+        tls: None,
+        listener: Default::default(),
+        receipt_ingest: None,
+        receipt_storage: Default::default(),
+        free_query_signature: None,
+        query_limits: None,
+        query_blocklist: None,
+        wasm_plugin: None,
+        paid_routes,
+        legacy_routes_sunset: None,
+    }
+}
+
+/// Router serving the test allocations from the graph-node at
+/// `graph_node_url`, along with the senders of its watchers
+async fn create_app(
+    database: PgPool,
+    graph_node_url: Url,
+    service: indexer_config::ServiceConfig,
+) -> (Router, impl Sized) {
     let http_client = reqwest::Client::builder()
         .tcp_nodelay(true)
         .build()
         .expect("Failed to init HTTP client");
 
-    let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
-    let deployment = allocation.subgraph_deployment.id;
-
-    let mock_server = MockServer::start().await;
-
-    let mock = Mock::given(method("POST"))
-        .and(path(format!("/subgraphs/id/{deployment}")))
-        .respond_with(ResponseTemplate::new(200).set_body_raw(
-            r#"
-                {
-                    "data": {
-                        "graphNetwork": {
-                            "currentEpoch": 960
-                        }
-                    }
-                }
-                "#,
-            "application/json",
-        ));
-    mock_server.register(mock).await;
-
-    let (_escrow_tx, escrow_accounts) = watch::channel(EscrowAccounts::new(
+    let (escrow_tx, escrow_accounts) = watch::channel(EscrowAccounts::new(
         test_assets::ESCROW_ACCOUNTS_BALANCES.clone(),
         test_assets::ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.clone(),
     ));
-    let (_dispute_tx, dispute_manager) = watch::channel(Address::ZERO);
+    let (dispute_tx, dispute_manager) = watch::channel(Address::ZERO);
 
-    let (_allocations_tx, allocations) = watch::channel(test_assets::INDEXER_ALLOCATIONS.clone());
-
-    let graph_node_url = Url::parse(&mock_server.uri()).unwrap();
+    let (allocations_tx, allocations) = watch::channel(test_assets::INDEXER_ALLOCATIONS.clone());
 
     let router = ServiceRouter::builder()
         .database(database)
@@ -69,25 +119,29 @@ async fn full_integration_test(database: PgPool) {
         .graph_node(GraphNodeConfig {
             query_url: graph_node_url.clone(),
             status_url: graph_node_url.clone(),
+            subscription_url: None,
+            query_nodes: None,
+            hedging: None,
+            client: GraphNodeClientConfig {
+                max_idle_connections: 10,
+                idle_timeout_secs: Duration::from_secs(90),
+                keepalive_interval_secs: Duration::from_secs(30),
+                keepalive_timeout_secs: Duration::from_secs(20),
+                http2_prior_knowledge: false,
+                unix_socket_path: None,
+            },
         })
         .indexer(IndexerConfig {
             indexer_address: test_assets::INDEXER_ADDRESS,
             operator_mnemonic: test_assets::INDEXER_MNEMONIC.clone(),
+            additional_indexers: vec![],
         })
-        .service(indexer_config::ServiceConfig {
-            serve_network_subgraph: false,
-            serve_escrow_subgraph: false,
-            serve_auth_token: None,
-            host_and_port: "0.0.0.0:0".parse().unwrap(),
-            url_prefix: "/".into(),
-            tap: indexer_config::ServiceTapConfig {
-                max_receipt_value_grt: NonZeroGRT::new(1000000000000).unwrap(),
-            },
-            free_query_auth_token: None,
-        })
+        .service(service)
         .blockchain(BlockchainConfig {
             chain_id: indexer_config::TheGraphChainId::Test,
             receipts_verifier_address: test_assets::VERIFIER_ADDRESS,
+            contract_signers: None,
+            dispute_manager_address: None,
         })
         .timestamp_buffer_secs(Duration::from_secs(10))
         .escrow_accounts_v1(escrow_accounts.clone())
@@ -97,7 +151,36 @@ async fn full_integration_test(database: PgPool) {
         .build();
 
     let socket_info = Extension(ConnectInfo(SocketAddr::from(([0, 0, 0, 0], 1337))));
-    let mut app = router.create_router().await.unwrap().layer(socket_info);
+    let app = router.create_router().await.unwrap().layer(socket_info);
+    (app, (escrow_tx, dispute_tx, allocations_tx))
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn full_integration_test(database: PgPool) {
+    let allocation = INDEXER_ALLOCATIONS.values().next().unwrap().clone();
+    let deployment = allocation.subgraph_deployment.id;
+
+    let mock_server = MockServer::start().await;
+
+    let mock = Mock::given(method("POST"))
+        .and(path(format!("/subgraphs/id/{deployment}")))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            r#"
+                {
+                    "data": {
+                        "graphNetwork": {
+                            "currentEpoch": 960
+                        }
+                    }
+                }
+                "#,
+            "application/json",
+        ));
+    mock_server.register(mock).await;
+
+    let graph_node_url = Url::parse(&mock_server.uri()).unwrap();
+    let (mut app, _watchers) =
+        create_app(database, graph_node_url, service_config(None, None)).await;
 
     let res = app
         .call(Request::get("/").body(String::new()).unwrap())
@@ -135,6 +218,8 @@ async fn full_integration_test(database: PgPool) {
     let res = app.call(request).await.unwrap();
 
     assert_eq!(res.status(), StatusCode::OK);
+    // served without the version prefix
+    assert_eq!(res.headers()["deprecation"], "true");
 
     let graphql_response = res.into_body();
     let bytes = to_bytes(graphql_response, usize::MAX).await.unwrap();
@@ -159,3 +244,100 @@ async fn full_integration_test(database: PgPool) {
 
     insta::assert_snapshot!(res);
 }
+
+/// Status query sent to `app`, with the receipt and the free query token if set
+async fn query_status(
+    app: &mut Router,
+    receipt_value: Option<u128>,
+    free_query_auth_token: Option<&str>,
+) -> StatusCode {
+    let allocation = INDEXER_ALLOCATIONS.values().next().unwrap();
+    let query = QueryBody {
+        query: "{ indexingStatuses { subgraph } }".into(),
+        variables: None,
+    };
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri("/status")
+        .header("content-type", "application/json");
+    if let Some(value) = receipt_value {
+        let receipt = create_signed_receipt(
+            SignedReceiptRequest::builder()
+                .allocation_id(allocation.id)
+                .value(value)
+                .build(),
+        )
+        .await;
+        request = request.header(TapHeader::name(), serde_json::to_string(&receipt).unwrap());
+    }
+    if let Some(token) = free_query_auth_token {
+        request = request.header("authorization", format!("Bearer {token}"));
+    }
+    let request = request
+        .body(serde_json::to_string(&query).unwrap())
+        .unwrap();
+    app.call(request).await.unwrap().status()
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn paid_status_test(database: PgPool) {
+    let mock_server = MockServer::start().await;
+    mock_server
+        .register(Mock::given(method("POST")).and(path("/")).respond_with(
+            ResponseTemplate::new(200).set_body_raw(
+                r#"{ "data": { "indexingStatuses": [] } }"#,
+                "application/json",
+            ),
+        ))
+        .await;
+    let graph_node_url = Url::parse(&mock_server.uri()).unwrap();
+    let paid_routes = || PaidRoutesConfig {
+        status_min_value_grt: Some(NonZeroGRT::new(100).unwrap()),
+        health_min_value_grt: None,
+    };
+
+    // without the free query token, every query must be paid
+    let (mut app, _watchers) = create_app(
+        database.clone(),
+        graph_node_url.clone(),
+        service_config(None, Some(paid_routes())),
+    )
+    .await;
+    assert_eq!(
+        query_status(&mut app, None, None).await,
+        StatusCode::PAYMENT_REQUIRED
+    );
+    assert_eq!(
+        query_status(&mut app, Some(100), None).await,
+        StatusCode::OK
+    );
+    // below the price of the route
+    assert_eq!(
+        query_status(&mut app, Some(99), None).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // with the free query token, the free queries must carry it
+    let (mut app, _watchers) = create_app(
+        database,
+        graph_node_url,
+        service_config(Some("superdupersecrettoken".into()), Some(paid_routes())),
+    )
+    .await;
+    assert_eq!(
+        query_status(&mut app, None, Some("superdupersecrettoken")).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        query_status(&mut app, None, Some("wrongtoken")).await,
+        StatusCode::PAYMENT_REQUIRED
+    );
+    assert_eq!(
+        query_status(&mut app, None, None).await,
+        StatusCode::PAYMENT_REQUIRED
+    );
+    assert_eq!(
+        query_status(&mut app, Some(100), None).await,
+        StatusCode::OK
+    );
+}
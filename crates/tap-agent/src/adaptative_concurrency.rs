@@ -22,6 +22,9 @@
 //!
 //! If we receive a failed response, we decrement our limit by half to quickly
 //! relieve the pressure in the system.
+//!
+//! Failures that can't be retried, like an invalid RAV, only release the slot
+//! since they are not related to the load of the aggregator.
 
 use std::ops::Range;
 
@@ -78,6 +81,12 @@ impl AdaptiveLimiter {
         self.in_flight -= 1;
         self.current_limit = (self.current_limit / 2).max(self.range.start);
     }
+
+    /// Callback function that removes in_flight counter
+    /// without changing the current limit
+    pub fn on_rejected(&mut self) {
+        self.in_flight -= 1;
+    }
 }
 
 #[cfg(test)]
@@ -119,5 +128,9 @@ mod tests {
 
         assert!(limiter.acquire());
         assert!(!limiter.acquire());
+
+        limiter.on_rejected();
+        assert_eq!(limiter.current_limit, 3);
+        assert_eq!(limiter.in_flight, 2);
     }
 }
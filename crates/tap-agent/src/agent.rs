@@ -35,16 +35,22 @@
 //! They process one message at a time and that's why concurrent primitives like
 //! [std::sync::Mutex]s aren't needed.
 
+use std::collections::HashSet;
+
+use anyhow::Context;
 use indexer_config::{
     Config, EscrowSubgraphConfig, GraphNodeConfig, IndexerConfig, NetworkSubgraphConfig,
     SubgraphConfig, SubgraphsConfig, TapConfig,
 };
 use indexer_monitor::{
-    escrow_accounts_v1, escrow_accounts_v2, indexer_allocations, DeploymentDetails, SubgraphClient,
+    escrow_accounts_v1, escrow_accounts_v2, indexer_allocations, merge_allocations,
+    DeploymentDetails, EscrowRpcFallback, SubgraphCache, SubgraphClient,
 };
+use indexer_watcher::map_watcher;
 use ractor::{concurrency::JoinHandle, Actor, ActorRef};
 use sender_account::SenderAccountConfig;
 use sender_accounts_manager::SenderAccountsManager;
+use sqlx::PgPool;
 
 use crate::{
     agent::sender_accounts_manager::{SenderAccountsManagerArgs, SenderAccountsManagerMessage},
@@ -64,15 +70,42 @@ pub mod unaggregated_receipts;
 /// This is the main entrypoint for starting up tap-agent
 ///
 /// It uses the static [crate::CONFIG] to configure the agent.
-pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>) {
+/// The database pool is returned along with the manager for the health checks.
+pub async fn start_agent() -> anyhow::Result<(
+    ActorRef<SenderAccountsManagerMessage>,
+    JoinHandle<()>,
+    PgPool,
+)> {
+    let pgpool = database::connect(CONFIG.database.clone()).await;
+    if crate::CLI.migrate {
+        let applied = indexer_schema::run_migrations(&pgpool)
+            .await
+            .context("Failed to migrate the database")?;
+        tracing::info!(count = applied.len(), "Database migrated");
+    }
+    tokio::spawn(database::monitor_pool(pgpool.clone(), "primary"));
+    let (manager, handle) = start_agent_with_pool(pgpool.clone(), crate::CLI.strict_schema).await?;
+    Ok((manager, handle, pgpool))
+}
+
+/// Starts the agent on a database pool that may be shared with
+/// indexer-service, see [crate::embedded]
+pub async fn start_agent_with_pool(
+    pgpool: PgPool,
+    strict_schema: bool,
+) -> anyhow::Result<(ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>)> {
     let Config {
-        indexer: IndexerConfig {
-            indexer_address, ..
-        },
+        indexer:
+            IndexerConfig {
+                indexer_address,
+                additional_indexers,
+                ..
+            },
         graph_node:
             GraphNodeConfig {
                 status_url: graph_node_status_endpoint,
                 query_url: graph_node_query_endpoint,
+                ..
             },
         database,
         subgraphs:
@@ -85,29 +118,80 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
                                 query_auth_token: network_query_auth_token,
                                 deployment_id: network_deployment_id,
                                 syncing_interval_secs: network_sync_interval,
+                                ..
                             },
                         recently_closed_allocation_buffer_secs: recently_closed_allocation_buffer,
+                        max_block_lag,
+                        max_block_age_secs: max_block_age,
+                        ..
                     },
                 escrow:
                     EscrowSubgraphConfig {
                         config:
                             SubgraphConfig {
-                                query_url: escrow_query_url,
-                                query_auth_token: escrow_query_auth_token,
-                                deployment_id: escrow_deployment_id,
                                 syncing_interval_secs: escrow_sync_interval,
+                                ..
                             },
+                        rpc_fallback: escrow_rpc_fallback,
                     },
+                cache_directory: subgraph_cache_directory,
+                cache_max_age_secs: subgraph_cache_max_age,
             },
         tap:
             TapConfig {
                 // TODO: replace with a proper implementation once the gateway registry contract is ready
                 sender_aggregator_endpoints,
+                failure_retention_secs,
+                receipt_pruning,
+                fee_snapshot,
+                rav_redemptions,
+                checks: receipt_checks,
                 ..
             },
         ..
     } = &*CONFIG;
-    let pgpool = database::connect(database.clone()).await;
+    let replica_pgpool = match database::connect_replica(database).await {
+        Some(replica_pgpool) => {
+            tokio::spawn(database::monitor_pool(replica_pgpool.clone(), "replica"));
+            replica_pgpool
+        }
+        None => pgpool.clone(),
+    };
+
+    let mut required_tables = database::REQUIRED_TABLES.to_vec();
+    if fee_snapshot.is_some() {
+        required_tables.push(database::FEE_SNAPSHOT_TABLE);
+    }
+    if rav_redemptions.is_some() {
+        required_tables.push(database::RAV_REDEMPTIONS_TABLE);
+    }
+    indexer_schema::verify_schema(&pgpool, &required_tables, strict_schema).await?;
+
+    let disabled_checks = receipt_checks.disabled();
+    if !disabled_checks.is_empty() {
+        tracing::warn!(
+            ?disabled_checks,
+            "Some receipt checks are disabled, the receipts failing them are aggregated"
+        );
+    }
+
+    tokio::spawn(crate::domain_check::monitor_domain(
+        pgpool.clone(),
+        CONFIG.blockchain.chain_id as u64,
+        CONFIG.blockchain.receipts_verifier_address,
+    ));
+
+    // Must happen before the allocations sum up their invalid receipts
+    if let Some(retention) = failure_retention_secs {
+        match database::prune_stale_failures(&pgpool, *retention).await {
+            Ok(pruned) => tracing::info!(
+                failed_rav_requests = pruned.failed_rav_requests,
+                invalid_receipts = pruned.invalid_receipts,
+                "Pruned failures older than the retention"
+            ),
+            Err(error) => tracing::warn!(%error, "Failed to prune stale failures"),
+        }
+    }
 
     let http_client = reqwest::Client::new();
 
@@ -120,50 +204,113 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
                     graph_node_query_endpoint.clone(),
                     deployment,
                 )
+                .with_subscription_url(
+                    CONFIG
+                        .subgraphs
+                        .network
+                        .config
+                        .local_subscription_url(&CONFIG.graph_node),
+                )
             }),
             DeploymentDetails::for_query_url_with_token(
                 network_query_url.clone(),
                 network_query_auth_token.clone(),
-            ),
+            )
+            .with_subscription_url(CONFIG.subgraphs.network.config.remote_subscription_url()),
         )
         .await,
     ));
 
+    // The last allocations and escrow accounts read, used if the subgraphs are down
+    let subgraph_cache = subgraph_cache_directory
+        .as_ref()
+        .map(|directory| SubgraphCache::new(directory.clone(), *subgraph_cache_max_age));
+
     let indexer_allocations = indexer_allocations(
         network_subgraph,
         *indexer_address,
         *network_sync_interval,
         *recently_closed_allocation_buffer,
+        *max_block_lag,
+        *max_block_age,
+        subgraph_cache.clone(),
     )
     .await
-    .expect("Failed to initialize indexer_allocations watcher");
+    .expect("Failed to initialize indexer_allocations watcher")
+    .into_receiver();
 
-    let escrow_subgraph = Box::leak(Box::new(
-        SubgraphClient::new(
+    // The receipts of the other indexers served by the same indexer-service
+    // are in the same tables, they are left to their own tap-agent
+    let mut other_indexers_allocations = Vec::new();
+    for additional in additional_indexers {
+        other_indexers_allocations.push(
+            indexer_allocations(
+                network_subgraph,
+                additional.indexer_address,
+                *network_sync_interval,
+                *recently_closed_allocation_buffer,
+                *max_block_lag,
+                *max_block_age,
+                subgraph_cache.clone(),
+            )
+            .await
+            .expect("Failed to initialize indexer_allocations watcher")
+            .into_receiver(),
+        );
+    }
+    let other_indexers_allocations = match other_indexers_allocations.pop() {
+        Some(allocations) => map_watcher(
+            merge_allocations(allocations, other_indexers_allocations),
+            |allocations| allocations.keys().copied().collect(),
+        ),
+        None => tokio::sync::watch::channel(HashSet::new()).1,
+    };
+
+    let escrow_subgraph = Box::leak(Box::new(escrow_subgraph_client(http_client.clone()).await));
+
+    if let Some(redemptions) = rav_redemptions {
+        tokio::spawn(crate::redemptions::track_rav_redemptions(
+            pgpool.clone(),
+            escrow_subgraph,
+            redemptions.interval_secs,
+            redemptions.expiry_secs,
+        ));
+    }
+
+    let escrow_rpc_fallback = escrow_rpc_fallback.as_ref().map(|fallback| {
+        EscrowRpcFallback::new(
             http_client.clone(),
-            escrow_deployment_id.map(|deployment| {
-                DeploymentDetails::for_graph_node_url(
-                    graph_node_status_endpoint.clone(),
-                    graph_node_query_endpoint.clone(),
-                    deployment,
-                )
-            }),
-            DeploymentDetails::for_query_url_with_token(
-                escrow_query_url.clone(),
-                escrow_query_auth_token.clone(),
-            ),
+            fallback.rpc_url.clone(),
+            fallback.escrow_address,
+            fallback.max_subgraph_lag_secs,
         )
-        .await,
-    ));
-
+        .with_signers(fallback.signers.clone())
+    });
     let escrow_accounts_v1 = escrow_accounts_v1(
         escrow_subgraph,
         *indexer_address,
         *escrow_sync_interval,
         false,
+        escrow_rpc_fallback,
+        subgraph_cache,
+        // the stored receipts can be older than the max age of the service,
+        // all the revoked signers are needed to aggregate them
+        None,
     )
     .await
-    .expect("Error creating escrow_accounts channel");
+    .expect("Error creating escrow_accounts channel")
+    .into_receiver();
+
+    // the pruned receipts are v1 receipts, their signers are matched to the
+    // senders of the RAVs covering them
+    if let Some(pruning) = receipt_pruning {
+        tokio::spawn(database::maintain_receipt_pruning(
+            pgpool.clone(),
+            pruning.range_secs,
+            pruning.retention_secs,
+            escrow_accounts_v1.clone(),
+        ));
+    }
 
     let escrow_accounts_v2 = escrow_accounts_v2(
         escrow_subgraph,
@@ -172,15 +319,18 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
         false,
     )
     .await
-    .expect("Error creating escrow_accounts channel");
+    .expect("Error creating escrow_accounts channel")
+    .into_receiver();
 
     let config = Box::leak(Box::new(SenderAccountConfig::from_config(&CONFIG)));
 
     let args = SenderAccountsManagerArgs {
         config,
         domain_separator: EIP_712_DOMAIN.clone(),
-        pgpool,
+        pgpool: pgpool.clone(),
+        replica_pgpool,
         indexer_allocations,
+        other_indexers_allocations,
         escrow_accounts_v1,
         escrow_accounts_v2,
         escrow_subgraph,
@@ -189,7 +339,30 @@ pub async fn start_agent() -> (ActorRef<SenderAccountsManagerMessage>, JoinHandl
         prefix: None,
     };
 
-    SenderAccountsManager::spawn(None, SenderAccountsManager, args)
+    let (manager, handle) = SenderAccountsManager::spawn(None, SenderAccountsManager, args)
         .await
-        .expect("Failed to start sender accounts manager actor.")
+        .expect("Failed to start sender accounts manager actor.");
+    Ok((manager, handle))
+}
+
+/// Client of the escrow subgraph configured in [crate::CONFIG]
+pub(crate) async fn escrow_subgraph_client(http_client: reqwest::Client) -> SubgraphClient {
+    let config = &CONFIG.subgraphs.escrow.config;
+    SubgraphClient::new(
+        http_client,
+        config.deployment_id.map(|deployment| {
+            DeploymentDetails::for_graph_node_url(
+                CONFIG.graph_node.status_url.clone(),
+                CONFIG.graph_node.query_url.clone(),
+                deployment,
+            )
+            .with_subscription_url(config.local_subscription_url(&CONFIG.graph_node))
+        }),
+        DeploymentDetails::for_query_url_with_token(
+            config.query_url.clone(),
+            config.query_auth_token.clone(),
+        )
+        .with_subscription_url(config.remote_subscription_url()),
+    )
+    .await
 }
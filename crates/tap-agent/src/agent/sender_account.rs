@@ -4,16 +4,18 @@
 use std::{
     collections::{HashMap, HashSet},
     str::FromStr,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
 use futures::{stream, StreamExt};
-use indexer_monitor::{EscrowAccounts, SubgraphClient};
+use indexer_config::{AdaptiveTriggerConfig, ShardingConfig, TapReceiptChecksConfig};
+use indexer_error::ErrorCode;
+use indexer_monitor::{ContractSigners, EscrowAccounts, SubgraphClient};
 use indexer_query::{
     closed_allocations::{self, ClosedAllocations},
-    unfinalized_transactions, UnfinalizedTransactions,
+    unfinalized_transactions::{self, UnfinalizedTransactions},
 };
 use indexer_watcher::watch_pipe;
 use lazy_static::lazy_static;
@@ -35,17 +37,22 @@ use tonic::transport::{Channel, Endpoint};
 use tracing::Level;
 
 use super::{
-    sender_accounts_manager::{AllocationId, SenderType},
+    sender_accounts_manager::{sender_in_shard, AllocationId, SenderType},
     sender_allocation::{
-        AllocationConfig, SenderAllocation, SenderAllocationArgs, SenderAllocationMessage,
+        AllocationConfig, RavError, SenderAllocation, SenderAllocationArgs,
+        SenderAllocationMessage, EVICTED_REASON,
     },
 };
 use crate::{
     adaptative_concurrency::AdaptiveLimiter,
     agent::unaggregated_receipts::UnaggregatedReceipts,
     backoff::BackoffInfo,
+    events::{self, EventKind},
+    rav_budget::{RavRequestBudget, RavRequestGrant},
+    rav_trigger::AdaptiveTrigger,
+    sender_stats,
     tap::context::{Horizon, Legacy},
-    tracker::{SenderFeeTracker, SimpleFeeTracker},
+    tracker::{SenderFeeTracker, SimpleFeeTracker, TrackerExporter, TrackerMetrics},
 };
 
 lazy_static! {
@@ -57,30 +64,18 @@ lazy_static! {
         &["sender"]
     )
     .unwrap();
-    static ref UNAGGREGATED_FEES: GaugeVec = register_gauge_vec!(
-        "tap_unaggregated_fees_grt_total",
-        "Unggregated Fees value",
-        &["sender", "allocation"]
-    )
-    .unwrap();
+    static ref UNAGGREGATED_FEES: TrackerMetrics =
+        TrackerMetrics::register("tap_unaggregated_fees", "Unaggregated fees");
     static ref SENDER_FEE_TRACKER: GaugeVec = register_gauge_vec!(
         "tap_sender_fee_tracker_grt_total",
         "Sender fee tracker metric",
         &["sender"]
     )
     .unwrap();
-    static ref INVALID_RECEIPT_FEES: GaugeVec = register_gauge_vec!(
-        "tap_invalid_receipt_fees_grt_total",
-        "Failed receipt fees",
-        &["sender", "allocation"]
-    )
-    .unwrap();
-    static ref PENDING_RAV: GaugeVec = register_gauge_vec!(
-        "tap_pending_rav_grt_total",
-        "Pending ravs values",
-        &["sender", "allocation"]
-    )
-    .unwrap();
+    static ref INVALID_RECEIPT_FEES: TrackerMetrics =
+        TrackerMetrics::register("tap_invalid_receipt_fees", "Failed receipt fees");
+    static ref PENDING_RAV: TrackerMetrics =
+        TrackerMetrics::register("tap_pending_rav", "Pending RAV values");
     static ref MAX_FEE_PER_SENDER: GaugeVec = register_gauge_vec!(
         "tap_max_fee_per_sender_grt_total",
         "Max fee per sender in the config",
@@ -96,6 +91,8 @@ lazy_static! {
 }
 
 const INITIAL_RAV_REQUEST_CONCURRENT: usize = 1;
+/// Delay before trying again a RAV request refused by [RavRequestBudget]
+const RAV_REQUEST_BUDGET_RETRY: Duration = Duration::from_secs(1);
 
 type RavMap = HashMap<Address, u128>;
 type Balance = U256;
@@ -194,6 +191,9 @@ pub enum SenderAccountMessage {
     UpdateAllocationIds(HashSet<AllocationId>),
     /// Manual request to create a new Sender Allocation
     NewAllocationId(AllocationId),
+    /// Stops the [SenderAllocation] of a closed allocation once its grace
+    /// period is over, see [SenderAccountConfig::allocation_close_grace_period]
+    CloseAllocation(AllocationId),
     /// Updates the fee tracker for a given allocation
     ///
     /// All allowing or denying logic is called inside the message handler
@@ -205,13 +205,30 @@ pub enum SenderAccountMessage {
     UpdateInvalidReceiptFees(Address, UnaggregatedReceipts),
     /// Update rav tracker
     UpdateRav(RavInformation),
+    /// Stops the [SenderAllocation]s that didn't receive receipts for
+    /// [SenderAccountConfig::idle_timeout], and the [SenderAccount] itself
+    /// once it has nothing left to track
+    EvictIdleActors,
+    /// Exports the fee trackers to Prometheus, sent every
+    /// [SenderAccountConfig::tracker_export_interval]
+    ExportTrackers,
+    /// Requests a RAV for the allocation, or for all of them, whatever the
+    /// trigger value. Replies with the allocations a RAV was requested for
+    ForceRavRequest(
+        Option<Address>,
+        #[cfg_attr(
+            any(test, feature = "test"),
+            educe(PartialEq(ignore), Clone(method(crate::test::actors::clone_rpc_reply)))
+        )]
+        ractor::RpcReplyPort<Vec<Address>>,
+    ),
     #[cfg(test)]
     /// Returns the sender fee tracker, used for tests
     GetSenderFeeTracker(
         #[educe(PartialEq(ignore), Clone(method(crate::test::actors::clone_rpc_reply)))]
         ractor::RpcReplyPort<SenderFeeTracker>,
     ),
-    #[cfg(test)]
+    #[cfg(any(test, feature = "test"))]
     /// Returns the Deny status, used for tests
     GetDeny(
         #[educe(PartialEq(ignore), Clone(method(crate::test::actors::clone_rpc_reply)))]
@@ -243,6 +260,8 @@ pub struct SenderAccountArgs {
 
     /// Connection to database
     pub pgpool: PgPool,
+    /// Connection used for the read-only fee calculations of the allocations
+    pub replica_pgpool: PgPool,
     /// Current sender address
     pub sender_id: Address,
     /// Watcher that returns a list of escrow accounts for current indexer
@@ -297,8 +316,30 @@ pub struct State {
     rav_tracker: SimpleFeeTracker,
     /// Simple tracker used to monitor all invalid receipts ever.
     invalid_receipts_tracker: SimpleFeeTracker,
+    /// Exports [Self::sender_fee_tracker] to Prometheus
+    unaggregated_fees_exporter: TrackerExporter,
+    /// Exports [Self::rav_tracker] to Prometheus
+    rav_exporter: TrackerExporter,
+    /// Exports [Self::invalid_receipts_tracker] to Prometheus
+    invalid_receipts_exporter: TrackerExporter,
     /// Set containing current active allocations
     allocation_ids: HashSet<AllocationId>,
+    /// Closed allocations waiting for their grace period to end, with the
+    /// scheduled [SenderAccountMessage::CloseAllocation]
+    closing_allocation_ids:
+        HashMap<AllocationId, JoinHandle<Result<(), MessagingErr<SenderAccountMessage>>>>,
+    /// Allocations whose [SenderAllocation] was stopped while idle
+    ///
+    /// They are spawned again on their next receipt, or when they are
+    /// closed so their last RAV is still requested
+    evicted_allocation_ids: HashSet<AllocationId>,
+    /// Last receipt received per allocation, or when the allocation
+    /// was first checked for eviction
+    allocation_last_receipt: HashMap<Address, Instant>,
+    /// Last receipt received by the sender, or when the actor started
+    last_receipt: Instant,
+    /// Tasks forwarding the watchers to the actor, aborted once it stops
+    watchers: Vec<JoinHandle<()>>,
     /// Scheduler used to send a retry message in case sender is denied
     ///
     /// If scheduler is set, it's canceled in the first [SenderAccountMessage::UpdateReceiptFees]
@@ -321,6 +362,15 @@ pub struct State {
     /// This uses a simple algorithm where it increases by one in case
     /// of a success or decreases by half in case of a failure
     adaptive_limiter: AdaptiveLimiter,
+    /// Adjusts the trigger value to the receipts, the escrow headroom and
+    /// the aggregator, the trigger value is fixed if not set
+    rav_trigger: Option<AdaptiveTrigger>,
+    /// Grants of [SenderAccountConfig::rav_request_budget] held by the RAV
+    /// requests in flight, per allocation
+    rav_request_grants: HashMap<Address, RavRequestGrant>,
+    /// If the last RAV request was refused by the budget, a retry is
+    /// scheduled so it isn't delayed until the next receipt
+    waiting_for_budget: bool,
 
     /// Watcher containing the escrow accounts
     escrow_accounts: Receiver<EscrowAccounts>,
@@ -334,6 +384,8 @@ pub struct State {
     domain_separator: Eip712Domain,
     /// Database connection
     pgpool: PgPool,
+    /// Connection used for the read-only fee calculations of the allocations
+    replica_pgpool: PgPool,
     /// Aggregator client for V1
     ///
     /// This is only send to [SenderAllocation] in case
@@ -352,8 +404,15 @@ pub struct State {
     backoff_info: BackoffInfo,
 
     /// Allows the sender to go over escrow balance
-    /// limited to `max_amount_willing_to_lose_grt`
+    /// limited to `max_amount_willing_to_lose_grt`, without capping its
+    /// unaggregated fees
     trusted_sender: bool,
+    /// Maximum amount willing to lose for this sender, see
+    /// [SenderAccountConfig::max_amount_willing_to_lose_for]
+    max_amount_willing_to_lose: u128,
+    /// Fixed trigger value of this sender, see
+    /// [SenderAccountConfig::trigger_value_for]
+    base_trigger_value: u128,
 
     /// Sender type, used to decide which set of tables to use
     sender_type: SenderType,
@@ -370,12 +429,18 @@ pub struct SenderAccountConfig {
     pub max_amount_willing_to_lose_grt: u128,
     /// What value triggers a new Rav request
     pub trigger_value: u128,
+    /// Adjusts [Self::trigger_value] to each sender, if set
+    pub adaptive_trigger: Option<AdaptiveTriggerConfig>,
+    /// RAV requests in flight shared by every [SenderAccount]
+    pub rav_request_budget: RavRequestBudget,
 
     // allocation config
     /// Timeout config for rav requests
     pub rav_request_timeout: Duration,
     /// Limit of receipts sent in a Rav Request
     pub rav_request_receipt_limit: u64,
+    /// Recalculate unaggregated fees inside a repeatable read transaction
+    pub strict_fee_calculation: bool,
     /// Current indexer address
     pub indexer_address: Address,
     /// Polling interval for escrow subgraph
@@ -385,8 +450,37 @@ pub struct SenderAccountConfig {
     /// This is reached if the database is too slow
     pub tap_sender_timeout: Duration,
     /// Senders that are allowed to spend up to `max_amount_willing_to_lose_grt`
-    /// over the escrow balance
+    /// over the escrow balance, their unaggregated fees are not capped at it
     pub trusted_senders: HashSet<Address>,
+    /// Senders that use a different amount than [Self::max_amount_willing_to_lose_grt]
+    pub sender_max_amounts_willing_to_lose: HashMap<Address, u128>,
+    /// Senders that use a different trigger value than [Self::trigger_value]
+    pub sender_trigger_values: HashMap<Address, u128>,
+    /// Senders that use a different buffer than [Self::rav_request_buffer]
+    pub sender_rav_request_buffers: HashMap<Address, Duration>,
+    /// Idle period after which [SenderAccount]s and [SenderAllocation]s are stopped
+    ///
+    /// If set, they are only spawned once they receive a receipt, otherwise
+    /// every sender and allocation is kept running
+    pub idle_timeout: Option<Duration>,
+    /// How often [SenderAllocation]s save their fees, not saved if not set
+    pub fee_snapshot_interval: Option<Duration>,
+    /// Age after which the saved fees are ignored when a [SenderAllocation] starts
+    pub fee_snapshot_max_age: Duration,
+    /// Verifies the receipts signed for contract wallets, if enabled
+    pub contract_signers: Option<ContractSigners>,
+    /// Time a closed allocation keeps receiving receipts before its last RAV
+    pub allocation_close_grace_period: Duration,
+    /// Shard of the senders managed by this instance, all of them if not set
+    pub sharding: Option<ShardingConfig>,
+    /// Allocations exported with their own label by each tracker of a sender,
+    /// the ones with the largest fees
+    pub top_allocations: usize,
+    /// Interval between two exports of the fee trackers, rather than on every
+    /// receipt
+    pub tracker_export_interval: Duration,
+    /// Checks run on the receipts before they are aggregated
+    pub receipt_checks: TapReceiptChecksConfig,
 }
 
 impl SenderAccountConfig {
@@ -395,13 +489,86 @@ impl SenderAccountConfig {
         Self {
             rav_request_buffer: config.tap.rav_request.timestamp_buffer_secs,
             rav_request_receipt_limit: config.tap.rav_request.max_receipts_per_request,
+            strict_fee_calculation: config.tap.rav_request.strict_fee_calculation,
             indexer_address: config.indexer.indexer_address,
             escrow_polling_interval: config.subgraphs.escrow.config.syncing_interval_secs,
             max_amount_willing_to_lose_grt: config.tap.max_amount_willing_to_lose_grt.get_value(),
             trigger_value: config.tap.get_trigger_value(),
+            adaptive_trigger: config.tap.rav_request.adaptive_trigger.clone(),
+            rav_request_budget: RavRequestBudget::new(
+                config.tap.rav_request.max_concurrent_requests,
+            ),
             rav_request_timeout: config.tap.rav_request.request_timeout_secs,
             tap_sender_timeout: config.tap.sender_timeout_secs,
-            trusted_senders: config.tap.trusted_senders.clone(),
+            trusted_senders: config.tap.trusted_senders.keys().copied().collect(),
+            sender_max_amounts_willing_to_lose: config
+                .tap
+                .trusted_senders
+                .keys()
+                .map(|sender| (*sender, config.tap.max_amount_willing_to_lose_for(sender)))
+                .collect(),
+            sender_trigger_values: config
+                .tap
+                .trusted_senders
+                .keys()
+                .map(|sender| (*sender, config.tap.trigger_value_for(sender)))
+                .collect(),
+            sender_rav_request_buffers: config.tap.rav_request.sender_timestamp_buffer_secs.clone(),
+            idle_timeout: config.tap.actor_idle_timeout_secs,
+            fee_snapshot_interval: config
+                .tap
+                .fee_snapshot
+                .as_ref()
+                .map(|snapshot| snapshot.interval_secs),
+            fee_snapshot_max_age: config
+                .tap
+                .fee_snapshot
+                .as_ref()
+                .map_or(Duration::ZERO, |snapshot| snapshot.max_age_secs),
+            contract_signers: config.blockchain.contract_signers.as_ref().map(|config| {
+                ContractSigners::new(
+                    reqwest::Client::new(),
+                    config.rpc_url.clone(),
+                    config.cache_ttl_secs,
+                )
+            }),
+            allocation_close_grace_period: config.tap.allocation_close_grace_period_secs,
+            sharding: config.tap.sharding,
+            top_allocations: config.metrics.top_allocations,
+            tracker_export_interval: config.metrics.tracker_export_interval_secs,
+            receipt_checks: config.tap.checks,
+        }
+    }
+
+    /// Buffer used for the receipts of the sender
+    pub fn rav_request_buffer_for(&self, sender: &Address) -> Duration {
+        self.sender_rav_request_buffers
+            .get(sender)
+            .copied()
+            .unwrap_or(self.rav_request_buffer)
+    }
+
+    /// Maximum amount willing to lose for the sender
+    pub fn max_amount_willing_to_lose_for(&self, sender: &Address) -> u128 {
+        self.sender_max_amounts_willing_to_lose
+            .get(sender)
+            .copied()
+            .unwrap_or(self.max_amount_willing_to_lose_grt)
+    }
+
+    /// Value of the fees of the sender that triggers a RAV request
+    pub fn trigger_value_for(&self, sender: &Address) -> u128 {
+        self.sender_trigger_values
+            .get(sender)
+            .copied()
+            .unwrap_or(self.trigger_value)
+    }
+
+    /// Whether the sender is managed by this instance, see [Self::sharding]
+    pub fn manages_sender(&self, sender: &Address) -> bool {
+        match &self.sharding {
+            Some(sharding) => sender_in_shard(sender, sharding),
+            None => true,
         }
     }
 }
@@ -426,6 +593,7 @@ impl State {
             AllocationId::Legacy(id) => {
                 let args = SenderAllocationArgs::builder()
                     .pgpool(self.pgpool.clone())
+                    .replica_pgpool(self.replica_pgpool.clone())
                     .allocation_id(id)
                     .sender(self.sender)
                     .escrow_accounts(self.escrow_accounts.clone())
@@ -433,7 +601,10 @@ impl State {
                     .domain_separator(self.domain_separator.clone())
                     .sender_account_ref(sender_account_ref.clone())
                     .sender_aggregator(self.aggregator_v1.clone())
-                    .config(AllocationConfig::from_sender_config(self.config))
+                    .config(AllocationConfig::from_sender_config(
+                        self.config,
+                        &self.sender,
+                    ))
                     .build();
                 SenderAllocation::<Legacy>::spawn_linked(
                     Some(self.format_sender_allocation(&id)),
@@ -443,9 +614,11 @@ impl State {
                 )
                 .await?;
             }
-            AllocationId::Horizon(id) => {
+            AllocationId::Horizon(collection_id) => {
+                let id = collection_id.as_address();
                 let args = SenderAllocationArgs::builder()
                     .pgpool(self.pgpool.clone())
+                    .replica_pgpool(self.replica_pgpool.clone())
                     .allocation_id(id)
                     .sender(self.sender)
                     .escrow_accounts(self.escrow_accounts.clone())
@@ -453,7 +626,10 @@ impl State {
                     .domain_separator(self.domain_separator.clone())
                     .sender_account_ref(sender_account_ref.clone())
                     .sender_aggregator(self.aggregator_v2.clone())
-                    .config(AllocationConfig::from_sender_config(self.config))
+                    .config(AllocationConfig::from_sender_config(
+                        self.config,
+                        &self.sender,
+                    ))
                     .build();
 
                 SenderAllocation::<Horizon>::spawn_linked(
@@ -467,6 +643,47 @@ impl State {
         }
         Ok(())
     }
+
+    /// Stops the [SenderAllocation] of a closed allocation, which requests
+    /// its last RAV
+    ///
+    /// The receipts already sent to the allocation are processed before it
+    /// stops.
+    async fn close_allocation(
+        &mut self,
+        sender_account_ref: ActorRef<SenderAccountMessage>,
+        allocation_id: AllocationId,
+    ) {
+        // the last rav of an evicted allocation is requested
+        // by spawning it again before shutting it down
+        if self.evicted_allocation_ids.remove(&allocation_id) {
+            if let Err(error) = self
+                .create_sender_allocation(sender_account_ref, allocation_id)
+                .await
+            {
+                tracing::error!(
+                    %error,
+                    %allocation_id,
+                    "There was an error while creating Sender Allocation."
+                );
+            }
+        }
+        if let Some(sender_handle) = ActorRef::<SenderAllocationMessage>::where_is(
+            self.format_sender_allocation(&allocation_id.address()),
+        ) {
+            tracing::trace!(%allocation_id, "SenderAccount shutting down SenderAllocation");
+            // we can not send a rav request to this allocation
+            // because it's gonna trigger the last rav
+            self.sender_fee_tracker
+                .block_allocation_id(allocation_id.address());
+            if let Err(error) = sender_handle.drain() {
+                tracing::warn!(%error, %allocation_id, "Could not drain SenderAllocation, stopping it");
+                sender_handle.stop(None);
+            }
+            self.allocation_ids.remove(&allocation_id);
+        }
+    }
+
     fn format_sender_allocation(&self, allocation_id: &Address) -> String {
         let mut sender_allocation_id = String::new();
         if let Some(prefix) = &self.prefix {
@@ -477,10 +694,18 @@ impl State {
         sender_allocation_id
     }
 
+    /// Requests a RAV for the allocation with the most fees, the ones closing
+    /// first so their last RAV is as small as possible
     async fn rav_request_for_heaviest_allocation(&mut self) -> anyhow::Result<()> {
+        let closing_allocation_ids = self
+            .closing_allocation_ids
+            .keys()
+            .map(AllocationId::address)
+            .collect();
         let allocation_id = self
             .sender_fee_tracker
-            .get_heaviest_allocation_id()
+            .get_heaviest_allocation_id_among(&closing_allocation_ids)
+            .or_else(|| self.sender_fee_tracker.get_heaviest_allocation_id())
             .ok_or_else(|| {
                 self.backoff_info.fail();
                 anyhow::anyhow!(
@@ -504,6 +729,18 @@ impl State {
         let Some(allocation) = allocation else {
             anyhow::bail!("Error while getting allocation actor {allocation_id}");
         };
+        let grant = match self.config.rav_request_budget.try_grant(self.sender) {
+            Ok(grant) => grant,
+            Err(reason) => {
+                tracing::debug!(
+                    ?reason,
+                    %allocation_id,
+                    "RAV request budget not available, retrying later"
+                );
+                self.waiting_for_budget = true;
+                return Ok(());
+            }
+        };
 
         allocation
             .cast(SenderAllocationMessage::TriggerRavRequest)
@@ -513,11 +750,57 @@ impl State {
                 )
             })?;
         self.adaptive_limiter.acquire();
+        self.rav_request_grants.insert(allocation_id, grant);
         self.sender_fee_tracker.start_rav_request(allocation_id);
+        if let Some(rav_trigger) = &mut self.rav_trigger {
+            rav_trigger.start_rav_request(allocation_id, Instant::now());
+        }
 
         Ok(())
     }
 
+    /// Requests a RAV for `allocation_id`, or for every allocation, even if
+    /// the trigger value is not reached. Only the receipts outside of the
+    /// timestamp buffer are aggregated, the allocations without any, blocked,
+    /// or already requesting a RAV are skipped
+    async fn force_rav_request(&mut self, allocation_id: Option<Address>) -> Vec<Address> {
+        let allocation_ids = match allocation_id {
+            Some(allocation_id) => vec![allocation_id],
+            None => self
+                .sender_fee_tracker
+                .get_list_of_allocation_ids()
+                .into_iter()
+                .collect(),
+        };
+        let mut requested = vec![];
+        for allocation_id in allocation_ids {
+            if !self.sender_fee_tracker.can_trigger_rav(allocation_id)
+                || self
+                    .sender_fee_tracker
+                    .get_count_outside_buffer_for_allocation(&allocation_id)
+                    == 0
+            {
+                continue;
+            }
+            match self.rav_request_for_allocation(allocation_id).await {
+                // not granted if the RAV request budget is exhausted
+                Ok(()) if self.rav_request_grants.contains_key(&allocation_id) => {
+                    requested.push(allocation_id)
+                }
+                Ok(()) => {}
+                Err(error) => {
+                    tracing::warn!(%error, %allocation_id, "Failed to force a RAV request")
+                }
+            }
+        }
+        tracing::info!(
+            sender = %self.sender,
+            allocations = ?requested,
+            "Forced RAV requests"
+        );
+        requested
+    }
+
     /// Proccess the rav response sent by [SenderAllocation]
     ///
     /// This updates all backoff information for fee_tracker, backoff_info and
@@ -528,7 +811,18 @@ impl State {
         rav_response: (UnaggregatedReceipts, anyhow::Result<Option<RavInformation>>),
     ) {
         self.sender_fee_tracker.finish_rav_request(allocation_id);
+        self.rav_request_grants.remove(&allocation_id);
+        if let Some(rav_trigger) = &mut self.rav_trigger {
+            rav_trigger.finish_rav_request(allocation_id, Instant::now());
+        }
         let (fees, rav_result) = rav_response;
+        sender_stats::publish_rav_result(
+            self.sender,
+            allocation_id,
+            rav_result
+                .as_ref()
+                .map(|rav| rav.as_ref().map(|rav| rav.value_aggregate)),
+        );
         match rav_result {
             Ok(signed_rav) => {
                 self.sender_fee_tracker.ok_rav_request(allocation_id);
@@ -537,9 +831,18 @@ impl State {
                 self.update_rav(allocation_id, rav_value);
             }
             Err(err) => {
+                let code = err
+                    .downcast_ref::<RavError>()
+                    .map_or_else(|| err.error_code(), RavError::error_code);
                 self.sender_fee_tracker.failed_rav_backoff(allocation_id);
-                self.adaptive_limiter.on_failure();
+                if code.retriable() {
+                    self.adaptive_limiter.on_failure();
+                } else {
+                    self.adaptive_limiter.on_rejected();
+                }
                 tracing::error!(
+                    %code,
+                    retriable = code.retriable(),
                     "Error while requesting RAV for sender {} and allocation {}: {}",
                     self.sender,
                     allocation_id,
@@ -552,9 +855,7 @@ impl State {
 
     fn update_rav(&mut self, allocation_id: Address, rav_value: u128) {
         self.rav_tracker.update(allocation_id, rav_value);
-        PENDING_RAV
-            .with_label_values(&[&self.sender.to_string(), &allocation_id.to_string()])
-            .set(rav_value as f64);
+        self.publish_fees();
     }
 
     fn update_sender_fee(
@@ -567,16 +868,70 @@ impl State {
         SENDER_FEE_TRACKER
             .with_label_values(&[&self.sender.to_string()])
             .set(self.sender_fee_tracker.get_total_fee() as f64);
+        self.publish_fees();
+    }
 
-        UNAGGREGATED_FEES
-            .with_label_values(&[&self.sender.to_string(), &allocation_id.to_string()])
-            .set(unaggregated_fees.value as f64);
+    /// Exports the fee trackers to Prometheus
+    fn export_trackers(&mut self) {
+        self.unaggregated_fees_exporter
+            .export(&self.sender_fee_tracker);
+        self.rav_exporter.export(&self.rav_tracker);
+        self.invalid_receipts_exporter
+            .export(&self.invalid_receipts_tracker);
+    }
+
+    /// Publishes the fees not redeemed yet to the [sender_stats] subscribers
+    fn publish_fees(&self) {
+        sender_stats::publish_fees(
+            self.sender,
+            self.sender_fee_tracker.get_total_fee(),
+            self.rav_tracker.get_total_fee(),
+            self.sender_balance.to_u128().unwrap_or(u128::MAX),
+        );
+    }
+
+    /// Value of the fees that triggers a RAV request, adjusted by
+    /// [Self::rav_trigger] if set
+    fn trigger_value(&mut self) -> u128 {
+        let headroom = self.headroom();
+        let Some(rav_trigger) = &mut self.rav_trigger else {
+            return self.base_trigger_value;
+        };
+        let trigger_value = rav_trigger.trigger_value(headroom, Instant::now());
+        RAV_REQUEST_TRIGGER_VALUE
+            .with_label_values(&[&self.sender.to_string()])
+            .set(trigger_value as f64);
+        trigger_value
+    }
+
+    /// Value of the receipts that can still be received before
+    /// [Self::deny_condition_reached]
+    fn headroom(&self) -> u128 {
+        let pending_ravs = self.rav_tracker.get_total_fee();
+        let unaggregated_fees = self.sender_fee_tracker.get_total_fee();
+        let max_amount_willing_to_lose = self.max_amount_willing_to_lose;
+        let balance = if self.trusted_sender {
+            self.sender_balance + U256::from(max_amount_willing_to_lose)
+        } else {
+            self.sender_balance
+        };
+        let balance_headroom = balance
+            .saturating_sub(U256::from(pending_ravs + unaggregated_fees))
+            .to_u128()
+            .unwrap_or(u128::MAX);
+        if self.trusted_sender {
+            return balance_headroom;
+        }
+        let invalid_receipt_fees = self.invalid_receipts_tracker.get_total_fee();
+        let max_value_headroom =
+            max_amount_willing_to_lose.saturating_sub(unaggregated_fees + invalid_receipt_fees);
+        balance_headroom.min(max_value_headroom)
     }
 
     fn deny_condition_reached(&self) -> bool {
         let pending_ravs = self.rav_tracker.get_total_fee();
         let unaggregated_fees = self.sender_fee_tracker.get_total_fee();
-        let max_amount_willing_to_lose = self.config.max_amount_willing_to_lose_grt;
+        let max_amount_willing_to_lose = self.max_amount_willing_to_lose;
 
         // if it's a trusted sender, allow to spend up to max_amount_willing_to_lose
         let balance = if self.trusted_sender {
@@ -587,8 +942,9 @@ impl State {
 
         let pending_fees_over_balance = U256::from(pending_ravs + unaggregated_fees) >= balance;
         let invalid_receipt_fees = self.invalid_receipts_tracker.get_total_fee();
-        let total_fee_over_max_value =
-            unaggregated_fees + invalid_receipt_fees >= max_amount_willing_to_lose;
+        // the unaggregated fees of a trusted sender are only capped by its balance
+        let total_fee_over_max_value = !self.trusted_sender
+            && unaggregated_fees + invalid_receipt_fees >= max_amount_willing_to_lose;
 
         tracing::trace!(
             trusted_sender = %self.trusted_sender,
@@ -606,7 +962,7 @@ impl State {
             trusted_sender = %self.trusted_sender,
             fee_tracker = self.sender_fee_tracker.get_total_fee(),
             rav_tracker = self.rav_tracker.get_total_fee(),
-            max_amount_willing_to_lose = self.config.max_amount_willing_to_lose_grt,
+            max_amount_willing_to_lose = self.max_amount_willing_to_lose,
             sender_balance = self.sender_balance.to_u128(),
             "Denying sender."
         );
@@ -616,6 +972,8 @@ impl State {
         SENDER_DENIED
             .with_label_values(&[&self.sender.to_string()])
             .set(1);
+        sender_stats::publish_deny_status(self.sender, true);
+        events::publish(self.sender, EventKind::SenderDenied);
     }
 
     /// Will update [`State::denied`], as well as the denylist table in the database.
@@ -623,7 +981,7 @@ impl State {
         tracing::info!(
             fee_tracker = self.sender_fee_tracker.get_total_fee(),
             rav_tracker = self.rav_tracker.get_total_fee(),
-            max_amount_willing_to_lose = self.config.max_amount_willing_to_lose_grt,
+            max_amount_willing_to_lose = self.max_amount_willing_to_lose,
             sender_balance = self.sender_balance.to_u128(),
             "Allowing sender."
         );
@@ -658,6 +1016,8 @@ impl State {
         SENDER_DENIED
             .with_label_values(&[&self.sender.to_string()])
             .set(0);
+        sender_stats::publish_deny_status(self.sender, false);
+        events::publish(self.sender, EventKind::SenderAllowed);
     }
 
     /// Receives a list of possible closed allocations and verify
@@ -715,6 +1075,83 @@ impl State {
     }
 }
 
+/// Number of redeem transactions fetched per query
+const UNFINALIZED_TRANSACTIONS_PAGE_SIZE: i64 = 200;
+
+/// Number of times all the pages are fetched again when the block the
+/// first page was read at is not available anymore
+const MAX_REORG_RETRIES: u32 = 3;
+
+/// Returns the allocations of `allocation_ids` whose RAV was already
+/// redeemed by `sender`
+///
+/// All the pages are read at the block of the first one, otherwise a reorg
+/// between two pages could report a redeem transaction that was dropped.
+pub(crate) async fn query_redeemed_allocations(
+    escrow_subgraph: &'static SubgraphClient,
+    sender: Address,
+    allocation_ids: Vec<String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut block_hash: Option<String> = None;
+    let mut last = String::new();
+    let mut redeemed_allocations = vec![];
+    let mut retries = 0;
+
+    loop {
+        let result = escrow_subgraph
+            .query::<UnfinalizedTransactions, _>(unfinalized_transactions::Variables {
+                unfinalized_ravs_allocation_ids: allocation_ids.clone(),
+                sender: format!("{:x?}", sender),
+                block: block_hash
+                    .clone()
+                    .map(|hash| unfinalized_transactions::Block_height {
+                        hash: Some(hash),
+                        number: None,
+                        number_gte: None,
+                    }),
+                first: UNFINALIZED_TRANSACTIONS_PAGE_SIZE,
+                last: last.clone(),
+            })
+            .await
+            .and_then(|response| response);
+
+        let data = match result {
+            Ok(data) => data,
+            Err(error) if block_hash.is_some() && retries < MAX_REORG_RETRIES => {
+                retries += 1;
+                tracing::warn!(
+                    %error,
+                    retries,
+                    "Failed to query redeem transactions at the block of the first page, \
+                    it may have been reorged. Querying all pages again"
+                );
+                block_hash = None;
+                last = String::new();
+                redeemed_allocations.clear();
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+
+        let page_len = data.transactions.len();
+        if block_hash.is_none() {
+            block_hash = data.meta.and_then(|meta| meta.block.hash);
+        }
+        if let Some(transaction) = data.transactions.last() {
+            last = transaction.id.clone();
+        }
+        redeemed_allocations.extend(data.transactions.into_iter().map(|transaction| {
+            transaction
+                .allocation_id
+                .expect("all redeem tx must have allocation_id")
+        }));
+        if (page_len as i64) < UNFINALIZED_TRANSACTIONS_PAGE_SIZE {
+            break;
+        }
+    }
+    Ok(redeemed_allocations)
+}
+
 /// Actor implementation for [SenderAccount]
 #[async_trait::async_trait]
 impl Actor for SenderAccount {
@@ -731,6 +1168,7 @@ impl Actor for SenderAccount {
         SenderAccountArgs {
             config,
             pgpool,
+            replica_pgpool,
             sender_id,
             escrow_accounts,
             indexer_allocations,
@@ -745,7 +1183,7 @@ impl Actor for SenderAccount {
         }: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         let myself_clone = myself.clone();
-        watch_pipe(indexer_allocations, move |allocation_ids| {
+        let allocations_watcher = watch_pipe(indexer_allocations, move |allocation_ids| {
             let allocation_ids = allocation_ids.clone();
             // Update the allocation_ids
             myself_clone
@@ -759,7 +1197,7 @@ impl Actor for SenderAccount {
         let myself_clone = myself.clone();
         let pgpool_clone = pgpool.clone();
         let accounts_clone = escrow_accounts.clone();
-        watch_pipe(accounts_clone, move |escrow_account| {
+        let escrow_watcher = watch_pipe(accounts_clone, move |escrow_account| {
             let myself = myself_clone.clone();
             let pgpool = pgpool_clone.clone();
             // Get balance or default value for sender
@@ -805,29 +1243,17 @@ impl Actor for SenderAccount {
                 let redeemed_ravs_allocation_ids = match sender_type {
                     SenderType::Legacy => {
                         // This query returns unfinalized transactions for v1
-                        match escrow_subgraph
-                            .query::<UnfinalizedTransactions, _>(
-                                unfinalized_transactions::Variables {
-                                    unfinalized_ravs_allocation_ids: last_non_final_ravs
-                                        .iter()
-                                        .map(|rav| rav.0.to_string())
-                                        .collect::<Vec<_>>(),
-                                    sender: format!("{:x?}", sender_id),
-                                },
-                            )
-                            .await
-                        {
-                            Ok(Ok(response)) => response
-                                .transactions
-                                .into_iter()
-                                .map(|tx| {
-                                    tx.allocation_id
-                                        .expect("all redeem tx must have allocation_id")
-                                })
-                                .collect::<Vec<_>>(),
-                            // if we have any problems, we don't want to filter out
-                            _ => vec![],
-                        }
+                        query_redeemed_allocations(
+                            escrow_subgraph,
+                            sender_id,
+                            last_non_final_ravs
+                                .iter()
+                                .map(|rav| rav.0.to_string())
+                                .collect(),
+                        )
+                        .await
+                        // if we have any problems, we don't want to filter out
+                        .unwrap_or_default()
                     }
                     // TODO Implement query for unfinalized v2 transactions
                     // Depends on Escrow Subgraph Schema
@@ -908,13 +1334,15 @@ impl Actor for SenderAccount {
             .with_label_values(&[&sender_id.to_string()])
             .set(denied as i64);
 
+        let max_amount_willing_to_lose = config.max_amount_willing_to_lose_for(&sender_id);
+        let base_trigger_value = config.trigger_value_for(&sender_id);
         MAX_FEE_PER_SENDER
             .with_label_values(&[&sender_id.to_string()])
-            .set(config.max_amount_willing_to_lose_grt as f64);
+            .set(max_amount_willing_to_lose as f64);
 
         RAV_REQUEST_TRIGGER_VALUE
             .with_label_values(&[&sender_id.to_string()])
-            .set(config.trigger_value as f64);
+            .set(base_trigger_value as f64);
 
         let endpoint = Endpoint::new(sender_aggregator_endpoint.to_string())
             .context("Failed to create an endpoint for the sender aggregator")?;
@@ -944,25 +1372,50 @@ impl Actor for SenderAccount {
         let aggregator_v2 = aggregator_v2.send_compressed(tonic::codec::CompressionEncoding::Zstd);
         let state = State {
             prefix,
-            sender_fee_tracker: SenderFeeTracker::new(config.rav_request_buffer),
+            sender_fee_tracker: SenderFeeTracker::new(config.rav_request_buffer_for(&sender_id)),
             rav_tracker: SimpleFeeTracker::default(),
             invalid_receipts_tracker: SimpleFeeTracker::default(),
+            unaggregated_fees_exporter: TrackerExporter::new(
+                &UNAGGREGATED_FEES,
+                sender_id,
+                config.top_allocations,
+            ),
+            rav_exporter: TrackerExporter::new(&PENDING_RAV, sender_id, config.top_allocations),
+            invalid_receipts_exporter: TrackerExporter::new(
+                &INVALID_RECEIPT_FEES,
+                sender_id,
+                config.top_allocations,
+            ),
             allocation_ids: allocation_ids.clone(),
+            closing_allocation_ids: HashMap::new(),
+            evicted_allocation_ids: HashSet::new(),
+            allocation_last_receipt: HashMap::new(),
+            last_receipt: Instant::now(),
+            watchers: vec![allocations_watcher, escrow_watcher],
             scheduled_rav_request: None,
             sender: sender_id,
             denied,
             sender_balance,
             retry_interval,
             adaptive_limiter: AdaptiveLimiter::new(INITIAL_RAV_REQUEST_CONCURRENT, 1..50),
+            rav_trigger: config
+                .adaptive_trigger
+                .as_ref()
+                .map(|adaptive| AdaptiveTrigger::new(base_trigger_value, adaptive)),
+            rav_request_grants: HashMap::new(),
+            waiting_for_budget: false,
             escrow_accounts,
             escrow_subgraph,
             network_subgraph,
             domain_separator,
             pgpool,
+            replica_pgpool,
             aggregator_v1,
             aggregator_v2,
             backoff_info: BackoffInfo::default(),
             trusted_sender: config.trusted_senders.contains(&sender_id),
+            max_amount_willing_to_lose,
+            base_trigger_value,
             config,
             sender_type,
         };
@@ -976,6 +1429,13 @@ impl Actor for SenderAccount {
             .into_iter()
             .collect::<anyhow::Result<Vec<()>>>()?;
 
+        if let Some(idle_timeout) = config.idle_timeout {
+            myself.send_interval(idle_timeout, || SenderAccountMessage::EvictIdleActors);
+        }
+        myself.send_interval(config.tracker_export_interval, || {
+            SenderAccountMessage::ExportTrackers
+        });
+
         tracing::info!(sender = %sender_id, "SenderAccount created!");
         Ok(state)
     }
@@ -1010,10 +1470,6 @@ impl Actor for SenderAccount {
                 }
             }
             SenderAccountMessage::UpdateInvalidReceiptFees(allocation_id, unaggregated_fees) => {
-                INVALID_RECEIPT_FEES
-                    .with_label_values(&[&state.sender.to_string(), &allocation_id.to_string()])
-                    .set(unaggregated_fees.value as f64);
-
                 state
                     .invalid_receipts_tracker
                     .update(allocation_id, unaggregated_fees.value);
@@ -1054,22 +1510,18 @@ impl Actor for SenderAccount {
                         state
                             .sender_fee_tracker
                             .add(allocation_id, value, timestamp_ns);
+                        if let Some(rav_trigger) = &mut state.rav_trigger {
+                            rav_trigger.record_receipt(value, Instant::now());
+                        }
+                        if state.config.idle_timeout.is_some() {
+                            let now = Instant::now();
+                            state.last_receipt = now;
+                            state.allocation_last_receipt.insert(allocation_id, now);
+                        }
 
                         SENDER_FEE_TRACKER
                             .with_label_values(&[&state.sender.to_string()])
                             .set(state.sender_fee_tracker.get_total_fee() as f64);
-                        UNAGGREGATED_FEES
-                            .with_label_values(&[
-                                &state.sender.to_string(),
-                                &allocation_id.to_string(),
-                            ])
-                            .set(
-                                state
-                                    .sender_fee_tracker
-                                    .get_total_fee_for_allocation(&allocation_id)
-                                    .map(|fee| fee.value)
-                                    .unwrap_or_default() as f64,
-                            );
                     }
                     ReceiptFees::RavRequestResponse(fees, rav_result) => {
                         state.finalize_rav_request(allocation_id, (fees, rav_result));
@@ -1088,6 +1540,7 @@ impl Actor for SenderAccount {
                     state.add_to_denylist().await;
                 }
 
+                state.waiting_for_budget = false;
                 let has_available_slots_for_requests = state.adaptive_limiter.has_limit();
                 if has_available_slots_for_requests {
                     let total_fee_outside_buffer = state.sender_fee_tracker.get_ravable_total_fee();
@@ -1098,12 +1551,13 @@ impl Actor for SenderAccount {
                     let counter_greater_receipt_limit = total_counter_for_allocation
                         >= state.config.rav_request_receipt_limit
                         && can_trigger_rav;
+                    let trigger_value = state.trigger_value();
                     let rav_result = if !state.backoff_info.in_backoff()
-                        && total_fee_outside_buffer >= state.config.trigger_value
+                        && total_fee_outside_buffer >= trigger_value
                     {
                         tracing::debug!(
                             total_fee_outside_buffer,
-                            trigger_value = state.config.trigger_value,
+                            trigger_value,
                             "Total fee greater than the trigger value. Triggering RAV request"
                         );
                         state.rav_request_for_heaviest_allocation().await
@@ -1145,11 +1599,29 @@ impl Actor for SenderAccount {
                     }
                     _ => {}
                 }
+
+                if state.waiting_for_budget && state.scheduled_rav_request.is_none() {
+                    state.scheduled_rav_request =
+                        Some(myself.send_after(RAV_REQUEST_BUDGET_RETRY, move || {
+                            SenderAccountMessage::UpdateReceiptFees(
+                                allocation_id,
+                                ReceiptFees::Retry,
+                            )
+                        }));
+                }
             }
             SenderAccountMessage::UpdateAllocationIds(allocation_ids) => {
-                // Create new sender allocations
+                // Create new sender allocations, with an idle timeout
+                // they are only created once they receive a receipt
                 let mut new_allocation_ids = state.allocation_ids.clone();
-                for allocation_id in allocation_ids.difference(&state.allocation_ids) {
+                let allocations_to_create = match state.config.idle_timeout {
+                    Some(_) => HashSet::new(),
+                    None => allocation_ids
+                        .difference(&state.allocation_ids)
+                        .copied()
+                        .collect(),
+                };
+                for allocation_id in &allocations_to_create {
                     if let Err(error) = state
                         .create_sender_allocation(myself.clone(), *allocation_id)
                         .await
@@ -1164,45 +1636,65 @@ impl Actor for SenderAccount {
                     }
                 }
 
+                // an allocation that is back in the network subgraph
+                // is not closed anymore
+                state.closing_allocation_ids.retain(|allocation_id, scheduled| {
+                    let reopened = allocation_ids.contains(allocation_id);
+                    if reopened {
+                        tracing::info!(%allocation_id, "Allocation is open again, not closing it");
+                        scheduled.abort();
+                    }
+                    !reopened
+                });
+
                 let possibly_closed_allocations = state
                     .allocation_ids
-                    .difference(&allocation_ids)
+                    .union(&state.evicted_allocation_ids)
+                    .filter(|allocation_id| {
+                        !allocation_ids.contains(allocation_id)
+                            && !state.closing_allocation_ids.contains_key(allocation_id)
+                    })
+                    .copied()
                     .collect::<HashSet<_>>();
 
                 let really_closed = state
-                    .check_closed_allocations(possibly_closed_allocations.clone())
+                    .check_closed_allocations(possibly_closed_allocations.iter().collect())
                     .await
                     .inspect_err(|err| tracing::error!(error = %err, "There was an error while querying the subgraph for closed allocations"))
                     .unwrap_or_default();
 
-                // Remove sender allocations
-                for allocation_id in possibly_closed_allocations {
-                    if really_closed.contains(&allocation_id.address()) {
-                        if let Some(sender_handle) = ActorRef::<SenderAllocationMessage>::where_is(
-                            state.format_sender_allocation(&allocation_id.address()),
-                        ) {
-                            tracing::trace!(%allocation_id, "SenderAccount shutting down SenderAllocation");
-                            // we can not send a rav request to this allocation
-                            // because it's gonna trigger the last rav
-                            state
-                                .sender_fee_tracker
-                                .block_allocation_id(allocation_id.address());
-                            sender_handle.stop(None);
-                            new_allocation_ids.remove(allocation_id);
-                        }
-                    } else {
-                        tracing::warn!(%allocation_id, "Missing allocation was not closed yet");
-                    }
-                }
-
                 tracing::trace!(
                     old_ids= ?state.allocation_ids,
                     new_ids = ?new_allocation_ids,
                     "Updating allocation ids"
                 );
                 state.allocation_ids = new_allocation_ids;
+
+                // Remove sender allocations, once the receipts sent until
+                // the end of the grace period are received
+                let grace_period = state.config.allocation_close_grace_period;
+                for allocation_id in possibly_closed_allocations {
+                    if !really_closed.contains(&allocation_id.address()) {
+                        tracing::warn!(%allocation_id, "Missing allocation was not closed yet");
+                    } else if grace_period.is_zero() {
+                        state.close_allocation(myself.clone(), allocation_id).await;
+                    } else {
+                        tracing::debug!(
+                            %allocation_id,
+                            ?grace_period,
+                            "Allocation closed, requesting its last rav after the grace period"
+                        );
+                        let scheduled = myself.send_after(grace_period, move || {
+                            SenderAccountMessage::CloseAllocation(allocation_id)
+                        });
+                        state
+                            .closing_allocation_ids
+                            .insert(allocation_id, scheduled);
+                    }
+                }
             }
             SenderAccountMessage::NewAllocationId(allocation_id) => {
+                state.evicted_allocation_ids.remove(&allocation_id);
                 if let Err(error) = state
                     .create_sender_allocation(myself.clone(), allocation_id)
                     .await
@@ -1215,6 +1707,16 @@ impl Actor for SenderAccount {
                 }
                 state.allocation_ids.insert(allocation_id);
             }
+            SenderAccountMessage::CloseAllocation(allocation_id) => {
+                // cancelled if the allocation was opened again
+                if state
+                    .closing_allocation_ids
+                    .remove(&allocation_id)
+                    .is_some()
+                {
+                    state.close_allocation(myself.clone(), allocation_id).await;
+                }
+            }
             SenderAccountMessage::UpdateBalanceAndLastRavs(new_balance, non_final_last_ravs) => {
                 state.sender_balance = new_balance;
                 ESCROW_BALANCE
@@ -1239,16 +1741,12 @@ impl Actor for SenderAccount {
                     // if it's being tracked and we didn't receive any update from the non_final_last_ravs
                     // remove from the tracker
                     state.rav_tracker.remove(*allocation_id);
-
-                    let _ = PENDING_RAV.remove_label_values(&[
-                        &state.sender.to_string(),
-                        &allocation_id.to_string(),
-                    ]);
                 }
 
                 for (allocation_id, value) in non_final_last_ravs {
                     state.update_rav(allocation_id, value);
                 }
+                state.publish_fees();
                 // now that balance and rav tracker is updated, check
                 match (state.denied, state.deny_condition_reached()) {
                     (true, false) => state.remove_from_denylist().await,
@@ -1256,13 +1754,61 @@ impl Actor for SenderAccount {
                     (_, _) => {}
                 }
             }
+            SenderAccountMessage::ExportTrackers => state.export_trackers(),
+            SenderAccountMessage::EvictIdleActors => {
+                let Some(idle_timeout) = state.config.idle_timeout else {
+                    return Ok(());
+                };
+                let now = Instant::now();
+                for allocation_id in &state.allocation_ids {
+                    let allocation_id = allocation_id.address();
+                    let last_receipt = *state
+                        .allocation_last_receipt
+                        .entry(allocation_id)
+                        .or_insert(now);
+                    let has_unaggregated_fees = state
+                        .sender_fee_tracker
+                        .get_total_fee_for_allocation(&allocation_id)
+                        .is_some_and(|fees| fees.value > 0);
+                    if now.duration_since(last_receipt) < idle_timeout || has_unaggregated_fees {
+                        continue;
+                    }
+                    // the allocation checks again that it has nothing
+                    // to aggregate before stopping
+                    if let Some(allocation) = ActorRef::<SenderAllocationMessage>::where_is(
+                        state.format_sender_allocation(&allocation_id),
+                    ) {
+                        tracing::debug!(%allocation_id, "Evicting idle SenderAllocation");
+                        let _ = allocation.cast(SenderAllocationMessage::Evict);
+                    }
+                }
+
+                // the evicted allocations are left to the manager, which
+                // spawns the sender again once one of them is closed, but
+                // a denied sender must be kept until it is allowed again
+                let nothing_tracked = state.allocation_ids.is_empty()
+                    && state.closing_allocation_ids.is_empty()
+                    && !state.denied
+                    && state.sender_fee_tracker.get_total_fee() == 0
+                    && state.invalid_receipts_tracker.get_total_fee() == 0;
+                if nothing_tracked && now.duration_since(state.last_receipt) >= idle_timeout {
+                    tracing::debug!(sender = %state.sender, "Evicting idle SenderAccount");
+                    myself.stop(Some(EVICTED_REASON.to_string()));
+                }
+            }
+            SenderAccountMessage::ForceRavRequest(allocation_id, reply) => {
+                let requested = state.force_rav_request(allocation_id).await;
+                if !reply.is_closed() {
+                    let _ = reply.send(requested);
+                }
+            }
             #[cfg(test)]
             SenderAccountMessage::GetSenderFeeTracker(reply) => {
                 if !reply.is_closed() {
                     let _ = reply.send(state.sender_fee_tracker.clone());
                 }
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "test"))]
             SenderAccountMessage::GetDeny(reply) => {
                 if !reply.is_closed() {
                     let _ = reply.send(state.denied);
@@ -1293,7 +1839,7 @@ impl Actor for SenderAccount {
         );
 
         match message {
-            SupervisionEvent::ActorTerminated(cell, _, _) => {
+            SupervisionEvent::ActorTerminated(cell, _, reason) => {
                 // what to do in case of termination or panic?
                 let sender_allocation = cell.get_name();
                 tracing::warn!(?sender_allocation, "Actor SenderAllocation was terminated");
@@ -1311,6 +1857,25 @@ impl Actor for SenderAccount {
                     return Ok(());
                 };
 
+                state.allocation_last_receipt.remove(&allocation_id);
+                // an evicted allocation may already be running again
+                // if it received a receipt in the meantime
+                let respawned = ActorRef::<SenderAllocationMessage>::where_is(
+                    state.format_sender_allocation(&allocation_id),
+                )
+                .is_some();
+                if reason.as_deref() == Some(EVICTED_REASON) && !respawned {
+                    if let Some(evicted) = state
+                        .allocation_ids
+                        .iter()
+                        .find(|id| id.address() == allocation_id)
+                        .copied()
+                    {
+                        state.allocation_ids.remove(&evicted);
+                        state.evicted_allocation_ids.insert(evicted);
+                    }
+                }
+
                 // remove from sender_fee_tracker
                 state.sender_fee_tracker.remove(allocation_id);
 
@@ -1318,9 +1883,6 @@ impl Actor for SenderAccount {
                     .with_label_values(&[&state.sender.to_string()])
                     .set(state.sender_fee_tracker.get_total_fee() as f64);
 
-                let _ = UNAGGREGATED_FEES
-                    .remove_label_values(&[&state.sender.to_string(), &allocation_id.to_string()]);
-
                 // check for deny conditions
                 let _ = myself.cast(SenderAccountMessage::UpdateReceiptFees(
                     allocation_id,
@@ -1372,6 +1934,24 @@ impl Actor for SenderAccount {
         }
         Ok(())
     }
+
+    async fn post_stop(
+        &self,
+        _myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        for watcher in &state.watchers {
+            watcher.abort();
+        }
+        for scheduled in state.closing_allocation_ids.values() {
+            scheduled.abort();
+        }
+        state.unaggregated_fees_exporter.clear();
+        state.rav_exporter.clear();
+        state.invalid_receipts_exporter.clear();
+        sender_stats::forget(state.sender);
+        Ok(())
+    }
 }
 
 impl SenderAccount {
@@ -1574,6 +2154,75 @@ pub mod tests {
         assert!(actor_ref.is_none());
     }
 
+    #[rstest::rstest]
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_close_allocation_after_grace_period(
+        #[ignore] pgpool: PgPool,
+        #[future(awt)] mock_escrow_subgraph: MockServer,
+    ) {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("ClosedAllocations"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                            "meta": {
+                                "block": {
+                                    "number": 1,
+                                    "hash": "hash",
+                                    "timestamp": 1
+                                }
+                            },
+                            "allocations": [
+                                {"id": ALLOCATION_ID_0 }
+                            ]
+                        }
+                    }))),
+            )
+            .await;
+
+        let (sender_account, mut msg_receiver, prefix, _) = create_sender_account()
+            .pgpool(pgpool)
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph.uri())
+            .network_subgraph_endpoint(&mock_server.uri())
+            .allocation_close_grace_period(Duration::from_millis(500))
+            .call()
+            .await;
+
+        let allocation_id = AllocationId::Legacy(ALLOCATION_ID_0);
+        sender_account
+            .cast(SenderAccountMessage::UpdateAllocationIds(HashSet::from([
+                allocation_id,
+            ])))
+            .unwrap();
+        flush_messages(&mut msg_receiver).await;
+        let sender_allocation_id = format!("{}:{}:{}", prefix, SENDER.1, ALLOCATION_ID_0);
+
+        // the allocation keeps receiving receipts during the grace period
+        sender_account
+            .cast(SenderAccountMessage::UpdateAllocationIds(HashSet::new()))
+            .unwrap();
+        // the fees of the allocation may be updated in the meantime
+        while msg_receiver.recv().await.expect("Channel failed")
+            != SenderAccountMessage::UpdateAllocationIds(HashSet::new())
+        {}
+        assert!(
+            ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_id.clone()).is_some()
+        );
+
+        while msg_receiver.recv().await.expect("Channel failed")
+            != SenderAccountMessage::CloseAllocation(allocation_id)
+        {}
+        for _ in 0..100 {
+            if ActorRef::<SenderAllocationMessage>::where_is(sender_allocation_id.clone()).is_none()
+            {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("The SenderAllocation was not stopped");
+    }
+
     #[rstest::rstest]
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_new_allocation_id(
@@ -1801,6 +2450,46 @@ pub mod tests {
         assert_triggered!(&triggered_rav_request);
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_force_rav_request(pgpool: PgPool) {
+        let (sender_account, mut msg_receiver, prefix, _) =
+            create_sender_account().pgpool(pgpool).call().await;
+
+        // create a fake sender allocation
+        let (triggered_rav_request, _, _) = create_mock_sender_allocation(
+            prefix,
+            SENDER.1,
+            ALLOCATION_ID_0,
+            sender_account.clone(),
+        )
+        .await;
+
+        sender_account
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                ALLOCATION_ID_0,
+                ReceiptFees::NewReceipt(TRIGGER_VALUE - 1, get_current_timestamp_u64_ns()),
+            ))
+            .unwrap();
+        flush_messages(&mut msg_receiver).await;
+
+        // the receipt is still within the buffer
+        let requested = call!(
+            sender_account,
+            SenderAccountMessage::ForceRavRequest,
+            Some(ALLOCATION_ID_0)
+        )
+        .unwrap();
+        assert!(requested.is_empty());
+        assert_not_triggered!(&triggered_rav_request);
+
+        // wait for it to be outside buffer
+        tokio::time::sleep(BUFFER_DURATION).await;
+
+        let requested = call!(sender_account, SenderAccountMessage::ForceRavRequest, None).unwrap();
+        assert_eq!(requested, vec![ALLOCATION_ID_0]);
+        assert_triggered!(&triggered_rav_request);
+    }
+
     #[rstest::rstest]
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_remove_sender_account(
@@ -2171,6 +2860,31 @@ pub mod tests {
         let deny = call!(sender_account, SenderAccountMessage::GetDeny).unwrap();
         assert!(!deny);
 
+        // the unaggregated fees of a trusted sender are not capped
+        sender_account
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                ALLOCATION_ID_0,
+                ReceiptFees::UpdateValue(UnaggregatedReceipts {
+                    value: max_amount_willing_to_lose_grt,
+                    last_id: 11,
+                    counter: 0,
+                }),
+            ))
+            .unwrap();
+        flush_messages(&mut msg_receiver).await;
+        let deny = get_deny_status(&sender_account).await;
+        assert!(
+            !deny,
+            "it shouldn't deny a trusted sender over max willing to lose below escrow balance"
+        );
+        sender_account
+            .cast(SenderAccountMessage::UpdateReceiptFees(
+                ALLOCATION_ID_0,
+                ReceiptFees::UpdateValue(UnaggregatedReceipts::default()),
+            ))
+            .unwrap();
+        flush_messages(&mut msg_receiver).await;
+
         update_receipt_fees!(ESCROW_VALUE - 1);
         let deny = get_deny_status(&sender_account).await;
         assert!(!deny, "it shouldn't deny a sender below escrow balance");
@@ -2213,7 +2927,7 @@ pub mod tests {
                     .and(body_string_contains("transactions"))
                     .respond_with(ResponseTemplate::new(200).set_body_json(
                         json!({ "data": { "transactions": [
-                            {"allocationID": ALLOCATION_ID_0 }
+                            {"id": "0x01", "allocationID": ALLOCATION_ID_0 }
                         ]}}),
                     )),
             )
@@ -2261,8 +2975,8 @@ pub mod tests {
                     .and(body_string_contains("transactions"))
                     .respond_with(ResponseTemplate::new(200).set_body_json(
                         json!({ "data": { "transactions": [
-                            {"allocationID": ALLOCATION_ID_0 },
-                            {"allocationID": ALLOCATION_ID_1 }
+                            {"id": "0x01", "allocationID": ALLOCATION_ID_0 },
+                            {"id": "0x02", "allocationID": ALLOCATION_ID_1 }
                         ]}}),
                     )),
             )
@@ -3,22 +3,25 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    fmt::Display,
     str::FromStr,
     time::Duration,
 };
 
 use anyhow::{anyhow, bail};
 use futures::{stream, StreamExt};
-use indexer_allocation::Allocation;
-use indexer_monitor::{EscrowAccounts, SubgraphClient};
-use indexer_watcher::{map_watcher, watch_pipe};
+use indexer_allocation::{Allocation, CollectionId};
+use indexer_config::ShardingConfig;
+use indexer_monitor::{allocation_ids, EscrowAccounts, SubgraphClient};
+use indexer_watcher::watch_pipe;
 use prometheus::{register_counter_vec, CounterVec};
-use ractor::{Actor, ActorCell, ActorProcessingErr, ActorRef, SupervisionEvent};
+use ractor::{call, Actor, ActorCell, ActorProcessingErr, ActorRef, ActorStatus, SupervisionEvent};
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgListener, PgPool};
-use thegraph_core::alloy::{primitives::Address, sol_types::Eip712Domain};
+use thegraph_core::alloy::{
+    primitives::{keccak256, Address},
+    sol_types::Eip712Domain,
+};
 use tokio::{select, sync::watch::Receiver};
 
 use super::sender_account::{
@@ -30,7 +33,7 @@ lazy_static! {
     static ref RECEIPTS_CREATED: CounterVec = register_counter_vec!(
         "tap_receipts_received_total",
         "Receipts received since start of the program.",
-        &["sender", "allocation"]
+        &["sender"]
     )
     .unwrap();
 }
@@ -56,33 +59,19 @@ pub struct NewReceiptNotification {
 #[derive(Debug, Clone)]
 pub struct SenderAccountsManager;
 
-/// Wrapped AllocationId Address with two possible variants
+/// Whether `sender` hashes into the shard of `sharding`
 ///
-/// This is used by children actors to define what kind of
-/// SenderAllocation must be created to handle the correct
-/// Rav and Receipt types
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-pub enum AllocationId {
-    /// Legacy allocation
-    Legacy(Address),
-    /// New Subgraph DataService allocation
-    Horizon(Address),
-}
-
-impl AllocationId {
-    /// Take the inner address for both allocation types
-    pub fn address(&self) -> Address {
-        match self {
-            AllocationId::Legacy(address) | AllocationId::Horizon(address) => *address,
-        }
-    }
+/// The hash of the address is used, so that every instance and restart
+/// agrees on the shard of each sender.
+pub fn sender_in_shard(sender: &Address, sharding: &ShardingConfig) -> bool {
+    let hash = keccak256(sender);
+    let bucket = u64::from_be_bytes(hash[..8].try_into().expect("hash is 32 bytes"));
+    bucket % u64::from(sharding.shard_count) == u64::from(sharding.shard_index)
 }
 
-impl Display for AllocationId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.address().fmt(f)
-    }
-}
+/// Used by children actors to define what kind of SenderAllocation must be
+/// created to handle the correct Rav and Receipt types
+pub use indexer_allocation::AllocationId;
 
 /// Type used in [SenderAccountsManager] and [SenderAccount] to route the correct escrow queries
 /// and to use the correct set of tables
@@ -94,9 +83,24 @@ pub enum SenderType {
     Horizon,
 }
 
+/// Status of the actor tree and of the receipt notification listeners,
+/// returned by [SenderAccountsManagerMessage::GetHealth]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ManagerHealth {
+    /// Number of running [SenderAccount]s
+    pub sender_accounts: usize,
+    /// Number of running [crate::agent::sender_allocation::SenderAllocation]s
+    pub sender_allocations: usize,
+    /// `true` while the v1 receipt notifications are received
+    pub receipt_listener_v1: bool,
+    /// `true` while the v2 receipt notifications are received
+    pub receipt_listener_v2: bool,
+}
+
 /// Enum containing all types of messages that a [SenderAccountsManager] can receive
 #[derive(Debug)]
-#[cfg_attr(any(test, feature = "test"), derive(Clone))]
+#[cfg_attr(any(test, feature = "test"), derive(educe::Educe))]
+#[cfg_attr(any(test, feature = "test"), educe(Clone))]
 pub enum SenderAccountsManagerMessage {
     /// Spawn and Stop [SenderAccount]s that were added or removed
     /// in comparison with it current state and updates the state
@@ -109,6 +113,42 @@ pub enum SenderAccountsManagerMessage {
     ///
     /// This tracks only v2 accounts
     UpdateSenderAccountsV2(HashSet<Address>),
+
+    /// Spawns the [SenderAccount] of a sender that received a receipt
+    /// for the given allocation, if it's not running
+    ///
+    /// Used when [SenderAccountConfig::idle_timeout] is set, since
+    /// senders are then only spawned once they are needed
+    NewSenderAccount(Address, AllocationId),
+
+    /// Spawns again the [SenderAccount]s evicted while idle that have
+    /// receipts or a non final RAV for an allocation that is not in the
+    /// given open allocations anymore, so their last RAV is requested
+    ///
+    /// Used when [SenderAccountConfig::idle_timeout] is set
+    CloseEvictedAllocations(HashSet<AllocationId>),
+
+    /// Returns the status of the actor tree, used by the health endpoint
+    GetHealth(
+        #[cfg_attr(
+            any(test, feature = "test"),
+            educe(Clone(method(crate::test::actors::clone_rpc_reply)))
+        )]
+        ractor::RpcReplyPort<ManagerHealth>,
+    ),
+
+    /// Requests a RAV for an allocation of a sender, or for all of them,
+    /// whatever the trigger value. Replies with the allocations a RAV was
+    /// requested for, or `None` if the sender is not managed by this agent
+    ForceRavRequest(
+        Address,
+        Option<Address>,
+        #[cfg_attr(
+            any(test, feature = "test"),
+            educe(Clone(method(crate::test::actors::clone_rpc_reply)))
+        )]
+        ractor::RpcReplyPort<Option<Vec<Address>>>,
+    ),
 }
 
 /// Arguments received in startup while spawing [SenderAccount] actor
@@ -120,8 +160,14 @@ pub struct SenderAccountsManagerArgs {
 
     /// Database connection
     pub pgpool: PgPool,
+    /// Connection used for the read-only fee calculations, the read replica
+    /// if one is configured or [Self::pgpool] otherwise
+    pub replica_pgpool: PgPool,
     /// Watcher that returns a map of open and recently closed allocation ids
     pub indexer_allocations: Receiver<HashMap<Address, Allocation>>,
+    /// Watcher of the allocations of the other indexers served by the same
+    /// indexer-service, their receipts are left to their own tap-agent
+    pub other_indexers_allocations: Receiver<HashSet<Address>>,
     /// Watcher containing the escrow accounts for v1
     pub escrow_accounts_v1: Receiver<EscrowAccounts>,
     /// Watcher containing the escrow accounts for v2
@@ -150,7 +196,9 @@ pub struct State {
     config: &'static SenderAccountConfig,
     domain_separator: Eip712Domain,
     pgpool: PgPool,
+    replica_pgpool: PgPool,
     indexer_allocations: Receiver<HashSet<AllocationId>>,
+    other_indexers_allocations: Receiver<HashSet<Address>>,
     /// Watcher containing the escrow accounts for v1
     escrow_accounts_v1: Receiver<EscrowAccounts>,
     /// Watcher containing the escrow accounts for v2
@@ -177,7 +225,9 @@ impl Actor for SenderAccountsManager {
             config,
             domain_separator,
             indexer_allocations,
+            other_indexers_allocations,
             pgpool,
+            replica_pgpool,
             escrow_accounts_v1,
             escrow_accounts_v2,
             escrow_subgraph,
@@ -186,14 +236,7 @@ impl Actor for SenderAccountsManager {
             prefix,
         }: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        let indexer_allocations = map_watcher(indexer_allocations, move |allocation_id| {
-            allocation_id
-                .keys()
-                .cloned()
-                // TODO map based on the allocation type returned by the subgraph
-                .map(AllocationId::Legacy)
-                .collect::<HashSet<_>>()
-        });
+        let indexer_allocations = allocation_ids(indexer_allocations);
         // we need two connections because each one will listen to different notify events
         let pglistener_v1 = PgListener::connect_with(&pgpool.clone()).await.unwrap();
         let pglistener_v2 = PgListener::connect_with(&pgpool.clone()).await.unwrap();
@@ -225,6 +268,20 @@ impl Actor for SenderAccountsManager {
             async {}
         });
 
+        if config.idle_timeout.is_some() {
+            let myself_clone = myself.clone();
+            watch_pipe(indexer_allocations.clone(), move |allocation_ids| {
+                myself_clone
+                    .cast(SenderAccountsManagerMessage::CloseEvictedAllocations(
+                        allocation_ids.clone(),
+                    ))
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Error while closing evicted allocations: {:?}", e);
+                    });
+                async {}
+            });
+        }
+
         let mut state = State {
             config,
             domain_separator,
@@ -233,7 +290,9 @@ impl Actor for SenderAccountsManager {
             new_receipts_watcher_handle_v1: None,
             new_receipts_watcher_handle_v2: None,
             pgpool: pgpool.clone(),
+            replica_pgpool,
             indexer_allocations,
+            other_indexers_allocations: other_indexers_allocations.clone(),
             escrow_accounts_v1: escrow_accounts_v1.clone(),
             escrow_accounts_v2: escrow_accounts_v2.clone(),
             escrow_subgraph,
@@ -291,6 +350,8 @@ impl Actor for SenderAccountsManager {
                 .actor_cell(myself.get_cell())
                 .pglistener(pglistener_v1)
                 .escrow_accounts_rx(escrow_accounts_v1)
+                .other_indexers_allocations(other_indexers_allocations.clone())
+                .maybe_sharding(config.sharding)
                 .maybe_prefix(prefix.clone())
                 .call(),
         ));
@@ -302,6 +363,8 @@ impl Actor for SenderAccountsManager {
                 .actor_cell(myself.get_cell())
                 .pglistener(pglistener_v2)
                 .escrow_accounts_rx(escrow_accounts_v2)
+                .other_indexers_allocations(other_indexers_allocations)
+                .maybe_sharding(config.sharding)
                 .sender_type(SenderType::Horizon)
                 .maybe_prefix(prefix)
                 .call(),
@@ -340,9 +403,18 @@ impl Actor for SenderAccountsManager {
         );
 
         match msg {
-            SenderAccountsManagerMessage::UpdateSenderAccountsV1(target_senders) => {
-                // Create new sender accounts
+            SenderAccountsManagerMessage::UpdateSenderAccountsV1(mut target_senders) => {
+                target_senders.retain(|sender| state.config.manages_sender(sender));
+                // Create new sender accounts, with an idle timeout only the
+                // denied ones are needed right away to be allowed again
+                let denied_senders = state.lazy_spawn_filter(SenderType::Legacy).await;
                 for sender in target_senders.difference(&state.sender_ids_v1) {
+                    if denied_senders
+                        .as_ref()
+                        .is_some_and(|denied| !denied.contains(sender))
+                    {
+                        continue;
+                    }
                     state
                         .create_or_deny_sender(
                             myself.get_cell(),
@@ -365,9 +437,18 @@ impl Actor for SenderAccountsManager {
                 state.sender_ids_v1 = target_senders;
             }
 
-            SenderAccountsManagerMessage::UpdateSenderAccountsV2(target_senders) => {
-                // Create new sender accounts
+            SenderAccountsManagerMessage::UpdateSenderAccountsV2(mut target_senders) => {
+                target_senders.retain(|sender| state.config.manages_sender(sender));
+                // Create new sender accounts, with an idle timeout only the
+                // denied ones are needed right away to be allowed again
+                let denied_senders = state.lazy_spawn_filter(SenderType::Horizon).await;
                 for sender in target_senders.difference(&state.sender_ids_v2) {
+                    if denied_senders
+                        .as_ref()
+                        .is_some_and(|denied| !denied.contains(sender))
+                    {
+                        continue;
+                    }
                     state
                         .create_or_deny_sender(
                             myself.get_cell(),
@@ -389,6 +470,137 @@ impl Actor for SenderAccountsManager {
 
                 state.sender_ids_v2 = target_senders;
             }
+
+            SenderAccountsManagerMessage::NewSenderAccount(sender, allocation_id) => {
+                let sender_type = match allocation_id {
+                    AllocationId::Legacy(_) => SenderType::Legacy,
+                    AllocationId::Horizon(_) => SenderType::Horizon,
+                };
+                if let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(
+                    state.format_sender_account(&sender, sender_type),
+                ) {
+                    // spawned by a previous receipt in the meantime
+                    sender_account
+                        .cast(SenderAccountMessage::NewAllocationId(allocation_id))
+                        .unwrap_or_else(|e| {
+                            tracing::error!(
+                                %sender,
+                                "Error while sending new allocation id to sender_account: {:?}",
+                                e
+                            );
+                        });
+                } else if state.config.idle_timeout.is_some() {
+                    state
+                        .create_or_deny_sender(
+                            myself.get_cell(),
+                            sender,
+                            HashSet::from([allocation_id]),
+                            sender_type,
+                        )
+                        .await;
+                } else {
+                    tracing::error!(%sender, "No sender_account was found for address");
+                }
+            }
+
+            SenderAccountsManagerMessage::CloseEvictedAllocations(allocation_ids) => {
+                let pending = [
+                    (
+                        SenderType::Legacy,
+                        state.get_pending_sender_allocation_id_v1().await,
+                    ),
+                    (
+                        SenderType::Horizon,
+                        state.get_pending_sender_allocation_id_v2().await,
+                    ),
+                ];
+                for (sender_type, pending) in pending {
+                    for (sender, pending_allocation_ids) in pending {
+                        let closed = pending_allocation_ids
+                            .iter()
+                            .any(|allocation_id| !allocation_ids.contains(allocation_id));
+                        // a running sender closes its allocations itself
+                        if !closed
+                            || ActorRef::<SenderAccountMessage>::where_is(
+                                state.format_sender_account(&sender, sender_type),
+                            )
+                            .is_some()
+                        {
+                            continue;
+                        }
+                        tracing::debug!(
+                            %sender,
+                            "Spawning evicted SenderAccount to close its allocations"
+                        );
+                        state
+                            .create_or_deny_sender(
+                                myself.get_cell(),
+                                sender,
+                                pending_allocation_ids,
+                                sender_type,
+                            )
+                            .await;
+                    }
+                }
+            }
+
+            SenderAccountsManagerMessage::GetHealth(reply) => {
+                let sender_accounts: Vec<_> = myself
+                    .get_children()
+                    .into_iter()
+                    .filter(|cell| cell.get_status() == ActorStatus::Running)
+                    .collect();
+                let sender_allocations = sender_accounts
+                    .iter()
+                    .flat_map(|cell| cell.get_children())
+                    .filter(|cell| cell.get_status() == ActorStatus::Running)
+                    .count();
+                let is_listening = |handle: &Option<tokio::task::JoinHandle<()>>| {
+                    handle.as_ref().is_some_and(|handle| !handle.is_finished())
+                };
+                let health = ManagerHealth {
+                    sender_accounts: sender_accounts.len(),
+                    sender_allocations,
+                    receipt_listener_v1: is_listening(&state.new_receipts_watcher_handle_v1),
+                    receipt_listener_v2: is_listening(&state.new_receipts_watcher_handle_v2),
+                };
+                if !reply.is_closed() {
+                    let _ = reply.send(health);
+                }
+            }
+
+            SenderAccountsManagerMessage::ForceRavRequest(sender, allocation_id, reply) => {
+                let sender_accounts: Vec<_> = [SenderType::Legacy, SenderType::Horizon]
+                    .into_iter()
+                    .filter_map(|sender_type| {
+                        ActorRef::<SenderAccountMessage>::where_is(
+                            state.format_sender_account(&sender, sender_type),
+                        )
+                    })
+                    .collect();
+                // answered by the sender accounts, the manager keeps handling
+                // the receipts meanwhile
+                tokio::spawn(async move {
+                    let mut requested = None;
+                    for sender_account in sender_accounts {
+                        match call!(
+                            sender_account,
+                            SenderAccountMessage::ForceRavRequest,
+                            allocation_id
+                        ) {
+                            Ok(allocation_ids) => requested
+                                .get_or_insert_with(Vec::new)
+                                .extend(allocation_ids),
+                            Err(error) => {
+                                tracing::warn!(%error, %sender, "Failed to force a RAV request")
+                            }
+                        }
+                    }
+                    if !reply.is_closed() {
+                        let _ = reply.send(requested);
+                    }
+                });
+            }
         }
         Ok(())
     }
@@ -537,6 +749,40 @@ impl State {
         Ok(())
     }
 
+    /// Senders that must be spawned as soon as they show up in the escrow
+    /// accounts, `None` if all of them must be
+    ///
+    /// With an idle timeout, only denied senders are spawned right away,
+    /// since their [SenderAccount] is needed to allow them again. The
+    /// others are spawned on their first receipt.
+    async fn lazy_spawn_filter(&self, sender_type: SenderType) -> Option<HashSet<Address>> {
+        if self.config.idle_timeout.is_none() {
+            return None;
+        }
+        let senders = match sender_type {
+            SenderType::Legacy => {
+                sqlx::query_scalar!("SELECT sender_address FROM scalar_tap_denylist")
+                    .fetch_all(&self.pgpool)
+                    .await
+            }
+            SenderType::Horizon => {
+                sqlx::query_scalar!("SELECT sender_address FROM tap_horizon_denylist")
+                    .fetch_all(&self.pgpool)
+                    .await
+            }
+        };
+        // spawn every sender if the denylist can't be read
+        let senders = senders
+            .inspect_err(|error| tracing::error!(%error, "Error while reading the denylist"))
+            .ok()?;
+        Some(
+            senders
+                .iter()
+                .filter_map(|sender| Address::from_str(sender.trim()).ok())
+                .collect(),
+        )
+    }
+
     /// Gather all outstanding receipts and unfinalized RAVs from the database.
     /// Used to create [SenderAccount] instances for all senders that have unfinalized allocations
     /// and try to finalize them if they have become ineligible.
@@ -583,7 +829,7 @@ impl State {
             let sender_id = self
                 .escrow_accounts_v1
                 .borrow()
-                .get_sender_for_signer(&signer_id)
+                .get_sender_for_any_signer(&signer_id)
                 .expect("should be able to get sender from signer");
 
             // Accumulate allocations for the sender
@@ -627,7 +873,7 @@ impl State {
                 .or_default()
                 .extend(allocation_ids);
         }
-        unfinalized_sender_allocations_map
+        self.retain_own_allocations(unfinalized_sender_allocations_map)
     }
 
     /// Gather all outstanding receipts and unfinalized RAVs from the database.
@@ -676,7 +922,7 @@ impl State {
             let sender_id = self
                 .escrow_accounts_v1
                 .borrow()
-                .get_sender_for_signer(&signer_id)
+                .get_sender_for_any_signer(&signer_id)
                 .expect("should be able to get sender from signer");
 
             // Accumulate allocations for the sender
@@ -720,7 +966,27 @@ impl State {
                 .or_default()
                 .extend(allocation_ids);
         }
-        unfinalized_sender_allocations_map
+        self.retain_own_allocations(unfinalized_sender_allocations_map)
+    }
+
+    /// Leaves out the allocations of the other indexers served by the same
+    /// indexer-service, the senders of the other shards and the senders left
+    /// without any allocation
+    fn retain_own_allocations(
+        &self,
+        mut sender_allocations: HashMap<Address, HashSet<AllocationId>>,
+    ) -> HashMap<Address, HashSet<AllocationId>> {
+        let other_indexers_allocations = self.other_indexers_allocations.borrow();
+        sender_allocations.retain(|sender, allocation_ids| {
+            if !self.config.manages_sender(sender) {
+                return false;
+            }
+            allocation_ids.retain(|allocation_id| {
+                !other_indexers_allocations.contains(&allocation_id.address())
+            });
+            !allocation_ids.is_empty()
+        });
+        sender_allocations
     }
 
     /// Helper function to create [SenderAccountArgs]
@@ -736,6 +1002,7 @@ impl State {
         Ok(SenderAccountArgs {
             config: self.config,
             pgpool: self.pgpool.clone(),
+            replica_pgpool: self.replica_pgpool.clone(),
             sender_id: *sender_id,
             escrow_accounts: match sender_type {
                 SenderType::Legacy => self.escrow_accounts_v1.clone(),
@@ -768,9 +1035,12 @@ async fn new_receipts_watcher(
     actor_cell: ActorCell,
     mut pglistener: PgListener,
     escrow_accounts_rx: Receiver<EscrowAccounts>,
+    other_indexers_allocations: Option<Receiver<HashSet<Address>>>,
+    sharding: Option<ShardingConfig>,
     sender_type: SenderType,
     prefix: Option<String>,
 ) {
+    let manager = ActorRef::<SenderAccountsManagerMessage>::from(actor_cell.clone());
     match sender_type {
         SenderType::Legacy => {
             pglistener
@@ -808,11 +1078,23 @@ async fn new_receipts_watcher(
             );
             break;
         };
+        if other_indexers_allocations
+            .as_ref()
+            .is_some_and(|allocations| {
+                allocations
+                    .borrow()
+                    .contains(&new_receipt_notification.allocation_id)
+            })
+        {
+            continue;
+        }
         if let Err(e) = handle_notification(
             new_receipt_notification,
             escrow_accounts_rx.clone(),
+            sharding.as_ref(),
             sender_type,
             prefix.as_deref(),
+            &manager,
         )
         .await
         {
@@ -832,16 +1114,22 @@ async fn new_receipts_watcher(
 ///
 /// If the allocation doesn't exist yet, we trust that the whoever has
 /// access to the database already verified that the allocation really
-/// exists and we ask for the sender to create a new allocation.
+/// exists and we ask for the sender to create a new allocation. If the
+/// sender isn't running either, the manager is asked to spawn it.
 ///
 /// After a request to create allocation, we don't need to do anything
 /// since the startup script is going to recalculate the receipt in the
 /// database
+///
+/// The receipts of the senders of the other shards are left to their own
+/// tap-agent, which is notified as well.
 async fn handle_notification(
     new_receipt_notification: NewReceiptNotification,
     escrow_accounts_rx: Receiver<EscrowAccounts>,
+    sharding: Option<&ShardingConfig>,
     sender_type: SenderType,
     prefix: Option<&str>,
+    manager: &ActorRef<SenderAccountsManagerMessage>,
 ) -> anyhow::Result<()> {
     tracing::trace!(
         notification = ?new_receipt_notification,
@@ -850,7 +1138,7 @@ async fn handle_notification(
 
     let Ok(sender_address) = escrow_accounts_rx
         .borrow()
-        .get_sender_for_signer(&new_receipt_notification.signer_address)
+        .get_sender_for_any_signer(&new_receipt_notification.signer_address)
     else {
         // TODO: save the receipt in the failed receipts table?
         bail!(
@@ -859,9 +1147,11 @@ async fn handle_notification(
             new_receipt_notification.signer_address
         );
     };
+    if sharding.is_some_and(|sharding| !sender_in_shard(&sender_address, sharding)) {
+        return Ok(());
+    }
 
     let allocation_id = &new_receipt_notification.allocation_id;
-    let allocation_str = &allocation_id.to_string();
 
     let actor_name = format!(
         "{}{sender_address}:{allocation_id}",
@@ -888,18 +1178,27 @@ async fn handle_notification(
             }
         );
 
+        let allocation_id = match sender_type {
+            SenderType::Legacy => AllocationId::Legacy(*allocation_id),
+            SenderType::Horizon => AllocationId::Horizon(CollectionId::from(*allocation_id)),
+        };
         let Some(sender_account) = ActorRef::<SenderAccountMessage>::where_is(sender_account_name)
         else {
-            bail!(
-                "No sender_account was found for address: {}.",
-                sender_address
-            );
+            manager
+                .cast(SenderAccountsManagerMessage::NewSenderAccount(
+                    sender_address,
+                    allocation_id,
+                ))
+                .map_err(|e| {
+                    anyhow!(
+                        "Error while sending new sender account message to the manager: {:?}",
+                        e
+                    )
+                })?;
+            return Ok(());
         };
         sender_account
-            .cast(SenderAccountMessage::NewAllocationId(match sender_type {
-                SenderType::Legacy => AllocationId::Legacy(*allocation_id),
-                SenderType::Horizon => AllocationId::Horizon(*allocation_id),
-            }))
+            .cast(SenderAccountMessage::NewAllocationId(allocation_id))
             .map_err(|e| {
                 anyhow!(
                     "Error while sendeing new allocation id message to sender_account: {:?}",
@@ -921,24 +1220,28 @@ async fn handle_notification(
         })?;
 
     RECEIPTS_CREATED
-        .with_label_values(&[&sender_address.to_string(), allocation_str])
+        .with_label_values(&[&sender_address.to_string()])
         .inc();
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::{HashMap, HashSet};
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
 
+    use indexer_config::ShardingConfig;
     use indexer_monitor::{DeploymentDetails, EscrowAccounts, SubgraphClient};
-    use ractor::{Actor, ActorRef, ActorStatus};
+    use ractor::{call, Actor, ActorRef, ActorStatus};
     use reqwest::Url;
     use ruint::aliases::U256;
     use sqlx::{postgres::PgListener, PgPool};
     use test_assets::{
         assert_while_retry, flush_messages, TAP_SENDER as SENDER, TAP_SIGNER as SIGNER,
     };
-    use thegraph_core::alloy::hex::ToHexExt;
+    use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
     use tokio::sync::{
         mpsc::{self, error::TryRecvError},
         watch,
@@ -949,8 +1252,10 @@ mod tests {
         agent::{
             sender_account::SenderAccountMessage,
             sender_accounts_manager::{
-                handle_notification, AllocationId, NewReceiptNotification, SenderType,
+                handle_notification, sender_in_shard, AllocationId, NewReceiptNotification,
+                SenderType,
             },
+            sender_allocation::SenderAllocationMessage,
         },
         test::{
             actors::{DummyActor, MockSenderAccount, MockSenderAllocation, TestableActor},
@@ -996,8 +1301,10 @@ mod tests {
                 sender_ids_v2: HashSet::new(),
                 new_receipts_watcher_handle_v1: None,
                 new_receipts_watcher_handle_v2: None,
-                pgpool,
+                pgpool: pgpool.clone(),
+                replica_pgpool: pgpool,
                 indexer_allocations: watch::channel(HashSet::new()).1,
+                other_indexers_allocations: watch::channel(HashSet::new()).1,
                 escrow_accounts_v1: watch::channel(escrow_accounts.clone()).1,
                 escrow_accounts_v2: watch::channel(escrow_accounts).1,
                 escrow_subgraph: get_subgraph_client().await,
@@ -1035,6 +1342,34 @@ mod tests {
         assert_eq!(pending_allocation_id.get(&SENDER.1).unwrap().len(), 2);
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_pending_allocations_of_other_indexers(pgpool: PgPool) {
+        let (_, mut state) = create_state(pgpool.clone()).await;
+        state.other_indexers_allocations = watch::channel(HashSet::from([ALLOCATION_ID_0])).1;
+
+        for i in 1..=10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        let signed_rav = create_rav(ALLOCATION_ID_1, SIGNER.0.clone(), 4, 10);
+        store_rav(&pgpool, signed_rav, SENDER.1).await.unwrap();
+
+        // only the allocation with a RAV is of this indexer
+        let pending_allocation_id = state.get_pending_sender_allocation_id_v1().await;
+        assert_eq!(
+            pending_allocation_id.get(&SENDER.1),
+            Some(&HashSet::from([AllocationId::Legacy(ALLOCATION_ID_1)]))
+        );
+
+        // a sender left without allocations is not returned
+        state.other_indexers_allocations =
+            watch::channel(HashSet::from([ALLOCATION_ID_0, ALLOCATION_ID_1])).1;
+        let pending_allocation_id = state.get_pending_sender_allocation_id_v1().await;
+        assert!(pending_allocation_id.is_empty());
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_update_sender_allocation(pgpool: PgPool) {
         let (prefix, mut notify, (actor, join_handle)) =
@@ -1083,6 +1418,110 @@ mod tests {
         join_handle.await.unwrap();
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_lazy_sender_account(pgpool: PgPool) {
+        let (prefix, mut notify, (actor, join_handle)) = create_sender_accounts_manager()
+            .pgpool(pgpool)
+            .idle_timeout(Duration::from_secs(60))
+            .call()
+            .await;
+        let sender_account_name = format!("{}:legacy:{}", prefix, SENDER.1);
+
+        actor
+            .cast(SenderAccountsManagerMessage::UpdateSenderAccountsV1(
+                HashSet::from([SENDER.1]),
+            ))
+            .unwrap();
+        flush_messages(&mut notify).await;
+
+        // not spawned until it receives a receipt
+        assert!(ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone()).is_none());
+
+        actor
+            .cast(SenderAccountsManagerMessage::NewSenderAccount(
+                SENDER.1,
+                AllocationId::Legacy(ALLOCATION_ID_0),
+            ))
+            .unwrap();
+        flush_messages(&mut notify).await;
+
+        assert_while_retry! {
+            ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone()).is_none()
+        };
+        assert!(ActorRef::<SenderAllocationMessage>::where_is(format!(
+            "{}:{}:{}",
+            prefix, SENDER.1, ALLOCATION_ID_0
+        ))
+        .is_some());
+
+        actor.stop_and_wait(None, None).await.unwrap();
+        join_handle.await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_evict_idle_sender_account(pgpool: PgPool) {
+        let (prefix, _notify, (actor, join_handle)) = create_sender_accounts_manager()
+            .pgpool(pgpool)
+            .idle_timeout(Duration::from_millis(100))
+            .call()
+            .await;
+        let sender_account_name = format!("{}:legacy:{}", prefix, SENDER.1);
+
+        actor
+            .cast(SenderAccountsManagerMessage::NewSenderAccount(
+                SENDER.1,
+                AllocationId::Legacy(ALLOCATION_ID_0),
+            ))
+            .unwrap();
+        assert_while_retry! {
+            ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone()).is_none()
+        };
+
+        // the allocation is evicted first, then the sender
+        // once it has nothing left to track
+        assert_while_retry! {
+            ActorRef::<SenderAccountMessage>::where_is(sender_account_name.clone()).is_some()
+        };
+        assert!(ActorRef::<SenderAllocationMessage>::where_is(format!(
+            "{}:{}:{}",
+            prefix, SENDER.1, ALLOCATION_ID_0
+        ))
+        .is_none());
+
+        actor.stop_and_wait(None, None).await.unwrap();
+        join_handle.await.unwrap();
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_manager_health(pgpool: PgPool) {
+        let (prefix, mut notify, (actor, join_handle)) =
+            create_sender_accounts_manager().pgpool(pgpool).call().await;
+
+        actor
+            .cast(SenderAccountsManagerMessage::UpdateSenderAccountsV1(
+                vec![SENDER.1].into_iter().collect(),
+            ))
+            .unwrap();
+        flush_messages(&mut notify).await;
+
+        assert_while_retry! {
+            ActorRef::<SenderAccountMessage>::where_is(format!(
+                "{}:legacy:{}",
+                prefix.clone(),
+                SENDER.1
+            )).is_none()
+        };
+
+        let health = call!(actor, SenderAccountsManagerMessage::GetHealth).unwrap();
+        assert_eq!(health.sender_accounts, 1);
+        assert_eq!(health.sender_allocations, 0);
+        assert!(health.receipt_listener_v1);
+        assert!(health.receipt_listener_v2);
+
+        actor.stop_and_wait(None, None).await.unwrap();
+        join_handle.await.unwrap();
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_create_sender_account(pgpool: PgPool) {
         let (prefix, state) = create_state(pgpool.clone()).await;
@@ -1236,6 +1675,25 @@ mod tests {
         assert_eq!(dummy_actor.get_status(), ActorStatus::Stopped)
     }
 
+    #[test]
+    fn test_sender_in_single_shard() {
+        for byte in 0..=u8::MAX {
+            let sender = Address::repeat_byte(byte);
+            let shards = (0..3)
+                .filter(|shard_index| {
+                    sender_in_shard(
+                        &sender,
+                        &ShardingConfig {
+                            shard_index: *shard_index,
+                            shard_count: 3,
+                        },
+                    )
+                })
+                .count();
+            assert_eq!(shards, 1);
+        }
+    }
+
     #[tokio::test]
     async fn test_create_allocation_id() {
         let senders_to_signers = vec![(SENDER.1, vec![SIGNER.1])].into_iter().collect();
@@ -1264,11 +1722,14 @@ mod tests {
             value: 1,
         };
 
+        let manager = DummyActor::spawn().await;
         handle_notification(
             new_receipt_notification,
             escrow_accounts,
+            None,
             SenderType::Legacy,
             Some(&prefix),
+            &ActorRef::from(manager.get_cell()),
         )
         .await
         .unwrap();
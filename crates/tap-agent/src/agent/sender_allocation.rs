@@ -4,13 +4,16 @@
 use std::{
     future::Future,
     marker::PhantomData,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, ensure};
 use bigdecimal::{num_bigint::BigInt, ToPrimitive};
-use indexer_monitor::{EscrowAccounts, SubgraphClient};
+use indexer_config::TapReceiptChecksConfig;
+use indexer_error::{ErrorCode, IndexerErrorCode};
+use indexer_monitor::{ContractSigners, EscrowAccounts, SubgraphClient};
+use indexer_schema::{record_allocation_event, AllocationEvent};
 use itertools::{Either, Itertools};
 use prometheus::{register_counter_vec, register_histogram_vec, CounterVec, HistogramVec};
 use ractor::{Actor, ActorProcessingErr, ActorRef};
@@ -29,6 +32,7 @@ use tap_core::{
 use thegraph_core::alloy::{hex::ToHexExt, primitives::Address, sol_types::Eip712Domain};
 use thiserror::Error;
 use tokio::sync::watch::Receiver;
+use tracing::Instrument;
 
 use super::sender_account::SenderAccountConfig;
 use crate::{
@@ -37,11 +41,15 @@ use crate::{
         sender_accounts_manager::NewReceiptNotification,
         unaggregated_receipts::UnaggregatedReceipts,
     },
+    clock, database,
+    events::{self, EventKind},
     lazy_static,
+    rav_budget::RavRequestBudget,
     tap::{
         context::{
             checks::{AllocationId, Signature},
-            Horizon, Legacy, NetworkVersion, TapAgentContext,
+            horizon_fees_after_rav, legacy_fees_after_rav, Horizon, Legacy, NetworkVersion,
+            RavFees, ReceiptSums, TapAgentContext,
         },
         signers_trimmed, TapReceipt,
     },
@@ -56,14 +64,14 @@ lazy_static! {
     .unwrap();
     static ref RAVS_CREATED: CounterVec = register_counter_vec!(
         "tap_ravs_created_total",
-        "RAVs updated or created per sender allocation since the start of the program",
-        &["sender", "allocation"]
+        "RAVs updated or created per sender since the start of the program",
+        &["sender"]
     )
     .unwrap();
     static ref RAVS_FAILED: CounterVec = register_counter_vec!(
         "tap_ravs_failed_total",
         "RAV requests failed since the start of the program",
-        &["sender", "allocation"]
+        &["sender"]
     )
     .unwrap();
     static ref RAV_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
@@ -99,13 +107,37 @@ pub enum RavError {
     #[error("All receipts are invalid")]
     AllReceiptsInvalid,
 
+    /// RAV received from the aggregator is invalid
+    #[error("Invalid RAV, sender could be malicious: {0:?}.")]
+    InvalidRav(tap_core::Error),
+
     /// Other kind of error
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl ErrorCode for RavError {
+    fn error_code(&self) -> IndexerErrorCode {
+        match self {
+            RavError::Sqlx(error) => error.error_code(),
+            RavError::TapCore(error) => error.error_code(),
+            RavError::AggregationError(_) | RavError::AllReceiptsInvalid => {
+                IndexerErrorCode::NoValidReceipts
+            }
+            RavError::Grpc(status) => status.error_code(),
+            RavError::InvalidRav(_) => IndexerErrorCode::InvalidRav,
+            RavError::Other(error) => error.error_code(),
+        }
+    }
+}
+
 type TapManager<T> = tap_core::manager::Manager<TapAgentContext<T>, TapReceipt>;
 
+/// Reason given when a [SenderAllocation] stops after being idle
+///
+/// Its last RAV is not requested, since the allocation is still open
+pub(crate) const EVICTED_REASON: &str = "evicted";
+
 /// Manages unaggregated fees and the TAP lifecyle for a specific (allocation, sender) pair.
 ///
 /// We use PhantomData to be able to add bounds to T while implementing the Actor trait
@@ -132,6 +164,9 @@ pub struct SenderAllocationState<T: NetworkVersion> {
     latest_rav: Option<Eip712SignedMessage<T::Rav>>,
     /// Database connection
     pgpool: PgPool,
+    /// Connection used for the fee calculations of the startup, the read
+    /// replica if one is configured, see [Self::startup_pgpool]
+    replica_pgpool: PgPool,
     /// Instance of TapManager for our [NetworkVersion] T
     tap_manager: TapManager<T>,
     /// Current allocation address
@@ -159,6 +194,23 @@ pub struct SenderAllocationState<T: NetworkVersion> {
     timestamp_buffer_ns: u64,
     /// Limit of receipts sent in a Rav Request
     rav_request_receipt_limit: u64,
+    /// Recalculate unaggregated fees inside a repeatable read transaction
+    ///
+    /// Obsolete receipts are removed and fees are summed using the RAV
+    /// stored in the database instead of [Self::latest_rav]
+    strict_fee_calculation: bool,
+    /// Fees recalculated by the context along with the last RAV stored,
+    /// set with [Self::strict_fee_calculation]
+    rav_fees: Option<Arc<Mutex<RavFees>>>,
+    /// Set when stopped after being idle, the last RAV is not requested
+    evicted: bool,
+    /// How often the fees are saved, see [database::FeeSnapshot]
+    fee_snapshot_interval: Option<Duration>,
+    /// Age after which the saved fees are summed up again
+    fee_snapshot_max_age: Duration,
+    /// Budget shared by the senders, the last RAV is granted ahead of the
+    /// routine requests
+    rav_request_budget: RavRequestBudget,
 }
 
 /// Configuration derived from config.toml
@@ -168,20 +220,40 @@ pub struct AllocationConfig {
     pub timestamp_buffer_ns: u64,
     /// Limit of receipts sent in a Rav Request
     pub rav_request_receipt_limit: u64,
+    /// Recalculate unaggregated fees inside a repeatable read transaction
+    pub strict_fee_calculation: bool,
     /// Current indexer address
     pub indexer_address: Address,
     /// Polling interval for escrow subgraph
     pub escrow_polling_interval: Duration,
+    /// How often the fees are saved, see [crate::database::FeeSnapshot]
+    pub fee_snapshot_interval: Option<Duration>,
+    /// Age after which the saved fees are summed up again
+    pub fee_snapshot_max_age: Duration,
+    /// Verifies the receipts signed for contract wallets, if enabled
+    pub contract_signers: Option<ContractSigners>,
+    /// RAV requests in flight shared by every sender
+    pub rav_request_budget: RavRequestBudget,
+    /// Checks run on the receipts before they are aggregated
+    pub receipt_checks: TapReceiptChecksConfig,
 }
 
 impl AllocationConfig {
     /// Creates a [SenderAccountConfig] by getting a reference of [super::sender_account::SenderAccountConfig]
-    pub fn from_sender_config(config: &SenderAccountConfig) -> Self {
+    ///
+    /// The timestamp buffer is the one configured for `sender`
+    pub fn from_sender_config(config: &SenderAccountConfig, sender: &Address) -> Self {
         Self {
-            timestamp_buffer_ns: config.rav_request_buffer.as_nanos() as u64,
+            timestamp_buffer_ns: config.rav_request_buffer_for(sender).as_nanos() as u64,
             rav_request_receipt_limit: config.rav_request_receipt_limit,
+            strict_fee_calculation: config.strict_fee_calculation,
             indexer_address: config.indexer_address,
             escrow_polling_interval: config.escrow_polling_interval,
+            fee_snapshot_interval: config.fee_snapshot_interval,
+            fee_snapshot_max_age: config.fee_snapshot_max_age,
+            contract_signers: config.contract_signers.clone(),
+            rav_request_budget: config.rav_request_budget.clone(),
+            receipt_checks: config.receipt_checks,
         }
     }
 }
@@ -191,6 +263,9 @@ impl AllocationConfig {
 pub struct SenderAllocationArgs<T: NetworkVersion> {
     /// Database connection
     pub pgpool: PgPool,
+    /// Connection used for the fee calculations of the startup, the read
+    /// replica if one is configured
+    pub replica_pgpool: PgPool,
     /// Current allocation address
     pub allocation_id: Address,
     /// Address of the sender responsible for this [SenderAllocation]
@@ -227,6 +302,15 @@ pub enum SenderAllocationMessage {
     ///
     /// It notifies its parent with the response
     TriggerRavRequest,
+    /// Stops the allocation if all its receipts are aggregated, sent by
+    /// [super::sender_account::SenderAccount] once it is idle
+    ///
+    /// Its RAV is kept in the database and loaded again if it
+    /// receives new receipts
+    Evict,
+    /// Saves the fees in the database, sent periodically if
+    /// [AllocationConfig::fee_snapshot_interval] is set
+    SnapshotFees,
     #[cfg(any(test, feature = "test"))]
     /// Return the internal state (used for tests)
     GetUnaggregatedReceipts(
@@ -257,15 +341,15 @@ where
     /// actor
     async fn pre_start(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         args: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         let sender_account_ref = args.sender_account_ref.clone();
         let allocation_id = args.allocation_id;
         let mut state = SenderAllocationState::new(args).await?;
 
-        // update invalid receipts
-        state.invalid_receipts_fees = state.calculate_invalid_receipts_fee().await?;
+        // update invalid receipts and unaggregated_fees
+        state.restore_fees().await?;
         if state.invalid_receipts_fees.value > 0 {
             sender_account_ref.cast(SenderAccountMessage::UpdateInvalidReceiptFees(
                 allocation_id,
@@ -273,9 +357,6 @@ where
             ))?;
         }
 
-        // update unaggregated_fees
-        state.unaggregated_fees = state.recalculate_all_unaggregated_fees().await?;
-
         sender_account_ref.cast(SenderAccountMessage::UpdateReceiptFees(
             allocation_id,
             ReceiptFees::UpdateValue(state.unaggregated_fees),
@@ -286,6 +367,10 @@ where
             sender_account_ref.cast(SenderAccountMessage::UpdateRav(rav.into()))?;
         }
 
+        if let Some(interval) = state.fee_snapshot_interval {
+            myself.send_interval(interval, || SenderAllocationMessage::SnapshotFees);
+        }
+
         tracing::info!(
             sender = %state.sender,
             allocation_id = %state.allocation_id,
@@ -305,11 +390,43 @@ where
         _myself: ActorRef<Self::Msg>,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
+        if state.evicted {
+            tracing::debug!(
+                sender = %state.sender,
+                allocation_id = %state.allocation_id,
+                "SenderAllocation evicted after being idle",
+            );
+            state.store_fee_snapshot().await;
+            return Ok(());
+        }
         tracing::info!(
             sender = %state.sender,
             allocation_id = %state.allocation_id,
             "Closing SenderAllocation, triggering last rav",
         );
+        // the receipts received from now on would not be part of the last RAV
+        if let Err(error) =
+            database::mark_allocation_closing(&state.pgpool, state.allocation_id).await
+        {
+            tracing::warn!(
+                %error,
+                "Failed to stop the service from accepting receipts for the closed allocation"
+            );
+        }
+        // the receipts accepted until the service was notified are only
+        // aggregated once they are older than the timestamp buffer
+        tokio::time::sleep(Duration::from_nanos(state.timestamp_buffer_ns)).await;
+        if let Err(error) = record_allocation_event(
+            &state.pgpool,
+            state.allocation_id,
+            AllocationEvent::FinalRavRequested,
+            Some(state.sender),
+            None,
+        )
+        .await
+        {
+            tracing::warn!(%error, "Failed to record the last RAV request of the allocation");
+        }
         loop {
             match state.recalculate_all_unaggregated_fees().await {
                 Ok(value) => {
@@ -325,6 +442,7 @@ where
             }
         }
         // Request a RAV and mark the allocation as final.
+        let _grant = state.rav_request_budget.grant_last_rav(state.sender);
         while state.unaggregated_fees.value > 0 {
             if let Err(err) = state.request_rav().await {
                 tracing::error!(error = %err, "There was an error while requesting rav. Retrying in 30 seconds...");
@@ -342,6 +460,17 @@ where
             tokio::time::sleep(Duration::from_secs(30)).await;
         }
 
+        if let Err(error) = database::delete_fee_snapshot(
+            &state.pgpool,
+            SenderAllocationState::<T>::FEE_SNAPSHOT_VERSION,
+            state.sender,
+            state.allocation_id,
+        )
+        .await
+        {
+            tracing::warn!(%error, "Failed to delete the fee snapshot of the closed allocation");
+        }
+
         // Since this is only triggered after allocation is closed will be counted here
         CLOSED_SENDER_ALLOCATIONS
             .with_label_values(&[&state.sender.to_string()])
@@ -353,7 +482,7 @@ where
     /// Handle a new [SenderAllocationMessage] message
     async fn handle(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
@@ -369,9 +498,10 @@ where
             SenderAllocationMessage::NewReceipt(notification) => {
                 let NewReceiptNotification {
                     id,
+                    allocation_id,
+                    signer_address,
                     value: fees,
                     timestamp_ns,
-                    ..
                 } = notification;
                 if id <= unaggregated_fees.last_id {
                     // our world assumption is wrong
@@ -397,6 +527,16 @@ where
                             u128::MAX
                         });
                 unaggregated_fees.counter += 1;
+                events::publish(
+                    state.sender,
+                    EventKind::ReceiptAccepted {
+                        allocation_id,
+                        signer: signer_address,
+                        receipt_id: id,
+                        timestamp_ns,
+                        value: fees.to_string(),
+                    },
+                );
                 // it's fine to crash the actor, could not send a message to its parent
                 state
                     .sender_account_ref
@@ -421,6 +561,16 @@ where
                         ),
                     ))?;
             }
+            SenderAllocationMessage::SnapshotFees => {
+                state.store_fee_snapshot().await;
+            }
+            SenderAllocationMessage::Evict => {
+                // receipts could have been received since the eviction was requested
+                if unaggregated_fees.value == 0 {
+                    state.evicted = true;
+                    myself.stop(Some(EVICTED_REASON.to_string()));
+                }
+            }
             #[cfg(any(test, feature = "test"))]
             SenderAllocationMessage::GetUnaggregatedReceipts(reply) => {
                 if !reply.is_closed() {
@@ -442,11 +592,44 @@ where
         RavRead<T::Rav> + RavStore<T::Rav> + ReceiptDelete + ReceiptRead<TapReceipt>,
     SenderAllocationState<T>: DatabaseInteractions,
 {
+    /// The receipt checks enabled in `config.receipt_checks`
+    async fn required_checks(
+        config: &AllocationConfig,
+        sender: Address,
+        allocation_id: Address,
+        escrow_subgraph: &'static SubgraphClient,
+        domain_separator: &Eip712Domain,
+        escrow_accounts: &Receiver<EscrowAccounts>,
+    ) -> Vec<Arc<dyn Check<TapReceipt> + Send + Sync>> {
+        let mut required_checks: Vec<Arc<dyn Check<TapReceipt> + Send + Sync>> = Vec::new();
+        if config.receipt_checks.allocation_id {
+            required_checks.push(Arc::new(
+                AllocationId::new(
+                    config.indexer_address,
+                    config.escrow_polling_interval,
+                    sender,
+                    allocation_id,
+                    escrow_subgraph,
+                )
+                .await,
+            ));
+        }
+        if config.receipt_checks.signature {
+            required_checks.push(Arc::new(Signature::new(
+                domain_separator.clone(),
+                escrow_accounts.clone(),
+                config.contract_signers.clone(),
+            )));
+        }
+        required_checks
+    }
+
     /// Helper function to create a [SenderAllocationState]
     /// given [SenderAllocationArgs]
     async fn new(
         SenderAllocationArgs {
             pgpool,
+            replica_pgpool,
             allocation_id,
             sender,
             escrow_accounts,
@@ -457,28 +640,25 @@ where
             config,
         }: SenderAllocationArgs<T>,
     ) -> anyhow::Result<Self> {
-        let required_checks: Vec<Arc<dyn Check<TapReceipt> + Send + Sync>> = vec![
-            Arc::new(
-                AllocationId::new(
-                    config.indexer_address,
-                    config.escrow_polling_interval,
-                    sender,
-                    allocation_id,
-                    escrow_subgraph,
-                )
-                .await,
-            ),
-            Arc::new(Signature::new(
-                domain_separator.clone(),
-                escrow_accounts.clone(),
-            )),
-        ];
+        let required_checks = Self::required_checks(
+            &config,
+            sender,
+            allocation_id,
+            escrow_subgraph,
+            &domain_separator,
+            &escrow_accounts,
+        )
+        .await;
+        let rav_fees = config
+            .strict_fee_calculation
+            .then(|| Arc::new(Mutex::new(RavFees::default())));
         let context = TapAgentContext::builder()
             .pgpool(pgpool.clone())
             .allocation_id(allocation_id)
             .indexer_address(config.indexer_address)
             .sender(sender)
             .escrow_accounts(escrow_accounts.clone())
+            .maybe_rav_fees(rav_fees.clone())
             .build();
 
         let latest_rav = context.last_rav().await.unwrap_or_default();
@@ -490,6 +670,7 @@ where
 
         Ok(Self {
             pgpool,
+            replica_pgpool,
             tap_manager,
             allocation_id,
             sender,
@@ -503,9 +684,95 @@ where
             sender_aggregator,
             rav_request_receipt_limit: config.rav_request_receipt_limit,
             timestamp_buffer_ns: config.timestamp_buffer_ns,
+            strict_fee_calculation: config.strict_fee_calculation,
+            rav_fees,
+            evicted: false,
+            fee_snapshot_interval: config.fee_snapshot_interval,
+            fee_snapshot_max_age: config.fee_snapshot_max_age,
+            rav_request_budget: config.rav_request_budget,
         })
     }
 
+    /// Loads the fees from a recent [database::FeeSnapshot] and adds the
+    /// receipts received after it, or sums up all the receipts otherwise
+    async fn restore_fees(&mut self) -> anyhow::Result<()> {
+        if let Some(snapshot) = self.load_fee_snapshot().await {
+            let (unaggregated_fees, invalid_receipts_fees) =
+                self.calculate_fees_after_snapshot(&snapshot).await?;
+            self.unaggregated_fees = add_receipts(snapshot.unaggregated_fees, unaggregated_fees);
+            self.invalid_receipts_fees =
+                add_receipts(snapshot.invalid_receipts_fees, invalid_receipts_fees);
+            return Ok(());
+        }
+        self.invalid_receipts_fees = self.calculate_invalid_receipts_fee().await?;
+        self.unaggregated_fees = self.recalculate_all_unaggregated_fees().await?;
+        Ok(())
+    }
+
+    /// Connection of the fee calculations run when starting, which don't
+    /// follow a write of tap-agent and may read the replica, unless the strict
+    /// calculation is enabled
+    ///
+    /// The fees recalculated after the receipts are deleted, after a RAV
+    /// request, are always read on the primary.
+    fn startup_pgpool(&self) -> &PgPool {
+        if self.strict_fee_calculation {
+            &self.pgpool
+        } else {
+            &self.replica_pgpool
+        }
+    }
+
+    async fn load_fee_snapshot(&self) -> Option<database::FeeSnapshot> {
+        // the strict calculation must read every receipt in the same transaction
+        if self.fee_snapshot_interval.is_none() || self.strict_fee_calculation {
+            return None;
+        }
+        let snapshot = database::load_fee_snapshot(
+            &self.pgpool,
+            Self::FEE_SNAPSHOT_VERSION,
+            self.sender,
+            self.allocation_id,
+            self.fee_snapshot_max_age,
+        )
+        .await
+        .inspect_err(|error| tracing::warn!(%error, "Failed to load the fee snapshot"))
+        .ok()??;
+        // the receipts aggregated by a newer RAV were deleted since
+        (snapshot.rav_timestamp_ns == self.latest_rav_timestamp_ns()).then_some(snapshot)
+    }
+
+    async fn store_fee_snapshot(&self) {
+        let snapshot = database::FeeSnapshot {
+            unaggregated_fees: self.unaggregated_fees,
+            invalid_receipts_fees: self.invalid_receipts_fees,
+            rav_timestamp_ns: self.latest_rav_timestamp_ns(),
+        };
+        if let Err(error) = database::store_fee_snapshot(
+            &self.pgpool,
+            Self::FEE_SNAPSHOT_VERSION,
+            self.sender,
+            self.allocation_id,
+            &snapshot,
+        )
+        .await
+        {
+            tracing::warn!(
+                %error,
+                sender = %self.sender,
+                allocation_id = %self.allocation_id,
+                "Failed to save the fee snapshot"
+            );
+        }
+    }
+
+    fn latest_rav_timestamp_ns(&self) -> u64 {
+        self.latest_rav
+            .as_ref()
+            .map(|rav| rav.message.timestamp_ns())
+            .unwrap_or_default()
+    }
+
     async fn recalculate_all_unaggregated_fees(&self) -> anyhow::Result<UnaggregatedReceipts> {
         self.calculate_fee_until_last_id(i64::MAX).await
     }
@@ -516,12 +783,28 @@ where
     }
 
     async fn request_rav(&mut self) -> anyhow::Result<()> {
-        match self.rav_requester_single().await {
+        let span = tracing::info_span!(
+            "rav_request",
+            sender = %self.sender,
+            allocation_id = %self.allocation_id,
+        );
+        match self.rav_requester_single().instrument(span).await {
             Ok(rav) => {
-                self.unaggregated_fees = self.calculate_unaggregated_fee().await?;
+                self.unaggregated_fees = match self.take_rav_fees() {
+                    Some((max, sum, count)) => unaggregated_receipts(max, sum, count)?,
+                    None => self.calculate_unaggregated_fee().await?,
+                };
+                events::publish(
+                    self.sender,
+                    EventKind::RavCreated {
+                        allocation_id: self.allocation_id,
+                        value_aggregate: rav.message.value().to_string(),
+                        timestamp_ns: rav.message.timestamp_ns(),
+                    },
+                );
                 self.latest_rav = Some(rav);
                 RAVS_CREATED
-                    .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+                    .with_label_values(&[&self.sender.to_string()])
                     .inc();
                 Ok(())
             }
@@ -530,13 +813,19 @@ where
                     self.unaggregated_fees = self.calculate_unaggregated_fee().await?;
                 }
                 RAVS_FAILED
-                    .with_label_values(&[&self.sender.to_string(), &self.allocation_id.to_string()])
+                    .with_label_values(&[&self.sender.to_string()])
                     .inc();
+                e.error_code().record();
                 Err(e.into())
             }
         }
     }
 
+    /// Takes the fees recalculated when the last RAV was stored in strict mode
+    fn take_rav_fees(&self) -> Option<ReceiptSums> {
+        self.rav_fees.as_ref()?.lock().unwrap().sums.take()
+    }
+
     /// Request a RAV from the sender's TAP aggregator. Only one RAV request will be running at a
     /// time because actors run one message at a time.
     ///
@@ -552,7 +841,7 @@ where
             .tap_manager
             .create_rav_request(
                 &Context::new(),
-                self.timestamp_buffer_ns,
+                clock::system_buffer_ns(self.timestamp_buffer_ns),
                 Some(self.rav_request_receipt_limit),
             )
             .await?;
@@ -581,9 +870,14 @@ where
                     .max()
                     .expect("invalid receipts should not be empty");
 
+                let signatures: Vec<Vec<u8>> = invalid_receipts
+                    .iter()
+                    .map(|receipt| receipt.signed_receipt().signature().as_bytes().to_vec())
+                    .collect();
+
                 self.store_invalid_receipts(invalid_receipts).await?;
                 let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender).await?;
-                self.delete_receipts_between(&signers, min_timestamp, max_timestamp)
+                self.delete_receipts_between(&signers, min_timestamp, max_timestamp, &signatures)
                     .await?;
                 Err(RavError::AllReceiptsInvalid)
             }
@@ -619,6 +913,12 @@ where
                     self.store_invalid_receipts(invalid_receipts).await?;
                 }
 
+                if let Some(rav_fees) = &self.rav_fees {
+                    *rav_fees.lock().unwrap() = RavFees {
+                        last_id: self.unaggregated_fees.last_id as i64,
+                        sums: None,
+                    };
+                }
                 match self
                     .tap_manager
                     .verify_and_store_rav(expected_rav.clone(), signed_rav.clone())
@@ -645,11 +945,7 @@ where
                     ) => {
                         Self::store_failed_rav(self, &expected_rav, &signed_rav, &e.to_string())
                             .await?;
-                        return Err(anyhow::anyhow!(
-                            "Invalid RAV, sender could be malicious: {:?}.",
-                            e
-                        )
-                        .into());
+                        return Err(RavError::InvalidRav(e));
                     }
 
                     // All relevant errors should be handled above. If we get here, we forgot to handle
@@ -689,6 +985,15 @@ where
                 // note: it would be nice if we could get signed_receipt and error by value without
                 // cloning
                 let error = r.clone().error().to_string();
+                events::publish(
+                    self.sender,
+                    EventKind::ReceiptInvalid {
+                        allocation_id: self.allocation_id,
+                        timestamp_ns: r.signed_receipt().timestamp_ns(),
+                        value: r.signed_receipt().value().to_string(),
+                        error: error.clone(),
+                    },
+                );
                 match r.signed_receipt().clone() {
                     TapReceipt::V1(receipt) => Either::Left((receipt, error)),
                     TapReceipt::V2(receipt) => Either::Right((receipt, error)),
@@ -929,14 +1234,59 @@ where
     }
 }
 
+/// Builds [UnaggregatedReceipts] from the `MAX(id)`, `SUM(value)` and `COUNT(*)`
+/// returned by a strict fee calculation
+fn unaggregated_receipts(
+    max: Option<i64>,
+    sum: Option<BigDecimal>,
+    count: Option<i64>,
+) -> anyhow::Result<UnaggregatedReceipts> {
+    ensure!(
+        sum.is_none() == max.is_none(),
+        "Exactly one of SUM(value) and MAX(id) is null. This should not happen."
+    );
+
+    Ok(UnaggregatedReceipts {
+        last_id: max.unwrap_or(0).try_into()?,
+        value: sum
+            .unwrap_or(BigDecimal::from(0))
+            .to_string()
+            .parse::<u128>()?,
+        counter: count
+            .unwrap_or(0)
+            .to_u64()
+            .expect("default value exists, this shouldn't be empty"),
+    })
+}
+
+/// Adds the receipts received after a [database::FeeSnapshot] to its fees
+fn add_receipts(
+    snapshot: UnaggregatedReceipts,
+    after: UnaggregatedReceipts,
+) -> UnaggregatedReceipts {
+    UnaggregatedReceipts {
+        value: snapshot.value + after.value,
+        last_id: snapshot.last_id.max(after.last_id),
+        counter: snapshot.counter + after.counter,
+    }
+}
+
 /// Interactions with the database that needs some special treatment depending on the NetworkVersion
 pub trait DatabaseInteractions {
-    /// Delete receipts between `min_timestamp` and `max_timestamp`
+    /// Version the [database::FeeSnapshot]s are saved with
+    const FEE_SNAPSHOT_VERSION: &'static str;
+
+    /// Delete the receipts with `signatures` between `min_timestamp` and
+    /// `max_timestamp`
+    ///
+    /// Only the receipts read for the RAV request are deleted, not the ones
+    /// stored in the same range since.
     fn delete_receipts_between(
         &self,
         signers: &[String],
         min_timestamp: u64,
         max_timestamp: u64,
+        signatures: &[Vec<u8>],
     ) -> impl Future<Output = anyhow::Result<()>> + Send;
     /// Calculates fees for invalid receipts
     fn calculate_invalid_receipts_fee(
@@ -946,6 +1296,9 @@ pub trait DatabaseInteractions {
     /// Calculates all receipt fees until provided `last_id`
     /// Delete obsolete receipts in the DB w.r.t. the last RAV in DB, then update the tap manager
     /// with the latest unaggregated fees from the database.
+    ///
+    /// With `strict_fee_calculation` enabled, both steps run in the same repeatable read
+    /// transaction and use the RAV timestamp stored in the database.
     fn calculate_fee_until_last_id(
         &self,
         last_id: i64,
@@ -953,26 +1306,38 @@ pub trait DatabaseInteractions {
 
     /// Sends a database query and mark the allocation rav as last
     fn mark_rav_last(&self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Calculates the fees of the receipts and invalid receipts received
+    /// after the `last_id`s of `snapshot`
+    fn calculate_fees_after_snapshot(
+        &self,
+        snapshot: &database::FeeSnapshot,
+    ) -> impl Future<Output = anyhow::Result<(UnaggregatedReceipts, UnaggregatedReceipts)>> + Send;
 }
 
 impl DatabaseInteractions for SenderAllocationState<Legacy> {
+    const FEE_SNAPSHOT_VERSION: &'static str = "v1";
+
     async fn delete_receipts_between(
         &self,
         signers: &[String],
         min_timestamp: u64,
         max_timestamp: u64,
+        signatures: &[Vec<u8>],
     ) -> anyhow::Result<()> {
         sqlx::query!(
             r#"
-                        DELETE FROM scalar_tap_receipts
-                        WHERE timestamp_ns BETWEEN $1 AND $2
-                        AND allocation_id = $3
-                        AND signer_address IN (SELECT unnest($4::text[]));
-                    "#,
+                DELETE FROM scalar_tap_receipts
+                WHERE timestamp_ns BETWEEN $1 AND $2
+                    AND allocation_id = $3
+                    AND signer_address IN (SELECT unnest($4::text[]))
+                    AND signature = ANY($5)
+            "#,
             BigDecimal::from(min_timestamp),
             BigDecimal::from(max_timestamp),
             self.allocation_id.encode_hex(),
-            &signers,
+            signers,
+            signatures,
         )
         .execute(&self.pgpool)
         .await?;
@@ -997,7 +1362,7 @@ impl DatabaseInteractions for SenderAllocationState<Legacy> {
             self.allocation_id.encode_hex(),
             &signers
         )
-        .fetch_one(&self.pgpool)
+        .fetch_one(self.startup_pgpool())
         .await?;
 
         ensure!(
@@ -1027,9 +1392,46 @@ impl DatabaseInteractions for SenderAllocationState<Legacy> {
         last_id: i64,
     ) -> anyhow::Result<UnaggregatedReceipts> {
         tracing::trace!("calculate_unaggregated_fee()");
-        self.tap_manager.remove_obsolete_receipts().await?;
-
         let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender).await?;
+        if self.strict_fee_calculation {
+            let allocation_id = self.allocation_id.encode_hex();
+            let sender = self.sender.encode_hex();
+            let (max, sum, count) = database::repeatable_read(&self.pgpool, |conn| {
+                let allocation_id = allocation_id.clone();
+                let sender = sender.clone();
+                let signers = signers.clone();
+                Box::pin(async move {
+                    let last_rav_timestamp = sqlx::query!(
+                        r#"
+                            SELECT MAX(timestamp_ns)
+                            FROM scalar_tap_ravs
+                            WHERE allocation_id = $1 AND sender_address = $2
+                        "#,
+                        allocation_id,
+                        sender,
+                    )
+                    .fetch_one(&mut *conn)
+                    .await?
+                    .max
+                    .unwrap_or_default();
+
+                    legacy_fees_after_rav(
+                        &mut *conn,
+                        &allocation_id,
+                        &signers,
+                        last_id,
+                        &last_rav_timestamp,
+                    )
+                    .await
+                })
+            })
+            .await?;
+            return unaggregated_receipts(max, sum, count);
+        }
+
+        self.tap_manager.remove_obsolete_receipts().await?;
+        // read on the primary, the replica may still have the receipts deleted
+        // above and those aggregated by the RAV just stored
         let res = sqlx::query!(
             r#"
             SELECT
@@ -1114,26 +1516,83 @@ impl DatabaseInteractions for SenderAllocationState<Legacy> {
             ),
         }
     }
+
+    async fn calculate_fees_after_snapshot(
+        &self,
+        snapshot: &database::FeeSnapshot,
+    ) -> anyhow::Result<(UnaggregatedReceipts, UnaggregatedReceipts)> {
+        let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender).await?;
+        let fees = sqlx::query!(
+            r#"
+                SELECT
+                    MAX(id),
+                    SUM(value),
+                    COUNT(*)
+                FROM
+                    scalar_tap_receipts
+                WHERE
+                    allocation_id = $1
+                    AND id > $2
+                    AND signer_address IN (SELECT unnest($3::text[]))
+                    AND timestamp_ns > $4
+            "#,
+            self.allocation_id.encode_hex(),
+            i64::try_from(snapshot.unaggregated_fees.last_id)?,
+            &signers,
+            BigDecimal::from(snapshot.rav_timestamp_ns),
+        )
+        .fetch_one(&self.replica_pgpool)
+        .await?;
+        let unaggregated_fees = unaggregated_receipts(fees.max, fees.sum, fees.count)?;
+
+        let fees = sqlx::query!(
+            r#"
+                SELECT
+                    MAX(id),
+                    SUM(value),
+                    COUNT(*)
+                FROM
+                    scalar_tap_receipts_invalid
+                WHERE
+                    allocation_id = $1
+                    AND id > $2
+                    AND signer_address IN (SELECT unnest($3::text[]))
+            "#,
+            self.allocation_id.encode_hex(),
+            i64::try_from(snapshot.invalid_receipts_fees.last_id)?,
+            &signers,
+        )
+        .fetch_one(&self.replica_pgpool)
+        .await?;
+        let invalid_receipts_fees = unaggregated_receipts(fees.max, fees.sum, fees.count)?;
+
+        Ok((unaggregated_fees, invalid_receipts_fees))
+    }
 }
 
 impl DatabaseInteractions for SenderAllocationState<Horizon> {
+    const FEE_SNAPSHOT_VERSION: &'static str = "v2";
+
     async fn delete_receipts_between(
         &self,
         signers: &[String],
         min_timestamp: u64,
         max_timestamp: u64,
+        signatures: &[Vec<u8>],
     ) -> anyhow::Result<()> {
         sqlx::query!(
             r#"
-                        DELETE FROM scalar_tap_receipts
-                        WHERE timestamp_ns BETWEEN $1 AND $2
-                        AND allocation_id = $3
-                        AND signer_address IN (SELECT unnest($4::text[]));
-                    "#,
+                DELETE FROM tap_horizon_receipts
+                WHERE timestamp_ns BETWEEN $1 AND $2
+                    AND allocation_id = $3
+                    AND signer_address IN (SELECT unnest($4::text[]))
+                    AND signature = ANY($5)
+            "#,
             BigDecimal::from(min_timestamp),
             BigDecimal::from(max_timestamp),
             self.allocation_id.encode_hex(),
-            &signers,
+            signers,
+            signatures,
         )
         .execute(&self.pgpool)
         .await?;
@@ -1159,7 +1618,7 @@ impl DatabaseInteractions for SenderAllocationState<Horizon> {
             self.allocation_id.encode_hex(),
             &signers
         )
-        .fetch_one(&self.pgpool)
+        .fetch_one(self.startup_pgpool())
         .await?;
 
         ensure!(
@@ -1187,9 +1646,54 @@ impl DatabaseInteractions for SenderAllocationState<Horizon> {
         last_id: i64,
     ) -> anyhow::Result<UnaggregatedReceipts> {
         tracing::trace!("calculate_unaggregated_fee()");
-        self.tap_manager.remove_obsolete_receipts().await?;
-
         let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender).await?;
+        if self.strict_fee_calculation {
+            let allocation_id = self.allocation_id.encode_hex();
+            let payer = self.sender.encode_hex();
+            let service_provider = self.indexer_address.encode_hex();
+            let (max, sum, count) = database::repeatable_read(&self.pgpool, |conn| {
+                let allocation_id = allocation_id.clone();
+                let payer = payer.clone();
+                let service_provider = service_provider.clone();
+                let signers = signers.clone();
+                Box::pin(async move {
+                    let last_rav_timestamp = sqlx::query!(
+                        r#"
+                            SELECT MAX(timestamp_ns)
+                            FROM tap_horizon_ravs
+                            WHERE
+                                allocation_id = $1
+                                AND payer = $2
+                                AND service_provider = $3
+                        "#,
+                        allocation_id,
+                        payer,
+                        service_provider,
+                    )
+                    .fetch_one(&mut *conn)
+                    .await?
+                    .max
+                    .unwrap_or_default();
+
+                    horizon_fees_after_rav(
+                        &mut *conn,
+                        &allocation_id,
+                        &payer,
+                        &service_provider,
+                        &signers,
+                        last_id,
+                        &last_rav_timestamp,
+                    )
+                    .await
+                })
+            })
+            .await?;
+            return unaggregated_receipts(max, sum, count);
+        }
+
+        self.tap_manager.remove_obsolete_receipts().await?;
+        // read on the primary, the replica may still have the receipts deleted
+        // above and those aggregated by the RAV just stored
         let res = sqlx::query!(
             r#"
             SELECT
@@ -1281,6 +1785,60 @@ impl DatabaseInteractions for SenderAllocationState<Horizon> {
             ),
         }
     }
+
+    async fn calculate_fees_after_snapshot(
+        &self,
+        snapshot: &database::FeeSnapshot,
+    ) -> anyhow::Result<(UnaggregatedReceipts, UnaggregatedReceipts)> {
+        let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender).await?;
+        let fees = sqlx::query!(
+            r#"
+                SELECT
+                    MAX(id),
+                    SUM(value),
+                    COUNT(*)
+                FROM
+                    tap_horizon_receipts
+                WHERE
+                    allocation_id = $1
+                    AND id > $2
+                    AND signer_address IN (SELECT unnest($3::text[]))
+                    AND timestamp_ns > $4
+                    AND service_provider = $5
+            "#,
+            self.allocation_id.encode_hex(),
+            i64::try_from(snapshot.unaggregated_fees.last_id)?,
+            &signers,
+            BigDecimal::from(snapshot.rav_timestamp_ns),
+            self.indexer_address.encode_hex(),
+        )
+        .fetch_one(&self.replica_pgpool)
+        .await?;
+        let unaggregated_fees = unaggregated_receipts(fees.max, fees.sum, fees.count)?;
+
+        let fees = sqlx::query!(
+            r#"
+                SELECT
+                    MAX(id),
+                    SUM(value),
+                    COUNT(*)
+                FROM
+                    tap_horizon_receipts_invalid
+                WHERE
+                    allocation_id = $1
+                    AND id > $2
+                    AND signer_address IN (SELECT unnest($3::text[]))
+            "#,
+            self.allocation_id.encode_hex(),
+            i64::try_from(snapshot.invalid_receipts_fees.last_id)?,
+            &signers,
+        )
+        .fetch_one(&self.replica_pgpool)
+        .await?;
+        let invalid_receipts_fees = unaggregated_receipts(fees.max, fees.sum, fees.count)?;
+
+        Ok((unaggregated_fees, invalid_receipts_fees))
+    }
 }
 
 #[cfg(test)]
@@ -1295,6 +1853,7 @@ pub mod tests {
 
     use bigdecimal::ToPrimitive;
     use futures::future::join_all;
+    use indexer_config::TapReceiptChecksConfig;
     use indexer_monitor::{DeploymentDetails, EscrowAccounts, SubgraphClient};
     use indexer_receipt::TapReceipt;
     use ractor::{call, cast, Actor, ActorRef, ActorStatus};
@@ -1307,9 +1866,11 @@ pub mod tests {
         Context,
     };
     use test_assets::{
-        flush_messages, ALLOCATION_ID_0, TAP_EIP712_DOMAIN as TAP_EIP712_DOMAIN_SEPARATOR,
-        TAP_SENDER as SENDER, TAP_SIGNER as SIGNER,
+        assert_while_retry, flush_messages, ALLOCATION_ID_0,
+        TAP_EIP712_DOMAIN as TAP_EIP712_DOMAIN_SEPARATOR, TAP_SENDER as SENDER,
+        TAP_SIGNER as SIGNER,
     };
+    use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
     use tokio::sync::{mpsc, watch};
     use tonic::{transport::Endpoint, Code};
     use wiremock::{
@@ -1319,7 +1880,8 @@ pub mod tests {
     use wiremock_grpc::{MockBuilder, Then};
 
     use super::{
-        SenderAllocation, SenderAllocationArgs, SenderAllocationMessage, SenderAllocationState,
+        AllocationConfig, SenderAllocation, SenderAllocationArgs, SenderAllocationMessage,
+        SenderAllocationState,
     };
     use crate::{
         agent::{
@@ -1328,11 +1890,13 @@ pub mod tests {
             sender_allocation::DatabaseInteractions,
             unaggregated_receipts::UnaggregatedReceipts,
         },
+        clock, database,
+        rav_budget::RavRequestBudget,
         tap::{context::Legacy, CheckingReceipt},
         test::{
             actors::{create_mock_sender_account, TestableActor},
-            create_rav, create_received_receipt, get_grpc_url, store_batch_receipts,
-            store_invalid_receipt, store_rav, store_receipt, INDEXER,
+            create_rav, create_received_receipt, get_grpc_url, sender_account_config,
+            store_batch_receipts, store_invalid_receipt, store_rav, store_receipt, INDEXER,
         },
     };
 
@@ -1358,6 +1922,8 @@ pub mod tests {
         sender_aggregator_endpoint: Option<String>,
         escrow_subgraph_endpoint: &str,
         #[builder(default = 1000)] rav_request_receipt_limit: u64,
+        #[builder(default = false)] strict_fee_calculation: bool,
+        fee_snapshot_interval: Option<Duration>,
         sender_account: Option<ActorRef<SenderAccountMessage>>,
     ) -> SenderAllocationArgs<Legacy> {
         let escrow_subgraph = Box::leak(Box::new(
@@ -1398,6 +1964,7 @@ pub mod tests {
 
         SenderAllocationArgs::builder()
             .pgpool(pgpool.clone())
+            .replica_pgpool(pgpool.clone())
             .allocation_id(ALLOCATION_ID_0)
             .sender(SENDER.1)
             .escrow_accounts(escrow_accounts_rx)
@@ -1408,8 +1975,17 @@ pub mod tests {
             .config(super::AllocationConfig {
                 timestamp_buffer_ns: 1,
                 rav_request_receipt_limit,
+                strict_fee_calculation,
                 indexer_address: INDEXER.1,
                 escrow_polling_interval: Duration::from_millis(1000),
+                fee_snapshot_interval,
+                fee_snapshot_max_age: Duration::from_secs(60),
+                contract_signers: None,
+                rav_request_budget: RavRequestBudget::new(100),
+                receipt_checks: TapReceiptChecksConfig {
+                    allocation_id: true,
+                    signature: true,
+                },
             })
             .build()
     }
@@ -1807,6 +2383,13 @@ pub mod tests {
         // check if the actor is actually stopped
         assert_eq!(sender_allocation.get_status(), ActorStatus::Stopped);
 
+        // the service refuses the new receipts of the allocation
+        let closing = sqlx::query_scalar!("SELECT allocation_id FROM closing_allocations")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(closing, vec![ALLOCATION_ID_0.encode_hex()]);
+
         // check if message is sent to sender account
         assert_eq!(
             message_receiver.recv().await.unwrap(),
@@ -1817,6 +2400,41 @@ pub mod tests {
         );
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_evict_idle_allocation(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let (_, sender_account) = create_mock_sender_account().await;
+        let signed_rav = create_rav(ALLOCATION_ID_0, SIGNER.0.clone(), 4, 10);
+        store_rav(&pgpool, signed_rav, SENDER.1).await.unwrap();
+
+        let (sender_allocation, mut notify) = create_sender_allocation()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .sender_account(sender_account)
+            .call()
+            .await;
+
+        sender_allocation
+            .cast(SenderAllocationMessage::Evict)
+            .unwrap();
+        flush_messages(&mut notify).await;
+
+        assert_while_retry!(sender_allocation.get_status() != ActorStatus::Stopped);
+        // the allocation is still open, its rav must not be marked as last
+        let last_ravs =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_ravs WHERE last"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(last_ravs, 0);
+        let closing =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM closing_allocations"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(closing, 0);
+    }
+
     // used for test_close_allocation_with_pending_fees(pgpool:
     mod wiremock_gen {
         wiremock_grpc::generate!("tap_aggregator.v1.TapAggregator", MockTapAggregator);
@@ -1914,6 +2532,62 @@ pub mod tests {
         assert_eq!(total_unaggregated_fees.value, 45u128);
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_restore_unaggregated_fees_from_snapshot(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+
+        let args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .fee_snapshot_interval(Duration::from_secs(10))
+            .call()
+            .await;
+        let mut state = SenderAllocationState::new(args).await.unwrap();
+
+        for i in 1..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // the snapshot doesn't match the first receipts, to make sure
+        // they are not summed up again
+        let snapshot = database::FeeSnapshot {
+            unaggregated_fees: UnaggregatedReceipts {
+                value: 1000,
+                last_id: 5,
+                counter: 5,
+            },
+            ..Default::default()
+        };
+        database::store_fee_snapshot(&pgpool, "v1", SENDER.1, ALLOCATION_ID_0, &snapshot)
+            .await
+            .unwrap();
+
+        state.restore_fees().await.unwrap();
+        assert_eq!(
+            state.unaggregated_fees,
+            UnaggregatedReceipts {
+                value: 1000 + 6 + 7 + 8 + 9,
+                last_id: 9,
+                counter: 9,
+            }
+        );
+
+        // a newer rav deleted the receipts of the snapshot
+        let signed_rav = create_rav(ALLOCATION_ID_0, SIGNER.0.clone(), 4, 10);
+        store_rav(&pgpool, signed_rav, SENDER.1).await.unwrap();
+        let args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .fee_snapshot_interval(Duration::from_secs(10))
+            .call()
+            .await;
+        let mut state = SenderAllocationState::new(args).await.unwrap();
+        state.restore_fees().await.unwrap();
+        assert_eq!(state.unaggregated_fees.value, 5 + 6 + 7 + 8 + 9);
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn should_calculate_invalid_receipts_fee(pgpool: PgPool) {
         let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
@@ -1975,6 +2649,214 @@ pub mod tests {
         assert_eq!(total_unaggregated_fees.value, 35u128);
     }
 
+    /// In strict mode, the RAV stored in the database is used even if the in-memory
+    /// `latest_rav` is stale, and the receipts it covers are removed in the same transaction.
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_return_unaggregated_fees_with_rav_in_strict_mode(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .strict_fee_calculation(true)
+            .call()
+            .await;
+        let state = SenderAllocationState::new(args).await.unwrap();
+        assert!(state.latest_rav.is_none());
+
+        // The RAV is stored after the state was created, so `latest_rav` doesn't know about it
+        let signed_rav = create_rav(ALLOCATION_ID_0, SIGNER.0.clone(), 4, 10);
+        store_rav(&pgpool, signed_rav, SENDER.1).await.unwrap();
+
+        for i in 1..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        let total_unaggregated_fees = state.recalculate_all_unaggregated_fees().await.unwrap();
+        assert_eq!(total_unaggregated_fees.value, 35u128);
+        assert_eq!(total_unaggregated_fees.counter, 5);
+
+        let remaining_receipts = sqlx::query!(
+            r#"
+                SELECT count(*)
+                FROM scalar_tap_receipts
+            "#
+        )
+        .fetch_one(&pgpool)
+        .await
+        .unwrap()
+        .count
+        .unwrap();
+        assert_eq!(remaining_receipts, 5);
+    }
+
+    /// Receipts stored in the range of the invalid receipts after they were read
+    /// must not be deleted with them.
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_only_delete_receipts_read_in_strict_mode(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .strict_fee_calculation(true)
+            .call()
+            .await;
+        let state = SenderAllocationState::new(args).await.unwrap();
+
+        let mut signatures = Vec::new();
+        for i in 1..=5 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i * 2, i.into());
+            signatures.push(receipt.signed_receipt().signature().as_bytes().to_vec());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+        // Stored concurrently, between the first and the last receipts read
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 6, 5, 6);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        state
+            .delete_receipts_between(&[SIGNER.1.encode_hex()], 2, 10, &signatures)
+            .await
+            .unwrap();
+
+        let remaining_receipts = sqlx::query!(
+            r#"
+                SELECT nonce
+                FROM scalar_tap_receipts
+            "#
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(remaining_receipts.len(), 1);
+        assert_eq!(remaining_receipts[0].nonce, BigDecimal::from(6));
+    }
+
+    /// In strict mode, a receipt stored while the RAV is stored is either
+    /// covered by the RAV or counted in the fees recalculated with it, never both.
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_not_double_count_receipts_stored_with_rav_in_strict_mode(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .strict_fee_calculation(true)
+            .call()
+            .await;
+        let state = SenderAllocationState::new(args).await.unwrap();
+
+        for i in 1..10 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, i, i, i.into());
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        // Late receipts in the range of the RAV worth 7 and new ones worth 1000
+        // are stored concurrently
+        let writer = tokio::spawn({
+            let pgpool = pgpool.clone();
+            async move {
+                for i in 0..20 {
+                    let (timestamp, value) = if i % 2 == 0 {
+                        (i % 4 + 1, 7)
+                    } else {
+                        (10 + i, 1000)
+                    };
+                    let receipt = create_received_receipt(
+                        &ALLOCATION_ID_0,
+                        &SIGNER.0,
+                        100 + i,
+                        timestamp,
+                        value,
+                    );
+                    store_receipt(&pgpool, receipt.signed_receipt())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        // Covers the receipts 1 to 4
+        let signed_rav = create_rav(ALLOCATION_ID_0, SIGNER.0.clone(), 4, 10);
+        state.rav_fees.as_ref().unwrap().lock().unwrap().last_id = i64::MAX;
+        state
+            .tap_manager
+            .verify_and_store_rav(signed_rav.message.clone(), signed_rav)
+            .await
+            .unwrap();
+        writer.await.unwrap();
+
+        let (max, sum, count) = state.take_rav_fees().unwrap();
+        let fees = unaggregated_receipts(max, sum, count).unwrap();
+        // Only the receipts 5 to 9 and some of the new ones
+        let new_receipts = (fees.value - 35) / 1000;
+        assert_eq!(fees.value, 35 + new_receipts * 1000);
+        assert_eq!(fees.counter, 5 + new_receipts as u64);
+
+        let total_unaggregated_fees = state.recalculate_all_unaggregated_fees().await.unwrap();
+        assert_eq!(total_unaggregated_fees.value, 35 + 10 * 1000);
+        assert_eq!(total_unaggregated_fees.counter, 15);
+    }
+
+    /// The buffer set for a sender in `sender_timestamp_buffer_secs` overrides the
+    /// default one when the range of its RAV requests is computed
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn should_use_sender_timestamp_buffer_for_rav_request(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let mut sender_config = sender_account_config();
+        sender_config.rav_request_buffer = Duration::from_nanos(1);
+        sender_config.sender_rav_request_buffers =
+            HashMap::from([(SENDER.1, Duration::from_secs(3600))]);
+
+        let mut args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .call()
+            .await;
+        args.config = AllocationConfig::from_sender_config(&sender_config, &SENDER.1);
+        let state = SenderAllocationState::new(args).await.unwrap();
+        assert_eq!(state.timestamp_buffer_ns, 3600 * 1_000_000_000);
+
+        // a minute old, only in the buffer of the sender
+        let timestamp_ns = (SystemTime::now() - Duration::from_secs(60))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, timestamp_ns, 10);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        let rav_request = state
+            .tap_manager
+            .create_rav_request(
+                &Context::new(),
+                clock::system_buffer_ns(state.timestamp_buffer_ns),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(rav_request.valid_receipts.is_empty());
+
+        // the default buffer of the other senders
+        let default_buffer_ns = sender_config
+            .rav_request_buffer_for(&Address::ZERO)
+            .as_nanos() as u64;
+        assert_eq!(default_buffer_ns, 1);
+        let rav_request = state
+            .tap_manager
+            .create_rav_request(&Context::new(), default_buffer_ns, None)
+            .await
+            .unwrap();
+        assert_eq!(rav_request.valid_receipts.len(), 1);
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_store_failed_rav(pgpool: PgPool) {
         let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
@@ -1996,6 +2878,46 @@ pub mod tests {
         assert!(result.is_ok());
     }
 
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_required_checks_from_config(pgpool: PgPool) {
+        let (mock_escrow_subgraph_server, _mock_ecrow_subgraph) = mock_escrow_subgraph().await;
+        let args = create_sender_allocation_args()
+            .pgpool(pgpool.clone())
+            .escrow_subgraph_endpoint(&mock_escrow_subgraph_server.uri())
+            .call()
+            .await;
+
+        let all = TapReceiptChecksConfig {
+            allocation_id: true,
+            signature: true,
+        };
+        let none = TapReceiptChecksConfig {
+            allocation_id: false,
+            signature: false,
+        };
+        let no_signature = TapReceiptChecksConfig {
+            signature: false,
+            ..all
+        };
+        for (receipt_checks, expected_len) in [(all, 2), (no_signature, 1), (none, 0)] {
+            let config = super::AllocationConfig {
+                receipt_checks,
+                ..args.config.clone()
+            };
+            let checks = SenderAllocationState::<Legacy>::required_checks(
+                &config,
+                args.sender,
+                args.allocation_id,
+                args.escrow_subgraph,
+                &args.domain_separator,
+                &args.escrow_accounts,
+            )
+            .await;
+            assert_eq!(checks.len(), expected_len);
+        }
+        assert_eq!(none.disabled(), vec!["allocation_id", "signature"]);
+    }
+
     #[sqlx::test(migrations = "../../migrations")]
     async fn test_store_invalid_receipts(pgpool: PgPool) {
         struct FailingCheck;
@@ -11,8 +11,12 @@
 //! the request.
 //!
 //! This module is also used by [crate::tracker].
+//!
+//! The time is the one of tokio, so that backoffs can be paused and advanced in simulations.
+
+use std::time::Duration;
 
-use std::time::{Duration, Instant};
+use tokio::time::Instant;
 
 /// Backoff information based on [Instant]
 #[derive(Debug, Clone)]
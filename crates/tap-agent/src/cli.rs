@@ -6,13 +6,12 @@
 
 use std::path::PathBuf;
 
-use clap::Parser;
-use indexer_config::{Config as IndexerConfig, ConfigPrefix};
-use tracing::{
-    level_filters::LevelFilter,
-    subscriber::{set_global_default, SetGlobalDefaultError},
-};
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use clap::{Parser, Subcommand};
+use indexer_config::{Config as IndexerConfig, ConfigPrefix, LogConfig, OpenTelemetryConfig};
+use tap_core::tap_eip712_domain;
+use thegraph_core::alloy::primitives::Address;
+use tracing::{level_filters::LevelFilter, subscriber::set_global_default};
+use tracing_subscriber::{layer::SubscriberExt, FmtSubscriber};
 
 /// A [clap::Parser] that contains the path to the configuration
 #[derive(Parser)]
@@ -22,41 +21,162 @@ pub struct Cli {
     /// See https://github.com/graphprotocol/indexer-rs/tree/main/tap-agent for examples.
     #[arg(long, value_name = "FILE", verbatim_doc_comment)]
     pub config: Option<PathBuf>,
+
+    /// Refuse to start if the database schema is not compatible with this release,
+    /// otherwise the differences are only logged.
+    #[arg(long)]
+    pub strict_schema: bool,
+
+    /// Run the TAP and DIPS migrations of this release before starting, for
+    /// databases that are not migrated by indexer-agent.
+    #[arg(long)]
+    pub migrate: bool,
+
+    /// Runs a maintenance command instead of the agent
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance commands, the agent is not started
+#[derive(Subcommand)]
+pub enum Command {
+    /// Checks the invalid receipts again, for example once a misconfiguration of
+    /// the escrow accounts is fixed, and moves the ones that now pass back to the
+    /// receipts table so they are aggregated in the next RAVs. A running agent
+    /// must be restarted afterwards, it keeps counting them as invalid fees
+    /// until then
+    RevalidateInvalidReceipts {
+        /// Only report the receipts that would be moved
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Checks the configuration and prints it with the defaults and environment
+    /// variables applied, secrets redacted
+    ValidateConfig {
+        /// Also check that every configured URL accepts connections
+        #[arg(long)]
+        online: bool,
+    },
+    /// Runs the TAP and DIPS migrations of this release, for databases that
+    /// are not migrated by indexer-agent
+    Migrate,
+    /// Prints the fees of each allocation in the receipts and vouchers tables
+    /// of the legacy indexer-service, next to its TAP receipts and RAVs
+    LegacyReceiptsReport,
+    /// Asks the running tap-agent to request a RAV now, whatever the trigger
+    /// value, e.g. to flush the fees before a maintenance or before closing
+    /// an allocation. Requires `[tap] admin_token`
+    RavRequest {
+        /// Sender of the receipts
+        #[arg(long)]
+        sender: Address,
+        /// Only request the RAV of this allocation, every allocation of the
+        /// sender otherwise
+        #[arg(long)]
+        allocation: Option<Address>,
+        /// URL of the metrics server of tap-agent, the first configured
+        /// metrics address on localhost otherwise
+        #[arg(long)]
+        url: Option<String>,
+    },
 }
 
 /// Sets up tracing, allows log level to be set from the environment variables
-fn init_tracing(format: String) -> Result<(), SetGlobalDefaultError> {
-    let filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy();
+///
+/// Spans are also exported to an OpenTelemetry collector if configured
+fn init_tracing(
+    format: String,
+    opentelemetry: Option<&OpenTelemetryConfig>,
+    log: Option<&LogConfig>,
+) -> anyhow::Result<()> {
+    // the levels are filtered by a layer reloaded when they are overridden
+    // at runtime
     let subscriber_builder: tracing_subscriber::fmt::SubscriberBuilder<
         tracing_subscriber::fmt::format::DefaultFields,
         tracing_subscriber::fmt::format::Format,
-        EnvFilter,
-    > = FmtSubscriber::builder().with_env_filter(filter);
+        LevelFilter,
+    > = FmtSubscriber::builder().with_max_level(LevelFilter::TRACE);
+    let throttle = log.and_then(|log| log.throttle.as_ref());
+    let tracer = opentelemetry
+        .map(|config| indexer_telemetry::init_tracer(config, "indexer-tap-agent"))
+        .transpose()?;
     match format.as_str() {
-        "json" => set_global_default(subscriber_builder.json().finish()),
-        "full" => set_global_default(subscriber_builder.finish()),
-        "compact" => set_global_default(subscriber_builder.compact().finish()),
-        _ => set_global_default(subscriber_builder.with_ansi(true).pretty().finish()),
+        "json" => set_global_default(
+            subscriber_builder
+                .json()
+                .finish()
+                .with(indexer_telemetry::reloadable_filter())
+                .with(indexer_telemetry::throttle_layer(throttle))
+                .with(tracer.map(indexer_telemetry::layer)),
+        ),
+        "full" => set_global_default(
+            subscriber_builder
+                .finish()
+                .with(indexer_telemetry::reloadable_filter())
+                .with(indexer_telemetry::throttle_layer(throttle))
+                .with(tracer.map(indexer_telemetry::layer)),
+        ),
+        "compact" => set_global_default(
+            subscriber_builder
+                .compact()
+                .finish()
+                .with(indexer_telemetry::reloadable_filter())
+                .with(indexer_telemetry::throttle_layer(throttle))
+                .with(tracer.map(indexer_telemetry::layer)),
+        ),
+        _ => set_global_default(
+            subscriber_builder
+                .with_ansi(true)
+                .pretty()
+                .finish()
+                .with(indexer_telemetry::reloadable_filter())
+                .with(indexer_telemetry::throttle_layer(throttle))
+                .with(tracer.map(indexer_telemetry::layer)),
+        ),
+    }?;
+    Ok(())
+}
+
+/// Prints the diagnostics of the configuration given in [crate::CLI], fails
+/// if it's invalid
+pub fn validate_config(online: bool) -> anyhow::Result<()> {
+    let cli = &*crate::CLI;
+    let diagnostics = IndexerConfig::diagnose(ConfigPrefix::Tap, cli.config.as_ref(), online);
+    if let Some(config) = &diagnostics.config {
+        let domain = tap_eip712_domain(
+            config.blockchain.chain_id as u64,
+            config.blockchain.receipts_verifier_address,
+        );
+        println!(
+            "# TAP EIP-712 domain separator {}, indexer-service must report the same",
+            domain.separator()
+        );
     }
+    print!("{diagnostics}");
+    anyhow::ensure!(diagnostics.is_valid(), "Invalid configuration");
+    Ok(())
 }
 
-/// Helper function that parses the Cli and uses the provided arguments to return a [IndexerConfig]
+/// Helper function that uses the arguments of [crate::CLI] to return a [IndexerConfig]
 pub fn get_config() -> anyhow::Result<IndexerConfig> {
-    let cli = Cli::parse();
+    let cli = &*crate::CLI;
     let config = IndexerConfig::parse(ConfigPrefix::Tap, cli.config.as_ref()).map_err(|e| {
         tracing::error!(
             "Invalid configuration file `{}`: {}, if a value is missing you can also use \
                 --config to fill the rest of the values",
-            cli.config.unwrap_or_default().display(),
+            cli.config.clone().unwrap_or_default().display(),
             e
         );
         anyhow::anyhow!(e)
     })?;
 
     // add a LogFormat to config
-    init_tracing("pretty".to_string()).expect(
+    init_tracing(
+        "pretty".to_string(),
+        config.opentelemetry.as_ref(),
+        config.log.as_ref(),
+    )
+    .expect(
         "Could not set up global default subscriber for logger, check \
         environmental variable `RUST_LOG`",
     );
@@ -0,0 +1,64 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # clock
+//!
+//! System time the timestamps of the receipts are compared to.
+//!
+//! Once a [crate::simulation] is started, it follows the tokio clock instead of the system
+//! clock, so that the receipts leave the buffer as the time of the simulation is advanced.
+//! Until then, and without the `simulation` feature, it is the system clock.
+
+use std::time::SystemTime;
+
+/// Current system time
+pub fn now() -> SystemTime {
+    #[cfg(feature = "simulation")]
+    if let Some(now) = simulated::now() {
+        return now;
+    }
+    SystemTime::now()
+}
+
+/// Timestamp buffer to give tap_core for the receipts older than `buffer_ns` in [now] to be
+/// aggregated, tap_core comparing their timestamps to the system clock
+pub fn system_buffer_ns(buffer_ns: u64) -> u64 {
+    #[cfg(feature = "simulation")]
+    if let Some(now) = simulated::now() {
+        let behind = SystemTime::now().duration_since(now).unwrap_or_default();
+        return buffer_ns + behind.as_nanos() as u64;
+    }
+    buffer_ns
+}
+
+#[cfg(feature = "simulation")]
+pub(crate) mod simulated {
+    use std::{
+        sync::OnceLock,
+        time::{Duration, SystemTime},
+    };
+
+    /// The simulated time is this far behind the system time at the start, so that it stays
+    /// behind it, see [super::system_buffer_ns]
+    const BEHIND: Duration = Duration::from_secs(24 * 60 * 60);
+
+    static BASE: OnceLock<(SystemTime, std::time::Instant)> = OnceLock::new();
+
+    /// Makes [super::now] follow the tokio clock from now on
+    pub(crate) fn start() {
+        BASE.get_or_init(|| (SystemTime::now() - BEHIND, std::time::Instant::now()));
+    }
+
+    /// Current system time, moved by as much as the tokio clock was paused or advanced
+    ///
+    /// It is derived from the tokio clock alone, the system time of a given instant is the
+    /// same every time.
+    pub(super) fn now() -> Option<SystemTime> {
+        let (base_system, base_instant) = *BASE.get()?;
+        let tokio_now = tokio::time::Instant::now().into_std();
+        Some(match tokio_now.checked_duration_since(base_instant) {
+            Some(since) => base_system + since,
+            None => base_system - base_instant.duration_since(tokio_now),
+        })
+    }
+}
@@ -1,25 +1,1069 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::time::Duration;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use bigdecimal::{num_bigint::BigInt, ToPrimitive};
+use futures::future::BoxFuture;
+use indexer_config::DatabasePoolConfig;
+use indexer_monitor::EscrowAccounts;
+use indexer_schema::RequiredTable;
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, register_int_gauge_vec, HistogramVec, IntGaugeVec};
+use reqwest::Url;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    types::BigDecimal,
+    PgConnection, PgPool,
+};
+use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
+use tokio::sync::watch;
+
+use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+
+/// Maximum number of times a transaction is retried after a serialization failure
+const MAX_SERIALIZATION_RETRIES: u32 = 5;
+
+/// Postgres error code for `serialization_failure`
+const SERIALIZATION_FAILURE: &str = "40001";
+
+/// Upper bound of the time between two runs of [maintain_receipt_pruning]
+const MAX_RECEIPT_PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Age of the rows of `closing_allocations` removed by [mark_allocation_closing]
+const CLOSING_ALLOCATIONS_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Used when `database.pool.acquire_timeout_secs` is not set
+const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often the usage of the connection pools is recorded
+const POOL_METRICS_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "tap_database_pool_connections",
+        "Connections of the database pool",
+        &["pool", "state"]
+    )
+    .unwrap();
+    static ref POOL_ACQUIRE: HistogramVec = register_histogram_vec!(
+        "tap_database_pool_acquire_seconds",
+        "Time waited for a connection of the database pool, sampled",
+        &["pool"]
+    )
+    .unwrap();
+}
+
+/// Tables and columns tap-agent reads or writes
+pub const REQUIRED_TABLES: &[RequiredTable] = &[
+    RequiredTable {
+        name: "scalar_tap_receipts",
+        columns: &[
+            "id",
+            "signer_address",
+            "signature",
+            "allocation_id",
+            "timestamp_ns",
+            "nonce",
+            "value",
+        ],
+    },
+    RequiredTable {
+        name: "scalar_tap_receipts_invalid",
+        columns: &[
+            "id",
+            "signer_address",
+            "allocation_id",
+            "value",
+            "error_log",
+        ],
+    },
+    RequiredTable {
+        name: "scalar_tap_ravs",
+        columns: &[
+            "sender_address",
+            "signature",
+            "allocation_id",
+            "timestamp_ns",
+            "value_aggregate",
+            "last",
+            "final",
+        ],
+    },
+    RequiredTable {
+        name: "scalar_tap_rav_requests_failed",
+        columns: &["allocation_id", "sender_address", "reason", "created_at"],
+    },
+    RequiredTable {
+        name: "scalar_tap_denylist",
+        columns: &["sender_address"],
+    },
+    RequiredTable {
+        name: "tap_horizon_receipts",
+        columns: &[
+            "id",
+            "signer_address",
+            "signature",
+            "allocation_id",
+            "payer",
+            "data_service",
+            "service_provider",
+            "timestamp_ns",
+            "nonce",
+            "value",
+        ],
+    },
+    RequiredTable {
+        name: "tap_horizon_receipts_invalid",
+        columns: &[
+            "id",
+            "signer_address",
+            "allocation_id",
+            "value",
+            "error_log",
+        ],
+    },
+    RequiredTable {
+        name: "tap_horizon_ravs",
+        columns: &[
+            "signature",
+            "allocation_id",
+            "payer",
+            "data_service",
+            "service_provider",
+            "timestamp_ns",
+            "value_aggregate",
+            "metadata",
+            "last",
+            "final",
+        ],
+    },
+    RequiredTable {
+        name: "tap_horizon_rav_requests_failed",
+        columns: &["allocation_id", "payer", "reason", "created_at"],
+    },
+    RequiredTable {
+        name: "tap_horizon_denylist",
+        columns: &["sender_address"],
+    },
+    RequiredTable {
+        name: "closing_allocations",
+        columns: &["allocation_id", "closing_since"],
+    },
+    RequiredTable {
+        name: "allocation_events",
+        columns: &["allocation_id", "event", "sender_address", "recorded_at"],
+    },
+];
+
+/// Table of the fee snapshots, only required when they are enabled
+pub const FEE_SNAPSHOT_TABLE: RequiredTable = RequiredTable {
+    name: "tap_agent_state",
+    columns: &[
+        "version",
+        "sender_address",
+        "allocation_id",
+        "rav_timestamp_ns",
+        "last_id",
+        "value",
+        "counter",
+        "invalid_last_id",
+        "invalid_value",
+        "invalid_counter",
+    ],
+};
+
+/// Table of the RAV redemptions, only required when they are reconciled
+pub const RAV_REDEMPTIONS_TABLE: RequiredTable = RequiredTable {
+    name: "scalar_tap_rav_redemptions",
+    columns: &[
+        "allocation_id",
+        "sender_address",
+        "value_aggregate",
+        "status",
+        "checked_at",
+    ],
+};
 
 /// Uses `config` to connect to a postgres and returns a [PgPool] instance.
 ///
 /// This function panics if it wasn't possible to connect to the Db.
 pub async fn connect(config: indexer_config::DatabaseConfig) -> PgPool {
-    let url = &config.get_formated_postgres_url();
+    let pool = config.pool().clone();
+    connect_url(&config.get_formated_postgres_url(), &pool).await
+}
+
+/// Connects to the read replica of `config` if one is configured
+///
+/// This function panics if it wasn't possible to connect to the replica.
+pub async fn connect_replica(config: &indexer_config::DatabaseConfig) -> Option<PgPool> {
+    Some(connect_url(config.replica_url()?, config.pool()).await)
+}
+
+async fn connect_url(url: &Url, pool: &DatabasePoolConfig) -> PgPool {
     tracing::debug!(
         postgres_host = tracing::field::debug(&url.host()),
         postgres_port = tracing::field::debug(&url.port()),
         postgres_database = tracing::field::debug(&url.path()),
         "Connecting to database"
     );
+    let mut options =
+        PgConnectOptions::from_str(url.as_str()).expect("Could not parse DATABASE_URL");
+    if let Some(statement_timeout) = pool.statement_timeout_secs {
+        options = options.options([("statement_timeout", statement_timeout.as_millis())]);
+    }
     PgPoolOptions::new()
-        .max_connections(50)
-        .acquire_timeout(Duration::from_secs(3))
-        .connect(url.as_str())
+        .max_connections(pool.max_connections)
+        .acquire_timeout(pool.acquire_timeout_secs.unwrap_or(DEFAULT_ACQUIRE_TIMEOUT))
+        .idle_timeout(pool.idle_timeout_secs)
+        .connect_with(options)
         .await
         .expect("Could not connect to DATABASE_URL")
 }
+
+/// Records the connections of `pgpool` in use and idle, and how long it
+/// takes to get one, under the `name` label
+async fn record_pool_metrics(pgpool: &PgPool, name: &str) {
+    let size = pgpool.size() as i64;
+    let idle = pgpool.num_idle() as i64;
+    POOL_CONNECTIONS
+        .with_label_values(&[name, "in_use"])
+        .set(size - idle);
+    POOL_CONNECTIONS
+        .with_label_values(&[name, "idle"])
+        .set(idle);
+
+    let start = Instant::now();
+    match pgpool.acquire().await {
+        Ok(_) => POOL_ACQUIRE
+            .with_label_values(&[name])
+            .observe(start.elapsed().as_secs_f64()),
+        Err(error) => {
+            tracing::warn!(%error, pool = name, "Failed to acquire a database connection")
+        }
+    }
+}
+
+/// Records the metrics of `pgpool` every [POOL_METRICS_INTERVAL]. Never returns.
+pub async fn monitor_pool(pgpool: PgPool, name: &'static str) {
+    let mut interval = tokio::time::interval(POOL_METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+        record_pool_metrics(&pgpool, name).await;
+    }
+}
+
+/// Runs `operation` inside a `REPEATABLE READ` transaction and commits it.
+///
+/// All statements executed by `operation` see the same snapshot of the database.
+/// If the transaction fails because of a concurrent update (serialization failure),
+/// it's rolled back and `operation` is executed again, up to [MAX_SERIALIZATION_RETRIES] times.
+pub async fn repeatable_read<T, F>(pgpool: &PgPool, mut operation: F) -> Result<T, sqlx::Error>
+where
+    F: for<'c> FnMut(&'c mut PgConnection) -> BoxFuture<'c, Result<T, sqlx::Error>>,
+{
+    let mut retries = 0;
+    loop {
+        let result = async {
+            let mut transaction = pgpool.begin().await?;
+            sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+                .execute(&mut *transaction)
+                .await?;
+            let value = operation(&mut *transaction).await?;
+            transaction.commit().await?;
+            Ok::<_, sqlx::Error>(value)
+        }
+        .await;
+
+        match result {
+            Err(error)
+                if is_serialization_failure(&error) && retries < MAX_SERIALIZATION_RETRIES =>
+            {
+                retries += 1;
+                tracing::warn!(
+                    %error,
+                    retries,
+                    "Serialization failure while running repeatable read transaction, retrying"
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Rows deleted by [prune_stale_failures]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PrunedFailures {
+    pub failed_rav_requests: u64,
+    pub invalid_receipts: u64,
+}
+
+/// Deletes the failed RAV requests and invalid receipts older than `retention`
+///
+/// They are only kept for debugging, but the invalid receipts are summed up
+/// by every allocation when the trackers are built, so startup gets slower
+/// as they pile up. Invalid receipts are aged by their own timestamp.
+///
+/// The [FeeSnapshot]s are deleted if any invalid receipt is, since their
+/// sums would still include it.
+pub async fn prune_stale_failures(
+    pgpool: &PgPool,
+    retention: Duration,
+) -> Result<PrunedFailures, sqlx::Error> {
+    let retention_secs = retention.as_secs_f64();
+    let mut pruned = PrunedFailures::default();
+    let mut transaction = pgpool.begin().await?;
+
+    pruned.failed_rav_requests += sqlx::query!(
+        r#"
+            DELETE FROM scalar_tap_rav_requests_failed
+            WHERE created_at < NOW() - make_interval(secs => $1)
+        "#,
+        retention_secs
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    pruned.failed_rav_requests += sqlx::query!(
+        r#"
+            DELETE FROM tap_horizon_rav_requests_failed
+            WHERE created_at < NOW() - make_interval(secs => $1)
+        "#,
+        retention_secs
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    pruned.invalid_receipts += sqlx::query!(
+        r#"
+            DELETE FROM scalar_tap_receipts_invalid
+            WHERE timestamp_ns <
+                EXTRACT(EPOCH FROM NOW() - make_interval(secs => $1)) * 1000000000
+        "#,
+        retention_secs
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    pruned.invalid_receipts += sqlx::query!(
+        r#"
+            DELETE FROM tap_horizon_receipts_invalid
+            WHERE timestamp_ns <
+                EXTRACT(EPOCH FROM NOW() - make_interval(secs => $1)) * 1000000000
+        "#,
+        retention_secs
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    if pruned.invalid_receipts > 0 {
+        sqlx::query!("DELETE FROM tap_agent_state")
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(pruned)
+}
+
+/// Fees of an allocation and sender saved periodically by its
+/// [crate::agent::sender_allocation::SenderAllocation]
+///
+/// The receipts are summed up to the `last_id` of each [UnaggregatedReceipts],
+/// so only the receipts after it are read when the allocation starts again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeeSnapshot {
+    /// Receipts not aggregated in a RAV yet
+    pub unaggregated_fees: UnaggregatedReceipts,
+    /// Receipts that failed the checks of a RAV request
+    pub invalid_receipts_fees: UnaggregatedReceipts,
+    /// Timestamp of the last RAV when the snapshot was taken
+    ///
+    /// The receipts it covers are deleted once a newer RAV is received,
+    /// so the snapshot can only be used if it's still the last one.
+    pub rav_timestamp_ns: u64,
+}
+
+struct FeeSnapshotRow {
+    rav_timestamp_ns: String,
+    last_id: i64,
+    value: String,
+    counter: i64,
+    invalid_last_id: i64,
+    invalid_value: String,
+    invalid_counter: i64,
+}
+
+impl TryFrom<FeeSnapshotRow> for FeeSnapshot {
+    type Error = anyhow::Error;
+
+    fn try_from(row: FeeSnapshotRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            unaggregated_fees: UnaggregatedReceipts {
+                value: row.value.parse()?,
+                last_id: row.last_id.try_into()?,
+                counter: row.counter.try_into()?,
+            },
+            invalid_receipts_fees: UnaggregatedReceipts {
+                value: row.invalid_value.parse()?,
+                last_id: row.invalid_last_id.try_into()?,
+                counter: row.invalid_counter.try_into()?,
+            },
+            rav_timestamp_ns: row.rav_timestamp_ns.parse()?,
+        })
+    }
+}
+
+/// Saves the [FeeSnapshot] of `allocation_id` and `sender`, replacing the previous one
+///
+/// `version` is `v1` for legacy receipts and `v2` for horizon receipts.
+pub async fn store_fee_snapshot(
+    pgpool: &PgPool,
+    version: &str,
+    sender: Address,
+    allocation_id: Address,
+    snapshot: &FeeSnapshot,
+) -> anyhow::Result<()> {
+    let FeeSnapshot {
+        unaggregated_fees,
+        invalid_receipts_fees,
+        rav_timestamp_ns,
+    } = snapshot;
+    sqlx::query!(
+        r#"
+            INSERT INTO tap_agent_state (
+                version,
+                sender_address,
+                allocation_id,
+                rav_timestamp_ns,
+                last_id,
+                value,
+                counter,
+                invalid_last_id,
+                invalid_value,
+                invalid_counter
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            ON CONFLICT (version, sender_address, allocation_id) DO UPDATE SET
+                rav_timestamp_ns = EXCLUDED.rav_timestamp_ns,
+                last_id = EXCLUDED.last_id,
+                value = EXCLUDED.value,
+                counter = EXCLUDED.counter,
+                invalid_last_id = EXCLUDED.invalid_last_id,
+                invalid_value = EXCLUDED.invalid_value,
+                invalid_counter = EXCLUDED.invalid_counter,
+                updated_at = NOW()
+        "#,
+        version,
+        sender.encode_hex(),
+        allocation_id.encode_hex(),
+        BigDecimal::from(*rav_timestamp_ns),
+        i64::try_from(unaggregated_fees.last_id)?,
+        BigDecimal::from(BigInt::from(unaggregated_fees.value)),
+        i64::try_from(unaggregated_fees.counter)?,
+        i64::try_from(invalid_receipts_fees.last_id)?,
+        BigDecimal::from(BigInt::from(invalid_receipts_fees.value)),
+        i64::try_from(invalid_receipts_fees.counter)?,
+    )
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+/// Returns the [FeeSnapshot] of `allocation_id` and `sender` if it was
+/// saved less than `max_age` ago
+pub async fn load_fee_snapshot(
+    pgpool: &PgPool,
+    version: &str,
+    sender: Address,
+    allocation_id: Address,
+    max_age: Duration,
+) -> anyhow::Result<Option<FeeSnapshot>> {
+    let row = sqlx::query_as!(
+        FeeSnapshotRow,
+        r#"
+            SELECT
+                rav_timestamp_ns::TEXT AS "rav_timestamp_ns!",
+                last_id,
+                value::TEXT AS "value!",
+                counter,
+                invalid_last_id,
+                invalid_value::TEXT AS "invalid_value!",
+                invalid_counter
+            FROM tap_agent_state
+            WHERE version = $1
+                AND sender_address = $2
+                AND allocation_id = $3
+                AND updated_at > NOW() - make_interval(secs => $4)
+        "#,
+        version,
+        sender.encode_hex(),
+        allocation_id.encode_hex(),
+        max_age.as_secs_f64(),
+    )
+    .fetch_optional(pgpool)
+    .await?;
+    row.map(FeeSnapshot::try_from).transpose()
+}
+
+/// Deletes the [FeeSnapshot] of `allocation_id` and `sender`, once its last RAV is requested
+pub async fn delete_fee_snapshot(
+    pgpool: &PgPool,
+    version: &str,
+    sender: Address,
+    allocation_id: Address,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+            DELETE FROM tap_agent_state
+            WHERE version = $1 AND sender_address = $2 AND allocation_id = $3
+        "#,
+        version,
+        sender.encode_hex(),
+        allocation_id.encode_hex(),
+    )
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+/// Records that the last RAVs of `allocation_id` are requested, the service
+/// refuses its new receipts from then on since they would never be redeemed
+///
+/// The allocations closing for longer than [CLOSING_ALLOCATIONS_RETENTION]
+/// are removed, their receipts are refused as the receipts of any closed
+/// allocation by then.
+pub async fn mark_allocation_closing(
+    pgpool: &PgPool,
+    allocation_id: Address,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM closing_allocations WHERE closing_since < NOW() - $1::INTERVAL",
+        CLOSING_ALLOCATIONS_RETENTION
+    )
+    .execute(pgpool)
+    .await?;
+    sqlx::query!(
+        r#"
+            INSERT INTO closing_allocations (allocation_id)
+            VALUES ($1)
+            ON CONFLICT (allocation_id) DO NOTHING
+        "#,
+        allocation_id.encode_hex()
+    )
+    .execute(pgpool)
+    .await?;
+    Ok(())
+}
+
+/// Deletes the `scalar_tap_receipts` of the `width` ranges that ended more
+/// than `retention` before `now_ns` and that are fully covered by final RAVs,
+/// returns the start and end of the cleared ranges
+///
+/// Receipts don't have a sender, the sender of their signer is found in
+/// `escrow_accounts`. A range is covered when every (sender, allocation) with
+/// receipts in it has a final RAV not older than its last receipt, a range
+/// with receipts of an unknown signer is kept. The table is shared with
+/// indexer-agent, its schema is left as it is.
+pub async fn prune_covered_receipts(
+    pgpool: &PgPool,
+    width: Duration,
+    retention: Duration,
+    now_ns: u64,
+    escrow_accounts: &EscrowAccounts,
+) -> Result<Vec<(u64, u64)>, sqlx::Error> {
+    let width_ns = width.as_nanos() as u64;
+    let retention_ns = retention.as_nanos() as u64;
+    let mut after = BigDecimal::from(0);
+    let mut pruned = Vec::new();
+
+    loop {
+        // skips the empty ranges
+        let first = sqlx::query_scalar!(
+            r#"
+                SELECT MIN(timestamp_ns)
+                FROM scalar_tap_receipts
+                WHERE timestamp_ns >= $1
+            "#,
+            after,
+        )
+        .fetch_one(pgpool)
+        .await?;
+        let Some(start) = first.and_then(|first| first.to_u64()) else {
+            break;
+        };
+        let start = start / width_ns * width_ns;
+        let end = start + width_ns;
+        if end.saturating_add(retention_ns) > now_ns {
+            break;
+        }
+        after = BigDecimal::from(end);
+
+        let mut transaction = pgpool.begin().await?;
+        let last_receipts = sqlx::query!(
+            r#"
+                SELECT
+                    signer_address,
+                    allocation_id,
+                    MAX(timestamp_ns) AS "timestamp_ns!",
+                    MAX(id) AS "last_id!"
+                FROM scalar_tap_receipts
+                WHERE timestamp_ns >= $1 AND timestamp_ns < $2
+                GROUP BY signer_address, allocation_id
+            "#,
+            BigDecimal::from(start),
+            BigDecimal::from(end),
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        let mut senders = Vec::with_capacity(last_receipts.len());
+        let mut allocation_ids = Vec::with_capacity(last_receipts.len());
+        let mut timestamps_ns = Vec::with_capacity(last_receipts.len());
+        let mut last_id = 0;
+        let mut known = true;
+        for receipt in last_receipts {
+            let sender = Address::from_str(receipt.signer_address.trim())
+                .ok()
+                .and_then(|signer| escrow_accounts.get_sender_for_any_signer(&signer).ok());
+            let Some(sender) = sender else {
+                tracing::debug!(
+                    start,
+                    end,
+                    signer = %receipt.signer_address,
+                    "Receipts kept, the sender of a signer is unknown"
+                );
+                known = false;
+                break;
+            };
+            senders.push(sender.encode_hex());
+            allocation_ids.push(receipt.allocation_id);
+            timestamps_ns.push(receipt.timestamp_ns);
+            last_id = last_id.max(receipt.last_id);
+        }
+        if !known {
+            continue;
+        }
+
+        let covered = sqlx::query_scalar!(
+            r#"
+                SELECT NOT EXISTS (
+                    SELECT 1
+                    FROM UNNEST($1::CHAR(40)[], $2::CHAR(40)[], $3::NUMERIC(20)[])
+                        AS receipts(sender_address, allocation_id, timestamp_ns)
+                    WHERE NOT EXISTS (
+                        SELECT 1 FROM scalar_tap_ravs ravs
+                        WHERE ravs.sender_address = receipts.sender_address
+                            AND ravs.allocation_id = receipts.allocation_id
+                            AND ravs.final
+                            AND ravs.timestamp_ns >= receipts.timestamp_ns
+                    )
+                ) AS "covered!"
+            "#,
+            &senders,
+            &allocation_ids,
+            &timestamps_ns,
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        if covered {
+            // a receipt stored since the check wasn't looked at
+            sqlx::query!(
+                r#"
+                    DELETE FROM scalar_tap_receipts
+                    WHERE timestamp_ns >= $1 AND timestamp_ns < $2 AND id <= $3
+                "#,
+                BigDecimal::from(start),
+                BigDecimal::from(end),
+                last_id,
+            )
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await?;
+            pruned.push((start, end));
+        }
+    }
+    Ok(pruned)
+}
+
+/// Deletes the receipts covered by final RAVs every half `width`, at most an
+/// hour apart. Never returns.
+pub async fn maintain_receipt_pruning(
+    pgpool: PgPool,
+    width: Duration,
+    retention: Duration,
+    escrow_accounts: watch::Receiver<EscrowAccounts>,
+) {
+    let mut interval = tokio::time::interval((width / 2).min(MAX_RECEIPT_PRUNING_INTERVAL));
+    loop {
+        interval.tick().await;
+        let now_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        let accounts = escrow_accounts.borrow().clone();
+        match prune_covered_receipts(&pgpool, width, retention, now_ns, &accounts).await {
+            Ok(pruned) if !pruned.is_empty() => {
+                tracing::info!(?pruned, "Deleted the receipts covered by final RAVs")
+            }
+            Ok(_) => {}
+            Err(error) => tracing::warn!(%error, "Failed to delete the covered receipts"),
+        }
+    }
+}
+
+fn is_serialization_failure(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(error) => error.code().as_deref() == Some(SERIALIZATION_FAILURE),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use sqlx::{types::BigDecimal, PgPool};
+
+    use indexer_monitor::EscrowAccounts;
+    use test_assets::{
+        ALLOCATION_ID_0, ESCROW_ACCOUNTS_BALANCES, ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS, TAP_SENDER,
+        TAP_SIGNER,
+    };
+    use thegraph_core::alloy::{
+        hex::ToHexExt,
+        primitives::{address, Address},
+    };
+
+    use super::{
+        load_fee_snapshot, prune_covered_receipts, prune_stale_failures, record_pool_metrics,
+        repeatable_read, store_fee_snapshot, FeeSnapshot, PrunedFailures, FEE_SNAPSHOT_TABLE,
+        POOL_ACQUIRE, POOL_CONNECTIONS, RAV_REDEMPTIONS_TABLE, REQUIRED_TABLES,
+    };
+    use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_required_tables_migrated(pgpool: PgPool) {
+        let mut required = REQUIRED_TABLES.to_vec();
+        required.extend([FEE_SNAPSHOT_TABLE, RAV_REDEMPTIONS_TABLE]);
+        let diff = indexer_schema::check_schema(&pgpool, &required)
+            .await
+            .unwrap();
+        assert!(!diff.is_incompatible(), "{diff}");
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_record_pool_metrics(pgpool: PgPool) {
+        let connection = pgpool.acquire().await.unwrap();
+        record_pool_metrics(&pgpool, "test").await;
+
+        assert_eq!(
+            POOL_CONNECTIONS
+                .with_label_values(&["test", "in_use"])
+                .get(),
+            1
+        );
+        assert_eq!(
+            POOL_ACQUIRE.with_label_values(&["test"]).get_sample_count(),
+            1
+        );
+        drop(connection);
+    }
+
+    /// A row updated by another connection after the transaction snapshot was taken
+    /// causes a serialization failure, the retry must see the concurrent update.
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_repeatable_read_retries_serialization_failure(pgpool: PgPool) {
+        // the table only exists in this test, its queries can't be checked at compile time
+        sqlx::query("CREATE TABLE counter (id INT PRIMARY KEY, value INT NOT NULL)")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO counter VALUES (1, 0)")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+
+        let attempts = Arc::new(AtomicU32::new(0));
+        let value = repeatable_read(&pgpool, |conn| {
+            let pgpool = pgpool.clone();
+            let attempts = attempts.clone();
+            Box::pin(async move {
+                let value: i32 = sqlx::query_scalar("SELECT value FROM counter WHERE id = 1")
+                    .fetch_one(&mut *conn)
+                    .await?;
+                if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    sqlx::query("UPDATE counter SET value = value + 10 WHERE id = 1")
+                        .execute(&pgpool)
+                        .await?;
+                }
+                sqlx::query("UPDATE counter SET value = $1 WHERE id = 1")
+                    .bind(value + 1)
+                    .execute(&mut *conn)
+                    .await?;
+                Ok(value + 1)
+            })
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(value, 11);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_prune_stale_failures(pgpool: PgPool) {
+        for (allocation_id, age_days) in [("1", 60), ("2", 1)] {
+            sqlx::query!(
+                r#"
+                    INSERT INTO scalar_tap_rav_requests_failed
+                        (allocation_id, sender_address, expected_rav, rav_response, reason, created_at)
+                    VALUES ($1, '', '{}', '{}', '', NOW() - make_interval(days => $2))
+                "#,
+                allocation_id,
+                age_days,
+            )
+            .execute(&pgpool)
+            .await
+            .unwrap();
+
+            sqlx::query!(
+                r#"
+                    INSERT INTO scalar_tap_receipts_invalid
+                        (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+                    VALUES (
+                        '', '', $1,
+                        EXTRACT(EPOCH FROM NOW() - make_interval(days => $2)) * 1000000000, 0, 1
+                    )
+                "#,
+                allocation_id,
+                age_days,
+            )
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+
+        let pruned = prune_stale_failures(&pgpool, Duration::from_secs(30 * 24 * 60 * 60))
+            .await
+            .unwrap();
+        assert_eq!(
+            pruned,
+            PrunedFailures {
+                failed_rav_requests: 1,
+                invalid_receipts: 1,
+            }
+        );
+
+        let remaining = sqlx::query_scalar!(
+            r#"
+                SELECT allocation_id AS "allocation_id!" FROM scalar_tap_rav_requests_failed
+                UNION ALL SELECT allocation_id FROM scalar_tap_receipts_invalid
+            "#
+        )
+        .fetch_all(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining.iter().all(|id| id.trim() == "2"));
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_fee_snapshot(pgpool: PgPool) {
+        let max_age = Duration::from_secs(60);
+        let snapshot = FeeSnapshot {
+            unaggregated_fees: UnaggregatedReceipts {
+                value: u128::MAX,
+                last_id: 10,
+                counter: 3,
+            },
+            invalid_receipts_fees: UnaggregatedReceipts {
+                value: 5,
+                last_id: 2,
+                counter: 1,
+            },
+            rav_timestamp_ns: 1_700_000_000_000_000_000,
+        };
+        store_fee_snapshot(&pgpool, "v1", TAP_SENDER.1, ALLOCATION_ID_0, &snapshot)
+            .await
+            .unwrap();
+        // replaces the previous snapshot
+        let snapshot = FeeSnapshot {
+            rav_timestamp_ns: snapshot.rav_timestamp_ns + 1,
+            ..snapshot
+        };
+        store_fee_snapshot(&pgpool, "v1", TAP_SENDER.1, ALLOCATION_ID_0, &snapshot)
+            .await
+            .unwrap();
+
+        let loaded = load_fee_snapshot(&pgpool, "v1", TAP_SENDER.1, ALLOCATION_ID_0, max_age)
+            .await
+            .unwrap();
+        assert_eq!(loaded, Some(snapshot));
+        let other_version =
+            load_fee_snapshot(&pgpool, "v2", TAP_SENDER.1, ALLOCATION_ID_0, max_age)
+                .await
+                .unwrap();
+        assert_eq!(other_version, None);
+
+        sqlx::query!("UPDATE tap_agent_state SET updated_at = NOW() - INTERVAL '2 minutes'")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let stale = load_fee_snapshot(&pgpool, "v1", TAP_SENDER.1, ALLOCATION_ID_0, max_age)
+            .await
+            .unwrap();
+        assert_eq!(stale, None);
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_prune_covered_receipts(pgpool: PgPool) {
+        let width = Duration::from_nanos(1000);
+        // allocations 1 and 3 are covered by final RAVs, allocation 2 is not yet
+        for (allocation_id, timestamp_ns, final_rav) in
+            [("1", 1100, true), ("2", 2100, false), ("3", 3100, true)]
+        {
+            insert_receipt(&pgpool, TAP_SIGNER.1, allocation_id, timestamp_ns).await;
+            insert_rav(
+                &pgpool,
+                TAP_SENDER.1,
+                allocation_id,
+                timestamp_ns + 100,
+                final_rav,
+            )
+            .await;
+        }
+
+        // the range of allocation 3 didn't end long enough ago
+        let pruned = prune_covered_receipts(
+            &pgpool,
+            width,
+            Duration::from_nanos(500),
+            4000,
+            &escrow_accounts(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pruned, [(1000, 2000)]);
+
+        let pruned =
+            prune_covered_receipts(&pgpool, width, Duration::ZERO, 4000, &escrow_accounts())
+                .await
+                .unwrap();
+        assert_eq!(pruned, [(3000, 4000)]);
+
+        let remaining = sqlx::query_scalar!("SELECT allocation_id FROM scalar_tap_receipts")
+            .fetch_all(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].trim(), "2");
+    }
+
+    fn escrow_accounts() -> EscrowAccounts {
+        EscrowAccounts::new(
+            ESCROW_ACCOUNTS_BALANCES.to_owned(),
+            ESCROW_ACCOUNTS_SENDERS_TO_SIGNERS.to_owned(),
+        )
+    }
+
+    async fn insert_receipt(
+        pgpool: &PgPool,
+        signer: Address,
+        allocation_id: &str,
+        timestamp_ns: i64,
+    ) {
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_receipts
+                    (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+                VALUES ($1, '', $2, $3, 0, 1)
+            "#,
+            signer.encode_hex(),
+            allocation_id,
+            BigDecimal::from(timestamp_ns),
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    async fn insert_rav(
+        pgpool: &PgPool,
+        sender: Address,
+        allocation_id: &str,
+        timestamp_ns: i64,
+        final_rav: bool,
+    ) {
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_ravs
+                    (sender_address, signature, allocation_id, timestamp_ns, value_aggregate, last, final)
+                VALUES ($1, '', $2, $3, 1, TRUE, $4)
+            "#,
+            sender.encode_hex(),
+            allocation_id,
+            BigDecimal::from(timestamp_ns),
+            final_rav,
+        )
+        .execute(pgpool)
+        .await
+        .unwrap();
+    }
+
+    /// The final RAV of a sender doesn't cover the receipts of another sender
+    /// for the same allocation, nor the receipts of an unknown signer
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_receipts_are_covered_per_sender(pgpool: PgPool) {
+        let other_signer = address!("245059163ff6ee14279aa7b35ea8f0fdb967df6e");
+        let other_sender = address!("22d491bde2303f2f43325b2108d26f1eaba1e32b");
+        // only the ranges that ended before `now_ns` are expired
+        let prune = |now_ns| async move {
+            prune_covered_receipts(
+                &pgpool,
+                Duration::from_nanos(1000),
+                Duration::ZERO,
+                now_ns,
+                &escrow_accounts(),
+            )
+            .await
+            .unwrap()
+        };
+
+        // both senders have receipts for the allocation, only one is final
+        insert_receipt(&pgpool, TAP_SIGNER.1, "1", 1100).await;
+        insert_receipt(&pgpool, other_signer, "1", 1200).await;
+        insert_rav(&pgpool, TAP_SENDER.1, "1", 1500, true).await;
+        assert!(prune(2000).await.is_empty());
+
+        // the RAV of the other sender is final but older than its last receipt
+        insert_rav(&pgpool, other_sender, "1", 1100, true).await;
+        assert!(prune(2000).await.is_empty());
+
+        sqlx::query!(
+            "UPDATE scalar_tap_ravs SET timestamp_ns = 1300 WHERE sender_address = $1",
+            other_sender.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        assert_eq!(prune(2000).await, [(1000, 2000)]);
+
+        // the sender of an unknown signer can't be covered
+        insert_receipt(&pgpool, Address::ZERO, "1", 2100).await;
+        insert_rav(&pgpool, TAP_SENDER.1, "2", 2500, true).await;
+        assert!(prune(3000).await.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sanity check of the TAP EIP-712 domain
+//!
+//! The signer of a receipt is recovered with the domain built from
+//! `blockchain.chain_id` and `blockchain.receipts_verifier_address`. With
+//! the wrong values, every receipt recovers to an unknown address and all of
+//! them end up invalid, without anything pointing at the configuration.
+//!
+//! The receipts stored by indexer-service were accepted with its own domain,
+//! along with their signer. [monitor_domain] recovers the signer of the most
+//! recent ones again with the domain of tap-agent to compare them. The
+//! receipts rejected by tap-agent are sampled too, with a wrong domain they
+//! all end up there.
+
+use std::{collections::HashSet, str::FromStr, time::Duration};
+
+use anyhow::Context as _;
+use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use indexer_config::TheGraphChainId;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge};
+use sqlx::{types::BigDecimal, PgPool};
+use tap_core::tap_eip712_domain;
+use tap_graph::{Receipt, SignedReceipt};
+use thegraph_core::alloy::primitives::Address;
+
+/// Number of receipts recovered again by each check
+const SAMPLE_SIZE: i64 = 20;
+
+/// Time between two checks
+const DOMAIN_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+lazy_static! {
+    static ref DOMAIN_MISMATCH: IntGauge = register_int_gauge!(
+        "tap_eip712_domain_mismatch",
+        "Recent receipts were not signed for the configured EIP-712 domain"
+    )
+    .unwrap();
+}
+
+struct SampledReceiptRow {
+    signer_address: String,
+    signature: Vec<u8>,
+    allocation_id: String,
+    timestamp_ns: BigDecimal,
+    nonce: BigDecimal,
+    value: BigDecimal,
+}
+
+impl SampledReceiptRow {
+    /// Signer the receipt was stored with and the receipt
+    fn into_receipt(self) -> anyhow::Result<(Address, SignedReceipt)> {
+        let receipt = SignedReceipt {
+            message: Receipt {
+                allocation_id: Address::from_str(&self.allocation_id)?,
+                timestamp_ns: self.timestamp_ns.to_u64().context("Invalid timestamp_ns")?,
+                nonce: self.nonce.to_u64().context("Invalid nonce")?,
+                // BigDecimal::to_u128() goes through u64
+                value: self
+                    .value
+                    .to_bigint()
+                    .and_then(|value| value.to_u128())
+                    .context("Invalid value")?,
+            },
+            signature: self.signature.as_slice().try_into()?,
+        };
+        Ok((Address::from_str(&self.signer_address)?, receipt))
+    }
+}
+
+/// Result of [check_domain]
+#[derive(Debug, PartialEq, Eq)]
+pub enum DomainCheck {
+    /// No receipt was stored yet
+    NoReceipts,
+    /// The receipts were signed for the domain
+    Matching,
+    /// None of the receipts recover to the signer they were stored with
+    Mismatching {
+        /// Number of receipts recovered
+        sampled: usize,
+        /// Chain the receipts were signed for, if the verifier is the same
+        signed_chain_id: Option<u64>,
+    },
+}
+
+/// Recovers the signers of the most recent receipts, accepted or rejected,
+/// with the domain of `chain_id` and `verifier`
+pub async fn check_domain(
+    pgpool: &PgPool,
+    chain_id: u64,
+    verifier: Address,
+) -> anyhow::Result<DomainCheck> {
+    let rows = sqlx::query_as!(
+        SampledReceiptRow,
+        r#"
+            SELECT
+                signer_address AS "signer_address!",
+                signature AS "signature!",
+                allocation_id AS "allocation_id!",
+                timestamp_ns AS "timestamp_ns!",
+                nonce AS "nonce!",
+                value AS "value!"
+            FROM (
+                (
+                    SELECT signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                    FROM scalar_tap_receipts
+                    ORDER BY timestamp_ns DESC
+                    LIMIT $1
+                )
+                UNION ALL
+                (
+                    SELECT signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                    FROM scalar_tap_receipts_invalid
+                    ORDER BY timestamp_ns DESC
+                    LIMIT $1
+                )
+            ) AS sampled
+            ORDER BY timestamp_ns DESC
+            LIMIT $1
+        "#,
+        SAMPLE_SIZE
+    )
+    .fetch_all(pgpool)
+    .await?;
+    let receipts = rows
+        .into_iter()
+        .map(SampledReceiptRow::into_receipt)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if receipts.is_empty() {
+        return Ok(DomainCheck::NoReceipts);
+    }
+
+    let domain = tap_eip712_domain(chain_id, verifier);
+    let mut recovered_signers = HashSet::new();
+    for (signer, receipt) in &receipts {
+        let recovered = receipt.recover_signer(&domain)?;
+        // The wallet is stored instead of the key of receipts signed for a
+        // contract wallet. Such a key signs many receipts, while a wrong
+        // domain recovers a different address every time
+        if recovered == *signer || !recovered_signers.insert(recovered) {
+            return Ok(DomainCheck::Matching);
+        }
+    }
+
+    // tried to tell which chain the receipts were signed for
+    let signed_chain_id = TheGraphChainId::ALL
+        .into_iter()
+        .map(|known| known as u64)
+        .filter(|known| *known != chain_id)
+        .find(|known| {
+            let domain = tap_eip712_domain(*known, verifier);
+            receipts.iter().any(|(signer, receipt)| {
+                receipt
+                    .recover_signer(&domain)
+                    .is_ok_and(|recovered| recovered == *signer)
+            })
+        });
+    Ok(DomainCheck::Mismatching {
+        sampled: receipts.len(),
+        signed_chain_id,
+    })
+}
+
+/// Checks the domain when started and every [DOMAIN_CHECK_INTERVAL]. Never
+/// returns.
+pub async fn monitor_domain(pgpool: PgPool, chain_id: u64, verifier: Address) {
+    let mut interval = tokio::time::interval(DOMAIN_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        match check_domain(&pgpool, chain_id, verifier).await {
+            Ok(DomainCheck::Mismatching {
+                sampled,
+                signed_chain_id: Some(signed_chain_id),
+            }) => {
+                DOMAIN_MISMATCH.set(1);
+                tracing::error!(
+                    sampled,
+                    chain_id,
+                    signed_chain_id,
+                    "The recent receipts were signed for another chain, blockchain.chain_id \
+                    is likely wrong and every receipt will be invalid"
+                );
+            }
+            Ok(DomainCheck::Mismatching { sampled, .. }) => {
+                DOMAIN_MISMATCH.set(1);
+                tracing::error!(
+                    sampled,
+                    chain_id,
+                    %verifier,
+                    "The recent receipts were not signed for the configured EIP-712 domain, \
+                    blockchain.chain_id or blockchain.receipts_verifier_address is likely \
+                    wrong and every receipt will be invalid"
+                );
+            }
+            Ok(_) => DOMAIN_MISMATCH.set(0),
+            Err(error) => {
+                tracing::warn!(%error, "Failed to check the EIP-712 domain of the recent receipts")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use test_assets::{ALLOCATION_ID_0, TAP_SIGNER as SIGNER};
+    use thegraph_core::alloy::primitives::Address;
+
+    use super::{check_domain, DomainCheck};
+    use crate::test::{create_received_receipt, store_receipt};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_check_domain(pgpool: PgPool) {
+        // receipts of the test assets are signed for chain 1
+        let verifier = Address::from([0x11u8; 20]);
+        assert_eq!(
+            check_domain(&pgpool, 1, verifier).await.unwrap(),
+            DomainCheck::NoReceipts
+        );
+
+        for nonce in 0..3 {
+            let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, nonce, nonce, 10);
+            store_receipt(&pgpool, receipt.signed_receipt())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            check_domain(&pgpool, 1, verifier).await.unwrap(),
+            DomainCheck::Matching
+        );
+        assert_eq!(
+            check_domain(&pgpool, 42161, verifier).await.unwrap(),
+            DomainCheck::Mismatching {
+                sampled: 3,
+                signed_chain_id: Some(1)
+            }
+        );
+        assert_eq!(
+            check_domain(&pgpool, 1, Address::repeat_byte(0x22))
+                .await
+                .unwrap(),
+            DomainCheck::Mismatching {
+                sampled: 3,
+                signed_chain_id: None
+            }
+        );
+
+        // with a wrong domain, tap-agent rejects every receipt
+        sqlx::query!(
+            r#"
+                INSERT INTO scalar_tap_receipts_invalid
+                    (signer_address, signature, allocation_id, timestamp_ns, nonce, value)
+                SELECT signer_address, signature, allocation_id, timestamp_ns, nonce, value
+                FROM scalar_tap_receipts
+            "#
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        sqlx::query!("DELETE FROM scalar_tap_receipts")
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        assert_eq!(
+            check_domain(&pgpool, 42161, verifier).await.unwrap(),
+            DomainCheck::Mismatching {
+                sampled: 3,
+                signed_chain_id: Some(1)
+            }
+        );
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tap-agent running in the process of indexer-service
+//!
+//! Small indexers can run the agent along with the service instead of
+//! deploying both, with `indexer-service --with-tap-agent`. The agent uses
+//! the database pool of the service and reads the configuration given to
+//! [start] instead of parsing its command line. Both register their metrics
+//! in the same registry, so the routes of the agent are served on the
+//! metrics port of the service.
+//!
+//! Every replica of the service started with `--with-tap-agent` runs an
+//! agent, so several replicas must set `tap.leader_election`: only the
+//! leader starts its agent, the others serve queries as standbys (see
+//! [crate::leader_election]). Without leader election, the agent holds a
+//! lock of its own and a second replica exits instead of running a second
+//! agent.
+//!
+//! With `tap.sharding`, the embedded agent only manages the senders of its
+//! shard, like a standalone one: the replicas of each shard need their own
+//! `shard_index`, and elect their leader among themselves. The receipts of
+//! the other senders are left to the agents of their shards.
+
+use std::sync::{Arc, OnceLock};
+
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Router,
+};
+use indexer_config::Config;
+use ractor::{ActorRef, ActorStatus};
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use tower::ServiceExt;
+
+use crate::{
+    agent::{sender_accounts_manager::SenderAccountsManagerMessage, start_agent_with_pool},
+    events, leader_election, metrics, CONFIG, EMBEDDED_CONFIG,
+};
+
+/// Actor tree and routes of the agent, once started
+struct Started {
+    manager: ActorRef<SenderAccountsManagerMessage>,
+    routes: Router,
+}
+
+/// Agent started by [start]
+pub struct EmbeddedAgent {
+    /// Unset as long as this replica is a standby
+    started: Arc<OnceLock<Started>>,
+    /// Starts the agent and exits the process when it stops
+    supervisor: JoinHandle<()>,
+}
+
+/// Starts the agent with `config`
+///
+/// With `tap.leader_election`, the agent only starts once this replica is
+/// the leader, in the background. The process exits if the actor tree
+/// stops or the leadership is lost, the same way a standalone tap-agent
+/// does.
+///
+/// # Panics
+///
+/// If [crate::CONFIG] was already read.
+pub async fn start(config: Config, pgpool: PgPool, strict_schema: bool) -> EmbeddedAgent {
+    *EMBEDDED_CONFIG.lock().unwrap() = Some(config);
+    lazy_static::initialize(&CONFIG);
+    assert!(
+        EMBEDDED_CONFIG.lock().unwrap().is_none(),
+        "The configuration of tap-agent was already loaded"
+    );
+
+    let started = Arc::new(OnceLock::new());
+    let supervisor = tokio::spawn(supervise(started.clone(), pgpool, strict_schema));
+    EmbeddedAgent {
+        started,
+        supervisor,
+    }
+}
+
+async fn supervise(started: Arc<OnceLock<Started>>, pgpool: PgPool, strict_schema: bool) {
+    let leadership = match &CONFIG.tap.leader_election {
+        Some(config) => Some(
+            leader_election::wait_for_leadership(
+                CONFIG.database.clone(),
+                config,
+                CONFIG.tap.sharding.as_ref(),
+            )
+            .await,
+        ),
+        None => {
+            match leader_election::single_instance(
+                CONFIG.database.clone(),
+                CONFIG.tap.sharding.as_ref(),
+            )
+            .await
+            {
+                Ok(leadership) => Some(leadership),
+                Err(error) => {
+                    tracing::error!(%error, "Failed to start tap-agent");
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    let _shard_layout = leader_election::wait_for_shard_layout(
+        CONFIG.database.clone(),
+        CONFIG.tap.sharding.as_ref(),
+    )
+    .await;
+
+    if let Some(events) = &CONFIG.events {
+        events::start(events)
+            .await
+            .expect("Failed to connect to the events broker");
+    }
+
+    let (manager, handle) = match start_agent_with_pool(pgpool.clone(), strict_schema).await {
+        Ok(started) => started,
+        Err(error) => {
+            tracing::error!(%error, "Failed to start tap-agent");
+            std::process::exit(1);
+        }
+    };
+    let routes = metrics::routes(manager.clone(), pgpool);
+    let _ = started.set(Started { manager, routes });
+    tracing::info!("TAP Agent started.");
+
+    tokio::select! {
+        _ = handle => tracing::error!("SenderAccountsManager stopped"),
+        // Restarted as a standby, since another replica may be the leader by now
+        _ = leader_election::leadership_lost(leadership) => tracing::error!("Lost the leadership"),
+    }
+    std::process::exit(1);
+}
+
+impl EmbeddedAgent {
+    /// Routes of the agent served along with `/metrics`
+    ///
+    /// They answer `503 Service Unavailable` while this replica is a standby.
+    pub fn routes(&self) -> Router {
+        let started = self.started.clone();
+        Router::new().fallback(move |request: Request| {
+            let started = started.clone();
+            async move {
+                match started.get() {
+                    Some(started) => started
+                        .routes
+                        .clone()
+                        .oneshot(request)
+                        .await
+                        .into_response(),
+                    None => standby(),
+                }
+            }
+        })
+    }
+
+    /// Stops the actor tree without running its shutdown logic
+    pub async fn stop(self) {
+        self.supervisor.abort();
+        if let Some(started) = self.started.get() {
+            if started.manager.get_status() == ActorStatus::Running {
+                started
+                    .manager
+                    .kill_and_wait(None)
+                    .await
+                    .expect("Failed to kill manager.");
+            }
+        }
+    }
+}
+
+fn standby() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "tap-agent is waiting for the leadership",
+    )
+        .into_response()
+}
@@ -0,0 +1,303 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # events
+//!
+//! Publishes the receipts, RAVs and deny status changes of the senders as JSON
+//! events to Kafka or NATS, so that billing and analytics can follow them
+//! without reading the database.
+//!
+//! The actors call [publish], which only queues the event. A task started by
+//! [start] sends them to the broker configured under `[events]`, in order.
+//! Nothing is published if `[events]` is not configured. Kafka and NATS need
+//! the `kafka` and `nats` features.
+//!
+//! The delivery is best-effort, the actors are never slowed down by the
+//! broker: an event is dropped if the broker falls [QUEUE_SIZE] events behind
+//! or refuses it, and the queued events are lost when tap-agent stops. The
+//! database stays the source of truth, consumers needing every receipt must
+//! reconcile with it.
+//!
+//! The schema of the events is described in `docs/Events.md`, fields are only
+//! added to it as long as [SCHEMA_VERSION] is the same.
+
+use std::{
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use indexer_config::EventsConfig;
+use lazy_static::lazy_static;
+use prometheus::{register_counter_vec, CounterVec};
+use serde::Serialize;
+use thegraph_core::alloy::primitives::Address;
+use tokio::sync::mpsc;
+
+/// Version of the schema of the events, incremented on breaking changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Events waiting to be sent to the broker
+pub const QUEUE_SIZE: usize = 10_000;
+
+static QUEUE: OnceLock<mpsc::Sender<Event>> = OnceLock::new();
+
+lazy_static! {
+    static ref EVENTS_PUBLISHED: CounterVec = register_counter_vec!(
+        "tap_events_published_total",
+        "Events sent to the broker",
+        &["type"]
+    )
+    .unwrap();
+    static ref EVENTS_DROPPED: CounterVec = register_counter_vec!(
+        "tap_events_dropped_total",
+        "Events dropped because the queue was full or the broker refused them",
+        &["type"]
+    )
+    .unwrap();
+}
+
+/// Event published to the broker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// [SCHEMA_VERSION]
+    pub version: u32,
+    /// Sender the event is about
+    pub sender: Address,
+    /// Time the event happened at, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// What happened
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// What happened, serialized in the `type` field of the event with its own
+/// fields. The values in GRT wei are strings, they don't fit in a JSON number.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// A receipt stored by indexer-service was received
+    ReceiptAccepted {
+        /// Allocation the receipt pays for
+        allocation_id: Address,
+        /// Signer of the receipt, one of the signers of the sender
+        signer: Address,
+        /// Id of the receipt in the database
+        receipt_id: u64,
+        /// Timestamp of the receipt, in nanoseconds since the Unix epoch
+        timestamp_ns: u64,
+        /// Value of the receipt
+        value: String,
+    },
+    /// A receipt failed the checks of the RAV request, it is stored with the
+    /// invalid receipts and never aggregated
+    ReceiptInvalid {
+        /// Allocation the receipt pays for
+        allocation_id: Address,
+        /// Timestamp of the receipt, in nanoseconds since the Unix epoch
+        timestamp_ns: u64,
+        /// Value of the receipt
+        value: String,
+        /// Check the receipt failed
+        error: String,
+    },
+    /// The aggregator signed a RAV, it is stored and replaces the previous one
+    RavCreated {
+        /// Allocation of the RAV
+        allocation_id: Address,
+        /// Value of the receipts aggregated so far
+        value_aggregate: String,
+        /// Timestamp of the last receipt aggregated, in nanoseconds since the Unix epoch
+        timestamp_ns: u64,
+    },
+    /// The sender was added to the denylist
+    SenderDenied,
+    /// The sender was removed from the denylist
+    SenderAllowed,
+}
+
+impl EventKind {
+    /// Value of the `type` field
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::ReceiptAccepted { .. } => "receipt_accepted",
+            EventKind::ReceiptInvalid { .. } => "receipt_invalid",
+            EventKind::RavCreated { .. } => "rav_created",
+            EventKind::SenderDenied => "sender_denied",
+            EventKind::SenderAllowed => "sender_allowed",
+        }
+    }
+}
+
+/// Queues an event of `sender`, ignored if the events are not published
+pub fn publish(sender: Address, kind: EventKind) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    let event_type = kind.as_str();
+    let event = Event {
+        version: SCHEMA_VERSION,
+        sender,
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        kind,
+    };
+    if queue.try_send(event).is_err() {
+        EVENTS_DROPPED.with_label_values(&[event_type]).inc();
+    }
+}
+
+/// Connects to the broker of `config` and starts sending it the events
+/// published from now on
+///
+/// # Panics
+///
+/// If it was already started.
+pub async fn start(config: &EventsConfig) -> anyhow::Result<()> {
+    let broker = Broker::connect(config).await?;
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    QUEUE.set(sender).expect("The events were already started");
+    tokio::spawn(broker.run(receiver));
+    Ok(())
+}
+
+enum Broker {
+    #[cfg(feature = "kafka")]
+    Kafka {
+        producer: rdkafka::producer::FutureProducer,
+        topic: String,
+    },
+    #[cfg(feature = "nats")]
+    Nats {
+        client: async_nats::Client,
+        subject_prefix: String,
+    },
+}
+
+impl Broker {
+    async fn connect(config: &EventsConfig) -> anyhow::Result<Self> {
+        match config {
+            #[cfg(feature = "kafka")]
+            EventsConfig::Kafka { brokers, topic } => {
+                let producer = rdkafka::ClientConfig::new()
+                    .set("bootstrap.servers", brokers)
+                    .create()?;
+                tracing::info!(%brokers, %topic, "Publishing the events to Kafka");
+                Ok(Broker::Kafka {
+                    producer,
+                    topic: topic.clone(),
+                })
+            }
+            #[cfg(not(feature = "kafka"))]
+            EventsConfig::Kafka { .. } => {
+                anyhow::bail!("tap-agent was built without the `kafka` feature")
+            }
+            #[cfg(not(feature = "nats"))]
+            EventsConfig::Nats { .. } => {
+                anyhow::bail!("tap-agent was built without the `nats` feature")
+            }
+            #[cfg(feature = "nats")]
+            EventsConfig::Nats {
+                url,
+                subject_prefix,
+            } => {
+                let client = async_nats::connect(url.as_str()).await?;
+                tracing::info!(%url, %subject_prefix, "Publishing the events to NATS");
+                Ok(Broker::Nats {
+                    client,
+                    subject_prefix: subject_prefix.clone(),
+                })
+            }
+        }
+    }
+
+    async fn run(self, mut receiver: mpsc::Receiver<Event>) {
+        while let Some(event) = receiver.recv().await {
+            let event_type = event.kind.as_str();
+            match self.send(&event).await {
+                Ok(()) => EVENTS_PUBLISHED.with_label_values(&[event_type]).inc(),
+                Err(error) => {
+                    tracing::warn!(%error, event_type, "Failed to publish an event");
+                    EVENTS_DROPPED.with_label_values(&[event_type]).inc();
+                }
+            }
+        }
+    }
+
+    #[cfg(any(feature = "kafka", feature = "nats"))]
+    async fn send(&self, event: &Event) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(event)?;
+        match self {
+            #[cfg(feature = "kafka")]
+            Broker::Kafka { producer, topic } => {
+                let key = event.sender.to_string();
+                let record = rdkafka::producer::FutureRecord::to(topic)
+                    .key(&key)
+                    .payload(&payload);
+                producer
+                    .send(record, std::time::Duration::from_secs(5))
+                    .await
+                    .map_err(|(error, _)| error)?;
+            }
+            #[cfg(feature = "nats")]
+            Broker::Nats {
+                client,
+                subject_prefix,
+            } => {
+                let subject = format!("{subject_prefix}.{}", event.kind.as_str());
+                client.publish(subject, payload.into()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Without a broker feature, a broker can't be connected to
+    #[cfg(not(any(feature = "kafka", feature = "nats")))]
+    async fn send(&self, _: &Event) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use thegraph_core::alloy::primitives::Address;
+
+    use super::{Event, EventKind, SCHEMA_VERSION};
+
+    #[test]
+    fn test_event_schema() {
+        let event = Event {
+            version: SCHEMA_VERSION,
+            sender: Address::repeat_byte(0xaa),
+            timestamp_ms: 1_700_000_000_000,
+            kind: EventKind::RavCreated {
+                allocation_id: Address::repeat_byte(0xbb),
+                value_aggregate: "1000000000000000000000".to_string(),
+                timestamp_ns: 1_700_000_000_000_000_000,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            json!({
+                "version": 1,
+                "sender": "0xaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                "timestamp_ms": 1_700_000_000_000u64,
+                "type": "rav_created",
+                "allocation_id": "0xbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+                "value_aggregate": "1000000000000000000000",
+                "timestamp_ns": 1_700_000_000_000_000_000u64,
+            })
+        );
+
+        let event = Event {
+            kind: EventKind::SenderDenied,
+            ..event
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["type"],
+            "sender_denied"
+        );
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Health of the actor tree, served on the metrics port
+//!
+//! Tap-agent keeps running when the actor tree dies, the only visible
+//! effect being fees that stop being aggregated. `/healthz` answers
+//! `503 Service Unavailable` if the [SenderAccountsManager] is not
+//! running, doesn't answer, the database can't be reached or one of the
+//! receipt notification listeners stopped.
+//!
+//! [SenderAccountsManager]: crate::agent::sender_accounts_manager::SenderAccountsManager
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use ractor::{call_t, ActorRef, ActorStatus};
+use serde_json::json;
+use sqlx::PgPool;
+
+use crate::agent::sender_accounts_manager::SenderAccountsManagerMessage;
+
+/// Time given to the manager and the database to answer
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State of the `/healthz` route
+#[derive(Clone)]
+pub struct HealthState {
+    /// Root of the actor tree
+    pub manager: ActorRef<SenderAccountsManagerMessage>,
+    /// Database the receipts are read from
+    pub pgpool: PgPool,
+}
+
+/// Router serving `/healthz`
+pub fn router(state: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .with_state(state)
+}
+
+async fn healthz(State(state): State<HealthState>) -> impl IntoResponse {
+    let manager_status = state.manager.get_status();
+    let manager = if manager_status == ActorStatus::Running {
+        call_t!(
+            state.manager,
+            SenderAccountsManagerMessage::GetHealth,
+            HEALTH_CHECK_TIMEOUT.as_millis() as u64
+        )
+        .map_err(|error| error.to_string())
+    } else {
+        Err(format!("Manager is {manager_status:?}"))
+    };
+
+    let database = tokio::time::timeout(
+        HEALTH_CHECK_TIMEOUT,
+        sqlx::query!("SELECT 1 AS one").execute(&state.pgpool),
+    )
+    .await
+    .map_err(|_| "Timed out".to_string())
+    .and_then(|result| result.map(|_| ()).map_err(|error| error.to_string()));
+
+    let healthy = database.is_ok()
+        && manager
+            .as_ref()
+            .is_ok_and(|manager| manager.receipt_listener_v1 && manager.receipt_listener_v2);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = json!({
+        "status": if healthy { "ok" } else { "error" },
+        "manager": match &manager {
+            Ok(manager) => json!({ "status": "ok", "actors": manager }),
+            Err(error) => json!({ "status": "error", "error": error }),
+        },
+        "database": match &database {
+            Ok(()) => json!({ "status": "ok" }),
+            Err(error) => json!({ "status": "error", "error": error }),
+        },
+    });
+    (status, Json(body))
+}
@@ -0,0 +1,307 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # Leader election
+//!
+//! Several replicas of tap-agent can run on the same database when
+//! `tap.leader_election` is set. Only the replica holding the Postgres
+//! advisory lock `lock_key` starts the agent, so the receipt notifications
+//! are processed and the RAVs requested once. The others wait as standbys,
+//! trying to take the lock every `retry_interval_secs`.
+//!
+//! With `tap.sharding`, the replicas of each shard elect their own leader
+//! with the lock `lock_key + shard_index`.
+//!
+//! The lock is held by a connection of its own and released by Postgres as
+//! soon as this connection is closed, including when the leader dies. The
+//! leader checks its connection twice per `retry_interval_secs` and stops
+//! as soon as it is broken, since a standby may already hold the lock.
+//!
+//! Without `tap.leader_election`, the agent embedded in indexer-service
+//! takes the lock [SINGLE_INSTANCE_LOCK_KEY] instead, and refuses to start
+//! if another replica holds it.
+//!
+//! Every agent also holds a shared lock on its shard layout, see
+//! [wait_for_shard_layout], so that the agents of a new `shard_count` only
+//! start once the ones of the previous layout are stopped.
+
+use std::time::Duration;
+
+use indexer_config::{DatabaseConfig, LeaderElectionConfig, ShardingConfig};
+use sqlx::{Connection, PgConnection};
+
+/// Time given to the database to answer the leader
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Advisory lock held by the single agent embedded in indexer-service
+/// without leader election, `tapagent` in ASCII
+pub const SINGLE_INSTANCE_LOCK_KEY: i64 = i64::from_be_bytes(*b"tapagent");
+
+/// How often the single agent checks that it still holds its lock
+const SINGLE_INSTANCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Class of the advisory locks of the shard layouts, `shrd` in ASCII,
+/// locked along with the `shard_count` of the layout
+const SHARD_LAYOUT_CLASS: i32 = i32::from_be_bytes(*b"shrd");
+
+/// How often an agent checks whether the agents of another shard layout
+/// are stopped
+const SHARD_LAYOUT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Advisory lock held by the leader
+pub struct Leadership {
+    connection: PgConnection,
+    check_interval: Duration,
+}
+
+/// Waits until this replica holds the advisory lock of `config`, for its
+/// shard if `sharding` is set
+pub async fn wait_for_leadership(
+    database: DatabaseConfig,
+    config: &LeaderElectionConfig,
+    sharding: Option<&ShardingConfig>,
+) -> Leadership {
+    let url = database.get_formated_postgres_url();
+    let lock_key = config
+        .lock_key
+        .wrapping_add(sharding.map_or(0, |sharding| i64::from(sharding.shard_index)));
+    let mut waiting_logged = false;
+    loop {
+        match try_lock(url.as_str(), lock_key).await {
+            Ok(Some(connection)) => {
+                tracing::info!(lock_key, "Acquired the leadership");
+                return Leadership {
+                    connection,
+                    check_interval: config.retry_interval_secs / 2,
+                };
+            }
+            Ok(None) if !waiting_logged => {
+                tracing::info!(
+                    lock_key,
+                    "Another replica is the leader, waiting as a standby"
+                );
+                waiting_logged = true;
+            }
+            Ok(None) => {}
+            Err(error) => {
+                tracing::warn!(%error, "Could not try to acquire the leadership");
+            }
+        }
+        tokio::time::sleep(config.retry_interval_secs).await;
+    }
+}
+
+/// Takes the lock [SINGLE_INSTANCE_LOCK_KEY], for the shard if `sharding`
+/// is set, failing if another instance holds it
+pub async fn single_instance(
+    database: DatabaseConfig,
+    sharding: Option<&ShardingConfig>,
+) -> anyhow::Result<Leadership> {
+    let lock_key = SINGLE_INSTANCE_LOCK_KEY
+        .wrapping_add(sharding.map_or(0, |sharding| i64::from(sharding.shard_index)));
+    match try_lock(database.get_formated_postgres_url().as_str(), lock_key).await? {
+        Some(connection) => Ok(Leadership {
+            connection,
+            check_interval: SINGLE_INSTANCE_CHECK_INTERVAL,
+        }),
+        None => anyhow::bail!(
+            "Another replica already runs tap-agent, set tap.leader_election to run \
+            several replicas with --with-tap-agent"
+        ),
+    }
+}
+
+/// Connection holding the advisory lock `lock_key`, if it was free
+async fn try_lock(url: &str, lock_key: i64) -> Result<Option<PgConnection>, sqlx::Error> {
+    lock(PgConnection::connect(url).await?, lock_key).await
+}
+
+async fn lock(
+    mut connection: PgConnection,
+    lock_key: i64,
+) -> Result<Option<PgConnection>, sqlx::Error> {
+    let locked = sqlx::query_scalar!(r#"SELECT pg_try_advisory_lock($1) AS "locked!""#, lock_key)
+        .fetch_one(&mut connection)
+        .await?;
+    if locked {
+        Ok(Some(connection))
+    } else {
+        connection.close().await?;
+        Ok(None)
+    }
+}
+
+/// Shared advisory lock on the shard layout of the running agent, released
+/// once dropped
+pub struct ShardLayout {
+    _connection: PgConnection,
+}
+
+/// Waits until no agent runs with another `shard_count` than `sharding`, an
+/// agent without sharding having a single shard, and locks the layout
+///
+/// The senders move to other shards when `shard_count` changes, and their
+/// receipt notifications are filtered by the new layout. Until the agents of
+/// the previous layout are stopped, a sender could be managed by two agents,
+/// requesting its RAVs twice.
+pub async fn wait_for_shard_layout(
+    database: DatabaseConfig,
+    sharding: Option<&ShardingConfig>,
+) -> ShardLayout {
+    let url = database.get_formated_postgres_url();
+    let shard_count = sharding.map_or(1, |sharding| sharding.shard_count);
+    let mut waiting_logged = false;
+    loop {
+        let locked = match PgConnection::connect(url.as_str()).await {
+            Ok(connection) => lock_shard_layout(connection, shard_count).await,
+            Err(error) => Err(error),
+        };
+        match locked {
+            Ok(Some(connection)) => {
+                return ShardLayout {
+                    _connection: connection,
+                }
+            }
+            Ok(None) if !waiting_logged => {
+                tracing::info!(
+                    shard_count,
+                    "Agents of another shard layout are running, waiting for them to stop"
+                );
+                waiting_logged = true;
+            }
+            Ok(None) => {}
+            Err(error) => {
+                tracing::warn!(%error, "Could not lock the shard layout");
+            }
+        }
+        tokio::time::sleep(SHARD_LAYOUT_RETRY_INTERVAL).await;
+    }
+}
+
+/// Connection holding the shared lock of the layout of `shard_count`, if no
+/// agent holds the lock of another layout
+///
+/// The lock is taken before looking for the other layouts, so that two
+/// agents of different layouts starting together can't both start.
+async fn lock_shard_layout(
+    mut connection: PgConnection,
+    shard_count: u32,
+) -> Result<Option<PgConnection>, sqlx::Error> {
+    sqlx::query!(
+        "SELECT pg_advisory_lock_shared($1, $2)",
+        SHARD_LAYOUT_CLASS,
+        shard_count as i32
+    )
+    .execute(&mut connection)
+    .await?;
+    let other_layouts = sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM pg_locks
+                WHERE locktype = 'advisory'
+                    AND granted
+                    AND objsubid = 2
+                    AND database = (SELECT oid FROM pg_database WHERE datname = current_database())
+                    AND classid = $1::bigint::oid
+                    AND objid <> $2::bigint::oid
+            ) AS "other_layouts!"
+        "#,
+        i64::from(SHARD_LAYOUT_CLASS),
+        i64::from(shard_count),
+    )
+    .fetch_one(&mut connection)
+    .await?;
+    if other_layouts {
+        connection.close().await?;
+        Ok(None)
+    } else {
+        Ok(Some(connection))
+    }
+}
+
+impl Leadership {
+    /// Returns once the connection holding the lock is broken, the lock being
+    /// released by then
+    pub async fn lost(mut self) {
+        let mut interval = tokio::time::interval(self.check_interval);
+        loop {
+            interval.tick().await;
+            let check = tokio::time::timeout(CHECK_TIMEOUT, self.connection.ping()).await;
+            match check {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => {
+                    tracing::error!(%error, "Lost the connection holding the leadership");
+                    return;
+                }
+                Err(_) => {
+                    tracing::error!("Timed out checking the connection holding the leadership");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Returns once `leadership` is lost, never if there is none
+pub async fn leadership_lost(leadership: Option<Leadership>) {
+    match leadership {
+        Some(leadership) => leadership.lost().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::{lock, lock_shard_layout};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_single_leader(pgpool: PgPool) {
+        let connect = || async { pgpool.acquire().await.unwrap().detach() };
+
+        let leader = lock(connect().await, 1).await.unwrap();
+        assert!(leader.is_some());
+        assert!(lock(connect().await, 1).await.unwrap().is_none());
+
+        // a standby takes over once the connection of the leader is closed
+        drop(leader);
+        let mut standby = None;
+        for _ in 0..50 {
+            standby = lock(connect().await, 1).await.unwrap();
+            if standby.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(standby.is_some());
+    }
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_shard_layout(pgpool: PgPool) {
+        let connect = || async { pgpool.acquire().await.unwrap().detach() };
+
+        let shard_0 = lock_shard_layout(connect().await, 2).await.unwrap();
+        let shard_1 = lock_shard_layout(connect().await, 2).await.unwrap();
+        assert!(shard_0.is_some());
+        assert!(shard_1.is_some());
+        assert!(lock_shard_layout(connect().await, 3)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the new layout starts once the previous one is stopped
+        drop(shard_0);
+        drop(shard_1);
+        let mut new_layout = None;
+        for _ in 0..50 {
+            new_layout = lock_shard_layout(connect().await, 3).await.unwrap();
+            if new_layout.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(new_layout.is_some());
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reconciles the fees of the legacy indexer-service with TAP, see
+//! [crate::cli::Command::LegacyReceiptsReport]
+//!
+//! The TypeScript indexer-service stored the scalar receipts of the queries in
+//! `allocation_receipts`, and the vouchers they were exchanged for in
+//! `vouchers`. These receipts are not signed for TAP, so they can't be moved to
+//! `scalar_tap_receipts` and aggregated. Instead, the fees of each allocation
+//! are reported next to its TAP receipts and RAVs, so that the fees collected
+//! before the migration stay visible.
+//!
+//! The legacy tables are left untouched, and skipped if they don't exist.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use anyhow::Context as _;
+use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use serde::Serialize;
+use sqlx::{types::BigDecimal, PgPool};
+use thegraph_core::alloy::primitives::Address;
+
+use crate::{database, CONFIG};
+
+/// Fees of an allocation in the legacy tables and in the TAP tables
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct AllocationReport {
+    /// Receipts in `allocation_receipts`
+    pub legacy_receipts: u64,
+    /// Sum of the fees of the receipts in `allocation_receipts`
+    pub legacy_receipt_fees: u128,
+    /// Sum of the amounts of the vouchers in `vouchers`
+    pub legacy_voucher_fees: u128,
+    /// Receipts in `scalar_tap_receipts`, not aggregated yet
+    pub tap_receipts: u64,
+    /// Sum of the values of the receipts in `scalar_tap_receipts`
+    pub tap_receipt_fees: u128,
+    /// Value of the RAVs in `scalar_tap_ravs`, of all the senders
+    pub tap_rav_fees: u128,
+}
+
+/// Count and sum of a table per allocation
+type AllocationSums = HashMap<Address, (u64, u128)>;
+
+/// Reports the fees of the allocations found in the legacy tables, along with
+/// their TAP receipts and RAVs
+pub async fn legacy_receipts_report(
+    pgpool: &PgPool,
+) -> anyhow::Result<BTreeMap<Address, AllocationReport>> {
+    let mut report: BTreeMap<Address, AllocationReport> = BTreeMap::new();
+
+    if table_exists(pgpool, "allocation_receipts").await? {
+        let receipts = sums(
+            pgpool,
+            "SELECT allocation, COUNT(*), SUM(fees) FROM allocation_receipts GROUP BY allocation",
+        )
+        .await?;
+        for (allocation_id, (count, fees)) in receipts {
+            let allocation = report.entry(allocation_id).or_default();
+            allocation.legacy_receipts = count;
+            allocation.legacy_receipt_fees = fees;
+        }
+    } else {
+        tracing::warn!("No allocation_receipts table, the legacy receipts are not reported");
+    }
+
+    if table_exists(pgpool, "vouchers").await? {
+        let vouchers = sums(
+            pgpool,
+            "SELECT allocation, COUNT(*), SUM(amount) FROM vouchers GROUP BY allocation",
+        )
+        .await?;
+        for (allocation_id, (_, amount)) in vouchers {
+            report.entry(allocation_id).or_default().legacy_voucher_fees = amount;
+        }
+    } else {
+        tracing::warn!("No vouchers table, the legacy vouchers are not reported");
+    }
+
+    let tap_receipts = sums(
+        pgpool,
+        "SELECT allocation_id, COUNT(*), SUM(value) FROM scalar_tap_receipts GROUP BY allocation_id",
+    )
+    .await?;
+    let tap_ravs = sums(
+        pgpool,
+        "SELECT allocation_id, COUNT(*), SUM(value_aggregate) FROM scalar_tap_ravs \
+        GROUP BY allocation_id",
+    )
+    .await?;
+    for (allocation_id, allocation) in report.iter_mut() {
+        if let Some((count, fees)) = tap_receipts.get(allocation_id) {
+            allocation.tap_receipts = *count;
+            allocation.tap_receipt_fees = *fees;
+        }
+        if let Some((_, fees)) = tap_ravs.get(allocation_id) {
+            allocation.tap_rav_fees = *fees;
+        }
+    }
+    Ok(report)
+}
+
+async fn table_exists(pgpool: &PgPool, table: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(r#"SELECT to_regclass($1) IS NOT NULL AS "exists!""#, table)
+        .fetch_one(pgpool)
+        .await
+}
+
+/// Runs `query`, which selects an allocation id, a count and a sum
+///
+/// The legacy tables are not created by the migrations, so the queries can't
+/// be checked at compile time.
+async fn sums(pgpool: &PgPool, query: &str) -> anyhow::Result<AllocationSums> {
+    let rows: Vec<(String, i64, Option<BigDecimal>)> =
+        sqlx::query_as(query).fetch_all(pgpool).await?;
+    rows.into_iter()
+        .map(|(allocation_id, count, sum)| {
+            // the legacy tables store checksummed addresses, the TAP ones
+            // lowercase hex without prefix
+            let allocation_id = Address::from_str(allocation_id.trim())
+                .with_context(|| format!("Invalid allocation id {allocation_id}"))?;
+            let sum = match sum {
+                Some(sum) => sum
+                    .to_bigint()
+                    .and_then(|sum| sum.to_u128())
+                    .with_context(|| format!("Invalid fees of allocation {allocation_id}"))?,
+                None => 0,
+            };
+            Ok((allocation_id, (count as u64, sum)))
+        })
+        .collect()
+}
+
+/// Entrypoint of [crate::cli::Command::LegacyReceiptsReport], configured
+/// with [crate::CONFIG]
+///
+/// The report is printed as JSON, keyed by allocation id.
+pub async fn run() -> anyhow::Result<()> {
+    let pgpool = database::connect(CONFIG.database.clone()).await;
+    let report = legacy_receipts_report(&pgpool).await?;
+    tracing::info!(
+        allocations = report.len(),
+        legacy_receipt_fees = report
+            .values()
+            .map(|allocation| allocation.legacy_receipt_fees)
+            .sum::<u128>(),
+        "Legacy receipts reported"
+    );
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use test_assets::TAP_SIGNER as SIGNER;
+
+    use super::{legacy_receipts_report, AllocationReport};
+    use crate::test::{create_received_receipt, store_receipt, ALLOCATION_ID_0};
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_legacy_receipts_report(pgpool: PgPool) {
+        // only the TAP tables, nothing to report
+        assert!(legacy_receipts_report(&pgpool).await.unwrap().is_empty());
+
+        // the tables of the legacy indexer-service
+        sqlx::query(
+            r#"
+                CREATE TABLE allocation_receipts (
+                    id VARCHAR(66) PRIMARY KEY,
+                    allocation CHAR(42) NOT NULL,
+                    fees NUMERIC(78) NOT NULL,
+                    signature VARCHAR(132) NOT NULL
+                )
+            "#,
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"
+                CREATE TABLE vouchers (
+                    allocation CHAR(42) PRIMARY KEY,
+                    amount NUMERIC(78) NOT NULL,
+                    signature VARCHAR(132) NOT NULL
+                )
+            "#,
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+        let allocation = ALLOCATION_ID_0.to_checksum(None);
+        for (id, fees) in [("0x01", 100), ("0x02", 200)] {
+            sqlx::query(
+                "INSERT INTO allocation_receipts (id, allocation, fees, signature) \
+                VALUES ($1, $2, $3, '0x')",
+            )
+            .bind(id)
+            .bind(&allocation)
+            .bind(fees)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        }
+        sqlx::query("INSERT INTO vouchers (allocation, amount, signature) VALUES ($1, 250, '0x')")
+            .bind(&allocation)
+            .execute(&pgpool)
+            .await
+            .unwrap();
+        let receipt = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, 1, 10);
+        store_receipt(&pgpool, receipt.signed_receipt())
+            .await
+            .unwrap();
+
+        let report = legacy_receipts_report(&pgpool).await.unwrap();
+        assert_eq!(
+            report.get(&ALLOCATION_ID_0),
+            Some(&AllocationReport {
+                legacy_receipts: 2,
+                legacy_receipt_fees: 300,
+                legacy_voucher_fees: 250,
+                tap_receipts: 1,
+                tap_receipt_fees: 10,
+                tap_rav_fees: 0,
+            })
+        );
+    }
+}
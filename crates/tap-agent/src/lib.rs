@@ -11,14 +11,27 @@
 //! Its main goal is that the value never goes below the balance available
 //! in the escrow account for a given sender.
 
+use std::sync::Mutex;
+
+use clap::Parser;
 use indexer_config::Config;
 use lazy_static::lazy_static;
 use tap_core::tap_eip712_domain;
 use thegraph_core::alloy::sol_types::Eip712Domain;
 
+/// Configuration of an agent running in the process of indexer-service, read
+/// by [CONFIG] instead of the command line, see [embedded]
+static EMBEDDED_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
 lazy_static! {
+    /// Command line arguments
+    pub static ref CLI: cli::Cli = cli::Cli::parse();
     /// Static configuration
-    pub static ref CONFIG: Config = cli::get_config().expect("Failed to load configuration");
+    pub static ref CONFIG: Config = EMBEDDED_CONFIG
+        .lock()
+        .unwrap()
+        .take()
+        .unwrap_or_else(|| cli::get_config().expect("Failed to load configuration"));
     /// Static EIP_712_DOMAIN used with config values
     pub static ref EIP_712_DOMAIN: Eip712Domain = tap_eip712_domain(
         CONFIG.blockchain.chain_id as u64,
@@ -30,10 +43,26 @@ pub mod adaptative_concurrency;
 pub mod agent;
 pub mod backoff;
 pub mod cli;
+pub mod clock;
 /// Database helper
 pub mod database;
+pub mod domain_check;
+pub mod embedded;
+pub mod events;
+pub mod health;
+pub mod leader_election;
+pub mod legacy_receipts;
 /// Prometheus Metrics server
 pub mod metrics;
+pub mod rav_budget;
+pub mod rav_request;
+pub mod rav_trigger;
+pub mod redemptions;
+pub mod revalidate;
+pub mod sender_stats;
+/// Simulations of the tap actors in virtual time
+#[cfg(feature = "simulation")]
+pub mod simulation;
 pub mod tap;
 
 /// Test utils to interact with Tap Actors
@@ -1,26 +1,95 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use indexer_tap_agent::{agent, metrics, CONFIG};
+use indexer_tap_agent::{
+    agent,
+    cli::{self, Command},
+    database, events, leader_election, legacy_receipts, metrics, rav_request, revalidate,
+    sender_stats, CLI, CONFIG,
+};
 use ractor::ActorStatus;
 use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // The configuration is only parsed once diagnosed
+    if let Some(Command::ValidateConfig { online }) = CLI.command {
+        return cli::validate_config(online);
+    }
+
     // Parse basic configurations, also initializes logging.
     lazy_static::initialize(&CONFIG);
 
-    let (manager, handler) = agent::start_agent().await;
+    if let Some(Command::RevalidateInvalidReceipts { dry_run }) = CLI.command {
+        return revalidate::run(dry_run).await;
+    }
+
+    if let Some(Command::LegacyReceiptsReport) = CLI.command {
+        return legacy_receipts::run().await;
+    }
+
+    if let Some(Command::RavRequest {
+        sender,
+        allocation,
+        url,
+    }) = &CLI.command
+    {
+        return rav_request::run(*sender, *allocation, url.clone()).await;
+    }
+
+    if let Some(Command::Migrate) = CLI.command {
+        let pgpool = database::connect(CONFIG.database.clone()).await;
+        let applied = indexer_schema::run_migrations(&pgpool).await?;
+        tracing::info!(count = applied.len(), "Database migrated");
+        return Ok(());
+    }
+
+    let leadership = match &CONFIG.tap.leader_election {
+        Some(config) => {
+            let leadership = leader_election::wait_for_leadership(
+                CONFIG.database.clone(),
+                config,
+                CONFIG.tap.sharding.as_ref(),
+            );
+            Some(leadership.await)
+        }
+        None => None,
+    };
+    let _shard_layout = leader_election::wait_for_shard_layout(
+        CONFIG.database.clone(),
+        CONFIG.tap.sharding.as_ref(),
+    )
+    .await;
+
+    if let Some(events) = &CONFIG.events {
+        events::start(events).await?;
+    }
+
+    if CONFIG.tap.sender_stats.is_some() {
+        // from the start, for the subscribers to get the last state of every sender
+        sender_stats::enable();
+    }
+    let (manager, handler, pgpool) = agent::start_agent().await?;
     tracing::info!("TAP Agent started.");
 
-    tokio::spawn(metrics::run_server(CONFIG.metrics.port));
+    let routes = metrics::routes(manager.clone(), pgpool);
+    tokio::spawn(metrics::run_server(&CONFIG.metrics, routes));
     tracing::info!("Metrics port opened");
 
+    if let Some(sender_stats) = &CONFIG.tap.sender_stats {
+        tokio::spawn(sender_stats::serve(
+            sender_stats.host_and_port,
+            sender_stats.auth_token.clone(),
+        ));
+    }
+
     // Have tokio wait for SIGTERM or SIGINT.
     let mut signal_sigint = signal(SignalKind::interrupt())?;
     let mut signal_sigterm = signal(SignalKind::terminate())?;
+    let mut leadership_lost = false;
     tokio::select! {
         _ = handler => tracing::error!("SenderAccountsManager stopped"),
+        _ = leader_election::leadership_lost(leadership) => leadership_lost = true,
         _ = signal_sigint.recv() => tracing::debug!("Received SIGINT."),
         _ = signal_sigterm.recv() => tracing::debug!("Received SIGTERM."),
     }
@@ -35,6 +104,14 @@ async fn main() -> anyhow::Result<()> {
             .expect("Failed to kill manager.");
     }
 
+    // Export the spans that are still buffered
+    indexer_telemetry::shutdown();
+
+    // Restarted as a standby, since another replica may be the leader by now
+    if leadership_lost {
+        anyhow::bail!("Lost the leadership");
+    }
+
     // Stop the server and wait for it to finish gracefully.
     tracing::debug!("Goodbye!");
     Ok(())
@@ -1,11 +1,23 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{net::SocketAddr, panic};
+use std::panic;
 
 use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
 use futures_util::FutureExt;
+use indexer_config::MetricsConfig;
+use indexer_listener::Listener;
 use prometheus::TextEncoder;
+use ractor::ActorRef;
+use sqlx::PgPool;
+use tower_http::validate_request::ValidateRequestHeaderLayer;
+
+use crate::{
+    agent::sender_accounts_manager::SenderAccountsManagerMessage,
+    health::{self, HealthState},
+    rav_request::{self, RavRequestState},
+    redemptions, CONFIG,
+};
 
 async fn handler_metrics() -> (StatusCode, String) {
     let metric_families = prometheus::gather();
@@ -27,19 +39,25 @@ async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 Not Found")
 }
 
-async fn _run_server(port: u16) {
+async fn _run_server(config: &MetricsConfig, routes: Router) {
     let app = Router::new()
         .route("/metrics", get(handler_metrics))
+        .merge(routes)
         .fallback(handler_404);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = tokio::net::TcpListener::bind(addr)
+    let hosts_and_ports = if CONFIG.tap.metrics_hosts_and_ports.is_empty() {
+        config.get_socket_addrs()
+    } else {
+        CONFIG.tap.metrics_hosts_and_ports.clone()
+    };
+    let listeners = Listener::bind_all(&config.listener, &hosts_and_ports)
         .await
         .expect("Failed to Bind metrics address`");
-    let server = axum::serve(listener, app.into_make_service());
 
-    tracing::info!("Metrics server listening on {}", addr);
+    for listener in &listeners {
+        tracing::info!("Metrics server listening on {}", listener);
+    }
 
-    let res = server.await;
+    let res = indexer_listener::serve_all(listeners, app, std::future::pending()).await;
 
     tracing::debug!("Metrics server stopped");
 
@@ -48,16 +66,49 @@ async fn _run_server(port: u16) {
     };
 }
 
-/// Run the server on a given `port`.
+/// Run the server on the configured addresses or socket, along with the extra `routes`.
+///
+/// `[tap] metrics_hosts_and_ports` replaces the addresses of `config` if set.
 ///
 /// This is recommended to run inside a Task
-pub async fn run_server(port: u16) {
+pub async fn run_server(config: &MetricsConfig, routes: Router) {
     // Code here is to abort program if there is a panic in _run_server
     // Otherwise, when spawning the task, the panic will be silently ignored
-    let res = panic::AssertUnwindSafe(_run_server(port))
+    let res = panic::AssertUnwindSafe(_run_server(config, routes))
         .catch_unwind()
         .await;
     if res.is_err() {
         std::process::abort();
     }
 }
+
+/// Routes served along with `/metrics`, the health of the actor tree, and
+/// if their admin tokens are set, the RAV redemptions if they are
+/// reconciled, the forced RAV requests and the log levels
+pub fn routes(manager: ActorRef<SenderAccountsManagerMessage>, pgpool: PgPool) -> Router {
+    let mut routes = health::router(HealthState {
+        manager: manager.clone(),
+        pgpool: pgpool.clone(),
+    });
+    if let (Some(_), Some(admin_token)) = (&CONFIG.tap.rav_redemptions, &CONFIG.tap.admin_token) {
+        routes = routes.merge(
+            redemptions::router(pgpool)
+                .route_layer(ValidateRequestHeaderLayer::bearer(admin_token)),
+        );
+    }
+    if let Some(admin_token) = &CONFIG.tap.admin_token {
+        routes = routes.merge(rav_request::router(RavRequestState {
+            manager,
+            admin_token: admin_token.clone(),
+        }));
+    }
+    if let Some(admin_token) = CONFIG.log.as_ref().and_then(|log| log.admin_token.as_ref()) {
+        tracing::info!("Serving log levels management at /log-levels");
+        routes = routes.nest(
+            "/log-levels",
+            indexer_telemetry::log_levels_router()
+                .route_layer(ValidateRequestHeaderLayer::bearer(admin_token)),
+        );
+    }
+    routes
+}
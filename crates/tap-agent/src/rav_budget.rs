@@ -0,0 +1,253 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # RAV request budget
+//!
+//! This module provides [RavRequestBudget], which bounds the number of RAV
+//! requests in flight across all the senders, on top of the
+//! [AdaptiveLimiter](crate::adaptative_concurrency::AdaptiveLimiter) of each
+//! sender.
+//!
+//! ## Behaviour
+//! A sender gets a [RavRequestGrant] before requesting a RAV, and holds it
+//! until the response. The grant is given back once dropped.
+//!
+//! While other senders are waiting for a grant, a sender can't get more than
+//! its share of the budget, the budget divided by the number of senders
+//! holding or waiting for a grant. This way a sender whose aggregator is slow
+//! or failing gives its grants back to the others as its requests complete,
+//! instead of holding the whole budget.
+//!
+//! Grants are never waited for, since the senders are actors. A sender
+//! refused a grant is expected to try again a moment later.
+//!
+//! The last RAV of a closing allocation is always granted, see
+//! [RavRequestBudget::grant_last_rav], since its fees are lost if it isn't
+//! redeemed in time. It still counts in the budget, so the routine requests
+//! wait for it instead.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, IntCounterVec, IntGaugeVec};
+use thegraph_core::alloy::primitives::Address;
+
+lazy_static! {
+    static ref RAV_REQUESTS_IN_FLIGHT: IntGaugeVec = register_int_gauge_vec!(
+        "tap_rav_requests_in_flight",
+        "RAV requests in flight holding a grant of the budget",
+        &["sender"]
+    )
+    .unwrap();
+    static ref RAV_REQUEST_BUDGET_DENIED: IntCounterVec = register_int_counter_vec!(
+        "tap_rav_request_budget_denied_total",
+        "RAV requests delayed because the budget was exhausted or the sender had its share",
+        &["sender", "reason"]
+    )
+    .unwrap();
+}
+
+/// How long a sender refused a grant counts in the shares without asking again
+const WAITING_TTL: Duration = Duration::from_secs(30);
+
+/// Why a grant was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetDenied {
+    /// Every grant of the budget is held
+    Exhausted,
+    /// The sender holds its share while other senders are waiting
+    FairShare,
+}
+
+impl BudgetDenied {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BudgetDenied::Exhausted => "exhausted",
+            BudgetDenied::FairShare => "fair_share",
+        }
+    }
+}
+
+struct Inner {
+    capacity: usize,
+    /// Grants held per sender
+    in_flight: HashMap<Address, usize>,
+    /// Senders refused a grant, and when they last asked
+    waiting: HashMap<Address, Instant>,
+}
+
+/// Budget of RAV requests in flight shared by all the senders
+///
+/// More information on [crate::rav_budget]
+#[derive(Clone)]
+pub struct RavRequestBudget {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl RavRequestBudget {
+    /// Creates a [RavRequestBudget] of `capacity` RAV requests in flight
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                in_flight: HashMap::new(),
+                waiting: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Grant for a RAV request of `sender`, if the budget allows it
+    pub fn try_grant(&self, sender: Address) -> Result<RavRequestGrant, BudgetDenied> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner
+            .waiting
+            .retain(|_, asked_at| now.saturating_duration_since(*asked_at) < WAITING_TTL);
+
+        let held = inner.in_flight.get(&sender).copied().unwrap_or_default();
+        let total: usize = inner.in_flight.values().sum();
+        let contenders = inner
+            .in_flight
+            .keys()
+            .chain(inner.waiting.keys())
+            .filter(|contender| **contender != sender)
+            .collect::<HashSet<_>>()
+            .len()
+            + 1;
+        let share = inner.capacity.div_ceil(contenders).max(1);
+
+        let denied = if total >= inner.capacity {
+            Some(BudgetDenied::Exhausted)
+        } else if held >= share {
+            Some(BudgetDenied::FairShare)
+        } else {
+            None
+        };
+        if let Some(denied) = denied {
+            inner.waiting.insert(sender, now);
+            RAV_REQUEST_BUDGET_DENIED
+                .with_label_values(&[&sender.to_string(), denied.as_str()])
+                .inc();
+            return Err(denied);
+        }
+
+        inner.waiting.remove(&sender);
+        inner.in_flight.insert(sender, held + 1);
+        RAV_REQUESTS_IN_FLIGHT
+            .with_label_values(&[&sender.to_string()])
+            .set((held + 1) as i64);
+        Ok(RavRequestGrant {
+            budget: self.clone(),
+            sender,
+        })
+    }
+
+    /// Grant for the last RAV request of a closing allocation of `sender`,
+    /// given even if the budget is exhausted
+    pub fn grant_last_rav(&self, sender: Address) -> RavRequestGrant {
+        let mut inner = self.inner.lock().unwrap();
+        let held = inner.in_flight.entry(sender).or_default();
+        *held += 1;
+        RAV_REQUESTS_IN_FLIGHT
+            .with_label_values(&[&sender.to_string()])
+            .set(*held as i64);
+        RavRequestGrant {
+            budget: self.clone(),
+            sender,
+        }
+    }
+
+    /// Number of grants held by all the senders
+    pub fn in_flight(&self) -> usize {
+        self.inner.lock().unwrap().in_flight.values().sum()
+    }
+
+    fn release(&self, sender: Address) {
+        let mut inner = self.inner.lock().unwrap();
+        let held = match inner.in_flight.get_mut(&sender) {
+            Some(held) => {
+                *held -= 1;
+                *held
+            }
+            None => return,
+        };
+        if held == 0 {
+            inner.in_flight.remove(&sender);
+        }
+        RAV_REQUESTS_IN_FLIGHT
+            .with_label_values(&[&sender.to_string()])
+            .set(held as i64);
+    }
+}
+
+/// RAV request in flight of a sender, given back to the budget once dropped
+pub struct RavRequestGrant {
+    budget: RavRequestBudget,
+    sender: Address,
+}
+
+impl Drop for RavRequestGrant {
+    fn drop(&mut self) {
+        self.budget.release(self.sender);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use thegraph_core::alloy::primitives::Address;
+
+    use super::{BudgetDenied, RavRequestBudget};
+
+    #[test]
+    fn test_budget_is_shared() {
+        let budget = RavRequestBudget::new(4);
+        let slow_sender = Address::repeat_byte(1);
+        let other_sender = Address::repeat_byte(2);
+
+        // alone, a sender can use the whole budget
+        let mut slow_grants: Vec<_> = (0..4)
+            .map(|_| budget.try_grant(slow_sender).unwrap())
+            .collect();
+        assert_eq!(
+            budget.try_grant(other_sender).err(),
+            Some(BudgetDenied::Exhausted)
+        );
+
+        // once the other sender waits, the slow one is held to its share
+        slow_grants.pop();
+        assert_eq!(
+            budget.try_grant(slow_sender).err(),
+            Some(BudgetDenied::FairShare)
+        );
+        let other_grant = budget.try_grant(other_sender).unwrap();
+        assert_eq!(budget.in_flight(), 4);
+
+        drop(other_grant);
+        slow_grants.clear();
+        assert_eq!(budget.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_last_rav_is_always_granted() {
+        let budget = RavRequestBudget::new(1);
+        let sender = Address::repeat_byte(1);
+        let other_sender = Address::repeat_byte(2);
+
+        let grant = budget.try_grant(sender).unwrap();
+        let last_rav = budget.grant_last_rav(other_sender);
+        assert_eq!(budget.in_flight(), 2);
+
+        // the routine requests wait for the last ravs
+        drop(grant);
+        assert_eq!(
+            budget.try_grant(sender).err(),
+            Some(BudgetDenied::Exhausted)
+        );
+        drop(last_rav);
+        assert!(budget.try_grant(sender).is_ok());
+    }
+}
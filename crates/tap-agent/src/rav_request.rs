@@ -0,0 +1,164 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! RAV requests forced by the operator, e.g. to flush the fees before a
+//! maintenance or before closing an allocation
+//!
+//! `POST /rav-request` is served on the metrics port if `[tap] admin_token`
+//! is set, and called by the `rav-request` subcommand. The trigger value is
+//! ignored, but the receipts within the timestamp buffer are still left out
+//! of the RAVs.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use ractor::{call_t, ActorRef};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use thegraph_core::alloy::primitives::Address;
+
+use crate::{agent::sender_accounts_manager::SenderAccountsManagerMessage, CONFIG};
+
+/// Time given to the sender accounts to trigger the RAV requests, the RAVs
+/// themselves are not waited for
+const RAV_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of `POST /rav-request`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RavRequest {
+    /// Sender of the receipts
+    pub sender: Address,
+    /// Every allocation of the sender if not set
+    pub allocation_id: Option<Address>,
+}
+
+/// Response of `POST /rav-request`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RavRequestReport {
+    /// Allocations a RAV was requested for, the other ones had no receipts
+    /// outside of the timestamp buffer or were already requesting one
+    pub allocation_ids: Vec<Address>,
+}
+
+/// State of the `/rav-request` route
+#[derive(Clone)]
+pub struct RavRequestState {
+    /// Root of the actor tree
+    pub manager: ActorRef<SenderAccountsManagerMessage>,
+    /// Bearer token required to call the route
+    pub admin_token: String,
+}
+
+/// Router serving `/rav-request`
+pub fn router(state: RavRequestState) -> Router {
+    Router::new()
+        .route("/rav-request", post(rav_request))
+        .with_state(state)
+}
+
+async fn rav_request(
+    State(state): State<RavRequestState>,
+    headers: HeaderMap,
+    Json(RavRequest {
+        sender,
+        allocation_id,
+    }): Json<RavRequest>,
+) -> impl IntoResponse {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token.as_bytes().ct_eq(state.admin_token.as_bytes()).into());
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "Invalid admin token" })),
+        )
+            .into_response();
+    }
+
+    match call_t!(
+        state.manager,
+        SenderAccountsManagerMessage::ForceRavRequest,
+        RAV_REQUEST_TIMEOUT.as_millis() as u64,
+        sender,
+        allocation_id
+    ) {
+        Ok(Some(allocation_ids)) => Json(RavRequestReport { allocation_ids }).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("Sender {sender} is not managed by this tap-agent") })),
+        )
+            .into_response(),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": error.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+/// Asks the running tap-agent to request the RAVs, on `url` or on the first
+/// address its metrics are served on
+pub async fn run(
+    sender: Address,
+    allocation_id: Option<Address>,
+    url: Option<String>,
+) -> anyhow::Result<()> {
+    let Some(admin_token) = &CONFIG.tap.admin_token else {
+        bail!("`[tap] admin_token` is required to request RAVs");
+    };
+    let url = match url {
+        Some(url) => url,
+        None => {
+            let mut addr = CONFIG
+                .tap
+                .metrics_hosts_and_ports
+                .first()
+                .copied()
+                .or_else(|| CONFIG.metrics.get_socket_addrs().first().copied())
+                .ok_or_else(|| anyhow!("No metrics address is configured, use `--url`"))?;
+            if addr.ip().is_unspecified() {
+                addr.set_ip([127, 0, 0, 1].into());
+            }
+            format!("http://{addr}")
+        }
+    };
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/rav-request", url.trim_end_matches('/')))
+        .bearer_auth(admin_token)
+        .json(&RavRequest {
+            sender,
+            allocation_id,
+        })
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("tap-agent answered {status}: {}", response.text().await?);
+    }
+
+    let report: RavRequestReport = response.json().await?;
+    if report.allocation_ids.is_empty() {
+        tracing::info!(
+            %sender,
+            "No RAV was requested, the allocations have no receipts outside of the \
+            timestamp buffer or are already requesting one"
+        );
+    }
+    for allocation_id in report.allocation_ids {
+        tracing::info!(%sender, %allocation_id, "RAV requested");
+    }
+    Ok(())
+}
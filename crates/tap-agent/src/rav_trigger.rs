@@ -0,0 +1,195 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # Adaptive RAV trigger
+//!
+//! This module provides [AdaptiveTrigger], which adjusts the trigger value of
+//! a sender between a minimum and a maximum around the fixed trigger value.
+//!
+//! ## Behaviour
+//! The fixed trigger value is first raised by the ratio between the average
+//! duration of the RAV requests and the duration considered slow, so a slow
+//! aggregator gets fewer, larger requests.
+//!
+//! It's then lowered so the fees are aggregated while the headroom, what can
+//! still be received before the sender is denied, covers the receipts
+//! expected during the horizon at the rate they were received during the
+//! window. This way the trigger value drops when the receipts come in faster
+//! or the escrow balance is running out, before the sender gets denied.
+//!
+//! The result is kept between the minimum and the maximum, so a sender
+//! close to denial doesn't request a RAV for every receipt.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use indexer_config::AdaptiveTriggerConfig;
+use thegraph_core::alloy::primitives::Address;
+
+/// Weight of the last RAV request in the average duration of the requests
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// Trigger value of a sender adjusted to its receipts, escrow headroom and
+/// aggregator
+///
+/// More information on [crate::rav_trigger]
+pub struct AdaptiveTrigger {
+    base: u128,
+    min: u128,
+    max: u128,
+    window: Duration,
+    horizon: Duration,
+    slow_aggregator: Duration,
+    /// Value of the receipts received during the window, oldest first
+    inflow: VecDeque<(Instant, u128)>,
+    inflow_total: u128,
+    /// Moving average of the duration of the RAV requests
+    aggregator_latency: Option<Duration>,
+    /// Start of the RAV requests in flight, per allocation
+    rav_requests: HashMap<Address, Instant>,
+}
+
+impl AdaptiveTrigger {
+    /// Creates an [AdaptiveTrigger] around the fixed trigger value `base`
+    pub fn new(base: u128, config: &AdaptiveTriggerConfig) -> Self {
+        let min = scale(base, config.min_trigger_ratio).max(1);
+        Self {
+            base,
+            min,
+            max: scale(base, config.max_trigger_ratio).max(base).max(min),
+            window: config.velocity_window_secs,
+            horizon: config.headroom_horizon_secs,
+            slow_aggregator: config.slow_aggregator_secs,
+            inflow: VecDeque::new(),
+            inflow_total: 0,
+            aggregator_latency: None,
+            rav_requests: HashMap::new(),
+        }
+    }
+
+    /// Records the value of a receipt received at `now`
+    pub fn record_receipt(&mut self, value: u128, now: Instant) {
+        self.inflow.push_back((now, value));
+        self.inflow_total = self.inflow_total.saturating_add(value);
+        self.expire_inflow(now);
+    }
+
+    /// Records the start of a RAV request for `allocation_id`
+    pub fn start_rav_request(&mut self, allocation_id: Address, now: Instant) {
+        self.rav_requests.insert(allocation_id, now);
+    }
+
+    /// Records the end of the RAV request of `allocation_id`, successful or not
+    pub fn finish_rav_request(&mut self, allocation_id: Address, now: Instant) {
+        let Some(started_at) = self.rav_requests.remove(&allocation_id) else {
+            return;
+        };
+        let latency = now.saturating_duration_since(started_at);
+        self.aggregator_latency = Some(match self.aggregator_latency {
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING)
+            }
+            None => latency,
+        });
+    }
+
+    /// Value of the receipts received per second during the window
+    fn velocity(&self) -> f64 {
+        self.inflow_total as f64 / self.window.as_secs_f64()
+    }
+
+    fn expire_inflow(&mut self, now: Instant) {
+        while let Some((received_at, value)) = self.inflow.front() {
+            if now.saturating_duration_since(*received_at) <= self.window {
+                break;
+            }
+            self.inflow_total -= value;
+            self.inflow.pop_front();
+        }
+    }
+
+    /// Trigger value at `now`, given the value that can still be received
+    /// before the sender is denied
+    pub fn trigger_value(&mut self, headroom: u128, now: Instant) -> u128 {
+        self.expire_inflow(now);
+
+        let slowness = match self.aggregator_latency {
+            Some(latency) if !self.slow_aggregator.is_zero() && latency > self.slow_aggregator => {
+                latency.as_secs_f64() / self.slow_aggregator.as_secs_f64()
+            }
+            _ => 1.0,
+        };
+        let expected_inflow = (self.velocity() * self.horizon.as_secs_f64()) as u128;
+        scale(self.base, slowness)
+            .min(headroom.saturating_sub(expected_inflow))
+            .clamp(self.min, self.max)
+    }
+}
+
+fn scale(value: u128, ratio: f64) -> u128 {
+    (value as f64 * ratio) as u128
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use indexer_config::AdaptiveTriggerConfig;
+    use thegraph_core::alloy::primitives::Address;
+
+    use super::AdaptiveTrigger;
+
+    const BASE: u128 = 1_000;
+
+    fn trigger() -> AdaptiveTrigger {
+        AdaptiveTrigger::new(
+            BASE,
+            &AdaptiveTriggerConfig {
+                min_trigger_ratio: 0.1,
+                max_trigger_ratio: 2.0,
+                velocity_window_secs: Duration::from_secs(10),
+                headroom_horizon_secs: Duration::from_secs(5),
+                slow_aggregator_secs: Duration::from_secs(1),
+            },
+        )
+    }
+
+    #[test]
+    fn test_fixed_trigger_with_headroom() {
+        let mut trigger = trigger();
+        assert_eq!(trigger.trigger_value(u128::MAX, Instant::now()), BASE);
+    }
+
+    #[test]
+    fn test_lowered_by_velocity_and_headroom() {
+        let mut trigger = trigger();
+        let now = Instant::now();
+        // 100 per second over the window, 500 expected during the horizon
+        trigger.record_receipt(1_000, now);
+        assert_eq!(trigger.trigger_value(10_000, now), BASE);
+        assert_eq!(trigger.trigger_value(1_200, now), 700);
+        // never below the minimum
+        assert_eq!(trigger.trigger_value(300, now), 100);
+
+        // the receipts out of the window are forgotten
+        let later = now + Duration::from_secs(11);
+        assert_eq!(trigger.trigger_value(1_200, later), BASE);
+    }
+
+    #[test]
+    fn test_raised_by_slow_aggregator() {
+        let mut trigger = trigger();
+        let allocation = Address::ZERO;
+        let now = Instant::now();
+        trigger.start_rav_request(allocation, now);
+        trigger.finish_rav_request(allocation, now + Duration::from_millis(1_500));
+        assert_eq!(trigger.trigger_value(u128::MAX, now), 1_500);
+
+        // never above the maximum
+        trigger.start_rav_request(allocation, now);
+        trigger.finish_rav_request(allocation, now + Duration::from_secs(20));
+        assert_eq!(trigger.trigger_value(u128::MAX, now), 2 * BASE);
+    }
+}
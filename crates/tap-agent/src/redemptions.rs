@@ -0,0 +1,474 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reconciliation of the RAVs marked as last with their redemptions
+//!
+//! Once an allocation is closed, its last RAV is meant to be redeemed by
+//! indexer-agent. [track_rav_redemptions] periodically looks for the redeem
+//! transactions of these RAVs in the escrow subgraph and saves the status of
+//! each of them in `scalar_tap_rav_redemptions`, served with the totals per
+//! status at `/ravs/redemptions` on the metrics port.
+//!
+//! Only the legacy RAVs are reconciled, the escrow subgraph doesn't index
+//! the Horizon redemptions yet.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use bigdecimal::ToPrimitive;
+use indexer_monitor::SubgraphClient;
+use indexer_schema::{record_allocation_event, AllocationEvent};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{
+    types::{
+        chrono::{DateTime, Utc},
+        BigDecimal,
+    },
+    PgPool,
+};
+use thegraph_core::alloy::primitives::Address;
+
+use crate::agent::sender_account::query_redeemed_allocations;
+
+/// Number of decimals of GRT, values are stored in wei
+const GRT_DECIMALS: i64 = 18;
+
+lazy_static! {
+    static ref RAV_REDEMPTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "tap_rav_redemptions",
+        "RAVs marked as last per redemption status",
+        &["status"]
+    )
+    .unwrap();
+    static ref RAV_REDEMPTIONS_VALUE: GaugeVec = register_gauge_vec!(
+        "tap_rav_redemptions_grt_total",
+        "Value of the RAVs marked as last per redemption status",
+        &["status"]
+    )
+    .unwrap();
+}
+
+/// Redemption status of a RAV marked as last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RedemptionStatus {
+    /// No redeem transaction was indexed yet
+    Unredeemed,
+    /// Marked as final or a redeem transaction was indexed
+    Redeemed,
+    /// Still not redeemed after `tap.rav_redemptions.expiry_secs`
+    Expired,
+}
+
+impl RedemptionStatus {
+    const ALL: [RedemptionStatus; 3] = [
+        RedemptionStatus::Unredeemed,
+        RedemptionStatus::Redeemed,
+        RedemptionStatus::Expired,
+    ];
+
+    fn new(final_rav: bool, redeem_indexed: bool, expired: bool) -> Self {
+        if final_rav || redeem_indexed {
+            RedemptionStatus::Redeemed
+        } else if expired {
+            RedemptionStatus::Expired
+        } else {
+            RedemptionStatus::Unredeemed
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            RedemptionStatus::Unredeemed => "unredeemed",
+            RedemptionStatus::Redeemed => "redeemed",
+            RedemptionStatus::Expired => "expired",
+        }
+    }
+}
+
+/// Allocation, sender, value, final and whether the RAV outlived the expiry
+type LastRavRow = (String, String, BigDecimal, bool, bool);
+
+/// Reconciles the redemptions every `interval`. Never returns.
+pub async fn track_rav_redemptions(
+    pgpool: PgPool,
+    escrow_subgraph: &'static SubgraphClient,
+    interval: Duration,
+    expiry: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match reconcile_rav_redemptions(&pgpool, escrow_subgraph, expiry).await {
+            Ok(counts) => match counts.get(&RedemptionStatus::Expired) {
+                Some(expired) => tracing::warn!(
+                    expired,
+                    "RAVs marked as last were not redeemed before their expiry"
+                ),
+                None => tracing::debug!(?counts, "Reconciled the RAV redemptions"),
+            },
+            Err(error) => tracing::warn!(%error, "Failed to reconcile the RAV redemptions"),
+        }
+    }
+}
+
+/// Saves the redemption status of every RAV marked as last and returns the
+/// number of RAVs per status
+///
+/// Nothing is saved if the escrow subgraph can't be queried, a RAV would be
+/// reported as expired otherwise.
+pub async fn reconcile_rav_redemptions(
+    pgpool: &PgPool,
+    escrow_subgraph: &'static SubgraphClient,
+    expiry: Duration,
+) -> anyhow::Result<HashMap<RedemptionStatus, usize>> {
+    // `updated_at` is set every time a RAV is stored, so it's the time of
+    // the last one. It's missing for the RAVs stored by older versions
+    let ravs: Vec<LastRavRow> = sqlx::query!(
+        r#"
+            SELECT
+                allocation_id,
+                sender_address,
+                value_aggregate,
+                final AS final_rav,
+                COALESCE(updated_at, created_at, NOW()) < NOW() - make_interval(secs => $1)
+                    AS "expired!"
+            FROM scalar_tap_ravs
+            WHERE last
+        "#,
+        expiry.as_secs_f64()
+    )
+    .fetch_all(pgpool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            row.allocation_id,
+            row.sender_address,
+            row.value_aggregate,
+            row.final_rav,
+            row.expired,
+        )
+    })
+    .collect();
+
+    let mut unfinalized: HashMap<&str, Vec<String>> = HashMap::new();
+    for (allocation_id, sender, _, final_rav, _) in &ravs {
+        if !final_rav {
+            unfinalized
+                .entry(sender.as_str())
+                .or_default()
+                .push(allocation_id.clone());
+        }
+    }
+    let mut redeemed = HashSet::new();
+    for (sender, allocation_ids) in unfinalized {
+        let redeemed_allocations =
+            query_redeemed_allocations(escrow_subgraph, Address::from_str(sender)?, allocation_ids)
+                .await?;
+        for allocation_id in redeemed_allocations {
+            redeemed.insert((sender, Address::from_str(&allocation_id)?));
+        }
+    }
+
+    let mut allocation_ids = Vec::with_capacity(ravs.len());
+    let mut senders = Vec::with_capacity(ravs.len());
+    let mut values = Vec::with_capacity(ravs.len());
+    let mut statuses = Vec::with_capacity(ravs.len());
+    let mut totals: HashMap<RedemptionStatus, (usize, f64)> = HashMap::new();
+    let mut redeemed_ravs = Vec::new();
+    for (allocation_id, sender, value, final_rav, expired) in &ravs {
+        let redeem_indexed =
+            redeemed.contains(&(sender.as_str(), Address::from_str(allocation_id)?));
+        let status = RedemptionStatus::new(*final_rav, redeem_indexed, *expired);
+        if status == RedemptionStatus::Redeemed {
+            redeemed_ravs.push((
+                Address::from_str(allocation_id)?,
+                Address::from_str(sender)?,
+            ));
+        }
+        let total = totals.entry(status).or_default();
+        total.0 += 1;
+        total.1 += wei_to_grt(value).to_f64().unwrap_or_default();
+
+        allocation_ids.push(allocation_id.clone());
+        senders.push(sender.clone());
+        values.push(value.clone());
+        statuses.push(status.as_str().to_string());
+    }
+
+    let mut transaction = pgpool.begin().await?;
+    sqlx::query!("DELETE FROM scalar_tap_rav_redemptions")
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        r#"
+            INSERT INTO scalar_tap_rav_redemptions (
+                allocation_id,
+                sender_address,
+                value_aggregate,
+                status
+            ) SELECT * FROM UNNEST(
+                $1::CHAR(40)[],
+                $2::CHAR(40)[],
+                $3::NUMERIC(39)[],
+                $4::TEXT[]
+            )
+        "#,
+        &allocation_ids,
+        &senders,
+        &values,
+        &statuses,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    // stored once per allocation and sender, the first time it is seen
+    for (allocation_id, sender) in redeemed_ravs {
+        if let Err(error) = record_allocation_event(
+            pgpool,
+            allocation_id,
+            AllocationEvent::RavRedeemed,
+            Some(sender),
+            None,
+        )
+        .await
+        {
+            tracing::warn!(%error, %allocation_id, %sender, "Failed to record a RAV redemption");
+        }
+    }
+
+    for status in RedemptionStatus::ALL {
+        let (count, value) = totals.get(&status).copied().unwrap_or_default();
+        RAV_REDEMPTIONS
+            .with_label_values(&[status.as_str()])
+            .set(count as i64);
+        RAV_REDEMPTIONS_VALUE
+            .with_label_values(&[status.as_str()])
+            .set(value);
+    }
+    Ok(totals
+        .into_iter()
+        .map(|(status, (count, _))| (status, count))
+        .collect())
+}
+
+/// Number and value of the RAVs with a status
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusTotal {
+    count: i64,
+    value_grt: BigDecimal,
+}
+
+/// RAV marked as last that is not redeemed yet
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UnredeemedRav {
+    allocation_id: Address,
+    sender: Address,
+    value_grt: BigDecimal,
+    status: String,
+}
+
+/// Result of the last reconciliation
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RedemptionsReport {
+    checked_at: Option<String>,
+    totals: BTreeMap<String, StatusTotal>,
+    unredeemed: Vec<UnredeemedRav>,
+}
+
+/// Router serving `/ravs/redemptions`
+///
+/// Not authenticated, it must be served behind the admin token.
+pub fn router(pgpool: PgPool) -> Router {
+    Router::new()
+        .route("/ravs/redemptions", get(redemptions))
+        .with_state(pgpool)
+}
+
+async fn redemptions(State(pgpool): State<PgPool>) -> impl IntoResponse {
+    match redemptions_report(&pgpool).await {
+        Ok(report) => Json(report).into_response(),
+        Err(error) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": error.to_string() })),
+        )
+            .into_response(),
+    }
+}
+
+async fn redemptions_report(pgpool: &PgPool) -> anyhow::Result<RedemptionsReport> {
+    let mut totals: BTreeMap<_, _> = RedemptionStatus::ALL
+        .iter()
+        .map(|status| (status.as_str().to_string(), StatusTotal::default()))
+        .collect();
+    let rows = sqlx::query!(
+        r#"
+            SELECT status, COUNT(*) AS "count!", SUM(value_aggregate) AS "value!"
+            FROM scalar_tap_rav_redemptions
+            GROUP BY status
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+    for row in rows {
+        totals.insert(
+            row.status,
+            StatusTotal {
+                count: row.count,
+                value_grt: wei_to_grt(&row.value),
+            },
+        );
+    }
+
+    let rows = sqlx::query!(
+        r#"
+            SELECT allocation_id, sender_address, value_aggregate, status
+            FROM scalar_tap_rav_redemptions
+            WHERE status <> 'redeemed'
+            ORDER BY value_aggregate DESC
+        "#
+    )
+    .fetch_all(pgpool)
+    .await?;
+    let unredeemed = rows
+        .into_iter()
+        .map(|row| {
+            Ok(UnredeemedRav {
+                allocation_id: Address::from_str(&row.allocation_id)?,
+                sender: Address::from_str(&row.sender_address)?,
+                value_grt: wei_to_grt(&row.value_aggregate),
+                status: row.status,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let checked_at: Option<DateTime<Utc>> =
+        sqlx::query_scalar!("SELECT MAX(checked_at) FROM scalar_tap_rav_redemptions")
+            .fetch_one(pgpool)
+            .await?;
+
+    Ok(RedemptionsReport {
+        checked_at: checked_at.map(|checked_at| checked_at.to_rfc3339()),
+        totals,
+        unredeemed,
+    })
+}
+
+fn wei_to_grt(value: &BigDecimal) -> BigDecimal {
+    let (digits, scale) = value.as_bigint_and_exponent();
+    BigDecimal::new(digits, scale + GRT_DECIMALS).normalized()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use indexer_monitor::{DeploymentDetails, SubgraphClient};
+    use indexer_schema::{allocation_events, AllocationEvent};
+    use serde_json::json;
+    use sqlx::PgPool;
+    use test_assets::{
+        ALLOCATION_ID_0, ALLOCATION_ID_1, ALLOCATION_ID_2, ALLOCATION_ID_3, TAP_SENDER as SENDER,
+        TAP_SIGNER as SIGNER,
+    };
+    use thegraph_core::alloy::hex::ToHexExt;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{reconcile_rav_redemptions, redemptions_report, RedemptionStatus};
+    use crate::test::{create_rav, store_rav_with_options};
+
+    const GRT: u128 = 1_000_000_000_000_000_000;
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_reconcile_rav_redemptions(pgpool: PgPool) {
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("transactions"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(
+                        json!({ "data": { "transactions": [
+                            {"id": "0x01", "allocationID": ALLOCATION_ID_0 }
+                        ]}}),
+                    )),
+            )
+            .await;
+        let escrow_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&mock_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+
+        // redeem transaction indexed, marked as final, too old, pending
+        for (allocation_id, final_rav) in [
+            (ALLOCATION_ID_0, false),
+            (ALLOCATION_ID_1, true),
+            (ALLOCATION_ID_2, false),
+            (ALLOCATION_ID_3, false),
+        ] {
+            store_rav_with_options()
+                .pgpool(&pgpool)
+                .signed_rav(create_rav(allocation_id, SIGNER.0.clone(), 4, GRT))
+                .sender(SENDER.1)
+                .last(true)
+                .final_rav(final_rav)
+                .call()
+                .await
+                .unwrap();
+        }
+        sqlx::query!(
+            "UPDATE scalar_tap_ravs SET updated_at = NOW() - INTERVAL '2 days' \
+            WHERE allocation_id = $1",
+            ALLOCATION_ID_2.encode_hex()
+        )
+        .execute(&pgpool)
+        .await
+        .unwrap();
+
+        let counts =
+            reconcile_rav_redemptions(&pgpool, escrow_subgraph, Duration::from_secs(86400))
+                .await
+                .unwrap();
+        assert_eq!(counts[&RedemptionStatus::Redeemed], 2);
+        assert_eq!(counts[&RedemptionStatus::Expired], 1);
+        assert_eq!(counts[&RedemptionStatus::Unredeemed], 1);
+
+        let report = serde_json::to_value(redemptions_report(&pgpool).await.unwrap()).unwrap();
+        assert_eq!(report["totals"]["redeemed"]["count"], 2);
+        assert_eq!(report["totals"]["redeemed"]["valueGrt"], "2");
+        let unredeemed = report["unredeemed"].as_array().unwrap();
+        assert_eq!(unredeemed.len(), 2);
+        assert!(unredeemed.iter().any(|rav| rav["status"] == "expired"
+            && rav["allocationId"].as_str().unwrap().to_lowercase()
+                == ALLOCATION_ID_2.to_string().to_lowercase()));
+
+        // the redemptions are part of the timeline of the allocations
+        for allocation_id in [ALLOCATION_ID_0, ALLOCATION_ID_1] {
+            let events = allocation_events(&pgpool, allocation_id).await.unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event, AllocationEvent::RavRedeemed);
+            assert_eq!(events[0].sender, Some(SENDER.1));
+        }
+        assert!(allocation_events(&pgpool, ALLOCATION_ID_2)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks the invalid receipts again, see
+//! [crate::cli::Command::RevalidateInvalidReceipts]
+//!
+//! The receipts of `scalar_tap_receipts_invalid` that now pass the checks of
+//! a RAV request are moved back to `scalar_tap_receipts`. A running tap-agent
+//! is not told about it: it keeps counting their value as invalid fees, and
+//! keeps denying the senders denied because of them. It must be restarted
+//! once the receipts are moved, the fees are then summed up again from the
+//! database, the fee snapshots being deleted.
+
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, bail, Context as _};
+use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
+use indexer_monitor::{escrow_accounts_v1, EscrowAccounts, EscrowRpcFallback, SubgraphClient};
+use sqlx::{types::BigDecimal, PgPool};
+use tap_core::receipt::{checks::Check, Context};
+use tap_graph::{Receipt, SignedReceipt};
+use thegraph_core::alloy::{hex::ToHexExt, primitives::Address, sol_types::Eip712Domain};
+use tokio::sync::watch::Receiver;
+
+use crate::{
+    agent::{escrow_subgraph_client, sender_account::SenderAccountConfig},
+    database,
+    tap::{
+        context::checks::{AllocationId, Signature},
+        CheckingReceipt, TapReceipt,
+    },
+    CONFIG, EIP_712_DOMAIN,
+};
+
+/// Number of invalid receipts read at once
+const BATCH_SIZE: i64 = 1000;
+
+/// Result of [revalidate_invalid_receipts]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RevalidatedReceipts {
+    /// Invalid receipts checked again
+    pub checked: u64,
+    /// Receipts that passed the checks, moved back unless it's a dry run
+    pub valid: u64,
+    /// Sum of the values of the valid receipts
+    pub valid_value: u128,
+}
+
+struct InvalidReceiptRow {
+    id: i64,
+    signature: Vec<u8>,
+    allocation_id: String,
+    timestamp_ns: BigDecimal,
+    nonce: BigDecimal,
+    value: BigDecimal,
+}
+
+impl TryFrom<InvalidReceiptRow> for SignedReceipt {
+    type Error = anyhow::Error;
+
+    fn try_from(row: InvalidReceiptRow) -> Result<Self, Self::Error> {
+        Ok(SignedReceipt {
+            message: Receipt {
+                allocation_id: Address::from_str(&row.allocation_id)?,
+                timestamp_ns: row.timestamp_ns.to_u64().context("Invalid timestamp_ns")?,
+                nonce: row.nonce.to_u64().context("Invalid nonce")?,
+                // BigDecimal::to_u128() goes through u64
+                value: row
+                    .value
+                    .to_bigint()
+                    .and_then(|value| value.to_u128())
+                    .context("Invalid value")?,
+            },
+            signature: row.signature.as_slice().try_into()?,
+        })
+    }
+}
+
+/// Last RAV of a sender for an allocation
+struct LastRav {
+    timestamp_ns: u64,
+    last: bool,
+}
+
+/// Checks of a RAV request, run again on the invalid receipts
+struct Checks<'a> {
+    pgpool: &'a PgPool,
+    escrow_accounts: Receiver<EscrowAccounts>,
+    escrow_subgraph: &'static SubgraphClient,
+    config: &'a SenderAccountConfig,
+    domain_separator: &'a Eip712Domain,
+    signature: Signature,
+    /// per sender and allocation
+    allocation_ids: HashMap<(Address, Address), AllocationId>,
+    /// per sender and allocation
+    last_ravs: HashMap<(Address, Address), Option<LastRav>>,
+}
+
+impl Checks<'_> {
+    async fn check(&mut self, receipt: SignedReceipt) -> anyhow::Result<()> {
+        let signer = receipt.recover_signer(self.domain_separator)?;
+        let allocation_id = receipt.message.allocation_id;
+        let timestamp_ns = receipt.message.timestamp_ns;
+        let receipt = CheckingReceipt::new(TapReceipt::V1(receipt));
+        let context = Context::new();
+
+        self.signature.check(&context, &receipt).await?;
+        let sender = self.sender(signer)?;
+
+        if !self.allocation_ids.contains_key(&(sender, allocation_id)) {
+            let check = AllocationId::new(
+                self.config.indexer_address,
+                self.config.escrow_polling_interval,
+                sender,
+                allocation_id,
+                self.escrow_subgraph,
+            )
+            .await;
+            self.allocation_ids.insert((sender, allocation_id), check);
+        }
+        self.allocation_ids[&(sender, allocation_id)]
+            .check(&context, &receipt)
+            .await?;
+
+        if !self.last_ravs.contains_key(&(sender, allocation_id)) {
+            let last_rav = last_rav(self.pgpool, sender, allocation_id).await?;
+            self.last_ravs.insert((sender, allocation_id), last_rav);
+        }
+        match &self.last_ravs[&(sender, allocation_id)] {
+            Some(rav) if rav.last => {
+                bail!("The last RAV of allocation {allocation_id} was requested")
+            }
+            Some(rav) if rav.timestamp_ns >= timestamp_ns => {
+                bail!("Receipt is older than the last RAV of allocation {allocation_id}")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sender of a signer that passed the [Signature] check, which may be a
+    /// key of a contract wallet
+    fn sender(&self, signer: Address) -> anyhow::Result<Address> {
+        let escrow_accounts = self.escrow_accounts.borrow();
+        let wallet = self
+            .config
+            .contract_signers
+            .as_ref()
+            .and_then(|contract_signers| contract_signers.cached_wallet(&signer));
+        escrow_accounts
+            .get_sender_for_any_signer(&signer)
+            .or_else(|error| match wallet {
+                Some(wallet) => escrow_accounts.get_sender_for_signer(&wallet),
+                None => Err(error),
+            })
+            .map_err(|error| anyhow!(error))
+    }
+}
+
+async fn last_rav(
+    pgpool: &PgPool,
+    sender: Address,
+    allocation_id: Address,
+) -> anyhow::Result<Option<LastRav>> {
+    let rav = sqlx::query!(
+        r#"
+            SELECT timestamp_ns, last
+            FROM scalar_tap_ravs
+            WHERE sender_address = $1 AND allocation_id = $2
+        "#,
+        sender.encode_hex(),
+        allocation_id.encode_hex(),
+    )
+    .fetch_optional(pgpool)
+    .await?;
+    rav.map(|rav| {
+        Ok(LastRav {
+            timestamp_ns: rav
+                .timestamp_ns
+                .to_u64()
+                .context("Invalid RAV timestamp_ns")?,
+            last: rav.last,
+        })
+    })
+    .transpose()
+}
+
+/// Runs the checks of a RAV request on the legacy invalid receipts again and
+/// moves the ones that pass back to `scalar_tap_receipts`
+///
+/// A receipt is only moved if it's newer than the last RAV of its sender
+/// and allocation, and the last RAV was not requested yet, since it could
+/// not be aggregated otherwise. Nothing is changed if `dry_run` is set.
+pub async fn revalidate_invalid_receipts(
+    pgpool: &PgPool,
+    escrow_accounts: Receiver<EscrowAccounts>,
+    escrow_subgraph: &'static SubgraphClient,
+    config: &SenderAccountConfig,
+    domain_separator: &Eip712Domain,
+    dry_run: bool,
+) -> anyhow::Result<RevalidatedReceipts> {
+    let mut checks = Checks {
+        pgpool,
+        signature: Signature::new(
+            domain_separator.clone(),
+            escrow_accounts.clone(),
+            config.contract_signers.clone(),
+        ),
+        escrow_accounts,
+        escrow_subgraph,
+        config,
+        domain_separator,
+        allocation_ids: HashMap::new(),
+        last_ravs: HashMap::new(),
+    };
+    let mut revalidated = RevalidatedReceipts::default();
+    let mut last_id = 0;
+    loop {
+        let rows = sqlx::query_as!(
+            InvalidReceiptRow,
+            r#"
+                SELECT id, signature, allocation_id, timestamp_ns, nonce, value
+                FROM scalar_tap_receipts_invalid
+                WHERE id > $1
+                ORDER BY id
+                LIMIT $2
+            "#,
+            last_id,
+            BATCH_SIZE,
+        )
+        .fetch_all(pgpool)
+        .await?;
+        let Some(last_row) = rows.last() else {
+            break;
+        };
+        last_id = last_row.id;
+
+        let mut valid_ids = Vec::new();
+        for row in rows {
+            let id = row.id;
+            revalidated.checked += 1;
+            let receipt = match SignedReceipt::try_from(row) {
+                Ok(receipt) => receipt,
+                Err(error) => {
+                    tracing::warn!(id, %error, "Could not decode invalid receipt");
+                    continue;
+                }
+            };
+            let value = receipt.message.value;
+            match checks.check(receipt).await {
+                Ok(()) => {
+                    valid_ids.push(id);
+                    revalidated.valid += 1;
+                    revalidated.valid_value += value;
+                }
+                Err(error) => tracing::debug!(id, %error, "Receipt is still invalid"),
+            }
+        }
+        if !dry_run && !valid_ids.is_empty() {
+            move_to_receipts(pgpool, &valid_ids).await?;
+        }
+    }
+    Ok(revalidated)
+}
+
+/// Moves the invalid receipts with the given ids back to `scalar_tap_receipts`
+async fn move_to_receipts(pgpool: &PgPool, ids: &[i64]) -> Result<(), sqlx::Error> {
+    let mut transaction = pgpool.begin().await?;
+    sqlx::query!(
+        r#"
+            WITH moved AS (
+                DELETE FROM scalar_tap_receipts_invalid
+                WHERE id = ANY($1)
+                RETURNING signer_address, signature, allocation_id, timestamp_ns, nonce, value
+            )
+            INSERT INTO scalar_tap_receipts (
+                signer_address, signature, allocation_id, timestamp_ns, nonce, value
+            )
+            SELECT * FROM moved
+            ON CONFLICT DO NOTHING
+        "#,
+        ids
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // the snapshots include the moved receipts in their invalid fees
+    sqlx::query!("DELETE FROM tap_agent_state")
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await
+}
+
+/// Entrypoint of [crate::cli::Command::RevalidateInvalidReceipts], configured
+/// with [crate::CONFIG]
+pub async fn run(dry_run: bool) -> anyhow::Result<()> {
+    let pgpool = database::connect(CONFIG.database.clone()).await;
+    let http_client = reqwest::Client::new();
+    let escrow_subgraph = Box::leak(Box::new(escrow_subgraph_client(http_client.clone()).await));
+    let escrow_rpc_fallback = CONFIG
+        .subgraphs
+        .escrow
+        .rpc_fallback
+        .as_ref()
+        .map(|fallback| {
+            EscrowRpcFallback::new(
+                http_client,
+                fallback.rpc_url.clone(),
+                fallback.escrow_address,
+                fallback.max_subgraph_lag_secs,
+            )
+            .with_signers(fallback.signers.clone())
+        });
+    let escrow_accounts = escrow_accounts_v1(
+        escrow_subgraph,
+        CONFIG.indexer.indexer_address,
+        CONFIG.subgraphs.escrow.config.syncing_interval_secs,
+        false,
+        escrow_rpc_fallback,
+        // revalidated against the current accounts only, not the ones saved on disk
+        None,
+        // the receipts to revalidate can be older than any max age
+        None,
+    )
+    .await?
+    .into_receiver();
+    let config = SenderAccountConfig::from_config(&CONFIG);
+
+    let revalidated = revalidate_invalid_receipts(
+        &pgpool,
+        escrow_accounts,
+        escrow_subgraph,
+        &config,
+        &EIP_712_DOMAIN,
+        dry_run,
+    )
+    .await?;
+    tracing::info!(
+        checked = revalidated.checked,
+        valid = revalidated.valid,
+        valid_value = revalidated.valid_value,
+        dry_run,
+        "Invalid receipts checked again"
+    );
+    if !dry_run && revalidated.valid > 0 {
+        tracing::warn!(
+            "Restart tap-agent for the moved receipts to be removed from its invalid fees"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use indexer_monitor::{DeploymentDetails, EscrowAccounts, SubgraphClient};
+    use serde_json::json;
+    use sqlx::PgPool;
+    use test_assets::{TAP_SENDER as SENDER, TAP_SIGNER as SIGNER};
+    use thegraph_core::alloy::primitives::U256;
+    use tokio::sync::watch;
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{revalidate_invalid_receipts, RevalidatedReceipts};
+    use crate::test::{
+        create_received_receipt, get_sender_account_config, store_invalid_receipt, wallet,
+        ALLOCATION_ID_0, TAP_EIP712_DOMAIN_SEPARATOR,
+    };
+
+    #[sqlx::test(migrations = "../../migrations")]
+    async fn test_revalidate_invalid_receipts(pgpool: PgPool) {
+        let escrow_subgraph_server = MockServer::start().await;
+        escrow_subgraph_server
+            .register(
+                Mock::given(method("POST"))
+                    .and(body_string_contains("TapTransactions"))
+                    .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                            "transactions": [],
+                        }
+                    }))),
+            )
+            .await;
+        let escrow_subgraph = Box::leak(Box::new(
+            SubgraphClient::new(
+                reqwest::Client::new(),
+                None,
+                DeploymentDetails::for_query_url(&escrow_subgraph_server.uri()).unwrap(),
+            )
+            .await,
+        ));
+        let (_, escrow_accounts) = watch::channel(EscrowAccounts::new(
+            HashMap::from([(SENDER.1, U256::from(1000))]),
+            HashMap::from([(SENDER.1, vec![SIGNER.1])]),
+        ));
+
+        let valid = create_received_receipt(&ALLOCATION_ID_0, &SIGNER.0, 1, 1, 10);
+        store_invalid_receipt(&pgpool, valid.signed_receipt())
+            .await
+            .unwrap();
+        let (unknown_signer, _) = wallet(5);
+        let invalid = create_received_receipt(&ALLOCATION_ID_0, &unknown_signer, 2, 2, 20);
+        store_invalid_receipt(&pgpool, invalid.signed_receipt())
+            .await
+            .unwrap();
+
+        let expected = RevalidatedReceipts {
+            checked: 2,
+            valid: 1,
+            valid_value: 10,
+        };
+        for dry_run in [true, false] {
+            let revalidated = revalidate_invalid_receipts(
+                &pgpool,
+                escrow_accounts.clone(),
+                escrow_subgraph,
+                get_sender_account_config(),
+                &TAP_EIP712_DOMAIN_SEPARATOR,
+                dry_run,
+            )
+            .await
+            .unwrap();
+            assert_eq!(revalidated, expected);
+        }
+
+        let receipts =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(receipts, 1);
+        let invalid_receipts =
+            sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM scalar_tap_receipts_invalid"#)
+                .fetch_one(&pgpool)
+                .await
+                .unwrap();
+        assert_eq!(invalid_receipts, 1);
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # Sender stats
+//!
+//! gRPC service streaming the state changes of the senders, so that control
+//! planes can follow them without polling the metrics.
+//!
+//! The sender accounts publish their unaggregated fees, deny status and RAV
+//! results here as they change. A subscriber first gets the last known fees
+//! and deny status of each sender, then every change as it happens.
+//!
+//! The service is defined in `crates/dips/proto/sender_stats.proto` and only
+//! served if `tap.sender_stats` is configured, to the subscribers sending its
+//! `auth_token`. Nothing is published otherwise.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream, StreamExt};
+use lazy_static::lazy_static;
+use subtle::ConstantTimeEq;
+use thegraph_core::alloy::primitives::Address;
+use tokio::sync::broadcast;
+use tonic::{Request, Response, Status};
+
+pub use indexer_dips::proto::sender_stats::graphprotocol::indexer::tap as proto;
+
+use self::proto::{
+    sender_stats_event::Event,
+    sender_stats_service_server::{SenderStatsService, SenderStatsServiceServer},
+    DenyStatus, RavResult, SenderStatsEvent, SubscribeSenderStatsRequest, UnaggregatedFees,
+};
+
+/// Events kept for the subscribers lagging behind
+const CHANNEL_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref HUB: Hub = Hub::new();
+}
+
+/// Set by [enable], the changes are not published otherwise
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Publishes the state changes of the senders from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Last known state of a sender, sent first to new subscribers
+#[derive(Default)]
+struct LastState {
+    fees: Option<SenderStatsEvent>,
+    deny_status: Option<SenderStatsEvent>,
+}
+
+struct Hub {
+    sender: broadcast::Sender<SenderStatsEvent>,
+    last_states: Mutex<HashMap<Address, LastState>>,
+}
+
+impl Hub {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender,
+            last_states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn publish(&self, sender: Address, event: Event) {
+        let event = SenderStatsEvent {
+            sender: sender.to_vec(),
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            event: Some(event),
+        };
+        // sent under the lock, so that a new subscriber gets each change
+        // either in its snapshot or from the channel, never both
+        let mut last_states = self.last_states.lock().unwrap();
+        let last_state = last_states.entry(sender).or_default();
+        match event.event {
+            Some(Event::UnaggregatedFees(_)) => last_state.fees = Some(event.clone()),
+            Some(Event::DenyStatus(_)) => last_state.deny_status = Some(event.clone()),
+            _ => {}
+        }
+        // an error only means that nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    fn forget(&self, sender: Address) {
+        self.last_states.lock().unwrap().remove(&sender);
+    }
+
+    fn subscribe(&self) -> (Vec<SenderStatsEvent>, broadcast::Receiver<SenderStatsEvent>) {
+        let last_states = self.last_states.lock().unwrap();
+        let snapshot = last_states
+            .values()
+            .flat_map(|state| state.fees.iter().chain(state.deny_status.iter()))
+            .cloned()
+            .collect();
+        (snapshot, self.sender.subscribe())
+    }
+}
+
+/// Publishes the fees of `sender` not redeemed yet
+pub fn publish_fees(sender: Address, unaggregated_fees: u128, pending_ravs: u128, escrow: u128) {
+    if !enabled() {
+        return;
+    }
+    HUB.publish(
+        sender,
+        Event::UnaggregatedFees(UnaggregatedFees {
+            unaggregated_fees: unaggregated_fees.to_string(),
+            pending_ravs: pending_ravs.to_string(),
+            escrow_balance: escrow.to_string(),
+        }),
+    );
+}
+
+/// Publishes that `sender` was denied or allowed again
+pub fn publish_deny_status(sender: Address, denied: bool) {
+    if !enabled() {
+        return;
+    }
+    HUB.publish(sender, Event::DenyStatus(DenyStatus { denied }));
+}
+
+/// Publishes the result of a RAV request for `allocation_id`, with the value
+/// of the RAV received if any
+pub fn publish_rav_result(
+    sender: Address,
+    allocation_id: Address,
+    result: Result<Option<u128>, &anyhow::Error>,
+) {
+    if !enabled() {
+        return;
+    }
+    let (success, value_aggregate, error) = match result {
+        Ok(value) => (true, value.map(|value| value.to_string()), None),
+        Err(error) => (false, None, Some(error.to_string())),
+    };
+    HUB.publish(
+        sender,
+        Event::RavResult(RavResult {
+            allocation_id: allocation_id.to_vec(),
+            success,
+            value_aggregate,
+            error,
+        }),
+    );
+}
+
+/// Forgets the last state of `sender`, once its account is stopped
+pub fn forget(sender: Address) {
+    if enabled() {
+        HUB.forget(sender);
+    }
+}
+
+/// Serves [SenderStatsService] on `host_and_port`, to the requests with the
+/// `authorization: Bearer <auth_token>` metadata
+pub async fn serve(host_and_port: SocketAddr, auth_token: String) {
+    tracing::info!(%host_and_port, "Serving the sender stats gRPC service");
+    let authorize = move |request: Request<()>| {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if token.as_bytes().ct_eq(auth_token.as_bytes()).into() => Ok(request),
+            _ => Err(Status::unauthenticated("invalid sender stats auth token")),
+        }
+    };
+    if let Err(error) = tonic::transport::Server::builder()
+        .add_service(SenderStatsServiceServer::with_interceptor(
+            SenderStatsServer,
+            authorize,
+        ))
+        .serve(host_and_port)
+        .await
+    {
+        tracing::error!(%error, "The sender stats gRPC service stopped");
+    }
+}
+
+/// Implementation of [SenderStatsService] streaming the published changes
+pub struct SenderStatsServer;
+
+#[tonic::async_trait]
+impl SenderStatsService for SenderStatsServer {
+    type SubscribeSenderStatsStream =
+        Pin<Box<dyn Stream<Item = Result<SenderStatsEvent, Status>> + Send>>;
+
+    async fn subscribe_sender_stats(
+        &self,
+        request: Request<SubscribeSenderStatsRequest>,
+    ) -> Result<Response<Self::SubscribeSenderStatsStream>, Status> {
+        let senders = request
+            .into_inner()
+            .senders
+            .iter()
+            .map(|sender| {
+                Address::try_from(sender.as_slice())
+                    .map_err(|_| Status::invalid_argument("Senders must be 20 bytes addresses"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let is_followed = move |event: &SenderStatsEvent| {
+            senders.is_empty() || senders.iter().any(|s| event.sender == s.as_slice())
+        };
+
+        let (snapshot, receiver) = HUB.subscribe();
+        let snapshot: Vec<_> = snapshot.into_iter().filter(&is_followed).collect();
+        let changes = stream::unfold(
+            (receiver, is_followed),
+            |(mut receiver, is_followed)| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) if is_followed(&event) => {
+                            return Some((Ok(event), (receiver, is_followed)))
+                        }
+                        Ok(_) => {}
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::warn!(skipped, "A sender stats subscriber lagged behind");
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        );
+        let events = stream::iter(snapshot.into_iter().map(Ok)).chain(changes);
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use thegraph_core::alloy::primitives::Address;
+    use tonic::Request;
+
+    use super::{
+        enable, forget,
+        proto::{
+            sender_stats_event::Event, sender_stats_service_server::SenderStatsService,
+            SubscribeSenderStatsRequest,
+        },
+        publish_deny_status, publish_fees, SenderStatsServer, HUB,
+    };
+
+    #[tokio::test]
+    async fn test_subscribe_sender_stats() {
+        enable();
+        let sender = Address::repeat_byte(0xaa);
+        let other_sender = Address::repeat_byte(0xbb);
+        publish_fees(sender, 10, 20, 100);
+
+        let mut events = SenderStatsServer
+            .subscribe_sender_stats(Request::new(SubscribeSenderStatsRequest {
+                senders: vec![sender.to_vec()],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // the last known fees are sent first
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.sender, sender.to_vec());
+        assert!(matches!(
+            event.event,
+            Some(Event::UnaggregatedFees(fees)) if fees.unaggregated_fees == "10"
+        ));
+
+        // then the changes of the followed senders only
+        publish_deny_status(other_sender, true);
+        publish_deny_status(sender, true);
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.sender, sender.to_vec());
+        assert!(matches!(
+            event.event,
+            Some(Event::DenyStatus(status)) if status.denied
+        ));
+
+        // not sent to new subscribers once the account is stopped
+        forget(sender);
+        let (snapshot, _) = HUB.subscribe();
+        assert!(snapshot.iter().all(|event| event.sender != sender.to_vec()));
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! # simulation
+//!
+//! Drives a [SenderAccount](crate::agent::sender_account::SenderAccount) and its
+//! [SenderAllocation](crate::agent::sender_allocation::SenderAllocation)s in virtual time, with
+//! scripted responses of the aggregator, so that their deny/allow and backoff logic can be
+//! tested deterministically and without waiting.
+//!
+//! The actors are the real ones, reading and storing the receipts and RAVs in the database. Only
+//! the aggregator of the sender is scripted, it answers the RAV requests with the next
+//! [AggregatorResponse], the RAVs being signed by a [MockAggregator]. The tokio clock is paused
+//! once the actors are started and only moves with [Simulation::advance], the buffer of the
+//! receipts following it through [crate::clock].
+//!
+//! ```ignore
+//! #[sqlx::test(migrations = "../../migrations")]
+//! async fn backoff(pgpool: PgPool) {
+//!     let mut simulation = Simulation::start()
+//!         .pgpool(pgpool)
+//!         .responses(vec![AggregatorResponse::Unavailable, AggregatorResponse::Rav])
+//!         .call()
+//!         .await;
+//!     simulation.receipt(ALLOCATION_ID_0, 1100).await;
+//!     assert!(simulation.denied().await);
+//!     simulation.advance(Duration::from_secs(2)).await;
+//!     assert!(!simulation.denied().await);
+//! }
+//! ```
+//!
+//! Simulations need the current thread runtime `#[sqlx::test]` and `#[tokio::test]` use, and
+//! are only built with the `simulation` feature.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use indexer_test_harness::{aggregator::MockAggregator, subgraphs};
+use ractor::{call, ActorRef};
+use reqwest::Url;
+use sqlx::PgPool;
+use tap_aggregator::grpc::v1::{
+    tap_aggregator_client::TapAggregatorClient,
+    tap_aggregator_server::{TapAggregator, TapAggregatorServer},
+    RavRequest as AggregatorRequest, RavResponse,
+};
+use test_assets::{TAP_SENDER as SENDER, TAP_SIGNER as SIGNER};
+use thegraph_core::alloy::primitives::Address;
+use tokio::{net::TcpListener, sync::mpsc, time::Instant};
+use tonic::{
+    transport::{server::TcpIncoming, Channel, Server},
+    Request, Response, Status,
+};
+use wiremock::MockServer;
+
+use crate::{
+    agent::{
+        sender_account::SenderAccountMessage,
+        sender_accounts_manager::{AllocationId, NewReceiptNotification},
+        sender_allocation::SenderAllocationMessage,
+    },
+    clock,
+    test::{
+        create_received_receipt, create_sender_account, store_receipt, ALLOCATION_ID_0,
+        ESCROW_VALUE, TAP_EIP712_DOMAIN_SEPARATOR, TRIGGER_VALUE,
+    },
+};
+
+/// Time moves by steps of this duration, the timers due within a step fire at its end
+const TICK: Duration = Duration::from_millis(10);
+
+/// Response of the aggregator to a RAV request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregatorResponse {
+    /// The receipts of the allocation are aggregated in a RAV
+    Rav,
+    /// The aggregator can't be reached, the request is retriable
+    Unavailable,
+    /// The aggregator refuses the receipts
+    Rejected,
+}
+
+/// RAV request received by the aggregator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RavRequest {
+    /// Allocation of the receipts of the request
+    pub allocation_id: Address,
+    /// Virtual time since the start of the simulation
+    pub at: Duration,
+    /// Scripted response to the request
+    pub response: AggregatorResponse,
+}
+
+/// What the aggregator was asked and answered
+#[derive(Default)]
+struct Ledger {
+    /// Responses to the next RAV requests, [AggregatorResponse::Rav] once all were used
+    responses: VecDeque<AggregatorResponse>,
+    rav_requests: Vec<(Address, Instant, AggregatorResponse)>,
+    /// Value of the last RAV of each allocation
+    ravs: HashMap<Address, u128>,
+}
+
+/// Aggregator of the sender, answering with the scripted responses
+///
+/// The requests answered with [AggregatorResponse::Rav] are forwarded to a [MockAggregator].
+struct ScriptedAggregator {
+    aggregator: TapAggregatorClient<Channel>,
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+#[tonic::async_trait]
+impl TapAggregator for ScriptedAggregator {
+    async fn aggregate_receipts(
+        &self,
+        request: Request<AggregatorRequest>,
+    ) -> Result<Response<RavResponse>, Status> {
+        let allocation_id = request
+            .get_ref()
+            .receipts
+            .first()
+            .and_then(|receipt| receipt.message.as_ref())
+            .map(|receipt| Address::from_slice(&receipt.allocation_id))
+            .unwrap_or_default();
+        let response = {
+            let mut ledger = self.ledger.lock().unwrap();
+            let response = ledger
+                .responses
+                .pop_front()
+                .unwrap_or(AggregatorResponse::Rav);
+            ledger
+                .rav_requests
+                .push((allocation_id, Instant::now(), response));
+            response
+        };
+        match response {
+            AggregatorResponse::Rav => {
+                let response = self
+                    .aggregator
+                    .clone()
+                    .aggregate_receipts(request.into_inner())
+                    .await?
+                    .into_inner();
+                if let Ok(rav) = response.signed_rav() {
+                    self.ledger
+                        .lock()
+                        .unwrap()
+                        .ravs
+                        .insert(rav.message.allocationId, rav.message.valueAggregate);
+                }
+                Ok(Response::new(response))
+            }
+            AggregatorResponse::Unavailable => Err(Status::unavailable("aggregator unavailable")),
+            AggregatorResponse::Rejected => Err(Status::invalid_argument("receipts rejected")),
+        }
+    }
+}
+
+/// Keeps the paused clock from auto-advancing while the actors wait for the database or the
+/// aggregator, tokio doesn't auto-advance it while a blocking task runs
+struct ClockHold {
+    /// The blocking task returns once it is dropped
+    _release: std::sync::mpsc::Sender<()>,
+}
+
+impl ClockHold {
+    fn new() -> Self {
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        tokio::task::spawn_blocking(move || released.recv());
+        Self { _release: release }
+    }
+}
+
+/// A [SenderAccount](crate::agent::sender_account::SenderAccount) of [SENDER] and its
+/// allocations, in virtual time
+pub struct Simulation {
+    pgpool: PgPool,
+    sender_account: ActorRef<SenderAccountMessage>,
+    /// Messages handled by the sender account
+    handled: mpsc::Receiver<SenderAccountMessage>,
+    allocations: HashMap<Address, ActorRef<SenderAllocationMessage>>,
+    /// Nonce of the last receipt
+    nonce: u64,
+    ledger: Arc<Mutex<Ledger>>,
+    started: Instant,
+    _aggregator: MockAggregator,
+    _escrow_subgraph: MockServer,
+    _clock_hold: ClockHold,
+}
+
+#[bon::bon]
+impl Simulation {
+    /// Starts the sender account and its `allocations`, then pauses the time
+    ///
+    /// The aggregator answers the RAV requests with `responses` in turn, then with RAVs.
+    #[builder]
+    pub async fn start(
+        pgpool: PgPool,
+        #[builder(default = vec![ALLOCATION_ID_0])] allocations: Vec<Address>,
+        #[builder(default = TRIGGER_VALUE)] trigger_value: u128,
+        #[builder(default = TRIGGER_VALUE * 2)] max_amount_willing_to_lose_grt: u128,
+        #[builder(default = ESCROW_VALUE * 10)] escrow_balance: u128,
+        #[builder(default = Duration::from_millis(100))] rav_request_buffer: Duration,
+        #[builder(default = Duration::from_secs(1))] retry_interval: Duration,
+        #[builder(default)] responses: Vec<AggregatorResponse>,
+    ) -> Self {
+        clock::simulated::start();
+        let escrow_subgraph = subgraphs::escrow_subgraph().await;
+        let aggregator = MockAggregator::start()
+            .wallet(SIGNER.0.clone())
+            .accepted_addresses([SIGNER.1].into())
+            .domain_separator(TAP_EIP712_DOMAIN_SEPARATOR.clone())
+            .call()
+            .await;
+
+        let ledger = Arc::new(Mutex::new(Ledger {
+            responses: responses.into(),
+            ..Default::default()
+        }));
+        let scripted_aggregator = ScriptedAggregator {
+            aggregator: TapAggregatorClient::connect(aggregator.url())
+                .await
+                .expect("Failed to connect to the aggregator"),
+            ledger: ledger.clone(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind the scripted aggregator");
+        let aggregator_endpoint =
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None)
+            .expect("Failed to listen for the scripted aggregator");
+        tokio::spawn(
+            Server::builder()
+                .add_service(TapAggregatorServer::new(scripted_aggregator))
+                .serve_with_incoming(incoming),
+        );
+
+        let (sender_account, handled, prefix, _) = create_sender_account()
+            .pgpool(pgpool.clone())
+            .initial_allocation(
+                allocations
+                    .iter()
+                    .copied()
+                    .map(AllocationId::Legacy)
+                    .collect::<HashSet<_>>(),
+            )
+            .escrow_subgraph_endpoint(&escrow_subgraph.uri())
+            .aggregator_endpoint(aggregator_endpoint)
+            .rav_request_trigger_value(trigger_value)
+            .max_amount_willing_to_lose_grt(max_amount_willing_to_lose_grt)
+            .escrow_balance(escrow_balance)
+            .rav_request_buffer(rav_request_buffer)
+            .retry_interval(retry_interval)
+            .call()
+            .await;
+        let allocations = allocations
+            .into_iter()
+            .map(|allocation_id| {
+                let name = format!("{}:{}:{}", prefix, SENDER.1, allocation_id);
+                let allocation = ActorRef::<SenderAllocationMessage>::where_is(name)
+                    .expect("The allocation was not spawned");
+                (allocation_id, allocation)
+            })
+            .collect();
+
+        tokio::time::pause();
+        let mut simulation = Self {
+            pgpool,
+            sender_account,
+            handled,
+            allocations,
+            nonce: 0,
+            ledger,
+            started: Instant::now(),
+            _aggregator: aggregator,
+            _escrow_subgraph: escrow_subgraph,
+            _clock_hold: ClockHold::new(),
+        };
+        simulation.settle().await;
+        simulation
+    }
+}
+
+impl Simulation {
+    /// Receives a receipt of `value` for `allocation_id`, timestamped now
+    ///
+    /// The receipt is stored in the database and its allocation notified, like the
+    /// [SenderAccountsManager](crate::agent::sender_accounts_manager::SenderAccountsManager)
+    /// does.
+    pub async fn receipt(&mut self, allocation_id: Address, value: u128) {
+        let allocation = self
+            .allocations
+            .get(&allocation_id)
+            .expect("Not an allocation of the simulation")
+            .clone();
+        let timestamp_ns = clock::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos() as u64;
+        self.nonce += 1;
+        let receipt =
+            create_received_receipt(&allocation_id, &SIGNER.0, self.nonce, timestamp_ns, value);
+        let id = store_receipt(&self.pgpool, receipt.signed_receipt())
+            .await
+            .expect("Failed to store the receipt");
+        allocation
+            .cast(SenderAllocationMessage::NewReceipt(
+                NewReceiptNotification {
+                    id,
+                    allocation_id,
+                    signer_address: SIGNER.1,
+                    timestamp_ns,
+                    value,
+                },
+            ))
+            .expect("Failed to send the receipt");
+        self.settle().await;
+    }
+
+    /// Advances the time by `duration`, firing the timers due along the way
+    pub async fn advance(&mut self, duration: Duration) {
+        let end = Instant::now() + duration;
+        while Instant::now() < end {
+            tokio::time::advance(TICK.min(end - Instant::now())).await;
+            self.settle().await;
+        }
+    }
+
+    /// Whether the sender is denied
+    pub async fn denied(&self) -> bool {
+        call!(self.sender_account, SenderAccountMessage::GetDeny)
+            .expect("Failed to get the deny status")
+    }
+
+    /// RAV requests received by the aggregator so far
+    pub fn rav_requests(&self) -> Vec<RavRequest> {
+        self.ledger
+            .lock()
+            .unwrap()
+            .rav_requests
+            .iter()
+            .map(|&(allocation_id, at, response)| RavRequest {
+                allocation_id,
+                at: at - self.started,
+                response,
+            })
+            .collect()
+    }
+
+    /// Value of the last RAV of `allocation_id`
+    pub fn rav(&self, allocation_id: Address) -> u128 {
+        self.ledger
+            .lock()
+            .unwrap()
+            .ravs
+            .get(&allocation_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Virtual time since the start of the simulation
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Waits until the actors handled every message, those they sent each other included
+    ///
+    /// The allocations, then the sender account, are asked for their state, which they answer
+    /// once they handled the messages received before, RAV requests included. Everything is
+    /// handled once the sender account handled nothing else between two rounds: whatever it
+    /// sent the allocations was handled by them within the round, and whatever they sent it
+    /// back would have been handled before its answer.
+    async fn settle(&mut self) {
+        loop {
+            for allocation in self.allocations.values() {
+                // stopped allocations have nothing left to handle
+                let _ = call!(allocation, SenderAllocationMessage::GetUnaggregatedReceipts);
+            }
+            self.denied().await;
+            let mut settled = true;
+            while let Ok(message) = self.handled.try_recv() {
+                settled &= matches!(message, SenderAccountMessage::GetDeny(_));
+            }
+            if settled {
+                return;
+            }
+        }
+    }
+}
@@ -1,12 +1,16 @@
 // Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{future::Future, marker::PhantomData};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
 
 use indexer_monitor::EscrowAccounts;
 use indexer_receipt::TapReceipt;
 use serde::Serialize;
-use sqlx::PgPool;
+use sqlx::{types::BigDecimal, PgPool};
 use tap_aggregator::grpc::{
     v1::RavRequest as AggregatorRequestV1, v2::RavRequest as AggregatorRequestV2,
 };
@@ -24,6 +28,7 @@ mod rav;
 mod receipt;
 
 pub use error::AdapterError;
+pub(crate) use receipt::{horizon_fees_after_rav, legacy_fees_after_rav};
 use tonic::{transport::Channel, Code, Status};
 
 /// This trait represents a version of the network for TapAgentContext
@@ -149,6 +154,23 @@ impl NetworkVersion for Horizon {
     }
 }
 
+/// `MAX(id)`, `SUM(value)` and `COUNT(*)` of the receipts not covered by a RAV
+pub type ReceiptSums = (Option<i64>, Option<BigDecimal>, Option<i64>);
+
+/// Fees recalculated when a RAV is stored with `strict_fee_calculation` enabled
+///
+/// Storing the RAV deletes the receipts it covers and sums up the ones left in
+/// the same repeatable read transaction, so a receipt stored concurrently is
+/// either aggregated by the RAV or summed up, never both.
+#[derive(Debug, Default)]
+pub struct RavFees {
+    /// Only the receipts up to this id are summed up
+    pub last_id: i64,
+    /// Receipts left by the last RAV stored, taken by the
+    /// [crate::agent::sender_allocation::SenderAllocation]
+    pub sums: Option<ReceiptSums>,
+}
+
 /// Context used by [tap_core::manager::Manager] that enables certain helper methods
 ///
 /// This context is implemented for PostgresSQL
@@ -162,9 +184,57 @@ pub struct TapAgentContext<T> {
     #[cfg_attr(test, builder(default = crate::test::INDEXER.1))]
     indexer_address: Address,
     escrow_accounts: Receiver<EscrowAccounts>,
+    /// Set with `strict_fee_calculation`, see [RavFees]
+    rav_fees: Option<Arc<Mutex<RavFees>>>,
     /// We use phantom data as a marker since it's
     /// only used to define what methods are available
     /// for each type of network
     #[builder(default = PhantomData)]
     _phantom: PhantomData<T>,
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use prost::Message;
+    use tap_aggregator::grpc::{v1, v2};
+    use test_assets::TAP_SIGNER as SIGNER;
+    use thegraph_core::alloy::primitives::Address;
+
+    use crate::test::{create_rav, create_rav_v2};
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_rav_response(
+            allocation_id in any::<[u8; 20]>().prop_map(Address::from),
+            timestamp_ns in any::<u64>(),
+            value_aggregate in any::<u128>(),
+        ) {
+            let rav = create_rav(allocation_id, SIGNER.0.clone(), timestamp_ns, value_aggregate);
+            let response = v1::RavResponse {
+                rav: Some(rav.clone().into()),
+            };
+            let decoded = v1::RavResponse::decode(response.encode_to_vec().as_slice()).unwrap();
+            prop_assert_eq!(decoded.signed_rav().unwrap(), rav);
+
+            let rav = create_rav_v2(allocation_id, SIGNER.0.clone(), timestamp_ns, value_aggregate);
+            let response = v2::RavResponse {
+                rav: Some(rav.clone().into()),
+            };
+            let decoded = v2::RavResponse::decode(response.encode_to_vec().as_slice()).unwrap();
+            prop_assert_eq!(decoded.signed_rav().unwrap(), rav);
+        }
+
+        #[test]
+        fn test_arbitrary_rav_response(raw in prop::collection::vec(any::<u8>(), 0..1024)) {
+            if let Ok(response) = v1::RavResponse::decode(raw.as_slice()) {
+                let _ = response.signed_rav();
+            }
+            if let Ok(response) = v2::RavResponse::decode(raw.as_slice()) {
+                let _ = response.signed_rav();
+            }
+        }
+    }
+}
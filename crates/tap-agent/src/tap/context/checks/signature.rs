@@ -2,9 +2,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::anyhow;
-use indexer_monitor::EscrowAccounts;
-use tap_core::receipt::checks::{Check, CheckError, CheckResult};
-use thegraph_core::alloy::{primitives::U256, sol_types::Eip712Domain};
+use indexer_monitor::{ContractSigners, EscrowAccounts, EscrowAccountsError};
+use tap_core::receipt::{
+    checks::{Check, CheckError, CheckResult},
+    WithValueAndTimestamp,
+};
+use thegraph_core::alloy::{
+    primitives::{Address, Bytes, U256},
+    sol_types::Eip712Domain,
+};
 use tokio::sync::watch::Receiver;
 
 use crate::tap::{CheckingReceipt, TapReceipt};
@@ -14,19 +20,57 @@ use crate::tap::{CheckingReceipt, TapReceipt};
 /// Verifies if the signatures are signed correctly by the list of provided signers.
 /// This is an important step since [tap_core] doesn't verify signatures by default and RavRequests
 /// may fail if any of those are wrong.
+///
+/// A signer that is not authorized may be a key of a contract wallet, the
+/// receipt is then accepted if an authorized wallet validates its signature.
+///
+/// The receipts of a revoked signer are only accepted if they were signed
+/// before it was revoked.
 pub struct Signature {
     domain_separator: Eip712Domain,
     escrow_accounts: Receiver<EscrowAccounts>,
+    contract_signers: Option<ContractSigners>,
 }
 
 impl Signature {
     /// Creates a new signature check
-    pub fn new(domain_separator: Eip712Domain, escrow_accounts: Receiver<EscrowAccounts>) -> Self {
+    pub fn new(
+        domain_separator: Eip712Domain,
+        escrow_accounts: Receiver<EscrowAccounts>,
+        contract_signers: Option<ContractSigners>,
+    ) -> Self {
         Self {
             domain_separator,
             escrow_accounts,
+            contract_signers,
         }
     }
+
+    /// Sender of the contract wallet that accepts the signature of `receipt`
+    async fn wallet_sender(
+        &self,
+        receipt: &CheckingReceipt,
+        signer: Address,
+    ) -> anyhow::Result<Option<Address>> {
+        let Some(contract_signers) = &self.contract_signers else {
+            return Ok(None);
+        };
+        let receipt = receipt.signed_receipt();
+        let signers = self.escrow_accounts.borrow().get_signers();
+        let wallet = contract_signers
+            .find_wallet(
+                &signers,
+                receipt.signing_hash(&self.domain_separator),
+                Bytes::copy_from_slice(&receipt.signature().as_bytes()),
+                signer,
+            )
+            .await?;
+        Ok(Some(
+            self.escrow_accounts
+                .borrow()
+                .get_sender_for_signer(&wallet)?,
+        ))
+    }
 }
 
 #[async_trait::async_trait]
@@ -40,12 +84,27 @@ impl Check<TapReceipt> for Signature {
             .signed_receipt()
             .recover_signer(&self.domain_separator)
             .map_err(|e| CheckError::Failed(e.into()))?;
+        let timestamp_ns = receipt.signed_receipt().timestamp_ns();
+        let sender = self
+            .escrow_accounts
+            .borrow()
+            .get_sender_for_receipt_signer(&signer, timestamp_ns);
+        let sender = match sender {
+            Ok(sender) => sender,
+            Err(error @ EscrowAccountsError::SignerRevoked { .. }) => {
+                return Err(CheckError::Failed(error.into()))
+            }
+            Err(error) => match self.wallet_sender(receipt, signer).await {
+                Ok(Some(sender)) => sender,
+                Ok(None) => return Err(CheckError::Failed(error.into())),
+                Err(wallet_error) => {
+                    tracing::debug!(%signer, error = %wallet_error, "Receipt signer is not a wallet key");
+                    return Err(CheckError::Failed(error.into()));
+                }
+            },
+        };
         let escrow_accounts = self.escrow_accounts.borrow();
 
-        let sender = escrow_accounts
-            .get_sender_for_signer(&signer)
-            .map_err(|e| CheckError::Failed(e.into()))?;
-
         let balance = escrow_accounts
             .get_balance_for_sender(&sender)
             .map_err(|e| CheckError::Failed(e.into()))?;
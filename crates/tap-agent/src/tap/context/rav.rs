@@ -7,7 +7,10 @@ use bigdecimal::{
     num_bigint::{BigInt, ToBigInt},
     ToPrimitive,
 };
-use sqlx::types::{chrono, BigDecimal};
+use sqlx::{
+    types::{chrono, BigDecimal},
+    PgExecutor,
+};
 use tap_core::manager::adapters::{RavRead, RavStore};
 use tap_graph::{ReceiptAggregateVoucher, SignedRav};
 #[allow(deprecated)]
@@ -17,7 +20,11 @@ use thegraph_core::alloy::{
     primitives::{Address, Bytes},
 };
 
-use super::{error::AdapterError, Horizon, Legacy, TapAgentContext};
+use super::{
+    error::AdapterError, horizon_fees_after_rav, legacy_fees_after_rav, Horizon, Legacy,
+    TapAgentContext,
+};
+use crate::{database, tap::signers_trimmed};
 
 /// Implements a [RavRead] for [tap_graph::ReceiptAggregateVoucher]
 /// in case [super::NetworkVersion] is [Legacy]
@@ -104,44 +111,88 @@ impl RavStore<ReceiptAggregateVoucher> for TapAgentContext<Legacy> {
     type AdapterError = AdapterError;
 
     async fn update_last_rav(&self, rav: SignedRav) -> Result<(), Self::AdapterError> {
-        let signature_bytes: Vec<u8> = rav.signature.as_bytes().to_vec();
-
-        let _fut = sqlx::query!(
-            r#"
-                INSERT INTO scalar_tap_ravs (
-                    sender_address,
-                    signature,
-                    allocation_id,
-                    timestamp_ns,
-                    value_aggregate,
-                    created_at,
-                    updated_at
-
+        let sender = self.sender.encode_hex();
+        let allocation_id = self.allocation_id.encode_hex();
+        let Some(rav_fees) = &self.rav_fees else {
+            return store_legacy_rav(&self.pgpool, &sender, &allocation_id, &rav)
+                .await
+                .map_err(|e| AdapterError::RavStore {
+                    error: e.to_string(),
+                });
+        };
+
+        let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender)
+            .await
+            .map_err(|e| AdapterError::RavStore {
+                error: format!("{:?}.", e),
+            })?;
+        let last_id = rav_fees.lock().unwrap().last_id;
+        let sums = database::repeatable_read(&self.pgpool, |conn| {
+            let sender = sender.clone();
+            let allocation_id = allocation_id.clone();
+            let signers = signers.clone();
+            let rav = rav.clone();
+            Box::pin(async move {
+                store_legacy_rav(&mut *conn, &sender, &allocation_id, &rav).await?;
+                legacy_fees_after_rav(
+                    &mut *conn,
+                    &allocation_id,
+                    &signers,
+                    last_id,
+                    &BigDecimal::from(rav.message.timestampNs),
                 )
-                VALUES ($1, $2, $3, $4, $5, $6, $6)
-                ON CONFLICT (allocation_id, sender_address)
-                DO UPDATE SET
-                    signature = $2,
-                    timestamp_ns = $4,
-                    value_aggregate = $5,
-                    updated_at = $6
-            "#,
-            self.sender.encode_hex(),
-            signature_bytes,
-            self.allocation_id.encode_hex(),
-            BigDecimal::from(rav.message.timestampNs),
-            BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
-            chrono::Utc::now()
-        )
-        .execute(&self.pgpool)
+                .await
+            })
+        })
         .await
         .map_err(|e| AdapterError::RavStore {
             error: e.to_string(),
         })?;
+        rav_fees.lock().unwrap().sums = Some(sums);
         Ok(())
     }
 }
 
+/// Upserts the last [Legacy] RAV of `sender` for `allocation_id`
+async fn store_legacy_rav(
+    executor: impl PgExecutor<'_>,
+    sender: &str,
+    allocation_id: &str,
+    rav: &SignedRav,
+) -> Result<(), sqlx::Error> {
+    let signature_bytes: Vec<u8> = rav.signature.as_bytes().to_vec();
+
+    sqlx::query!(
+        r#"
+            INSERT INTO scalar_tap_ravs (
+                sender_address,
+                signature,
+                allocation_id,
+                timestamp_ns,
+                value_aggregate,
+                created_at,
+                updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            ON CONFLICT (allocation_id, sender_address)
+            DO UPDATE SET
+                signature = $2,
+                timestamp_ns = $4,
+                value_aggregate = $5,
+                updated_at = $6
+        "#,
+        sender,
+        signature_bytes,
+        allocation_id,
+        BigDecimal::from(rav.message.timestampNs),
+        BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+        chrono::Utc::now()
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
 /// Implements a [RavRead] for [tap_graph::v2::ReceiptAggregateVoucher]
 /// in case [super::NetworkVersion] is [Horizon]
 ///
@@ -275,50 +326,97 @@ impl RavStore<tap_graph::v2::ReceiptAggregateVoucher> for TapAgentContext<Horizo
         &self,
         rav: tap_graph::v2::SignedRav,
     ) -> Result<(), Self::AdapterError> {
-        let signature_bytes: Vec<u8> = rav.signature.as_bytes().to_vec();
-
-        let _fut = sqlx::query!(
-            r#"
-                INSERT INTO tap_horizon_ravs (
-                    payer,
-                    data_service,
-                    service_provider,
-                    metadata,
-                    signature,
-                    allocation_id,
-                    timestamp_ns,
-                    value_aggregate,
-                    created_at,
-                    updated_at
+        let Some(rav_fees) = &self.rav_fees else {
+            return store_horizon_rav(&self.pgpool, &rav).await.map_err(|e| {
+                AdapterError::RavStore {
+                    error: e.to_string(),
+                }
+            });
+        };
+
+        let signers = signers_trimmed(self.escrow_accounts.clone(), self.sender)
+            .await
+            .map_err(|e| AdapterError::RavStore {
+                error: format!("{:?}.", e),
+            })?;
+        let last_id = rav_fees.lock().unwrap().last_id;
+        let allocation_id = self.allocation_id.encode_hex();
+        let payer = self.sender.encode_hex();
+        let service_provider = self.indexer_address.encode_hex();
+        let sums = database::repeatable_read(&self.pgpool, |conn| {
+            let allocation_id = allocation_id.clone();
+            let payer = payer.clone();
+            let service_provider = service_provider.clone();
+            let signers = signers.clone();
+            let rav = rav.clone();
+            Box::pin(async move {
+                store_horizon_rav(&mut *conn, &rav).await?;
+                horizon_fees_after_rav(
+                    &mut *conn,
+                    &allocation_id,
+                    &payer,
+                    &service_provider,
+                    &signers,
+                    last_id,
+                    &BigDecimal::from(rav.message.timestampNs),
                 )
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
-                ON CONFLICT (payer, data_service, service_provider, allocation_id)
-                DO UPDATE SET
-                    signature = $5,
-                    timestamp_ns = $7,
-                    value_aggregate = $8,
-                    updated_at = $9,
-                    metadata = $4
-            "#,
-            rav.message.payer.encode_hex(),
-            rav.message.dataService.encode_hex(),
-            rav.message.serviceProvider.encode_hex(),
-            rav.message.metadata.as_ref(),
-            signature_bytes,
-            rav.message.allocationId.encode_hex(),
-            BigDecimal::from(rav.message.timestampNs),
-            BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
-            chrono::Utc::now()
-        )
-        .execute(&self.pgpool)
+                .await
+            })
+        })
         .await
         .map_err(|e| AdapterError::RavStore {
             error: e.to_string(),
         })?;
+        rav_fees.lock().unwrap().sums = Some(sums);
         Ok(())
     }
 }
 
+/// Upserts the last [Horizon] RAV of its payer for its allocation
+async fn store_horizon_rav(
+    executor: impl PgExecutor<'_>,
+    rav: &tap_graph::v2::SignedRav,
+) -> Result<(), sqlx::Error> {
+    let signature_bytes: Vec<u8> = rav.signature.as_bytes().to_vec();
+
+    sqlx::query!(
+        r#"
+            INSERT INTO tap_horizon_ravs (
+                payer,
+                data_service,
+                service_provider,
+                metadata,
+                signature,
+                allocation_id,
+                timestamp_ns,
+                value_aggregate,
+                created_at,
+                updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9)
+            ON CONFLICT (payer, data_service, service_provider, allocation_id)
+            DO UPDATE SET
+                signature = $5,
+                timestamp_ns = $7,
+                value_aggregate = $8,
+                updated_at = $9,
+                metadata = $4
+        "#,
+        rav.message.payer.encode_hex(),
+        rav.message.dataService.encode_hex(),
+        rav.message.serviceProvider.encode_hex(),
+        rav.message.metadata.as_ref(),
+        signature_bytes,
+        rav.message.allocationId.encode_hex(),
+        BigDecimal::from(rav.message.timestampNs),
+        BigDecimal::from(BigInt::from(rav.message.valueAggregate)),
+        chrono::Utc::now()
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
 
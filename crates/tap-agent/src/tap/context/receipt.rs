@@ -9,12 +9,12 @@ use std::{
 
 use bigdecimal::{num_bigint::ToBigInt, ToPrimitive};
 use indexer_receipt::TapReceipt;
-use sqlx::{postgres::types::PgRange, types::BigDecimal};
+use sqlx::{postgres::types::PgRange, types::BigDecimal, PgConnection};
 use tap_core::manager::adapters::{safe_truncate_receipts, ReceiptDelete, ReceiptRead};
 use tap_graph::{Receipt, SignedReceipt};
 use thegraph_core::alloy::{hex::ToHexExt, primitives::Address};
 
-use super::{error::AdapterError, Horizon, Legacy, TapAgentContext};
+use super::{error::AdapterError, Horizon, Legacy, ReceiptSums, TapAgentContext};
 use crate::tap::{signers_trimmed, CheckingReceipt};
 impl From<TryFromIntError> for AdapterError {
     fn from(error: TryFromIntError) -> Self {
@@ -378,6 +378,109 @@ impl ReceiptDelete for TapAgentContext<Horizon> {
     }
 }
 
+/// Deletes the [Legacy] receipts covered by a RAV with `rav_timestamp_ns`
+/// and sums up the ones left up to `last_id`
+pub(crate) async fn legacy_fees_after_rav(
+    conn: &mut PgConnection,
+    allocation_id: &str,
+    signers: &[String],
+    last_id: i64,
+    rav_timestamp_ns: &BigDecimal,
+) -> Result<ReceiptSums, sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM scalar_tap_receipts
+            WHERE allocation_id = $1
+                AND signer_address IN (SELECT unnest($2::text[]))
+                AND timestamp_ns <= $3
+        "#,
+        allocation_id,
+        signers,
+        rav_timestamp_ns,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let res = sqlx::query!(
+        r#"
+            SELECT
+                MAX(id),
+                SUM(value),
+                COUNT(*)
+            FROM
+                scalar_tap_receipts
+            WHERE
+                allocation_id = $1
+                AND id <= $2
+                AND signer_address IN (SELECT unnest($3::text[]))
+                AND timestamp_ns > $4
+        "#,
+        allocation_id,
+        last_id,
+        signers,
+        rav_timestamp_ns,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok((res.max, res.sum, res.count))
+}
+
+/// Deletes the [Horizon] receipts covered by a RAV with `rav_timestamp_ns`
+/// and sums up the ones left up to `last_id`
+pub(crate) async fn horizon_fees_after_rav(
+    conn: &mut PgConnection,
+    allocation_id: &str,
+    payer: &str,
+    service_provider: &str,
+    signers: &[String],
+    last_id: i64,
+    rav_timestamp_ns: &BigDecimal,
+) -> Result<ReceiptSums, sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM tap_horizon_receipts
+            WHERE
+                allocation_id = $1
+                AND payer = $2
+                AND service_provider = $3
+                AND signer_address IN (SELECT unnest($4::text[]))
+                AND timestamp_ns <= $5
+        "#,
+        allocation_id,
+        payer,
+        service_provider,
+        signers,
+        rav_timestamp_ns,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let res = sqlx::query!(
+        r#"
+            SELECT
+                MAX(id),
+                SUM(value),
+                COUNT(*)
+            FROM
+                tap_horizon_receipts
+            WHERE
+                allocation_id = $1
+                AND service_provider = $2
+                AND id <= $3
+                AND signer_address IN (SELECT unnest($4::text[]))
+                AND timestamp_ns > $5
+        "#,
+        allocation_id,
+        service_provider,
+        last_id,
+        signers,
+        rav_timestamp_ns,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok((res.max, res.sum, res.count))
+}
+
 #[cfg(test)]
 mod test {
     use std::{
@@ -18,14 +18,15 @@ pub type CheckingReceipt = ReceiptWithState<Checking, TapReceipt>;
 /// Context implmentation
 pub mod context;
 
-/// Helper function used to get a list of all signers in [String] for a given `sender`.
+/// Helper function used to get a list of all signers in [String] for a given `sender`,
+/// including the revoked ones whose receipts signed before they were revoked are aggregated.
 pub async fn signers_trimmed(
     escrow_accounts_rx: Receiver<EscrowAccounts>,
     sender: Address,
 ) -> Result<Vec<String>, anyhow::Error> {
     let escrow_accounts = escrow_accounts_rx.borrow();
     let signers = escrow_accounts
-        .get_signers_for_sender(&sender)
+        .get_receipt_signers_for_sender(&sender)
         .iter()
         .map(|s| s.encode_hex())
         .collect::<Vec<String>>();
@@ -4,21 +4,21 @@
 #![allow(missing_docs)]
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
     time::Duration,
 };
 
 use actors::TestableActor;
 use anyhow::anyhow;
 use bigdecimal::num_bigint::BigInt;
+use indexer_config::TapReceiptChecksConfig;
 use indexer_monitor::{DeploymentDetails, EscrowAccounts, SubgraphClient};
 use indexer_receipt::TapReceipt;
+use indexer_test_harness::aggregator::MockAggregator;
 use lazy_static::lazy_static;
 use ractor::{concurrency::JoinHandle, Actor, ActorRef};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use reqwest::Url;
 use sqlx::{types::BigDecimal, PgPool};
-use tap_aggregator::server::run_server;
 use tap_core::{signed_message::Eip712SignedMessage, tap_eip712_domain};
 use tap_graph::{Receipt, ReceiptAggregateVoucher, SignedRav, SignedReceipt};
 use test_assets::{flush_messages, TAP_SENDER as SENDER, TAP_SIGNER as SIGNER};
@@ -46,6 +46,7 @@ use crate::{
             SenderAccountsManagerMessage, SenderType,
         },
     },
+    rav_budget::RavRequestBudget,
     tap::{
         context::{AdapterError, Horizon, Legacy, NetworkVersion},
         CheckingReceipt,
@@ -83,17 +84,39 @@ pub fn generate_random_prefix() -> String {
 }
 
 pub fn get_sender_account_config() -> &'static SenderAccountConfig {
-    Box::leak(Box::new(SenderAccountConfig {
+    Box::leak(Box::new(sender_account_config()))
+}
+
+pub fn sender_account_config() -> SenderAccountConfig {
+    SenderAccountConfig {
         rav_request_buffer: RAV_REQUEST_BUFFER,
         max_amount_willing_to_lose_grt: TRIGGER_VALUE + 100,
         trigger_value: TRIGGER_VALUE,
+        adaptive_trigger: None,
+        rav_request_budget: RavRequestBudget::new(100),
         rav_request_timeout: Duration::from_secs(30),
         rav_request_receipt_limit: 1000,
+        strict_fee_calculation: false,
         indexer_address: INDEXER.1,
         escrow_polling_interval: ESCROW_POLLING_INTERVAL,
         tap_sender_timeout: Duration::from_secs(63),
         trusted_senders: HashSet::new(),
-    }))
+        sender_max_amounts_willing_to_lose: HashMap::new(),
+        sender_trigger_values: HashMap::new(),
+        sender_rav_request_buffers: HashMap::new(),
+        idle_timeout: None,
+        fee_snapshot_interval: None,
+        fee_snapshot_max_age: Duration::ZERO,
+        contract_signers: None,
+        allocation_close_grace_period: Duration::ZERO,
+        sharding: None,
+        top_allocations: 10,
+        tracker_export_interval: Duration::from_secs(15),
+        receipt_checks: TapReceiptChecksConfig {
+            allocation_id: true,
+            signature: true,
+        },
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -108,6 +131,10 @@ pub async fn create_sender_account(
     #[builder(default = RECEIPT_LIMIT)] rav_request_receipt_limit: u64,
     aggregator_endpoint: Option<Url>,
     #[builder(default = false)] trusted_sender: bool,
+    #[builder(default = Duration::ZERO)] allocation_close_grace_period: Duration,
+    #[builder(default = BUFFER_DURATION)] rav_request_buffer: Duration,
+    #[builder(default = RETRY_DURATION)] retry_interval: Duration,
+    #[builder(default = ESCROW_VALUE)] escrow_balance: u128,
 ) -> (
     ActorRef<SenderAccountMessage>,
     mpsc::Receiver<SenderAccountMessage>,
@@ -120,15 +147,33 @@ pub async fn create_sender_account(
         HashSet::new()
     };
     let config = Box::leak(Box::new(SenderAccountConfig {
-        rav_request_buffer: BUFFER_DURATION,
+        rav_request_buffer,
         max_amount_willing_to_lose_grt,
         trigger_value: rav_request_trigger_value,
+        adaptive_trigger: None,
+        rav_request_budget: RavRequestBudget::new(100),
         rav_request_timeout: RAV_REQUEST_TIMEOUT,
         rav_request_receipt_limit,
+        strict_fee_calculation: false,
         indexer_address: INDEXER.1,
         escrow_polling_interval: Duration::default(),
         tap_sender_timeout: TAP_SENDER_TIMEOUT,
         trusted_senders,
+        sender_max_amounts_willing_to_lose: HashMap::new(),
+        sender_trigger_values: HashMap::new(),
+        sender_rav_request_buffers: HashMap::new(),
+        idle_timeout: None,
+        fee_snapshot_interval: None,
+        fee_snapshot_max_age: Duration::ZERO,
+        contract_signers: None,
+        allocation_close_grace_period,
+        sharding: None,
+        top_allocations: 10,
+        tracker_export_interval: Duration::from_secs(15),
+        receipt_checks: TapReceiptChecksConfig {
+            allocation_id: true,
+            signature: true,
+        },
     }));
 
     let network_subgraph = Box::leak(Box::new(
@@ -152,7 +197,7 @@ pub async fn create_sender_account(
     let (escrow_accounts_tx, escrow_accounts_rx) = watch::channel(EscrowAccounts::default());
     escrow_accounts_tx
         .send(EscrowAccounts::new(
-            HashMap::from([(SENDER.1, U256::from(ESCROW_VALUE))]),
+            HashMap::from([(SENDER.1, U256::from(escrow_balance))]),
             HashMap::from([(SENDER.1, vec![SIGNER.1])]),
         ))
         .expect("Failed to update escrow_accounts channel");
@@ -166,7 +211,8 @@ pub async fn create_sender_account(
 
     let args = SenderAccountArgs {
         config,
-        pgpool,
+        pgpool: pgpool.clone(),
+        replica_pgpool: pgpool,
         sender_id: SENDER.1,
         escrow_accounts: escrow_accounts_rx,
         indexer_allocations: watch::channel(initial_allocation).1,
@@ -176,7 +222,7 @@ pub async fn create_sender_account(
         sender_aggregator_endpoint: aggregator_url,
         allocation_ids: HashSet::new(),
         prefix: Some(prefix.clone()),
-        retry_interval: RETRY_DURATION,
+        retry_interval,
         sender_type: SenderType::Legacy,
     };
 
@@ -200,12 +246,16 @@ pub async fn create_sender_accounts_manager(
     escrow_subgraph: Option<&str>,
     initial_escrow_accounts_v1: Option<EscrowAccounts>,
     initial_escrow_accounts_v2: Option<EscrowAccounts>,
+    idle_timeout: Option<Duration>,
 ) -> (
     String,
     mpsc::Receiver<SenderAccountsManagerMessage>,
     (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>),
 ) {
-    let config = get_sender_account_config();
+    let config = Box::leak(Box::new(SenderAccountConfig {
+        idle_timeout,
+        ..sender_account_config()
+    }));
     let (_allocations_tx, allocations_rx) = watch::channel(HashMap::new());
     let escrow_subgraph = Box::leak(Box::new(
         SubgraphClient::new(
@@ -241,8 +291,10 @@ pub async fn create_sender_accounts_manager(
     let args = SenderAccountsManagerArgs {
         config,
         domain_separator: TAP_EIP712_DOMAIN_SEPARATOR.clone(),
-        pgpool,
+        pgpool: pgpool.clone(),
+        replica_pgpool: pgpool,
         indexer_allocations: allocations_rx,
+        other_indexers_allocations: watch::channel(HashSet::new()).1,
         escrow_accounts_v1: escrow_accounts_rx,
         escrow_accounts_v2: escrow_accounts_rx_v2,
         escrow_subgraph,
@@ -636,33 +688,15 @@ pub async fn store_rav(
         .await
 }
 
-// TODO use static and check for possible errors with connection refused
+/// Starts an aggregator signing with [SIGNER] and returns its URL
 pub async fn get_grpc_url() -> String {
-    let (_, addr) = create_grpc_aggregator().await;
-    format!("http://{}", addr)
-}
-
-/// Function to start a aggregator server for testing
-async fn create_grpc_aggregator() -> (JoinHandle<()>, SocketAddr) {
-    let wallet = SIGNER.0.clone();
-    let accepted_addresses = vec![SIGNER.1].into_iter().collect();
-    let domain_separator = TAP_EIP712_DOMAIN_SEPARATOR.clone();
-    let max_request_body_size = 1024 * 1024; // 1 MB
-    let max_response_body_size = 1024 * 1024; // 1 MB
-    let max_concurrent_connections = 255;
-    let port = 0;
-
-    run_server(
-        port,
-        wallet,
-        accepted_addresses,
-        domain_separator,
-        max_request_body_size,
-        max_response_body_size,
-        max_concurrent_connections,
-    )
-    .await
-    .unwrap()
+    MockAggregator::start()
+        .wallet(SIGNER.0.clone())
+        .accepted_addresses([SIGNER.1].into())
+        .domain_separator(TAP_EIP712_DOMAIN_SEPARATOR.clone())
+        .call()
+        .await
+        .url()
 }
 
 #[bon::builder]
@@ -13,18 +13,25 @@
 //! any key in X amount of seconds ago. This is important since, receipt timestamp_ns is provided
 //! by senders, we want to have a tolerance buffer where we still accept receipts with timestamp
 //! older than our current clock.
+//!
+//! Allocations are kept ordered by fee and the fees leave the buffer one receipt at a time, so
+//! that updating a fee and selecting the heaviest allocation are O(log n) in the number of
+//! allocations.
 
 pub use extra_data::{DefaultFromExtra, DurationInfo, NoExtraData};
 use generic_tracker::GenericTracker;
 pub use sender_fee_stats::SenderFeeStats;
 
+mod exporter;
 mod extra_data;
+mod fee_index;
 mod generic_tracker;
 mod global_tracker;
 mod sender_fee_stats;
 #[cfg(test)]
 mod tracker_tests;
 
+pub use exporter::{TrackerExporter, TrackerMetrics};
 pub use generic_tracker::GlobalFeeTracker;
 
 use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
@@ -59,4 +66,12 @@ pub trait AllocationStats<U> {
     /// for example, you don't want to trigger a Rav Request if your only allocation is currently
     /// requesting, so this should return a value that doesn't contain that allocation
     fn get_valid_fee(&mut self) -> u128;
+    /// Returns the fees in the buffer, not valid yet
+    fn get_buffered_fee(&self) -> u128 {
+        0
+    }
+    /// Removes the fees that left the buffer and returns their value
+    fn expire_buffer(&mut self) -> u128 {
+        0
+    }
 }
@@ -0,0 +1,128 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exports the trackers of a sender to Prometheus
+//!
+//! A label per allocation doesn't scale to the thousands of allocations of
+//! an indexer, every allocation ever tracked stays in the memory of
+//! Prometheus. The fees of a tracker are exported by sender instead, along
+//! with the count of the allocations with fees, and with the fees of the few
+//! allocations with the largest fees.
+
+use std::collections::HashSet;
+
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use thegraph_core::alloy::primitives::Address;
+
+use super::{global_tracker::GlobalTracker, AllocationStats, DefaultFromExtra, GenericTracker};
+
+/// Metrics of a tracker, registered in the global registry
+pub struct TrackerMetrics {
+    /// `<name>_grt_total`, fees of all the allocations
+    ///
+    /// Labels: "sender"
+    total: GaugeVec,
+    /// `<name>_allocations`, allocations with fees
+    ///
+    /// Labels: "sender"
+    allocations: IntGaugeVec,
+    /// `<name>_top_allocations_grt`, fees of the allocations with the
+    /// largest fees
+    ///
+    /// Labels: "sender", "allocation"
+    top_allocations: GaugeVec,
+}
+
+impl TrackerMetrics {
+    /// Registers the metrics of the trackers of `name`, `help` describing
+    /// their fees
+    pub fn register(name: &str, help: &str) -> Self {
+        Self {
+            total: register_gauge_vec!(format!("{name}_grt_total"), help, &["sender"]).unwrap(),
+            allocations: register_int_gauge_vec!(
+                format!("{name}_allocations"),
+                format!("Allocations with {}", help.to_lowercase()),
+                &["sender"]
+            )
+            .unwrap(),
+            top_allocations: register_gauge_vec!(
+                format!("{name}_top_allocations_grt"),
+                format!("{help} of the allocations with the largest fees"),
+                &["sender", "allocation"]
+            )
+            .unwrap(),
+        }
+    }
+}
+
+/// Exports a tracker of a sender to its [TrackerMetrics]
+///
+/// Only the `top_allocations` allocations with the largest fees are exported
+/// with their own label, the labels of the allocations leaving the top are
+/// removed.
+pub struct TrackerExporter {
+    metrics: &'static TrackerMetrics,
+    sender: String,
+    top_allocations: usize,
+    /// allocations currently exported with their own label
+    exported: HashSet<Address>,
+}
+
+impl TrackerExporter {
+    pub fn new(metrics: &'static TrackerMetrics, sender: Address, top_allocations: usize) -> Self {
+        Self {
+            metrics,
+            sender: sender.to_string(),
+            top_allocations,
+            exported: HashSet::new(),
+        }
+    }
+
+    /// Exports the current fees of `tracker`
+    pub fn export<G, F, E, U>(&mut self, tracker: &GenericTracker<G, F, E, U>)
+    where
+        G: GlobalTracker<U>,
+        U: Copy,
+        F: AllocationStats<U> + DefaultFromExtra<E>,
+    {
+        self.metrics
+            .total
+            .with_label_values(&[&self.sender])
+            .set(tracker.get_total_fee() as f64);
+        self.metrics
+            .allocations
+            .with_label_values(&[&self.sender])
+            .set(tracker.get_allocation_count() as i64);
+
+        let top = tracker.get_top_allocations(self.top_allocations);
+        let top_ids: HashSet<Address> = top.iter().map(|(id, _)| *id).collect();
+        for allocation_id in self.exported.difference(&top_ids) {
+            let _ = self
+                .metrics
+                .top_allocations
+                .remove_label_values(&[&self.sender, &allocation_id.to_string()]);
+        }
+        for (allocation_id, fee) in top {
+            self.metrics
+                .top_allocations
+                .with_label_values(&[&self.sender, &allocation_id.to_string()])
+                .set(fee as f64);
+        }
+        self.exported = top_ids;
+    }
+
+    /// Removes every metric of the sender, once it is stopped
+    pub fn clear(&mut self) {
+        let _ = self.metrics.total.remove_label_values(&[&self.sender]);
+        let _ = self
+            .metrics
+            .allocations
+            .remove_label_values(&[&self.sender]);
+        for allocation_id in self.exported.drain() {
+            let _ = self
+                .metrics
+                .top_allocations
+                .remove_label_values(&[&self.sender, &allocation_id.to_string()]);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{BTreeSet, HashMap};
+
+use thegraph_core::alloy::primitives::Address;
+
+/// Allocations ordered by fee
+///
+/// Updating the fee of an allocation is O(log n), and the allocations with the largest fees are
+/// read first without going over all of them. Allocations without fees are not indexed.
+#[derive(Debug, Clone, Default)]
+pub struct FeeIndex {
+    fees: HashMap<Address, u128>,
+    ordered: BTreeSet<(u128, Address)>,
+}
+
+impl FeeIndex {
+    /// Sets the fee of `id`, removing it from the index if it is zero
+    pub(super) fn set(&mut self, id: Address, fee: u128) {
+        if let Some(previous) = self.fees.remove(&id) {
+            self.ordered.remove(&(previous, id));
+        }
+        if fee > 0 {
+            self.fees.insert(id, fee);
+            self.ordered.insert((fee, id));
+        }
+    }
+
+    pub(super) fn remove(&mut self, id: Address) {
+        self.set(id, 0);
+    }
+
+    /// Fee of `id`, zero if it isn't indexed
+    pub(super) fn get(&self, id: &Address) -> u128 {
+        self.fees.get(id).copied().unwrap_or_default()
+    }
+
+    /// Number of allocations with fees
+    pub(super) fn count(&self) -> usize {
+        self.fees.len()
+    }
+
+    /// Allocations with fees, largest fee first
+    pub(super) fn largest_first(&self) -> impl Iterator<Item = (Address, u128)> + '_ {
+        self.ordered.iter().rev().map(|&(fee, id)| (id, fee))
+    }
+}
@@ -2,17 +2,19 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
     marker::PhantomData,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use thegraph_core::alloy::primitives::Address;
 
 use super::{
-    global_tracker::GlobalTracker, AllocationStats, DefaultFromExtra, DurationInfo, SenderFeeStats,
+    fee_index::FeeIndex, global_tracker::GlobalTracker, AllocationStats, DefaultFromExtra,
+    DurationInfo, SenderFeeStats,
 };
-use crate::agent::unaggregated_receipts::UnaggregatedReceipts;
+use crate::{agent::unaggregated_receipts::UnaggregatedReceipts, clock};
 
 /// Global Fee Tracker used inside SenderFeeTracker
 ///
@@ -40,6 +42,15 @@ where
     pub(super) id_to_fee: HashMap<Address, F>,
     pub(super) extra_data: E,
 
+    /// Allocations by total fee
+    by_total_fee: FeeIndex,
+    /// Allocations by valid fee, to select the heaviest one
+    by_valid_fee: FeeIndex,
+    /// When the fees added to the buffer of an allocation expire, soonest first
+    buffer_expiries: BinaryHeap<Reverse<(SystemTime, Address)>>,
+    /// Sum of the fees in the buffer of every allocation
+    buffered_fee: u128,
+
     _update: PhantomData<U>,
 }
 
@@ -60,40 +71,82 @@ where
             .entry(id)
             .or_insert(F::default_from_extra(&self.extra_data));
 
+        let previous_fee = fee.get_total_fee();
         fee.update(value);
+        let total_fee = self.global.get_total_fee() - previous_fee + fee.get_total_fee();
+        self.global.update(total_fee);
+        self.reindex(id);
+    }
 
-        self.recalculate_global();
+    pub fn remove(&mut self, id: Address) {
+        if let Some(fee) = self.id_to_fee.remove(&id) {
+            let total_fee = self.global.get_total_fee() - fee.get_total_fee();
+            self.global.update(total_fee);
+            self.buffered_fee -= fee.get_buffered_fee();
+            self.by_total_fee.remove(id);
+            self.by_valid_fee.remove(id);
+        }
     }
 
-    fn recalculate_global(&mut self) {
-        self.global
-            .update(self.id_to_fee.values().map(|fee| fee.get_total_fee()).sum());
+    /// Updates the position of `id` in the indexes, after its fees changed
+    fn reindex(&mut self, id: Address) {
+        if let Some(fee) = self.id_to_fee.get_mut(&id) {
+            self.by_total_fee.set(id, fee.get_total_fee());
+            self.by_valid_fee.set(id, fee.get_valid_fee());
+        }
     }
 
-    pub fn remove(&mut self, id: Address) {
-        if self.id_to_fee.remove(&id).is_some() {
-            self.recalculate_global();
+    /// Removes the fees that left the buffer of the allocations
+    ///
+    /// O(log n) for each receipt that left the buffer since the last call
+    fn expire_buffers(&mut self) {
+        let now = clock::now();
+        while let Some(&Reverse((expiry, id))) = self.buffer_expiries.peek() {
+            if expiry > now {
+                break;
+            }
+            self.buffer_expiries.pop();
+            // the allocation may have been removed since
+            let Some(fee) = self.id_to_fee.get_mut(&id) else {
+                continue;
+            };
+            let expired = fee.expire_buffer();
+            if expired > 0 {
+                self.buffered_fee -= expired;
+                self.reindex(id);
+            }
         }
     }
 
     pub fn get_heaviest_allocation_id(&mut self) -> Option<Address> {
-        // just loop over and get the biggest fee
+        self.expire_buffers();
+        // the heaviest allocations not allowed to trigger a RAV request are skipped, there are
+        // only as many as allocations requesting, blocked or in backoff
+        self.by_valid_fee
+            .largest_first()
+            .find(|(id, _)| self.is_allowed_to_trigger_rav_request(id))
+            .map(|(id, _)| id)
+    }
+
+    /// Same as [Self::get_heaviest_allocation_id], among `allocation_ids` only
+    pub fn get_heaviest_allocation_id_among(
+        &mut self,
+        allocation_ids: &HashSet<Address>,
+    ) -> Option<Address> {
+        self.expire_buffers();
+        allocation_ids
+            .iter()
+            .filter(|id| self.is_allowed_to_trigger_rav_request(id))
+            .map(|id| (self.by_valid_fee.get(id), *id))
+            .filter(|(fee, _)| *fee > 0)
+            .max()
+            .map(|(_, id)| id)
+    }
+
+    fn is_allowed_to_trigger_rav_request(&self, id: &Address) -> bool {
         self.id_to_fee
-            .iter_mut()
-            .filter(|(_, fee)| fee.is_allowed_to_trigger_rav_request())
-            .fold(None, |acc: Option<(&Address, u128)>, (addr, value)| {
-                if let Some((_, max_fee)) = acc {
-                    if value.get_valid_fee() > max_fee {
-                        Some((addr, value.get_valid_fee()))
-                    } else {
-                        acc
-                    }
-                } else {
-                    Some((addr, value.get_valid_fee()))
-                }
-            })
-            .filter(|(_, fee)| *fee > 0)
-            .map(|(&id, _)| id)
+            .get(id)
+            .is_some_and(|fee| fee.is_allowed_to_trigger_rav_request())
     }
 
     pub fn get_list_of_allocation_ids(&self) -> HashSet<Address> {
@@ -107,6 +160,16 @@ where
     pub fn get_total_fee_for_allocation(&self, allocation: &Address) -> Option<U> {
         self.id_to_fee.get(allocation).map(|fee| fee.get_stats())
     }
+
+    /// Number of allocations with fees
+    pub fn get_allocation_count(&self) -> usize {
+        self.by_total_fee.count()
+    }
+
+    /// The `n` allocations with the largest total fee, largest first
+    pub fn get_top_allocations(&self, n: usize) -> Vec<(Address, u128)> {
+        self.by_total_fee.largest_first().take(n).collect()
+    }
 }
 
 impl GenericTracker<GlobalFeeTracker, SenderFeeStats, DurationInfo, UnaggregatedReceipts> {
@@ -115,6 +178,10 @@ impl GenericTracker<GlobalFeeTracker, SenderFeeStats, DurationInfo, Unaggregated
             extra_data: DurationInfo { buffer_duration },
             global: Default::default(),
             id_to_fee: Default::default(),
+            by_total_fee: Default::default(),
+            by_valid_fee: Default::default(),
+            buffer_expiries: Default::default(),
+            buffered_fee: 0,
             _update: Default::default(),
         }
     }
@@ -139,8 +206,11 @@ impl GenericTracker<GlobalFeeTracker, SenderFeeStats, DurationInfo, Unaggregated
         entry.count += 1;
 
         if contains_buffer {
-            entry.buffer_info.new_entry(value, timestamp_ns);
+            let expiry = entry.buffer_info.new_entry(value, timestamp_ns);
+            self.buffer_expiries.push(Reverse((expiry, id)));
+            self.buffered_fee += value;
         }
+        self.reindex(id);
     }
 
     fn contains_buffer(&self) -> bool {
@@ -148,18 +218,12 @@ impl GenericTracker<GlobalFeeTracker, SenderFeeStats, DurationInfo, Unaggregated
     }
 
     pub fn get_ravable_total_fee(&mut self) -> u128 {
-        self.get_total_fee()
-            - self.global.requesting
-            - self.get_buffered_fee().min(self.global.total_fee)
-    }
-
-    fn get_buffered_fee(&mut self) -> u128 {
-        self.id_to_fee
-            .values_mut()
-            .fold(0u128, |acc, expiring| acc + expiring.buffer_info.get_sum())
+        self.expire_buffers();
+        self.get_total_fee() - self.global.requesting - self.buffered_fee.min(self.global.total_fee)
     }
 
     pub fn get_count_outside_buffer_for_allocation(&mut self, allocation_id: &Address) -> u64 {
+        self.expire_buffers();
         self.id_to_fee
             .get_mut(allocation_id)
             .map(|alloc| alloc.ravable_count())
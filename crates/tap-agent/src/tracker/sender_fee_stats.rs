@@ -7,7 +7,7 @@ use std::{
 };
 
 use super::{AllocationStats, DefaultFromExtra, DurationInfo};
-use crate::{agent::unaggregated_receipts::UnaggregatedReceipts, backoff::BackoffInfo};
+use crate::{agent::unaggregated_receipts::UnaggregatedReceipts, backoff::BackoffInfo, clock};
 
 /// Stats for a given allocation
 #[derive(Debug, Clone, Default)]
@@ -31,9 +31,10 @@ pub struct SenderFeeStats {
 }
 
 impl SenderFeeStats {
-    pub(super) fn ravable_count(&mut self) -> u64 {
+    /// Receipts outside of the buffer, once the expired fees were removed from it
+    pub(super) fn ravable_count(&self) -> u64 {
         let allocation_counter = self.count;
-        let counter_in_buffer = self.buffer_info.get_count();
+        let counter_in_buffer = self.buffer_info.entries.len() as u64;
         allocation_counter - counter_in_buffer
     }
 }
@@ -46,7 +47,8 @@ pub struct BufferInfo {
 }
 
 impl BufferInfo {
-    pub(super) fn new_entry(&mut self, value: u128, timestamp_ns: u64) {
+    /// Adds a fee to the buffer, returns when it leaves it
+    pub(super) fn new_entry(&mut self, value: u128, timestamp_ns: u64) -> SystemTime {
         let duration_since_epoch = Duration::from_nanos(timestamp_ns);
         // Create a SystemTime from the UNIX_EPOCH plus the duration
         let system_time = UNIX_EPOCH + duration_since_epoch;
@@ -55,13 +57,16 @@ impl BufferInfo {
             .expect("Should be within bounds");
         self.entries.push_back((system_time, value));
         self.fee_in_buffer += value;
+        system_time
     }
 
+    #[cfg(test)]
     pub(super) fn get_sum(&mut self) -> u128 {
         self.cleanup();
         self.fee_in_buffer
     }
 
+    #[cfg(test)]
     pub(super) fn get_count(&mut self) -> u64 {
         self.cleanup();
         self.entries.len() as u64
@@ -69,7 +74,7 @@ impl BufferInfo {
 
     // O(Receipts expired)
     fn cleanup(&mut self) -> (u128, u64) {
-        let now = SystemTime::now();
+        let now = clock::now();
 
         let mut total_value_expired = 0;
         let mut total_count_expired = 0;
@@ -120,7 +125,15 @@ impl AllocationStats<UnaggregatedReceipts> for SenderFeeStats {
     }
 
     fn get_valid_fee(&mut self) -> u128 {
-        self.total_fee - self.buffer_info.get_sum().min(self.total_fee)
+        self.total_fee - self.buffer_info.fee_in_buffer.min(self.total_fee)
+    }
+
+    fn get_buffered_fee(&self) -> u128 {
+        self.buffer_info.fee_in_buffer
+    }
+
+    fn expire_buffer(&mut self) -> u128 {
+        self.buffer_info.cleanup().0
     }
 
     fn get_total_fee(&self) -> u128 {
@@ -2,13 +2,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
+    collections::HashSet,
     thread::sleep,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use thegraph_core::alloy::primitives::address;
 
-use super::SimpleFeeTracker;
+use super::{SimpleFeeTracker, TrackerExporter, TrackerMetrics};
 use crate::{agent::unaggregated_receipts::UnaggregatedReceipts, tracker::SenderFeeTracker};
 
 #[test]
@@ -243,6 +244,28 @@ fn test_filtered_backed_off_allocations() {
     assert_eq!(tracker.get_total_fee(), 30);
 }
 
+#[test]
+fn test_heaviest_allocation_among() {
+    let allocation_id_0 = address!("abababababababababababababababababababab");
+    let allocation_id_1 = address!("bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc");
+    let closing = HashSet::from([allocation_id_0]);
+
+    let mut tracker = SenderFeeTracker::new(Duration::ZERO);
+    assert_eq!(tracker.get_heaviest_allocation_id_among(&closing), None);
+
+    tracker.update(allocation_id_0, 10.into());
+    tracker.update(allocation_id_1, 20.into());
+    assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_1));
+    assert_eq!(
+        tracker.get_heaviest_allocation_id_among(&closing),
+        Some(allocation_id_0)
+    );
+
+    // not while it's blocked for its last rav
+    tracker.block_allocation_id(allocation_id_0);
+    assert_eq!(tracker.get_heaviest_allocation_id_among(&closing), None);
+}
+
 #[test]
 fn test_ongoing_rav_requests() {
     let allocation_id_0 = address!("abababababababababababababababababababab");
@@ -328,6 +351,32 @@ fn check_counter_and_fee_outside_buffer_unordered() {
     assert_eq!(tracker.get_ravable_total_fee(), 30);
 }
 
+#[test]
+fn test_remove_allocation_in_buffer() {
+    let allocation_id_0 = address!("abababababababababababababababababababab");
+    let allocation_id_1 = address!("bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc");
+
+    const BUFFER_WINDOW: Duration = Duration::from_millis(20);
+    let mut tracker = SenderFeeTracker::new(BUFFER_WINDOW);
+
+    tracker.add(allocation_id_0, 10, get_current_timestamp_u64_ns());
+    tracker.add(allocation_id_1, 20, get_current_timestamp_u64_ns());
+    sleep(BUFFER_WINDOW);
+    tracker.add(allocation_id_0, 30, get_current_timestamp_u64_ns());
+    assert_eq!(tracker.get_ravable_total_fee(), 30);
+    assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_1));
+
+    // its fees in the buffer are removed with it
+    tracker.remove(allocation_id_0);
+    assert_eq!(tracker.get_total_fee(), 20);
+    assert_eq!(tracker.get_ravable_total_fee(), 20);
+    assert_eq!(tracker.get_allocation_count(), 1);
+
+    sleep(BUFFER_WINDOW);
+    assert_eq!(tracker.get_ravable_total_fee(), 20);
+    assert_eq!(tracker.get_heaviest_allocation_id(), Some(allocation_id_1));
+}
+
 #[test]
 fn check_get_count_updates_sum() {
     let allocation_id_0 = address!("abababababababababababababababababababab");
@@ -362,3 +411,85 @@ fn check_get_count_updates_sum() {
     assert_eq!(expiring_sum.buffer_info.get_count(), 0);
     assert_eq!(expiring_sum.buffer_info.get_sum(), 0);
 }
+
+#[test]
+fn test_top_allocations() {
+    let allocation_id_0 = address!("abababababababababababababababababababab");
+    let allocation_id_1 = address!("bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc");
+    let allocation_id_2 = address!("cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd");
+
+    let mut tracker = SimpleFeeTracker::default();
+    tracker.update(allocation_id_0, 10);
+    tracker.update(allocation_id_1, 30);
+    tracker.update(allocation_id_2, 20);
+    assert_eq!(tracker.get_allocation_count(), 3);
+    assert_eq!(
+        tracker.get_top_allocations(2),
+        vec![(allocation_id_1, 30), (allocation_id_2, 20)]
+    );
+    assert_eq!(tracker.get_top_allocations(5).len(), 3);
+    assert!(tracker.get_top_allocations(0).is_empty());
+
+    // allocations without fees are not counted
+    tracker.update(allocation_id_1, 0);
+    assert_eq!(tracker.get_allocation_count(), 2);
+    assert_eq!(
+        tracker.get_top_allocations(5),
+        vec![(allocation_id_2, 20), (allocation_id_0, 10)]
+    );
+}
+
+#[test]
+fn test_tracker_exporter() {
+    let sender = address!("1111111111111111111111111111111111111111");
+    let allocation_id_0 = address!("abababababababababababababababababababab");
+    let allocation_id_1 = address!("bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc");
+
+    let metrics = Box::leak(Box::new(TrackerMetrics::register(
+        "tap_exporter_test",
+        "Test fees",
+    )));
+    let mut exporter = TrackerExporter::new(metrics, sender, 1);
+    let exported_allocations = || {
+        prometheus::gather()
+            .into_iter()
+            .find(|family| family.get_name() == "tap_exporter_test_top_allocations_grt")
+            .map(|family| {
+                family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| {
+                        let allocation = metric
+                            .get_label()
+                            .iter()
+                            .find(|label| label.get_name() == "allocation")
+                            .unwrap();
+                        (
+                            allocation.get_value().to_string(),
+                            metric.get_gauge().get_value(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    };
+
+    let mut tracker = SimpleFeeTracker::default();
+    tracker.update(allocation_id_0, 10);
+    exporter.export(&tracker);
+    assert_eq!(
+        exported_allocations(),
+        vec![(allocation_id_0.to_string(), 10.0)]
+    );
+
+    // the allocation leaving the top is removed
+    tracker.update(allocation_id_1, 20);
+    exporter.export(&tracker);
+    assert_eq!(
+        exported_allocations(),
+        vec![(allocation_id_1.to_string(), 20.0)]
+    );
+
+    exporter.clear();
+    assert!(exported_allocations().is_empty());
+}
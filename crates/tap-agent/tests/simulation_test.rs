@@ -0,0 +1,102 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::time::Duration;
+
+use indexer_tap_agent::{
+    simulation::{AggregatorResponse, RavRequest, Simulation},
+    test::ALLOCATION_ID_0,
+};
+use sqlx::PgPool;
+
+fn rav_requests(requests: &[(u64, AggregatorResponse)]) -> Vec<RavRequest> {
+    requests
+        .iter()
+        .map(|&(at_ms, response)| RavRequest {
+            allocation_id: ALLOCATION_ID_0,
+            at: Duration::from_millis(at_ms),
+            response,
+        })
+        .collect()
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn test_receipts_leave_the_buffer(pgpool: PgPool) {
+    let mut simulation = Simulation::start().pgpool(pgpool).call().await;
+
+    // over the trigger value, but in the buffer
+    simulation.receipt(ALLOCATION_ID_0, 600).await;
+    simulation.advance(Duration::from_millis(90)).await;
+    simulation.receipt(ALLOCATION_ID_0, 1).await;
+    assert!(simulation.rav_requests().is_empty());
+
+    simulation.advance(Duration::from_millis(10)).await;
+    simulation.receipt(ALLOCATION_ID_0, 1).await;
+    assert_eq!(
+        simulation.rav_requests(),
+        rav_requests(&[(100, AggregatorResponse::Rav)])
+    );
+    // the last two receipts are still in the buffer
+    assert_eq!(simulation.rav(ALLOCATION_ID_0), 600);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn test_denied_until_rav(pgpool: PgPool) {
+    let mut simulation = Simulation::start().pgpool(pgpool).call().await;
+
+    simulation.receipt(ALLOCATION_ID_0, 600).await;
+    assert!(!simulation.denied().await);
+    // over the max amount willing to lose
+    simulation.receipt(ALLOCATION_ID_0, 500).await;
+    assert!(simulation.denied().await);
+
+    // denied senders don't send receipts, the RAV is requested on retry
+    simulation.advance(Duration::from_millis(990)).await;
+    assert!(simulation.denied().await);
+    assert!(simulation.rav_requests().is_empty());
+
+    simulation.advance(Duration::from_millis(10)).await;
+    assert_eq!(
+        simulation.rav_requests(),
+        rav_requests(&[(1000, AggregatorResponse::Rav)])
+    );
+    assert_eq!(simulation.rav(ALLOCATION_ID_0), 1100);
+    assert!(!simulation.denied().await);
+}
+
+#[sqlx::test(migrations = "../../migrations")]
+async fn test_aggregator_failures_backoff(pgpool: PgPool) {
+    let mut simulation = Simulation::start()
+        .pgpool(pgpool)
+        .responses(vec![AggregatorResponse::Unavailable; 5])
+        .call()
+        .await;
+
+    simulation.receipt(ALLOCATION_ID_0, 1100).await;
+    assert!(simulation.denied().await);
+
+    simulation.advance(Duration::from_secs(6)).await;
+    assert!(simulation.denied().await);
+    // a RAV is requested on every retry, the allocation backing off for
+    // 100ms, 200ms, 400ms and 800ms after the first four failures
+    assert_eq!(
+        simulation.rav_requests(),
+        rav_requests(&[
+            (1000, AggregatorResponse::Unavailable),
+            (2000, AggregatorResponse::Unavailable),
+            (3000, AggregatorResponse::Unavailable),
+            (4000, AggregatorResponse::Unavailable),
+            (5000, AggregatorResponse::Unavailable),
+        ])
+    );
+
+    // it backs off for 1.6s after the fifth one, the retry at 6s was skipped
+    simulation.advance(Duration::from_secs(1)).await;
+    assert_eq!(simulation.rav_requests().len(), 6);
+    assert_eq!(
+        simulation.rav_requests()[5],
+        rav_requests(&[(7000, AggregatorResponse::Rav)])[0]
+    );
+    assert_eq!(simulation.elapsed(), Duration::from_secs(7));
+    assert!(!simulation.denied().await);
+}
@@ -7,6 +7,7 @@ use std::{
     time::Duration,
 };
 
+use indexer_config::TapReceiptChecksConfig;
 use indexer_monitor::{DeploymentDetails, EscrowAccounts, SubgraphClient};
 use indexer_tap_agent::{
     agent::{
@@ -16,11 +17,12 @@ use indexer_tap_agent::{
         },
         sender_allocation::SenderAllocationMessage,
     },
+    rav_budget::RavRequestBudget,
     test::{actors::TestableActor, create_received_receipt, get_grpc_url, store_batch_receipts},
 };
+use indexer_test_harness::subgraphs;
 use ractor::{call, concurrency::JoinHandle, Actor, ActorRef};
 use reqwest::Url;
-use serde_json::json;
 use sqlx::PgPool;
 use test_assets::{
     assert_while_retry, flush_messages, ALLOCATION_ID_0, ALLOCATION_ID_1, ALLOCATION_ID_2,
@@ -29,7 +31,7 @@ use test_assets::{
 };
 use thegraph_core::alloy::primitives::Address;
 use tokio::sync::{mpsc, watch};
-use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+use wiremock::MockServer;
 
 pub async fn start_agent(
     pgpool: PgPool,
@@ -37,15 +39,7 @@ pub async fn start_agent(
     mpsc::Receiver<SenderAccountsManagerMessage>,
     (ActorRef<SenderAccountsManagerMessage>, JoinHandle<()>),
 ) {
-    let escrow_subgraph_mock_server: MockServer = MockServer::start().await;
-    escrow_subgraph_mock_server
-        .register(Mock::given(method("POST")).respond_with(
-            ResponseTemplate::new(200).set_body_json(json!({ "data": {
-                    "transactions": [],
-                }
-            })),
-        ))
-        .await;
+    let escrow_subgraph_mock_server = subgraphs::escrow_subgraph().await;
 
     let network_subgraph_mock_server = MockServer::start().await;
 
@@ -86,19 +80,39 @@ pub async fn start_agent(
         rav_request_buffer: Duration::from_millis(500),
         max_amount_willing_to_lose_grt: 50,
         trigger_value: 150,
+        adaptive_trigger: None,
+        rav_request_budget: RavRequestBudget::new(100),
         rav_request_timeout: Duration::from_secs(60),
         rav_request_receipt_limit: 10,
+        strict_fee_calculation: false,
         indexer_address: INDEXER_ADDRESS,
         escrow_polling_interval: Duration::from_secs(10),
         tap_sender_timeout: Duration::from_secs(30),
         trusted_senders: HashSet::new(),
+        sender_max_amounts_willing_to_lose: HashMap::new(),
+        sender_trigger_values: HashMap::new(),
+        sender_rav_request_buffers: HashMap::new(),
+        idle_timeout: None,
+        fee_snapshot_interval: None,
+        fee_snapshot_max_age: Duration::ZERO,
+        contract_signers: None,
+        allocation_close_grace_period: Duration::ZERO,
+        sharding: None,
+        top_allocations: 10,
+        tracker_export_interval: Duration::from_secs(15),
+        receipt_checks: TapReceiptChecksConfig {
+            allocation_id: true,
+            signature: true,
+        },
     }));
 
     let args = SenderAccountsManagerArgs {
         config,
         domain_separator: TAP_EIP712_DOMAIN.clone(),
-        pgpool,
+        pgpool: pgpool.clone(),
+        replica_pgpool: pgpool,
         indexer_allocations: indexer_allocations1,
+        other_indexers_allocations: watch::channel(HashSet::new()).1,
         escrow_accounts_v1: escrow_accounts.clone(),
         escrow_accounts_v2: watch::channel(EscrowAccounts::default()).1,
         escrow_subgraph,
@@ -0,0 +1,93 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! OpenTelemetry tracing shared by indexer-service and tap-agent
+//!
+//! Spans created with [tracing] are exported to an OTLP collector and
+//! the W3C `traceparent` header is used to continue traces started by
+//! the gateway and to propagate them to graph-node.
+//!
+//! The log levels of the modules can be overridden at runtime through
+//! [log_levels_router], and the repeated warnings and errors are throttled
+//! by [throttle_layer].
+
+mod log_levels;
+mod throttle;
+
+use http::HeaderMap;
+use indexer_config::OpenTelemetryConfig;
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, Tracer, TracerProvider},
+    Resource,
+};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+pub use log_levels::{log_levels, log_levels_router, reloadable_filter, set_log_level, LogLevels};
+pub use throttle::{throttle_layer, ThrottleLayer};
+
+/// Installs the global tracer provider and the W3C trace context propagator
+///
+/// Must be called from within a Tokio runtime, spans are exported in batches
+/// by a background task.
+pub fn init_tracer(
+    config: &OpenTelemetryConfig,
+    default_service_name: &'static str,
+) -> anyhow::Result<Tracer> {
+    let service_name = config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| default_service_name.to_string());
+
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(config.otlp_endpoint.as_str())
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+        .build();
+    let tracer = provider.tracer(default_service_name);
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider);
+
+    Ok(tracer)
+}
+
+/// Layer that forwards the spans of a subscriber to the given tracer
+pub fn layer<S>(tracer: Tracer) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Flushes the pending spans, called before the process exits
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Continues the trace found in the `traceparent` header, if any
+pub fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+/// Adds the `traceparent` header of the span to an outgoing request
+pub fn inject_context(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
@@ -0,0 +1,247 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log levels of the modules, overridden at runtime
+//!
+//! The filter set by `RUST_LOG`, `info` by default, is reloaded with the
+//! overrides so that the logs of a module can be raised while investigating
+//! an issue, without restarting and losing the faulty state. The overrides
+//! are lost on restart.
+
+use std::{collections::BTreeMap, str::FromStr, sync::Mutex};
+
+use anyhow::anyhow;
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{level_filters::LevelFilter, Subscriber};
+use tracing_subscriber::{filter::Directive, reload, EnvFilter};
+
+type ReloadFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+struct LogFilter {
+    /// directives of `RUST_LOG`
+    directives: String,
+    overrides: BTreeMap<String, LevelFilter>,
+    reload: ReloadFilter,
+}
+
+/// Set once the filter is installed by [reloadable_filter]
+static LOG_FILTER: Mutex<Option<LogFilter>> = Mutex::new(None);
+
+/// Log levels returned by the `/log-levels` routes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogLevels {
+    /// Directives of `RUST_LOG`, `info` if empty
+    pub directives: String,
+    /// Levels of the modules overridden at runtime
+    pub overrides: BTreeMap<String, String>,
+}
+
+impl LogFilter {
+    fn levels(&self) -> LogLevels {
+        LogLevels {
+            directives: self.directives.clone(),
+            overrides: self
+                .overrides
+                .iter()
+                .map(|(target, level)| (target.clone(), level.to_string().to_lowercase()))
+                .collect(),
+        }
+    }
+}
+
+fn env_filter(directives: &str) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse_lossy(directives)
+}
+
+/// Filter of the logs set by `RUST_LOG`, `info` by default, whose levels
+/// can be overridden per module with [set_log_level]
+pub fn reloadable_filter<S>() -> reload::Layer<EnvFilter, S>
+where
+    S: Subscriber + 'static,
+{
+    let directives = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+    let (layer, handle) = reload::Layer::new(env_filter(&directives));
+    *LOG_FILTER.lock().unwrap() = Some(LogFilter {
+        directives,
+        overrides: BTreeMap::new(),
+        reload: Box::new(move |filter| handle.reload(filter)),
+    });
+    layer
+}
+
+/// Current log levels, fails if the filter is not a [reloadable_filter]
+pub fn log_levels() -> anyhow::Result<LogLevels> {
+    LOG_FILTER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(LogFilter::levels)
+        .ok_or_else(|| anyhow!("The log levels can't be changed at runtime"))
+}
+
+/// Overrides the level of `target` and its submodules, or removes its
+/// override if `level` is `None`
+pub fn set_log_level(target: &str, level: Option<LevelFilter>) -> anyhow::Result<LogLevels> {
+    let mut log_filter = LOG_FILTER.lock().unwrap();
+    let log_filter = log_filter
+        .as_mut()
+        .ok_or_else(|| anyhow!("The log levels can't be changed at runtime"))?;
+    match level {
+        Some(level) => {
+            // checked before it's kept
+            Directive::from_str(&format!("{target}={level}"))?;
+            log_filter.overrides.insert(target.to_string(), level);
+        }
+        None => {
+            log_filter.overrides.remove(target);
+        }
+    }
+
+    let filter = log_filter.overrides.iter().try_fold(
+        env_filter(&log_filter.directives),
+        |filter, (target, level)| {
+            Ok::<_, anyhow::Error>(filter.add_directive(format!("{target}={level}").parse()?))
+        },
+    )?;
+    (log_filter.reload)(filter)?;
+    Ok(log_filter.levels())
+}
+
+#[derive(Debug, Deserialize)]
+struct SetLogLevel {
+    level: String,
+}
+
+fn error_response(status: StatusCode, error: anyhow::Error) -> Response {
+    (status, Json(json!({ "error": error.to_string() }))).into_response()
+}
+
+/// Routes to read the log levels, and to override (`PUT`, `{"level": "debug"}`)
+/// or reset (`DELETE`) the level of a module, e.g. `/indexer_service_rs::routes`
+///
+/// Not authenticated, they must be served behind an admin token.
+pub fn log_levels_router() -> Router {
+    Router::new()
+        .route("/", get(get_log_levels))
+        .route("/:target", put(put_log_level).delete(delete_log_level))
+}
+
+async fn get_log_levels() -> Response {
+    match log_levels() {
+        Ok(levels) => Json(levels).into_response(),
+        Err(error) => error_response(StatusCode::NOT_FOUND, error),
+    }
+}
+
+async fn put_log_level(
+    Path(target): Path<String>,
+    Json(SetLogLevel { level }): Json<SetLogLevel>,
+) -> Response {
+    let level = match LevelFilter::from_str(&level) {
+        Ok(level) => level,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, error.into()),
+    };
+    match set_log_level(&target, Some(level)) {
+        Ok(levels) => {
+            tracing::warn!(%target, %level, "Overriding the log level of a module");
+            Json(levels).into_response()
+        }
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    }
+}
+
+async fn delete_log_level(Path(target): Path<String>) -> Response {
+    match set_log_level(&target, None) {
+        Ok(levels) => {
+            tracing::info!(%target, "Removed the log level override of a module");
+            Json(levels).into_response()
+        }
+        Err(error) => error_response(StatusCode::BAD_REQUEST, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::{log_levels_router, reloadable_filter};
+
+    async fn send(
+        router: &Router,
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let response = router
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_log_levels_router() {
+        let router = log_levels_router();
+
+        // the levels can't be changed without the reloadable filter
+        let (status, _) = send(&router, Method::GET, "/", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // kept alive for the filter to be reloaded
+        let _subscriber = Registry::default().with(reloadable_filter());
+
+        let (status, levels) = send(&router, Method::GET, "/", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(levels["overrides"], json!({}));
+
+        let (status, levels) = send(
+            &router,
+            Method::PUT,
+            "/indexer_telemetry",
+            Some(json!({ "level": "debug" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(levels["overrides"], json!({ "indexer_telemetry": "debug" }));
+
+        let (status, _) = send(
+            &router,
+            Method::PUT,
+            "/indexer_telemetry",
+            Some(json!({ "level": "verbose" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, levels) = send(&router, Method::DELETE, "/indexer_telemetry", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(levels["overrides"], json!({}));
+    }
+}
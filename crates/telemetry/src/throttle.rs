@@ -0,0 +1,219 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throttling of the repeated warnings and errors
+//!
+//! A failing dependency can log the same error for every request, burying
+//! the other logs. Only `max_per_window` warnings and errors of each log
+//! statement are logged per window, the number of the suppressed ones is
+//! logged at the end of the window, or along with the first one of the next
+//! window if it comes first.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Once},
+    thread,
+    time::{Duration, Instant},
+};
+
+use indexer_config::LogThrottleConfig;
+use tracing::{
+    callsite::Identifier,
+    dispatcher::{self, WeakDispatch},
+    Dispatch, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Longest time between the end of a window and the report of its
+/// suppressed messages
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Window {
+    metadata: &'static Metadata<'static>,
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+type Callsites = Mutex<HashMap<Identifier, Window>>;
+
+/// Layer dropping the warnings and errors of a log statement over
+/// `max_per_window` per window, see [throttle_layer]
+pub struct ThrottleLayer {
+    max_per_window: u32,
+    window: Duration,
+    callsites: Arc<Callsites>,
+    reporter: Once,
+}
+
+/// Throttles the repeated warnings and errors if configured
+pub fn throttle_layer(config: Option<&LogThrottleConfig>) -> Option<ThrottleLayer> {
+    config.map(|config| ThrottleLayer {
+        max_per_window: config.max_per_window,
+        window: config.window_secs,
+        callsites: Arc::new(Mutex::new(HashMap::new())),
+        reporter: Once::new(),
+    })
+}
+
+/// Logs the suppressed messages of the ended windows through `dispatch`,
+/// until the subscriber is dropped
+fn report_suppressed(dispatch: WeakDispatch, callsites: Arc<Callsites>, window: Duration) {
+    loop {
+        thread::sleep(window.min(REPORT_INTERVAL));
+        let Some(dispatch) = dispatch.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        let mut ended = Vec::new();
+        callsites.lock().unwrap().retain(|_, callsite| {
+            if now.duration_since(callsite.started) < window {
+                return true;
+            }
+            if callsite.suppressed > 0 {
+                ended.push((callsite.metadata, callsite.suppressed));
+            }
+            false
+        });
+        // logged once the lock is released, it goes through the layer too
+        dispatcher::with_default(&dispatch, || {
+            for (metadata, suppressed) in ended {
+                log_suppressed(metadata, suppressed);
+            }
+        });
+    }
+}
+
+fn log_suppressed(metadata: &Metadata<'_>, suppressed: u64) {
+    tracing::warn!(
+        suppressed,
+        target = metadata.target(),
+        file = metadata.file().unwrap_or_default(),
+        line = metadata.line().unwrap_or_default(),
+        "Suppressed repeated log messages"
+    );
+}
+
+impl<S: Subscriber> Layer<S> for ThrottleLayer {
+    fn on_register_dispatch(&self, dispatch: &Dispatch) {
+        self.reporter.call_once(|| {
+            let dispatch = dispatch.downgrade();
+            let callsites = self.callsites.clone();
+            let window = self.window;
+            thread::Builder::new()
+                .name("log-throttle".to_string())
+                .spawn(move || report_suppressed(dispatch, callsites, window))
+                .expect("Failed to start the log throttle reporter");
+        });
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, _: Context<'_, S>) -> bool {
+        let metadata = event.metadata();
+        // the more verbose levels are greater
+        if *metadata.level() > Level::WARN {
+            return true;
+        }
+
+        let suppressed = {
+            let now = Instant::now();
+            let mut callsites = self.callsites.lock().unwrap();
+            let window = callsites
+                .entry(metadata.callsite())
+                .or_insert_with(|| Window {
+                    metadata,
+                    started: now,
+                    logged: 0,
+                    suppressed: 0,
+                });
+            if now.duration_since(window.started) >= self.window {
+                let suppressed = window.suppressed;
+                window.started = now;
+                window.logged = 1;
+                window.suppressed = 0;
+                suppressed
+            } else if window.logged < self.max_per_window {
+                window.logged += 1;
+                0
+            } else {
+                window.suppressed += 1;
+                return false;
+            }
+        };
+        // logged once the lock is released, it goes through this layer too
+        if suppressed > 0 {
+            log_suppressed(metadata, suppressed);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use indexer_config::LogThrottleConfig;
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, Layer, Registry};
+
+    use super::throttle_layer;
+
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for CountingLayer {
+        fn on_event(&self, _: &Event<'_>, _: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_repeated_errors_are_throttled() {
+        let logged = Arc::new(AtomicUsize::new(0));
+        let subscriber = Registry::default()
+            .with(CountingLayer(logged.clone()))
+            .with(throttle_layer(Some(&LogThrottleConfig {
+                max_per_window: 2,
+                window_secs: Duration::from_secs(3600),
+            })));
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..5 {
+                tracing::error!("Graph-node is unreachable");
+            }
+            assert_eq!(logged.load(Ordering::SeqCst), 2);
+
+            // only the warnings and errors are throttled
+            for _ in 0..5 {
+                tracing::info!("Query served");
+            }
+            assert_eq!(logged.load(Ordering::SeqCst), 7);
+        });
+    }
+
+    #[test]
+    fn test_suppressed_reported_at_window_end() {
+        let logged = Arc::new(AtomicUsize::new(0));
+        let subscriber = Registry::default()
+            .with(CountingLayer(logged.clone()))
+            .with(throttle_layer(Some(&LogThrottleConfig {
+                max_per_window: 2,
+                window_secs: Duration::from_millis(100),
+            })));
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..5 {
+                tracing::error!("Graph-node is unreachable");
+            }
+            assert_eq!(logged.load(Ordering::SeqCst), 2);
+
+            // reported without waiting for the statement to be logged again
+            std::thread::sleep(Duration::from_millis(500));
+            assert_eq!(logged.load(Ordering::SeqCst), 3);
+        });
+    }
+}
@@ -22,6 +22,9 @@ use thegraph_core::{
 };
 use tokio::sync::mpsc;
 
+#[cfg(feature = "mock-rpc")]
+pub mod mock_rpc;
+
 /// Assert something is true while sleeping and retrying
 ///
 /// This macro creates a loop that keeps retrying the expression
@@ -71,33 +74,42 @@ pub const ESCROW_QUERY_RESPONSE: &str = r#"
         "data": {
             "escrowAccounts": [
                 {
+                    "id": "0x9858effd232b4033e47d90003d41ec34ecaeda94-0xd75c4dbcb215a6cf9097cfbcc70aab2596b96a9c",
                     "balance": "34",
                     "totalAmountThawing": "10",
                     "sender": {
                         "id": "0x9858EfFD232B4033E47d90003D41EC34EcaEda94",
                         "signers": [
                             {
-                                "id": "0x533661F0fb14d2E8B26223C86a610Dd7D2260892"
+                                "id": "0x533661F0fb14d2E8B26223C86a610Dd7D2260892",
+                                "isAuthorized": true,
+                                "thawEndTimestamp": "0"
                             },
                             {
-                                "id": "0x2740f6fA9188cF53ffB6729DDD21575721dE92ce"
+                                "id": "0x2740f6fA9188cF53ffB6729DDD21575721dE92ce",
+                                "isAuthorized": true,
+                                "thawEndTimestamp": "0"
                             }
                         ]
                     }
                 },
                 {
+                    "id": "0x22d491bde2303f2f43325b2108d26f1eaba1e32b-0xd75c4dbcb215a6cf9097cfbcc70aab2596b96a9c",
                     "balance": "42",
                     "totalAmountThawing": "0",
                     "sender": {
                         "id": "0x22d491bde2303f2f43325b2108d26f1eaba1e32b",
                         "signers": [
                             {
-                                "id": "0x245059163ff6ee14279aa7b35ea8f0fdb967df6e"
+                                "id": "0x245059163ff6ee14279aa7b35ea8f0fdb967df6e",
+                                "isAuthorized": true,
+                                "thawEndTimestamp": "0"
                             }
                         ]
                     }
                 },
                 {
+                    "id": "0x192c3b6e0184fa0cc5b9d2bddeb6b79fb216a002-0xd75c4dbcb215a6cf9097cfbcc70aab2596b96a9c",
                     "balance": "2987",
                     "totalAmountThawing": "12",
                     "sender": {
@@ -160,6 +172,7 @@ lazy_static! {
                 poi: None,
                 query_fee_rebates: None,
                 query_fees_collected: None,
+                provision: None,
             },
         ),
         (
@@ -184,6 +197,7 @@ lazy_static! {
                 poi: None,
                 query_fee_rebates: None,
                 query_fees_collected: None,
+                provision: None,
             },
         ),
         (
@@ -208,6 +222,7 @@ lazy_static! {
                 poi: None,
                 query_fee_rebates: None,
                 query_fees_collected: None,
+                provision: None,
             },
         ),
         (
@@ -232,6 +247,7 @@ lazy_static! {
                 poi: None,
                 query_fee_rebates: None,
                 query_fees_collected: None,
+                provision: None,
             },
         ),
     ]);
@@ -0,0 +1,242 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mock Ethereum JSON-RPC server
+//!
+//! Answers the calls made by the features that read the chain, with
+//! values set by the test. Calls to unknown methods are answered with a
+//! `-32601` JSON-RPC error, so a test fails loudly when a feature starts
+//! depending on a method that is not mocked yet.
+//!
+//! ```ignore
+//! let rpc = MockRpcServer::start().await;
+//! rpc.set_balance(ESCROW_ADDRESS, U256::from(1000));
+//! rpc.set_paused(VERIFIER_ADDRESS, true);
+//! let url = rpc.url();
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+use thegraph_core::alloy::{
+    hex,
+    primitives::{fixed_bytes, Address, Bytes, ChainId, FixedBytes, U256},
+};
+use wiremock::{matchers::method, Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// Selector of `paused()`, shared by the pausable contracts
+const PAUSED_SELECTOR: FixedBytes<4> = fixed_bytes!("5c975abb");
+
+#[derive(Debug)]
+struct ChainState {
+    chain_id: ChainId,
+    block_number: u64,
+    gas_price: u128,
+    balances: HashMap<Address, U256>,
+    codes: HashMap<Address, Bytes>,
+    /// Results of `eth_call`, by contract and function selector
+    calls: HashMap<(Address, FixedBytes<4>), Bytes>,
+}
+
+/// Mock of an Ethereum node, see the [module documentation](self)
+pub struct MockRpcServer {
+    server: MockServer,
+    state: Arc<Mutex<ChainState>>,
+}
+
+impl MockRpcServer {
+    /// Starts a server for chain `1337` at block `1`, with a gas price of 1 gwei
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let state = Arc::new(Mutex::new(ChainState {
+            chain_id: 1337,
+            block_number: 1,
+            gas_price: 1_000_000_000,
+            balances: HashMap::new(),
+            codes: HashMap::new(),
+            calls: HashMap::new(),
+        }));
+        Mock::given(method("POST"))
+            .respond_with(JsonRpcResponder(state.clone()))
+            .mount(&server)
+            .await;
+        Self { server, state }
+    }
+
+    pub fn url(&self) -> String {
+        self.server.uri()
+    }
+
+    pub fn set_chain_id(&self, chain_id: ChainId) {
+        self.state.lock().unwrap().chain_id = chain_id;
+    }
+
+    pub fn set_block_number(&self, block_number: u64) {
+        self.state.lock().unwrap().block_number = block_number;
+    }
+
+    pub fn set_gas_price(&self, gas_price: u128) {
+        self.state.lock().unwrap().gas_price = gas_price;
+    }
+
+    pub fn set_balance(&self, address: Address, balance: U256) {
+        self.state.lock().unwrap().balances.insert(address, balance);
+    }
+
+    /// Sets the code of `address`, making it a contract
+    pub fn set_code(&self, address: Address, code: Bytes) {
+        self.state.lock().unwrap().codes.insert(address, code);
+    }
+
+    /// Sets the ABI encoded result of calling `selector` on `contract`
+    pub fn set_call_result(&self, contract: Address, selector: FixedBytes<4>, result: Bytes) {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .insert((contract, selector), result);
+    }
+
+    /// Sets the result of `paused()` on `contract`
+    pub fn set_paused(&self, contract: Address, paused: bool) {
+        let result = U256::from(paused as u8).to_be_bytes::<32>();
+        self.set_call_result(contract, PAUSED_SELECTOR, Bytes::from(result));
+    }
+
+    /// Number of JSON-RPC requests received so far, batches count as one
+    pub async fn received_requests(&self) -> usize {
+        self.server
+            .received_requests()
+            .await
+            .map_or(0, |requests| requests.len())
+    }
+}
+
+struct JsonRpcResponder(Arc<Mutex<ChainState>>);
+
+impl Respond for JsonRpcResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let Ok(body) = serde_json::from_slice::<Value>(&request.body) else {
+            return ResponseTemplate::new(200).set_body_json(error(
+                Value::Null,
+                -32700,
+                "Parse error",
+            ));
+        };
+        let state = self.0.lock().unwrap();
+        let response = match body {
+            Value::Array(calls) => {
+                Value::Array(calls.into_iter().map(|call| answer(&state, call)).collect())
+            }
+            call => answer(&state, call),
+        };
+        ResponseTemplate::new(200).set_body_json(response)
+    }
+}
+
+fn answer(state: &ChainState, call: Value) -> Value {
+    let id = call["id"].clone();
+    let params = &call["params"];
+    let result = match call["method"].as_str().unwrap_or_default() {
+        "eth_chainId" => quantity(state.chain_id),
+        "net_version" => json!(state.chain_id.to_string()),
+        "eth_blockNumber" => quantity(state.block_number),
+        "eth_gasPrice" => quantity(state.gas_price),
+        "eth_maxPriorityFeePerGas" => quantity(0u64),
+        "eth_getBalance" => match params[0].as_str().and_then(|a| a.parse().ok()) {
+            Some(address) => quantity(state.balances.get(&address).copied().unwrap_or_default()),
+            None => return error(id, -32602, "Invalid address"),
+        },
+        "eth_getCode" => match params[0].as_str().and_then(|a| a.parse().ok()) {
+            Some(address) => json!(state.codes.get(&address).cloned().unwrap_or_default()),
+            None => return error(id, -32602, "Invalid address"),
+        },
+        "eth_call" => {
+            let to = params[0]["to"].as_str().and_then(|to| to.parse().ok());
+            let input = params[0]["input"]
+                .as_str()
+                .or(params[0]["data"].as_str())
+                .and_then(|input| hex::decode(input).ok())
+                .filter(|input| input.len() >= 4);
+            let result = to.zip(input).and_then(|(to, input)| {
+                state.calls.get(&(to, FixedBytes::from_slice(&input[..4])))
+            });
+            match result {
+                Some(result) => json!(result),
+                None => return error(id, 3, "execution reverted"),
+            }
+        }
+        method => return error(id, -32601, &format!("Method {method} is not mocked")),
+    };
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn quantity(value: impl Into<U256>) -> Value {
+    json!(format!("{:#x}", value.into()))
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use thegraph_core::alloy::primitives::{address, U256};
+
+    use super::MockRpcServer;
+
+    async fn call(rpc: &MockRpcServer, body: Value) -> Value {
+        let response = reqwest::Client::new()
+            .post(rpc.url())
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        serde_json::from_slice(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mock_rpc_server() {
+        let account = address!("deadbeefcafebabedeadbeefcafebabedeadbeef");
+        let rpc = MockRpcServer::start().await;
+        rpc.set_balance(account, U256::from(1000));
+        rpc.set_gas_price(42);
+        rpc.set_paused(account, true);
+
+        let response = call(
+            &rpc,
+            json!([
+                { "jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [account, "latest"] },
+                { "jsonrpc": "2.0", "id": 2, "method": "eth_gasPrice", "params": [] },
+                { "jsonrpc": "2.0", "id": 3, "method": "eth_call", "params": [{ "to": account, "input": "0x5c975abb" }, "latest"] },
+            ]),
+        )
+        .await;
+        assert_eq!(response[0]["result"], "0x3e8");
+        assert_eq!(response[1]["result"], "0x2a");
+        assert_eq!(
+            response[2]["result"],
+            "0x0000000000000000000000000000000000000000000000000000000000000001"
+        );
+
+        let response = call(
+            &rpc,
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "eth_sendRawTransaction", "params": [] }),
+        )
+        .await;
+        assert_eq!(response["error"]["code"], -32601);
+        assert_eq!(rpc.received_requests().await, 2);
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! TAP aggregator of a sender
+//!
+//! Runs the aggregator server of `tap_aggregator` on a random port, which
+//! serves the v1 and v2 gRPC services and JSON-RPC on the same address.
+
+use std::{collections::HashSet, net::SocketAddr};
+
+use test_assets::{TAP_EIP712_DOMAIN, TAP_SIGNER};
+use thegraph_core::alloy::{
+    primitives::Address, signers::local::PrivateKeySigner, sol_types::Eip712Domain,
+};
+use tokio::task::JoinHandle;
+
+const MAX_BODY_SIZE: u32 = 1024 * 1024;
+const MAX_CONCURRENT_CONNECTIONS: u32 = 255;
+
+/// Aggregator server, stopped with the runtime of the test or by [Self::stop]
+pub struct MockAggregator {
+    handle: JoinHandle<()>,
+    addr: SocketAddr,
+}
+
+#[bon::bon]
+impl MockAggregator {
+    /// Starts an aggregator signing the RAVs with `wallet`, for the
+    /// receipts signed by one of `accepted_addresses`
+    #[builder]
+    pub async fn start(
+        #[builder(default = TAP_SIGNER.0.clone())] wallet: PrivateKeySigner,
+        #[builder(default = HashSet::from([TAP_SIGNER.1]))] accepted_addresses: HashSet<Address>,
+        #[builder(default = TAP_EIP712_DOMAIN.clone())] domain_separator: Eip712Domain,
+    ) -> Self {
+        let (handle, addr) = tap_aggregator::server::run_server(
+            0,
+            wallet,
+            accepted_addresses,
+            domain_separator,
+            MAX_BODY_SIZE,
+            MAX_BODY_SIZE,
+            MAX_CONCURRENT_CONNECTIONS,
+        )
+        .await
+        .expect("Failed to start the aggregator");
+        Self { handle, addr }
+    }
+}
+
+impl MockAggregator {
+    /// Endpoint of the aggregator, as configured in `tap.sender_aggregator_endpoints`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stop(self) {
+        self.handle.abort();
+    }
+}
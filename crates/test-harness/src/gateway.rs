@@ -0,0 +1,208 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Gateway paying for its queries with receipts
+//!
+//! The receipts are signed by [TAP_SIGNER] for [TAP_EIP712_DOMAIN] unless
+//! set otherwise, with a new nonce for each of them, and sent in the
+//! `Tap-Receipt` header the way gateways do: v1 receipts as JSON, v2
+//! receipts as base64 encoded protobuf.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::prelude::*;
+use indexer_receipt::TapReceipt;
+use prost::Message;
+use reqwest::{header::CONTENT_TYPE, Client, Response, Url};
+use serde_json::json;
+use tap_core::signed_message::Eip712SignedMessage;
+use test_assets::{INDEXER_ADDRESS, TAP_EIP712_DOMAIN, TAP_SENDER, TAP_SIGNER};
+use thegraph_core::{
+    alloy::{primitives::Address, signers::local::PrivateKeySigner, sol_types::Eip712Domain},
+    DeploymentId,
+};
+
+const TAP_RECEIPT_HEADER: &str = "tap-receipt";
+
+/// Gateway sending queries to a single service
+pub struct MockGateway {
+    client: Client,
+    service_url: Url,
+    wallet: PrivateKeySigner,
+    domain_separator: Eip712Domain,
+    /// Payer of the v2 receipts
+    payer: Address,
+    /// Indexer the v2 receipts are for
+    service_provider: Address,
+    data_service: Address,
+    next_nonce: AtomicU64,
+}
+
+impl MockGateway {
+    pub fn new(service_url: Url) -> Self {
+        Self {
+            client: Client::new(),
+            service_url,
+            wallet: TAP_SIGNER.0.clone(),
+            domain_separator: TAP_EIP712_DOMAIN.clone(),
+            payer: TAP_SENDER.1,
+            service_provider: INDEXER_ADDRESS,
+            data_service: Address::ZERO,
+            next_nonce: AtomicU64::new(0),
+        }
+    }
+
+    /// Signs the receipts with another key, e.g. one that is not authorized
+    pub fn with_signer(mut self, wallet: PrivateKeySigner) -> Self {
+        self.wallet = wallet;
+        self
+    }
+
+    pub fn with_domain_separator(mut self, domain_separator: Eip712Domain) -> Self {
+        self.domain_separator = domain_separator;
+        self
+    }
+
+    /// Sets the payer, data service and service provider of the v2 receipts
+    pub fn with_v2_parties(
+        mut self,
+        payer: Address,
+        data_service: Address,
+        service_provider: Address,
+    ) -> Self {
+        self.payer = payer;
+        self.data_service = data_service;
+        self.service_provider = service_provider;
+        self
+    }
+
+    fn next_nonce(&self) -> u64 {
+        self.next_nonce.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn now_ns() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+
+    /// Signs a v1 receipt for `allocation_id`, timestamped now
+    pub fn receipt_v1(&self, allocation_id: Address, value: u128) -> tap_graph::SignedReceipt {
+        Eip712SignedMessage::new(
+            &self.domain_separator,
+            tap_graph::Receipt {
+                allocation_id,
+                timestamp_ns: Self::now_ns(),
+                nonce: self.next_nonce(),
+                value,
+            },
+            &self.wallet,
+        )
+        .expect("Failed to sign the receipt")
+    }
+
+    /// Signs a v2 receipt for `allocation_id`, timestamped now
+    pub fn receipt_v2(&self, allocation_id: Address, value: u128) -> tap_graph::v2::SignedReceipt {
+        Eip712SignedMessage::new(
+            &self.domain_separator,
+            tap_graph::v2::Receipt {
+                allocation_id,
+                payer: self.payer,
+                data_service: self.data_service,
+                service_provider: self.service_provider,
+                timestamp_ns: Self::now_ns(),
+                nonce: self.next_nonce(),
+                value,
+            },
+            &self.wallet,
+        )
+        .expect("Failed to sign the receipt")
+    }
+
+    /// Sends `query` to `deployment` paid with `receipt`, or for free if
+    /// `receipt` is `None`
+    pub async fn query(
+        &self,
+        deployment: DeploymentId,
+        query: &str,
+        receipt: Option<&TapReceipt>,
+    ) -> reqwest::Result<Response> {
+        let url = self
+            .service_url
+            .join(&format!("subgraphs/id/{deployment}"))
+            .expect("Invalid service URL");
+        let mut request = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "query": query, "variables": null }).to_string());
+        if let Some(receipt) = receipt {
+            request = request.header(TAP_RECEIPT_HEADER, encode_receipt(receipt));
+        }
+        request.send().await
+    }
+
+    /// Sends `query` to `deployment` with a new v1 receipt of `value`
+    pub async fn query_v1(
+        &self,
+        deployment: DeploymentId,
+        allocation_id: Address,
+        value: u128,
+        query: &str,
+    ) -> reqwest::Result<Response> {
+        let receipt = TapReceipt::V1(self.receipt_v1(allocation_id, value));
+        self.query(deployment, query, Some(&receipt)).await
+    }
+
+    /// Sends `query` to `deployment` with a new v2 receipt of `value`
+    pub async fn query_v2(
+        &self,
+        deployment: DeploymentId,
+        allocation_id: Address,
+        value: u128,
+        query: &str,
+    ) -> reqwest::Result<Response> {
+        let receipt = TapReceipt::V2(self.receipt_v2(allocation_id, value));
+        self.query(deployment, query, Some(&receipt)).await
+    }
+}
+
+/// Value of the `Tap-Receipt` header for `receipt`
+pub fn encode_receipt(receipt: &TapReceipt) -> String {
+    match receipt {
+        TapReceipt::V1(receipt) => {
+            serde_json::to_string(receipt).expect("Failed to serialize the receipt")
+        }
+        TapReceipt::V2(receipt) => {
+            let receipt = tap_aggregator::grpc::v2::SignedReceipt::from(receipt.clone());
+            BASE64_STANDARD.encode(receipt.encode_to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexer_receipt::TapReceipt;
+    use test_assets::{ALLOCATION_ID_0, TAP_EIP712_DOMAIN, TAP_SIGNER};
+
+    use super::MockGateway;
+
+    #[test]
+    fn test_receipts_are_signed_with_new_nonces() {
+        let gateway = MockGateway::new("http://localhost:7600".parse().unwrap());
+        let first = TapReceipt::V1(gateway.receipt_v1(ALLOCATION_ID_0, 10));
+        let second = TapReceipt::V2(gateway.receipt_v2(ALLOCATION_ID_0, 10));
+
+        assert_ne!(first.nonce(), second.nonce());
+        for receipt in [first, second] {
+            assert_eq!(
+                receipt.recover_signer(&TAP_EIP712_DOMAIN).unwrap(),
+                TAP_SIGNER.1
+            );
+        }
+    }
+}
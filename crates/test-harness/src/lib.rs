@@ -0,0 +1,30 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mocks of the services indexer-service and tap-agent talk to, for
+//! end-to-end tests
+//!
+//! - [gateway::MockGateway] signs receipts and sends paid queries to the
+//!   service, like a gateway does.
+//! - [aggregator::MockAggregator] serves the TAP aggregator of a sender,
+//!   answering RAV requests of both receipt versions.
+//! - [subgraphs] starts network and escrow subgraphs answering the queries
+//!   of the watchers with the values of [assets].
+//!
+//! ```ignore
+//! let network_subgraph = subgraphs::network_subgraph(&assets::INDEXER_ALLOCATIONS).await;
+//! let escrow_subgraph = subgraphs::escrow_subgraph().await;
+//! let aggregator = MockAggregator::start().call().await;
+//! // start the service with these URLs, then
+//! let gateway = MockGateway::new(service_url);
+//! let response = gateway
+//!     .query_v1(deployment, assets::ALLOCATION_ID_0, 100, "{ _meta { block { number } } }")
+//!     .await?;
+//! ```
+
+pub mod aggregator;
+pub mod gateway;
+pub mod subgraphs;
+
+/// Wallets, addresses and allocations the mocks are set up with
+pub use test_assets as assets;
@@ -0,0 +1,195 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Network and escrow subgraphs
+//!
+//! The queries are told apart by the name of their operation, queries the
+//! mocks don't know about are answered with empty data.
+
+use std::collections::HashMap;
+
+use indexer_allocation::Allocation;
+use serde_json::{json, Value};
+use test_assets::{DISPUTE_MANAGER_ADDRESS, ESCROW_QUERY_RESPONSE};
+use thegraph_core::alloy::primitives::Address;
+use wiremock::{
+    matchers::{body_string_contains, method},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Block the subgraphs are indexed at
+const BLOCK_NUMBER: u64 = 1;
+
+fn meta() -> Value {
+    json!({
+        "block": {
+            "number": BLOCK_NUMBER,
+            "hash": format!("0x{}", "0".repeat(64)),
+            "timestamp": 0,
+        }
+    })
+}
+
+/// Answers `operation` with `data`
+async fn register(server: &MockServer, operation: &str, data: Value) {
+    server
+        .register(
+            Mock::given(method("POST"))
+                .and(body_string_contains(operation))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": data }))),
+        )
+        .await;
+}
+
+/// Answers the queries that are not mocked with empty data, the `_meta`
+/// field is queried with the `meta` alias
+async fn register_fallback(server: &MockServer, data: Value) {
+    server
+        .register(
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": data })))
+                .with_priority(u8::MAX),
+        )
+        .await;
+}
+
+fn allocation_json(allocation: &Allocation) -> Value {
+    json!({
+        "id": allocation.id,
+        "indexer": { "id": allocation.indexer },
+        "allocatedTokens": allocation.allocated_tokens.to_string(),
+        "createdAtBlockHash": allocation.created_at_block_hash,
+        "createdAtEpoch": allocation.created_at_epoch,
+        "closedAtEpoch": allocation.closed_at_epoch,
+        "subgraphDeployment": {
+            "id": allocation.subgraph_deployment.id.to_string(),
+            "deniedAt": allocation.subgraph_deployment.denied_at.unwrap_or_default(),
+        },
+        "provision": allocation.provision.as_ref().map(|provision| json!({
+            "indexer": { "id": provision.service_provider },
+            "dataService": { "id": provision.data_service },
+        })),
+    })
+}
+
+/// Network subgraph with `allocations` as the allocations of the indexer,
+/// e.g. [test_assets::INDEXER_ALLOCATIONS]
+pub async fn network_subgraph(allocations: &HashMap<Address, Allocation>) -> MockServer {
+    let server = MockServer::start().await;
+    let mut allocations: Vec<_> = allocations.values().collect();
+    allocations.sort_by_key(|allocation| allocation.id);
+    register(
+        &server,
+        "AllocationsQuery",
+        json!({
+            "meta": meta(),
+            "allocations": allocations.into_iter().map(allocation_json).collect::<Vec<_>>(),
+        }),
+    )
+    .await;
+    register(
+        &server,
+        "DisputeManager",
+        json!({ "graphNetwork": {
+            "disputeManager": DISPUTE_MANAGER_ADDRESS,
+            "minimumDisputeDeposit": "10000000000000000000000",
+        } }),
+    )
+    .await;
+    register_fallback(&server, json!({ "meta": meta() })).await;
+    server
+}
+
+/// Escrow subgraph with the accounts of [ESCROW_QUERY_RESPONSE] and no
+/// redeemed RAV
+pub async fn escrow_subgraph() -> MockServer {
+    let server = MockServer::start().await;
+    let accounts: Value =
+        serde_json::from_str(ESCROW_QUERY_RESPONSE).expect("Invalid escrow accounts response");
+    register(&server, "EscrowAccountQuery", accounts["data"].clone()).await;
+    register_fallback(
+        &server,
+        json!({ "meta": meta(), "transactions": [], "escrowAccounts": [] }),
+    )
+    .await;
+    server
+}
+
+#[cfg(test)]
+mod tests {
+    use graphql_client::{GraphQLQuery, QueryBody, Response};
+    use indexer_query::{
+        dispute_manager::{self, DisputeManager},
+        network_subgraph_meta,
+        unfinalized_transactions::{self, UnfinalizedTransactions},
+        NetworkSubgraphMeta,
+    };
+    use serde::{de::DeserializeOwned, Serialize};
+    use test_assets::{DISPUTE_MANAGER_ADDRESS, INDEXER_ALLOCATIONS};
+    use wiremock::MockServer;
+
+    use super::{escrow_subgraph, network_subgraph, BLOCK_NUMBER};
+
+    /// Sends `body` to `server` as the clients of the subgraphs do
+    async fn query<V: Serialize, T: DeserializeOwned>(
+        server: &MockServer,
+        body: QueryBody<V>,
+    ) -> T {
+        let response: Response<T> = serde_json::from_str(
+            &reqwest::Client::new()
+                .post(server.uri())
+                .body(serde_json::to_string(&body).unwrap())
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        response.data.expect("The mock answered without data")
+    }
+
+    #[tokio::test]
+    async fn test_network_subgraph() {
+        let server = network_subgraph(&INDEXER_ALLOCATIONS).await;
+
+        let data: dispute_manager::ResponseData = query(
+            &server,
+            DisputeManager::build_query(dispute_manager::Variables {}),
+        )
+        .await;
+        assert_eq!(
+            data.graph_network.unwrap().dispute_manager,
+            DISPUTE_MANAGER_ADDRESS
+        );
+
+        // answered by the fallback
+        let data: network_subgraph_meta::ResponseData = query(
+            &server,
+            NetworkSubgraphMeta::build_query(network_subgraph_meta::Variables),
+        )
+        .await;
+        assert_eq!(data.meta.unwrap().block.number, BLOCK_NUMBER as i64);
+    }
+
+    #[tokio::test]
+    async fn test_escrow_subgraph() {
+        let server = escrow_subgraph().await;
+
+        // answered by the fallback
+        let data: unfinalized_transactions::ResponseData = query(
+            &server,
+            UnfinalizedTransactions::build_query(unfinalized_transactions::Variables {
+                unfinalized_ravs_allocation_ids: vec![],
+                sender: String::new(),
+                block: None,
+                first: 1000,
+                last: String::new(),
+            }),
+        )
+        .await;
+        assert_eq!(data.meta.unwrap().block.number, BLOCK_NUMBER as i64);
+        assert!(data.transactions.is_empty());
+    }
+}
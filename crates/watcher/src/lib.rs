@@ -6,17 +6,336 @@
 //! usually carry like initializing things without initializing
 //! its values
 
-use std::{future::Future, time::Duration};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_counter_vec, GaugeVec, IntCounterVec};
+use rand::Rng;
 use tokio::{
     select,
-    sync::watch::{self, Ref},
+    sync::{
+        mpsc,
+        watch::{self, Ref},
+    },
     task::JoinHandle,
-    time::{self, sleep},
+    time::{self, sleep, Instant},
 };
 
+lazy_static! {
+    static ref LAST_SUCCESS: GaugeVec = register_gauge_vec!(
+        "indexer_watcher_last_success_timestamp_seconds",
+        "Unix timestamp of the last time the value of a watcher was updated",
+        &["watcher"]
+    )
+    .unwrap();
+    static ref FAILURES: IntCounterVec = register_int_counter_vec!(
+        "indexer_watcher_failures_total",
+        "Failed updates of a watcher, retried after a jittered delay",
+        &["watcher"]
+    )
+    .unwrap();
+}
+
+/// Value kept up to date from a source, e.g. a subgraph
+///
+/// Every watcher updates its value the same way: it is fetched once before
+/// the watcher is returned, then every `interval` or whenever its
+/// subscription is notified. A failed update is retried after half the
+/// interval, give or take a random jitter so that the watchers failing
+/// together don't retry together, and the previous value is kept meanwhile.
+///
+/// Named watchers report the time of their last update and their failures
+/// in the `indexer_watcher_*` metrics.
+pub struct Watcher<T> {
+    receiver: watch::Receiver<T>,
+    last_success: watch::Receiver<Instant>,
+}
+
+impl<T> Clone for Watcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            receiver: self.receiver.clone(),
+            last_success: self.last_success.clone(),
+        }
+    }
+}
+
+impl<T> Watcher<T>
+where
+    T: Send + Sync + 'static,
+{
+    /// Fetches the value with `fetch` every `interval`
+    pub async fn poll<F, Fut>(
+        name: impl Into<String>,
+        interval: Duration,
+        fetch: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+    {
+        let name: String = name.into();
+        Self::spawn_polling(Some(name.into()), interval, fetch).await
+    }
+
+    /// Fetches the value with `fetch` every time the subscription returned
+    /// by `subscribe` is notified, and at least every interval so that a
+    /// value that did not change is not reported as stale
+    ///
+    /// While there is no active subscription, it falls back to fetching the
+    /// value every interval and tries to subscribe again on each tick.
+    pub async fn subscription<F, Fut, S, SFut>(
+        name: impl Into<String>,
+        interval: Duration,
+        fetch: F,
+        subscribe: S,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+        S: Fn() -> SFut + Send + 'static,
+        SFut: Future<Output = anyhow::Result<mpsc::Receiver<()>>> + Send,
+    {
+        let name: String = name.into();
+        Self::spawn_subscription(Some(name.into()), interval, fetch, subscribe).await
+    }
+
+    async fn spawn_polling<F, Fut>(
+        name: Option<Arc<str>>,
+        interval: Duration,
+        fetch: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+    {
+        let initial_value = fetch().await;
+        let (updater, watcher) = Updater::start(name, interval, initial_value)?;
+        tokio::spawn(async move {
+            // the initial value was just fetched, skip the immediate first tick
+            let mut time_interval = time::interval_at(Instant::now() + interval, interval);
+            time_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+            loop {
+                time_interval.tick().await;
+                loop {
+                    let result = fetch().await;
+                    match updater.update(result) {
+                        Ok(()) => break,
+                        Err(delay) => sleep(delay).await,
+                    }
+                }
+                time_interval.reset();
+            }
+        });
+        Ok(watcher)
+    }
+
+    async fn spawn_subscription<F, Fut, S, SFut>(
+        name: Option<Arc<str>>,
+        interval: Duration,
+        fetch: F,
+        subscribe: S,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+        S: Fn() -> SFut + Send + 'static,
+        SFut: Future<Output = anyhow::Result<mpsc::Receiver<()>>> + Send,
+    {
+        let initial_value = fetch().await;
+        let (updater, watcher) = Updater::start(name, interval, initial_value)?;
+        tokio::spawn(async move {
+            // the initial value was just fetched, skip the immediate first tick
+            let mut time_interval = time::interval_at(Instant::now() + interval, interval);
+            time_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+            loop {
+                match subscribe().await {
+                    Ok(mut notifications) => {
+                        time_interval.reset();
+                        loop {
+                            select! {
+                                notification = notifications.recv() => {
+                                    if notification.is_none() {
+                                        break;
+                                    }
+                                }
+                                _ = time_interval.tick() => {}
+                            }
+                            loop {
+                                let result = fetch().await;
+                                match updater.update(result) {
+                                    Ok(()) => break,
+                                    Err(delay) => sleep(delay).await,
+                                }
+                            }
+                            time_interval.reset();
+                        }
+                        tracing::warn!(
+                            watcher = updater.name(),
+                            "Subscription ended, falling back to polling"
+                        );
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            watcher = updater.name(),
+                            error = %err,
+                            "Failed to subscribe, falling back to polling"
+                        );
+                    }
+                }
+
+                time_interval.tick().await;
+                loop {
+                    let result = fetch().await;
+                    match updater.update(result) {
+                        Ok(()) => break,
+                        Err(delay) => sleep(delay).await,
+                    }
+                }
+            }
+        });
+        Ok(watcher)
+    }
+}
+
+impl<T> Watcher<T> {
+    /// Receiver of the value, updated by the watcher
+    pub fn receiver(&self) -> watch::Receiver<T> {
+        self.receiver.clone()
+    }
+
+    pub fn into_receiver(self) -> watch::Receiver<T> {
+        self.receiver
+    }
+
+    /// Time the value was last updated at
+    pub fn last_success(&self) -> Instant {
+        *self.last_success.borrow()
+    }
+
+    /// Whether the value was updated less than `max_age` ago
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        self.last_success().elapsed() <= max_age
+    }
+
+    /// Waits until the value was updated less than `max_age` ago, returns
+    /// right away if it already was
+    ///
+    /// Fails if the watcher stopped, its value is never updated again.
+    pub async fn wait_until_fresh(&self, max_age: Duration) -> anyhow::Result<()> {
+        let mut last_success = self.last_success.clone();
+        last_success.mark_unchanged();
+        while !self.is_fresh(max_age) {
+            if last_success.changed().await.is_err() {
+                anyhow::bail!("The watcher stopped, its value is stale");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> From<Watcher<T>> for watch::Receiver<T> {
+    fn from(watcher: Watcher<T>) -> Self {
+        watcher.into_receiver()
+    }
+}
+
+/// Updates the value of a [Watcher]
+struct Updater<T> {
+    name: Option<Arc<str>>,
+    interval: Duration,
+    value: watch::Sender<T>,
+    last_success: watch::Sender<Instant>,
+}
+
+impl<T> Updater<T> {
+    /// Fails if the initial value couldn't be fetched
+    fn start(
+        name: Option<Arc<str>>,
+        interval: Duration,
+        initial_value: anyhow::Result<T>,
+    ) -> anyhow::Result<(Self, Watcher<T>)> {
+        let initial_value = match initial_value {
+            Ok(value) => value,
+            Err(err) => {
+                if let Some(name) = &name {
+                    FAILURES.with_label_values(&[name.as_ref()]).inc();
+                }
+                return Err(err);
+            }
+        };
+        let (value, receiver) = watch::channel(initial_value);
+        let (last_success, last_success_receiver) = watch::channel(Instant::now());
+        let updater = Self {
+            name,
+            interval,
+            value,
+            last_success,
+        };
+        updater.record_success();
+        let watcher = Watcher {
+            receiver,
+            last_success: last_success_receiver,
+        };
+        Ok((updater, watcher))
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+
+    /// Updates the value with the result of a fetch, or returns the delay
+    /// after which the failed fetch is retried
+    fn update(&self, result: anyhow::Result<T>) -> Result<(), Duration> {
+        match result {
+            Ok(value) => {
+                self.value.send(value).expect("Failed to update channel");
+                self.last_success.send_replace(Instant::now());
+                self.record_success();
+                Ok(())
+            }
+            Err(err) => {
+                let delay = retry_delay(self.interval);
+                tracing::warn!(
+                    watcher = self.name(),
+                    error = %err,
+                    retry_in_ms = delay.as_millis() as u64,
+                    "There was an error while updating watcher"
+                );
+                if let Some(name) = &self.name {
+                    FAILURES.with_label_values(&[name.as_ref()]).inc();
+                }
+                Err(delay)
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        if let Some(name) = &self.name {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            LAST_SUCCESS.with_label_values(&[name.as_ref()]).set(now);
+        }
+    }
+}
+
+/// Half of `interval`, plus or minus up to a half of it
+fn retry_delay(interval: Duration) -> Duration {
+    interval
+        .div_f64(2.0)
+        .mul_f64(rand::rng().random_range(0.5..1.5))
+}
+
 /// Creates a new watcher that auto initializes it with initial_value
 /// and updates it given an interval
+///
+/// Unlike [Watcher::poll], its updates are not reported in the metrics.
 pub async fn new_watcher<T, F, Fut>(
     interval: Duration,
     function: F,
@@ -26,28 +345,32 @@ where
     T: Sync + Send + 'static,
     Fut: Future<Output = anyhow::Result<T>> + Send,
 {
-    let initial_value = function().await?;
-
-    let (tx, rx) = watch::channel(initial_value);
+    Watcher::spawn_polling(None, interval, function)
+        .await
+        .map(Watcher::into_receiver)
+}
 
-    tokio::spawn(async move {
-        let mut time_interval = time::interval(interval);
-        time_interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
-        loop {
-            time_interval.tick().await;
-            let result = function().await;
-            match result {
-                Ok(value) => tx.send(value).expect("Failed to update channel"),
-                Err(err) => {
-                    // TODO mark it as delayed
-                    tracing::warn!(error = %err, "There was an error while updating watcher");
-                    // Sleep for a bit before we retry
-                    sleep(interval.div_f32(2.0)).await;
-                }
-            }
-        }
-    });
-    Ok(rx)
+/// Creates a new watcher that auto initializes it with initial_value
+/// and updates it every time the subscription returned by `subscribe` is notified
+///
+/// While there is no active subscription, it falls back to updating the value
+/// every interval and tries to subscribe again on each tick. Unlike
+/// [Watcher::subscription], its updates are not reported in the metrics.
+pub async fn new_subscription_watcher<T, F, Fut, S, SFut>(
+    interval: Duration,
+    function: F,
+    subscribe: S,
+) -> anyhow::Result<watch::Receiver<T>>
+where
+    F: Fn() -> Fut + Send + 'static,
+    T: Sync + Send + 'static,
+    Fut: Future<Output = anyhow::Result<T>> + Send,
+    S: Fn() -> SFut + Send + 'static,
+    SFut: Future<Output = anyhow::Result<mpsc::Receiver<()>>> + Send,
+{
+    Watcher::spawn_subscription(None, interval, function, subscribe)
+        .await
+        .map(Watcher::into_receiver)
 }
 
 /// Join two watch::Receiver
@@ -143,3 +466,92 @@ where
     });
     rx
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use anyhow::anyhow;
+    use tokio::sync::mpsc;
+
+    use super::Watcher;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watcher_retries_and_freshness() {
+        let interval = Duration::from_secs(10);
+        let calls = Arc::new(AtomicUsize::new(0));
+        // fails on the second and third calls
+        let watcher = Watcher::poll("test", interval, {
+            let calls = calls.clone();
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    match call {
+                        1 | 2 => Err(anyhow!("unreachable")),
+                        _ => Ok(call),
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let receiver = watcher.receiver();
+        assert_eq!(*receiver.borrow(), 0);
+        assert!(watcher.is_fresh(interval));
+
+        // the first update fails, the value is kept
+        tokio::time::sleep(interval + Duration::from_millis(1)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(*receiver.borrow(), 0);
+        assert!(!watcher.is_fresh(interval));
+
+        // retried within an interval, the third call fails too
+        tokio::time::timeout(interval * 2, watcher.wait_until_fresh(interval))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(*receiver.borrow(), 3);
+        assert!(watcher.is_fresh(interval));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscription_refreshed_every_interval() {
+        let interval = Duration::from_secs(10);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (notify, notifications) = mpsc::channel(1);
+        let notifications = Arc::new(Mutex::new(Some(notifications)));
+        let watcher = Watcher::subscription(
+            "test-subscription",
+            interval,
+            {
+                let calls = calls.clone();
+                move || {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    async move { Ok(call) }
+                }
+            },
+            move || {
+                let notifications = notifications.lock().unwrap().take();
+                async move { notifications.ok_or_else(|| anyhow!("already subscribed")) }
+            },
+        )
+        .await
+        .unwrap();
+
+        notify.send(()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // nothing changed, the value is still read again
+        tokio::time::sleep(interval + Duration::from_millis(1)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert!(watcher.is_fresh(interval));
+    }
+}
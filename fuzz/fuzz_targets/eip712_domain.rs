@@ -0,0 +1,61 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Recovers the signer of a receipt with an arbitrary signature for an
+//! arbitrary EIP-712 domain
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use indexer_receipt::TapReceipt;
+use libfuzzer_sys::fuzz_target;
+use tap_core::{signed_message::Eip712SignedMessage, tap_eip712_domain};
+use thegraph_core::alloy::{primitives::Address, signers::Signature};
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    chain_id: u64,
+    verifier: [u8; 20],
+    v2: bool,
+    allocation_id: [u8; 20],
+    payer: [u8; 20],
+    service_provider: [u8; 20],
+    data_service: [u8; 20],
+    timestamp_ns: u64,
+    nonce: u64,
+    value: u128,
+    signature: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let Ok(signature) = Signature::try_from(input.signature.as_slice()) else {
+        return;
+    };
+    let domain = tap_eip712_domain(input.chain_id, Address::from(input.verifier));
+    let receipt = if input.v2 {
+        TapReceipt::V2(Eip712SignedMessage {
+            message: tap_graph::v2::Receipt {
+                payer: Address::from(input.payer),
+                service_provider: Address::from(input.service_provider),
+                data_service: Address::from(input.data_service),
+                allocation_id: Address::from(input.allocation_id),
+                timestamp_ns: input.timestamp_ns,
+                nonce: input.nonce,
+                value: input.value,
+            },
+            signature,
+        })
+    } else {
+        TapReceipt::V1(Eip712SignedMessage {
+            message: tap_graph::Receipt {
+                allocation_id: Address::from(input.allocation_id),
+                timestamp_ns: input.timestamp_ns,
+                nonce: input.nonce,
+                value: input.value,
+            },
+            signature,
+        })
+    };
+    let _ = receipt.signing_hash(&domain);
+    let _ = receipt.recover_signer(&domain);
+});
@@ -0,0 +1,19 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decodes the response of a sender aggregator to a RAV request
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prost::Message;
+use tap_aggregator::grpc::{v1, v2};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = v1::RavResponse::decode(data) {
+        let _ = response.signed_rav();
+    }
+    if let Ok(response) = v2::RavResponse::decode(data) {
+        let _ = response.signed_rav();
+    }
+});
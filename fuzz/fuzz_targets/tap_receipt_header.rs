@@ -0,0 +1,17 @@
+// Copyright 2023-, Edge & Node, GraphOps, and Semiotic Labs.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decodes the value of a `Tap-Receipt` header, as sent by the gateways
+
+#![no_main]
+
+use axum_extra::headers::{Header, HeaderValue};
+use indexer_service_rs::service::{decode_receipts, TapHeader};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_receipts(data);
+    if let Ok(header_value) = HeaderValue::from_bytes(data) {
+        let _ = TapHeader::decode(&mut [&header_value].into_iter());
+    }
+});
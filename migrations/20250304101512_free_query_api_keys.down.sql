@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS free_query_api_key_usage CASCADE;
+
+DROP TABLE IF EXISTS free_query_api_keys CASCADE;
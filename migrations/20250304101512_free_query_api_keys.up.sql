@@ -0,0 +1,21 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS free_query_api_keys (
+    name VARCHAR(255) PRIMARY KEY,
+    -- keccak256 of the token, the token itself is never stored
+    key_hash CHAR(64) NOT NULL,
+    -- maximum number of queries per UTC day, NULL means unlimited
+    daily_quota BIGINT,
+    -- deployments this key can query, NULL means any deployment
+    allowed_deployments TEXT[],
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS free_query_api_keys_key_hash_idx ON free_query_api_keys (key_hash);
+
+CREATE TABLE IF NOT EXISTS free_query_api_key_usage (
+    name VARCHAR(255) NOT NULL REFERENCES free_query_api_keys (name) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    query_count BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (name, day)
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE scalar_tap_rav_requests_failed DROP COLUMN IF EXISTS created_at;
+
+ALTER TABLE tap_horizon_rav_requests_failed DROP COLUMN IF EXISTS created_at;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- Lets tap-agent delete the failed RAV requests that are older than the retention
+ALTER TABLE scalar_tap_rav_requests_failed
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
+
+ALTER TABLE tap_horizon_rav_requests_failed
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
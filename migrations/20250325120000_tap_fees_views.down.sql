@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP VIEW IF EXISTS tap_fees_ravs_daily;
+
+DROP VIEW IF EXISTS tap_fees_receipts_daily;
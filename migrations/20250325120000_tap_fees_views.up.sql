@@ -0,0 +1,48 @@
+-- Add up migration script here
+-- Daily rollups of the receipts and RAVs, used by the `/fees/summary` route
+-- of indexer-service and by dashboards that don't know the TAP tables
+CREATE OR REPLACE VIEW tap_fees_receipts_daily AS
+SELECT
+    'v1' AS version,
+    allocation_id,
+    signer_address,
+    NULL::CHAR(40) AS sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    COUNT(*) AS receipts,
+    SUM(value) AS value
+FROM scalar_tap_receipts
+GROUP BY allocation_id, signer_address, 5
+UNION ALL
+SELECT
+    'v2' AS version,
+    allocation_id,
+    signer_address,
+    payer AS sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    COUNT(*) AS receipts,
+    SUM(value) AS value
+FROM tap_horizon_receipts
+GROUP BY allocation_id, signer_address, payer, 5;
+
+-- A RAV covers every receipt of the allocation and sender up to its
+-- timestamp, `final` is set by indexer-agent once it is redeemed
+CREATE OR REPLACE VIEW tap_fees_ravs_daily AS
+SELECT
+    'v1' AS version,
+    allocation_id,
+    sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    value_aggregate,
+    last,
+    final
+FROM scalar_tap_ravs
+UNION ALL
+SELECT
+    'v2' AS version,
+    allocation_id,
+    payer AS sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    value_aggregate,
+    last,
+    final
+FROM tap_horizon_ravs;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Fees of each allocation and sender saved periodically by tap-agent, so it
+-- only has to sum up the receipts received after `last_id` when it starts
+CREATE TABLE IF NOT EXISTS tap_agent_state (
+    version TEXT NOT NULL,
+    sender_address CHAR(40) NOT NULL,
+    allocation_id CHAR(40) NOT NULL,
+    -- timestamp of the last RAV, the snapshot is stale once a newer one is received
+    rav_timestamp_ns NUMERIC(20) NOT NULL,
+    last_id BIGINT NOT NULL,
+    value NUMERIC(39) NOT NULL,
+    counter BIGINT NOT NULL,
+    invalid_last_id BIGINT NOT NULL,
+    invalid_value NUMERIC(39) NOT NULL,
+    invalid_counter BIGINT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (version, sender_address, allocation_id)
+);
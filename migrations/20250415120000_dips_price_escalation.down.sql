@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE indexing_agreements
+    DROP COLUMN IF EXISTS version,
+    DROP COLUMN IF EXISTS amended_at,
+    DROP COLUMN IF EXISTS unprofitable_at;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+ALTER TABLE indexing_agreements
+    -- vouchers proposed with version 2 have price escalation terms in their metadata
+    ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1,
+    -- the terms were replaced by an amendment, prices escalate from then
+    ADD COLUMN IF NOT EXISTS amended_at TIMESTAMP WITH TIME ZONE,
+    -- set while the current price of the agreement is below the minimum price
+    ADD COLUMN IF NOT EXISTS unprofitable_at TIMESTAMP WITH TIME ZONE;
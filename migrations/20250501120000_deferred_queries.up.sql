@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Queries served without a receipt, waiting for it to be submitted to the
+-- receipt ingest service of indexer-service
+CREATE TABLE IF NOT EXISTS deferred_queries (
+    -- `Graph-Query-Id` header of the query
+    query_id VARCHAR(255) PRIMARY KEY,
+    deployment_id VARCHAR(255) NOT NULL,
+    query TEXT NOT NULL,
+    variables TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- set once the receipt of the query is stored
+    paid_at TIMESTAMP WITH TIME ZONE,
+    -- set once the query is reported as unpaid
+    expired BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX IF NOT EXISTS deferred_queries_unpaid_idx
+    ON deferred_queries (created_at) WHERE paid_at IS NULL;
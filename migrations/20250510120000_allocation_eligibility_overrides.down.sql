@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS allocation_eligibility_override_update ON allocation_eligibility_overrides CASCADE;
+
+DROP FUNCTION IF EXISTS allocation_eligibility_override_notify() CASCADE;
+
+DROP TABLE IF EXISTS allocation_eligibility_overrides CASCADE;
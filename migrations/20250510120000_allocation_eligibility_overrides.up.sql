@@ -0,0 +1,25 @@
+-- Add up migration script here
+-- Allocations forced in or out of the allocations eligible for receipts,
+-- e.g. while the network subgraph is not reliable
+CREATE TABLE IF NOT EXISTS allocation_eligibility_overrides (
+    allocation_id CHAR(40) PRIMARY KEY,
+    -- whether receipts for the allocation are accepted
+    eligible BOOLEAN NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- the override is ignored afterwards
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE FUNCTION allocation_eligibility_override_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('allocation_eligibility_override_notification', TG_OP);
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER allocation_eligibility_override_update AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+    ON allocation_eligibility_overrides
+    FOR EACH STATEMENT EXECUTE PROCEDURE allocation_eligibility_override_notify();
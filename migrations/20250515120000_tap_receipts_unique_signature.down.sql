@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS scalar_tap_receipts_signature_idx;
+
+DROP INDEX IF EXISTS tap_horizon_receipts_signature_idx;
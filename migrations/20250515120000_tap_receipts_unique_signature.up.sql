@@ -0,0 +1,20 @@
+-- Add up migration script here
+-- A receipt sent to several replicas of indexer-service must be stored once,
+-- the duplicates stored before are dropped, keeping the first one.
+-- A replayed receipt has the same timestamp, which is part of the index.
+DELETE FROM scalar_tap_receipts a
+USING scalar_tap_receipts b
+WHERE a.signature = b.signature AND a.timestamp_ns = b.timestamp_ns AND a.id > b.id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS scalar_tap_receipts_signature_idx
+    ON scalar_tap_receipts (signature, timestamp_ns);
+
+DELETE FROM tap_horizon_receipts a
+USING tap_horizon_receipts b
+WHERE a.signature = b.signature AND a.id > b.id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS tap_horizon_receipts_signature_idx
+    ON tap_horizon_receipts (signature);
+
+-- The snapshots of tap-agent may include the dropped duplicates
+DELETE FROM tap_agent_state;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+-- Status of the RAVs marked as last, reconciled by tap-agent with the redeem
+-- transactions indexed by the escrow subgraph
+CREATE TABLE IF NOT EXISTS scalar_tap_rav_redemptions (
+    allocation_id CHAR(40) NOT NULL,
+    sender_address CHAR(40) NOT NULL,
+    value_aggregate NUMERIC(39) NOT NULL,
+    -- 'unredeemed', 'redeemed' or 'expired'
+    status TEXT NOT NULL,
+    checked_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (allocation_id, sender_address)
+);
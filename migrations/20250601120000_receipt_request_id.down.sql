@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE scalar_tap_receipts DROP COLUMN IF EXISTS request_id;
+ALTER TABLE tap_horizon_receipts DROP COLUMN IF EXISTS request_id;
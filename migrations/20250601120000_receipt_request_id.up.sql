@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- Request paid with several receipts, shared by all of its receipts
+ALTER TABLE scalar_tap_receipts ADD COLUMN IF NOT EXISTS request_id UUID;
+ALTER TABLE tap_horizon_receipts ADD COLUMN IF NOT EXISTS request_id UUID;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS query_blocklist_update ON query_blocklist CASCADE;
+
+DROP FUNCTION IF EXISTS query_blocklist_notify() CASCADE;
+
+DROP TABLE IF EXISTS query_blocklist CASCADE;
@@ -0,0 +1,25 @@
+-- Add up migration script here
+-- Queries refused by indexer-service, added with the `/query-blocklist`
+-- management routes on top of the ones of its configuration
+CREATE TABLE IF NOT EXISTS query_blocklist (
+    id BIGSERIAL PRIMARY KEY,
+    -- either `hash`, the fingerprint of the queries, or `regex`
+    kind VARCHAR(5) NOT NULL CHECK (kind IN ('hash', 'regex')),
+    pattern TEXT NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (kind, pattern)
+);
+
+CREATE FUNCTION query_blocklist_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('query_blocklist_notification', TG_OP);
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER query_blocklist_update AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+    ON query_blocklist
+    FOR EACH STATEMENT EXECUTE PROCEDURE query_blocklist_notify();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE indexing_agreements
+    DROP COLUMN IF EXISTS indexing_started_at,
+    DROP COLUMN IF EXISTS synced_at;
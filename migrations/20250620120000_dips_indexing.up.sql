@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE indexing_agreements
+    -- the deployment of the agreement was handed to indexer-agent or graph-node
+    ADD COLUMN IF NOT EXISTS indexing_started_at TIMESTAMP WITH TIME ZONE,
+    -- graph-node reported the deployment as synced
+    ADD COLUMN IF NOT EXISTS synced_at TIMESTAMP WITH TIME ZONE;
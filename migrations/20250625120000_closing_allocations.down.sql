@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS closing_allocation_update ON closing_allocations CASCADE;
+
+DROP FUNCTION IF EXISTS closing_allocation_notify() CASCADE;
+
+DROP TABLE IF EXISTS closing_allocations CASCADE;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+-- Allocations whose last RAVs are requested by tap-agent, the receipts of
+-- these allocations are refused by the service since they would never be
+-- redeemed
+CREATE TABLE IF NOT EXISTS closing_allocations (
+    allocation_id CHAR(40) PRIMARY KEY,
+    closing_since TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE FUNCTION closing_allocation_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('closing_allocation_notification', TG_OP);
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER closing_allocation_update AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+    ON closing_allocations
+    FOR EACH STATEMENT EXECUTE PROCEDURE closing_allocation_notify();
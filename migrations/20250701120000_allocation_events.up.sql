@@ -0,0 +1,14 @@
+-- Add up migration script here
+-- Timeline of the allocations: the events observed by the allocations
+-- monitor of the service and the RAV events of tap-agent
+CREATE TABLE IF NOT EXISTS allocation_events (
+    id BIGSERIAL PRIMARY KEY,
+    allocation_id CHAR(40) NOT NULL,
+    -- created, eligible, closed, final_rav_requested or rav_redeemed
+    event TEXT NOT NULL,
+    -- sender of the RAV events, empty for the other events
+    sender_address TEXT NOT NULL DEFAULT '',
+    recorded_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    -- every replica records the events it observes
+    UNIQUE (allocation_id, event, sender_address)
+);
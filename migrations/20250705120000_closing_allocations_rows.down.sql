@@ -0,0 +1,17 @@
+-- Add down migration script here
+DROP TRIGGER IF EXISTS closing_allocation_truncate ON closing_allocations CASCADE;
+
+DROP TRIGGER IF EXISTS closing_allocation_update ON closing_allocations CASCADE;
+
+CREATE OR REPLACE FUNCTION closing_allocation_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    PERFORM pg_notify('closing_allocation_notification', TG_OP);
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER closing_allocation_update AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE
+    ON closing_allocations
+    FOR EACH STATEMENT EXECUTE PROCEDURE closing_allocation_notify();
@@ -0,0 +1,27 @@
+-- Add up migration script here
+-- The allocation of the changed row is sent with the notification, so that
+-- the service updates its closing allocations without reloading the table
+DROP TRIGGER IF EXISTS closing_allocation_update ON closing_allocations;
+
+CREATE OR REPLACE FUNCTION closing_allocation_notify()
+RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM pg_notify('closing_allocation_notification', TG_OP || ' ' || OLD.allocation_id);
+    ELSIF TG_OP = 'TRUNCATE' THEN
+        PERFORM pg_notify('closing_allocation_notification', TG_OP);
+    ELSE
+        PERFORM pg_notify('closing_allocation_notification', TG_OP || ' ' || NEW.allocation_id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE 'plpgsql';
+
+CREATE TRIGGER closing_allocation_update AFTER INSERT OR UPDATE OR DELETE
+    ON closing_allocations
+    FOR EACH ROW EXECUTE PROCEDURE closing_allocation_notify();
+
+CREATE TRIGGER closing_allocation_truncate AFTER TRUNCATE
+    ON closing_allocations
+    FOR EACH STATEMENT EXECUTE PROCEDURE closing_allocation_notify();
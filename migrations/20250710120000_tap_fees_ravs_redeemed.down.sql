@@ -0,0 +1,24 @@
+-- Add down migration script here
+-- `redeemed_at` is kept, it is also written by indexer-agent
+DROP VIEW IF EXISTS tap_fees_ravs_daily;
+
+CREATE VIEW tap_fees_ravs_daily AS
+SELECT
+    'v1' AS version,
+    allocation_id,
+    sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    value_aggregate,
+    last,
+    final
+FROM scalar_tap_ravs
+UNION ALL
+SELECT
+    'v2' AS version,
+    allocation_id,
+    payer AS sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    value_aggregate,
+    last,
+    final
+FROM tap_horizon_ravs;
@@ -0,0 +1,29 @@
+-- Add up migration script here
+-- indexer-agent sets `redeemed_at` once it redeemed a RAV and only marks it
+-- `final` later, once the redemption can't be rolled back anymore. The
+-- columns are only added on databases where indexer-agent didn't add them yet.
+ALTER TABLE scalar_tap_ravs ADD COLUMN IF NOT EXISTS redeemed_at TIMESTAMP WITH TIME ZONE DEFAULT NULL;
+ALTER TABLE tap_horizon_ravs ADD COLUMN IF NOT EXISTS redeemed_at TIMESTAMP WITH TIME ZONE DEFAULT NULL;
+
+CREATE OR REPLACE VIEW tap_fees_ravs_daily AS
+SELECT
+    'v1' AS version,
+    allocation_id,
+    sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    value_aggregate,
+    last,
+    final,
+    redeemed_at IS NOT NULL AS redeemed
+FROM scalar_tap_ravs
+UNION ALL
+SELECT
+    'v2' AS version,
+    allocation_id,
+    payer AS sender_address,
+    (to_timestamp((timestamp_ns / 1000000000)::DOUBLE PRECISION) AT TIME ZONE 'UTC')::DATE AS day,
+    value_aggregate,
+    last,
+    final,
+    redeemed_at IS NOT NULL AS redeemed
+FROM tap_horizon_ravs;